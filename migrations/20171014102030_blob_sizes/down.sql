@@ -0,0 +1,9 @@
+CREATE TABLE blobs_without_size (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB,
+        tag	INT
+);
+INSERT INTO blobs_without_size SELECT id, name, tag FROM blobs;
+DROP TABLE blobs;
+ALTER TABLE blobs_without_size RENAME TO blobs;
+CREATE UNIQUE INDEX IF NOT EXISTS Blobs_UniqueName ON blobs(name);
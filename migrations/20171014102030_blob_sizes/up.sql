@@ -0,0 +1 @@
+ALTER TABLE blobs ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+CREATE TABLE snapshots_without_trash (
+	id		INTEGER PRIMARY KEY,
+	tag		INTEGER,
+	family_id	INTEGER,
+	utc_datetime    TEXT,
+	snapshot_id	INTEGER,
+	msg		BLOB,
+	hash		BLOB,
+	hash_ref	BLOB
+);
+INSERT INTO snapshots_without_trash
+	SELECT id, tag, family_id, utc_datetime, snapshot_id, msg, hash, hash_ref FROM snapshots;
+DROP TABLE snapshots;
+ALTER TABLE snapshots_without_trash RENAME TO snapshots;
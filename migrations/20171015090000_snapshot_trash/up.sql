@@ -0,0 +1 @@
+ALTER TABLE snapshots ADD COLUMN trashed_utc_datetime TEXT;
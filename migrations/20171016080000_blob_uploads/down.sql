@@ -0,0 +1,10 @@
+CREATE TABLE blobs_without_upload_id (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB,
+        tag	INT,
+        size	INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO blobs_without_upload_id SELECT id, name, tag, size FROM blobs;
+DROP TABLE blobs;
+ALTER TABLE blobs_without_upload_id RENAME TO blobs;
+CREATE UNIQUE INDEX IF NOT EXISTS Blobs_UniqueName ON blobs(name);
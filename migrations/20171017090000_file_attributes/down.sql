@@ -0,0 +1,26 @@
+CREATE TABLE key_data_without_attributes (
+	node_id        INTEGER,
+	committed      BOOLEAN,
+	tag            INTEGER,
+
+	created        Integer,
+	modified       Integer,
+	accessed       Integer,
+
+	permissions    Integer,
+	user_id        Integer,
+	group_id       Integer,
+
+	symbolic_link_path BLOB,
+
+	hash           BLOB,
+	hash_ref       BLOB,
+
+	PRIMARY KEY (node_id, committed) ON CONFLICT REPLACE,
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
+INSERT INTO key_data_without_attributes
+	SELECT node_id, committed, tag, created, modified, accessed, permissions, user_id,
+	       group_id, symbolic_link_path, hash, hash_ref FROM key_data;
+DROP TABLE key_data;
+ALTER TABLE key_data_without_attributes RENAME TO key_data;
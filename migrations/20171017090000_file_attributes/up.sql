@@ -0,0 +1,2 @@
+ALTER TABLE key_data ADD COLUMN capabilities BLOB;
+ALTER TABLE key_data ADD COLUMN attribute_flags INTEGER;
@@ -0,0 +1,16 @@
+CREATE TABLE snapshots_without_pins (
+	id		INTEGER PRIMARY KEY,
+	tag		INTEGER,
+	family_id	INTEGER,
+	utc_datetime    TEXT,
+	snapshot_id	INTEGER,
+	msg		BLOB,
+	hash		BLOB,
+	hash_ref	BLOB,
+	trashed_utc_datetime TEXT
+);
+INSERT INTO snapshots_without_pins
+	SELECT id, tag, family_id, utc_datetime, snapshot_id, msg, hash, hash_ref,
+	       trashed_utc_datetime FROM snapshots;
+DROP TABLE snapshots;
+ALTER TABLE snapshots_without_pins RENAME TO snapshots;
@@ -0,0 +1 @@
+ALTER TABLE snapshots ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0;
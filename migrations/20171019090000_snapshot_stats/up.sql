@@ -0,0 +1,10 @@
+CREATE TABLE snapshot_stats (
+	snapshot_id	INTEGER PRIMARY KEY,
+	bytes_read	INTEGER NOT NULL,
+	bytes_uploaded	INTEGER NOT NULL,
+	new_chunks	INTEGER NOT NULL,
+	reused_chunks	INTEGER NOT NULL,
+	wall_time_ms	INTEGER NOT NULL,
+
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+DROP TABLE dir_hashes;
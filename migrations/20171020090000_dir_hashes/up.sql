@@ -0,0 +1,5 @@
+CREATE TABLE dir_hashes (
+	node_id        INTEGER PRIMARY KEY ON CONFLICT REPLACE,
+	hash_ref       BLOB NOT NULL,
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
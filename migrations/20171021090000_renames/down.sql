@@ -0,0 +1,35 @@
+DROP TABLE snapshot_renames;
+DROP TABLE key_renames;
+DROP INDEX key_data_hash;
+DROP INDEX key_data_inode;
+
+CREATE TABLE key_data_without_renames (
+	node_id        INTEGER,
+	committed      BOOLEAN,
+	tag            INTEGER,
+
+	created        Integer,
+	modified       Integer,
+	accessed       Integer,
+
+	permissions    Integer,
+	user_id        Integer,
+	group_id       Integer,
+
+	symbolic_link_path BLOB,
+
+	hash           BLOB,
+	hash_ref       BLOB,
+
+	capabilities   BLOB,
+	attribute_flags INTEGER,
+
+	PRIMARY KEY (node_id, committed) ON CONFLICT REPLACE,
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
+INSERT INTO key_data_without_renames
+	SELECT node_id, committed, tag, created, modified, accessed, permissions, user_id,
+	       group_id, symbolic_link_path, hash, hash_ref, capabilities, attribute_flags
+	FROM key_data;
+DROP TABLE key_data;
+ALTER TABLE key_data_without_renames RENAME TO key_data;
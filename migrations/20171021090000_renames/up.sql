@@ -0,0 +1,21 @@
+ALTER TABLE key_data ADD COLUMN inode INTEGER;
+ALTER TABLE key_data ADD COLUMN byte_length INTEGER;
+CREATE INDEX key_data_inode ON key_data(inode);
+CREATE INDEX key_data_hash ON key_data(hash);
+
+CREATE TABLE key_renames (
+	node_id        INTEGER PRIMARY KEY ON CONFLICT REPLACE,
+	source_id      INTEGER NOT NULL,
+	source_path    BLOB NOT NULL,
+
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
+
+CREATE TABLE snapshot_renames (
+	snapshot_id    INTEGER NOT NULL,
+	from_path      BLOB NOT NULL,
+	to_path        BLOB NOT NULL,
+
+	PRIMARY KEY (snapshot_id, to_path) ON CONFLICT REPLACE,
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+DROP INDEX snapshot_paths_last;
+DROP INDEX snapshot_paths_name;
+DROP TABLE snapshot_paths;
@@ -0,0 +1,12 @@
+CREATE TABLE snapshot_paths (
+	family_id      INTEGER NOT NULL,
+	path           BLOB NOT NULL,
+	name           BLOB NOT NULL,
+	first_snapshot INTEGER NOT NULL,
+	last_snapshot  INTEGER NOT NULL,
+
+	PRIMARY KEY (family_id, path, first_snapshot) ON CONFLICT REPLACE,
+	FOREIGN KEY(family_id) REFERENCES family(id) ON DELETE CASCADE
+);
+CREATE INDEX snapshot_paths_name ON snapshot_paths(name);
+CREATE INDEX snapshot_paths_last ON snapshot_paths(family_id, last_snapshot);
@@ -0,0 +1,22 @@
+DROP INDEX text_words_hash;
+DROP TABLE text_words;
+DROP INDEX snapshot_paths_hash;
+
+CREATE TABLE snapshot_paths_without_hash (
+	family_id      INTEGER NOT NULL,
+	path           BLOB NOT NULL,
+	name           BLOB NOT NULL,
+	first_snapshot INTEGER NOT NULL,
+	last_snapshot  INTEGER NOT NULL,
+
+	PRIMARY KEY (family_id, path, first_snapshot) ON CONFLICT REPLACE,
+	FOREIGN KEY(family_id) REFERENCES family(id) ON DELETE CASCADE
+);
+INSERT INTO snapshot_paths_without_hash
+	SELECT family_id, path, name, first_snapshot, last_snapshot FROM snapshot_paths;
+DROP INDEX snapshot_paths_last;
+DROP INDEX snapshot_paths_name;
+DROP TABLE snapshot_paths;
+ALTER TABLE snapshot_paths_without_hash RENAME TO snapshot_paths;
+CREATE INDEX snapshot_paths_name ON snapshot_paths(name);
+CREATE INDEX snapshot_paths_last ON snapshot_paths(family_id, last_snapshot);
@@ -0,0 +1,10 @@
+ALTER TABLE snapshot_paths ADD COLUMN hash BLOB;
+CREATE INDEX snapshot_paths_hash ON snapshot_paths(hash);
+
+CREATE TABLE text_words (
+	hash           BLOB NOT NULL,
+	word           TEXT NOT NULL,
+
+	PRIMARY KEY (word, hash) ON CONFLICT IGNORE
+);
+CREATE INDEX text_words_hash ON text_words(hash);
@@ -0,0 +1,11 @@
+CREATE TABLE blobs_without_checksum (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB,
+        tag	INT,
+        size	INTEGER NOT NULL DEFAULT 0,
+        upload_id	TEXT
+);
+INSERT INTO blobs_without_checksum SELECT id, name, tag, size, upload_id FROM blobs;
+DROP TABLE blobs;
+ALTER TABLE blobs_without_checksum RENAME TO blobs;
+CREATE UNIQUE INDEX IF NOT EXISTS Blobs_UniqueName ON blobs(name);
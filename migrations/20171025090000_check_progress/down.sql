@@ -0,0 +1,14 @@
+DROP TABLE check_cursor;
+
+CREATE TABLE blobs_without_verified_at (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB,
+        tag	INT,
+        size	INTEGER NOT NULL DEFAULT 0,
+        upload_id	TEXT,
+        checksum	BLOB
+);
+INSERT INTO blobs_without_verified_at SELECT id, name, tag, size, upload_id, checksum FROM blobs;
+DROP TABLE blobs;
+ALTER TABLE blobs_without_verified_at RENAME TO blobs;
+CREATE UNIQUE INDEX IF NOT EXISTS Blobs_UniqueName ON blobs(name);
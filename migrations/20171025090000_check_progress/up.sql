@@ -0,0 +1,7 @@
+ALTER TABLE blobs ADD COLUMN verified_at INTEGER;
+
+CREATE TABLE check_cursor (
+	subset	TEXT NOT NULL,
+	last_blob_id	INTEGER NOT NULL,
+	PRIMARY KEY (subset) ON CONFLICT REPLACE
+);
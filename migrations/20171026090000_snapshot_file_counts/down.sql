@@ -0,0 +1,15 @@
+CREATE TABLE snapshot_stats_without_files (
+	snapshot_id	INTEGER PRIMARY KEY,
+	bytes_read	INTEGER NOT NULL,
+	bytes_uploaded	INTEGER NOT NULL,
+	new_chunks	INTEGER NOT NULL,
+	reused_chunks	INTEGER NOT NULL,
+	wall_time_ms	INTEGER NOT NULL,
+
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
+INSERT INTO snapshot_stats_without_files
+	SELECT snapshot_id, bytes_read, bytes_uploaded, new_chunks, reused_chunks, wall_time_ms
+	FROM snapshot_stats;
+DROP TABLE snapshot_stats;
+ALTER TABLE snapshot_stats_without_files RENAME TO snapshot_stats;
@@ -0,0 +1,2 @@
+ALTER TABLE snapshot_stats ADD COLUMN new_files INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE snapshot_stats ADD COLUMN unchanged_files INTEGER NOT NULL DEFAULT 0;
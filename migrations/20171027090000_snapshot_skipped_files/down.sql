@@ -0,0 +1,18 @@
+CREATE TABLE snapshot_stats_without_skipped (
+	snapshot_id	INTEGER PRIMARY KEY,
+	bytes_read	INTEGER NOT NULL,
+	bytes_uploaded	INTEGER NOT NULL,
+	new_chunks	INTEGER NOT NULL,
+	reused_chunks	INTEGER NOT NULL,
+	wall_time_ms	INTEGER NOT NULL,
+	new_files	INTEGER NOT NULL DEFAULT 0,
+	unchanged_files	INTEGER NOT NULL DEFAULT 0,
+
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
+INSERT INTO snapshot_stats_without_skipped
+	SELECT snapshot_id, bytes_read, bytes_uploaded, new_chunks, reused_chunks, wall_time_ms,
+	       new_files, unchanged_files
+	FROM snapshot_stats;
+DROP TABLE snapshot_stats;
+ALTER TABLE snapshot_stats_without_skipped RENAME TO snapshot_stats;
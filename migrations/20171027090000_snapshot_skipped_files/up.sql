@@ -0,0 +1 @@
+ALTER TABLE snapshot_stats ADD COLUMN skipped_files INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+CREATE TABLE blobs_without_location (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB,
+        tag	INT,
+        size	INTEGER NOT NULL DEFAULT 0,
+        upload_id	TEXT,
+        checksum	BLOB,
+        verified_at	INTEGER
+);
+INSERT INTO blobs_without_location SELECT id, name, tag, size, upload_id, checksum, verified_at FROM blobs;
+DROP TABLE blobs;
+ALTER TABLE blobs_without_location RENAME TO blobs;
+CREATE UNIQUE INDEX IF NOT EXISTS Blobs_UniqueName ON blobs(name);
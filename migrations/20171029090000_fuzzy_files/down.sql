@@ -0,0 +1,22 @@
+DROP TABLE snapshot_fuzzy_files;
+DROP TABLE key_fuzzy_files;
+
+CREATE TABLE snapshot_stats_without_fuzzy (
+	snapshot_id	INTEGER PRIMARY KEY,
+	bytes_read	INTEGER NOT NULL,
+	bytes_uploaded	INTEGER NOT NULL,
+	new_chunks	INTEGER NOT NULL,
+	reused_chunks	INTEGER NOT NULL,
+	wall_time_ms	INTEGER NOT NULL,
+	new_files	INTEGER NOT NULL DEFAULT 0,
+	unchanged_files	INTEGER NOT NULL DEFAULT 0,
+	skipped_files	INTEGER NOT NULL DEFAULT 0,
+
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
+INSERT INTO snapshot_stats_without_fuzzy
+	SELECT snapshot_id, bytes_read, bytes_uploaded, new_chunks, reused_chunks, wall_time_ms,
+	       new_files, unchanged_files, skipped_files
+	FROM snapshot_stats;
+DROP TABLE snapshot_stats;
+ALTER TABLE snapshot_stats_without_fuzzy RENAME TO snapshot_stats;
@@ -0,0 +1,15 @@
+ALTER TABLE snapshot_stats ADD COLUMN fuzzy_files INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE key_fuzzy_files (
+	node_id        INTEGER PRIMARY KEY ON CONFLICT REPLACE,
+
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
+
+CREATE TABLE snapshot_fuzzy_files (
+	snapshot_id    INTEGER NOT NULL,
+	path           BLOB NOT NULL,
+
+	PRIMARY KEY (snapshot_id, path) ON CONFLICT REPLACE,
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP TABLE snapshot_commands;
+DROP TABLE key_commands;
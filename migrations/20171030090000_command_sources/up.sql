@@ -0,0 +1,17 @@
+CREATE TABLE key_commands (
+	name           TEXT PRIMARY KEY ON CONFLICT REPLACE,
+	command        TEXT NOT NULL,
+	exit_code      INTEGER,
+	timed_out      BOOLEAN NOT NULL
+);
+
+CREATE TABLE snapshot_commands (
+	snapshot_id    INTEGER NOT NULL,
+	name           TEXT NOT NULL,
+	command        TEXT NOT NULL,
+	exit_code      INTEGER,
+	timed_out      BOOLEAN NOT NULL,
+
+	PRIMARY KEY (snapshot_id, name) ON CONFLICT REPLACE,
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
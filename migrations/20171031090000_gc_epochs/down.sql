@@ -0,0 +1,18 @@
+DROP TABLE gc_epochs;
+
+CREATE TABLE hashes_without_epoch (
+    id          INTEGER PRIMARY KEY,
+    hash        BLOB,
+    tag         INTEGER,
+    height      INTEGER,
+    leaf_type   INTEGER,
+    childs      BLOB,
+    blob_id     INTEGER,
+    blob_ref    BLOB,
+    ready       BOOLEAN
+);
+INSERT INTO hashes_without_epoch
+	SELECT id, hash, tag, height, leaf_type, childs, blob_id, blob_ref, ready FROM hashes;
+DROP TABLE hashes;
+ALTER TABLE hashes_without_epoch RENAME TO hashes;
+CREATE UNIQUE INDEX IF NOT EXISTS Hashes_UniqueHash ON hashes(hash);
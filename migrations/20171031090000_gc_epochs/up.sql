@@ -0,0 +1,5 @@
+ALTER TABLE hashes ADD COLUMN epoch INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE gc_epochs (
+	epoch          INTEGER PRIMARY KEY
+);
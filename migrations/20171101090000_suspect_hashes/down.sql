@@ -0,0 +1,17 @@
+CREATE TABLE hashes_without_suspect (
+    id          INTEGER PRIMARY KEY,
+    hash        BLOB,
+    tag         INTEGER,
+    height      INTEGER,
+    leaf_type   INTEGER,
+    childs      BLOB,
+    blob_id     INTEGER,
+    blob_ref    BLOB,
+    ready       BOOLEAN,
+    epoch       INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO hashes_without_suspect
+	SELECT id, hash, tag, height, leaf_type, childs, blob_id, blob_ref, ready, epoch FROM hashes;
+DROP TABLE hashes;
+ALTER TABLE hashes_without_suspect RENAME TO hashes;
+CREATE UNIQUE INDEX IF NOT EXISTS Hashes_UniqueHash ON hashes(hash);
@@ -0,0 +1 @@
+ALTER TABLE hashes ADD COLUMN suspect BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,36 @@
+CREATE TABLE key_data_without_content_types (
+	node_id        INTEGER,
+	committed      BOOLEAN,
+	tag            INTEGER,
+
+	created        Integer,
+	modified       Integer,
+	accessed       Integer,
+
+	permissions    Integer,
+	user_id        Integer,
+	group_id       Integer,
+
+	symbolic_link_path BLOB,
+
+	hash           BLOB,
+	hash_ref       BLOB,
+
+	capabilities   BLOB,
+	attribute_flags INTEGER,
+
+	inode          INTEGER,
+	byte_length    INTEGER,
+
+	PRIMARY KEY (node_id, committed) ON CONFLICT REPLACE,
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
+INSERT INTO key_data_without_content_types
+	SELECT node_id, committed, tag, created, modified, accessed, permissions, user_id,
+	       group_id, symbolic_link_path, hash, hash_ref, capabilities, attribute_flags,
+	       inode, byte_length
+	FROM key_data;
+DROP TABLE key_data;
+ALTER TABLE key_data_without_content_types RENAME TO key_data;
+CREATE INDEX key_data_inode ON key_data(inode);
+CREATE INDEX key_data_hash ON key_data(hash);
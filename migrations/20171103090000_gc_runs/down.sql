@@ -0,0 +1,6 @@
+CREATE TABLE gc_epochs_without_times (
+	epoch          INTEGER PRIMARY KEY
+);
+INSERT INTO gc_epochs_without_times SELECT epoch FROM gc_epochs;
+DROP TABLE gc_epochs;
+ALTER TABLE gc_epochs_without_times RENAME TO gc_epochs;
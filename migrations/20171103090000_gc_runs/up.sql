@@ -0,0 +1 @@
+ALTER TABLE gc_epochs ADD COLUMN started_at INTEGER;
@@ -0,0 +1,2 @@
+DROP TABLE snapshot_deletions;
+DROP TABLE key_deletions;
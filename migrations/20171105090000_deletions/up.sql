@@ -0,0 +1,12 @@
+CREATE TABLE key_deletions (
+	path           BLOB PRIMARY KEY ON CONFLICT REPLACE
+);
+
+CREATE TABLE snapshot_deletions (
+	snapshot_id    INTEGER NOT NULL,
+	path           BLOB NOT NULL,
+
+	PRIMARY KEY (snapshot_id, path) ON CONFLICT REPLACE,
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
+CREATE INDEX snapshot_deletions_path ON snapshot_deletions(path);
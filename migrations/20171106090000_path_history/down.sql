@@ -0,0 +1,17 @@
+CREATE TABLE snapshot_paths_without_history (
+	family_id      INTEGER NOT NULL,
+	path           BLOB NOT NULL,
+	name           BLOB NOT NULL,
+	first_snapshot INTEGER NOT NULL,
+	last_snapshot  INTEGER NOT NULL,
+	hash           BLOB,
+
+	PRIMARY KEY (family_id, path, first_snapshot) ON CONFLICT REPLACE,
+	FOREIGN KEY(family_id) REFERENCES family(id) ON DELETE CASCADE
+);
+INSERT INTO snapshot_paths_without_history
+	SELECT family_id, path, name, first_snapshot, last_snapshot, hash FROM snapshot_paths;
+DROP TABLE snapshot_paths;
+ALTER TABLE snapshot_paths_without_history RENAME TO snapshot_paths;
+CREATE INDEX snapshot_paths_name ON snapshot_paths(name);
+CREATE INDEX snapshot_paths_last ON snapshot_paths(family_id, last_snapshot);
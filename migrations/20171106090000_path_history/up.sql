@@ -0,0 +1,2 @@
+ALTER TABLE snapshot_paths ADD COLUMN size INTEGER;
+ALTER TABLE snapshot_paths ADD COLUMN modified INTEGER;
@@ -0,0 +1,2 @@
+DROP TABLE snapshot_exclusions;
+DROP TABLE key_exclusions;
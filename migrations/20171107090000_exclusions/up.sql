@@ -0,0 +1,13 @@
+CREATE TABLE key_exclusions (
+	path           BLOB PRIMARY KEY ON CONFLICT REPLACE,
+	reason         TEXT NOT NULL
+);
+
+CREATE TABLE snapshot_exclusions (
+	snapshot_id    INTEGER NOT NULL,
+	path           BLOB NOT NULL,
+	reason         TEXT NOT NULL,
+
+	PRIMARY KEY (snapshot_id, path) ON CONFLICT REPLACE,
+	FOREIGN KEY(snapshot_id) REFERENCES snapshots(id) ON DELETE CASCADE
+);
@@ -0,0 +1,92 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Wrapper that turns any backend into an append-only one.
+
+use backend::{BlobStat, Capabilities, StoreBackend};
+use crypto::CipherText;
+use std::sync::Arc;
+
+
+/// Stores and retrieves blobs through `B`, but never deletes or renames them. Useful when the
+/// credentials used for backups should not be able to destroy earlier backups.
+pub struct AppendOnlyBackend<B> {
+    inner: B,
+}
+
+impl<B: StoreBackend> AppendOnlyBackend<B> {
+    pub fn new(inner: B) -> AppendOnlyBackend<B> {
+        AppendOnlyBackend { inner: inner }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for AppendOnlyBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, _name: &[u8]) -> Result<(), String> {
+        Err("Backend is append-only".to_owned())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            delete: false,
+            rename: false,
+            archival: self.inner.capabilities().archival,
+        }
+    }
+
+    fn free_space(&self) -> Result<Option<u64>, String> {
+        self.inner.free_space()
+    }
+
+    fn stat(&self, name: &[u8]) -> Result<Option<BlobStat>, String> {
+        self.inner.stat(name)
+    }
+
+    fn is_readable(&self, name: &[u8]) -> Result<bool, String> {
+        self.inner.is_readable(name)
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.request_restore(name)
+    }
+
+    fn store_resumable(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        upload_id: &str,
+    ) -> Result<(), String> {
+        self.inner.store_resumable(name, data, upload_id)
+    }
+
+    fn abort_upload(&self, name: &[u8], upload_id: &str) -> Result<(), String> {
+        self.inner.abort_upload(name, upload_id)
+    }
+}
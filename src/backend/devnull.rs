@@ -14,6 +14,7 @@
 
 use backend::StoreBackend;
 use crypto::CipherText;
+use std::sync::Arc;
 
 pub struct DevNullBackend;
 
@@ -22,7 +23,7 @@ impl StoreBackend for DevNullBackend {
         Ok(())
     }
 
-    fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn retrieve(&self, _name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
         Ok(None)
     }
 
@@ -13,64 +13,185 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{BlobStat, Capabilities, StoreBackend};
 use crypto::CipherText;
 use hex::{FromHex, ToHex};
+use libc;
+use std::cmp;
+use std::ffi::CString;
+use std::fmt;
 use std::collections::BTreeMap;
 use std::fs;
 use std::io;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+
+/// Name of the file in the root directory recording the layout.
+const LAYOUT_FILE: &'static str = "layout";
+
+/// Deepest supported fan-out.
+pub const MAX_FAN_OUT: usize = 4;
+
+/// How blob files are arranged below the root directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Layout {
+    /// All blobs directly in the root directory.
+    Flat,
+    /// Blobs in this many levels of directories named after the leading bytes of the blob
+    /// name, e.g. `ab/cd/abcdef...` for two levels.
+    FanOut(usize),
+}
+
+impl Layout {
+    fn levels(&self) -> usize {
+        match *self {
+            Layout::Flat => 0,
+            Layout::FanOut(levels) => levels,
+        }
+    }
+
+    fn parse(s: &str) -> Result<Layout, String> {
+        let mut words = s.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("flat"), None, None) => Ok(Layout::Flat),
+            (Some("fan-out"), Some(levels), None) => {
+                match levels.parse::<usize>() {
+                    Ok(levels) if levels > 0 && levels <= MAX_FAN_OUT => {
+                        Ok(Layout::FanOut(levels))
+                    }
+                    _ => Err(format!("Invalid fan-out in blob layout: {}", levels)),
+                }
+            }
+            _ => Err(format!("Unknown blob layout: {}", s.trim())),
+        }
+    }
+
+    /// Read the layout recorded in `root`. Directories without a record use the flat layout.
+    pub fn read(root: &Path) -> Result<Layout, String> {
+        use self::io::Read;
+
+        let mut s = String::new();
+        match fs::File::open(root.join(LAYOUT_FILE)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Layout::Flat),
+            Err(e) => return Err(e.to_string()),
+            Ok(mut f) => f.read_to_string(&mut s).map_err(|e| e.to_string())?,
+        };
+        Layout::parse(&s)
+    }
+
+    fn write(&self, root: &Path) -> Result<(), String> {
+        use self::io::Write;
+
+        let es = &|e: io::Error| e.to_string();
+        let tmp = root.join(format!("{}.tmp", LAYOUT_FILE));
+        let mut f = fs::File::create(&tmp).map_err(es)?;
+        writeln!(f, "{}", self).map_err(es)?;
+        f.sync_all().map_err(es)?;
+        fs::rename(&tmp, root.join(LAYOUT_FILE)).map_err(es)
+    }
+
+    /// Path of the blob with hex name `hex` below `root`.
+    fn path(&self, root: &Path, hex: &str) -> PathBuf {
+        let mut p = root.to_owned();
+        for level in 0..cmp::min(self.levels(), hex.len() / 2) {
+            p.push(&hex[2 * level..2 * level + 2]);
+        }
+        p.push(hex);
+        p
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Layout::Flat => write!(f, "flat"),
+            Layout::FanOut(levels) => write!(f, "fan-out {}", levels),
+        }
+    }
+}
 
 pub struct FileBackend {
     root: PathBuf,
-    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, String>>>,
+    layout: Layout,
+    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Arc<Vec<u8>>>, String>>>,
     max_cache_size: usize,
 }
 
 impl FileBackend {
+    /// Open the blob directory `root`, using the layout recorded in it.
     pub fn new(root: PathBuf) -> FileBackend {
+        let layout = Layout::read(&root).expect("Could not read blob layout");
         FileBackend {
             root: root,
+            layout: layout,
             read_cache: Mutex::new(BTreeMap::new()),
             max_cache_size: 10,
         }
     }
 
-    fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
+    /// Move all blobs below `root` to `layout` and record it. Blobs are found wherever they
+    /// are, so an interrupted migration can be completed by running it again.
+    /// Returns the number of blobs moved.
+    pub fn migrate_layout(root: &Path, layout: Layout) -> Result<u64, String> {
+        let es = &|e: io::Error| e.to_string();
+
+        let mut moved = 0;
+        for old in list_files(root).map_err(es)? {
+            let hex = match old.file_name().and_then(|n| n.to_str()) {
+                Some(hex) => hex.to_owned(),
+                None => continue,
+            };
+            let new = layout.path(root, &hex);
+            if new != old {
+                if let Some(dir) = new.parent() {
+                    fs::create_dir_all(dir).map_err(es)?;
+                }
+                fs::rename(&old, &new).map_err(es)?;
+                moved += 1;
+            }
+        }
+        layout.write(root)?;
+        Ok(moved)
+    }
+
+    fn blob_path(&self, name: &[u8]) -> PathBuf {
+        self.layout.path(&self.root, &name.to_hex())
+    }
+
+    fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Arc<Vec<u8>>>, String>> {
         match self.read_cache.lock() {
             Err(e) => Some(Err(e.to_string())),
             Ok(cache) => cache.get(name).cloned(),
         }
     }
 
-    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn get(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
         use self::io::Read;
 
-        // Read key:
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
-
-        match fs::File::open(&path) {
+        match fs::File::open(&self.blob_path(name)) {
             Err(_) => Ok(None),
             Ok(mut fd) => {
                 let mut buf = Vec::new();
                 match fd.read_to_end(&mut buf) {
-                    Ok(_) => Ok(Some(buf)),
+                    Ok(_) => Ok(Some(Arc::new(buf))),
                     Err(e) => Err(e.to_string()),
                 }
             }
         }
     }
 
+    fn part_path(&self, name: &[u8], upload_id: &str) -> PathBuf {
+        let mut p = self.root.clone();
+        p.push(format!("{}.{}.part", name.to_hex(), upload_id));
+        p
+    }
+
     fn guarded_cache_delete(&self, name: &[u8]) {
         self.read_cache.lock().unwrap().remove(name);
     }
 
-    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, String>) {
+    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Arc<Vec<u8>>>, String>) {
         let mut cache = self.read_cache.lock().unwrap();
         if cache.len() >= self.max_cache_size {
             cache.clear();
@@ -83,10 +204,15 @@ impl StoreBackend for FileBackend {
     fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
         use self::io::Write;
 
-        let mut path = self.root.clone();
-        path.push(&name.to_hex());
+        let path = self.blob_path(name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
 
-        let mut file = match fs::File::create(&path) {
+        // Replace an earlier copy whole, e.g. of the index backup, so that a crash leaves
+        // either the old or the new one.
+        let tmp = path.with_extension("tmp");
+        let mut file = match fs::File::create(&tmp) {
             Err(e) => return Err(e.to_string()),
             Ok(f) => f,
         };
@@ -96,10 +222,69 @@ impl StoreBackend for FileBackend {
                 return Err(e.to_string());
             }
         }
+        file.sync_all().map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &path).map_err(|e| e.to_string())?;
+        self.guarded_cache_delete(name);
         Ok(())
     }
 
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn store_resumable(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        upload_id: &str,
+    ) -> Result<(), String> {
+        use self::io::{Read, Seek, SeekFrom, Write};
+
+        let es = &|e: io::Error| e.to_string();
+        let part = self.part_path(name, upload_id);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&part)
+            .map_err(es)?;
+
+        // An upload id is only ever used for one blob, so continue where an earlier attempt
+        // stopped. After a crash, the end of the part may not hold what was written, so only
+        // a part that matches the start of the blob is kept.
+        let mut written = vec![];
+        file.read_to_end(&mut written).map_err(es)?;
+        let offset = if is_prefix(&written[..], data) {
+            written.len()
+        } else {
+            0
+        };
+        file.set_len(offset as u64).map_err(es)?;
+        file.seek(SeekFrom::Start(offset as u64)).map_err(es)?;
+
+        let mut skip = offset;
+        for r in data.slices() {
+            if skip >= r.len() {
+                skip -= r.len();
+                continue;
+            }
+            file.write_all(&r[skip..]).map_err(es)?;
+            skip = 0;
+        }
+        file.sync_all().map_err(es)?;
+
+        let path = self.blob_path(name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(es)?;
+        }
+        fs::rename(&part, &path).map_err(es)
+    }
+
+    fn abort_upload(&self, name: &[u8], upload_id: &str) -> Result<(), String> {
+        match fs::remove_file(self.part_path(name, upload_id)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.to_string()),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
         // Check for key in cache:
         let value_opt = self.guarded_cache_get(name);
         if let Some(r) = value_opt {
@@ -118,13 +303,7 @@ impl StoreBackend for FileBackend {
         let name = name.to_vec();
         self.guarded_cache_delete(&name);
 
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
-
-        match fs::remove_file(&path) {
+        match fs::remove_file(&self.blob_path(&name)) {
             Ok(_) => Ok(()),
             Err(e) => Err(e.to_string()),
         }
@@ -134,11 +313,9 @@ impl StoreBackend for FileBackend {
         let es = &|e: io::Error| e.to_string();
 
         let mut out = vec![];
-        for p in fs::read_dir(&self.root).map_err(es)? {
-            if let Some(name) = p.map_err(es)?.path().file_name() {
-                name.to_str().map(|s| Vec::from_hex(s).unwrap()).map(|b| {
-                    out.push(b.into_boxed_slice())
-                });
+        for path in list_files(&self.root).map_err(es)? {
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                out.push(Vec::from_hex(name).unwrap().into_boxed_slice());
             }
         }
         Ok(out)
@@ -147,4 +324,212 @@ impl StoreBackend for FileBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            delete: true,
+            rename: true,
+            archival: false,
+        }
+    }
+
+    fn rename(&self, from: &[u8], to: &[u8]) -> Result<(), String> {
+        let es = &|e: io::Error| e.to_string();
+
+        self.guarded_cache_delete(from);
+        self.guarded_cache_delete(to);
+
+        // Hard linking fails if the target exists, unlike renaming.
+        let (old, new) = (self.blob_path(from), self.blob_path(to));
+        if let Some(dir) = new.parent() {
+            fs::create_dir_all(dir).map_err(es)?;
+        }
+        fs::hard_link(&old, &new).map_err(|e| if e.kind() == io::ErrorKind::AlreadyExists {
+            format!("Blob already exists: {}", to.to_hex())
+        } else {
+            e.to_string()
+        })?;
+        fs::remove_file(&old).map_err(es)
+    }
+
+    fn stat(&self, name: &[u8]) -> Result<Option<BlobStat>, String> {
+        match fs::metadata(&self.blob_path(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+            Ok(meta) => {
+                Ok(Some(BlobStat {
+                    size: meta.len(),
+                    stored_at: meta.modified().ok(),
+                }))
+            }
+        }
+    }
+
+    fn free_space(&self) -> Result<Option<u64>, String> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(self.root.as_os_str().as_bytes()).map_err(
+            |e| e.to_string(),
+        )?;
+        let mut stat: libc::statvfs = unsafe { ::std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+}
+
+/// Whether `part` holds the first bytes of `data`.
+fn is_prefix(part: &[u8], data: &CipherText) -> bool {
+    let mut rest = part;
+    for slice in data.slices() {
+        if rest.is_empty() {
+            return true;
+        }
+        let n = ::std::cmp::min(rest.len(), slice.len());
+        if rest[..n] != slice[..n] {
+            return false;
+        }
+        rest = &rest[n..];
+    }
+    rest.is_empty()
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && Vec::<u8>::from_hex(s).is_ok()
+}
+
+/// Paths of all blob files below `root`, in any layout. Skips anything that is not a blob,
+/// such as partial uploads and the layout record.
+fn list_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut out = vec![];
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let is_blob_or_prefix = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if entry.file_type()?.is_dir() => name.len() == 2 && is_hex(name),
+                Some(name) => is_hex(name),
+                None => false,
+            };
+            if !is_blob_or_prefix {
+                continue;
+            } else if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                out.push(path);
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_out_paths() {
+        let root = Path::new("/blobs");
+        assert_eq!(Layout::Flat.path(root, "abcdef"), Path::new("/blobs/abcdef"));
+        assert_eq!(Layout::FanOut(2).path(root, "abcdef"), Path::new("/blobs/ab/cd/abcdef"));
+    }
+
+    #[test]
+    fn layout_descriptions_parse() {
+        for layout in &[Layout::Flat, Layout::FanOut(1), Layout::FanOut(MAX_FAN_OUT)] {
+            assert_eq!(Layout::parse(&layout.to_string()), Ok(*layout));
+        }
+        assert!(Layout::parse("fan-out 0").is_err());
+        assert!(Layout::parse("fan-out").is_err());
+        assert!(Layout::parse("spiral").is_err());
+    }
+
+    #[test]
+    fn migrate_flat_to_fan_out() {
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-blob-layout-{}", nanos));
+        fs::create_dir_all(&root).unwrap();
+
+        let names: Vec<Vec<u8>> = vec![vec![0xab, 0xcd, 0x01], vec![0x12, 0x34, 0x56]];
+        for name in &names {
+            fs::File::create(root.join(name.to_hex())).unwrap();
+        }
+        fs::File::create(root.join("0123.x.part")).unwrap();
+
+        assert_eq!(FileBackend::migrate_layout(&root, Layout::FanOut(2)), Ok(2));
+        assert_eq!(Layout::read(&root), Ok(Layout::FanOut(2)));
+        assert!(root.join("ab/cd/abcd01").exists());
+
+        let backend = FileBackend::new(root.clone());
+        let mut listed: Vec<Vec<u8>> =
+            backend.list().unwrap().into_iter().map(|b| b.into_vec()).collect();
+        listed.sort();
+        assert_eq!(listed, vec![names[1].clone(), names[0].clone()]);
+        assert_eq!(backend.retrieve(&names[0]), Ok(Some(Arc::new(vec![]))));
+
+        // Migrating again finds nothing to move.
+        assert_eq!(FileBackend::migrate_layout(&root, Layout::FanOut(2)), Ok(0));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stores_replace_whole_blobs() {
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-blob-replace-{}", nanos));
+        fs::create_dir_all(&root).unwrap();
+
+        let backend = FileBackend::new(root.clone());
+        backend.store(b"idx", &CipherText::new(b"first copy".to_vec())).unwrap();
+        backend.store(b"idx", &CipherText::new(b"second".to_vec())).unwrap();
+        assert_eq!(backend.retrieve(b"idx"), Ok(Some(Arc::new(b"second".to_vec()))));
+
+        // Nothing is left behind next to the blob.
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn resumed_uploads_check_the_part() {
+        use std::io::Write;
+
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-blob-resume-{}", nanos));
+        fs::create_dir_all(&root).unwrap();
+        let backend = FileBackend::new(root.clone());
+        let mut data = CipherText::new(b"first half, ".to_vec());
+        data.append(CipherText::new(b"second half".to_vec()));
+
+        // A part that was cut short is continued, and one whose end was garbled is redone.
+        for part in &[&b"first ha"[..], &b"first ha\0\0\0\0"[..]] {
+            let mut file = fs::File::create(backend.part_path(b"blob", "up")).unwrap();
+            file.write_all(part).unwrap();
+            backend.store_resumable(b"blob", &data, "up").unwrap();
+            let stored = backend.retrieve(b"blob").unwrap().unwrap();
+            assert_eq!(&stored[..], &b"first half, second half"[..]);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rename_does_not_overwrite() {
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-blob-rename-{}", nanos));
+        fs::create_dir_all(&root).unwrap();
+        FileBackend::migrate_layout(&root, Layout::FanOut(1)).unwrap();
+
+        let backend = FileBackend::new(root.clone());
+        let data = |bytes: &[u8]| CipherText::new(bytes.to_vec());
+        backend.store(&[1], &data(b"one")).unwrap();
+        backend.store(&[2], &data(b"two")).unwrap();
+
+        assert!(backend.rename(&[1], &[2]).is_err());
+        assert_eq!(backend.retrieve(&[2]), Ok(Some(Arc::new(b"two".to_vec()))));
+
+        backend.rename(&[1], &[3]).unwrap();
+        assert_eq!(backend.retrieve(&[1]), Ok(None));
+        assert_eq!(backend.retrieve(&[3]), Ok(Some(Arc::new(b"one".to_vec()))));
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
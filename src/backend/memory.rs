@@ -13,13 +13,13 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{Capabilities, StoreBackend};
 use crypto::CipherText;
 use std::collections::BTreeMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 pub struct MemoryBackend {
-    files: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    files: Mutex<BTreeMap<Vec<u8>, Arc<Vec<u8>>>>,
 }
 
 impl MemoryBackend {
@@ -27,7 +27,7 @@ impl MemoryBackend {
         MemoryBackend { files: Mutex::new(BTreeMap::new()) }
     }
 
-    fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
+    fn guarded_insert(&self, key: Vec<u8>, value: Arc<Vec<u8>>) -> Result<(), String> {
         let mut guarded_files = self.files.lock().unwrap();
         if guarded_files.contains_key(&key) {
             return Err(format!("Key already exists: '{:?}'", key));
@@ -36,7 +36,7 @@ impl MemoryBackend {
         Ok(())
     }
 
-    fn guarded_retrieve(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn guarded_retrieve(&self, key: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
         match self.files.lock() {
             Err(e) => Err(e.to_string()),
             Ok(map) => Ok(map.get(key).cloned()),
@@ -49,6 +49,20 @@ impl MemoryBackend {
         Ok(())
     }
 
+    fn guarded_rename(&self, from: &[u8], to: &[u8]) -> Result<(), String> {
+        let mut guarded_files = self.files.lock().unwrap();
+        if guarded_files.contains_key(to) {
+            return Err(format!("Key already exists: '{:?}'", to));
+        }
+        match guarded_files.remove(from) {
+            Some(value) => {
+                guarded_files.insert(to.to_vec(), value);
+                Ok(())
+            }
+            None => Err(format!("No such key: '{:?}'", from)),
+        }
+    }
+
     fn guarded_list(&self) -> Result<Vec<Box<[u8]>>, String> {
         let guarded_files = self.files.lock().unwrap();
         Ok(
@@ -63,10 +77,10 @@ impl MemoryBackend {
 
 impl StoreBackend for MemoryBackend {
     fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
-        self.guarded_insert(name.to_vec(), data.to_vec())
+        self.guarded_insert(name.to_vec(), Arc::new(data.to_vec()))
     }
 
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
         self.guarded_retrieve(name)
     }
 
@@ -81,4 +95,16 @@ impl StoreBackend for MemoryBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            delete: true,
+            rename: true,
+            archival: false,
+        }
+    }
+
+    fn rename(&self, from: &[u8], to: &[u8]) -> Result<(), String> {
+        self.guarded_rename(from, to)
+    }
 }
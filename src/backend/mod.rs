@@ -12,20 +12,166 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod append_only;
 mod devnull;
 mod file;
 mod memory;
+mod registry;
+mod tiered;
 
 use crypto::CipherText;
+use std::sync::Arc;
+use std::time::SystemTime;
 
+pub use self::append_only::AppendOnlyBackend;
 pub use self::devnull::DevNullBackend;
-pub use self::file::FileBackend;
+pub use self::file::{FileBackend, Layout, MAX_FAN_OUT};
 pub use self::memory::MemoryBackend;
+pub use self::registry::{BackendUrl, DynBackend, Registry};
+pub use self::tiered::{TierPolicy, TierStatus, TieredBackend};
+
+/// Operations a backend supports beyond storing, retrieving and listing blobs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Blobs can be deleted. Without this, garbage collection marks unused blobs but keeps them.
+    pub delete: bool,
+    /// Blobs can be renamed.
+    pub rename: bool,
+    /// Blobs may be in archival storage, and must be restored before they can be read.
+    pub archival: bool,
+}
+
+/// Size and age of a stored blob.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlobStat {
+    pub size: u64,
+    /// When the blob was stored, if the backend keeps track.
+    pub stored_at: Option<SystemTime>,
+}
 
 pub trait StoreBackend: Sync + Send + 'static {
     fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String>;
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String>;
+    /// The contents of blob `name`, shared so that blobs can be cached and handed out without
+    /// copying them.
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String>;
     fn delete(&self, name: &[u8]) -> Result<(), String>;
     fn list(&self) -> Result<Vec<Box<[u8]>>, String>;
     fn flush(&self) -> Result<(), String>;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            delete: true,
+            rename: false,
+            archival: false,
+        }
+    }
+
+    /// Rename blob `from` to `to`. Fails rather than overwrite an existing blob `to`.
+    fn rename(&self, _from: &[u8], _to: &[u8]) -> Result<(), String> {
+        Err("Backend does not support renaming blobs".to_owned())
+    }
+
+    /// Bytes available on the storage target, if the backend can tell.
+    fn free_space(&self) -> Result<Option<u64>, String> {
+        Ok(None)
+    }
+
+    /// Size and age of blob `name`, or `None` if there is no such blob. Backends that cannot
+    /// tell without reading the blob fetch it.
+    fn stat(&self, name: &[u8]) -> Result<Option<BlobStat>, String> {
+        Ok(self.retrieve(name)?.map(|data| {
+            BlobStat {
+                size: data.len() as u64,
+                stored_at: None,
+            }
+        }))
+    }
+
+    /// Whether blob `name` can be read now. On archival backends, blobs can only be read once
+    /// a restore requested with `request_restore` has completed.
+    fn is_readable(&self, _name: &[u8]) -> Result<bool, String> {
+        Ok(true)
+    }
+
+    /// Ask an archival backend to make blob `name` readable. Restores complete in the
+    /// background, and asking again for a blob that is being restored does nothing.
+    fn request_restore(&self, _name: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Store `data` under `name`, continuing from where an earlier attempt with the same
+    /// `upload_id` stopped, possibly in an earlier process. An upload id is only used for one
+    /// `data`. Backends that cannot resume uploads start over.
+    fn store_resumable(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        _upload_id: &str,
+    ) -> Result<(), String> {
+        self.store(name, data)
+    }
+
+    /// Discard whatever an unfinished upload left behind.
+    fn abort_upload(&self, _name: &[u8], _upload_id: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<B: StoreBackend + ?Sized> StoreBackend for Box<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        (**self).store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        (**self).retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        (**self).delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        (**self).list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        (**self).flush()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn rename(&self, from: &[u8], to: &[u8]) -> Result<(), String> {
+        (**self).rename(from, to)
+    }
+
+    fn free_space(&self) -> Result<Option<u64>, String> {
+        (**self).free_space()
+    }
+
+    fn stat(&self, name: &[u8]) -> Result<Option<BlobStat>, String> {
+        (**self).stat(name)
+    }
+
+    fn is_readable(&self, name: &[u8]) -> Result<bool, String> {
+        (**self).is_readable(name)
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<(), String> {
+        (**self).request_restore(name)
+    }
+
+    fn store_resumable(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        upload_id: &str,
+    ) -> Result<(), String> {
+        (**self).store_resumable(name, data, upload_id)
+    }
+
+    fn abort_upload(&self, name: &[u8], upload_id: &str) -> Result<(), String> {
+        (**self).abort_upload(name, upload_id)
+    }
 }
@@ -0,0 +1,178 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backends chosen by URL, so that commands can open blob storage without knowing its type.
+
+use backend::{FileBackend, Layout, MemoryBackend, StoreBackend};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+
+/// A backend of any type.
+pub type DynBackend = Box<StoreBackend>;
+
+/// Where blobs are stored: the scheme names the type of backend, and the location is
+/// interpreted by that backend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BackendUrl {
+    pub scheme: String,
+    pub location: String,
+}
+
+impl BackendUrl {
+    /// Parse `scheme://location`. Anything without a scheme is a path for the file backend.
+    pub fn parse(url: &str) -> Result<BackendUrl, String> {
+        let (scheme, location) = match url.find("://") {
+            Some(i) => (&url[..i], &url[i + 3..]),
+            None => ("file", url),
+        };
+        let valid_scheme = scheme.chars().enumerate().all(|(i, c)| match c {
+            'a'...'z' => true,
+            '0'...'9' | '+' | '-' | '.' => i > 0,
+            _ => false,
+        });
+        if scheme.is_empty() || !valid_scheme {
+            return Err(format!("Invalid backend URL scheme: {}", url));
+        }
+        Ok(BackendUrl {
+            scheme: scheme.to_owned(),
+            location: location.to_owned(),
+        })
+    }
+
+    /// The same backend, with `path` below the location.
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> BackendUrl {
+        BackendUrl {
+            scheme: self.scheme.clone(),
+            location: Path::new(&self.location).join(path).to_string_lossy().into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for BackendUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.location)
+    }
+}
+
+type Factory = Box<Fn(&BackendUrl) -> Result<DynBackend, String> + Send + Sync>;
+
+/// The types of backend that can be opened by URL, by scheme.
+pub struct Registry {
+    backends: BTreeMap<String, (String, Factory)>,
+}
+
+impl Registry {
+    /// A registry without any backends.
+    pub fn empty() -> Registry {
+        Registry { backends: BTreeMap::new() }
+    }
+
+    /// Open URLs with `scheme` using `factory`, replacing any earlier registration.
+    pub fn register<F>(&mut self, scheme: &str, description: &str, factory: F)
+    where
+        F: Fn(&BackendUrl) -> Result<DynBackend, String> + Send + Sync + 'static,
+    {
+        self.backends.insert(
+            scheme.to_owned(),
+            (description.to_owned(), Box::new(factory)),
+        );
+    }
+
+    /// The registered schemes and what they store blobs in.
+    pub fn schemes(&self) -> Vec<(&str, &str)> {
+        self.backends.iter().map(|(scheme, &(ref desc, _))| (&scheme[..], &desc[..])).collect()
+    }
+
+    pub fn open(&self, url: &BackendUrl) -> Result<DynBackend, String> {
+        match self.backends.get(&url.scheme) {
+            Some(&(_, ref factory)) => factory(url),
+            None => {
+                Err(format!(
+                    "No backend for {}:// URLs, known schemes are: {}",
+                    url.scheme,
+                    self.backends.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            }
+        }
+    }
+
+    /// Parse `url` and open it.
+    pub fn open_url(&self, url: &str) -> Result<DynBackend, String> {
+        self.open(&BackendUrl::parse(url)?)
+    }
+}
+
+impl Default for Registry {
+    /// The backends that come with hat.
+    fn default() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register("file", "a local directory", open_file);
+        registry.register("mem", "memory, for tests; nothing is kept", |_| {
+            Ok(Box::new(MemoryBackend::new()))
+        });
+        registry
+    }
+}
+
+fn open_file(url: &BackendUrl) -> Result<DynBackend, String> {
+    if url.location.is_empty() {
+        return Err(format!("Missing directory in {}", url));
+    }
+    let root = Path::new(&url.location);
+    fs::create_dir_all(root).map_err(|e| format!("Could not create {}: {}", root.display(), e))?;
+    Layout::read(root).map_err(|e| format!("Could not read blob layout of {}: {}", url, e))?;
+    Ok(Box::new(FileBackend::new(root.to_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::CipherText;
+
+    #[test]
+    fn urls_default_to_files() {
+        let url = BackendUrl::parse("s3://bucket/prefix").unwrap();
+        assert_eq!(("s3", "bucket/prefix"), (&url.scheme[..], &url.location[..]));
+        assert_eq!("s3://bucket/prefix/namespaces/a", url.join("namespaces/a").to_string());
+
+        let url = BackendUrl::parse("/mnt/blobs").unwrap();
+        assert_eq!(("file", "/mnt/blobs"), (&url.scheme[..], &url.location[..]));
+        assert_eq!("mem://", BackendUrl::parse("mem://").unwrap().to_string());
+
+        assert!(BackendUrl::parse("://x").is_err());
+        assert!(BackendUrl::parse("S3://x").is_err());
+        assert!(BackendUrl::parse("3s://x").is_err());
+    }
+
+    #[test]
+    fn open_registered_backends() {
+        let registry = Registry::default();
+        assert_eq!(
+            vec!["file", "mem"],
+            registry.schemes().into_iter().map(|(s, _)| s).collect::<Vec<_>>()
+        );
+
+        let backend = registry.open_url("mem://").unwrap();
+        backend.store(b"name", &CipherText::new(b"data".to_vec())).unwrap();
+        assert_eq!(b"data".to_vec(), *backend.retrieve(b"name").unwrap().unwrap());
+        assert!(backend.capabilities().delete);
+
+        assert!(registry.open_url("file://").is_err());
+        let err = registry.open_url("sftp://host/dir").err().unwrap();
+        assert_eq!("No backend for sftp:// URLs, known schemes are: file, mem", err);
+    }
+}
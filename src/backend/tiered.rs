@@ -0,0 +1,308 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Two storage tiers: new blobs on a fast backend, moved to a slower one as they age.
+
+use backend::{BlobStat, Capabilities, StoreBackend};
+use blob;
+use crypto::CipherText;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+
+/// When blobs move from the hot tier to the cold one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TierPolicy {
+    /// Blobs that have been in the hot tier for longer than this are offloaded.
+    pub max_age: Duration,
+    /// Offload the oldest blobs until the hot tier holds at most this many bytes.
+    pub max_hot_bytes: Option<u64>,
+}
+
+impl Default for TierPolicy {
+    fn default() -> TierPolicy {
+        TierPolicy {
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            max_hot_bytes: None,
+        }
+    }
+}
+
+/// Number and size of the blobs in each tier.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TierStatus {
+    pub hot_blobs: u64,
+    pub hot_bytes: u64,
+    pub cold_blobs: u64,
+    pub cold_bytes: u64,
+    /// Hot blobs that the policy would offload now.
+    pub due_blobs: u64,
+    pub due_bytes: u64,
+}
+
+/// Stores new blobs in the hot backend `H` and reads from it first, falling back to the cold
+/// backend `C` for blobs that have been offloaded.
+///
+/// Objects other than blobs, such as index backups, are written to both tiers, so the cold
+/// tier alone always holds a complete repository once everything is offloaded. Without a
+/// cold tier, this passes everything through to `H`.
+pub struct TieredBackend<H, C> {
+    hot: H,
+    cold: Option<C>,
+}
+
+impl<H: StoreBackend, C: StoreBackend> TieredBackend<H, C> {
+    pub fn new(hot: H, cold: Option<C>) -> TieredBackend<H, C> {
+        TieredBackend {
+            hot: hot,
+            cold: cold,
+        }
+    }
+
+    fn cold(&self) -> Result<&C, String> {
+        self.cold.as_ref().ok_or_else(|| "No cold tier configured".to_owned())
+    }
+
+    /// The hot blobs that `policy` would offload now, oldest first.
+    fn due(&self, policy: &TierPolicy) -> Result<Vec<(Box<[u8]>, BlobStat)>, String> {
+        let mut blobs = vec![];
+        for name in self.hot.list()? {
+            if !blob::is_blob_name(&name) {
+                continue;
+            }
+            if let Some(stat) = self.hot.stat(&name)? {
+                blobs.push((name, stat));
+            }
+        }
+        // Blobs of unknown age sort first, and are treated as old.
+        blobs.sort_by_key(|&(_, stat)| stat.stored_at);
+
+        let now = SystemTime::now();
+        let mut hot_bytes: u64 = blobs.iter().map(|&(_, stat)| stat.size).sum();
+        let mut due = vec![];
+        for (name, stat) in blobs {
+            let age = stat.stored_at.map(|t| now.duration_since(t).unwrap_or_default());
+            let too_old = age.map_or(true, |age| age > policy.max_age);
+            let too_big = policy.max_hot_bytes.map_or(false, |max| hot_bytes > max);
+            if !too_old && !too_big {
+                // Everything after this blob is younger.
+                break;
+            }
+            hot_bytes -= stat.size;
+            due.push((name, stat));
+        }
+        Ok(due)
+    }
+
+    /// Count the blobs in each tier, and those that `policy` would offload now.
+    pub fn status(&self, policy: &TierPolicy) -> Result<TierStatus, String> {
+        let mut status = TierStatus::default();
+        for name in self.hot.list()? {
+            if let (true, Some(stat)) = (blob::is_blob_name(&name), self.hot.stat(&name)?) {
+                status.hot_blobs += 1;
+                status.hot_bytes += stat.size;
+            }
+        }
+        if let Some(ref cold) = self.cold {
+            for name in cold.list()? {
+                if let (true, Some(stat)) = (blob::is_blob_name(&name), cold.stat(&name)?) {
+                    status.cold_blobs += 1;
+                    status.cold_bytes += stat.size;
+                }
+            }
+        }
+        for (_, stat) in self.due(policy)? {
+            status.due_blobs += 1;
+            status.due_bytes += stat.size;
+        }
+        Ok(status)
+    }
+
+    /// Move the blobs that `policy` selects to the cold tier. Each blob is only removed from
+    /// the hot tier once the cold tier holds all of it, so an interrupted offload loses
+    /// nothing. Returns the number of blobs and bytes moved.
+    pub fn offload(&self, policy: &TierPolicy) -> Result<(u64, u64), String> {
+        let cold = self.cold()?;
+        let (mut blobs, mut bytes) = (0, 0);
+        for (name, stat) in self.due(policy)? {
+            let already_cold = cold.stat(&name)?.map_or(false, |s| s.size == stat.size);
+            if !already_cold {
+                let data = match self.hot.retrieve(&name)? {
+                    Some(data) => data,
+                    None => continue,
+                };
+                cold.store(&name, &CipherText::new(data.to_vec()))?;
+                if cold.stat(&name)?.map(|s| s.size) != Some(data.len() as u64) {
+                    return Err("Cold tier did not keep an offloaded blob".to_owned());
+                }
+            }
+            self.hot.delete(&name)?;
+            blobs += 1;
+            bytes += stat.size;
+        }
+        self.hot.flush()?;
+        Ok((blobs, bytes))
+    }
+}
+
+impl<H: StoreBackend, C: StoreBackend> StoreBackend for TieredBackend<H, C> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        if let (false, Some(cold)) = (blob::is_blob_name(name), self.cold.as_ref()) {
+            cold.store(name, data)?;
+        }
+        self.hot.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        match (self.hot.retrieve(name)?, self.cold.as_ref()) {
+            (Some(data), _) => Ok(Some(data)),
+            (None, Some(cold)) => cold.retrieve(name),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        let cold = match self.cold {
+            Some(ref cold) => cold,
+            None => return self.hot.delete(name),
+        };
+        // The blob may be in either tier, or in both after an interrupted offload.
+        match (self.hot.delete(name), cold.delete(name)) {
+            (Err(e), Err(_)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        let mut names: BTreeSet<Box<[u8]>> = self.hot.list()?.into_iter().collect();
+        if let Some(ref cold) = self.cold {
+            names.extend(cold.list()?);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        if let Some(ref cold) = self.cold {
+            cold.flush()?;
+        }
+        self.hot.flush()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        match self.cold {
+            None => self.hot.capabilities(),
+            Some(ref cold) => {
+                Capabilities {
+                    delete: self.hot.capabilities().delete && cold.capabilities().delete,
+                    rename: false,
+                    archival: self.hot.capabilities().archival || cold.capabilities().archival,
+                }
+            }
+        }
+    }
+
+    fn rename(&self, from: &[u8], to: &[u8]) -> Result<(), String> {
+        match self.cold {
+            None => self.hot.rename(from, to),
+            Some(_) => Err("Tiered backends do not support renaming blobs".to_owned()),
+        }
+    }
+
+    fn free_space(&self) -> Result<Option<u64>, String> {
+        self.hot.free_space()
+    }
+
+    fn stat(&self, name: &[u8]) -> Result<Option<BlobStat>, String> {
+        match (self.hot.stat(name)?, self.cold.as_ref()) {
+            (Some(stat), _) => Ok(Some(stat)),
+            (None, Some(cold)) => cold.stat(name),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn is_readable(&self, name: &[u8]) -> Result<bool, String> {
+        match (self.hot.stat(name)?, self.cold.as_ref()) {
+            (None, Some(cold)) => cold.is_readable(name),
+            _ => self.hot.is_readable(name),
+        }
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<(), String> {
+        match (self.hot.stat(name)?, self.cold.as_ref()) {
+            (None, Some(cold)) => cold.request_restore(name),
+            _ => self.hot.request_restore(name),
+        }
+    }
+
+    fn store_resumable(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        upload_id: &str,
+    ) -> Result<(), String> {
+        if blob::is_blob_name(name) {
+            self.hot.store_resumable(name, data, upload_id)
+        } else {
+            self.store(name, data)
+        }
+    }
+
+    fn abort_upload(&self, name: &[u8], upload_id: &str) -> Result<(), String> {
+        self.hot.abort_upload(name, upload_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+
+    fn blob_name(i: u8) -> Vec<u8> {
+        vec![i; 8 + ::crypto::sealed::desc::SEALBYTES]
+    }
+
+    #[test]
+    fn offload_moves_blobs_to_the_cold_tier() {
+        let tiers = TieredBackend::new(MemoryBackend::new(), Some(MemoryBackend::new()));
+        for i in 0..3 {
+            tiers.store(&blob_name(i), &CipherText::new(vec![i; 100])).unwrap();
+        }
+        tiers.store(b"idx", &CipherText::new(vec![1, 2, 3])).unwrap();
+
+        // The memory backend does not know when blobs were stored, so they count as old.
+        let offload_all = TierPolicy {
+            max_age: Duration::from_secs(0),
+            max_hot_bytes: None,
+        };
+        let status = tiers.status(&offload_all).unwrap();
+        assert_eq!((3, 300, 0), (status.hot_blobs, status.hot_bytes, status.cold_blobs));
+        assert_eq!((3, 300), (status.due_blobs, status.due_bytes));
+
+        assert_eq!((3, 300), tiers.offload(&offload_all).unwrap());
+        let status = tiers.status(&offload_all).unwrap();
+        assert_eq!((0, 3, 0), (status.hot_blobs, status.cold_blobs, status.due_blobs));
+
+        // Reads fall through to the cold tier; other objects stay in both.
+        let data = tiers.retrieve(&blob_name(1)).unwrap().unwrap();
+        assert_eq!(vec![1; 100], *data);
+        assert!(tiers.hot.retrieve(b"idx").unwrap().is_some());
+        assert!(tiers.cold().unwrap().retrieve(b"idx").unwrap().is_some());
+        assert_eq!(4, tiers.list().unwrap().len());
+
+        tiers.delete(&blob_name(1)).unwrap();
+        assert!(tiers.retrieve(&blob_name(1)).unwrap().is_none());
+    }
+}
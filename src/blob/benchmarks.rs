@@ -13,7 +13,7 @@
 // limitations under the License.
 
 
-use blob::{Blob, ChunkRef, NodeType, LeafType};
+use blob::{Blob, ChunkRef, NodeType, LeafType, RefFormat};
 use crypto;
 use hash::Hash;
 use hash::tree::HashRef;
@@ -41,8 +41,11 @@ fn dummy_hashref(keys: &crypto::keys::Keeper) -> HashRef {
             length: 0,
             packing: None,
             key: None,
+            integrity_length: None,
+            format: RefFormat::Legacy,
         },
         info: None,
+        size: None,
     }
 }
 
@@ -19,7 +19,7 @@ use hash::tree::HashRef;
 use std::mem;
 use std::sync::Arc;
 
-use super::BlobError;
+use super::{BlobError, RefError, RefFormat};
 
 
 pub struct Blob {
@@ -43,6 +43,16 @@ impl Blob {
         }
     }
 
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Change the size of the blob. Only allowed while it is empty.
+    pub fn set_max_len(&mut self, max_len: usize) {
+        assert_eq!(0, self.chunks.len());
+        self.max_len = max_len;
+    }
+
     pub fn upperbound_len(&self) -> usize {
         if self.chunks.len() == 0 {
             0
@@ -163,10 +173,35 @@ impl<'b> BlobReader<'b> {
         Ok(hrefs)
     }
 
+    /// Read the chunk `href` refers to. Stale or corrupt references give `BlobError::InvalidRef`.
     pub fn read_chunk(&self, href: &HashRef) -> Result<Vec<u8>, BlobError> {
-        Ok(
-            crypto::RefKey::unseal(&self.access_key, href, self.blob.as_ref())?
-                .into_vec(),
-        )
+        let cref = &href.persistent_ref;
+        if let RefFormat::Unknown(n) = cref.format {
+            return Err(BlobError::InvalidRef(RefError::UnknownFormat(n)));
+        }
+        // The sealed footer follows the chunks, so a chunk never reaches the end of the blob.
+        let blob_length = self.blob.len();
+        match cref.offset.checked_add(cref.length) {
+            Some(end) if end < blob_length => (),
+            _ => {
+                return Err(BlobError::InvalidRef(RefError::OutOfBounds {
+                    offset: cref.offset,
+                    length: cref.length,
+                    blob_length: blob_length,
+                }))
+            }
+        }
+
+        let chunk = crypto::RefKey::unseal(&self.access_key, href, self.blob.as_ref())?
+            .into_vec();
+        match cref.integrity_length {
+            Some(expected) if expected != chunk.len() as u64 => {
+                Err(BlobError::InvalidRef(RefError::LengthMismatch {
+                    expected: expected,
+                    actual: chunk.len() as u64,
+                }))
+            }
+            _ => Ok(chunk),
+        }
     }
 }
@@ -15,6 +15,8 @@
 use capnp;
 use root_capnp;
 use secstr;
+use std::error;
+use std::fmt;
 
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -97,6 +99,82 @@ impl From<LeafType> for u64 {
     }
 }
 
+/// Version of the reference format a `ChunkRef` was written with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RefFormat {
+    /// Written before references recorded an integrity length.
+    Legacy,
+    /// Records the unsealed length of the chunk.
+    V1,
+    /// Written by a newer version of hat.
+    Unknown(u8),
+}
+
+impl From<u8> for RefFormat {
+    fn from(n: u8) -> RefFormat {
+        match n {
+            0 => RefFormat::Legacy,
+            1 => RefFormat::V1,
+            n => RefFormat::Unknown(n),
+        }
+    }
+}
+
+impl From<RefFormat> for u8 {
+    fn from(f: RefFormat) -> u8 {
+        match f {
+            RefFormat::Legacy => 0,
+            RefFormat::V1 => 1,
+            RefFormat::Unknown(n) => n,
+        }
+    }
+}
+
+/// Why a `ChunkRef` cannot be read from its blob.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RefError {
+    /// The referenced slice does not lie inside the blob.
+    OutOfBounds {
+        offset: usize,
+        length: usize,
+        blob_length: usize,
+    },
+    /// The chunk unsealed to a different length than recorded.
+    LengthMismatch { expected: u64, actual: u64 },
+    /// The reference was written in a format this version cannot read.
+    UnknownFormat(u8),
+}
+
+impl fmt::Display for RefError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RefError::OutOfBounds {
+                offset,
+                length,
+                blob_length,
+            } => {
+                write!(
+                    f,
+                    "Chunk at {}+{} lies outside its blob of {} bytes",
+                    offset,
+                    length,
+                    blob_length
+                )
+            }
+            RefError::LengthMismatch { expected, actual } => {
+                write!(f, "Chunk has {} bytes, expected {}", actual, expected)
+            }
+            RefError::UnknownFormat(n) => write!(f, "Unknown chunk reference format: {}", n),
+        }
+    }
+}
+
+impl error::Error for RefError {
+    fn description(&self) -> &str {
+        "invalid chunk reference"
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ChunkRef {
     pub blob_id: Option<i64>,
@@ -105,6 +183,9 @@ pub struct ChunkRef {
     pub length: usize,
     pub packing: Option<Packing>,
     pub key: Option<Key>,
+    /// Unsealed length of the chunk, if recorded.
+    pub integrity_length: Option<u64>,
+    pub format: RefFormat,
 }
 
 impl ChunkRef {
@@ -164,6 +245,9 @@ impl ChunkRef {
             Some(Packing::GZip) => msg.borrow().init_packing().set_gzip(()),
             Some(Packing::Snappy) => msg.borrow().init_packing().set_snappy(()),
         }
+
+        msg.set_integrity_length(self.integrity_length.unwrap_or(0));
+        msg.set_format(From::from(self.format));
     }
 
     pub fn read_msg(msg: &root_capnp::chunk_ref::Reader) -> Result<ChunkRef, capnp::Error> {
@@ -183,6 +267,11 @@ impl ChunkRef {
                     Some(Key::AeadChacha20Poly1305(secstr::SecStr::from(res?)))
                 }
             },
+            integrity_length: match RefFormat::from(msg.get_format()) {
+                RefFormat::Legacy => None,
+                _ => Some(msg.get_integrity_length()),
+            },
+            format: RefFormat::from(msg.get_format()),
         })
     }
 }
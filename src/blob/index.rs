@@ -15,6 +15,8 @@
 //! Local state for external blobs and their states.
 
 
+use backend::StoreBackend;
+use blob::{BlobError, Quota};
 use crypto;
 use db;
 
@@ -34,7 +36,13 @@ pub struct BlobDesc {
 pub struct InternalBlobIndex {
     index: Arc<db::Index>,
     next_id: Arc<Mutex<i64>>,
+    // New blob ids are taken from this range.
+    id_range: Mutex<(i64, i64)>,
+    // The backend record that must still name this owner for the range to be ours.
+    id_range_owner: Mutex<Option<(Vec<u8>, Vec<u8>)>>,
     keys: Arc<crypto::keys::Keeper>,
+    used_bytes: Mutex<u64>,
+    quota: Mutex<Quota>,
 }
 
 pub struct BlobIndex(InternalBlobIndex);
@@ -48,9 +56,14 @@ impl InternalBlobIndex {
         let bi = InternalBlobIndex {
             index: index,
             next_id: Arc::new(Mutex::new(0)),
+            id_range: Mutex::new((0, i64::max_value())),
+            id_range_owner: Mutex::new(None),
             keys: keys,
+            used_bytes: Mutex::new(0),
+            quota: Mutex::new(Default::default()),
         };
         bi.refresh_next_id();
+        bi.refresh_used_bytes();
         Ok(bi)
     }
 
@@ -79,20 +92,28 @@ impl InternalBlobIndex {
     }
 
     pub fn refresh_next_id(&self) {
+        let (start, end) = *self.id_range.lock().unwrap();
         let id = {
-            self.index.lock().blob_next_id()
+            self.index.lock().blob_next_id(start, end)
         };
         let mut next_id = self.next_id.lock().unwrap();
         *next_id = 1 + id;
     }
 
+    pub fn refresh_used_bytes(&self) {
+        let used = {
+            self.index.lock().blob_total_size()
+        };
+        *self.used_bytes.lock().unwrap() = used;
+    }
+
     fn next_id(&self) -> i64 {
         let mut id = self.next_id.lock().unwrap();
         *id += 1;
         *id
     }
 
-    fn recover(&self, name: Vec<u8>) -> BlobDesc {
+    fn recover(&self, name: Vec<u8>, size: u64) -> BlobDesc {
         let wanted_id = self.id_of_name(&name).unwrap();
         if let Some(id) = {
             self.index.lock().blob_id_from_name(&name[..])
@@ -108,8 +129,8 @@ impl InternalBlobIndex {
             name: name,
             id: wanted_id,
         };
-        self.index.lock().blob_in_air(&blob);
-        self.index.lock().blob_commit(&blob);
+        self.index.lock().blob_in_air(&blob, None);
+        self.index.lock().blob_commit(&blob, size, None);
 
         blob
     }
@@ -132,23 +153,107 @@ impl BlobIndex {
         self.0.reserve()
     }
 
+    /// Take the ids of new blobs from `[start, end)`, so that clients sharing a repository
+    /// never pick the same blob id.
+    pub fn set_id_range(&self, start: i64, end: i64) {
+        *self.0.id_range.lock().unwrap() = (start, end);
+        self.0.refresh_next_id();
+    }
+
+    /// Only use the id range while the backend record `record` names `owner`. Two clients can
+    /// both believe they claimed a range, so the record is checked before every upload.
+    pub fn set_id_range_owner(&self, record: Vec<u8>, owner: Vec<u8>) {
+        *self.0.id_range_owner.lock().unwrap() = Some((record, owner));
+    }
+
+    /// Check that the id range is still ours, if it was claimed in `backend`.
+    pub fn check_id_range_owner<B: StoreBackend>(&self, backend: &B) -> Result<(), String> {
+        let owner = self.0.id_range_owner.lock().unwrap().clone();
+        if let Some((record, owner)) = owner {
+            match backend.retrieve(&record)? {
+                Some(ref found) if found[..] == owner[..] => (),
+                _ => {
+                    return Err(format!(
+                        "Another client claimed blob id range {}",
+                        String::from_utf8_lossy(&record)
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Report that this blob is in the process of being committed to persistent storage. If a
     /// blob is in this state when the system starts up, it may or may not exist in the persistent
     /// storage, but **should not** be referenced elsewhere, and is therefore safe to delete.
-    pub fn in_air(&self, blob: &BlobDesc) {
-        self.0.index.lock().blob_in_air(blob)
+    ///
+    /// The `upload_id` is kept until the blob is committed, so that a partial upload left behind
+    /// by a crash can be found and discarded.
+    pub fn in_air(&self, blob: &BlobDesc, upload_id: Option<&str>) {
+        self.0.index.lock().blob_in_air(blob, upload_id)
+    }
+
+    /// List blobs with an unfinished upload, and the ids of those uploads.
+    pub fn list_uploads(&self) -> Vec<(BlobDesc, String)> {
+        self.0.index.lock().blob_list_uploads()
+    }
+
+    pub fn clear_upload(&self, blob: &BlobDesc) {
+        self.0.index.lock().blob_clear_upload(blob)
     }
 
     /// Report that this blob has been fully committed to persistent storage. We can now use its
     /// reference internally. Only committed blobs are considered "safe to use".
-    pub fn commit_done(&self, blob: &BlobDesc) {
-        self.0.index.lock().blob_commit(blob)
+    ///
+    /// The `checksum` is compared against the blob whenever it is downloaded again.
+    pub fn commit_done(&self, blob: &BlobDesc, size: u64, checksum: Option<&[u8]>) {
+        self.0.index.lock().blob_commit(blob, size, checksum);
+        *self.0.used_bytes.lock().unwrap() += size;
+    }
+
+    /// The size and checksum recorded when the named blob was uploaded. Blobs recovered from
+    /// the backend have none.
+    pub fn checksum(&self, name: &[u8]) -> Option<(u64, Vec<u8>)> {
+        self.0.index.lock().blob_checksum(name)
+    }
+
+    /// The backend name the named blob is stored under. Blobs are stored under their own name
+    /// until they are moved, which leaves the chunk references into them unchanged.
+    pub fn location(&self, name: &[u8]) -> Vec<u8> {
+        self.0.index.lock().blob_location(name).unwrap_or_else(
+            || name.to_vec(),
+        )
+    }
+
+    /// Record that `blob` is now stored under the backend name `location`.
+    pub fn set_location(&self, blob: &BlobDesc, location: &[u8]) {
+        self.0.index.lock().blob_set_location(blob, Some(location))
+    }
+
+    /// Number of bytes stored in committed blobs.
+    pub fn used_bytes(&self) -> u64 {
+        *self.0.used_bytes.lock().unwrap()
+    }
+
+    pub fn set_quota(&self, quota: Quota) {
+        *self.0.quota.lock().unwrap() = quota;
+    }
+
+    /// Check that `extra` more bytes fit within the quota.
+    pub fn check_quota(&self, extra: u64, free: Option<u64>) -> Result<(), BlobError> {
+        self.0.quota.lock().unwrap().check(
+            self.used_bytes(),
+            extra,
+            free,
+        )
     }
 
     /// Reinstall blob recovered by from external storage.
-    /// Creates a new blob by a known external name.
-    pub fn recover(&self, name: Vec<u8>) -> BlobDesc {
-        self.0.recover(name)
+    /// Creates a new blob by a known external name, of `size` bytes as stored.
+    pub fn recover(&self, name: Vec<u8>, size: u64) -> BlobDesc {
+        let blob = self.0.recover(name, size);
+        self.0.refresh_used_bytes();
+        blob
     }
 
     pub fn find(&self, name: &[u8]) -> Option<BlobDesc> {
@@ -162,6 +267,21 @@ impl BlobIndex {
         }
     }
 
+    /// Record that the blob was downloaded and checked at `when`, in seconds since the epoch.
+    pub fn set_verified(&self, blob: &BlobDesc, when: i64) {
+        self.0.index.lock().blob_set_verified(blob, when)
+    }
+
+    /// The id of the last blob checked by an unfinished check of `subset`.
+    pub fn check_cursor(&self, subset: &str) -> Option<i64> {
+        self.0.index.lock().check_cursor(subset)
+    }
+
+    /// Persist how far a check of `subset` got, or clear it with `None` when it is done.
+    pub fn set_check_cursor(&self, subset: &str, blob_id: Option<i64>) {
+        self.0.index.lock().check_set_cursor(subset, blob_id)
+    }
+
     pub fn tag(&self, blob: &BlobDesc, tag: tags::Tag) {
         self.0.index.lock().blob_set_tag(tag, Some(blob))
     }
@@ -170,12 +290,32 @@ impl BlobIndex {
         self.0.index.lock().blob_set_tag(tag, None)
     }
 
+    pub fn retag(&self, from: tags::Tag, to: tags::Tag) {
+        self.0.index.lock().blob_retag(from, to)
+    }
+
+    /// Move `blob` to tag `to`, if it is tagged `from`.
+    pub fn retag_blob(&self, blob: &BlobDesc, from: tags::Tag, to: tags::Tag) {
+        self.0.index.lock().blob_retag_one(blob, from, to)
+    }
+
     pub fn list_by_tag(&self, tag: tags::Tag) -> Vec<BlobDesc> {
         self.0.index.lock().blob_list_by_tag(tag)
     }
 
+    /// List the id and size in bytes of every blob.
+    pub fn list_sizes(&self) -> Vec<(i64, u64)> {
+        self.0.index.lock().blob_list_sizes()
+    }
+
+    pub fn delete(&self, blob: &BlobDesc) {
+        self.0.index.lock().blob_delete(blob);
+        self.0.refresh_used_bytes();
+    }
+
     pub fn delete_by_tag(&self, tag: tags::Tag) {
-        self.0.index.lock().blob_delete_by_tag(tag)
+        self.0.index.lock().blob_delete_by_tag(tag);
+        self.0.refresh_used_bytes();
     }
 
     pub fn flush(&self) {
@@ -23,16 +23,21 @@ use hash::Hash;
 use hash::tree::HashRef;
 use std::borrow::Cow;
 use std::mem;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::Duration;
 use tags;
-use util::FnBox;
+use util::{FnBox, IdGenerator};
 use key;
 
 
 mod chunk;
 mod blob;
 mod index;
+mod quota;
+mod sizing;
+mod upload;
 #[cfg(test)]
 pub mod tests;
 
@@ -41,8 +46,11 @@ mod benchmarks;
 
 
 pub use self::blob::{Blob, BlobReader};
-pub use self::chunk::{ChunkRef, Key, NodeType, LeafType, Packing};
+pub use self::chunk::{ChunkRef, Key, NodeType, LeafType, Packing, RefError, RefFormat};
 pub use self::index::{BlobDesc, BlobIndex};
+pub use self::quota::Quota;
+pub use self::sizing::BlobSizing;
+pub use self::upload::DEFAULT_MAX_IN_FLIGHT;
 
 
 error_type! {
@@ -58,12 +66,94 @@ error_type! {
         },
         DataSerialization(capnp::Error) {
             cause;
+        },
+        InvalidRef(RefError) {
+            cause;
+        },
+        Verification(errors::VerificationError) {
+            cause;
         }
     }
 }
 
+/// Whether `name` can be the backend name of a blob. The backend may also hold other objects,
+/// such as index backups, under names that are never this long.
+pub fn is_blob_name(name: &[u8]) -> bool {
+    // A blob name is its sealed 8-byte id, possibly followed by a relocation generation.
+    name.len() == BLOB_NAME_LEN || parse_location_name(name).is_some()
+}
+
+const BLOB_NAME_LEN: usize = 8 + crypto::sealed::desc::SEALBYTES;
+
+/// Backend name of the copy of blob `name` written when it was moved for the `generation`th
+/// time.
+pub fn location_name(name: &[u8], generation: u64) -> Vec<u8> {
+    let mut location = name.to_vec();
+    location.extend(format!(".{}", generation).into_bytes());
+    location
+}
+
+/// The blob name and generation of a moved copy of a blob, or `None` if `location` is not one.
+pub fn parse_location_name(location: &[u8]) -> Option<(&[u8], u64)> {
+    if location.len() <= BLOB_NAME_LEN + 1 || location[BLOB_NAME_LEN] != b'.' {
+        return None;
+    }
+    let name = &location[..BLOB_NAME_LEN];
+    let generation = ::std::str::from_utf8(&location[BLOB_NAME_LEN + 1..])
+        .ok()
+        .and_then(|g| g.parse().ok());
+    match generation {
+        // Only accept the canonical spelling, so that every copy has a single name.
+        Some(g) if location_name(name, g) == location => Some((name, g)),
+        _ => None,
+    }
+}
+
 pub struct BlobStore<B>(Arc<Mutex<StoreInner<B>>>);
 
+/// Downloads and checks blobs without holding the lock of the blob store, so that several
+/// blobs can be checked at the same time.
+pub struct BlobChecker<B> {
+    keys: Arc<crypto::keys::Keeper>,
+    backend: Arc<B>,
+    blob_index: Arc<BlobIndex>,
+}
+
+fn chunk_blob(chunk: ChunkRef) -> BlobDesc {
+    BlobDesc {
+        id: chunk.blob_id.unwrap_or(0),
+        name: chunk.blob_name,
+    }
+}
+
+/// Download a blob and check it against the size and checksum recorded when it was
+/// uploaded, so that a damaged download fails here rather than as a bad chunk later.
+fn fetch<B: StoreBackend>(
+    backend: &B,
+    blob_index: &BlobIndex,
+    name: &[u8],
+) -> Result<Option<Arc<Vec<u8>>>, BlobError> {
+    let ct = match backend.retrieve(&blob_index.location(name))? {
+        Some(ct) => ct,
+        None => return Ok(None),
+    };
+    if let Some((size, checksum)) = blob_index.checksum(name) {
+        if ct.len() as u64 != size {
+            return Err(From::from(errors::VerificationError::from(format!(
+                "Downloaded blob has {} bytes, but {} were uploaded",
+                ct.len(),
+                size
+            ))));
+        }
+        if crypto::CipherTextRef::new(&ct[..]).authentication() != Some(&checksum[..]) {
+            return Err(From::from(errors::VerificationError::from(
+                "Downloaded blob does not match its upload checksum",
+            )));
+        }
+    }
+    Ok(Some(ct))
+}
+
 pub struct StoreInner<B> {
     keys: Arc<crypto::keys::Keeper>,
     backend: Arc<B>,
@@ -71,6 +161,10 @@ pub struct StoreInner<B> {
     blob_desc: BlobDesc,
     blob_refs: Vec<(Box<FnBox<(), ()>>)>,
     blob: Blob,
+    uploader: upload::Uploader<B>,
+    sizing: Option<Arc<Mutex<BlobSizing>>>,
+    // Free space of the backend, asked for once per blob rather than once per file.
+    free_space: Option<Option<u64>>,
 }
 
 impl<B> Drop for StoreInner<B> {
@@ -89,11 +183,14 @@ impl<B: StoreBackend> StoreInner<B> {
     ) -> StoreInner<B> {
         let mut bs = StoreInner {
             keys: keys.clone(),
-            backend: backend,
-            blob_index: index,
+            backend: backend.clone(),
+            blob_index: index.clone(),
             blob_desc: Default::default(),
             blob_refs: Vec::new(),
             blob: Blob::new(keys, max_blob_size),
+            uploader: upload::Uploader::new(backend, index),
+            sizing: None,
+            free_space: None,
         };
         bs.reserve_new_blob();
         bs
@@ -103,25 +200,25 @@ impl<B: StoreBackend> StoreInner<B> {
         mem::replace(&mut self.blob_desc, self.blob_index.reserve())
     }
 
-    fn flush(&mut self) {
+    fn flush(&mut self) -> Result<(), BlobError> {
         let ct = match self.blob.to_ciphertext() {
-            None => return,
+            None => return Ok(()),
             Some(ct) => ct,
         };
 
+        // Size the next blob from the uploads so far.
+        if let Some(ref sizing) = self.sizing {
+            self.blob.set_max_len(sizing.lock().unwrap().next_size());
+        }
+
         // Replace blob id
         let old_blob_desc = self.reserve_new_blob();
+        self.free_space = None;
 
-        self.blob_index.in_air(&old_blob_desc);
-        self.backend.store(&old_blob_desc.name[..], &ct).expect(
-            "Store operation failed",
-        );
-        self.blob_index.commit_done(&old_blob_desc);
-
-        // Go through callbacks
-        while let Some(callback) = self.blob_refs.pop() {
-            callback.call(());
-        }
+        // Upload in the background, so packing of the next blob can continue.
+        let callbacks = mem::replace(&mut self.blob_refs, Vec::new());
+        self.uploader.upload(old_blob_desc, ct, callbacks)?;
+        Ok(())
     }
 
     fn store(
@@ -132,12 +229,13 @@ impl<B: StoreBackend> StoreInner<B> {
         leaf: LeafType,
         info: Option<&key::Info>,
         callback: Box<FnBox<(), ()>>,
-    ) -> HashRef {
+    ) -> Result<HashRef, BlobError> {
         let mut href = HashRef {
             hash: hash,
             node: node,
             leaf: leaf,
             info: info.cloned(),
+            size: None,
             persistent_ref: ChunkRef {
                 blob_id: Some(0),
                 blob_name: vec![0],
@@ -146,6 +244,8 @@ impl<B: StoreBackend> StoreInner<B> {
                 offset: 0,
                 length: 0,
                 key: None,
+                integrity_length: None,
+                format: chunk::RefFormat::Legacy,
             },
         };
 
@@ -156,7 +256,7 @@ impl<B: StoreBackend> StoreInner<B> {
             href.persistent_ref.blob_id = Some(self.blob_desc.id);
             href.persistent_ref.blob_name = self.blob_desc.name.clone();
             if let Err(()) = self.blob.try_append(chunk, &mut href) {
-                self.flush();
+                self.flush()?;
                 href.persistent_ref.blob_id = Some(self.blob_desc.id);
                 href.persistent_ref.blob_name = self.blob_desc.name.clone();
 
@@ -170,28 +270,59 @@ impl<B: StoreBackend> StoreInner<B> {
         // Info is internal to the blob only.
         href.info = None;
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
-        href
+        Ok(href)
+    }
+
+    fn set_size_bounds(&mut self, min: usize, max: usize) {
+        let sizing = Arc::new(Mutex::new(BlobSizing::new(min, max, self.blob.max_len())));
+        self.uploader.set_sizing(Some(sizing.clone()));
+        self.sizing = Some(sizing);
+    }
+
+    fn set_ids(&mut self, ids: Arc<IdGenerator>) {
+        self.uploader.set_ids(ids);
+    }
+
+    fn set_spool_dir(&mut self, dir: Option<PathBuf>) -> Result<(), String> {
+        self.uploader.set_spool_dir(dir)
+    }
+
+    fn check_space(&mut self, extra: u64) -> Result<(), BlobError> {
+        // Account for the data already buffered in the current blob.
+        let pending = self.blob.upperbound_len() as u64;
+        let free = match self.free_space {
+            Some(free) => free,
+            None => {
+                let free = self.backend.free_space()?;
+                self.free_space = Some(free);
+                free
+            }
+        };
+        self.blob_index.check_quota(pending + extra, free)
+    }
+
+    fn fetch(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, BlobError> {
+        fetch(&*self.backend, &self.blob_index, name)
     }
 
     fn retrieve(&mut self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
         if href.persistent_ref.offset == 0 && href.persistent_ref.length == 0 {
             return Ok(Some(Vec::new()));
         }
-        match self.backend.retrieve(&href.persistent_ref.blob_name[..]) {
-            Ok(Some(blob)) => {
+        match self.fetch(&href.persistent_ref.blob_name[..])? {
+            Some(blob) => {
                 Ok(Some(BlobReader::new(
                     self.keys.clone(),
                     crypto::CipherTextRef::new(&blob[..]),
                 )?
                     .read_chunk(href)?))
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(e.into()),
+            None => Ok(None),
         }
     }
 
     fn retrieve_refs(&mut self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
-        match self.backend.retrieve(&blob.name[..])? {
+        match self.fetch(&blob.name[..])? {
             None => Ok(None),
             Some(ct) => {
                 let hrefs = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?
@@ -206,37 +337,128 @@ impl<B: StoreBackend> StoreInner<B> {
         }
     }
 
+    fn verify(&mut self, blob: &BlobDesc) -> Result<(u64, u64), BlobError> {
+        let checker = self.checker();
+        let ct = checker.fetch(blob)?;
+        checker.verify_chunks(&ct[..])
+    }
+
+    fn checker(&self) -> BlobChecker<B> {
+        BlobChecker {
+            keys: self.keys.clone(),
+            backend: self.backend.clone(),
+            blob_index: self.blob_index.clone(),
+        }
+    }
+
     fn recover(&mut self) -> Result<(), String> {
-        self.backend.list()?.into_iter()
-            .filter(|b| b.len() > 4)  // FIXME(jos): Remove when "root" is gone.
-            .map(|b| self.blob_index.recover(b.into_vec())).last();
+        let mut moved = vec![];
+        for name in self.backend.list()?.into_iter().filter(|b| is_blob_name(b)) {
+            let parsed = parse_location_name(&name).map(|(b, g)| (g, b.to_vec()));
+            match parsed {
+                Some((generation, blob_name)) => {
+                    moved.push((generation, blob_name, name.into_vec()))
+                }
+                None => {
+                    let size = self.stored_size(&name)?;
+                    self.blob_index.recover(name.into_vec(), size);
+                }
+            }
+        }
+        // Moved copies take precedence over the original, and later moves over earlier ones.
+        moved.sort();
+        for (_, blob_name, location) in moved {
+            let size = self.stored_size(&location)?;
+            let blob = self.blob_index.recover(blob_name, size);
+            self.blob_index.set_location(&blob, &location);
+        }
+        Ok(())
+    }
+
+    /// The size of the blob stored under `location`, for the quota of recovered blobs.
+    fn stored_size(&self, location: &[u8]) -> Result<u64, String> {
+        match self.backend.stat(location)? {
+            Some(stat) => Ok(stat.size),
+            None => Err(From::from("A blob disappeared during recovery")),
+        }
+    }
+
+    fn relocate(&mut self, blob: &BlobDesc) -> Result<(), BlobError> {
+        let old = self.blob_index.location(&blob.name);
+        let ct = match self.fetch(&blob.name)? {
+            Some(ct) => ct,
+            None => return Err(From::from("Blob is missing from the backend")),
+        };
+        let generation = parse_location_name(&old).map_or(0, |(_, g)| g) + 1;
+        let new = location_name(&blob.name, generation);
+
+        // Record the new location before removing the old copy, so that an interruption
+        // leaves at most an unused copy behind.
+        self.backend.store(&new, &crypto::CipherText::new(ct.to_vec()))?;
+        self.blob_index.set_location(blob, &new);
+        self.backend.delete(&old)?;
         Ok(())
     }
 
     fn tag(&mut self, chunk: ChunkRef, tag: tags::Tag) {
-        self.blob_index.tag(
-            &BlobDesc {
-                id: chunk.blob_id.unwrap_or(0),
-                name: chunk.blob_name,
-            },
-            tag,
-        );
+        self.blob_index.tag(&chunk_blob(chunk), tag);
+    }
+
+    fn retag_chunk(&mut self, chunk: ChunkRef, from: tags::Tag, to: tags::Tag) {
+        self.blob_index.retag_blob(&chunk_blob(chunk), from, to);
     }
 
     fn tag_all(&mut self, tag: tags::Tag) {
         self.blob_index.tag_all(tag);
     }
 
+    /// Delete `blob` from the backend and forget it right away, so that an interrupted prune
+    /// never has to repeat the deletion.
+    fn delete(&mut self, blob: &BlobDesc) -> Result<(), String> {
+        self.backend.delete(&self.blob_index.location(&blob.name))?;
+        self.blob_index.delete(blob);
+        self.blob_index.flush();
+        Ok(())
+    }
+
     fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), String> {
         let blobs = self.blob_index.list_by_tag(tag);
         for b in &blobs {
-            self.backend.delete(&b.name)?;
+            self.backend.delete(&self.blob_index.location(&b.name))?;
         }
         self.blob_index.delete_by_tag(tag);
         Ok(())
     }
 }
 
+impl<B: StoreBackend> BlobChecker<B> {
+    /// Download `blob`, failing if it is missing from the backend.
+    pub fn fetch(&self, blob: &BlobDesc) -> Result<Arc<Vec<u8>>, BlobError> {
+        match fetch(&*self.backend, &self.blob_index, &blob.name[..])? {
+            Some(ct) => Ok(ct),
+            None => Err(From::from("Blob is missing from the backend")),
+        }
+    }
+
+    /// Check that every chunk in the downloaded blob `ct` can be read and has the hash it is
+    /// stored under. Returns the number of chunks and their total length.
+    pub fn verify_chunks(&self, ct: &[u8]) -> Result<(u64, u64), BlobError> {
+        let reader = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(ct))?;
+        let hrefs = reader.refs()?;
+        let mut bytes = 0;
+        for href in &hrefs {
+            let chunk = reader.read_chunk(href)?;
+            if Hash::new(&self.keys, href.node, href.leaf, &chunk[..]) != href.hash {
+                return Err(From::from(
+                    format!("Chunk at offset {} has the wrong hash", href.persistent_ref.offset),
+                ));
+            }
+            bytes += chunk.len() as u64;
+        }
+        Ok((hrefs.len() as u64, bytes))
+    }
+}
+
 impl<B: StoreBackend> BlobStore<B> {
     pub fn new(
         keys: Arc<crypto::keys::Keeper>,
@@ -255,7 +477,7 @@ impl<B: StoreBackend> BlobStore<B> {
 
     /// Store a new data chunk into the current blob. The callback is triggered after the blob
     /// containing the chunk has been committed to persistent storage (it is then safe to use the
-    /// `ChunkRef` as persistent reference).
+    /// `ChunkRef` as persistent reference). Fails if an earlier upload failed.
     pub fn store(
         &self,
         chunk: &[u8],
@@ -264,11 +486,18 @@ impl<B: StoreBackend> BlobStore<B> {
         leaf: LeafType,
         info: Option<&key::Info>,
         callback: Box<FnBox<(), ()>>,
-    ) -> HashRef {
+    ) -> Result<HashRef, BlobError> {
         let mut guard = self.lock();
         guard.store(chunk, hash, node, leaf, info, callback)
     }
 
+    /// Check that `extra` more bytes can be stored without exceeding the repository quota or
+    /// the free space headroom of the backend. The free space is measured again after each
+    /// blob is flushed.
+    pub fn check_space(&self, extra: u64) -> Result<(), BlobError> {
+        self.lock().check_space(extra)
+    }
+
     /// Retrieve the data chunk identified by `ChunkRef`.
     pub fn retrieve(&self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
         self.lock().retrieve(href)
@@ -279,11 +508,28 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().retrieve_refs(blob)
     }
 
+    /// Download `blob` and check that every chunk in it can be read and has the hash it is
+    /// stored under. Returns the number of chunks and their total length.
+    pub fn verify(&self, blob: &BlobDesc) -> Result<(u64, u64), BlobError> {
+        self.lock().verify(blob)
+    }
+
+    /// A handle for checking blobs from several threads at once.
+    pub fn checker(&self) -> BlobChecker<B> {
+        self.lock().checker()
+    }
+
     /// Reinstall a blob recovered from external storage.
     pub fn recover(&self) -> Result<(), String> {
         self.lock().recover()
     }
 
+    /// Store `blob` under a new backend name and delete the old copy. Chunk references keep
+    /// naming the blob, and are resolved to its current location when read.
+    pub fn relocate(&self, blob: &BlobDesc) -> Result<(), BlobError> {
+        self.lock().relocate(blob)
+    }
+
     pub fn tag(&self, chunk: ChunkRef, tag: tags::Tag) {
         self.lock().tag(chunk, tag)
     }
@@ -292,6 +538,20 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().tag_all(tag)
     }
 
+    /// Move all blobs tagged `from` to `to`.
+    pub fn retag(&self, from: tags::Tag, to: tags::Tag) {
+        self.lock().blob_index.retag(from, to)
+    }
+
+    /// Move the blob containing `chunk` to tag `to`, if it is tagged `from`.
+    pub fn retag_chunk(&self, chunk: ChunkRef, from: tags::Tag, to: tags::Tag) {
+        self.lock().retag_chunk(chunk, from, to)
+    }
+
+    pub fn delete(&self, blob: &BlobDesc) -> Result<(), String> {
+        self.lock().delete(blob)
+    }
+
     pub fn delete_by_tag(&self, tag: tags::Tag) -> Result<(), String> {
         self.lock().delete_by_tag(tag)
     }
@@ -311,10 +571,46 @@ impl<B: StoreBackend> BlobStore<B> {
         }
     }
 
-    /// Flush the current blob, independent of its size.
-    pub fn flush(&self) {
+    /// Set how many blobs may be uploading at the same time.
+    pub fn set_max_in_flight(&self, max_in_flight: usize) {
+        self.lock().uploader.set_max_in_flight(max_in_flight)
+    }
+
+    /// Give up waiting for the backend after `timeout` when starting or flushing uploads.
+    pub fn set_upload_timeout(&self, timeout: Option<Duration>) {
+        self.lock().uploader.set_timeout(timeout)
+    }
+
+    /// Adapt the size of the blobs to the measured latency and throughput of the backend,
+    /// keeping it between `min` and `max` bytes. Blobs must have room for the largest chunk.
+    pub fn set_size_bounds(&self, min: usize, max: usize) {
+        self.lock().set_size_bounds(min, max)
+    }
+
+    /// Take the ids of uploads from `ids`.
+    pub fn set_ids(&self, ids: Arc<IdGenerator>) {
+        self.lock().set_ids(ids)
+    }
+
+    /// Keep blobs in `dir` until their upload is committed, so that uploads interrupted by
+    /// the end of the process can be finished when the repository is opened again.
+    pub fn set_spool_dir(&self, dir: Option<PathBuf>) -> Result<(), String> {
+        self.lock().set_spool_dir(dir)
+    }
+
+    /// Finish the uploads left behind by an interrupted process, or discard those whose blob
+    /// was not spooled. Must be called before anything is stored.
+    pub fn resume_unfinished_uploads(&self) -> Result<(usize, usize), String> {
+        self.lock().uploader.resume_unfinished()
+    }
+
+    /// Flush the current blob, independent of its size, and wait for all uploads to finish.
+    /// Returns the error of the first upload that failed.
+    pub fn flush(&self) -> Result<(), BlobError> {
         let mut guard = self.lock();
-        guard.flush();
+        guard.flush()?;
+        guard.uploader.wait()?;
         guard.blob_index.flush();
+        Ok(())
     }
 }
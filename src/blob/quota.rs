@@ -0,0 +1,58 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Limits on how much space the repository may consume.
+
+use blob::BlobError;
+
+
+/// Configurable repository size limits.
+///
+/// A limit of `None` is never enforced. The default quota has no limits.
+#[derive(Clone, Debug, Default)]
+pub struct Quota {
+    /// Maximum number of bytes stored in blobs.
+    pub max_repository_bytes: Option<u64>,
+    /// Minimum number of bytes that must remain free on the backend target.
+    pub min_free_bytes: Option<u64>,
+}
+
+impl Quota {
+    /// Check that `extra` more bytes can be stored given the current usage and the free space
+    /// reported by the backend (if known).
+    pub fn check(&self, used: u64, extra: u64, free: Option<u64>) -> Result<(), BlobError> {
+        if let Some(max) = self.max_repository_bytes {
+            if used.saturating_add(extra) > max {
+                return Err(From::from(format!(
+                    "Repository quota exceeded: {} bytes used, {} more needed, quota is {}",
+                    used,
+                    extra,
+                    max
+                )));
+            }
+        }
+        if let (Some(min), Some(free)) = (self.min_free_bytes, free) {
+            if free < min.saturating_add(extra) {
+                return Err(From::from(format!(
+                    "Free space too low: {} bytes free, {} more needed, keeping {} free",
+                    free,
+                    extra,
+                    min
+                )));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Choice of the blob size from measured upload times.
+
+use std::collections::VecDeque;
+
+
+/// Uploads remembered for estimating the latency and throughput of the backend.
+const SAMPLES: usize = 16;
+
+/// Share of the upload time of a blob that may go to the fixed latency of the backend.
+const LATENCY_SHARE: f64 = 0.1;
+
+/// Bounds on the size of the blobs, and the size to try next.
+///
+/// Every upload is modelled as a fixed latency plus its size divided by the throughput. Blobs
+/// are made just large enough that the latency stays a small share of each upload: small blobs
+/// for a local disk, large blobs for a remote object store.
+#[derive(Clone, Debug)]
+pub struct BlobSizing {
+    min: usize,
+    max: usize,
+    current: usize,
+    probe: bool,
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl BlobSizing {
+    pub fn new(min: usize, max: usize, initial: usize) -> BlobSizing {
+        assert!(min > 0 && min <= max);
+        BlobSizing {
+            min: min,
+            max: max,
+            current: clamp(initial, min, max),
+            probe: false,
+            samples: VecDeque::with_capacity(SAMPLES),
+        }
+    }
+
+    /// Record that uploading `bytes` took `secs` seconds.
+    pub fn record(&mut self, bytes: usize, secs: f64) {
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((bytes as f64, secs));
+    }
+
+    /// Estimated latency in seconds and throughput in bytes per second, if the uploads so far
+    /// differ enough in size to tell them apart.
+    pub fn estimate(&self) -> Option<(f64, f64)> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let mean_x = self.samples.iter().map(|s| s.0).sum::<f64>() / n;
+        let mean_y = self.samples.iter().map(|s| s.1).sum::<f64>() / n;
+        let var_x: f64 = self.samples.iter().map(|s| (s.0 - mean_x).powi(2)).sum();
+        let cov: f64 = self.samples.iter().map(|s| (s.0 - mean_x) * (s.1 - mean_y)).sum();
+        if var_x <= 0.0 || cov <= 0.0 {
+            return None;
+        }
+        // Least squares fit of: secs = latency + bytes / throughput.
+        let secs_per_byte = cov / var_x;
+        let latency = (mean_y - secs_per_byte * mean_x).max(0.0);
+        Some((latency, 1.0 / secs_per_byte))
+    }
+
+    /// The size of the next blob.
+    pub fn next_size(&mut self) -> usize {
+        match self.estimate() {
+            Some((latency, throughput)) => {
+                let target = latency * throughput * (1.0 - LATENCY_SHARE) / LATENCY_SHARE;
+                self.current = clamp(target as usize, self.min, self.max);
+                self.current
+            }
+            None => {
+                // Alternate with a smaller size until the sizes can be told apart.
+                self.probe = !self.probe;
+                if self.probe {
+                    clamp(self.current / 2, self.min, self.max)
+                } else {
+                    self.current
+                }
+            }
+        }
+    }
+}
+
+fn clamp(size: usize, min: usize, max: usize) -> usize {
+    if size < min {
+        min
+    } else if size > max {
+        max
+    } else {
+        size
+    }
+}
@@ -13,14 +13,19 @@
 // limitations under the License
 
 use backend::{MemoryBackend, StoreBackend};
-use blob::{Blob, BlobReader, BlobError, BlobIndex, BlobStore, ChunkRef, NodeType, LeafType};
+use blob::{Blob, BlobReader, BlobError, BlobIndex, BlobSizing, BlobStore, ChunkRef, NodeType,
+           LeafType, Quota, RefError, RefFormat, is_blob_name, location_name,
+           parse_location_name};
 use crypto;
 use db;
 use hash;
 use quickcheck;
 
 use std::collections::HashSet;
-use std::sync::Arc;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
 
 #[test]
 fn identity() {
@@ -44,12 +49,12 @@ fn identity() {
                     leaf,
                     None,
                     Box::new(move |_| {}),
-                ),
+                ).unwrap(),
                 chunk,
             ));
         }
 
-        bs_p.flush();
+        bs_p.flush().unwrap();
 
         // Non-empty chunks must be in the backend now:
         for &(ref id, chunk) in ids.iter() {
@@ -93,10 +98,10 @@ fn identity_with_excessive_flushing() {
                     leaf,
                     None,
                     Box::new(move |_| {}),
-                ),
+                ).unwrap(),
                 chunk,
             ));
-            bs_p.flush();
+            bs_p.flush().unwrap();
             let &(ref id, chunk) = ids.last().unwrap();
             assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), &chunk[..]);
         }
@@ -131,6 +136,8 @@ fn blobid_identity() {
             length: length,
             packing: None,
             key: None,
+            integrity_length: Some(length as u64),
+            format: RefFormat::V1,
         };
         let blob_name_bytes = blob_name.as_bytes();
         let recovered = ChunkRef::from_bytes(&mut &blob_name_bytes[..]).unwrap();
@@ -140,6 +147,8 @@ fn blobid_identity() {
         assert!(recovered.blob_id.is_none());
         assert!(recovered.packing.is_none());
         assert!(recovered.key.is_none());
+        assert_eq!(Some(length as u64), recovered.integrity_length);
+        assert_eq!(RefFormat::V1, recovered.format);
 
         true
     }
@@ -157,6 +166,7 @@ fn blob_reuse() {
         node: node,
         leaf: leaf,
         info: None,
+        size: None,
         persistent_ref: ChunkRef {
             blob_id: None,
             blob_name: Vec::new(),
@@ -164,6 +174,8 @@ fn blob_reuse() {
             length: 0,
             packing: None,
             key: None,
+            integrity_length: None,
+            format: RefFormat::Legacy,
         },
     };
     let mut c2 = c1.clone();
@@ -207,6 +219,7 @@ fn blob_identity() {
                 node: node,
                 leaf: leaf,
                 info: None,
+                size: None,
                 persistent_ref: ChunkRef {
                     blob_id: None,
                     blob_name: Vec::new(),
@@ -214,6 +227,8 @@ fn blob_identity() {
                     length: 0,
                     packing: None,
                     key: None,
+                    integrity_length: None,
+                    format: RefFormat::Legacy,
                 },
             };
             if let Err(_) = b.try_append(&chunk[..], &mut cref) {
@@ -251,6 +266,69 @@ fn blob_identity() {
     quickcheck::quickcheck(prop as fn(Vec<Vec<u8>>) -> bool);
 }
 
+#[test]
+fn stale_refs_are_rejected() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+
+    let mut b = Blob::new(keys.clone(), 10000);
+    let mut href = hash::tree::HashRef {
+        hash: hash::Hash::new(&keys, node, leaf, b"chunk"),
+        node: node,
+        leaf: leaf,
+        info: None,
+        size: None,
+        persistent_ref: ChunkRef {
+            blob_id: None,
+            blob_name: Vec::new(),
+            offset: 0,
+            length: 0,
+            packing: None,
+            key: None,
+            integrity_length: None,
+            format: RefFormat::Legacy,
+        },
+    };
+    b.try_append(b"chunk", &mut href).unwrap();
+    assert_eq!(Some(5), href.persistent_ref.integrity_length);
+    assert_eq!(RefFormat::V1, href.persistent_ref.format);
+
+    let out = b.to_ciphertext().unwrap().to_vec();
+    let reader = BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&out[..])).unwrap();
+    assert_eq!(b"chunk".to_vec(), reader.read_chunk(&href).unwrap());
+
+    let mut outside = href.clone();
+    outside.persistent_ref.offset = out.len();
+    match reader.read_chunk(&outside) {
+        Err(BlobError::InvalidRef(RefError::OutOfBounds { .. })) => (),
+        other => panic!("Expected out of bounds, got: {:?}", other),
+    }
+
+    let mut overflow = href.clone();
+    overflow.persistent_ref.length = usize::max_value();
+    match reader.read_chunk(&overflow) {
+        Err(BlobError::InvalidRef(RefError::OutOfBounds { .. })) => (),
+        other => panic!("Expected out of bounds, got: {:?}", other),
+    }
+
+    let mut wrong_length = href.clone();
+    wrong_length.persistent_ref.integrity_length = Some(6);
+    match reader.read_chunk(&wrong_length) {
+        Err(BlobError::InvalidRef(RefError::LengthMismatch {
+                                      expected: 6,
+                                      actual: 5,
+                                  })) => (),
+        other => panic!("Expected length mismatch, got: {:?}", other),
+    }
+
+    let mut future = href.clone();
+    future.persistent_ref.format = RefFormat::Unknown(7);
+    match reader.read_chunk(&future) {
+        Err(BlobError::InvalidRef(RefError::UnknownFormat(7))) => (),
+        other => panic!("Expected unknown format, got: {:?}", other),
+    }
+}
 
 #[test]
 fn random_input_fails() {
@@ -272,6 +350,7 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
             node: node,
             leaf: leaf,
             info: None,
+            size: None,
             persistent_ref: ChunkRef {
                 blob_id: None,
                 blob_name: Vec::new(),
@@ -279,6 +358,8 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
                 length: block.len(),
                 packing: None,
                 key: None,
+                integrity_length: None,
+                format: RefFormat::Legacy,
             },
         };
         match blob.try_append(&block[..], &mut cref) {
@@ -348,3 +429,556 @@ fn blob_ciphertext_authed_allbytes() {
     // We did not corrupt the blob.
     assert_eq!(vs, verify(&keys, &bytes[..]).unwrap());
 }
+
+#[test]
+fn quota_limits_repository_size() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index.clone(), backend, 1024);
+
+    blob_index.set_quota(Quota {
+        max_repository_bytes: Some(512),
+        min_free_bytes: None,
+    });
+    assert!(bs_p.check_space(256).is_ok());
+
+    let chunk = vec![1u8; 300];
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    bs_p.store(
+        &chunk[..],
+        hash::Hash::new(&keys, node, leaf, &chunk[..]),
+        node,
+        leaf,
+        None,
+        Box::new(move |_| {}),
+    ).unwrap();
+    bs_p.flush().unwrap();
+
+    assert!(blob_index.used_bytes() >= 300);
+    assert!(bs_p.check_space(256).is_err());
+    assert!(bs_p.check_space(0).is_ok());
+}
+
+#[test]
+fn quota_keeps_free_space() {
+    let quota = Quota {
+        max_repository_bytes: None,
+        min_free_bytes: Some(1000),
+    };
+    assert!(quota.check(0, 100, None).is_ok());
+    assert!(quota.check(0, 100, Some(1100)).is_ok());
+    assert!(quota.check(0, 101, Some(1100)).is_err());
+}
+
+#[test]
+fn uploads_complete_in_order() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+    bs_p.set_max_in_flight(3);
+
+    // Each chunk fills a blob of its own.
+    let done = Arc::new(Mutex::new(vec![]));
+    for i in 0..20u8 {
+        let chunk = vec![i; 600];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        let done = done.clone();
+        bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| done.lock().unwrap().push(i)),
+        ).unwrap();
+    }
+    bs_p.flush().unwrap();
+
+    assert_eq!(*done.lock().unwrap(), (0..20).collect::<Vec<u8>>());
+    assert_eq!(backend.list().unwrap().len(), 20);
+}
+
+#[test]
+fn callbacks_run_without_blocking_uploads() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+    bs_p.set_max_in_flight(1);
+
+    // The callback of the first blob waits for a lock that is held while the next blobs are
+    // uploaded, like the hash index does while a chunk is being stored.
+    let held = Arc::new(Mutex::new(()));
+    let guard = held.lock().unwrap();
+    for i in 0..3u8 {
+        let chunk = vec![i; 600];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        let held = held.clone();
+        bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| drop(held.lock().unwrap())),
+        ).unwrap();
+    }
+    drop(guard);
+    bs_p.flush().unwrap();
+
+    assert_eq!(backend.list().unwrap().len(), 3);
+}
+
+/// Refuses every blob.
+struct FailingBackend;
+
+impl StoreBackend for FailingBackend {
+    fn store(&self, _name: &[u8], _data: &crypto::CipherText) -> Result<(), String> {
+        Err("No space left".to_owned())
+    }
+
+    fn retrieve(&self, _name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        Ok(None)
+    }
+
+    fn delete(&self, _name: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        Ok(vec![])
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+fn upload_errors_are_returned() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, Arc::new(FailingBackend), 1024);
+
+    let store = |i: u8| {
+        let chunk = vec![i; 600];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| panic!("A chunk of a failed blob was committed")),
+        )
+    };
+    store(1).unwrap();
+    assert!(bs_p.flush().is_err());
+
+    // The error sticks, and is returned rather than raised as a panic.
+    store(2).unwrap();
+    assert!(store(3).is_err());
+    assert!(bs_p.flush().is_err());
+}
+
+/// Never finishes storing a blob, like a backend that stopped responding.
+struct HangingBackend;
+
+impl StoreBackend for HangingBackend {
+    fn store(&self, _name: &[u8], _data: &crypto::CipherText) -> Result<(), String> {
+        loop {
+            thread::park();
+        }
+    }
+
+    fn retrieve(&self, _name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        Ok(None)
+    }
+
+    fn delete(&self, _name: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        Ok(vec![])
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+fn hung_uploads_time_out() {
+    use std::time::Duration;
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, Arc::new(HangingBackend), 1024);
+    bs_p.set_max_in_flight(1);
+    bs_p.set_upload_timeout(Some(Duration::from_millis(50)));
+
+    let store = |i: u8| {
+        let chunk = vec![i; 600];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| panic!("A chunk of a hung blob was committed")),
+        )
+    };
+    // The first blob goes out with the second chunk, and never finishes. The next upload
+    // would wait for it.
+    store(1).unwrap();
+    store(2).unwrap();
+    assert!(store(3).is_err());
+    assert!(bs_p.flush().is_err());
+}
+
+#[test]
+fn upload_ids_are_kept_until_commit() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = BlobIndex::new(keys.clone(), db).unwrap();
+
+    let a = blob_index.reserve();
+    let b = blob_index.reserve();
+    blob_index.in_air(&a, Some("a-1"));
+    blob_index.in_air(&b, Some("b-1"));
+    blob_index.commit_done(&a, 10, None);
+
+    let uploads = blob_index.list_uploads();
+    assert_eq!(uploads.len(), 1);
+    assert_eq!(uploads[0].0.id, b.id);
+    assert_eq!(uploads[0].1, "b-1");
+
+    blob_index.clear_upload(&b);
+    assert!(blob_index.list_uploads().is_empty());
+}
+
+/// Writes the first half of a blob, and then stops like a process that was killed.
+struct InterruptedBackend {
+    root: PathBuf,
+    interrupted: Mutex<mpsc::Sender<()>>,
+}
+
+impl StoreBackend for InterruptedBackend {
+    fn store(&self, _name: &[u8], _data: &crypto::CipherText) -> Result<(), String> {
+        Err("Only resumable uploads are supported".to_owned())
+    }
+
+    fn store_resumable(
+        &self,
+        name: &[u8],
+        data: &crypto::CipherText,
+        upload_id: &str,
+    ) -> Result<(), String> {
+        use hex::ToHex;
+        use std::io::Write;
+
+        let data = data.to_vec();
+        let part = self.root.join(format!("{}.{}.part", name.to_hex(), upload_id));
+        fs::File::create(&part)
+            .and_then(|mut f| f.write_all(&data[..data.len() / 2]))
+            .unwrap();
+        self.interrupted.lock().unwrap().send(()).unwrap();
+        loop {
+            thread::park();
+        }
+    }
+
+    fn retrieve(&self, _name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        Ok(None)
+    }
+
+    fn delete(&self, _name: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        Ok(vec![])
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+fn interrupted_uploads_are_finished_on_restart() {
+    use backend::FileBackend;
+    use std::mem;
+
+    let root = ::std::env::temp_dir().join(format!("hat-resume-{}", ::time::precise_time_ns()));
+    let spool = root.join("uploads");
+    fs::create_dir_all(&root).unwrap();
+
+    // The blob index outlives the process, like the index file would.
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+
+    let (tx, rx) = mpsc::channel();
+    let backend = Arc::new(InterruptedBackend {
+        root: root.clone(),
+        interrupted: Mutex::new(tx),
+    });
+    let bs_p = BlobStore::new(keys.clone(), blob_index.clone(), backend, 1024);
+    bs_p.set_spool_dir(Some(spool.clone())).unwrap();
+    let href = {
+        let store = |chunk: &[u8]| {
+            let node = NodeType::Leaf;
+            let leaf = LeafType::FileChunk;
+            let hash = hash::Hash::new(&keys, node, leaf, chunk);
+            bs_p.store(chunk, hash, node, leaf, None, Box::new(|_| ())).unwrap()
+        };
+        let href = store(&[7u8; 600][..]);
+        // The second chunk does not fit, and sends the first blob off.
+        store(&[8u8; 600][..]);
+        href
+    };
+    rx.recv().unwrap();
+    // The process is gone before its uploads finished.
+    mem::forget(bs_p);
+    assert_eq!(blob_index.list_uploads().len(), 1);
+
+    let bs_p = BlobStore::new(
+        keys.clone(),
+        blob_index.clone(),
+        Arc::new(FileBackend::new(root.clone())),
+        1024,
+    );
+    bs_p.set_spool_dir(Some(spool.clone())).unwrap();
+    assert_eq!(bs_p.resume_unfinished_uploads(), Ok((1, 0)));
+    assert!(blob_index.list_uploads().is_empty());
+    assert_eq!(bs_p.retrieve(&href).unwrap(), Some(vec![7u8; 600]));
+
+    // Nothing is left to resume.
+    assert_eq!(fs::read_dir(&spool).unwrap().count(), 0);
+    assert_eq!(bs_p.resume_unfinished_uploads(), Ok((0, 0)));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn sizing_estimates_backend() {
+    let mut sizing = BlobSizing::new(1 << 20, 64 << 20, 4 << 20);
+    assert_eq!(None, sizing.estimate());
+
+    // 50 ms latency at 10 MB/s.
+    for &bytes in &[2 << 20, 4 << 20, 2 << 20, 4 << 20] {
+        sizing.record(bytes, 0.05 + bytes as f64 / 10e6);
+    }
+    let (latency, throughput) = sizing.estimate().unwrap();
+    assert!((latency - 0.05).abs() < 1e-6);
+    assert!((throughput - 10e6).abs() < 1.0);
+}
+
+#[test]
+fn sizing_follows_latency() {
+    let mut sizes = vec![];
+    for &latency in &[0.001, 0.05, 1.0] {
+        let mut sizing = BlobSizing::new(1 << 20, 64 << 20, 4 << 20);
+        for _ in 0..8 {
+            let bytes = sizing.next_size();
+            sizing.record(bytes, latency + bytes as f64 / 10e6);
+        }
+        sizes.push(sizing.next_size());
+    }
+    // Fast local disk, object store, slow link.
+    assert_eq!(1 << 20, sizes[0]);
+    assert!(sizes[1] > 4 << 20 && sizes[1] < 64 << 20);
+    assert_eq!(64 << 20, sizes[2]);
+}
+
+/// Takes 30 ms plus 1 us per byte to store a blob, like a backend with high latency.
+struct SlowBackend(MemoryBackend);
+
+impl StoreBackend for SlowBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        use std::time::Duration;
+        thread::sleep(Duration::from_millis(30) + Duration::new(0, 1000 * data.len() as u32));
+        self.0.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        self.0.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.0.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.0.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.0.flush()
+    }
+}
+
+#[test]
+fn sized_blobs_roundtrip() {
+    let backend = Arc::new(SlowBackend(MemoryBackend::new()));
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 4096);
+    bs_p.set_size_bounds(2048, 8192);
+    bs_p.set_max_in_flight(1);
+
+    let mut refs = vec![];
+    for i in 0..100u8 {
+        let chunk = vec![i; 500];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        let href = bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| {}),
+        ).unwrap();
+        refs.push((href, chunk));
+    }
+    bs_p.flush().unwrap();
+
+    // The latency dominates the upload of a blob of the initial size, so blobs grow to the
+    // maximum once the probes have measured it.
+    let largest = backend
+        .list()
+        .unwrap()
+        .iter()
+        .map(|name| backend.retrieve(name).unwrap().unwrap().len())
+        .max()
+        .unwrap();
+    assert!(largest > 6000, "largest blob has {} bytes", largest);
+
+    for (href, chunk) in refs {
+        assert_eq!(bs_p.retrieve(&href).unwrap(), Some(chunk));
+    }
+}
+
+#[test]
+fn damaged_downloads_are_detected() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    // Each chunk fills a blob of its own.
+    let mut refs = vec![];
+    for i in 0..2u8 {
+        let chunk = vec![i; 600];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        refs.push(bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| {}),
+        ).unwrap());
+    }
+    bs_p.flush().unwrap();
+    assert_eq!(bs_p.retrieve(&refs[0]).unwrap(), Some(vec![0; 600]));
+
+    let name = refs[0].persistent_ref.blob_name.clone();
+    let other = refs[1].persistent_ref.blob_name.clone();
+    let blob = backend.retrieve(&name[..]).unwrap().unwrap();
+
+    // A truncated download.
+    backend.delete(&name[..]).unwrap();
+    backend
+        .store(&name[..], &crypto::CipherText::new(blob[..blob.len() - 1].to_vec()))
+        .unwrap();
+    assert!(bs_p.retrieve(&refs[0]).is_err());
+
+    // A well-formed blob, but not the one that was uploaded under this name.
+    let swapped = backend.retrieve(&other[..]).unwrap().unwrap();
+    backend.delete(&name[..]).unwrap();
+    backend.store(&name[..], &crypto::CipherText::new(swapped.to_vec())).unwrap();
+    assert!(bs_p.retrieve(&refs[0]).is_err());
+}
+
+#[test]
+fn moved_blobs_are_found_through_their_location() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index.clone(), backend.clone(), 1024);
+
+    let chunk = vec![1; 600];
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let href = bs_p.store(
+        &chunk[..],
+        hash::Hash::new(&keys, node, leaf, &chunk[..]),
+        node,
+        leaf,
+        None,
+        Box::new(move |_| {}),
+    ).unwrap();
+    bs_p.flush().unwrap();
+
+    let name = href.persistent_ref.blob_name.clone();
+    let blob = bs_p.find(&name[..]).unwrap();
+    bs_p.relocate(&blob).unwrap();
+    bs_p.relocate(&blob).unwrap();
+
+    // Only the latest copy is left, and the unchanged reference still reads from it.
+    let second = location_name(&name[..], 2);
+    assert_eq!(blob_index.location(&name[..]), second);
+    assert_eq!(backend.list().unwrap(), vec![second.clone().into_boxed_slice()]);
+    assert_eq!(bs_p.retrieve(&href).unwrap(), Some(chunk.clone()));
+    assert_eq!(bs_p.verify(&blob).unwrap(), (1, 600));
+
+    // A rebuilt index finds the moved copy.
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let recovered = BlobStore::new(keys.clone(), blob_index.clone(), backend.clone(), 1024);
+    recovered.recover().unwrap();
+    assert_eq!(blob_index.location(&name[..]), second);
+    assert_eq!(blob_index.used_bytes(), 1024);
+    assert_eq!(recovered.retrieve(&href).unwrap(), Some(chunk));
+
+    bs_p.flush().unwrap();
+    recovered.flush();
+}
+
+#[test]
+fn location_names() {
+    let name = vec![7; 8 + crypto::sealed::desc::SEALBYTES];
+    assert!(is_blob_name(&name[..]));
+    assert_eq!(parse_location_name(&name[..]), None);
+
+    let location = location_name(&name[..], 12);
+    assert!(is_blob_name(&location[..]));
+    assert_eq!(parse_location_name(&location[..]), Some((&name[..], 12)));
+
+    let mut padded = name.clone();
+    padded.extend(b".012".iter());
+    assert_eq!(parse_location_name(&padded[..]), None);
+    padded.truncate(name.len() + 1);
+    assert_eq!(parse_location_name(&padded[..]), None);
+}
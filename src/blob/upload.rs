@@ -0,0 +1,396 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Background uploads of finished blobs.
+
+use backend::StoreBackend;
+use blob::{BlobDesc, BlobIndex, BlobSizing};
+use crypto::CipherText;
+use hex::ToHex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use time;
+use util::{FnBox, IdGenerator, TimeIds};
+
+
+/// Number of blobs that may be uploading at the same time, unless configured otherwise.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 4;
+
+/// Attempts per blob before giving up. Later attempts continue where the earlier ones stopped,
+/// if the backend supports it.
+const MAX_ATTEMPTS: usize = 3;
+
+type Callbacks = Vec<Box<FnBox<(), ()>>>;
+
+struct State {
+    max_in_flight: usize,
+    in_flight: usize,
+    next_seq: u64,
+    next_done: u64,
+    // Callbacks of finished uploads, waiting for earlier uploads to finish.
+    done: BTreeMap<u64, Callbacks>,
+    // Whether a thread is running callbacks. Only one does at a time, to keep them in order.
+    draining: bool,
+    error: Option<String>,
+    // How long to wait for an upload to finish before giving up on the backend.
+    timeout: Option<Duration>,
+}
+
+/// Uploads blobs on background threads, with a bounded number in flight.
+///
+/// The callbacks of an upload run after the blob has been stored, and after the callbacks of all
+/// earlier uploads, so the hash index sees blobs commit in the order they were written. They run
+/// without holding the state of the uploader, so they may take locks held by a thread that is
+/// waiting to start an upload.
+///
+/// Once an upload fails, later uploads are refused and the error is returned to the caller.
+///
+/// With a spool directory, each blob is kept there until its upload is committed, so that an
+/// upload interrupted by the end of the process can be finished by the next one.
+pub struct Uploader<B> {
+    backend: Arc<B>,
+    blob_index: Arc<BlobIndex>,
+    state: Arc<(Mutex<State>, Condvar)>,
+    sizing: Option<Arc<Mutex<BlobSizing>>>,
+    ids: Arc<IdGenerator>,
+    spool_dir: Option<PathBuf>,
+}
+
+impl State {
+    fn check(&self) -> Result<(), String> {
+        match self.error {
+            Some(ref e) => Err(format!("Store operation failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether uploads or their callbacks are still running.
+    fn busy(&self) -> bool {
+        self.in_flight > 0 || self.draining || !self.done.is_empty()
+    }
+
+    /// Take the callbacks that are next in order, up to the first unfinished upload.
+    fn take_ready(&mut self) -> Vec<Callbacks> {
+        let mut ready = vec![];
+        loop {
+            let next = self.next_done;
+            match self.done.remove(&next) {
+                Some(callbacks) => {
+                    ready.push(callbacks);
+                    self.next_done += 1;
+                }
+                None => return ready,
+            }
+        }
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<State> {
+    state.lock().expect("Uploader was poisoned")
+}
+
+/// Wait for a change of `guard`, failing once `deadline` has passed.
+fn wait<'a>(
+    cvar: &Condvar,
+    guard: MutexGuard<'a, State>,
+    deadline: Option<(Instant, Duration)>,
+) -> Result<MutexGuard<'a, State>, String> {
+    match deadline {
+        None => Ok(cvar.wait(guard).expect("Uploader was poisoned")),
+        Some((deadline, timeout)) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("No upload finished within {} seconds", timeout.as_secs()));
+            }
+            let (guard, _) = cvar.wait_timeout(guard, deadline - now).expect(
+                "Uploader was poisoned",
+            );
+            Ok(guard)
+        }
+    }
+}
+
+fn deadline(state: &State) -> Option<(Instant, Duration)> {
+    state.timeout.map(|timeout| (Instant::now() + timeout, timeout))
+}
+
+fn spool_path(dir: &Path, name: &[u8], upload_id: &str) -> PathBuf {
+    dir.join(format!("{}.{}", name.to_hex(), upload_id))
+}
+
+/// Write `ct` to `path` in full or not at all.
+fn write_spool(path: &Path, ct: &CipherText) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        for r in ct.slices() {
+            file.write_all(r)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+fn read_spool(path: &Path) -> io::Result<Option<CipherText>> {
+    let mut file = match fs::File::open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+        Ok(f) => f,
+    };
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    Ok(Some(CipherText::new(bytes)))
+}
+
+fn remove_spool(path: &Path) {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => warn!("Could not remove spooled blob {}: {}", path.display(), e),
+        Ok(()) => (),
+    }
+}
+
+impl<B: StoreBackend> Uploader<B> {
+    pub fn new(backend: Arc<B>, blob_index: Arc<BlobIndex>) -> Uploader<B> {
+        Uploader {
+            backend: backend,
+            blob_index: blob_index,
+            state: Arc::new((
+                Mutex::new(State {
+                    max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                    in_flight: 0,
+                    next_seq: 0,
+                    next_done: 0,
+                    done: BTreeMap::new(),
+                    draining: false,
+                    error: None,
+                    timeout: None,
+                }),
+                Condvar::new(),
+            )),
+            sizing: None,
+            ids: Arc::new(TimeIds),
+            spool_dir: None,
+        }
+    }
+
+    /// Keep blobs in `dir` while they are uploading, so that `resume_unfinished` can finish
+    /// their uploads after a restart.
+    pub fn set_spool_dir(&mut self, dir: Option<PathBuf>) -> Result<(), String> {
+        if let Some(ref dir) = dir {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        self.spool_dir = dir;
+        Ok(())
+    }
+
+    /// Name uploads with ids from `ids`, so that resumed uploads can be told apart.
+    pub fn set_ids(&mut self, ids: Arc<IdGenerator>) {
+        self.ids = ids;
+    }
+
+    /// Report the time taken by successful uploads to `sizing`. Only uploads that had the
+    /// backend to themselves are reported, as concurrent uploads slow each other down.
+    pub fn set_sizing(&mut self, sizing: Option<Arc<Mutex<BlobSizing>>>) {
+        self.sizing = sizing;
+    }
+
+    pub fn set_max_in_flight(&self, max_in_flight: usize) {
+        assert!(max_in_flight > 0);
+        lock(&self.state.0).max_in_flight = max_in_flight;
+        self.state.1.notify_all();
+    }
+
+    /// Give up waiting for uploads after `timeout`, so that a hung backend fails `upload` and
+    /// `wait` instead of blocking them forever. The uploads keep running.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        lock(&self.state.0).timeout = timeout;
+    }
+
+    /// Store `ct` as `blob` in the background. Blocks while the maximum number of uploads are
+    /// in flight, up to the timeout. Fails if an earlier upload failed.
+    pub fn upload(
+        &self,
+        blob: BlobDesc,
+        ct: CipherText,
+        callbacks: Callbacks,
+    ) -> Result<(), String> {
+        // A client that lost its id range would overwrite the blobs of the one that won it.
+        self.blob_index.check_id_range_owner(&*self.backend)?;
+        let (seq, alone) = {
+            let (ref state, ref cvar) = *self.state;
+            let mut guard = lock(state);
+            guard.check()?;
+            let deadline = deadline(&guard);
+            while guard.in_flight >= guard.max_in_flight {
+                guard = wait(cvar, guard, deadline)?;
+                guard.check()?;
+            }
+            // Only uploads that run alone tell how fast the backend is.
+            let alone = guard.in_flight == 0;
+            guard.in_flight += 1;
+            guard.next_seq += 1;
+            (guard.next_seq - 1, alone)
+        };
+
+        let upload_id = format!("{}-{}", blob.id, self.ids.next_id());
+        self.blob_index.in_air(&blob, Some(&upload_id));
+
+        let backend = self.backend.clone();
+        let blob_index = self.blob_index.clone();
+        let state = self.state.clone();
+        let sizing = self.sizing.clone();
+        let spool = self.spool_dir.as_ref().map(|dir| spool_path(dir, &blob.name[..], &upload_id));
+        thread::spawn(move || {
+            if let Some(ref spool) = spool {
+                if let Err(e) = write_spool(spool, &ct) {
+                    // The upload still goes ahead, but cannot be resumed after a restart.
+                    warn!("Could not spool blob {}: {}", spool.display(), e);
+                }
+            }
+
+            let mut res = Err(String::new());
+            let mut secs = 0.0;
+            for attempt in 0..MAX_ATTEMPTS {
+                let start = time::precise_time_ns();
+                res = backend.store_resumable(&blob.name[..], &ct, &upload_id);
+                match res {
+                    Ok(()) => {
+                        secs = (time::precise_time_ns() - start) as f64 / 1e9;
+                        break;
+                    }
+                    Err(ref e) => warn!("Upload attempt {} failed: {}", attempt + 1, e),
+                }
+            }
+            match res {
+                Ok(()) => {
+                    blob_index.commit_done(&blob, ct.len() as u64, Some(ct.authentication()))
+                }
+                Err(_) => {
+                    if let Err(e) = backend.abort_upload(&blob.name[..], &upload_id) {
+                        warn!("Could not discard partial upload: {}", e);
+                    }
+                }
+            }
+            if let Some(ref spool) = spool {
+                remove_spool(spool);
+            }
+
+            let (ref state, ref cvar) = *state;
+            let mut guard = lock(state);
+            match res {
+                Ok(()) => {
+                    // Skip uploads that shared the backend with one started after them.
+                    if let Some(ref sizing) = sizing {
+                        if alone && guard.next_seq == seq + 1 {
+                            sizing.lock().unwrap().record(ct.len(), secs);
+                        }
+                    }
+                    guard.done.insert(seq, callbacks);
+                }
+                Err(e) => {
+                    // The chunks in this blob are never committed.
+                    guard.done.insert(seq, vec![]);
+                    if guard.error.is_none() {
+                        guard.error = Some(e);
+                    }
+                }
+            }
+            guard.in_flight -= 1;
+            cvar.notify_all();
+            if guard.draining {
+                // The thread running callbacks picks these up too.
+                return;
+            }
+
+            guard.draining = true;
+            loop {
+                let ready = guard.take_ready();
+                if ready.is_empty() {
+                    break;
+                }
+                drop(guard);
+                let ran = panic::catch_unwind(AssertUnwindSafe(move || for mut callbacks in ready {
+                    while let Some(callback) = callbacks.pop() {
+                        callback.call(());
+                    }
+                }));
+                guard = lock(state);
+                if ran.is_err() && guard.error.is_none() {
+                    guard.error = Some("A commit callback panicked".to_owned());
+                }
+            }
+            guard.draining = false;
+            cvar.notify_all();
+        });
+        Ok(())
+    }
+
+    /// Finish the uploads left behind by an earlier process from their spooled blobs,
+    /// continuing where they stopped. Uploads whose blob was not spooled are discarded.
+    ///
+    /// Must run before any upload of this process starts, as it takes every unfinished upload
+    /// for its own. Returns the number of finished and discarded uploads.
+    pub fn resume_unfinished(&self) -> Result<(usize, usize), String> {
+        let es = |e: io::Error| e.to_string();
+        let (mut finished, mut discarded) = (0, 0);
+        for (blob, upload_id) in self.blob_index.list_uploads() {
+            let ct = match self.spool_dir {
+                Some(ref dir) => read_spool(&spool_path(dir, &blob.name[..], &upload_id))
+                    .map_err(&es)?,
+                None => None,
+            };
+            match ct {
+                Some(ct) => {
+                    self.backend.store_resumable(&blob.name[..], &ct, &upload_id)?;
+                    self.blob_index.commit_done(&blob, ct.len() as u64, Some(ct.authentication()));
+                    finished += 1;
+                }
+                None => {
+                    self.backend.abort_upload(&blob.name[..], &upload_id)?;
+                    self.blob_index.clear_upload(&blob);
+                    discarded += 1;
+                }
+            }
+        }
+
+        // Whatever is left was spooled for an upload that never got recorded, or was already
+        // committed when the process stopped.
+        if let Some(ref dir) = self.spool_dir {
+            for entry in fs::read_dir(dir).map_err(&es)? {
+                remove_spool(&entry.map_err(&es)?.path());
+            }
+        }
+        Ok((finished, discarded))
+    }
+
+    /// Wait for all uploads to finish and their callbacks to run, up to the timeout. Fails if
+    /// an upload failed.
+    pub fn wait(&self) -> Result<(), String> {
+        let (ref state, ref cvar) = *self.state;
+        let mut guard = lock(state);
+        let deadline = deadline(&guard);
+        while guard.busy() {
+            guard = wait(cvar, guard, deadline)?;
+        }
+        guard.check()
+    }
+}
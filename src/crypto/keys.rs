@@ -42,6 +42,22 @@ pub fn random_bytes(size: usize) -> secstr::SecStr {
     secstr::SecStr::new(r)
 }
 
+/// Check a signature made by `Keeper::sign`, given the public key it was made with.
+pub fn verify_signature(public: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+    if public.len() != 32 || signature.len() != 64 {
+        return false;
+    }
+    let ret = unsafe {
+        libsodium_sys::crypto_sign_ed25519_verify_detached(
+            signature.as_ptr() as *const [u8; 64],
+            msg.as_ptr(),
+            msg.len() as u64,
+            public.as_ptr() as *const [u8; 32],
+        )
+    };
+    ret == 0
+}
+
 pub fn keyed_fingerprint(sk: &[u8], msg: &[u8], salt: &[u8], out: &mut [u8]) {
     use libsodium_sys::{crypto_generichash_blake2b_SALTBYTES,
                         crypto_generichash_blake2b_PERSONALBYTES};
@@ -66,6 +82,28 @@ pub fn keyed_fingerprint(sk: &[u8], msg: &[u8], salt: &[u8], out: &mut [u8]) {
     assert_eq!(ret, 0);
 }
 
+/// The secret all other keys of a repository are derived from.
+pub struct MasterKey(secstr::SecStr);
+
+impl MasterKey {
+    pub fn from_passphrase(phrase: &str) -> MasterKey {
+        MasterKey(Keeper::strengthen(phrase, "hat-backup:universal-key"))
+    }
+
+    /// A random key, for repositories that are only unlocked through recipients.
+    pub fn generate() -> MasterKey {
+        MasterKey(random_bytes(64))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> MasterKey {
+        MasterKey(secstr::SecStr::new(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.unsecure()
+    }
+}
+
 pub struct Keeper {
     universal_key: secstr::SecStr,
     fingerprint_key: Option<secstr::SecStr>,
@@ -83,9 +121,12 @@ pub struct Keeper {
 
 impl Keeper {
     pub fn new(universal: &str) -> Keeper {
-        let app: &str = "hat-backup:universal-key";
+        Keeper::from_master_key(&MasterKey::from_passphrase(universal))
+    }
+
+    pub fn from_master_key(master: &MasterKey) -> Keeper {
         let mut keeper = Keeper {
-            universal_key: Keeper::strengthen(universal, app),
+            universal_key: secstr::SecStr::new(master.as_bytes().to_vec()),
             fingerprint_key: None,
             blob_authentication_key: None,
             data_key_pk: None,
@@ -188,6 +229,50 @@ impl Keeper {
         (PublicKey(pk), SecretKey(sk))
     }
 
+    /// The Ed25519 key pair for signing statements about the repository. It is derived from the
+    /// universal key, so it stays the same for the life of the repository.
+    fn signing_key_pair(&self) -> ([u8; 32], secstr::SecStr) {
+        let mut pk = [0u8; 32];
+        let mut sk = secstr::SecStr::new(vec![0; 64]);
+
+        let seed = self.from_nonce(b"attestation signing key", 32);
+
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_seed_keypair(
+                &mut pk,
+                sk.unsecure_mut().as_mut_ptr() as *mut [u8; 64],
+                seed.unsecure().as_ptr() as *const [u8; 32],
+            )
+        };
+        assert_eq!(ret, 0);
+
+        (pk, sk)
+    }
+
+    /// Sign `msg` with the signing key of the repository. Returns the public key to verify the
+    /// signature with, and the signature.
+    pub fn sign(&self, msg: &[u8]) -> ([u8; 32], Vec<u8>) {
+        let (pk, sk) = self.signing_key_pair();
+        let mut signature = [0u8; 64];
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_detached(
+                &mut signature,
+                ::std::ptr::null_mut(),
+                msg.as_ptr(),
+                msg.len() as u64,
+                sk.unsecure().as_ptr() as *const [u8; 64],
+            )
+        };
+        assert_eq!(ret, 0);
+
+        (pk, signature.to_vec())
+    }
+
+    /// The public key that signatures made by `sign` verify against.
+    pub fn signing_public_key(&self) -> [u8; 32] {
+        self.signing_key_pair().0
+    }
+
     fn asymmetric_lock(pk: &PublicKey, msg: &[u8]) -> Vec<u8> {
         let mut out = vec![0; msg.len() + libsodium_sys::crypto_box_SEALBYTES];
         let ret = unsafe {
@@ -307,6 +392,19 @@ impl Keeper {
     }
 
     pub fn symmetric_unlock(key: &[u8], ciphertext: &[u8], ad: &[u8], nonce: &[u8]) -> Vec<u8> {
+        Keeper::try_symmetric_unlock(key, ciphertext, ad, nonce).expect("Could not decrypt")
+    }
+
+    /// Like `symmetric_unlock`, but returns `None` if the ciphertext is not authentic.
+    pub fn try_symmetric_unlock(
+        key: &[u8],
+        ciphertext: &[u8],
+        ad: &[u8],
+        nonce: &[u8],
+    ) -> Option<Vec<u8>> {
+        if ciphertext.len() < libsodium_sys::crypto_aead_chacha20poly1305_ABYTES {
+            return None;
+        }
         let mut out =
             vec![0u8; ciphertext.len() - libsodium_sys::crypto_aead_chacha20poly1305_ABYTES];
         let mut out_len = 0;
@@ -324,9 +422,11 @@ impl Keeper {
                 key.as_ptr() as *const [u8; 32],
             )
         };
-        assert_eq!(0, ret);
+        if ret != 0 {
+            return None;
+        }
         assert_eq!(out_len, out.len() as u64);
 
-        out
+        Some(out)
     }
 }
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use blob::Key;
+use blob::{Key, RefFormat};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 pub use errors::CryptoError;
 use hash::tree::HashRef;
@@ -20,6 +20,8 @@ use std::io;
 use std::mem;
 
 pub mod keys;
+pub mod provider;
+pub mod recipients;
 
 pub struct PlainText(Vec<u8>);
 pub struct PlainTextRef<'a>(&'a [u8]);
@@ -257,6 +259,13 @@ impl CipherText {
         self.len = blob.len();
     }
 
+    /// The authentication tag added by `append_authentication`.
+    pub fn authentication(&self) -> &[u8] {
+        assert_eq!(self.chunks.len(), 1);
+        let blob = &self.chunks[0];
+        &blob[blob.len() - authed::hash::DIGESTBYTES..]
+    }
+
     pub fn slices(&self) -> Vec<&[u8]> {
         self.chunks.iter().map(|x| &x[..]).collect()
     }
@@ -294,12 +303,24 @@ impl<'a> CipherTextRef<'a> {
         nonce: &authed::desc::Nonce,
         key: &authed::desc::Key,
     ) -> Result<PlainText, CryptoError> {
-        Ok(PlainText::new(keys::Keeper::symmetric_unlock(
+        match keys::Keeper::try_symmetric_unlock(
             key.unsecure(),
             &self.0,
             additional_data,
             nonce.unsecure(),
-        )))
+        ) {
+            Some(pt) => Ok(PlainText::new(pt)),
+            None => Err("crypto read failed: to_plaintext".into()),
+        }
+    }
+
+    /// The authentication tag at the end of the blob, without checking it.
+    pub fn authentication(&self) -> Option<&'a [u8]> {
+        if self.0.len() < authed::hash::DIGESTBYTES {
+            None
+        } else {
+            Some(&self.0[self.0.len() - authed::hash::DIGESTBYTES..])
+        }
     }
 
     pub fn strip_authentication(&self, keys: &keys::Keeper) -> Result<CipherTextRef, CryptoError> {
@@ -335,6 +356,8 @@ impl RefKey {
         let additional_data = keys::compute_salt(href.node, href.leaf);
         let ct = pt.to_ciphertext(&additional_data, &nonce, &key);
         href.persistent_ref.length = ct.len();
+        href.persistent_ref.integrity_length = Some(pt.len() as u64);
+        href.persistent_ref.format = RefFormat::V1;
 
         ct
     }
@@ -344,7 +367,10 @@ impl RefKey {
         href: &HashRef,
         ct: CipherTextRef,
     ) -> Result<PlainText, CryptoError> {
-        assert!(ct.len() > href.persistent_ref.offset + href.persistent_ref.length);
+        match href.persistent_ref.offset.checked_add(href.persistent_ref.length) {
+            Some(end) if end < ct.len() => (),
+            _ => return Err("crypto read failed: reference out of bounds".into()),
+        }
         let ct = ct.slice(
             href.persistent_ref.offset,
             href.persistent_ref.offset + href.persistent_ref.length,
@@ -0,0 +1,257 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sources of the repository master key.
+//!
+//! Headless machines can get the key from the environment, a file, a password manager or the
+//! keyring, or have an external key management service unwrap it, instead of embedding a
+//! passphrase in scripts.
+
+use crypto::CryptoError;
+use crypto::keys::MasterKey;
+use crypto::recipients::{Identity, KeyFile};
+use hex::{FromHex, ToHex};
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+
+pub trait KeyProvider {
+    fn master_key(&self) -> Result<MasterKey, CryptoError>;
+}
+
+/// Derives the key from a fixed passphrase.
+pub struct PassphraseKey(pub String);
+
+impl KeyProvider for PassphraseKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        Ok(MasterKey::from_passphrase(&self.0))
+    }
+}
+
+/// Derives the key from a passphrase in an environment variable.
+pub struct EnvKey {
+    pub var: String,
+}
+
+impl KeyProvider for EnvKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        match env::var(&self.var) {
+            Ok(ref phrase) if !phrase.is_empty() => Ok(MasterKey::from_passphrase(phrase)),
+            _ => Err(From::from(format!("Environment variable {} is not set", self.var))),
+        }
+    }
+}
+
+/// Derives the key from the first line of a file, e.g. one only the backup user can read.
+pub struct FileKey {
+    pub path: PathBuf,
+}
+
+impl KeyProvider for FileKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        let mut contents = String::new();
+        File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("Could not read {}: {}", self.path.display(), e))?;
+        let source = self.path.display().to_string();
+        from_passphrase(contents.lines().next().unwrap_or(""), &source)
+    }
+}
+
+/// Derives the key from the passphrase printed by a command, e.g. a password manager.
+pub struct PasswordCommandKey {
+    pub command: String,
+}
+
+impl KeyProvider for PasswordCommandKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        from_passphrase(&run(&self.command, "")?, &self.command)
+    }
+}
+
+/// Derives the key from a passphrase in the desktop keyring, looked up with libsecret's
+/// `secret-tool` under the attributes `service hat account <account>`.
+pub struct KeyringKey {
+    pub account: String,
+}
+
+impl KeyProvider for KeyringKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        let output = Command::new("secret-tool")
+            .args(&["lookup", "service", "hat", "account", &self.account])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Could not run secret-tool: {}", e))?;
+        if !output.status.success() {
+            return Err(From::from(
+                format!("No passphrase in the keyring for account {}", self.account),
+            ));
+        }
+        let phrase = String::from_utf8(output.stdout)
+            .map_err(|_| "secret-tool printed invalid UTF-8")?;
+        from_passphrase(phrase.trim_right_matches('\n'), "the keyring")
+    }
+}
+
+fn from_passphrase(phrase: &str, source: &str) -> Result<MasterKey, CryptoError> {
+    if phrase.is_empty() {
+        Err(From::from(format!("Empty passphrase from {}", source)))
+    } else {
+        Ok(MasterKey::from_passphrase(phrase))
+    }
+}
+
+/// Unseals the key with the identity of one of the recipients.
+pub struct RecipientKey {
+    pub keys: KeyFile,
+    pub identity: Identity,
+}
+
+impl KeyProvider for RecipientKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        self.keys.unlock(&self.identity)
+    }
+}
+
+/// Has an external command unwrap the key, e.g. the client of a key management service.
+///
+/// The command gets the wrapped key on stdin and must print the hex-encoded key. With Vault's
+/// transit engine, the key can be wrapped with
+/// `base64 -w0 | vault write -field=ciphertext transit/encrypt/hat plaintext=-` and unwrapped
+/// with `vault write -field=plaintext transit/decrypt/hat ciphertext=- | base64 -d`.
+pub struct CommandKey {
+    pub command: String,
+    pub wrapped: String,
+}
+
+impl CommandKey {
+    /// Wrap `master` with `command`, which gets the hex-encoded key on stdin and must print
+    /// the wrapped key.
+    pub fn wrap(command: &str, master: &MasterKey) -> Result<String, CryptoError> {
+        wrap_with(master, |input| run(command, input))
+    }
+}
+
+impl KeyProvider for CommandKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        unwrap_with(&self.wrapped, |input| run(&self.command, input))
+            .map_err(|e| From::from(format!("{} ({})", e, self.command)))
+    }
+}
+
+fn wrap_with<F>(master: &MasterKey, wrap: F) -> Result<String, CryptoError>
+where
+    F: FnOnce(&str) -> Result<String, CryptoError>,
+{
+    wrap(&master.as_bytes().to_hex())
+}
+
+fn unwrap_with<F>(wrapped: &str, unwrap: F) -> Result<MasterKey, CryptoError>
+where
+    F: FnOnce(&str) -> Result<String, CryptoError>,
+{
+    let key = unwrap(wrapped)?;
+    let bytes = Vec::from_hex(key.trim())
+        .map_err(|_| "Key command did not print a hex-encoded key")?;
+    Ok(MasterKey::from_bytes(bytes))
+}
+
+/// Run `command` with the shell, feeding it `input`. Returns what it printed.
+fn run(command: &str, input: &str) -> Result<String, CryptoError> {
+    let es = &|e: ::std::io::Error| format!("Could not run {}: {}", command, e);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(es)?;
+    child.stdin.take().unwrap().write_all(input.as_bytes()).map_err(es)?;
+
+    let output = child.wait_with_output().map_err(es)?;
+    if !output.status.success() {
+        return Err(From::from(format!("{} failed: {}", command, output.status)));
+    }
+    String::from_utf8(output.stdout)
+        .map(|s| s.trim().to_owned())
+        .map_err(|_| From::from(format!("{} printed invalid UTF-8", command)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a real key service.
+    fn reverse(input: &str) -> Result<String, CryptoError> {
+        Ok(input.chars().rev().collect())
+    }
+
+    #[test]
+    fn wrapped_key_round_trip() {
+        let master = MasterKey::from_bytes(vec![7, 8, 9]);
+        let wrapped = wrap_with(&master, reverse).unwrap();
+        assert_eq!(wrapped, "908070");
+        assert_eq!(unwrap_with(&wrapped, reverse).unwrap().as_bytes(), master.as_bytes());
+
+        assert!(unwrap_with(&wrapped, |_| Ok("not hex".to_owned())).is_err());
+        assert!(unwrap_with(&wrapped, |_| Err(From::from("unavailable"))).is_err());
+    }
+
+    #[test]
+    fn command_key_runs_the_command() {
+        let master = MasterKey::from_bytes(vec![7; 64]);
+        // Only uses shell builtins, so that nothing needs to be installed.
+        let echo = "read -r key; echo \"$key\"";
+        let wrapped = CommandKey::wrap(echo, &master).unwrap();
+
+        let provider = CommandKey {
+            command: echo.to_owned(),
+            wrapped: wrapped,
+        };
+        assert_eq!(provider.master_key().unwrap().as_bytes(), master.as_bytes());
+
+        let failing = CommandKey {
+            command: "exit 1".to_owned(),
+            wrapped: String::new(),
+        };
+        assert!(failing.master_key().is_err());
+    }
+
+    #[test]
+    fn passphrase_from_file_and_command() {
+        let expected = MasterKey::from_passphrase("secret");
+
+        let path = ::std::env::temp_dir()
+            .join(format!("hat-passphrase-{}", ::time::precise_time_ns()));
+        File::create(&path).unwrap().write_all(b"secret\nignored\n").unwrap();
+        let from_file = FileKey { path: path.clone() }.master_key().unwrap();
+        assert_eq!(from_file.as_bytes(), expected.as_bytes());
+        ::std::fs::remove_file(&path).unwrap();
+
+        let command = PasswordCommandKey { command: "echo secret".to_owned() };
+        assert_eq!(command.master_key().unwrap().as_bytes(), expected.as_bytes());
+
+        let empty = PasswordCommandKey { command: "true".to_owned() };
+        assert!(empty.master_key().is_err());
+    }
+
+    #[test]
+    fn env_key_requires_variable() {
+        let provider = EnvKey { var: "HAT_TEST_UNSET_KEY_VARIABLE".to_owned() };
+        assert!(provider.master_key().is_err());
+    }
+}
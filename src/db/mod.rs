@@ -28,14 +28,20 @@ use errors::DieselError;
 
 use hash;
 use root_capnp;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::sync::{Mutex, MutexGuard};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tags;
 use time::Duration;
-use util::{Counter, InfoWriter, PeriodicTimer};
+use util::{human, Counter, InfoWriter, PeriodicTimer};
 
 mod schema;
 
+pub use self::schema::SnapshotPath;
+
 
 pub struct Index(Mutex<InternalIndex>);
 pub type IndexGuard<'a> = MutexGuard<'a, InternalIndex>;
@@ -44,6 +50,11 @@ impl Index {
     pub fn new(migrations_dir: &Path, path: &str) -> Result<Index, DieselError> {
         Ok(Index(Mutex::new(InternalIndex::new(migrations_dir, path)?)))
     }
+    /// Open the index at `path` for reading only. Reads see the state last flushed by a
+    /// writer before the first read, and stay consistent with each other after that.
+    pub fn new_read_only(path: &str) -> Result<Index, DieselError> {
+        Ok(Index(Mutex::new(InternalIndex::new_read_only(path)?)))
+    }
     pub fn lock(&self) -> MutexGuard<InternalIndex> {
         self.0.lock().expect("Database mutex is poisoned")
     }
@@ -86,6 +97,28 @@ fn decode_childs(bytes: &[u8]) -> Result<Vec<u64>, capnp::Error> {
     Ok(out)
 }
 
+fn to_queue_entry(
+    hash_: self::schema::Hash,
+    blob_: Option<self::schema::Blob>,
+) -> QueueEntry {
+    let childs_ = hash_.childs.and_then(|b| if b.is_empty() {
+        None
+    } else {
+        Some(decode_childs(&b).unwrap())
+    });
+    let persistent_ref = decode_chunk_ref(hash_.blob_ref.as_ref(), blob_);
+    QueueEntry {
+        id: hash_.id as u64,
+        node: From::from(hash_.height as u64),
+        leaf: From::from(hash_.leaf_type as u64),
+        tag: tags::tag_from_num(hash_.tag),
+        childs: childs_,
+        persistent_ref: persistent_ref,
+        epoch: hash_.epoch as u64,
+        suspect: hash_.suspect,
+    }
+}
+
 fn decode_chunk_ref(
     cref: Option<&Vec<u8>>,
     blob: Option<self::schema::Blob>,
@@ -138,6 +171,136 @@ pub struct SnapshotStatus {
     pub created: chrono::DateTime<chrono::Utc>,
     pub msg: Option<String>,
     pub status: SnapshotWorkStatus,
+    /// When this snapshot was moved to the trash, if it was.
+    pub trashed: Option<chrono::DateTime<chrono::Utc>>,
+    /// Pinned snapshots cannot be deleted or purged from the trash.
+    pub pinned: bool,
+    /// What the run creating this snapshot read and stored, if it was recorded.
+    pub stats: Option<SnapshotStats>,
+    /// Files moved since the previous snapshot, as paths from and to.
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    /// Files that changed while they were read, so their data may be inconsistent.
+    pub fuzzy_files: Vec<PathBuf>,
+    /// Commands whose output was stored in the snapshot, by name.
+    pub commands: Vec<CommandRun>,
+}
+
+/// A snapshot that recorded a path as deleted since the previous snapshot of its family.
+#[derive(Clone, Debug)]
+pub struct PathDeletion {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+}
+
+/// A path in a snapshot, as names joined with `/`, with what is known about its data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathRecord {
+    pub path: Vec<u8>,
+    pub size: Option<u64>,
+    /// Modification time in seconds since the epoch.
+    pub modified: Option<u64>,
+    pub hash: Option<Vec<u8>>,
+}
+
+/// A snapshot of a family that contained a path, or that recorded it as deleted.
+#[derive(Clone, Debug)]
+pub struct PathVersion {
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub size: Option<u64>,
+    pub modified: Option<u64>,
+    pub hash: Option<Vec<u8>>,
+    /// Whether the data differs from the previous version listed, or there is none.
+    pub changed: bool,
+    /// Whether the snapshot recorded the path as deleted, rather than containing it.
+    pub deleted: bool,
+}
+
+/// How a command whose output is stored as a file in a snapshot ended.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommandRun {
+    /// Name of the file holding the output.
+    pub name: String,
+    pub command: String,
+    /// `None` if the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// Whether the command was killed for running too long.
+    pub timed_out: bool,
+}
+
+impl CommandRun {
+    /// Whether the command finished by itself and reported success, so that its output is
+    /// complete.
+    pub fn succeeded(&self) -> bool {
+        !self.timed_out && self.exit_code == Some(0)
+    }
+}
+
+impl fmt::Display for CommandRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): ", self.name, self.command)?;
+        match (self.timed_out, self.exit_code) {
+            (true, _) => write!(f, "timed out"),
+            (false, Some(code)) => write!(f, "exit code {}", code),
+            (false, None) => write!(f, "killed by a signal"),
+        }
+    }
+}
+
+/// Totals of a single snapshot run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotStats {
+    /// File data read from the source.
+    pub bytes_read: u64,
+    /// Size of the new chunks after deduplication, compression and encryption.
+    pub bytes_uploaded: u64,
+    pub new_chunks: u64,
+    pub reused_chunks: u64,
+    pub wall_time_ms: u64,
+    /// Files whose data was read, because they were new or changed.
+    pub new_files: u64,
+    /// Files that kept their data from an earlier snapshot without being read.
+    pub unchanged_files: u64,
+    /// Files left out because they could not be read.
+    pub skipped_files: u64,
+    /// Files that changed while they were read, even after any retries.
+    pub fuzzy_files: u64,
+}
+
+impl SnapshotStats {
+    /// Average bytes read per second.
+    pub fn throughput(&self) -> u64 {
+        if self.wall_time_ms == 0 {
+            0
+        } else {
+            self.bytes_read.saturating_mul(1000) / self.wall_time_ms
+        }
+    }
+}
+
+impl fmt::Display for SnapshotStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Files: {} new, {} unchanged",
+            human::count(self.new_files),
+            human::count(self.unchanged_files)
+        )?;
+        if self.skipped_files > 0 {
+            write!(f, ", {} skipped", human::count(self.skipped_files))?;
+        }
+        if self.fuzzy_files > 0 {
+            write!(f, ", {} changed while reading", human::count(self.fuzzy_files))?;
+        }
+        write!(
+            f,
+            "; Data: {} read, {} stored after dedup+compression; Duration {}",
+            human::bytes(self.bytes_read),
+            human::bytes(self.bytes_uploaded),
+            human::duration_ms(self.wall_time_ms)
+        )
+    }
 }
 
 fn tag_to_work_status(tag: tags::Tag) -> SnapshotWorkStatus {
@@ -181,6 +344,31 @@ pub struct Entry {
     pub ready: bool,
 }
 
+/// The parts of a hash entry needed to compute reachability.
+#[derive(Clone, Debug)]
+pub struct HashNode {
+    pub id: u64,
+    pub childs: Vec<u64>,
+    pub blob_id: i64,
+    /// Length of the chunk this hash refers to, or zero if it has none.
+    pub length: u64,
+}
+
+/// A hash entry as stored, for exporting the index.
+#[derive(Clone, Debug)]
+pub struct HashRow {
+    pub id: u64,
+    pub hash: Vec<u8>,
+    pub tag: i64,
+    /// Zero for leaves.
+    pub height: u64,
+    pub leaf_type: u64,
+    pub blob_id: i64,
+    /// Length of the chunk this hash refers to, or zero if it has none.
+    pub length: u64,
+    pub childs: Vec<u64>,
+}
+
 #[derive(Clone)]
 pub struct QueueEntry {
     pub id: u64,
@@ -189,11 +377,16 @@ pub struct QueueEntry {
     pub childs: Option<Vec<u64>>,
     pub persistent_ref: Option<blob::ChunkRef>,
     pub tag: Option<tags::Tag>,
+    /// The GC epoch in which the hash was last written or reused.
+    pub epoch: u64,
+    /// Whether the stored chunk may be damaged, as a check failed to read it back.
+    pub suspect: bool,
 }
 
 pub struct InternalIndex {
     conn: SqliteConnection,
     hash_id_counter: Counter,
+    gc_epoch: u64,
     flush_timer: PeriodicTimer,
     flush_periodically: bool,
 }
@@ -202,10 +395,13 @@ pub struct InternalIndex {
 impl InternalIndex {
     fn new(migrations_dir: &Path, path: &str) -> Result<InternalIndex, DieselError> {
         let conn = SqliteConnection::establish(path)?;
+        // Readers in other processes see the last commit instead of waiting for the writer.
+        conn.execute("PRAGMA journal_mode = WAL;")?;
 
         let mut idx = InternalIndex {
             conn: conn,
             hash_id_counter: Counter::new(0),
+            gc_epoch: 0,
             flush_timer: PeriodicTimer::new(Duration::seconds(10)),
             flush_periodically: true,
         };
@@ -222,6 +418,28 @@ impl InternalIndex {
         }
 
         idx.hash_refresh_id_counter();
+        idx.gc_refresh_epoch();
+        Ok(idx)
+    }
+
+    fn new_read_only(path: &str) -> Result<InternalIndex, DieselError> {
+        let conn = SqliteConnection::establish(path)?;
+        conn.execute("PRAGMA query_only = ON;")?;
+
+        let mut idx = InternalIndex {
+            conn: conn,
+            hash_id_counter: Counter::new(0),
+            gc_epoch: 0,
+            flush_timer: PeriodicTimer::new(Duration::seconds(10)),
+            flush_periodically: true,
+        };
+        {
+            let tm = idx.conn.transaction_manager();
+            tm.begin_transaction(&idx.conn)?;
+        }
+
+        idx.hash_refresh_id_counter();
+        idx.gc_refresh_epoch();
         Ok(idx)
     }
 
@@ -237,22 +455,28 @@ impl InternalIndex {
             .optional()
             .expect("Error querying hashes");
 
-        result_opt.map(|(hash_, blob_)| {
-            let childs_ = hash_.childs.and_then(|b| if b.is_empty() {
-                None
-            } else {
-                Some(decode_childs(&b).unwrap())
-            });
-            let persistent_ref = decode_chunk_ref(hash_.blob_ref.as_ref(), blob_);
-            QueueEntry {
-                id: hash_.id as u64,
-                node: From::from(hash_.height as u64),
-                leaf: From::from(hash_.leaf_type as u64),
-                tag: tags::tag_from_num(hash_.tag),
-                childs: childs_,
-                persistent_ref: persistent_ref,
+        result_opt.map(|(hash_, blob_)| to_queue_entry(hash_, blob_))
+    }
+
+    /// Locate several hashes at once, in the order they are given.
+    pub fn hash_locate_many(&mut self, hashes_: &[hash::Hash]) -> Vec<Option<QueueEntry>> {
+        use self::schema::hashes::dsl::*;
+        use self::schema::blobs::dsl::blobs;
+
+        let mut found = HashMap::new();
+        // Stay below the limit on the number of parameters in a query.
+        for batch in hashes_.chunks(500) {
+            let wanted: Vec<Vec<u8>> = batch.iter().map(|h| h.bytes.clone()).collect();
+            let rows = hashes
+                .left_outer_join(blobs)
+                .filter(hash.eq_any(wanted))
+                .load::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
+                .expect("Error querying hashes");
+            for (hash_, blob_) in rows {
+                found.insert(hash_.hash.clone(), to_queue_entry(hash_, blob_));
             }
-        })
+        }
+        hashes_.iter().map(|h| found.get(&h.bytes).cloned()).collect()
     }
 
     pub fn hash_locate_by_id(&mut self, id_: u64) -> Option<Entry> {
@@ -294,6 +518,81 @@ impl InternalIndex {
         self.hash_id_counter = Counter::new(id_opt.unwrap_or(0));
     }
 
+    fn gc_refresh_epoch(&mut self) {
+        use self::schema::gc_epochs::dsl::*;
+        use diesel::expression::max;
+
+        let epoch_opt = gc_epochs
+            .select(max(epoch))
+            .first::<Option<i64>>(&self.conn)
+            .expect("Error selecting current GC epoch");
+
+        self.gc_epoch = epoch_opt.unwrap_or(0) as u64;
+    }
+
+    /// The GC epoch that hashes written or reused now belong to.
+    pub fn gc_epoch(&self) -> u64 {
+        self.gc_epoch
+    }
+
+    /// Start a new GC epoch and return it.
+    pub fn gc_begin_epoch(&mut self) -> u64 {
+        use self::schema::gc_epochs::dsl::*;
+
+        let new = schema::NewGcEpoch {
+            epoch: self.gc_epoch as i64 + 1,
+            started_at: Some(chrono::Utc::now().timestamp()),
+        };
+        diesel::insert(&new)
+            .into(gc_epochs)
+            .execute(&self.conn)
+            .expect("Error inserting GC epoch");
+        self.gc_epoch += 1;
+        self.gc_epoch
+    }
+
+    /// When the last garbage collection started, in seconds since the epoch.
+    pub fn gc_last_run(&self) -> Option<i64> {
+        use self::schema::gc_epochs::dsl::*;
+        use diesel::expression::max;
+
+        gc_epochs
+            .select(max(started_at))
+            .first::<Option<i64>>(&self.conn)
+            .expect("Error reading GC epochs")
+    }
+
+    /// Number of hashes that are reserved, but whose data is not committed yet.
+    pub fn hash_count_unready(&self) -> u64 {
+        use self::schema::hashes::dsl::*;
+
+        hashes
+            .filter(ready.eq(false))
+            .count()
+            .get_result::<i64>(&self.conn)
+            .expect("Error counting hashes") as u64
+    }
+
+    /// Move a hash that is reused into the current GC epoch.
+    pub fn hash_touch(&mut self, id_: u64) {
+        use self::schema::hashes::dsl::*;
+        diesel::update(hashes.find(id_ as i64))
+            .set(epoch.eq(self.gc_epoch as i64))
+            .execute(&self.conn)
+            .expect("Error updating hash epoch");
+    }
+
+    pub fn hash_epoch(&mut self, id_: u64) -> Option<u64> {
+        use self::schema::hashes::dsl::*;
+        hashes
+            .find(id_ as i64)
+            .select(epoch)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error querying hash epoch")
+            .map(|e| e as u64)
+    }
+
     pub fn hash_next_id(&mut self) -> u64 {
         self.hash_id_counter.next() as u64
     }
@@ -317,6 +616,7 @@ impl InternalIndex {
             blob_id: entry.persistent_ref.and_then(|r| r.blob_id).unwrap_or(0),
             blob_ref: blob_ref_.as_ref().map(|v| &v[..]),
             ready: false,
+            epoch: entry.epoch as i64,
         };
 
         diesel::insert(&new)
@@ -344,11 +644,74 @@ impl InternalIndex {
         };
     }
 
-    pub fn hash_delete_not_ready(&mut self) {
+    /// Resolve hash entries left reserved by an earlier run. Entries whose chunk was stored in a
+    /// committed blob, and whose children are ready, are completed. The rest are removed so
+    /// their content can be inserted again. Entries for which `in_flight` holds are left alone.
+    /// Returns the number of completed and removed entries.
+    pub fn hash_resolve_not_ready<F>(&mut self, in_flight: F) -> (u64, u64)
+    where
+        F: Fn(&Vec<u8>) -> bool,
+    {
+        use self::schema::hashes::dsl::*;
+        use self::schema::blobs::dsl::blobs;
+
+        // Children have lower ids than their parents, so they are resolved first.
+        let rows = hashes
+            .left_outer_join(blobs)
+            .filter(ready.eq(false))
+            .order(id.asc())
+            .load::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
+            .expect("Error querying non-ready hashes");
+
+        let (mut completed, mut removed) = (0, 0);
+        for (hash_, blob_) in rows {
+            if in_flight(&hash_.hash) {
+                continue;
+            }
+            let stored = hash_.blob_ref.is_some() &&
+                blob_.map_or(false, |b| b.tag == tags::Tag::Done as i32);
+            let childs_ready = match hash_.childs {
+                Some(ref b) if !b.is_empty() => {
+                    decode_childs(b).unwrap().into_iter().all(|c| {
+                        hashes
+                            .find(c as i64)
+                            .select(ready)
+                            .first::<bool>(&self.conn)
+                            .optional()
+                            .expect("Error querying hash")
+                            .unwrap_or(false)
+                    })
+                }
+                _ => true,
+            };
+            if stored && childs_ready {
+                diesel::update(hashes.find(hash_.id))
+                    .set(ready.eq(true))
+                    .execute(&self.conn)
+                    .expect("Failed to set hash ready");
+                completed += 1;
+            } else {
+                diesel::delete(hashes.find(hash_.id))
+                    .execute(&self.conn)
+                    .expect("Failed to delete non-ready hash");
+                removed += 1;
+            }
+        }
+        (completed, removed)
+    }
+
+    /// Record where the chunk of a reserved hash is stored, before the hash is ready.
+    pub fn hash_set_ref(&mut self, id_: u64, persistent_ref: &blob::ChunkRef) {
         use self::schema::hashes::dsl::*;
-        diesel::delete(hashes.filter(ready.eq(false)))
-            .execute(&self.conn)
-            .expect("Failed to delete non-ready hashes");
+        if let Some(blob_id_) = persistent_ref.blob_id {
+            diesel::update(hashes.find(id_ as i64))
+                .set((
+                    blob_id.eq(blob_id_),
+                    blob_ref.eq(&persistent_ref.as_bytes_no_name()[..]),
+                ))
+                .execute(&self.conn)
+                .expect("Failed to update hash reference");
+        }
     }
 
     pub fn hash_set_ready(&mut self, id_: u64, entry: &QueueEntry) {
@@ -377,11 +740,21 @@ impl InternalIndex {
                 height.eq(height_ as i64),
                 leaf_type.eq(leaf_type_ as i64),
                 childs.eq(childs_.as_ref().map(|v| &v[..])),
+                suspect.eq(false),
             ))
             .execute(&self.conn)
             .expect("Failed to set hash ready");
     }
 
+    /// Flag the hashes stored in the blob `blob_id_` as suspect. Returns how many there are.
+    pub fn hash_mark_suspect_in_blob(&mut self, blob_id_: i64) -> u64 {
+        use self::schema::hashes::dsl::*;
+        diesel::update(hashes.filter(blob_id.eq(blob_id_)))
+            .set(suspect.eq(true))
+            .execute(&self.conn)
+            .expect("Failed to mark hashes suspect") as u64
+    }
+
     pub fn hash_get_tag(&mut self, id_: u64) -> Option<tags::Tag> {
         use self::schema::hashes::dsl::*;
 
@@ -531,6 +904,55 @@ impl InternalIndex {
             .collect()
     }
 
+    pub fn hash_list_nodes(&mut self) -> Vec<HashNode> {
+        use self::schema::hashes::dsl::*;
+
+        hashes
+            .load::<self::schema::Hash>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|hash_| {
+                HashNode {
+                    id: hash_.id as u64,
+                    childs: hash_.childs.map_or(vec![], |p| decode_childs(&p).unwrap()),
+                    blob_id: hash_.blob_id,
+                    length: hash_.blob_ref.as_ref().map_or(0, |c| {
+                        blob::ChunkRef::from_bytes(&mut &c[..])
+                            .expect("Failed to decode chunk")
+                            .length as u64
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    pub fn hash_list_rows(&mut self) -> Vec<HashRow> {
+        use self::schema::hashes::dsl::*;
+
+        hashes
+            .order(id)
+            .load::<self::schema::Hash>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|hash_| {
+                HashRow {
+                    id: hash_.id as u64,
+                    hash: hash_.hash,
+                    tag: hash_.tag,
+                    height: hash_.height as u64,
+                    leaf_type: hash_.leaf_type as u64,
+                    blob_id: hash_.blob_id,
+                    length: hash_.blob_ref.as_ref().map_or(0, |c| {
+                        blob::ChunkRef::from_bytes(&mut &c[..])
+                            .expect("Failed to decode chunk")
+                            .length as u64
+                    }),
+                    childs: hash_.childs.map_or(vec![], |p| decode_childs(&p).unwrap()),
+                }
+            })
+            .collect()
+    }
+
     pub fn hash_delete(&mut self, id_: u64) {
         {
             use self::schema::hashes::dsl::*;
@@ -567,27 +989,33 @@ impl InternalIndex {
         tm.begin_transaction(&self.conn).unwrap();
     }
 
-    pub fn blob_next_id(&mut self) -> i64 {
+    /// The largest blob id in `[start, end)`, or `start` if there is none.
+    pub fn blob_next_id(&mut self, start: i64, end: i64) -> i64 {
         // TODO(jos): use an id_counter.
         use diesel::expression::max;
         use self::schema::blobs::dsl::*;
 
         blobs
+            .filter(id.ge(start))
+            .filter(id.lt(end))
             .select(max(id))
             .first::<Option<i64>>(&self.conn)
             .optional()
             .expect("Error querying blobs")
             .and_then(|x| x)
-            .unwrap_or(0)
+            .unwrap_or(start)
     }
 
-    pub fn blob_in_air(&mut self, blob: &blob::BlobDesc) {
+    pub fn blob_in_air(&mut self, blob: &blob::BlobDesc, upload_id_: Option<&str>) {
         use self::schema::blobs::dsl::*;
 
         let new = schema::NewBlob {
             id: blob.id,
             name: &blob.name,
             tag: tags::Tag::InProgress as i32,
+            size: 0,
+            upload_id: upload_id_,
+            checksum: None,
         };
         diesel::insert(&new)
             .into(blobs)
@@ -597,16 +1025,85 @@ impl InternalIndex {
         self.flush();
     }
 
-    pub fn blob_commit(&mut self, blob: &blob::BlobDesc) {
+    pub fn blob_commit(&mut self, blob: &blob::BlobDesc, size_: u64, checksum_: Option<&[u8]>) {
         use self::schema::blobs::dsl::*;
 
         diesel::update(blobs.find(blob.id))
-            .set(tag.eq(tags::Tag::Done as i32))
+            .set((
+                tag.eq(tags::Tag::Done as i32),
+                size.eq(size_ as i64),
+                upload_id.eq(None::<String>),
+                checksum.eq(checksum_),
+            ))
             .execute(&self.conn)
             .expect("Error updating blob");
         self.flush();
     }
 
+    /// List blobs with an unfinished upload, and the ids of those uploads.
+    pub fn blob_list_uploads(&self) -> Vec<(blob::BlobDesc, String)> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .filter(upload_id.is_not_null())
+            .load::<schema::Blob>(&self.conn)
+            .expect("Error listing blobs")
+            .into_iter()
+            .map(|blob_| {
+                (
+                    blob::BlobDesc {
+                        id: blob_.id,
+                        name: blob_.name,
+                    },
+                    blob_.upload_id.unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn blob_clear_upload(&self, blob: &blob::BlobDesc) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set(upload_id.eq(None::<String>))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+    }
+
+    /// Total size in bytes of all blobs known to the index.
+    pub fn blob_total_size(&self) -> u64 {
+        use self::schema::blobs::dsl::*;
+
+        // Summed as an integer by SQLite, as diesel sums BigInt columns as Numeric.
+        blobs
+            .select(diesel::expression::sql::<diesel::types::BigInt>("COALESCE(SUM(size), 0)"))
+            .first::<i64>(&self.conn)
+            .expect("Error reading blob sizes") as u64
+    }
+
+    /// List the id and size in bytes of every blob.
+    pub fn blob_list_sizes(&self) -> Vec<(i64, u64)> {
+        use self::schema::blobs::dsl::*;
+
+        blobs
+            .select((id, size))
+            .load::<(i64, i64)>(&self.conn)
+            .expect("Error reading blob sizes")
+            .into_iter()
+            .map(|(id_, size_)| (id_, size_ as u64))
+            .collect()
+    }
+
+    /// The size and checksum recorded when the named blob was uploaded, if known.
+    pub fn blob_checksum(&self, name_: &[u8]) -> Option<(u64, Vec<u8>)> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .filter(name.eq(name_))
+            .select((size, checksum))
+            .first::<(i64, Option<Vec<u8>>)>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+            .and_then(|(size_, checksum_)| checksum_.map(|c| (size_ as u64, c)))
+    }
+
     pub fn blob_id_from_name(&self, name_: &[u8]) -> Option<i64> {
         use self::schema::blobs::dsl::*;
         blobs
@@ -617,6 +1114,80 @@ impl InternalIndex {
             .expect("Error reading blob")
     }
 
+    /// The backend name the named blob was moved to, if it was.
+    pub fn blob_location(&self, name_: &[u8]) -> Option<Vec<u8>> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .filter(name.eq(name_))
+            .select(location)
+            .first::<Option<Vec<u8>>>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+            .and_then(|location_| location_)
+    }
+
+    pub fn blob_set_location(&mut self, blob: &blob::BlobDesc, location_: Option<&[u8]>) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set(location.eq(location_))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+        self.flush();
+    }
+
+    /// Record that the blob was downloaded and checked at `when`, in seconds since the epoch.
+    pub fn blob_set_verified(&self, blob: &blob::BlobDesc, when: i64) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set(verified_at.eq(Some(when)))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+    }
+
+    /// When a blob was last downloaded and checked, in seconds since the epoch.
+    pub fn blob_last_verified(&self) -> Option<i64> {
+        use self::schema::blobs::dsl::*;
+        use diesel::expression::max;
+
+        blobs
+            .select(max(verified_at))
+            .first::<Option<i64>>(&self.conn)
+            .expect("Error reading blobs")
+    }
+
+    /// The id of the last blob checked by an unfinished check of `subset_`.
+    pub fn check_cursor(&self, subset_: &str) -> Option<i64> {
+        use self::schema::check_cursor::dsl::*;
+        check_cursor
+            .filter(subset.eq(subset_))
+            .select(last_blob_id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading check cursor")
+    }
+
+    pub fn check_set_cursor(&mut self, subset_: &str, blob_id: Option<i64>) {
+        use self::schema::check_cursor::dsl::*;
+        match blob_id {
+            Some(blob_id) => {
+                let new = schema::NewCheckCursor {
+                    subset: subset_,
+                    last_blob_id: blob_id,
+                };
+                diesel::insert(&new)
+                    .into(check_cursor)
+                    .execute(&self.conn)
+                    .expect("Error updating check cursor");
+            }
+            None => {
+                diesel::delete(check_cursor.filter(subset.eq(subset_)))
+                    .execute(&self.conn)
+                    .expect("Error clearing check cursor");
+            }
+        }
+        self.flush();
+    }
+
     pub fn blob_set_tag(&self, tag_: tags::Tag, target: Option<&blob::BlobDesc>) {
         use self::schema::blobs::dsl::*;
         match target {
@@ -648,6 +1219,38 @@ impl InternalIndex {
         };
     }
 
+    pub fn blob_retag(&self, from: tags::Tag, to: tags::Tag) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.filter(tag.eq(from as i32)))
+            .set(tag.eq(to as i32))
+            .execute(&self.conn)
+            .expect("Error updating blob tags");
+    }
+
+    /// Move `blob` to tag `to`, if it is tagged `from`.
+    pub fn blob_retag_one(&self, blob: &blob::BlobDesc, from: tags::Tag, to: tags::Tag) {
+        use self::schema::blobs::dsl::*;
+        let matching = blobs.filter(tag.eq(from as i32));
+        if blob.id > 0 {
+            diesel::update(matching.filter(id.eq(blob.id)))
+                .set(tag.eq(to as i32))
+                .execute(&self.conn)
+                .expect("Error updating blob tag");
+        } else {
+            diesel::update(matching.filter(name.eq(&blob.name)))
+                .set(tag.eq(to as i32))
+                .execute(&self.conn)
+                .expect("Error updating blob tag");
+        }
+    }
+
+    pub fn blob_delete(&self, blob: &blob::BlobDesc) {
+        use self::schema::blobs::dsl::*;
+        diesel::delete(blobs.find(blob.id)).execute(&self.conn).expect(
+            "Error deleting blob",
+        );
+    }
+
     pub fn blob_delete_by_tag(&self, tag_: tags::Tag) {
         use self::schema::blobs::dsl::*;
         diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
@@ -701,6 +1304,43 @@ impl InternalIndex {
         ).execute(&self.conn)
             .expect("Error deleting snapshots");
         assert!(count <= 1);
+
+        diesel::delete(self::schema::snapshot_stats::table.find(info.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot stats");
+        diesel::delete(self::schema::snapshot_renames::table.filter(
+            self::schema::snapshot_renames::snapshot_id.eq(info.unique_id as i64),
+        )).execute(&self.conn)
+            .expect("Error deleting snapshot renames");
+        diesel::delete(self::schema::snapshot_fuzzy_files::table.filter(
+            self::schema::snapshot_fuzzy_files::snapshot_id.eq(info.unique_id as i64),
+        )).execute(&self.conn)
+            .expect("Error deleting snapshot fuzzy files");
+        diesel::delete(self::schema::snapshot_deletions::table.filter(
+            self::schema::snapshot_deletions::snapshot_id.eq(info.unique_id as i64),
+        )).execute(&self.conn)
+            .expect("Error deleting snapshot deletions");
+        diesel::delete(self::schema::snapshot_exclusions::table.filter(
+            self::schema::snapshot_exclusions::snapshot_id.eq(info.unique_id as i64),
+        )).execute(&self.conn)
+            .expect("Error deleting snapshot exclusions");
+        diesel::delete(self::schema::snapshot_commands::table.filter(
+            self::schema::snapshot_commands::snapshot_id.eq(info.unique_id as i64),
+        )).execute(&self.conn)
+            .expect("Error deleting snapshot commands");
+
+        // Forget paths that were only in this snapshot. Longer ranges may cover deleted
+        // snapshots; they are filtered out when searching.
+        {
+            use self::schema::snapshot_paths::dsl::*;
+            diesel::delete(
+                snapshot_paths
+                    .filter(family_id.eq(info.family_id as i64))
+                    .filter(first_snapshot.eq(info.snapshot_id as i64))
+                    .filter(last_snapshot.eq(info.snapshot_id as i64)),
+            ).execute(&self.conn)
+                .expect("Error deleting snapshot paths");
+        }
     }
 
     pub fn get_or_create_family_id(&mut self, name_: &str) -> i64 {
@@ -756,6 +1396,8 @@ impl InternalIndex {
                 msg,
                 hash,
                 hash_ref,
+                trashed_utc_datetime,
+                pinned,
             ))
             .first::<self::schema::Snapshot>(&self.conn)
             .optional()
@@ -776,7 +1418,11 @@ impl InternalIndex {
         })
     }
 
-    pub fn snapshot_reserve(&mut self, family_: String) -> SnapshotInfo {
+    pub fn snapshot_reserve(
+        &mut self,
+        family_: String,
+        created: chrono::DateTime<chrono::Utc>,
+    ) -> SnapshotInfo {
         use self::schema::snapshots::dsl::*;
 
         let family_id_ = self.get_or_create_family_id(&family_);
@@ -786,10 +1432,12 @@ impl InternalIndex {
             family_id: family_id_,
             snapshot_id: snapshot_id_,
             tag: tags::Tag::Reserved as i32,
-            utc_datetime: chrono::Utc::now().naive_utc(),
+            utc_datetime: created.naive_utc(),
             msg: None,
             hash: None,
             hash_ref: None,
+            trashed_utc_datetime: None,
+            pinned: false,
         };
 
         diesel::insert(&new)
@@ -834,7 +1482,496 @@ impl InternalIndex {
             .expect("Error updating snapshot");
     }
 
-    /// Extract latest snapshot data for family.
+    /// Move a snapshot to another family, where it becomes that family's latest snapshot.
+    pub fn snapshot_move(&mut self, snapshot_: &SnapshotInfo, family_: &str) -> SnapshotInfo {
+        use self::schema::snapshots::dsl::*;
+
+        let family_id_ = self.get_or_create_family_id(family_);
+        let snapshot_id_ = 1 + self.snapshot_latest_id(family_id_).unwrap_or(0);
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set((family_id.eq(family_id_), snapshot_id.eq(snapshot_id_)))
+            .execute(&self.conn)
+            .expect("Error moving snapshot");
+
+        SnapshotInfo {
+            unique_id: snapshot_.unique_id,
+            family_id: family_id_ as u64,
+            snapshot_id: snapshot_id_ as u64,
+        }
+    }
+
+    /// Move a snapshot to the trash, or restore it from the trash when `when` is `None`.
+    pub fn snapshot_set_trashed(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        when: Option<chrono::DateTime<chrono::Utc>>,
+    ) {
+        use self::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set(trashed_utc_datetime.eq(when.map(|t| t.naive_utc())))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    pub fn snapshot_set_pinned(&mut self, snapshot_: &SnapshotInfo, pinned_: bool) {
+        use self::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set(pinned.eq(pinned_))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    pub fn snapshot_is_pinned(&mut self, snapshot_: &SnapshotInfo) -> bool {
+        use self::schema::snapshots::dsl::*;
+
+        snapshots
+            .find(snapshot_.unique_id as i64)
+            .select(pinned)
+            .first::<bool>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot info")
+            .unwrap_or(false)
+    }
+
+    /// Record what the run that created this snapshot read and stored.
+    pub fn snapshot_set_stats(&mut self, snapshot_: &SnapshotInfo, stats: &SnapshotStats) {
+        use self::schema::snapshot_stats::dsl::*;
+
+        diesel::delete(snapshot_stats.find(snapshot_.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot stats");
+
+        let new = self::schema::SnapshotStats {
+            snapshot_id: snapshot_.unique_id as i64,
+            bytes_read: stats.bytes_read as i64,
+            bytes_uploaded: stats.bytes_uploaded as i64,
+            new_chunks: stats.new_chunks as i64,
+            reused_chunks: stats.reused_chunks as i64,
+            wall_time_ms: stats.wall_time_ms as i64,
+            new_files: stats.new_files as i64,
+            unchanged_files: stats.unchanged_files as i64,
+            skipped_files: stats.skipped_files as i64,
+            fuzzy_files: stats.fuzzy_files as i64,
+        };
+        diesel::insert(&new)
+            .into(snapshot_stats)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot stats");
+    }
+
+    pub fn snapshot_set_renames(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        renames: &[(PathBuf, PathBuf)],
+    ) {
+        use self::schema::snapshot_renames::dsl::*;
+
+        for &(ref from, ref to) in renames {
+            let new = self::schema::NewSnapshotRename {
+                snapshot_id: snapshot_.unique_id as i64,
+                from_path: from.as_os_str().as_bytes(),
+                to_path: to.as_os_str().as_bytes(),
+            };
+            diesel::insert(&new)
+                .into(snapshot_renames)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot rename");
+        }
+    }
+
+    /// Record the files that changed while they were read for this snapshot.
+    pub fn snapshot_set_fuzzy_files(&mut self, snapshot_: &SnapshotInfo, paths: &[PathBuf]) {
+        use self::schema::snapshot_fuzzy_files::dsl::*;
+
+        for p in paths {
+            let new = self::schema::NewSnapshotFuzzyFile {
+                snapshot_id: snapshot_.unique_id as i64,
+                path: p.as_os_str().as_bytes(),
+            };
+            diesel::insert(&new)
+                .into(snapshot_fuzzy_files)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot fuzzy file");
+        }
+    }
+
+    /// Record the paths deleted since the previous snapshot.
+    pub fn snapshot_set_deletions(&mut self, snapshot_: &SnapshotInfo, paths: &[PathBuf]) {
+        use self::schema::snapshot_deletions::dsl::*;
+
+        for p in paths {
+            let new = self::schema::NewSnapshotDeletion {
+                snapshot_id: snapshot_.unique_id as i64,
+                path: p.as_os_str().as_bytes(),
+            };
+            diesel::insert(&new)
+                .into(snapshot_deletions)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot deletion");
+        }
+    }
+
+    /// The paths deleted since the previous snapshot of the same family, sorted.
+    pub fn snapshot_deletions(&mut self, snapshot_: &SnapshotInfo) -> Vec<PathBuf> {
+        use self::schema::snapshot_deletions::dsl::*;
+
+        snapshot_deletions
+            .filter(snapshot_id.eq(snapshot_.unique_id as i64))
+            .select(path)
+            .order(path.asc())
+            .load::<Vec<u8>>(&self.conn)
+            .expect("Error reading snapshot deletions")
+            .into_iter()
+            .map(|p| PathBuf::from(OsString::from_vec(p)))
+            .collect()
+    }
+
+    /// Record the directories left out of the snapshot, and why.
+    pub fn snapshot_set_exclusions(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        excluded: &[(PathBuf, String)],
+    ) {
+        use self::schema::snapshot_exclusions::dsl::*;
+
+        for &(ref p, ref r) in excluded {
+            let new = self::schema::NewSnapshotExclusion {
+                snapshot_id: snapshot_.unique_id as i64,
+                path: p.as_os_str().as_bytes(),
+                reason: r,
+            };
+            diesel::insert(&new)
+                .into(snapshot_exclusions)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot exclusion");
+        }
+    }
+
+    /// The directories left out of the snapshot, sorted, with the reason they were.
+    pub fn snapshot_exclusions(&mut self, snapshot_: &SnapshotInfo) -> Vec<(PathBuf, String)> {
+        use self::schema::snapshot_exclusions::dsl::*;
+
+        snapshot_exclusions
+            .filter(snapshot_id.eq(snapshot_.unique_id as i64))
+            .select((path, reason))
+            .order(path.asc())
+            .load::<(Vec<u8>, String)>(&self.conn)
+            .expect("Error reading snapshot exclusions")
+            .into_iter()
+            .map(|(p, r)| (PathBuf::from(OsString::from_vec(p)), r))
+            .collect()
+    }
+
+    /// The snapshots that recorded `path_` as deleted, of one family if given, oldest first.
+    pub fn path_deletions(&mut self, family: Option<&str>, path_: &[u8]) -> Vec<PathDeletion> {
+        let families: HashMap<i64, String> = self::schema::family::table
+            .load::<self::schema::Family>(&self.conn)
+            .expect("Error reading families")
+            .into_iter()
+            .map(|f| (f.id, f.name))
+            .collect();
+        let ids = self::schema::snapshot_deletions::table
+            .filter(self::schema::snapshot_deletions::path.eq(path_))
+            .select(self::schema::snapshot_deletions::snapshot_id)
+            .load::<i64>(&self.conn)
+            .expect("Error reading snapshot deletions");
+
+        let mut found = vec![];
+        for id in ids {
+            let snap = self::schema::snapshots::table
+                .find(id)
+                .first::<self::schema::Snapshot>(&self.conn)
+                .optional()
+                .expect("Error reading snapshots");
+            let snap = match snap {
+                Some(snap) => snap,
+                None => continue,
+            };
+            let family_name = match families.get(&snap.family_id) {
+                Some(name) => name,
+                None => continue,
+            };
+            if family.map_or(true, |f| f == family_name) {
+                found.push(PathDeletion {
+                    family_name: family_name.clone(),
+                    snapshot_id: snap.snapshot_id as u64,
+                    created: chrono::DateTime::from_utc(snap.utc_datetime, chrono::Utc),
+                });
+            }
+        }
+        found.sort_by(|a, b| a.created.cmp(&b.created));
+        found
+    }
+
+    /// The committed snapshots of `family` that contained `path_` or recorded it as deleted,
+    /// oldest first, or `None` if there is no such family.
+    pub fn path_history(&mut self, family: &str, path_: &[u8]) -> Option<Vec<PathVersion>> {
+        let family = match self.family_id_from_name(family) {
+            Some(id) => id,
+            None => return None,
+        };
+        let snaps = self::schema::snapshots::table
+            .filter(self::schema::snapshots::family_id.eq(family))
+            .filter(self::schema::snapshots::hash.is_not_null())
+            .filter(self::schema::snapshots::trashed_utc_datetime.is_null())
+            .order(self::schema::snapshots::snapshot_id.asc())
+            .load::<self::schema::Snapshot>(&self.conn)
+            .expect("Error reading snapshots");
+        let ranges = self::schema::snapshot_paths::table
+            .filter(self::schema::snapshot_paths::family_id.eq(family))
+            .filter(self::schema::snapshot_paths::path.eq(path_))
+            .load::<self::schema::SnapshotPath>(&self.conn)
+            .expect("Error reading snapshot paths");
+        let deleted: HashSet<i64> = self::schema::snapshot_deletions::table
+            .filter(self::schema::snapshot_deletions::path.eq(path_))
+            .select(self::schema::snapshot_deletions::snapshot_id)
+            .load::<i64>(&self.conn)
+            .expect("Error reading snapshot deletions")
+            .into_iter()
+            .collect();
+
+        let mut versions: Vec<PathVersion> = vec![];
+        for snap in snaps {
+            let created = chrono::DateTime::from_utc(snap.utc_datetime, chrono::Utc);
+            let range = ranges.iter().find(|r| {
+                r.first_snapshot <= snap.snapshot_id && snap.snapshot_id <= r.last_snapshot
+            });
+            if let Some(range) = range {
+                let changed = versions.last().map_or(true, |v| v.deleted || v.hash != range.hash);
+                versions.push(PathVersion {
+                    snapshot_id: snap.snapshot_id as u64,
+                    created: created,
+                    size: range.size.map(|s| s as u64),
+                    modified: range.modified.map(|m| m as u64),
+                    hash: range.hash.clone(),
+                    changed: changed,
+                    deleted: false,
+                });
+            } else if deleted.contains(&snap.id) {
+                versions.push(PathVersion {
+                    snapshot_id: snap.snapshot_id as u64,
+                    created: created,
+                    size: None,
+                    modified: None,
+                    hash: None,
+                    changed: true,
+                    deleted: true,
+                });
+            }
+        }
+        Some(versions)
+    }
+
+    /// Record how the commands whose output is stored in this snapshot ended.
+    pub fn snapshot_set_commands(&mut self, snapshot_: &SnapshotInfo, runs: &[CommandRun]) {
+        use self::schema::snapshot_commands::dsl::*;
+
+        for run in runs {
+            let new = self::schema::NewSnapshotCommand {
+                snapshot_id: snapshot_.unique_id as i64,
+                name: &run.name,
+                command: &run.command,
+                exit_code: run.exit_code,
+                timed_out: run.timed_out,
+            };
+            diesel::insert(&new)
+                .into(snapshot_commands)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot command");
+        }
+    }
+
+    /// Record the paths in the snapshot and the hashes of their data, for finding them by name
+    /// or content later. Each path is kept as a range of the family's snapshots that contained
+    /// it with the same data, so unchanged paths cost nothing. The size and modification time
+    /// are those seen when the range started.
+    pub fn snapshot_set_paths(&mut self, snapshot_: &SnapshotInfo, paths: &[PathRecord]) {
+        use self::schema::snapshot_paths::dsl::*;
+        use diesel::expression::max;
+
+        let family = snapshot_.family_id as i64;
+        let current = snapshot_.snapshot_id as i64;
+        let previous = self::schema::snapshots::table
+            .filter(self::schema::snapshots::family_id.eq(family))
+            .filter(self::schema::snapshots::snapshot_id.lt(current))
+            .select(max(self::schema::snapshots::snapshot_id))
+            .first::<Option<i64>>(&self.conn)
+            .expect("Error reading previous snapshot id");
+
+        // Ranges ending at the previous snapshot, or at this one when resuming a commit.
+        let open_from = previous.unwrap_or(current);
+        let open: HashMap<Vec<u8>, (i64, Option<Vec<u8>>)> = snapshot_paths
+            .filter(family_id.eq(family))
+            .filter(last_snapshot.ge(open_from))
+            .select((path, first_snapshot, hash))
+            .load::<(Vec<u8>, i64, Option<Vec<u8>>)>(&self.conn)
+            .expect("Error reading snapshot paths")
+            .into_iter()
+            .map(|(p, first, h)| (p, (first, h)))
+            .collect();
+
+        diesel::update(snapshot_paths.filter(family_id.eq(family)).filter(
+            last_snapshot.ge(open_from),
+        )).set(last_snapshot.eq(current))
+            .execute(&self.conn)
+            .expect("Error extending snapshot paths");
+
+        let present: HashMap<&[u8], &Option<Vec<u8>>> =
+            paths.iter().map(|r| (&r.path[..], &r.hash)).collect();
+
+        // Close the ranges of paths that are gone or have changed.
+        for (p, &(first, ref h)) in &open {
+            if present.get(&p[..]) == Some(&h) {
+                continue;
+            }
+            let row = snapshot_paths
+                .filter(family_id.eq(family))
+                .filter(path.eq(&p[..]))
+                .filter(first_snapshot.eq(first));
+            match previous {
+                Some(prev) if first <= prev => {
+                    diesel::update(row).set(last_snapshot.eq(prev)).execute(&self.conn)
+                }
+                _ => diesel::delete(row).execute(&self.conn),
+            }.expect("Error closing snapshot path");
+        }
+
+        for record in paths {
+            let p = &record.path[..];
+            if let Some(&(_, ref open_hash)) = open.get(p) {
+                if open_hash == &record.hash {
+                    continue;
+                }
+            }
+            let new = self::schema::NewSnapshotPath {
+                family_id: family,
+                path: p,
+                name: p.iter().rposition(|&b| b == b'/').map_or(p, |i| &p[i + 1..]),
+                first_snapshot: current,
+                last_snapshot: current,
+                hash: record.hash.as_ref().map(|h| &h[..]),
+                size: record.size.map(|s| s as i64),
+                modified: record.modified.map(|m| m as i64),
+            };
+            diesel::insert(&new)
+                .into(snapshot_paths)
+                .execute(&self.conn)
+                .expect("Error inserting snapshot path");
+        }
+    }
+
+    /// The recorded paths, of one family if given, with the given name if given.
+    pub fn snapshot_paths(
+        &mut self,
+        family: Option<&str>,
+        name_: Option<&[u8]>,
+    ) -> Vec<(String, SnapshotPath)> {
+        use self::schema::snapshot_paths::dsl::*;
+
+        let families: HashMap<i64, String> = self::schema::family::table
+            .load::<self::schema::Family>(&self.conn)
+            .expect("Error reading families")
+            .into_iter()
+            .map(|f| (f.id, f.name))
+            .collect();
+        let family_filter = match family {
+            Some(family_name) => {
+                match self.family_id_from_name(family_name) {
+                    Some(id) => Some(id),
+                    None => return vec![],
+                }
+            }
+            None => None,
+        };
+
+        let rows = match (family_filter, name_) {
+            (Some(f), Some(n)) => {
+                snapshot_paths
+                    .filter(family_id.eq(f))
+                    .filter(name.eq(n))
+                    .load::<self::schema::SnapshotPath>(&self.conn)
+            }
+            (Some(f), None) => {
+                snapshot_paths
+                    .filter(family_id.eq(f))
+                    .load::<self::schema::SnapshotPath>(&self.conn)
+            }
+            (None, Some(n)) => {
+                snapshot_paths
+                    .filter(name.eq(n))
+                    .load::<self::schema::SnapshotPath>(&self.conn)
+            }
+            (None, None) => snapshot_paths.load::<self::schema::SnapshotPath>(&self.conn),
+        }.expect("Error reading snapshot paths");
+
+        rows.into_iter()
+            .filter_map(|row| families.get(&row.family_id).map(|f| (f.clone(), row)))
+            .collect()
+    }
+
+    /// The recorded paths whose data has one of `hashes`, of one family if given.
+    pub fn snapshot_paths_with_data(
+        &mut self,
+        family: Option<&str>,
+        hashes: &[Vec<u8>],
+    ) -> Vec<(String, SnapshotPath)> {
+        let mut rows = vec![];
+        for h in hashes {
+            rows.extend(
+                self::schema::snapshot_paths::table
+                    .filter(self::schema::snapshot_paths::hash.eq(&h[..]))
+                    .load::<self::schema::SnapshotPath>(&self.conn)
+                    .expect("Error reading snapshot paths"),
+            );
+        }
+        let families: HashMap<i64, String> = self::schema::family::table
+            .load::<self::schema::Family>(&self.conn)
+            .expect("Error reading families")
+            .into_iter()
+            .map(|f| (f.id, f.name))
+            .collect();
+        rows.into_iter()
+            .filter_map(|row| families.get(&row.family_id).map(|f| (f.clone(), row)))
+            .filter(|&(ref f, _)| family.map_or(true, |family| f == family))
+            .collect()
+    }
+
+    /// Remember the words in the data with these hashes.
+    pub fn text_words_add(&mut self, words: &[(Vec<u8>, String)]) {
+        for &(ref hash_, ref word_) in words {
+            let new = self::schema::NewTextWord {
+                hash: &hash_[..],
+                word: &word_[..],
+            };
+            diesel::insert(&new)
+                .into(self::schema::text_words::table)
+                .execute(&self.conn)
+                .expect("Error inserting text word");
+        }
+    }
+
+    /// The hashes of the data containing `word`.
+    pub fn text_word_hashes(&mut self, word_: &str) -> Vec<Vec<u8>> {
+        use self::schema::text_words::dsl::*;
+
+        text_words
+            .filter(word.eq(word_))
+            .select(hash)
+            .load::<Vec<u8>>(&self.conn)
+            .expect("Error reading text words")
+    }
+
+    /// Forget all remembered words. Returns how many there were.
+    pub fn text_words_purge(&mut self) -> usize {
+        diesel::delete(self::schema::text_words::table)
+            .execute(&self.conn)
+            .expect("Error deleting text words")
+    }
+
+    /// Extract latest snapshot data for family, ignoring snapshots in the trash.
     pub fn snapshot_latest(
         &mut self,
         family: &str,
@@ -845,6 +1982,7 @@ impl InternalIndex {
 
             let row_opt = snapshots
                 .filter(family_id.eq(family_id_))
+                .filter(trashed_utc_datetime.is_null())
                 .order(snapshot_id.desc())
                 .first::<self::schema::Snapshot>(&self.conn)
                 .optional()
@@ -884,6 +2022,63 @@ impl InternalIndex {
             }
         }.unwrap();
 
+        let mut stats: HashMap<i64, SnapshotStats> = self::schema::snapshot_stats::table
+            .load::<self::schema::SnapshotStats>(&self.conn)
+            .unwrap()
+            .into_iter()
+            .map(|row| {
+                (
+                    row.snapshot_id,
+                    SnapshotStats {
+                        bytes_read: row.bytes_read as u64,
+                        bytes_uploaded: row.bytes_uploaded as u64,
+                        new_chunks: row.new_chunks as u64,
+                        reused_chunks: row.reused_chunks as u64,
+                        wall_time_ms: row.wall_time_ms as u64,
+                        new_files: row.new_files as u64,
+                        unchanged_files: row.unchanged_files as u64,
+                        skipped_files: row.skipped_files as u64,
+                        fuzzy_files: row.fuzzy_files as u64,
+                    },
+                )
+            })
+            .collect();
+
+        let mut renames: HashMap<i64, Vec<(PathBuf, PathBuf)>> = HashMap::new();
+        for row in self::schema::snapshot_renames::table
+            .load::<self::schema::SnapshotRename>(&self.conn)
+            .unwrap()
+        {
+            renames.entry(row.snapshot_id).or_insert_with(Vec::new).push((
+                PathBuf::from(OsString::from_vec(row.from_path)),
+                PathBuf::from(OsString::from_vec(row.to_path)),
+            ));
+        }
+
+        let mut fuzzy_files: HashMap<i64, Vec<PathBuf>> = HashMap::new();
+        for row in self::schema::snapshot_fuzzy_files::table
+            .load::<self::schema::SnapshotFuzzyFile>(&self.conn)
+            .unwrap()
+        {
+            fuzzy_files.entry(row.snapshot_id).or_insert_with(Vec::new).push(
+                PathBuf::from(OsString::from_vec(row.path)),
+            );
+        }
+
+        let mut commands: HashMap<i64, Vec<CommandRun>> = HashMap::new();
+        for row in self::schema::snapshot_commands::table
+            .order(self::schema::snapshot_commands::name)
+            .load::<self::schema::SnapshotCommand>(&self.conn)
+            .unwrap()
+        {
+            commands.entry(row.snapshot_id).or_insert_with(Vec::new).push(CommandRun {
+                name: row.name,
+                command: row.command,
+                exit_code: row.exit_code,
+                timed_out: row.timed_out,
+            });
+        }
+
         rows.into_iter()
             .map(|(snap, fam)| {
                 let status = tags::tag_from_num(snap.tag as i64).map_or(
@@ -903,6 +2098,14 @@ impl InternalIndex {
                     hash: hash_,
                     hash_ref: snap.hash_ref,
                     status: status,
+                    trashed: snap.trashed_utc_datetime.map(|t| {
+                        chrono::DateTime::from_utc(t, chrono::Utc)
+                    }),
+                    pinned: snap.pinned,
+                    stats: stats.remove(&snap.id),
+                    renames: renames.remove(&snap.id).unwrap_or_else(Vec::new),
+                    fuzzy_files: fuzzy_files.remove(&snap.id).unwrap_or_else(Vec::new),
+                    commands: commands.remove(&snap.id).unwrap_or_else(Vec::new),
                     info: SnapshotInfo {
                         unique_id: snap.id as u64,
                         snapshot_id: snap.snapshot_id as u64,
@@ -945,6 +2148,8 @@ impl InternalIndex {
                 hash: Some(&hash_ref_.hash.bytes[..]),
                 hash_ref: Some(&hash_ref_bytes[..]),
                 tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
+                trashed_utc_datetime: None,
+                pinned: false,
             };
 
             diesel::insert(&new)
@@ -28,6 +28,8 @@ table! {
         blob_id -> BigInt,
         blob_ref -> Nullable<Binary>,
         ready -> Bool,
+        epoch -> BigInt,
+        suspect -> Bool,
     }
 }
 
@@ -46,6 +48,11 @@ table! {
         id -> BigInt,
         name -> Binary,
         tag -> Integer,
+        size -> BigInt,
+        upload_id -> Nullable<VarChar>,
+        checksum -> Nullable<Binary>,
+        verified_at -> Nullable<BigInt>,
+        location -> Nullable<Binary>,
     }
 }
 
@@ -66,6 +73,97 @@ table! {
         msg -> Nullable<VarChar>,
         hash -> Nullable<Binary>,
         hash_ref -> Nullable<Binary>,
+        trashed_utc_datetime -> Nullable<Timestamp>,
+        pinned -> Bool,
+    }
+}
+
+table! {
+    snapshot_stats (snapshot_id) {
+        snapshot_id -> BigInt,
+        bytes_read -> BigInt,
+        bytes_uploaded -> BigInt,
+        new_chunks -> BigInt,
+        reused_chunks -> BigInt,
+        wall_time_ms -> BigInt,
+        new_files -> BigInt,
+        unchanged_files -> BigInt,
+        skipped_files -> BigInt,
+        fuzzy_files -> BigInt,
+    }
+}
+
+table! {
+    snapshot_renames (snapshot_id, to_path) {
+        snapshot_id -> BigInt,
+        from_path -> Binary,
+        to_path -> Binary,
+    }
+}
+
+table! {
+    snapshot_fuzzy_files (snapshot_id, path) {
+        snapshot_id -> BigInt,
+        path -> Binary,
+    }
+}
+
+table! {
+    snapshot_deletions (snapshot_id, path) {
+        snapshot_id -> BigInt,
+        path -> Binary,
+    }
+}
+
+table! {
+    snapshot_exclusions (snapshot_id, path) {
+        snapshot_id -> BigInt,
+        path -> Binary,
+        reason -> Text,
+    }
+}
+
+table! {
+    snapshot_commands (snapshot_id, name) {
+        snapshot_id -> BigInt,
+        name -> Text,
+        command -> Text,
+        exit_code -> Nullable<Integer>,
+        timed_out -> Bool,
+    }
+}
+
+table! {
+    snapshot_paths (family_id, path, first_snapshot) {
+        family_id -> BigInt,
+        path -> Binary,
+        name -> Binary,
+        first_snapshot -> BigInt,
+        last_snapshot -> BigInt,
+        hash -> Nullable<Binary>,
+        size -> Nullable<BigInt>,
+        modified -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    text_words (word, hash) {
+        hash -> Binary,
+        word -> Text,
+    }
+}
+
+table! {
+    check_cursor (subset) {
+        subset -> Text,
+        last_blob_id -> BigInt,
+    }
+}
+
+table! {
+    gc_epochs (epoch) {
+        epoch -> BigInt,
+        started_at -> Nullable<BigInt>,
     }
 }
 
@@ -85,6 +183,8 @@ pub struct Hash {
     pub blob_id: i64,
     pub blob_ref: Option<Vec<u8>>,
     pub ready: bool,
+    pub epoch: i64,
+    pub suspect: bool,
 }
 
 #[derive(Insertable)]
@@ -99,6 +199,7 @@ pub struct NewHash<'a> {
     pub blob_id: i64,
     pub blob_ref: Option<&'a [u8]>,
     pub ready: bool,
+    pub epoch: i64,
 }
 
 #[derive(Queryable)]
@@ -124,6 +225,11 @@ pub struct Blob {
     pub id: i64,
     pub name: Vec<u8>,
     pub tag: i32,
+    pub size: i64,
+    pub upload_id: Option<String>,
+    pub checksum: Option<Vec<u8>>,
+    pub verified_at: Option<i64>,
+    pub location: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
@@ -132,6 +238,9 @@ pub struct NewBlob<'a> {
     pub id: i64,
     pub name: &'a [u8],
     pub tag: i32,
+    pub size: i64,
+    pub upload_id: Option<&'a str>,
+    pub checksum: Option<&'a [u8]>,
 }
 
 #[derive(Queryable)]
@@ -157,6 +266,8 @@ pub struct Snapshot {
     pub msg: Option<String>,
     pub hash: Option<Vec<u8>>,
     pub hash_ref: Option<Vec<u8>>,
+    pub trashed_utc_datetime: Option<chrono::NaiveDateTime>,
+    pub pinned: bool,
 }
 
 #[derive(Insertable)]
@@ -169,4 +280,129 @@ pub struct NewSnapshot<'a> {
     pub msg: Option<&'a str>,
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
+    pub trashed_utc_datetime: Option<chrono::NaiveDateTime>,
+    pub pinned: bool,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name = "snapshot_stats"]
+pub struct SnapshotStats {
+    pub snapshot_id: i64,
+    pub bytes_read: i64,
+    pub bytes_uploaded: i64,
+    pub new_chunks: i64,
+    pub reused_chunks: i64,
+    pub wall_time_ms: i64,
+    pub new_files: i64,
+    pub unchanged_files: i64,
+    pub skipped_files: i64,
+    pub fuzzy_files: i64,
+}
+
+#[derive(Queryable)]
+pub struct SnapshotRename {
+    pub snapshot_id: i64,
+    pub from_path: Vec<u8>,
+    pub to_path: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_renames"]
+pub struct NewSnapshotRename<'a> {
+    pub snapshot_id: i64,
+    pub from_path: &'a [u8],
+    pub to_path: &'a [u8],
+}
+
+#[derive(Queryable)]
+pub struct SnapshotFuzzyFile {
+    pub snapshot_id: i64,
+    pub path: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_fuzzy_files"]
+pub struct NewSnapshotFuzzyFile<'a> {
+    pub snapshot_id: i64,
+    pub path: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_deletions"]
+pub struct NewSnapshotDeletion<'a> {
+    pub snapshot_id: i64,
+    pub path: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_exclusions"]
+pub struct NewSnapshotExclusion<'a> {
+    pub snapshot_id: i64,
+    pub path: &'a [u8],
+    pub reason: &'a str,
+}
+
+#[derive(Queryable)]
+pub struct SnapshotCommand {
+    pub snapshot_id: i64,
+    pub name: String,
+    pub command: String,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_commands"]
+pub struct NewSnapshotCommand<'a> {
+    pub snapshot_id: i64,
+    pub name: &'a str,
+    pub command: &'a str,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+#[derive(Queryable)]
+pub struct SnapshotPath {
+    pub family_id: i64,
+    pub path: Vec<u8>,
+    pub name: Vec<u8>,
+    pub first_snapshot: i64,
+    pub last_snapshot: i64,
+    pub hash: Option<Vec<u8>>,
+    pub size: Option<i64>,
+    pub modified: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_paths"]
+pub struct NewSnapshotPath<'a> {
+    pub family_id: i64,
+    pub path: &'a [u8],
+    pub name: &'a [u8],
+    pub first_snapshot: i64,
+    pub last_snapshot: i64,
+    pub hash: Option<&'a [u8]>,
+    pub size: Option<i64>,
+    pub modified: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[table_name = "text_words"]
+pub struct NewTextWord<'a> {
+    pub hash: &'a [u8],
+    pub word: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "check_cursor"]
+pub struct NewCheckCursor<'a> {
+    pub subset: &'a str,
+    pub last_blob_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "gc_epochs"]
+pub struct NewGcEpoch {
+    pub epoch: i64,
+    pub started_at: Option<i64>,
 }
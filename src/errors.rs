@@ -14,10 +14,80 @@
 
 pub use self::crypto_error::CryptoError;
 pub use self::diesel_error::DieselError;
-
 pub use self::hat_error::HatError;
+pub use self::kind_errors::{LockError, SourceError, VerificationError};
+use blob;
+use key;
 use std::{error, fmt};
 
+/// Broad classes of errors, for callers that need to tell them apart.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The files being backed up could not be read.
+    Source,
+    /// The repository or its local state could not be read or written.
+    Repository,
+    /// Another process holds the repository.
+    Locked,
+    /// Stored data failed verification.
+    Verification,
+}
+
+/// Process exit codes. The values are stable, so that scripts can rely on them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    Success = 0,
+    /// A usage error, or a failure that fits no other code.
+    Failure = 1,
+    /// The command finished, but left something undone, such as files it could not read.
+    Warnings = 2,
+    Source = 3,
+    Repository = 4,
+    Locked = 5,
+    Verification = 6,
+}
+
+impl ExitCode {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// The code for a run that ended in both `self` and `other`. Failures win over warnings,
+    /// and earlier failures over later ones.
+    pub fn combine(self, other: ExitCode) -> ExitCode {
+        match self {
+            ExitCode::Success | ExitCode::Warnings if other != ExitCode::Success => other,
+            _ => self,
+        }
+    }
+}
+
+impl From<ErrorKind> for ExitCode {
+    fn from(kind: ErrorKind) -> ExitCode {
+        match kind {
+            ErrorKind::Source => ExitCode::Source,
+            ErrorKind::Repository => ExitCode::Repository,
+            ErrorKind::Locked => ExitCode::Locked,
+            ErrorKind::Verification => ExitCode::Verification,
+        }
+    }
+}
+
+impl HatError {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            HatError::Source(_) => ErrorKind::Source,
+            HatError::Locked(_) => ErrorKind::Locked,
+            HatError::Verification(_) |
+            HatError::Blob(blob::BlobError::Verification(_)) |
+            HatError::Keys(key::MsgError::Blob(blob::BlobError::Verification(_))) => {
+                ErrorKind::Verification
+            }
+            _ => ErrorKind::Repository,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct RetryError;
 
@@ -72,6 +142,15 @@ mod hat_error {
             Blob(blob::BlobError) {
                 cause;
             },
+            Source(super::SourceError) {
+                cause;
+            },
+            Locked(super::LockError) {
+                cause;
+            },
+            Verification(super::VerificationError) {
+                cause;
+            },
         }
     }
 
@@ -119,3 +198,41 @@ mod crypto_error {
         }
     }
 }
+
+mod kind_errors {
+    use std::str;
+    use std::borrow::Cow;
+
+    error_type! {
+        #[derive(Debug)]
+        pub enum SourceError {
+            Message(Cow<'static, str>) {
+                desc (e) &**e;
+                from (s: &'static str) s.into();
+                from (s: String) s.into();
+            },
+        }
+    }
+
+    error_type! {
+        #[derive(Debug)]
+        pub enum LockError {
+            Message(Cow<'static, str>) {
+                desc (e) &**e;
+                from (s: &'static str) s.into();
+                from (s: String) s.into();
+            },
+        }
+    }
+
+    error_type! {
+        #[derive(Debug)]
+        pub enum VerificationError {
+            Message(Cow<'static, str>) {
+                desc (e) &**e;
+                from (s: &'static str) s.into();
+                from (s: String) s.into();
+            },
+        }
+    }
+}
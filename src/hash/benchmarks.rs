@@ -14,9 +14,13 @@
 
 use hash::tests::MemoryBackend;
 
-use blob::LeafType;
+use blob::{LeafType, NodeType};
+use crypto;
+use hash::Hash;
 use hash::tree::*;
+use key;
 use test::Bencher;
+use util::corpus::{self, Corpus};
 
 #[bench]
 fn append_unknown_16x128_kb(bench: &mut Bencher) {
@@ -48,3 +52,29 @@ fn append_known_16x128_kb(bench: &mut Bencher) {
 
     bench.bytes = 128 * 1024 * 16;
 }
+
+fn fingerprint_chunks(bench: &mut Bencher, corpus: Corpus, chunk_len: usize) {
+    let keys = crypto::keys::Keeper::new_for_testing();
+    let bytes = corpus::generate(corpus, 4 * 1024 * 1024);
+
+    bench.iter(|| for chunk in bytes.chunks(chunk_len) {
+        Hash::new(&keys, NodeType::Leaf, LeafType::FileChunk, chunk);
+    });
+
+    bench.bytes = bytes.len() as u64;
+}
+
+#[bench]
+fn fingerprint_4_mb_text(bench: &mut Bencher) {
+    fingerprint_chunks(bench, Corpus::Text, key::DEFAULT_CHUNK_LEN);
+}
+
+#[bench]
+fn fingerprint_4_mb_random(bench: &mut Bencher) {
+    fingerprint_chunks(bench, Corpus::Random, key::DEFAULT_CHUNK_LEN);
+}
+
+#[bench]
+fn fingerprint_4_mb_random_fixed_blocks(bench: &mut Bencher) {
+    fingerprint_chunks(bench, Corpus::Random, key::FIXED_BLOCK_LEN);
+}
@@ -0,0 +1,356 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Cache of decoded hash tree nodes, for operations that walk the same trees repeatedly.
+
+use crypto::keys;
+use hash::Hash;
+use hash::tree::HashRef;
+use hex::ToHex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+
+/// Memory used by the cache of a repository, unless configured otherwise.
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+const CHECKSUM_BYTES: usize = 32;
+const CHECKSUM_SALT: &'static [u8] = b"hat:node-cache~~";
+
+/// A decoded tree node: the child references of a branch, or the data of a metadata leaf.
+#[derive(Debug)]
+pub enum CachedNode {
+    Branch(Vec<HashRef>),
+    Leaf(Vec<u8>),
+}
+
+impl CachedNode {
+    /// Approximate memory use.
+    fn size(&self) -> usize {
+        match *self {
+            CachedNode::Branch(ref refs) => {
+                refs.iter()
+                    .map(|r| {
+                        mem::size_of::<HashRef>() + r.hash.bytes.len() +
+                            r.persistent_ref.blob_name.len()
+                    })
+                    .sum()
+            }
+            CachedNode::Leaf(ref data) => data.len(),
+        }
+    }
+
+    /// The data of a leaf node. Taken without copying when nothing else holds the node, as for
+    /// file chunks, which are never cached.
+    pub fn into_leaf(node: Arc<CachedNode>) -> Vec<u8> {
+        match Arc::try_unwrap(node) {
+            Ok(CachedNode::Leaf(data)) => data,
+            Err(shared) => {
+                match *shared {
+                    CachedNode::Leaf(ref data) => data.clone(),
+                    CachedNode::Branch(..) => unreachable!("Leaf decoded as branch"),
+                }
+            }
+            Ok(CachedNode::Branch(..)) => unreachable!("Leaf decoded as branch"),
+        }
+    }
+
+    /// The child references of a branch node, copied only if the node is shared.
+    pub fn into_branch(node: Arc<CachedNode>) -> Vec<HashRef> {
+        match Arc::try_unwrap(node) {
+            Ok(CachedNode::Branch(childs)) => childs,
+            Err(shared) => {
+                match *shared {
+                    CachedNode::Branch(ref childs) => childs.clone(),
+                    CachedNode::Leaf(..) => unreachable!("Branch decoded as leaf"),
+                }
+            }
+            Ok(CachedNode::Leaf(..)) => unreachable!("Branch decoded as leaf"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lookups = self.hits + self.misses;
+        let rate = if lookups == 0 {
+            0.0
+        } else {
+            100.0 * self.hits as f64 / lookups as f64
+        };
+        write!(
+            f,
+            "{} hits, {} misses ({:.1}% hit rate), {} nodes in {} bytes",
+            self.hits,
+            self.misses,
+            rate,
+            self.entries,
+            self.bytes
+        )
+    }
+}
+
+struct Inner {
+    entries: HashMap<Hash, (Arc<CachedNode>, u64)>,
+    // Least recently used first.
+    order: BTreeMap<u64, Hash>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+/// Least-recently-used cache of tree nodes keyed by hash, bounded in memory, and optionally
+/// backed by a directory holding the encoded nodes.
+///
+/// Nodes are addressed by their keyed hash, so a cached node is never stale.
+pub struct NodeCache {
+    max_bytes: usize,
+    disk_dir: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+impl NodeCache {
+    pub fn new(max_bytes: usize) -> NodeCache {
+        NodeCache {
+            max_bytes: max_bytes,
+            disk_dir: None,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// Also keep encoded nodes in `dir`, so they survive the process.
+    ///
+    /// The entries are not encrypted: the directory holds plaintext metadata, like file names
+    /// and sizes, and must be protected like the local index. Each entry carries a checksum
+    /// over the node hash and its encoding, so a damaged or misplaced entry reads as a miss.
+    /// This guards against corruption, not against someone who can write to the directory.
+    pub fn with_disk_dir(mut self, dir: PathBuf) -> Result<NodeCache, String> {
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        self.disk_dir = Some(dir);
+        Ok(self)
+    }
+
+    /// Look up a node in memory, counting a hit or a miss.
+    pub fn get(&self, hash: &Hash) -> Option<Arc<CachedNode>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let found = match inner.entries.get_mut(hash) {
+            Some(entry) => {
+                let last_used = entry.1;
+                entry.1 = tick;
+                Some((entry.0.clone(), last_used))
+            }
+            None => None,
+        };
+        let (node, last_used) = match found {
+            Some(found) => found,
+            None => {
+                inner.stats.misses += 1;
+                return None;
+            }
+        };
+        inner.order.remove(&last_used);
+        inner.order.insert(tick, hash.clone());
+        inner.stats.hits += 1;
+        Some(node)
+    }
+
+    /// The encoded node from the disk cache, if there is one. Entries that fail their checksum
+    /// are removed.
+    pub fn load(&self, hash: &Hash) -> Option<Vec<u8>> {
+        let path = match self.disk_dir {
+            Some(ref dir) => dir.join(hash.bytes.to_hex()),
+            None => return None,
+        };
+        let mut data = vec![];
+        if fs::File::open(&path).and_then(|mut f| f.read_to_end(&mut data)).is_err() {
+            return None;
+        }
+        if data.len() < CHECKSUM_BYTES ||
+            data[..CHECKSUM_BYTES] != checksum(hash, &data[CHECKSUM_BYTES..])[..]
+        {
+            warn!("Dropping damaged node cache entry {}", path.display());
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        Some(data.split_off(CHECKSUM_BYTES))
+    }
+
+    /// Keep the encoded node in the disk cache, if there is one.
+    pub fn save(&self, hash: &Hash, encoded: &[u8]) {
+        if let Some(ref dir) = self.disk_dir {
+            let path = dir.join(hash.bytes.to_hex());
+            let tmp = dir.join(format!("{}.tmp", hash.bytes.to_hex()));
+            let res = fs::File::create(&tmp)
+                .and_then(|mut f| {
+                    f.write_all(&checksum(hash, encoded)[..])?;
+                    f.write_all(encoded)
+                })
+                .and_then(|()| fs::rename(&tmp, &path));
+            if let Err(e) = res {
+                warn!("Could not write to the node cache: {}", e);
+            }
+        }
+    }
+
+    /// Cache a decoded node, and its encoding on disk if given, evicting the least recently
+    /// used nodes to stay within the memory limit.
+    pub fn insert(&self, hash: &Hash, node: Arc<CachedNode>, encoded: Option<&[u8]>) {
+        if let Some(data) = encoded {
+            self.save(hash, data);
+        }
+
+        let size = node.size();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((old, last_used)) = inner.entries.insert(hash.clone(), (node, tick)) {
+            inner.order.remove(&last_used);
+            inner.stats.bytes -= old.size();
+            inner.stats.entries -= 1;
+        }
+        inner.order.insert(tick, hash.clone());
+        inner.stats.bytes += size;
+        inner.stats.entries += 1;
+
+        while inner.stats.bytes > self.max_bytes {
+            let oldest = match inner.order.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let evicted = inner.order.remove(&oldest).unwrap();
+            if let Some((node, _)) = inner.entries.remove(&evicted) {
+                inner.stats.bytes -= node.size();
+                inner.stats.entries -= 1;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+fn checksum(hash: &Hash, encoded: &[u8]) -> [u8; CHECKSUM_BYTES] {
+    let mut msg = Vec::with_capacity(hash.bytes.len() + encoded.len());
+    msg.extend_from_slice(&hash.bytes[..]);
+    msg.extend_from_slice(encoded);
+    let mut out = [0; CHECKSUM_BYTES];
+    keys::keyed_fingerprint(&[], &msg[..], CHECKSUM_SALT, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u8) -> Hash {
+        Hash { bytes: vec![n; 32] }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = NodeCache::new(300);
+        for n in 0..3 {
+            cache.insert(&hash(n), Arc::new(CachedNode::Leaf(vec![n; 100])), None);
+        }
+        assert!(cache.get(&hash(0)).is_some());
+
+        // Node 1 is now the least recently used.
+        cache.insert(&hash(3), Arc::new(CachedNode::Leaf(vec![3; 100])), None);
+        assert!(cache.get(&hash(1)).is_none());
+        assert!(cache.get(&hash(0)).is_some());
+        assert!(cache.get(&hash(3)).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+        assert_eq!((stats.entries, stats.bytes), (3, 300));
+    }
+
+    #[test]
+    fn unshared_nodes_are_not_copied() {
+        let data = vec![1; 100];
+        let ptr = data.as_ptr();
+        assert_eq!(ptr, CachedNode::into_leaf(Arc::new(CachedNode::Leaf(data))).as_ptr());
+
+        // A node that is also held by the cache is copied.
+        let cache = NodeCache::new(300);
+        cache.insert(&hash(1), Arc::new(CachedNode::Leaf(vec![1; 100])), None);
+        let cached = cache.get(&hash(1)).unwrap();
+        let ptr = match *cached {
+            CachedNode::Leaf(ref data) => data.as_ptr(),
+            CachedNode::Branch(..) => unreachable!(),
+        };
+        assert!(ptr != CachedNode::into_leaf(cached).as_ptr());
+    }
+
+    #[test]
+    fn disk_cache_survives() {
+        let nanos = ::time::precise_time_ns();
+        let dir = ::std::env::temp_dir().join(format!("hat-node-cache-{}", nanos));
+        let cache = NodeCache::new(0).with_disk_dir(dir.clone()).unwrap();
+        cache.insert(&hash(1), Arc::new(CachedNode::Leaf(vec![1])), Some(b"encoded"));
+        assert!(cache.get(&hash(1)).is_none());
+
+        let cache = NodeCache::new(0).with_disk_dir(dir.clone()).unwrap();
+        assert_eq!(cache.load(&hash(1)), Some(b"encoded".to_vec()));
+        assert_eq!(cache.load(&hash(2)), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_disk_entries_are_misses() {
+        let nanos = ::time::precise_time_ns();
+        let dir = ::std::env::temp_dir().join(format!("hat-node-cache-damaged-{}", nanos));
+        let cache = NodeCache::new(0).with_disk_dir(dir.clone()).unwrap();
+        cache.save(&hash(1), b"encoded");
+        cache.save(&hash(2), b"encoded");
+
+        // Flip a bit of one entry, and file the other under a different hash.
+        let path = dir.join(hash(1).bytes.to_hex());
+        let mut data = vec![];
+        fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::File::create(&path).unwrap().write_all(&data[..]).unwrap();
+        fs::rename(dir.join(hash(2).bytes.to_hex()), dir.join(hash(3).bytes.to_hex())).unwrap();
+
+        assert_eq!(cache.load(&hash(1)), None);
+        assert!(!path.exists());
+        assert_eq!(cache.load(&hash(3)), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
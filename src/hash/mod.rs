@@ -21,10 +21,14 @@ use db;
 
 use errors::{DieselError, RetryError};
 
+use std::cmp;
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tags;
 use util::UniquePriorityQueue;
 
+pub mod cache;
 pub mod tree;
 
 #[cfg(test)]
@@ -45,6 +49,10 @@ pub struct Hash {
 
 impl Hash {
     /// Computes `hash(text)` and stores this digest as the `bytes` field in a new `Hash` structure.
+    ///
+    /// The digest is keyed with the fingerprint key of the repository, so someone without the
+    /// master key cannot tell whether a known chunk is stored. This only protects repositories
+    /// whose master key is secret, i.e. not derived from the built-in passphrase.
     pub fn new(
         keys: &crypto::keys::Keeper,
         nodetype: blob::NodeType,
@@ -88,9 +96,35 @@ pub enum ReserveResult {
 
 type Queue = UniquePriorityQueue<u64, Vec<u8>, db::QueueEntry>;
 
+/// Keeps the garbage collector from deleting hashes that were written or reused in the GC
+/// epoch the pin was taken in, or later, until it is dropped.
+///
+/// Hashes only become reachable for the GC once the snapshot using them is registered, so a
+/// backup holds a pin from when it starts until its snapshot is.
+pub struct EpochPin {
+    pins: Arc<Mutex<Vec<u64>>>,
+    epoch: u64,
+}
+
+impl Drop for EpochPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().expect("Epoch pins mutex poisoned");
+        if let Some(i) = pins.iter().position(|&e| e == self.epoch) {
+            pins.swap_remove(i);
+        }
+    }
+}
+
 pub struct InternalHashIndex {
     index: Arc<db::Index>,
     queue: Mutex<Queue>,
+    pins: Arc<Mutex<Vec<u64>>>,
+    // Read-only indexes leave the epochs of the hashes they find alone.
+    read_only: bool,
+    // Whether suspect hashes are stored again instead of being reused.
+    rewrite_suspect: AtomicBool,
+    // Reserved hashes of earlier runs that were completed and removed on opening the index.
+    resolved_on_open: (u64, u64),
 }
 
 impl Drop for InternalHashIndex {
@@ -106,6 +140,10 @@ impl InternalHashIndex {
         Ok(InternalHashIndex {
             index: index,
             queue: Mutex::new(UniquePriorityQueue::new()),
+            pins: Arc::new(Mutex::new(vec![])),
+            read_only: false,
+            rewrite_suspect: AtomicBool::new(false),
+            resolved_on_open: (0, 0),
         })
     }
 
@@ -124,9 +162,74 @@ impl InternalHashIndex {
         hash: &Hash,
         queue: &MutexGuard<Queue>,
         index: &mut db::IndexGuard,
+    ) -> Option<db::QueueEntry> {
+        self.locate_stored(hash, queue, index).and_then(|entry| self.reusable(entry))
+    }
+
+    /// Like `locate`, but also find the hashes that are not to be reused.
+    fn locate_stored(
+        &self,
+        hash: &Hash,
+        queue: &MutexGuard<Queue>,
+        index: &mut db::IndexGuard,
     ) -> Option<db::QueueEntry> {
         let result_opt = queue.find_value_of_key(&hash.bytes).cloned();
-        result_opt.or_else(|| index.hash_locate(hash))
+        result_opt.or_else(|| {
+            index.hash_locate(hash).map(|entry| self.touch(entry, index))
+        })
+    }
+
+    /// Whether `entry` must not be reused, as its chunk may be damaged and is to be stored again.
+    fn avoids(&self, entry: &db::QueueEntry) -> bool {
+        entry.suspect && self.rewrite_suspect.load(Ordering::SeqCst)
+    }
+
+    fn reusable(&self, entry: db::QueueEntry) -> Option<db::QueueEntry> {
+        if self.avoids(&entry) {
+            None
+        } else {
+            Some(entry)
+        }
+    }
+
+    /// Move a stored hash that was found into the current GC epoch, as it may be about to be
+    /// reused by a backup that the GC does not know about yet.
+    fn touch(&self, mut entry: db::QueueEntry, index: &mut db::IndexGuard) -> db::QueueEntry {
+        let current = index.gc_epoch();
+        if !self.read_only && entry.epoch < current {
+            index.hash_touch(entry.id);
+            entry.epoch = current;
+        }
+        entry
+    }
+
+    fn locate_many(
+        &self,
+        hashes: &[Hash],
+        queue: &MutexGuard<Queue>,
+        index: &mut db::IndexGuard,
+    ) -> Vec<Option<db::QueueEntry>> {
+        let mut found: Vec<_> = hashes
+            .iter()
+            .map(|h| queue.find_value_of_key(&h.bytes).cloned())
+            .collect();
+        let missing: Vec<Hash> = hashes
+            .iter()
+            .zip(&found)
+            .filter(|&(_, f)| f.is_none())
+            .map(|(h, _)| h.clone())
+            .collect();
+        if !missing.is_empty() {
+            let mut stored = index.hash_locate_many(&missing).into_iter();
+            for f in found.iter_mut().filter(|f| f.is_none()) {
+                *f = stored
+                    .next()
+                    .unwrap()
+                    .map(|entry| self.touch(entry, index))
+                    .and_then(|entry| self.reusable(entry));
+            }
+        }
+        found
     }
 
     fn reserve(
@@ -154,6 +257,8 @@ impl InternalHashIndex {
             childs: childs.clone(),
             tag: None,
             persistent_ref: persistent_ref.clone(),
+            epoch: index.gc_epoch(),
+            suspect: false,
         };
         index.hash_insert_new(my_id, hash.bytes.clone(), qe.clone());
         assert!(queue.put_value(my_id, hash.bytes.clone(), qe).is_ok());
@@ -161,11 +266,35 @@ impl InternalHashIndex {
         my_id
     }
 
+    /// Reserve the suspect hash `stored` again, to store a new copy of its chunk. The hash
+    /// keeps its id, so everything referring to it gets the new copy once it is committed.
+    fn reserve_again(
+        &self,
+        stored: db::QueueEntry,
+        hash_entry: &Entry,
+        queue: &mut MutexGuard<Queue>,
+    ) -> u64 {
+        let id = stored.id;
+        let qe = db::QueueEntry {
+            childs: hash_entry.childs.clone(),
+            persistent_ref: None,
+            ..stored
+        };
+        assert!(queue.put_value(id, hash_entry.hash.bytes.clone(), qe).is_ok());
+        id
+    }
+
     fn reserved_id(&self, hash: &Hash, queue: &MutexGuard<Queue>) -> Option<u64> {
         queue.find_key(&hash.bytes).cloned()
     }
 
-    fn update_reserved(&self, id: u64, hash_entry: Entry, mut queue: &mut MutexGuard<Queue>) {
+    fn update_reserved(
+        &self,
+        id: u64,
+        hash_entry: Entry,
+        mut queue: &mut MutexGuard<Queue>,
+        index: &mut db::IndexGuard,
+    ) {
         let Entry {
             hash,
             node,
@@ -182,6 +311,14 @@ impl InternalHashIndex {
         }
 
 
+        // Remember where the chunk went, so a crash before commit can be resolved later. A new
+        // copy of a suspect chunk only replaces the old one once committed.
+        if let Some(ref r) = persistent_ref {
+            if !queue.find_value_of_key(&hash.bytes).map_or(false, |qe| qe.suspect) {
+                index.hash_set_ref(id, r);
+            }
+        }
+
         // If we didn't already commit and pop() the hash, update it:
         queue.update_value(&hash.bytes, |qe| {
             qe.node = node;
@@ -210,7 +347,7 @@ impl InternalHashIndex {
         mut queue: &mut MutexGuard<Queue>,
         mut index: &mut db::IndexGuard,
     ) {
-        entry_opt.map(|e| self.update_reserved(id, e, queue));
+        entry_opt.map(|e| self.update_reserved(id, e, queue, index));
 
         queue.set_ready(&id);
         self.insert_completed_in_order(&mut queue, &mut index);
@@ -220,8 +357,43 @@ impl InternalHashIndex {
 
 impl HashIndex {
     pub fn new(index: Arc<db::Index>) -> Result<HashIndex, DieselError> {
-        index.lock().hash_delete_not_ready();
-        Ok(HashIndex(InternalHashIndex::new(index)?))
+        let mut hash_index = HashIndex(InternalHashIndex::new(index)?);
+        let (completed, removed) = hash_index.resolve_reserved();
+        if completed > 0 || removed > 0 {
+            info!(
+                "Resolved reserved hashes: {} completed, {} removed",
+                completed,
+                removed
+            );
+        }
+        hash_index.0.resolved_on_open = (completed, removed);
+        Ok(hash_index)
+    }
+
+    /// Use `index` without touching the reservations of a writer that may be using it too.
+    pub fn new_read_only(index: Arc<db::Index>) -> Result<HashIndex, DieselError> {
+        let mut internal = InternalHashIndex::new(index)?;
+        internal.read_only = true;
+        Ok(HashIndex(internal))
+    }
+
+    /// Complete or remove hash entries that an earlier run reserved but never committed,
+    /// depending on whether their data reached a committed blob. Hashes reserved by this
+    /// index are left alone. Returns the number of completed and removed entries.
+    pub fn resolve_reserved(&self) -> (u64, u64) {
+        let (queue, mut index) = self.0.lock();
+        index.hash_resolve_not_ready(|hash| queue.find_key(hash).is_some())
+    }
+
+    /// The number of reserved entries that were completed and removed when the index was
+    /// opened. Read-only indexes leave them alone.
+    pub fn resolved_on_open(&self) -> (u64, u64) {
+        self.0.resolved_on_open
+    }
+
+    /// Whether a thread panicked while using the index.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.queue.is_poisoned()
     }
 
     /// Locate the local ID of this hash.
@@ -245,6 +417,43 @@ impl HashIndex {
         self.0.locate(hash, &queue, &mut index).is_some()
     }
 
+    /// Check which of `hashes` already exist in the system, with a single lookup.
+    pub fn hashes_exist(&self, hashes: &[Hash]) -> Vec<bool> {
+        let (queue, mut index) = self.0.lock();
+        self.0
+            .locate_many(hashes, &queue, &mut index)
+            .iter()
+            .map(Option::is_some)
+            .collect()
+    }
+
+    /// Locate the IDs and hash references of `hashes` with a single lookup. Hashes that are
+    /// unknown, or not yet stored, are `None`.
+    pub fn fetch_hash_refs(&self, hashes: &[Hash]) -> Vec<Option<(u64, tree::HashRef)>> {
+        let (queue, mut index) = self.0.lock();
+        let found = self.0.locate_many(hashes, &queue, &mut index);
+        hashes
+            .iter()
+            .zip(found)
+            .map(|(hash, entry)| {
+                entry.and_then(|e| {
+                    let (id, node, leaf) = (e.id, e.node, e.leaf);
+                    e.persistent_ref.map(|persistent_ref| {
+                        let href = tree::HashRef {
+                            hash: hash.clone(),
+                            node: node,
+                            leaf: leaf,
+                            info: None,
+                            size: None,
+                            persistent_ref: persistent_ref,
+                        };
+                        (id, href)
+                    })
+                })
+            })
+            .collect()
+    }
+
     /// Locate the local childs of the `Hash`.
     pub fn fetch_childs(&self, hash: &Hash) -> Option<Option<Vec<u64>>> {
         assert!(!hash.bytes.is_empty());
@@ -277,6 +486,7 @@ impl HashIndex {
                     node: queue_entry.node,
                     leaf: queue_entry.leaf,
                     info: None,
+                    size: None,
                     persistent_ref: queue_entry.persistent_ref.expect("persistent_ref"),
                 }))
             }
@@ -292,8 +502,14 @@ impl HashIndex {
         // storage. This allows us to continue after a crash without needing to scan
         // through and delete uncommitted entries.
         let (mut queue, mut index) = self.0.lock();
-        match self.0.locate(&hash_entry.hash, &queue, &mut index) {
-            Some(entry) => ReserveResult::HashKnown(entry.id),
+        match self.0.locate_stored(&hash_entry.hash, &queue, &mut index) {
+            Some(entry) => {
+                if self.0.avoids(&entry) {
+                    ReserveResult::ReserveOk(self.0.reserve_again(entry, hash_entry, &mut queue))
+                } else {
+                    ReserveResult::HashKnown(entry.id)
+                }
+            }
             None => {
                 let id = self.0.reserve(hash_entry, &mut queue, &mut index);
                 ReserveResult::ReserveOk(id)
@@ -312,8 +528,8 @@ impl HashIndex {
     /// references to the `Hash` to be created before it is committed).
     pub fn update_reserved(&self, id: u64, hash_entry: Entry) {
         assert!(!hash_entry.hash.bytes.is_empty());
-        let mut queue = self.0.queue_lock();
-        self.0.update_reserved(id, hash_entry, &mut queue);
+        let (mut queue, mut index) = self.0.lock();
+        self.0.update_reserved(id, hash_entry, &mut queue, &mut index);
     }
 
     /// A `Hash` is committed when it has been `finalized` in the external storage. `Commit`
@@ -328,11 +544,89 @@ impl HashIndex {
         self.0.index.lock().hash_list()
     }
 
+    /// List the ids, children and chunk locations of all hash entries.
+    pub fn list_nodes(&self) -> Vec<db::HashNode> {
+        self.0.index.lock().hash_list_nodes()
+    }
+
+    /// List all hash entries as stored, ordered by id.
+    pub fn list_rows(&self) -> Vec<db::HashRow> {
+        self.0.index.lock().hash_list_rows()
+    }
+
     /// Permanently delete hash by its ID.
     pub fn delete(&self, id: u64) {
         self.0.index.lock().hash_delete(id)
     }
 
+    /// Store new copies of suspect chunks when they are inserted again, rather than reusing the
+    /// stored copies that may be damaged. Until then, suspect hashes are treated as unknown.
+    pub fn set_rewrite_suspect(&self, rewrite: bool) {
+        self.0.rewrite_suspect.store(rewrite, Ordering::SeqCst);
+    }
+
+    /// Flag the chunks stored in the blob `blob_id` as suspect, after the blob failed a check.
+    /// Returns the number of flagged hashes.
+    pub fn mark_suspect_in_blob(&self, blob_id: i64) -> u64 {
+        self.0.index.lock().hash_mark_suspect_in_blob(blob_id)
+    }
+
+    /// Pin the current GC epoch, see `EpochPin`.
+    pub fn pin_epoch(&self) -> EpochPin {
+        // Pins are taken and read with the index locked, so no epoch starts in between.
+        let index = self.0.index.lock();
+        let epoch = index.gc_epoch();
+        self.0.pins.lock().expect("Epoch pins mutex poisoned").push(epoch);
+        EpochPin {
+            pins: self.0.pins.clone(),
+            epoch: epoch,
+        }
+    }
+
+    /// Start a new GC epoch for a garbage collection. Returns the oldest epoch whose hashes the
+    /// collection must keep: the oldest pinned epoch, or the new one if nothing is pinned.
+    pub fn begin_gc_epoch(&self) -> u64 {
+        let mut index = self.0.index.lock();
+        let epoch = index.gc_begin_epoch();
+        let pins = self.0.pins.lock().expect("Epoch pins mutex poisoned");
+        pins.iter().fold(epoch, |oldest, &pinned| cmp::min(oldest, pinned))
+    }
+
+    /// Delete the hashes in `ids`, which the GC found unused, except for those that are still
+    /// being written or were written or reused in epoch `keep_from` or later, and the hashes
+    /// below those. Returns the number of deleted hashes.
+    pub fn delete_unused(&self, ids: Vec<u64>, keep_from: u64) -> u64 {
+        // Hold the lock throughout, so that no hash is reused between the check and the delete.
+        let (mut queue, mut index) = self.0.lock();
+        let unused: HashSet<u64> = ids.iter().cloned().collect();
+
+        let mut kept = HashSet::new();
+        let mut recent: Vec<u64> = ids.iter()
+            .cloned()
+            .filter(|&id| {
+                queue.find_mut_value_of_priority(&id).is_some() ||
+                    index.hash_epoch(id).map_or(false, |epoch| epoch >= keep_from)
+            })
+            .collect();
+        while let Some(id) = recent.pop() {
+            if !kept.insert(id) {
+                continue;
+            }
+            if let Some(childs) = index.hash_locate_by_id(id).and_then(|entry| entry.childs) {
+                recent.extend(childs.into_iter().filter(|child| unused.contains(child)));
+            }
+        }
+
+        let mut deleted = 0;
+        for id in ids {
+            if !kept.contains(&id) {
+                index.hash_delete(id);
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
     /// API related to tagging, which is useful to indicate state during operation stages.
     /// It operates directly on the underlying IDs.
     pub fn set_tag(&self, id: u64, tag: tags::Tag) {
@@ -13,9 +13,10 @@
 // limitations under the License.
 
 
-use blob::{ChunkRef, NodeType, LeafType};
+use blob::{ChunkRef, NodeType, LeafType, RefFormat};
 use crypto;
 use hash::Hash;
+use hash::cache::NodeCache;
 use hash::tree::*;
 use key;
 use quickcheck;
@@ -27,6 +28,7 @@ use std::sync::{Arc, Mutex};
 pub struct MemoryBackend {
     chunks: Arc<Mutex<BTreeMap<Vec<u8>, (NodeType, LeafType, Option<Vec<u64>>, Vec<u8>)>>>,
     seen_chunks: Arc<Mutex<BTreeSet<Vec<u8>>>>,
+    node_cache: Option<Arc<NodeCache>>,
 }
 
 impl MemoryBackend {
@@ -34,8 +36,13 @@ impl MemoryBackend {
         MemoryBackend {
             chunks: Arc::new(Mutex::new(BTreeMap::new())),
             seen_chunks: Arc::new(Mutex::new(BTreeSet::new())),
+            node_cache: None,
         }
     }
+    pub fn with_node_cache(mut self, cache: NodeCache) -> MemoryBackend {
+        self.node_cache = Some(Arc::new(cache));
+        self
+    }
     pub fn saw_chunk(&self, chunk: &Vec<u8>) -> bool {
         let guarded_seen = self.seen_chunks.lock().unwrap();
         guarded_seen.contains(chunk)
@@ -75,12 +82,18 @@ impl HashTreeBackend for MemoryBackend {
                     length: chunk.len(),
                     packing: None,
                     key: None,
+                    integrity_length: None,
+                    format: RefFormat::Legacy,
                 })
             }
             None => None,
         }
     }
 
+    fn node_cache(&self) -> Option<&NodeCache> {
+        self.node_cache.as_ref().map(|c| &**c)
+    }
+
     fn insert_chunk(
         &self,
         chunk: &[u8],
@@ -106,6 +119,7 @@ impl HashTreeBackend for MemoryBackend {
                 node: node,
                 leaf: leaf,
                 info: None,
+                size: None,
                 persistent_ref: ChunkRef {
                     blob_id: None,
                     blob_name: hash.bytes.clone(),
@@ -113,6 +127,8 @@ impl HashTreeBackend for MemoryBackend {
                     length: len,
                     packing: None,
                     key: None,
+                    integrity_length: None,
+                    format: RefFormat::Legacy,
                 },
             },
         ))
@@ -156,6 +172,33 @@ fn identity_many_small_blocks() {
     quickcheck::quickcheck(prop as fn(u8) -> bool);
 }
 
+#[test]
+fn node_cache_serves_repeated_walks() {
+    let backend = MemoryBackend::new().with_node_cache(NodeCache::new(1 << 20));
+    let mut ht = SimpleHashTreeWriter::new(LeafType::TreeList, 4, backend.clone());
+    for i in 0..20u8 {
+        ht.append(&[i]).unwrap();
+    }
+    let hash_ref = ht.hash(None).unwrap();
+
+    let walk = || {
+        LeafIterator::new(backend.clone(), hash_ref.clone())
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>()
+    };
+    let first = walk();
+    assert_eq!(first.len(), 20);
+    let misses = backend.node_cache().unwrap().stats().misses;
+    assert!(misses > 0);
+
+    // The second walk is served from the cache.
+    assert_eq!(walk(), first);
+    let stats = backend.node_cache().unwrap().stats();
+    assert_eq!(stats.misses, misses);
+    assert_eq!(stats.hits, misses);
+}
+
 #[test]
 fn identity_empty() {
     let block = Vec::new();
@@ -280,3 +323,252 @@ fn identity_1_short_of_flush() {
         assert_eq!(bytes, chunk);
     }
 }
+
+#[test]
+fn hashes_depend_on_master_key() {
+    use crypto::keys::{Keeper, MasterKey};
+
+    let keys1 = Keeper::from_master_key(&MasterKey::from_bytes(vec![1; 64]));
+    let keys2 = Keeper::from_master_key(&MasterKey::from_bytes(vec![2; 64]));
+    let chunk = b"a well-known chunk of plaintext";
+
+    let hash = |keys: &Keeper| Hash::new(keys, NodeType::Leaf, LeafType::FileChunk, chunk);
+    assert_eq!(hash(&keys1), hash(&keys1));
+    assert!(hash(&keys1) != hash(&keys2));
+}
+
+#[test]
+fn hashes_exist_in_one_lookup() {
+    use crypto::keys::{Keeper, MasterKey};
+    use hash::{Entry, HashIndex, ReserveResult};
+
+    let index = HashIndex::new(Arc::new(::db::Index::new_for_testing())).unwrap();
+    let keys = Keeper::from_master_key(&MasterKey::from_bytes(vec![1; 64]));
+    let hash = |data: &[u8]| Hash::new(&keys, NodeType::Leaf, LeafType::FileChunk, data);
+    let reserve = |data: &[u8]| {
+        let entry = Entry {
+            hash: hash(data),
+            node: NodeType::Leaf,
+            leaf: LeafType::FileChunk,
+            childs: None,
+            persistent_ref: None,
+        };
+        match index.reserve(&entry) {
+            ReserveResult::ReserveOk(id) => id,
+            ReserveResult::HashKnown(_) => panic!("Hash already known"),
+        }
+    };
+
+    // One hash is stored in the database, and one is still queued.
+    let stored = reserve(b"stored");
+    index.commit(stored, None);
+    let queued = reserve(b"queued");
+
+    assert_eq!(
+        vec![true, false, true, false],
+        index.hashes_exist(&[hash(b"stored"), hash(b"unknown"), hash(b"queued"), hash(b"")])
+    );
+    assert!(index.hashes_exist(&[]).is_empty());
+
+    index.commit(queued, None);
+    index.flush();
+}
+
+#[test]
+fn reserved_hashes_are_resolved_on_restart() {
+    use blob::BlobDesc;
+    use crypto::keys::{Keeper, MasterKey};
+    use hash::{Entry, HashIndex, ReserveResult};
+
+    let db = Arc::new(::db::Index::new_for_testing());
+    let keys = Keeper::from_master_key(&MasterKey::from_bytes(vec![1; 64]));
+    let hash = |data: &[u8]| Hash::new(&keys, NodeType::Leaf, LeafType::FileChunk, data);
+    for &(id, done) in &[(1, true), (2, false)] {
+        let desc = BlobDesc {
+            name: vec![id as u8],
+            id: id,
+        };
+        db.lock().blob_in_air(&desc, None);
+        if done {
+            db.lock().blob_commit(&desc, 10, None);
+        }
+    }
+
+    let index = HashIndex::new(db.clone()).unwrap();
+    {
+        let reserve = |data: &[u8], blob_id: Option<i64>| {
+            let mut entry = Entry {
+                hash: hash(data),
+                node: NodeType::Leaf,
+                leaf: LeafType::FileChunk,
+                childs: None,
+                persistent_ref: None,
+            };
+            let id = match index.reserve(&entry) {
+                ReserveResult::ReserveOk(id) => id,
+                ReserveResult::HashKnown(_) => panic!("Hash already known"),
+            };
+            if let Some(blob_id) = blob_id {
+                entry.persistent_ref = Some(ChunkRef {
+                    blob_id: Some(blob_id),
+                    blob_name: vec![blob_id as u8],
+                    offset: 0,
+                    length: data.len(),
+                    packing: None,
+                    key: None,
+                    integrity_length: None,
+                    format: RefFormat::Legacy,
+                });
+                index.update_reserved(id, entry);
+            }
+        };
+        reserve(b"stored", Some(1));
+        reserve(b"uploading", Some(2));
+        reserve(b"unplaced", None);
+    }
+    assert_eq!((0, 0), index.resolve_reserved());
+
+    // Crash without committing the reserved hashes.
+    ::std::mem::forget(index);
+
+    let index = HashIndex::new(db).unwrap();
+    assert_eq!((1, 2), index.resolved_on_open());
+    assert_eq!((0, 0), index.resolve_reserved());
+    assert_eq!(
+        vec![true, false, false],
+        index.hashes_exist(&[hash(b"stored"), hash(b"uploading"), hash(b"unplaced")])
+    );
+    assert!(index.fetch_hash_ref(&hash(b"stored")).unwrap().is_some());
+}
+
+#[test]
+fn suspect_hashes_are_stored_again() {
+    use blob::BlobDesc;
+    use crypto::keys::{Keeper, MasterKey};
+    use hash::{Entry, HashIndex, ReserveResult};
+
+    let db = Arc::new(::db::Index::new_for_testing());
+    let keys = Keeper::from_master_key(&MasterKey::from_bytes(vec![1; 64]));
+    for id in 1..3 {
+        let desc = BlobDesc {
+            name: vec![id as u8],
+            id: id,
+        };
+        db.lock().blob_in_air(&desc, None);
+        db.lock().blob_commit(&desc, 10, None);
+    }
+
+    let index = HashIndex::new(db).unwrap();
+    let entry = |blob_id: Option<i64>| {
+        Entry {
+            hash: Hash::new(&keys, NodeType::Leaf, LeafType::FileChunk, b"chunk"),
+            node: NodeType::Leaf,
+            leaf: LeafType::FileChunk,
+            childs: None,
+            persistent_ref: blob_id.map(|blob_id| {
+                ChunkRef {
+                    blob_id: Some(blob_id),
+                    blob_name: vec![blob_id as u8],
+                    offset: 0,
+                    length: 5,
+                    packing: None,
+                    key: None,
+                    integrity_length: None,
+                    format: RefFormat::Legacy,
+                }
+            }),
+        }
+    };
+    let stored_in = |index: &HashIndex| {
+        index.fetch_hash_ref(&entry(None).hash).unwrap().map(|r| r.persistent_ref.blob_name)
+    };
+
+    let id = match index.reserve(&entry(None)) {
+        ReserveResult::ReserveOk(id) => id,
+        ReserveResult::HashKnown(_) => panic!("Hash already known"),
+    };
+    index.commit(id, Some(entry(Some(1))));
+
+    assert_eq!(1, index.mark_suspect_in_blob(1));
+    match index.reserve(&entry(None)) {
+        ReserveResult::HashKnown(known) => assert_eq!(id, known),
+        ReserveResult::ReserveOk(_) => panic!("Suspect hash stored again without asking"),
+    }
+
+    index.set_rewrite_suspect(true);
+    assert!(!index.hash_exists(&entry(None).hash));
+    match index.reserve(&entry(None)) {
+        ReserveResult::ReserveOk(again) => assert_eq!(id, again),
+        ReserveResult::HashKnown(_) => panic!("Suspect hash reused"),
+    }
+    // The damaged copy stays in place until the new one is committed.
+    index.update_reserved(id, entry(Some(2)));
+    let in_db = index.get_hash(id).and_then(|e| e.persistent_ref).map(|r| r.blob_name);
+    assert_eq!(Some(vec![1]), in_db);
+    index.commit(id, None);
+
+    assert_eq!(Some(vec![2]), stored_in(&index));
+    match index.reserve(&entry(None)) {
+        ReserveResult::HashKnown(known) => assert_eq!(id, known),
+        ReserveResult::ReserveOk(_) => panic!("Repaired hash stored again"),
+    }
+    index.flush();
+}
+
+#[test]
+fn subtree_sizes_allow_seeking() {
+    fn prop(lengths: Vec<u8>, offset: u16) -> bool {
+        let backend = MemoryBackend::new();
+        let mut ht = SimpleHashTreeWriter::new(LeafType::FileChunk, 4, backend.clone());
+        let mut data = vec![];
+        for (i, &len) in lengths.iter().enumerate() {
+            let chunk = vec![i as u8; len as usize];
+            ht.append(&chunk[..]).unwrap();
+            data.extend(chunk);
+        }
+        let hash_ref = ht.hash(None).unwrap();
+
+        let size = tree_size(backend.clone(), hash_ref.clone()).unwrap();
+        assert_eq!(Some(size), hash_ref.size);
+        assert_eq!(size.bytes, data.len() as u64);
+        assert_eq!(size.chunks, ::std::cmp::max(lengths.len(), 1) as u64);
+
+        let offset = offset as u64;
+        let (it, start) = LeafIterator::seek(backend, hash_ref, offset).unwrap();
+        let chunks: Vec<Vec<u8>> = it.collect();
+        if offset < data.len() as u64 {
+            // The first leaf holds the offset.
+            assert!(start <= offset && offset < start + chunks[0].len() as u64);
+        } else {
+            assert_eq!(start, data.len() as u64);
+            assert!(chunks.is_empty());
+        }
+        assert_eq!(chunks.concat(), &data[start as usize..]);
+
+        true
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>, u16) -> bool);
+}
+
+#[test]
+fn trees_without_sizes_are_read_from_the_start() {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(LeafType::FileChunk, 4, backend.clone());
+    for i in 0..10u8 {
+        ht.append(&[i, i]).unwrap();
+    }
+    let mut hash_ref = ht.hash(None).unwrap();
+
+    // References written by older versions do not record sizes.
+    let mut legacy = hash_ref.clone();
+    legacy.size = None;
+    hash_ref = HashRef::from_bytes(&mut &legacy.as_bytes()[..]).unwrap();
+    assert_eq!(hash_ref.size, None);
+
+    let size = tree_size(backend.clone(), hash_ref.clone()).unwrap();
+    assert_eq!(size, TreeSize { bytes: 20, chunks: 10 });
+
+    let (it, start) = LeafIterator::seek(backend, hash_ref, 15).unwrap();
+    assert_eq!(start, 0);
+    assert_eq!(it.count(), 10);
+}
@@ -19,18 +19,44 @@
 
 
 use key;
-use blob::{ChunkRef, NodeType, LeafType};
+use blob::{ChunkRef, NodeType, LeafType, RefFormat};
 
 use capnp;
 use hash::Hash;
+use hash::cache::{CachedNode, NodeCache};
 
 #[cfg(test)]
 use quickcheck;
 use root_capnp;
 use std::collections::VecDeque;
 use std::fmt;
+use std::ops::Add;
+use std::sync::Arc;
 
 
+/// Format of hash references that record the size of their subtree.
+const SIZED_FORMAT: u8 = 1;
+
+/// Size of the data below a node in a hash tree.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TreeSize {
+    /// Total length of the leaf chunks.
+    pub bytes: u64,
+    /// Number of leaf chunks.
+    pub chunks: u64,
+}
+
+impl Add for TreeSize {
+    type Output = TreeSize;
+
+    fn add(self, other: TreeSize) -> TreeSize {
+        TreeSize {
+            bytes: self.bytes + other.bytes,
+            chunks: self.chunks + other.chunks,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HashRef {
     pub hash: Hash,
@@ -38,6 +64,8 @@ pub struct HashRef {
     pub leaf: LeafType, // What kind of data the tree leafs contain.
     pub persistent_ref: ChunkRef,
     pub info: Option<key::Info>,
+    /// Size of the subtree below this reference, if it was written with one.
+    pub size: Option<TreeSize>,
 }
 
 impl HashRef {
@@ -60,6 +88,11 @@ impl HashRef {
                 extra.set_none(());
             }
         }
+        if let Some(size) = self.size {
+            msg.set_subtree_bytes(size.bytes);
+            msg.set_subtree_chunks(size.chunks);
+            msg.set_format(SIZED_FORMAT);
+        }
     }
 
     pub fn read_msg(msg: &root_capnp::hash_ref::Reader) -> Result<HashRef, capnp::Error> {
@@ -72,6 +105,14 @@ impl HashRef {
                 root_capnp::hash_ref::extra::None(()) => None,
                 root_capnp::hash_ref::extra::Info(st) => Some(key::Info::read(st?)?),
             },
+            // Later formats are expected to keep recording sizes.
+            size: match msg.get_format() {
+                0 => None,
+                _ => Some(TreeSize {
+                    bytes: msg.get_subtree_bytes(),
+                    chunks: msg.get_subtree_chunks(),
+                }),
+            },
         })
     }
 
@@ -111,6 +152,11 @@ pub trait HashTreeBackend: Clone {
         Option<Vec<u64>>,
         Option<&key::Info>,
     ) -> Result<(u64, HashRef), Self::Err>;
+
+    /// Cache for the nodes that walkers fetch, if any.
+    fn node_cache(&self) -> Option<&NodeCache> {
+        None
+    }
 }
 
 
@@ -134,16 +180,25 @@ fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
         return Some(out);
     }
 
-    let reader = capnp::serialize_packed::read_message(
+    let reader = match capnp::serialize_packed::read_message(
         &mut &bytes[..],
         capnp::message::ReaderOptions::new(),
-    ).unwrap();
-    let msg = reader
+    ) {
+        Ok(reader) => reader,
+        Err(_) => return None,
+    };
+    let refs = match reader
         .get_root::<root_capnp::hash_ref_list::Reader>()
-        .unwrap();
-
-    for ref_ in msg.get_hash_refs().unwrap().iter() {
-        out.push(HashRef::read_msg(&ref_).unwrap());
+        .and_then(|msg| msg.get_hash_refs()) {
+        Ok(refs) => refs,
+        Err(_) => return None,
+    };
+
+    for ref_ in refs.iter() {
+        match HashRef::read_msg(&ref_) {
+            Ok(ref_) => out.push(ref_),
+            Err(_) => return None,
+        }
     }
 
     Some(out)
@@ -159,6 +214,8 @@ fn test_hash_refs_identity() {
             length: n,
             packing: None,
             key: None,
+            integrity_length: None,
+            format: RefFormat::Legacy,
         };
         let mut v = vec![];
         for i in 1..count + 1 {
@@ -168,6 +225,14 @@ fn test_hash_refs_identity() {
                 leaf: LeafType::FileChunk,
                 info: None,
                 persistent_ref: chunk_ref.clone(),
+                size: if i % 2 == 0 {
+                    Some(TreeSize {
+                        bytes: n as u64,
+                        chunks: i as u64,
+                    })
+                } else {
+                    None
+                },
             });
         }
         let bytes = hash_refs_to_bytes(&v);
@@ -176,6 +241,7 @@ fn test_hash_refs_identity() {
             assert_eq!(v[i].node, r.node);
             assert_eq!(v[i].leaf, r.leaf);
             assert_eq!(v[i].info, r.info);
+            assert_eq!(v[i].size, r.size);
             assert!(v[i].persistent_ref.blob_id.is_none());
             assert_eq!(v[i].persistent_ref.blob_name, r.persistent_ref.blob_name);
             assert_eq!(v[i].persistent_ref.offset, r.persistent_ref.offset);
@@ -238,23 +304,29 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
     /// 1-byte blocks when reading; if needed, accummulation of data must be handled by the
     /// `backend`).
     pub fn append(&mut self, chunk: &[u8]) -> Result<(), B::Err> {
-        self.append_at(0, chunk, None, None)
+        let size = TreeSize {
+            bytes: chunk.len() as u64,
+            chunks: 1,
+        };
+        self.append_at(0, chunk, size, None, None)
     }
 
     fn append_at(
         &mut self,
         level: usize,
         data: &[u8],
+        size: TreeSize,
         childs: Option<Vec<u64>>,
         info: Option<&key::Info>,
     ) -> Result<(), B::Err> {
-        let (id, hash_ref) = self.backend.insert_chunk(
+        let (id, mut hash_ref) = self.backend.insert_chunk(
             &data,
             From::from(level as u64),
             self.leaf,
             childs,
             info,
         )?;
+        hash_ref.size = Some(size);
         self.append_hashref_at(level, id, hash_ref, info)
     }
 
@@ -290,9 +362,12 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
 
         // All data from this level (hashes and references):
         let ids: Vec<u64> = level_v.iter().map(|&(id, _)| id).collect();
+        let size = level_v.iter().fold(TreeSize::default(), |acc, &(_, ref hr)| {
+            acc + hr.size.expect("Appended references have sizes")
+        });
         let data = hash_refs_to_bytes(&level_v.into_iter().map(|(_, hr)| hr).collect());
 
-        self.append_at(level + 1, &data[..], Some(ids), info)
+        self.append_at(level + 1, &data[..], size, Some(ids), info)
     }
 
     /// Retrieve the hash and backend persistent reference that identified this tree.
@@ -399,24 +474,17 @@ where
                 None => cycle_start = Some(node.hash.clone()),
                 Some(ref hash) => assert!(hash != &node.hash),
             }
-            let fetch_chunk = |backend: &B, child: &HashRef| {
-                backend.fetch_chunk(child).map(|opt| {
-                    opt.expect("Invalid hash ref")
-                })
-            };
-
             match node.node {
                 NodeType::Leaf => {
                     if visitor.leaf_enter(&node) {
-                        let data = fetch_chunk(&self.backend, &node)?;
+                        let data = CachedNode::into_leaf(fetch_node(&self.backend, &node)?);
                         if visitor.leaf_leave(data, &node) {
                             break;
                         }
                     }
                 }
                 NodeType::Branch(..) => {
-                    let data = fetch_chunk(&self.backend, &node)?;
-                    let mut new_childs = hash_refs_from_bytes(&data[..]).unwrap();
+                    let mut new_childs = CachedNode::into_branch(fetch_node(&self.backend, &node)?);
                     if visitor.branch_enter(&node, &new_childs) {
                         self.stack.push(StackItem::LeaveBranch(node));
                         new_childs.reverse();
@@ -433,6 +501,96 @@ where
     }
 }
 
+/// Fetch and decode `node`, through the node cache of `backend` if it has one. File contents
+/// are never cached: they are seldom read twice and would push out the metadata.
+///
+/// A disk cache entry that does not decode is dropped and fetched again.
+fn fetch_node<B: HashTreeBackend>(backend: &B, node: &HashRef) -> Result<Arc<CachedNode>, B::Err> {
+    let decode = |data: Vec<u8>| match node.node {
+        NodeType::Branch(..) => hash_refs_from_bytes(&data[..]).map(CachedNode::Branch),
+        NodeType::Leaf => Some(CachedNode::Leaf(data)),
+    };
+    let fetch = || {
+        backend.fetch_chunk(node).map(
+            |opt| opt.expect("Invalid hash ref"),
+        )
+    };
+
+    let cache = match backend.node_cache() {
+        Some(cache) if node.node != NodeType::Leaf || node.leaf != LeafType::FileChunk => cache,
+        _ => return Ok(Arc::new(decode(fetch()?).expect("Invalid branch node"))),
+    };
+    if let Some(cached) = cache.get(&node.hash) {
+        return Ok(cached);
+    }
+    let decoded = match cache.load(&node.hash).and_then(&decode) {
+        Some(decoded) => Arc::new(decoded),
+        None => {
+            let data = fetch()?;
+            cache.save(&node.hash, &data[..]);
+            Arc::new(decode(data).expect("Invalid branch node"))
+        }
+    };
+    cache.insert(&node.hash, decoded.clone(), None);
+    Ok(decoded)
+}
+
+/// The leaves below `root_ref` in order, without fetching their data.
+pub fn leaf_refs<B>(backend: B, root_ref: HashRef) -> Result<Vec<HashRef>, B::Err>
+where
+    B: HashTreeBackend,
+{
+    struct Refs(Vec<HashRef>);
+    impl Visitor for Refs {
+        fn leaf_enter(&mut self, href: &HashRef) -> bool {
+            self.0.push(href.clone());
+            false
+        }
+    }
+
+    let mut refs = Refs(vec![]);
+    if let Some(mut walker) = Walker::new(backend, root_ref)? {
+        while walker.resume(&mut refs)? {}
+    }
+    Ok(refs.0)
+}
+
+/// The size of the tree below `root_ref`. Trees written before sizes were recorded are walked
+/// to count their leaves.
+pub fn tree_size<B>(backend: B, root_ref: HashRef) -> Result<TreeSize, B::Err>
+where
+    B: HashTreeBackend,
+{
+    struct Sizes(TreeSize);
+    impl Visitor for Sizes {
+        fn leaf_enter(&mut self, href: &HashRef) -> bool {
+            match href.size {
+                Some(size) => {
+                    self.0 = self.0 + size;
+                    false
+                }
+                None => true,
+            }
+        }
+        fn leaf_leave(&mut self, chunk: Vec<u8>, _href: &HashRef) -> bool {
+            self.0 = self.0 + TreeSize {
+                bytes: chunk.len() as u64,
+                chunks: 1,
+            };
+            false
+        }
+    }
+
+    if let Some(size) = root_ref.size {
+        return Ok(size);
+    }
+    let mut sizes = Sizes(TreeSize::default());
+    if let Some(mut walker) = Walker::new(backend, root_ref)? {
+        while walker.resume(&mut sizes)? {}
+    }
+    Ok(sizes.0)
+}
+
 pub struct LeafIterator<B> {
     walker: Walker<B>,
     visitor: LeafVisitor,
@@ -450,6 +608,56 @@ where
             }
         }))
     }
+
+    /// Iterate from the leaf that holds byte `offset` of the tree, which starts at the returned
+    /// offset. Subtrees that end before `offset` are skipped without fetching them, as long as
+    /// their sizes are recorded; trees written without sizes are read from the start.
+    pub fn seek(
+        backend: B,
+        root_ref: HashRef,
+        offset: u64,
+    ) -> Result<(LeafIterator<B>, u64), B::Err> {
+        let mut stack = vec![];
+        let mut start = 0;
+        let mut node = Some(root_ref);
+        while let Some(href) = node.take() {
+            if href.node == NodeType::Leaf || href.size.is_none() {
+                stack.push(StackItem::Enter(href));
+                break;
+            }
+            let mut childs = CachedNode::into_branch(fetch_node(&backend, &href)?);
+            let mut skip = 0;
+            for child in &childs {
+                match child.size {
+                    Some(size) if start + size.bytes <= offset => {
+                        start += size.bytes;
+                        skip += 1;
+                    }
+                    _ => break,
+                }
+            }
+            // Later siblings are visited after the subtree holding the offset, if there is one.
+            let rest: Vec<HashRef> = childs.drain(skip..).collect();
+            let mut rest = rest.into_iter();
+            node = rest.next();
+            stack.extend(rest.rev().map(StackItem::Enter));
+        }
+
+        let it = LeafIterator {
+            walker: Walker {
+                backend: backend,
+                stack: stack,
+            },
+            visitor: LeafVisitor { leafs: VecDeque::new() },
+        };
+        Ok((it, start))
+    }
+
+    /// Like `next`, but returns backend errors instead of panicking on them.
+    pub fn try_next(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
+        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor)? {}
+        Ok(self.visitor.leafs.pop_front())
+    }
 }
 
 pub struct LeafVisitor {
@@ -467,7 +675,6 @@ impl<B: HashTreeBackend> Iterator for LeafIterator<B> {
     type Item = Vec<u8>;
 
     fn next(&mut self) -> Option<Vec<u8>> {
-        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor).unwrap() {}
-        self.visitor.leafs.pop_front()
+        self.try_next().unwrap()
     }
 }
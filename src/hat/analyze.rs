@@ -0,0 +1,317 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Estimates of how a source tree would chunk and deduplicate, before it is backed up.
+
+use key;
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use util::human;
+
+
+/// Chunk lengths that are compared: the ones a commit can choose between, the default and
+/// `--fixed-blocks`. Each divides the largest, so that files are read once.
+pub const CHUNK_LENS: [usize; 2] = [key::DEFAULT_CHUNK_LEN, key::FIXED_BLOCK_LEN];
+
+/// Approximate bytes stored for each chunk besides its data: the authentication of its
+/// ciphertext, its entry in the blob footer, its reference in the hash tree and its row in the
+/// hash index.
+const CHUNK_OVERHEAD: u64 = 256;
+
+/// The outcome of chunking the sample with one chunk length.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkingEstimate {
+    pub chunk_len: usize,
+    pub chunks: u64,
+    /// Chunks with content that no earlier chunk of the sample had.
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+    /// Predicted repository size after a first backup of the whole tree.
+    pub predicted_bytes: u64,
+}
+
+/// What analyzing a source tree found, see `analyze`.
+#[derive(Clone, Debug, Default)]
+pub struct Analysis {
+    pub files: u64,
+    pub bytes: u64,
+    pub sampled_files: u64,
+    pub sampled_bytes: u64,
+    /// Files that could not be read, and are left out of the sample.
+    pub unreadable_files: u64,
+    pub estimates: Vec<ChunkingEstimate>,
+}
+
+impl ChunkingEstimate {
+    /// How a commit gets chunks of this length.
+    fn option(&self) -> &'static str {
+        if self.chunk_len == key::FIXED_BLOCK_LEN {
+            "commit --fixed-blocks"
+        } else {
+            "default"
+        }
+    }
+}
+
+impl Analysis {
+    /// The estimate with the smallest predicted repository, preferring longer chunks on ties.
+    pub fn recommended(&self) -> Option<&ChunkingEstimate> {
+        self.estimates.iter().rev().min_by_key(|e| e.predicted_bytes)
+    }
+}
+
+struct Simulation {
+    chunk_len: usize,
+    seen: HashSet<(u64, usize)>,
+    chunks: u64,
+    unique_bytes: u64,
+}
+
+impl Simulation {
+    fn add(&mut self, chunk: &[u8]) {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(chunk);
+        self.chunks += 1;
+        if self.seen.insert((hasher.finish(), chunk.len())) {
+            self.unique_bytes += chunk.len() as u64;
+        }
+    }
+
+    fn estimate(&self, scale: f64) -> ChunkingEstimate {
+        let unique_chunks = self.seen.len() as u64;
+        let stored = self.unique_bytes + unique_chunks * CHUNK_OVERHEAD;
+        ChunkingEstimate {
+            chunk_len: self.chunk_len,
+            chunks: self.chunks,
+            unique_chunks: unique_chunks,
+            unique_bytes: self.unique_bytes,
+            predicted_bytes: (stored as f64 * scale) as u64,
+        }
+    }
+}
+
+/// The regular files below `root` and their sizes, in path order. Symbolic links are not
+/// followed.
+fn list_files(root: &Path) -> Vec<(PathBuf, u64)> {
+    let mut files = vec![];
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(list) => list.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(e) => {
+                warn!("Could not list {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        entries.sort();
+        // Directories are pushed in reverse, so that they are listed in order.
+        for path in entries.into_iter().rev() {
+            match fs::symlink_metadata(&path) {
+                Ok(ref meta) if meta.is_dir() => dirs.push(path),
+                Ok(ref meta) if meta.is_file() => files.push((path, meta.len())),
+                _ => (),
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Read until `buf` is full or the data ends. Returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Chunk and deduplicate a sample of the files below `root` with each of `CHUNK_LENS`, without
+/// storing anything. The sample is spread over the tree and holds at most `sample_bytes`.
+/// Predictions for the whole tree assume that the rest of it deduplicates like the sample.
+pub fn analyze(root: &Path, sample_bytes: u64) -> io::Result<Analysis> {
+    fs::metadata(root)?;
+    let files = list_files(root);
+
+    let mut analysis = Analysis::default();
+    analysis.files = files.len() as u64;
+    analysis.bytes = files.iter().map(|&(_, size)| size).sum();
+
+    let mut sims: Vec<Simulation> = CHUNK_LENS
+        .iter()
+        .map(|&len| {
+            Simulation {
+                chunk_len: len,
+                seen: HashSet::new(),
+                chunks: 0,
+                unique_bytes: 0,
+            }
+        })
+        .collect();
+    let mut buf = vec![0; CHUNK_LENS[CHUNK_LENS.len() - 1]];
+
+    let mut listed = 0;
+    for (path, size) in files {
+        listed += size;
+        // Keep the sample in proportion to how much of the tree has been passed.
+        let target = (sample_bytes as f64 * listed as f64 / analysis.bytes.max(1) as f64) as u64;
+        if analysis.sampled_bytes >= target && size > 0 {
+            continue;
+        }
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Could not read {}: {}", path.display(), e);
+                analysis.unreadable_files += 1;
+                continue;
+            }
+        };
+        let mut reader = file.take(sample_bytes - analysis.sampled_bytes);
+        loop {
+            let len = match read_full(&mut reader, &mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    warn!("Could not read {}: {}", path.display(), e);
+                    analysis.unreadable_files += 1;
+                    break;
+                }
+            };
+            if len == 0 {
+                break;
+            }
+            for sim in &mut sims {
+                for chunk in buf[..len].chunks(sim.chunk_len) {
+                    sim.add(chunk);
+                }
+            }
+            analysis.sampled_bytes += len as u64;
+        }
+        analysis.sampled_files += 1;
+    }
+
+    let scale = if analysis.sampled_bytes > 0 {
+        analysis.bytes as f64 / analysis.sampled_bytes as f64
+    } else {
+        0.0
+    };
+    analysis.estimates = sims.iter().map(|s| s.estimate(scale)).collect();
+    Ok(analysis)
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Files:            {} ({})",
+            human::count(self.files),
+            human::bytes(self.bytes)
+        )?;
+        writeln!(
+            f,
+            "Sampled:          {} ({})",
+            human::count(self.sampled_files),
+            human::bytes(self.sampled_bytes)
+        )?;
+        if self.unreadable_files > 0 {
+            writeln!(f, "Unreadable:       {}", human::count(self.unreadable_files))?;
+        }
+        writeln!(f, "")?;
+        writeln!(
+            f,
+            "{:>12}  {:>12}  {:>12}  {:>12}  {:>12}",
+            "chunk size",
+            "chunks",
+            "distinct",
+            "distinct size",
+            "predicted"
+        )?;
+        for e in &self.estimates {
+            writeln!(
+                f,
+                "{:>12}  {:>12}  {:>12}  {:>12}  {:>12}  ({})",
+                human::bytes(e.chunk_len as u64),
+                human::count(e.chunks),
+                human::count(e.unique_chunks),
+                human::bytes(e.unique_bytes),
+                human::bytes(e.predicted_bytes),
+                e.option()
+            )?;
+        }
+        writeln!(f, "")?;
+        match self.recommended() {
+            Some(e) => {
+                writeln!(
+                    f,
+                    "Recommended:      {} chunks ({}), about {} stored",
+                    human::bytes(e.chunk_len as u64),
+                    e.option(),
+                    human::bytes(e.predicted_bytes)
+                )?
+            }
+            None => writeln!(f, "Recommended:      nothing to sample")?,
+        }
+        write!(f, "Compression:      none, data is stored as it is read")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn duplicate_files_are_counted_once() {
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-analyze-{}", nanos));
+        fs::create_dir_all(root.join("copy")).unwrap();
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        for path in &[root.join("a"), root.join("copy").join("a")] {
+            fs::File::create(path).unwrap().write_all(&data).unwrap();
+        }
+
+        let analysis = analyze(&root, 1 << 30).unwrap();
+        assert_eq!((2, 2), (analysis.files, analysis.sampled_files));
+        assert_eq!(analysis.bytes, analysis.sampled_bytes);
+
+        let default = analysis
+            .estimates
+            .iter()
+            .find(|e| e.chunk_len == key::DEFAULT_CHUNK_LEN)
+            .unwrap();
+        assert_eq!((6, 3), (default.chunks, default.unique_chunks));
+        assert_eq!(data.len() as u64, default.unique_bytes);
+        assert!(analysis.recommended().is_some());
+        // Only chunk lengths that a commit can be asked for are estimated.
+        let lens: Vec<_> = analysis.estimates.iter().map(|e| e.chunk_len).collect();
+        assert_eq!(lens, vec![key::DEFAULT_CHUNK_LEN, key::FIXED_BLOCK_LEN]);
+        assert_eq!((2, 1), (analysis.estimates[1].chunks, analysis.estimates[1].unique_chunks));
+
+        // A smaller sample is scaled up to the whole tree.
+        let half = analyze(&root, data.len() as u64).unwrap();
+        assert_eq!(data.len() as u64, half.sampled_bytes);
+        assert!(half.estimates[0].predicted_bytes > half.estimates[0].unique_bytes);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
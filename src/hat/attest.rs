@@ -0,0 +1,149 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Signed statements about snapshots, for publishing outside the repository.
+//!
+//! An attestation records the root hash of a snapshot along with its size. Once published,
+//! e.g. to a transparency log, any later change to the snapshot in the repository shows up as
+//! a mismatch when the attestation is verified.
+
+use chrono;
+use crypto::keys::{self, Keeper};
+use db;
+use errors::{HatError, VerificationError};
+use hex::{FromHex, ToHex};
+use std::collections::{HashMap, HashSet};
+
+
+const VERSION: &'static str = "1";
+
+/// The facts an attestation vouches for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Attestation {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub root_hash: Vec<u8>,
+    /// Stored chunks the snapshot refers to, including those of its directory listings.
+    pub chunks: u64,
+    /// Sum of the stored chunk lengths.
+    pub bytes: u64,
+    /// When the attestation was made.
+    pub attested: chrono::DateTime<chrono::Utc>,
+}
+
+impl Attestation {
+    fn statement(&self, public_key: &[u8]) -> String {
+        format!(
+            "hat-attestation: {}\nfamily: {}\nsnapshot: {}\ncreated: {}\nroot-hash: {}\n\
+             chunks: {}\nbytes: {}\nattested: {}\npublic-key: {}\n",
+            VERSION,
+            self.family_name,
+            self.snapshot_id,
+            self.created.to_rfc3339(),
+            self.root_hash.to_hex(),
+            self.chunks,
+            self.bytes,
+            self.attested.to_rfc3339(),
+            public_key.to_hex()
+        )
+    }
+
+    /// The attestation as text, signed with the signing key of the repository.
+    pub fn sign(&self, keys: &Keeper) -> String {
+        let public_key = keys.signing_public_key();
+        let statement = self.statement(&public_key);
+        let (_, signature) = keys.sign(statement.as_bytes());
+        format!("{}signature: {}\n", statement, signature.to_hex())
+    }
+
+    /// Parse an attestation made by `sign`. Returns it along with the public key it is signed
+    /// with, once the signature has been checked.
+    pub fn verify_signed(text: &str) -> Result<(Attestation, Vec<u8>), HatError> {
+        let text = text.trim_left();
+        let sig_start = text.rfind("signature: ").ok_or("Not a signed attestation")?;
+        let (statement, signature_line) = text.split_at(sig_start);
+
+        let mut fields = HashMap::new();
+        for line in statement.lines().chain(signature_line.lines()) {
+            let mut parts = line.splitn(2, ": ");
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => {
+                    fields.insert(key, value.trim().to_owned());
+                }
+                _ => return Err(From::from(format!("Invalid attestation line: {}", line))),
+            }
+        }
+        let field = |name: &str| -> Result<String, HatError> {
+            fields.get(name).cloned().ok_or_else(|| {
+                From::from(format!("Attestation has no {}", name))
+            })
+        };
+        let number = |name: &str| -> Result<u64, HatError> {
+            field(name)?.parse().map_err(|_| {
+                From::from(format!("Invalid {} in attestation", name))
+            })
+        };
+        let time = |name: &str| -> Result<chrono::DateTime<chrono::Utc>, HatError> {
+            chrono::DateTime::parse_from_rfc3339(&field(name)?)
+                .map(|t| t.with_timezone(&chrono::Utc))
+                .map_err(|_| From::from(format!("Invalid {} in attestation", name)))
+        };
+        let bytes = |name: &str| -> Result<Vec<u8>, HatError> {
+            Vec::from_hex(field(name)?).map_err(|_| {
+                From::from(format!("Invalid {} in attestation", name))
+            })
+        };
+
+        if field("hat-attestation")? != VERSION {
+            return Err(From::from("Unsupported attestation version"));
+        }
+        let public_key = bytes("public-key")?;
+        if !keys::verify_signature(&public_key, statement.as_bytes(), &bytes("signature")?) {
+            return Err(From::from(
+                VerificationError::from("The attestation signature does not match its contents"),
+            ));
+        }
+
+        let attestation = Attestation {
+            family_name: field("family")?,
+            snapshot_id: number("snapshot")?,
+            created: time("created")?,
+            root_hash: bytes("root-hash")?,
+            chunks: number("chunks")?,
+            bytes: number("bytes")?,
+            attested: time("attested")?,
+        };
+        Ok((attestation, public_key))
+    }
+}
+
+/// Count the chunks reachable from the hash ids in `roots`, and the bytes they take up.
+pub fn count_reachable(nodes: Vec<db::HashNode>, roots: Vec<u64>) -> (u64, u64) {
+    let nodes: HashMap<u64, db::HashNode> = nodes.into_iter().map(|n| (n.id, n)).collect();
+    let mut seen = HashSet::new();
+    let mut queue = roots;
+    let (mut chunks, mut bytes) = (0, 0);
+    while let Some(id) = queue.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        if let Some(node) = nodes.get(&id) {
+            chunks += 1;
+            bytes += node.length;
+            queue.extend(node.childs.iter().cloned());
+        }
+    }
+    (chunks, bytes)
+}
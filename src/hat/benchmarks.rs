@@ -151,3 +151,44 @@ fn insert_large_unique_files(mut bench: &mut Bencher) {
 fn insert_large_identical_files(mut bench: &mut Bencher) {
     insert_files(&mut bench, 8 * 1024 * 1024, false);
 }
+
+fn insert_file_from_disk(bench: &mut Bencher, mmap: bool) {
+    use std::fs;
+    use std::io::Write;
+    use util::MMAP_MIN_LEN;
+
+    let (hat, family) = setup_family();
+
+    let filesize = 2 * MMAP_MIN_LEN as usize;
+    let path = ::std::env::temp_dir().join(format!("hat-bench-{}", ::time::precise_time_ns()));
+    let mut data = vec![0; filesize];
+    UniqueBlockFiller::new(0).fill_bytes(&mut data);
+    fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+    let mut name = vec![0; 8];
+    bench.iter(|| {
+        name[0] = name[0].wrapping_add(1);
+        let file = if mmap {
+            FileIterator::new_mapped(&path).unwrap()
+        } else {
+            FileIterator::new(&path).unwrap()
+        };
+        family
+            .snapshot_direct(entry(name.clone()), false, Some(file))
+            .unwrap();
+    });
+
+    hat.data_flush().unwrap();
+    fs::remove_file(&path).unwrap();
+    bench.bytes = filesize as u64;
+}
+
+#[bench]
+fn insert_file_buffered(mut bench: &mut Bencher) {
+    insert_file_from_disk(&mut bench, false);
+}
+
+#[bench]
+fn insert_file_mapped(mut bench: &mut Bencher) {
+    insert_file_from_disk(&mut bench, true);
+}
@@ -0,0 +1,267 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Verification of the stored data, optionally of one part of it per run.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+
+/// One of `parts` deterministic parts of the blobs in a repository, numbered from 1.
+///
+/// Blobs are assigned to parts by their name, so checking parts 1 to `parts` in turn reads
+/// every blob exactly once, and the assignment does not change as blobs are added.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Subset {
+    pub part: u64,
+    pub parts: u64,
+}
+
+impl Subset {
+    /// Every blob.
+    pub fn all() -> Subset {
+        Subset { part: 1, parts: 1 }
+    }
+
+    /// The part for the week `days_since_epoch` falls in, so that a weekly check covers the
+    /// whole repository every `parts` weeks.
+    pub fn for_week(parts: u64, days_since_epoch: u64) -> Subset {
+        Subset {
+            part: (days_since_epoch / 7) % parts + 1,
+            parts: parts,
+        }
+    }
+
+    /// Parse `N/M` for part N of M, or `auto/M` for the part of the current week.
+    pub fn parse(s: &str) -> Result<Subset, String> {
+        let mut split = s.splitn(2, '/');
+        let (part, parts) = match (split.next(), split.next()) {
+            (Some(part), Some(parts)) => (part, parts),
+            _ => return Err(format!("Expected N/M: {}", s)),
+        };
+        let parts = match parts.parse::<u64>() {
+            Ok(m) if m > 0 => m,
+            _ => return Err(format!("Invalid number of parts: {}", parts)),
+        };
+        if part == "auto" {
+            let days = ::time::get_time().sec as u64 / (24 * 3600);
+            return Ok(Subset::for_week(parts, days));
+        }
+        match part.parse::<u64>() {
+            Ok(n) if n >= 1 && n <= parts => {
+                Ok(Subset {
+                    part: n,
+                    parts: parts,
+                })
+            }
+            _ => Err(format!("Invalid part, expected 1 to {}: {}", parts, part)),
+        }
+    }
+
+    /// Whether the blob named `name` belongs to this part.
+    pub fn contains(&self, name: &[u8]) -> bool {
+        // FNV-1a; blob names are already uniformly distributed, but have no fixed layout.
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        for &b in name {
+            h = (h ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        h % self.parts == self.part - 1
+    }
+}
+
+impl fmt::Display for Subset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.part, self.parts)
+    }
+}
+
+/// The outcome of checking a subset of the blobs.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CheckReport {
+    pub blobs: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+    /// One message per blob that failed the check.
+    pub errors: Vec<String>,
+    /// Whether the check continued where an interrupted one stopped.
+    pub resumed: bool,
+    /// Whether the check reached the last blob, rather than running out of time.
+    pub complete: bool,
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} blobs, {} chunks, {} bytes checked, {} errors",
+            self.blobs,
+            self.chunks,
+            self.bytes,
+            self.errors.len()
+        )?;
+        if self.resumed {
+            write!(f, ", resumed")?;
+        }
+        if !self.complete {
+            write!(f, ", out of time (run again to continue)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of checking the structure of every snapshot, without reading file data.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TreeCheckReport {
+    pub snapshots: u64,
+    pub directories: u64,
+    /// Distinct hash tree nodes looked up in the hash index.
+    pub nodes: u64,
+    /// Distinct blobs confirmed to be listed by the backend.
+    pub blobs: u64,
+    /// One message per problem found.
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for TreeCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} snapshots, {} directories, {} nodes in {} blobs checked, {} errors",
+            self.snapshots,
+            self.directories,
+            self.nodes,
+            self.blobs,
+            self.errors.len()
+        )
+    }
+}
+
+/// How many blobs a check downloads at the same time, and how many threads verify the
+/// downloaded blobs. Downloads wait on the backend, verification on the CPU.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CheckLimits {
+    pub fetchers: usize,
+    pub hashers: usize,
+}
+
+impl Default for CheckLimits {
+    fn default() -> CheckLimits {
+        CheckLimits {
+            fetchers: 4,
+            hashers: 2,
+        }
+    }
+}
+
+/// Outcomes of a check in progress, shared by the threads doing the work.
+///
+/// Blobs are numbered in the order they were handed out, but may finish in any order. The
+/// check cursor only moves past a blob once every blob before it has finished too, so that an
+/// interrupted check never skips a blob when resumed.
+#[derive(Debug, Default)]
+pub struct CheckProgress {
+    report: CheckReport,
+    // Ids of finished blobs that the cursor has not moved past yet, by number.
+    finished: BTreeMap<usize, i64>,
+    next: usize,
+}
+
+impl CheckProgress {
+    pub fn new(resumed: bool) -> CheckProgress {
+        let mut progress = CheckProgress::default();
+        progress.report.resumed = resumed;
+        progress
+    }
+
+    /// Record the outcome of checking blob number `seq` with id `blob_id`. Returns the new
+    /// position of the cursor if it moved.
+    pub fn record(
+        &mut self,
+        seq: usize,
+        blob_id: i64,
+        outcome: Result<(u64, u64), String>,
+    ) -> Option<i64> {
+        self.report.blobs += 1;
+        match outcome {
+            Ok((chunks, bytes)) => {
+                self.report.chunks += chunks;
+                self.report.bytes += bytes;
+            }
+            Err(e) => self.report.errors.push(e),
+        }
+
+        self.finished.insert(seq, blob_id);
+        let mut cursor = None;
+        while let Some(id) = self.finished.remove(&self.next) {
+            cursor = Some(id);
+            self.next += 1;
+        }
+        cursor
+    }
+
+    /// The number of blobs that the cursor has moved past.
+    pub fn done(&self) -> usize {
+        self.next
+    }
+
+    pub fn into_report(self) -> CheckReport {
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_cover_every_name_once() {
+        let names: Vec<Vec<u8>> = (0..200u32).map(|i| format!("blob{}", i).into_bytes()).collect();
+        for name in &names {
+            assert!(Subset::all().contains(name));
+            let owners = (1..6)
+                .filter(|&n| Subset { part: n, parts: 5 }.contains(name))
+                .count();
+            assert_eq!(1, owners);
+        }
+    }
+
+    #[test]
+    fn parse_subsets() {
+        assert_eq!(Ok(Subset { part: 3, parts: 52 }), Subset::parse("3/52"));
+        assert!(Subset::parse("0/52").is_err());
+        assert!(Subset::parse("53/52").is_err());
+        assert!(Subset::parse("1/0").is_err());
+        assert!(Subset::parse("52").is_err());
+        assert_eq!(52, Subset::parse("auto/52").unwrap().parts);
+
+        assert_eq!(Subset { part: 1, parts: 4 }, Subset::for_week(4, 6));
+        assert_eq!(Subset { part: 2, parts: 4 }, Subset::for_week(4, 7));
+        assert_eq!(Subset { part: 1, parts: 4 }, Subset::for_week(4, 28));
+    }
+
+    #[test]
+    fn cursor_waits_for_earlier_blobs() {
+        let mut progress = CheckProgress::new(false);
+        assert_eq!(None, progress.record(1, 20, Ok((1, 10))));
+        assert_eq!(None, progress.record(2, 30, Err("blob 30: bad".to_owned())));
+        assert_eq!(Some(30), progress.record(0, 10, Ok((2, 5))));
+        assert_eq!(Some(40), progress.record(3, 40, Ok((0, 0))));
+        assert_eq!(4, progress.done());
+
+        let report = progress.into_report();
+        assert_eq!((4, 3, 15), (report.blobs, report.chunks, report.bytes));
+        assert_eq!(vec!["blob 30: bad".to_owned()], report.errors);
+    }
+}
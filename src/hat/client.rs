@@ -0,0 +1,219 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Several clients backing up to one repository.
+//!
+//! Every client takes new blob ids from its own range, and publishes each snapshot it commits
+//! as an append-only delta stored with the blobs. Other clients merge these deltas into their
+//! local index, under the family name `CLIENT/FAMILY`, so they can deduplicate against them.
+//!
+//! Ranges are claimed with a record in the backend naming their owner. A client takes the first
+//! range, from the one its name hashes to, that no other client owns.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use crypto::keys::{self, Keeper};
+use errors::HatError;
+use hat::namespace::Namespace;
+
+
+/// Prefix of the backend names of deltas. Delta names are never as long as blob names.
+const DELTA_PREFIX: &'static str = "hat-delta/";
+/// Prefix of the backend names of the records of who owns which id range.
+const RANGE_PREFIX: &'static str = "hat-range/";
+/// Number of id ranges of 2^40 ids each. Range 0 holds the ids of blobs written before the
+/// repository was shared.
+const RANGES: u32 = 0xffff;
+const MAX_NAME_LEN: usize = 24;
+const NONCE_BYTES: usize = 8;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Client {
+    name: String,
+}
+
+impl Client {
+    /// Names follow the rules for namespace names, and are at most 24 characters long.
+    pub fn new(name: &str) -> Result<Client, HatError> {
+        if name.len() > MAX_NAME_LEN || Namespace::new(name).is_err() {
+            return Err(From::from(format!("Invalid client name: '{}'", name)));
+        }
+        Ok(Client { name: name.to_owned() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The range this client tries to claim first.
+    fn home_range(&self) -> u32 {
+        // FNV-1a, to spread clients over the ranges.
+        let mut h: u32 = 0x811c_9dc5;
+        for b in self.name.bytes() {
+            h = (h ^ b as u32).wrapping_mul(0x0100_0193);
+        }
+        h % RANGES
+    }
+
+    /// Claim an id range for this client in `backend`, or find the one it claimed before.
+    /// Returns the blob ids this client may use.
+    ///
+    /// Backends cannot create a record only if it does not exist, so every claim is read back.
+    /// Two clients claiming a range at the same time can still both read back their own name,
+    /// if each reads before the other writes. The record ends up naming one of them, and the
+    /// other fails its next upload once `BlobIndex::check_id_range_owner` sees that.
+    pub fn claim_blob_id_range<B: StoreBackend>(
+        &self,
+        backend: &B,
+    ) -> Result<(i64, i64), HatError> {
+        let home = self.home_range();
+        for i in 0..RANGES {
+            let range = (home + i) % RANGES;
+            let name = range_name(range);
+            let owned = match backend.retrieve(&name)? {
+                Some(owner) => &owner[..] == self.name.as_bytes(),
+                None => {
+                    backend.store(&name, &CipherText::new(self.name.clone().into_bytes()))?;
+                    backend.flush()?;
+                    backend.retrieve(&name)?.map_or(false, |o| &o[..] == self.name.as_bytes())
+                }
+            };
+            if owned {
+                let slot = 1 + range as i64;
+                return Ok((slot << 40, (slot + 1) << 40));
+            }
+        }
+        Err(From::from("Every blob id range is claimed by another client"))
+    }
+
+    /// The backend record of the id range starting at `start`, as claimed by this client.
+    /// The record holds the name of the client that owns the range.
+    pub fn range_record(&self, start: i64) -> (Vec<u8>, Vec<u8>) {
+        (range_name(((start >> 40) - 1) as u32), self.name.clone().into_bytes())
+    }
+
+    /// The family a snapshot of `family_name` from this client is merged into.
+    pub fn foreign_family(&self, family_name: &str) -> String {
+        format!("{}/{}", self.name, family_name)
+    }
+
+    /// The backend name of this client's delta number `seq`.
+    pub fn delta_name(&self, seq: u64) -> Vec<u8> {
+        format!("{}{}/{:016x}", DELTA_PREFIX, self.name, seq).into_bytes()
+    }
+}
+
+/// The client and sequence number of a delta, or `None` if `name` is not one.
+pub fn parse_delta_name(name: &[u8]) -> Option<(Client, u64)> {
+    let name = match ::std::str::from_utf8(name) {
+        Ok(name) if name.starts_with(DELTA_PREFIX) => &name[DELTA_PREFIX.len()..],
+        _ => return None,
+    };
+    let mut parts = name.splitn(2, '/');
+    let client = parts.next().and_then(|c| Client::new(c).ok());
+    let seq = parts.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+    match (client, seq) {
+        (Some(client), Some(seq)) => Some((client, seq)),
+        _ => None,
+    }
+}
+
+fn range_name(range: u32) -> Vec<u8> {
+    format!("{}{:04x}", RANGE_PREFIX, range).into_bytes()
+}
+
+fn delta_key(keys: &Keeper) -> ::secstr::SecStr {
+    keys.from_nonce(b"hat:delta-key", 32)
+}
+
+/// Encrypt a delta, bound to its backend name.
+pub fn seal_delta(keys: &Keeper, name: &[u8], delta: &[u8]) -> Vec<u8> {
+    let nonce = keys::random_bytes(NONCE_BYTES);
+    let mut out = nonce.unsecure().to_vec();
+    out.extend(Keeper::symmetric_lock(
+        delta,
+        name,
+        nonce.unsecure(),
+        delta_key(keys).unsecure(),
+    ));
+    out
+}
+
+pub fn unseal_delta(keys: &Keeper, name: &[u8], sealed: &[u8]) -> Result<Vec<u8>, HatError> {
+    if sealed.len() < NONCE_BYTES {
+        return Err(From::from("Delta is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+    Keeper::try_symmetric_unlock(delta_key(keys).unsecure(), ciphertext, name, nonce)
+        .ok_or_else(|| From::from("Could not decrypt delta"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use blob;
+    use std::collections::HashMap;
+
+    #[test]
+    fn delta_names() {
+        let client = Client::new("laptop").unwrap();
+        let name = client.delta_name(7);
+        assert_eq!(parse_delta_name(&name), Some((client, 7)));
+        assert_eq!(parse_delta_name(b"idx"), None);
+        assert_eq!(parse_delta_name(b"hat-delta/../1"), None);
+
+        let longest = Client::new(&"x".repeat(MAX_NAME_LEN)).unwrap();
+        assert!(!blob::is_blob_name(&longest.delta_name(u64::max_value())));
+        assert!(Client::new(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn id_ranges_are_disjoint_from_legacy_ids() {
+        let backend = MemoryBackend::new();
+        for name in &["a", "laptop", "server-01"] {
+            let (start, end) = Client::new(name).unwrap().claim_blob_id_range(&backend).unwrap();
+            assert!(start >= 1 << 40);
+            assert_eq!(end - start, 1 << 40);
+        }
+        assert!(!blob::is_blob_name(&range_name(RANGES - 1)));
+    }
+
+    #[test]
+    fn colliding_clients_get_their_own_ranges() {
+        let mut seen = HashMap::new();
+        let (a, b) = (0..)
+            .map(|i| Client::new(&format!("client-{}", i)).unwrap())
+            .filter_map(|c| seen.insert(c.home_range(), c.clone()).map(|prev| (prev, c)))
+            .next()
+            .unwrap();
+
+        let backend = MemoryBackend::new();
+        let range_a = a.claim_blob_id_range(&backend).unwrap();
+        let range_b = b.claim_blob_id_range(&backend).unwrap();
+        assert!(range_a != range_b);
+        // Claims are found again, whichever client asks first.
+        assert_eq!(b.claim_blob_id_range(&backend).unwrap(), range_b);
+        assert_eq!(a.claim_blob_id_range(&backend).unwrap(), range_a);
+    }
+
+    #[test]
+    fn deltas_are_bound_to_their_name() {
+        let keys = Keeper::new_for_testing();
+        let sealed = seal_delta(&keys, b"hat-delta/a/0", b"delta");
+        assert_eq!(unseal_delta(&keys, b"hat-delta/a/0", &sealed).unwrap(), b"delta");
+        assert!(unseal_delta(&keys, b"hat-delta/b/0", &sealed).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What a restore does about files that are already where it writes.
+
+use errors::HatError;
+use key;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+
+/// What to do when a restore finds something at the path of an entry it restores.
+/// Existing directories are always restored into, whatever the policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CollisionPolicy {
+    /// Fail the restore, so that nothing is replaced without asking for it.
+    Refuse,
+    Overwrite,
+    SkipExisting,
+    /// Replace what is there if the snapshot has a later modification time than it.
+    OnlyIfNewer,
+    /// Rename what is there to `NAME.hat-backup` first, or `NAME.hat-backup.N` if taken.
+    BackupExisting,
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> CollisionPolicy {
+        CollisionPolicy::Refuse
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Resolution {
+    Refuse,
+    Replace,
+    Skip,
+    MoveAside,
+}
+
+fn resolve(
+    policy: CollisionPolicy,
+    existing_mtime: i64,
+    restored_mtime: Option<u64>,
+) -> Resolution {
+    match policy {
+        CollisionPolicy::Refuse => Resolution::Refuse,
+        CollisionPolicy::Overwrite => Resolution::Replace,
+        CollisionPolicy::SkipExisting => Resolution::Skip,
+        CollisionPolicy::OnlyIfNewer => {
+            match restored_mtime {
+                Some(mtime) if existing_mtime < 0 || mtime > existing_mtime as u64 => {
+                    Resolution::Replace
+                }
+                _ => Resolution::Skip,
+            }
+        }
+        CollisionPolicy::BackupExisting => Resolution::MoveAside,
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".hat-backup");
+    let mut candidate = path.with_file_name(&name);
+    let mut n = 1;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", n));
+        candidate = path.with_file_name(numbered);
+        n += 1;
+    }
+    candidate
+}
+
+/// Make way for restoring the entry `info` at `path`. Returns false if the entry, and
+/// everything below it for a directory, should not be restored.
+///
+/// Directories are never deleted: replacing one with a file or link fails under any policy
+/// that would remove it.
+pub fn prepare(
+    policy: CollisionPolicy,
+    path: &Path,
+    info: &key::Info,
+    is_dir: bool,
+) -> Result<bool, HatError> {
+    let existing = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(From::from(e)),
+    };
+    if is_dir && existing.is_dir() {
+        return Ok(true);
+    }
+
+    match resolve(policy, existing.mtime(), info.modified_ts_secs) {
+        Resolution::Refuse => {
+            Err(From::from(format!("Refusing to replace existing {}", path.display())))
+        }
+        Resolution::Skip => Ok(false),
+        Resolution::Replace if existing.is_dir() => {
+            Err(From::from(format!("Refusing to replace directory {}", path.display())))
+        }
+        Resolution::Replace => {
+            fs::remove_file(path)?;
+            Ok(true)
+        }
+        Resolution::MoveAside => {
+            fs::rename(path, backup_path(path))?;
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn policies_resolve_collisions() {
+        assert_eq!(Resolution::Refuse, resolve(CollisionPolicy::default(), 10, Some(20)));
+        assert_eq!(Resolution::Replace, resolve(CollisionPolicy::Overwrite, 10, Some(5)));
+        assert_eq!(Resolution::Skip, resolve(CollisionPolicy::SkipExisting, 10, Some(20)));
+        assert_eq!(Resolution::Replace, resolve(CollisionPolicy::OnlyIfNewer, 10, Some(20)));
+        assert_eq!(Resolution::Skip, resolve(CollisionPolicy::OnlyIfNewer, 10, Some(10)));
+        assert_eq!(Resolution::Skip, resolve(CollisionPolicy::OnlyIfNewer, 10, None));
+        assert_eq!(Resolution::MoveAside, resolve(CollisionPolicy::BackupExisting, 10, None));
+    }
+
+    #[test]
+    fn prepare_makes_way_for_restored_files() {
+        let nanos = ::time::precise_time_ns();
+        let dir = ::std::env::temp_dir().join(format!("hat-collision-{}", nanos));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let file = dir.join("file");
+        fs::File::create(&file).unwrap().write_all(b"old").unwrap();
+        let info = key::Info::new(b"file".to_vec(), None);
+
+        assert!(prepare(CollisionPolicy::Refuse, &dir.join("new"), &info, false).unwrap());
+        assert!(prepare(CollisionPolicy::Refuse, &dir.join("sub"), &info, true).unwrap());
+        assert!(prepare(CollisionPolicy::Refuse, &file, &info, false).is_err());
+        assert!(prepare(CollisionPolicy::Overwrite, &dir.join("sub"), &info, false).is_err());
+
+        assert!(!prepare(CollisionPolicy::SkipExisting, &file, &info, false).unwrap());
+        assert!(file.exists());
+
+        assert!(prepare(CollisionPolicy::BackupExisting, &file, &info, false).unwrap());
+        assert!(!file.exists());
+        assert!(dir.join("file.hat-backup").exists());
+        fs::File::create(&file).unwrap();
+        assert!(prepare(CollisionPolicy::BackupExisting, &file, &info, false).unwrap());
+        assert!(dir.join("file.hat-backup.1").exists());
+
+        fs::File::create(&file).unwrap();
+        assert!(prepare(CollisionPolicy::Overwrite, &file, &info, false).unwrap());
+        assert!(!file.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Commands whose output is stored as a file in a snapshot, such as database dumps.
+
+use db::CommandRun;
+use libc;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use util::FileIterator;
+
+
+/// How often a command with a timeout is checked for having finished.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// A command to run while taking a snapshot. Its standard output is stored as the file `name`
+/// at the top of the snapshot, replacing the output of the previous run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommandSource {
+    /// Name of the file holding the output.
+    pub name: String,
+    /// Run with `sh -c`.
+    pub command: String,
+    /// Kill the command if it runs longer than this.
+    pub timeout: Option<Duration>,
+}
+
+impl CommandSource {
+    pub fn new(name: String, command: String) -> CommandSource {
+        CommandSource {
+            name: name,
+            command: command,
+            timeout: None,
+        }
+    }
+}
+
+/// A started command, until it has ended and been waited for.
+pub struct RunningCommand {
+    name: String,
+    command: String,
+    supervisor: thread::JoinHandle<(Option<i32>, bool)>,
+}
+
+/// Start the command of `source`. Returns its output, and the command to `finish` once the
+/// output has been read.
+pub fn spawn(source: &CommandSource) -> io::Result<(FileIterator, RunningCommand)> {
+    if source.name.is_empty() || source.name.contains('/') || source.name == "." ||
+        source.name == ".."
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not a file name: {:?}", source.name),
+        ));
+    }
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&source.command).stdin(Stdio::null()).stdout(Stdio::piped());
+    // In a process group of its own, so that a timeout kills everything the shell started.
+    unsafe {
+        command.before_exec(|| if libc::setpgid(0, 0) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        });
+    }
+    let mut child = command.spawn()?;
+    let output = child.stdout.take().expect("Output is piped");
+    let timeout = source.timeout;
+    let supervisor = thread::spawn(move || supervise(child, timeout));
+    Ok((
+        FileIterator::Pipe(output),
+        RunningCommand {
+            name: source.name.clone(),
+            command: source.command.clone(),
+            supervisor: supervisor,
+        },
+    ))
+}
+
+impl RunningCommand {
+    /// Wait for the command to end, and report how it did.
+    pub fn finish(self) -> CommandRun {
+        let (exit_code, timed_out) = self.supervisor.join().unwrap_or((None, false));
+        CommandRun {
+            name: self.name,
+            command: self.command,
+            exit_code: exit_code,
+            timed_out: timed_out,
+        }
+    }
+}
+
+/// Wait for `child` to exit, killing it once `timeout` has passed. Killing the command also
+/// closes its output, so that whoever reads it is not left waiting. Returns the exit code and
+/// whether the command timed out.
+fn supervise(mut child: Child, timeout: Option<Duration>) -> (Option<i32>, bool) {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut timed_out = false;
+    if let Some(deadline) = deadline {
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return (status.code(), false),
+                Ok(None) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL_MS))
+                }
+                Ok(None) => {
+                    warn!("Command timed out, killing it");
+                    // The group outlives the exited shell until it has been waited for.
+                    let group = -(child.id() as libc::pid_t);
+                    timed_out = unsafe { libc::kill(group, libc::SIGKILL) } == 0;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Could not check on command: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    match child.wait() {
+        Ok(status) => (status.code(), timed_out),
+        Err(e) => {
+            warn!("Could not wait for command: {}", e);
+            (None, timed_out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn run(source: &CommandSource) -> (Vec<u8>, CommandRun) {
+        let (mut output, running) = spawn(source).unwrap();
+        let mut data = vec![];
+        output.read_to_end(&mut data).unwrap();
+        (data, running.finish())
+    }
+
+    #[test]
+    fn output_and_exit_code_are_captured() {
+        let source = CommandSource::new("out".to_owned(), "echo hello; exit 3".to_owned());
+        let (data, run) = run(&source);
+        assert_eq!(data, b"hello\n");
+        assert_eq!(run.exit_code, Some(3));
+        assert!(!run.succeeded());
+    }
+
+    #[test]
+    fn commands_are_killed_after_their_timeout() {
+        let source = CommandSource {
+            timeout: Some(Duration::from_millis(200)),
+            ..CommandSource::new("out".to_owned(), "echo partial; sleep 10; echo done".to_owned())
+        };
+        let start = Instant::now();
+        let (data, run) = run(&source);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(data, b"partial\n");
+        assert!(run.timed_out);
+        assert!(!run.succeeded());
+    }
+
+    #[test]
+    fn names_must_be_plain_file_names() {
+        for name in &["", "..", "a/b"] {
+            let source = CommandSource::new(name.to_string(), "true".to_owned());
+            assert!(spawn(&source).is_err());
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CSV exports of the local indexes, for analyzing deduplication in other tools.
+
+use db;
+use errors::HatError;
+use hex::ToHex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+
+
+/// Write one line per hash: its id, hash, height, leaf type, tag, blob id, chunk length,
+/// number of children and number of hashes referring to it.
+pub fn write_hashes<W: Write>(rows: Vec<db::HashRow>, out: &mut W) -> Result<(), HatError> {
+    let mut parents: HashMap<u64, u64> = HashMap::new();
+    for row in &rows {
+        for &child in &row.childs {
+            *parents.entry(child).or_insert(0) += 1;
+        }
+    }
+
+    writeln!(out, "id,hash,height,leaf_type,tag,blob_id,length,children,parents")?;
+    for row in rows {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            row.id,
+            row.hash.to_hex(),
+            row.height,
+            row.leaf_type,
+            row.tag,
+            row.blob_id,
+            row.length,
+            row.childs.len(),
+            parents.get(&row.id).cloned().unwrap_or(0)
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_entries_header<W: Write>(out: &mut W) -> Result<(), HatError> {
+    writeln!(out, "family,path,size,hash")?;
+    Ok(())
+}
+
+/// Write one line per entry of the key index of `family`: the family, path, size and hash of
+/// the data. Size and hash are empty for directories.
+pub fn write_entries<W: Write>(
+    family: &str,
+    entries: Vec<(Vec<u8>, Option<u64>, Option<Vec<u8>>)>,
+    out: &mut W,
+) -> Result<(), HatError> {
+    for (path, size, hash) in entries {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            quote(family),
+            quote(&String::from_utf8_lossy(&path)),
+            size.map_or(String::new(), |s| s.to_string()),
+            hash.map_or(String::new(), |h| h.to_hex())
+        )?;
+    }
+    Ok(())
+}
+
+/// Quote a CSV field if needed.
+fn quote(field: &str) -> Cow<str> {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        let mut out = vec![];
+        write_entries(
+            "fam",
+            vec![
+                (b"a,b".to_vec(), Some(3), Some(vec![0xab])),
+                (b"dir".to_vec(), None, None),
+            ],
+            &mut out,
+        ).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "fam,\"a,b\",3,ab\nfam,dir,,\n");
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reports of files stored more than once in a snapshot.
+
+use db;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+
+/// Distinct paths in a snapshot whose data has the same hash, and so is stored only once.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DuplicateFiles {
+    pub hash: Vec<u8>,
+    pub size: u64,
+    /// Sorted by path.
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateFiles {
+    /// The bytes that would have been stored again without deduplication.
+    pub fn savings(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// Group the recorded paths of snapshot `snapshot_id` by the hash of their data.
+/// Returns the groups of at least two non-empty files, largest savings first.
+pub fn group(rows: Vec<db::SnapshotPath>, snapshot_id: u64) -> Vec<DuplicateFiles> {
+    let id = snapshot_id as i64;
+    let mut by_hash: HashMap<Vec<u8>, DuplicateFiles> = HashMap::new();
+    for row in rows {
+        if row.first_snapshot > id || row.last_snapshot < id {
+            continue;
+        }
+        let (hash, size) = match (row.hash, row.size) {
+            (Some(hash), Some(size)) if size > 0 => (hash, size as u64),
+            _ => continue,
+        };
+        by_hash
+            .entry(hash.clone())
+            .or_insert_with(|| {
+                DuplicateFiles {
+                    hash: hash,
+                    size: size,
+                    paths: vec![],
+                }
+            })
+            .paths
+            .push(PathBuf::from(OsString::from_vec(row.path)));
+    }
+
+    let mut groups: Vec<DuplicateFiles> = by_hash
+        .into_iter()
+        .map(|(_, group)| group)
+        .filter(|group| group.paths.len() > 1)
+        .collect();
+    for group in &mut groups {
+        group.paths.sort();
+    }
+    groups.sort_by(|a, b| {
+        b.savings().cmp(&a.savings()).then_with(|| a.paths.cmp(&b.paths))
+    });
+    groups
+}
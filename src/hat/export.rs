@@ -0,0 +1,179 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of snapshot subtrees as tar streams.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker::Content;
+use key;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tar;
+
+
+/// Find the entry at `path` below the snapshot directory `root`.
+/// The snapshot root itself has no entry of its own.
+pub fn resolve<B: StoreBackend>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    root: hash::tree::HashRef,
+    path: &Path,
+) -> Result<(Option<key::Entry>, Content), HatError> {
+    let mut current = (None, Content::Dir(root));
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name.as_bytes(),
+            Component::RootDir | Component::CurDir => continue,
+            _ => {
+                return Err(From::from(
+                    format!("Unsupported path in snapshot: {}", path.display()),
+                ))
+            }
+        };
+        let dir_ref = match current.1 {
+            Content::Dir(dir_ref) => dir_ref,
+            _ => return Err(From::from(format!("Not a directory: {}", path.display()))),
+        };
+        current = family
+            .fetch_dir_data(dir_ref, backend.clone())?
+            .into_iter()
+            .find(|&(ref entry, _)| &entry.info.name[..] == name)
+            .map(|(entry, content)| (Some(entry), content))
+            .ok_or_else(|| {
+                format!("No such file or directory in snapshot: {}", path.display())
+            })?;
+    }
+    Ok(current)
+}
+
+/// Write `content` and everything below it to `out` as a tar stream.
+/// Archive paths start at the name of `entry`, or below it for the snapshot root.
+pub fn write_tar<B: StoreBackend, W: Write>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    entry: Option<key::Entry>,
+    content: Content,
+    out: W,
+) -> Result<W, HatError> {
+    let mut builder = tar::Builder::new(out);
+
+    let mut stack = vec![];
+    match (entry, content) {
+        (Some(entry), content) => stack.push((entry_path(None, &entry), entry, content)),
+        (None, Content::Dir(dir_ref)) => {
+            for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+                stack.push((entry_path(None, &entry), entry, content));
+            }
+        }
+        (None, _) => return Err(From::from("Snapshot root is not a directory")),
+    }
+    stack.reverse();
+
+    while let Some((path, entry, content)) = stack.pop() {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(
+            entry.info.permissions.as_ref().map(|p| p.mode() & 0o7777).unwrap_or(0o644),
+        );
+        header.set_mtime(entry.info.modified_ts_secs.unwrap_or(0));
+        header.set_uid(entry.info.user_id.unwrap_or(0));
+        header.set_gid(entry.info.group_id.unwrap_or(0));
+
+        match content {
+            Content::Dir(dir_ref) => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, &path, io::empty())?;
+
+                let children = family.fetch_dir_data(dir_ref, backend.clone())?;
+                for (child, content) in children.into_iter().rev() {
+                    stack.push((entry_path(Some(&path), &child), child, content));
+                }
+            }
+            Content::Data(data_ref) => {
+                // The header needs the size up front. The recorded length may not match
+                // what was read during the snapshot, so use the size of the stored data.
+                let size = hash::tree::tree_size(backend.clone(), data_ref.clone())?.bytes;
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(size);
+                let chunks = hash::tree::LeafIterator::new(backend.clone(), data_ref)?;
+                builder.append_data(&mut header, &path, ChunkReader::new(chunks))?;
+            }
+            Content::Link(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                header.set_link_name(&target)?;
+                builder.append_data(&mut header, &path, io::empty())?;
+            }
+        }
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+fn entry_path(parent: Option<&Path>, entry: &key::Entry) -> PathBuf {
+    let name = OsStr::from_bytes(&entry.info.name[..]);
+    match parent {
+        Some(parent) => parent.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// Reads the leaf chunks of a hash tree as one stream, fetching them as they are needed.
+pub struct ChunkReader<B: StoreBackend> {
+    chunks: Option<hash::tree::LeafIterator<key::HashStoreBackend<B>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<B: StoreBackend> ChunkReader<B> {
+    pub fn new(chunks: Option<hash::tree::LeafIterator<key::HashStoreBackend<B>>>) -> Self {
+        ChunkReader {
+            chunks: chunks,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl<B: StoreBackend> Read for ChunkReader<B> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let next = match self.chunks.as_mut() {
+                Some(it) => {
+                    it.try_next().map_err(|e| {
+                        io::Error::new(io::ErrorKind::Other, e.to_string())
+                    })?
+                }
+                None => None,
+            };
+            match next {
+                Some(chunk) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let len = ::std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
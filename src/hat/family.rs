@@ -16,18 +16,28 @@
 use backend::StoreBackend;
 use blob;
 use capnp;
-use errors::HatError;
+use db;
+use errors::{HatError, SourceError};
 use hash;
+use hat::command_source::{self, CommandSource};
 use hat::insert_path_handler::InsertPathHandler;
+use hat::jobs::JobControl;
+use hat::metadata::MetadataPolicy;
+use hat::source_filter::SourceFilter;
 use hat::walker;
 use key;
 use root_capnp;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, PathBuf};
 use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use std::vec;
 use util::{FileIterator, FnBox, PathHandler};
-use filetime;
 
 fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
 where
@@ -168,6 +178,25 @@ pub mod recover {
     }
 }
 
+/// Read the entries of the directory stored in the tree `dir_hash`.
+pub fn fetch_dir_data<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
+    dir_hash: hash::tree::HashRef,
+    backend: HTB,
+) -> Result<Vec<(key::Entry, walker::Content)>, HatError> {
+    let mut it = hash::tree::LeafIterator::new(backend, dir_hash)?.expect(
+        "unable to open dir",
+    );
+
+    let mut out = Vec::new();
+    while let Some(chunk) = it.try_next()? {
+        if !chunk.is_empty() {
+            parse_dir_data(&chunk[..], &mut out)?;
+        }
+    }
+
+    Ok(out.into_iter().map(|f| (f.meta, f.hash_ref)).collect())
+}
+
 fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<(), HatError> {
     if chunk.is_empty() {
         return Ok(());
@@ -219,7 +248,10 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
             info: key::Info::read(f.get_info()?.borrow())?,
             data: data,
             parent_id: None,
-            node_id: Some(f.get_id()),
+            node_id: match f.get_id() {
+                0 => None,
+                id => Some(id),
+            },
         };
 
         out.push(walker::FileEntry {
@@ -230,10 +262,55 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
     Ok(())
 }
 
+/// Number of entries fetched from the key store at a time when listing a directory.
+const LIST_PAGE_LEN: usize = 1024;
+
+/// Lists a directory of the key store a page at a time, so that large directories are never
+/// held in memory all at once.
+pub struct DirListing<B: StoreBackend> {
+    key_store: key::StoreProcess<FileIterator, B>,
+    dir_id: Option<u64>,
+    after: Option<Vec<u8>>,
+    page: vec::IntoIter<key::DirElem<B>>,
+    done: bool,
+}
+
+impl<B: StoreBackend> Iterator for DirListing<B> {
+    type Item = Result<key::DirElem<B>, HatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(elem) = self.page.next() {
+            return Some(Ok(elem));
+        }
+        if self.done {
+            return None;
+        }
+        let msg = key::Msg::ListDirPage(self.dir_id, self.after.take(), LIST_PAGE_LEN);
+        match self.key_store.send_reply(msg) {
+            Ok(key::Reply::ListPage(elems, next)) => {
+                self.after = next;
+                self.done = next.is_none();
+                self.page = elems.into_iter();
+                self.page.next().map(Ok)
+            }
+            Ok(_) => {
+                self.done = true;
+                Some(Err(From::from("Unexpected result from key store")))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(From::from(e)))
+            }
+        }
+    }
+}
+
 pub struct Family<B> {
     pub name: String,
     pub key_store: key::Store<B>,
     pub key_store_process: Vec<key::StoreProcess<FileIterator, B>>,
+    /// How long to wait for the key stores to flush their data to the backend, if limited.
+    pub backend_timeout: Option<Duration>,
 }
 impl<B: StoreBackend> Clone for Family<B> {
     fn clone(&self) -> Family<B> {
@@ -241,22 +318,136 @@ impl<B: StoreBackend> Clone for Family<B> {
             name: self.name.clone(),
             key_store: self.key_store.clone(),
             key_store_process: self.key_store_process.clone(),
+            backend_timeout: self.backend_timeout,
         }
     }
 }
 
 impl<B: StoreBackend> Family<B> {
-    pub fn snapshot_dir(&self, dir: PathBuf) {
-        let handler = InsertPathHandler::new(self.key_store_process.clone());
+    pub fn snapshot_dir(&self, dir: PathBuf, filter: &SourceFilter) -> Result<(), HatError> {
+        self.snapshot_dir_mirrored(&[], dir, filter).remove(0)
+    }
+
+    /// Snapshot `dir` into this family and into each of `mirrors` in the same pass, reading
+    /// every file only once. Returns one result for this family followed by one per mirror;
+    /// a failing family does not stop the others.
+    pub fn snapshot_dir_mirrored(
+        &self,
+        mirrors: &[&Family<B>],
+        dir: PathBuf,
+        filter: &SourceFilter,
+    ) -> Vec<Result<(), HatError>> {
+        self.snapshot_dirs(mirrors, dir, filter, None)
+    }
+
+    /// Like `snapshot_dir`, reporting progress to `control` and stopping if it is cancelled.
+    pub fn snapshot_dir_controlled(
+        &self,
+        dir: PathBuf,
+        filter: &SourceFilter,
+        control: Arc<JobControl>,
+    ) -> Result<(), HatError> {
+        self.snapshot_dirs(&[], dir, filter, Some(control)).remove(0)
+    }
+
+    /// Snapshot only the entries of `files` below `dir`, e.g. the output of `find -newer` or a
+    /// build manifest, without walking the rest of the tree. Relative paths are taken from
+    /// `dir`, and listed directories are recorded without their contents. The directories
+    /// above each entry are recorded too, and everything else is kept from the previous
+    /// snapshot of the family.
+    pub fn snapshot_listed(
+        &self,
+        dir: PathBuf,
+        files: &[PathBuf],
+        filter: &SourceFilter,
+    ) -> Result<(), HatError> {
+        let dir = fs::canonicalize(&dir).map_err(|e| {
+            SourceError::from(format!("Could not read {}: {}", dir.display(), e))
+        })?;
+        let handler = InsertPathHandler::new(vec![self.key_store_process.clone()], filter.clone());
+
+        // The ids of the directories recorded so far, as payload for their children.
+        let mut dirs: HashMap<PathBuf, Vec<Option<u64>>> = HashMap::new();
+        for file in files {
+            let path = dir.join(file);
+            if !path.starts_with(&dir) ||
+                path.components().any(|c| c == Component::ParentDir)
+            {
+                warn!("Skipping '{}': not below {}", file.display(), dir.display());
+                self.key_store.io_stats().add_skipped_files(1);
+                continue;
+            }
+
+            let mut parents = vec![None];
+            let mut current = PathBuf::from("/");
+            let mut found = true;
+            for name in path.parent().into_iter().flat_map(|p| p.iter()).skip(1) {
+                current.push(name);
+                if let Some(ids) = dirs.get(&current) {
+                    parents = ids.clone();
+                    continue;
+                }
+                match handler.handle_path(&parents, &current) {
+                    Some(ids) => {
+                        dirs.insert(current.clone(), ids.clone());
+                        parents = ids;
+                    }
+                    None => {
+                        found = false;
+                        break;
+                    }
+                }
+            }
+            if found && path != current {
+                if let Some(ids) = handler.handle_path(&parents, &path) {
+                    dirs.insert(path, ids);
+                }
+            }
+        }
+        self.key_store.io_stats().add_skipped_files(handler.skipped());
+
+        if let Some(e) = handler.take_error(0) {
+            return Err(From::from(e));
+        }
+        // Commit without cleaning up, to keep the entries that were not listed.
+        match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(None)) {
+            Ok(key::Reply::Ok) => Ok(()),
+            _ => panic!("Unexpected reply from keystore"),
+        }
+    }
+
+    fn snapshot_dirs(
+        &self,
+        mirrors: &[&Family<B>],
+        dir: PathBuf,
+        filter: &SourceFilter,
+        control: Option<Arc<JobControl>>,
+    ) -> Vec<Result<(), HatError>> {
+        let families: Vec<&Family<B>> =
+            Some(self).into_iter().chain(mirrors.iter().cloned()).collect();
+        let mut handler = InsertPathHandler::new(
+            families.iter().map(|f| f.key_store_process.clone()).collect(),
+            filter.clone(),
+        );
+        handler.set_control(control);
 
         let mut parent_path = PathBuf::from("/");
 
-        let dir = fs::canonicalize(dir).unwrap();
+        let dir = match fs::canonicalize(&dir) {
+            Ok(dir) => dir,
+            Err(e) => {
+                let msg = format!("Could not read {}: {}", dir.display(), e);
+                return families
+                    .iter()
+                    .map(|_| Err(From::from(SourceError::from(msg.clone()))))
+                    .collect();
+            }
+        };
         info!("Committing: {}", dir.display());
         assert!(dir.is_absolute());
 
         let mut bailout = false;
-        let mut parent = None;
+        let mut parents = vec![None; families.len()];
         let mut inside_non_dir = false;
         for name in dir.iter().map(PathBuf::from).filter(|p| !p.has_root()) {
             if inside_non_dir {
@@ -270,8 +461,8 @@ impl<B: StoreBackend> Family<B> {
                 break;
             }
             parent_path.push(name);
-            if let Some(new_parent) = handler.handle_path(&parent, &parent_path) {
-                parent = new_parent;
+            if let Some(new_parents) = handler.handle_path(&parents, &parent_path) {
+                parents = new_parents;
             } else {
                 // Trigger warning if this is not the final component.
                 // If this is the final component, we just commit'ed a file or link, which is OK.
@@ -279,23 +470,56 @@ impl<B: StoreBackend> Family<B> {
             }
         }
 
-        if !bailout && dir.is_dir() {
-            handler.recurse(PathBuf::from(&dir), parent);
+        let descend = !bailout && dir.is_dir();
+        if descend {
+            if filter.one_file_system {
+                let dev = match fs::metadata(&dir) {
+                    Ok(meta) => meta.dev(),
+                    Err(e) => {
+                        let msg = format!("Could not read {}: {}", dir.display(), e);
+                        return families
+                            .iter()
+                            .map(|_| Err(From::from(SourceError::from(msg.clone()))))
+                            .collect();
+                    }
+                };
+                handler.set_root_device(dev);
+            }
+            handler.recurse(PathBuf::from(&dir), parents.clone());
 
-            match self.key_store_process[0].send_reply(
-                key::Msg::CommitReservedNodes(
-                    Some(parent),
-                ),
-            ) {
-                Ok(key::Reply::Ok) => (),
-                _ => panic!("Unexpected reply from keystore"),
+            let (files, bytes) = handler.excluded();
+            if files > 0 {
+                info!("Excluded by filters: {} files ({} bytes)", files, bytes);
             }
-        } else {
-            match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(None)) {
-                Ok(key::Reply::Ok) => (),
-                _ => panic!("Unexpected reply from keystore"),
+        }
+        for (path, reason) in handler.excluded_dirs() {
+            info!("Excluded directory with {}: {}", reason, path.display());
+            let rel = path.strip_prefix("/").unwrap_or(&path).as_os_str().as_bytes();
+            for family in &families {
+                if let Err(e) = family.key_store.add_exclusion(rel, &reason) {
+                    warn!("Could not record excluded directory {}: {}", path.display(), e);
+                }
             }
         }
+        for family in &families {
+            family.key_store.io_stats().add_skipped_files(handler.skipped());
+        }
+
+        families
+            .iter()
+            .enumerate()
+            .map(|(i, family)| {
+                if let Some(e) = handler.take_error(i) {
+                    // Leave the reserved nodes uncommitted; the next snapshot picks up from here.
+                    return Err(From::from(e));
+                }
+                let top = if descend { Some(parents[i]) } else { None };
+                match family.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(top)) {
+                    Ok(key::Reply::Ok) => Ok(()),
+                    _ => panic!("Unexpected reply from keystore"),
+                }
+            })
+            .collect()
     }
 
     pub fn snapshot_direct(
@@ -310,7 +534,7 @@ impl<B: StoreBackend> Family<B> {
             Some(Box::new(move |()| contents) as Box<FnBox<(), _>>)
         };
         let ks = self.key_store_process.iter().last().unwrap();
-        let id = match ks.send_reply(key::Msg::Insert(file, f))? {
+        let id = match ks.send_reply(key::Msg::Insert(file, f, key::Chunking::Default))? {
             key::Reply::Id(id) => id,
             _ => return Err(From::from("Unexpected reply from key store")),
         };
@@ -321,9 +545,34 @@ impl<B: StoreBackend> Family<B> {
         Ok(id)
     }
 
+    /// Run the command of `source` and insert its output as a file at the top of the snapshot.
+    /// The output is kept even if the command fails; how it ended is returned, and recorded
+    /// with the next snapshot.
+    pub fn snapshot_command(&self, source: &CommandSource) -> Result<db::CommandRun, HatError> {
+        let (output, running) = command_source::spawn(source).map_err(|e| {
+            SourceError::from(format!("Could not run {}: {}", source.name, e))
+        })?;
+        let entry = key::Entry::new(
+            None,
+            source.name.clone().into_bytes(),
+            key::Data::FilePlaceholder,
+            None,
+        );
+        let inserted = self.snapshot_direct(entry, false, Some(output));
+        // Wait for the command even if its output could not be stored.
+        let run = running.finish();
+        inserted?;
+        self.key_store.add_command_run(&run)?;
+        Ok(run)
+    }
+
     pub fn flush(&self) -> Result<(), HatError> {
         for ks in &self.key_store_process {
-            if let key::Reply::FlushOk = ks.send_reply(key::Msg::Flush)? {
+            let reply = match self.backend_timeout {
+                Some(timeout) => ks.send_reply_timeout(key::Msg::Flush, timeout)?,
+                None => ks.send_reply(key::Msg::Flush)?,
+            };
+            if let key::Reply::FlushOk = reply {
                 continue;
             }
             return Err(From::from("Unexpected reply from key store"));
@@ -331,18 +580,27 @@ impl<B: StoreBackend> Family<B> {
         Ok(())
     }
 
-    pub fn write_file_chunks<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
+    /// Write the data of a file tree to `fd`, calling `progress` with the size of each chunk.
+    /// Chunks that cannot be fetched, or do not match their hash, fail the write.
+    pub fn write_file_chunks<HTB, F>(
         &self,
         fd: &mut fs::File,
-        tree: hash::tree::LeafIterator<HTB>,
-    ) {
-        for chunk in tree {
+        mut chunks: hash::tree::LeafIterator<HTB>,
+        mut progress: F,
+    ) -> Result<(), HatError>
+    where
+        HTB: hash::tree::HashTreeBackend<Err = key::MsgError>,
+        F: FnMut(usize),
+    {
+        while let Some(chunk) = chunks.try_next()? {
             try_a_few_times_then_panic(
                 || fd.write_all(&chunk[..]).is_ok(),
                 "Could not write chunk.",
             );
+            progress(chunk.len());
         }
         try_a_few_times_then_panic(|| fd.flush().is_ok(), "Could not flush file.");
+        Ok(())
     }
 
     // FIXME(jos): Merge with hat's checkout_in_dir which checks out snapshots.
@@ -351,41 +609,38 @@ impl<B: StoreBackend> Family<B> {
         &self,
         output_dir: PathBuf,
         dir_id: Option<u64>,
+        policy: &MetadataPolicy,
     ) -> Result<(), HatError> {
         let mut path = output_dir;
-        for (entry, _ref, read_fn_opt) in self.list_from_key_store(dir_id)? {
+        for elem in self.list_pages_from_key_store(dir_id) {
+            let (entry, _ref, read_fn_opt) = elem?;
             // Extend directory with filename:
             path.push(str::from_utf8(&entry.info.name[..]).unwrap());
 
-            match entry.data {
+            let is_symlink = match entry.data {
                 key::Data::DirPlaceholder => {
                     // This is a directory, recurse!
                     fs::create_dir_all(&path).unwrap();
-                    self.checkout_in_dir(path.clone(), entry.node_id)?;
+                    self.checkout_in_dir(path.clone(), entry.node_id, policy)?;
+                    false
                 }
                 key::Data::FilePlaceholder => {
                     // This is a file, write it
                     let mut fd = fs::File::create(&path).unwrap();
                     if let Some(tree) = read_fn_opt.expect("File has data").init()? {
-                        self.write_file_chunks(&mut fd, tree);
+                        self.write_file_chunks(&mut fd, tree, |_| ())?;
                     }
+                    false
                 }
                 key::Data::Symlink(link_path) => {
                     use std::os::unix::fs::symlink;
-                    symlink(link_path, &path).unwrap()
+                    symlink(link_path, &path).unwrap();
+                    true
                 }
                 _ => unreachable!("Unexpected data entry"),
-            }
+            };
 
-            if let Some(perms) = entry.info.permissions {
-                fs::set_permissions(&path, perms)?;
-            }
-
-            if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
-                let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
-                let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
-                filetime::set_file_times(&path, atime, mtime).unwrap();
-            }
+            policy.apply(&path, &entry.info, is_symlink)?;
 
             // Prepare for next filename:
             path.pop();
@@ -406,23 +661,23 @@ impl<B: StoreBackend> Family<B> {
         }
     }
 
+    /// List the directory `dir_id` of the key store a page at a time.
+    pub fn list_pages_from_key_store(&self, dir_id: Option<u64>) -> DirListing<B> {
+        DirListing {
+            key_store: self.key_store_process.iter().last().unwrap().clone(),
+            dir_id: dir_id,
+            after: None,
+            page: vec![].into_iter(),
+            done: false,
+        }
+    }
+
     pub fn fetch_dir_data<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
         &self,
         dir_hash: hash::tree::HashRef,
         backend: HTB,
     ) -> Result<Vec<(key::Entry, walker::Content)>, HatError> {
-        let it = hash::tree::LeafIterator::new(backend, dir_hash)?.expect(
-            "unable to open dir",
-        );
-
-        let mut out = Vec::new();
-        for chunk in it {
-            if !chunk.is_empty() {
-                parse_dir_data(&chunk[..], &mut out)?;
-            }
-        }
-
-        Ok(out.into_iter().map(|f| (f.meta, f.hash_ref)).collect())
+        fetch_dir_data(dir_hash, backend)
     }
 
     pub fn commit<F>(&mut self, top_hash_fn: &F) -> Result<hash::tree::HashRef, HatError>
@@ -447,7 +702,7 @@ impl<B: StoreBackend> Family<B> {
     {
 
         let files_at_a_time = 1024;
-        let mut it = self.list_from_key_store(dir_id)?.into_iter();
+        let mut it = self.list_pages_from_key_store(dir_id);
 
         loop {
             let mut current_msg_is_empty = true;
@@ -457,15 +712,17 @@ impl<B: StoreBackend> Family<B> {
                 let files_root = file_block_msg.init_root::<root_capnp::file_list::Builder>();
                 let mut files = files_root.init_files(files_at_a_time as u32);
 
-                for (idx, (entry, data_ref, _data_res_open)) in
-                    it.by_ref().take(files_at_a_time).enumerate()
-                {
+                for (idx, elem) in it.by_ref().take(files_at_a_time).enumerate() {
+                    let (entry, data_ref, _data_res_open) = elem?;
                     assert!(idx < files_at_a_time);
 
                     current_msg_is_empty = false;
                     let mut file_msg = files.borrow().get(idx as u32);
 
-                    file_msg.set_id(entry.node_id.unwrap_or(0));
+                    // Node ids are local to the key index. Leaving them out, and listing in name
+                    // order, makes equal directories serialize to equal chunks, so that they are
+                    // deduplicated like file data, also across families and after a recovery.
+                    file_msg.set_id(0);
 
                     {
                         entry.info.populate_msg(
@@ -491,16 +748,31 @@ impl<B: StoreBackend> Family<B> {
                             top_hash_fn(&hash::Hash { bytes: href.hash.bytes });
                         }
                         key::Data::DirPlaceholder => {
-                            // This is a directory, recurse!
-                            let mut inner_tree =
-                                self.key_store.hash_tree_writer(blob::LeafType::TreeList);
-                            self.commit_to_tree(
-                                &mut inner_tree,
-                                entry.node_id,
-                                top_hash_fn,
-                            )?;
-                            // Store a reference for the sub-tree in our tree:
-                            let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
+                            let node_id = entry.node_id.expect("Directory without id");
+                            // Nothing below an unchanged directory needs to be listed or
+                            // hashed again, but its hashes are still registered.
+                            let dir_hash_ref = match self.key_store.unchanged_dir_hash(node_id)? {
+                                Some((dir_hash_ref, hashes)) => {
+                                    for hash in &hashes {
+                                        top_hash_fn(hash);
+                                    }
+                                    dir_hash_ref
+                                }
+                                None => {
+                                    // This is a directory, recurse!
+                                    let mut inner_tree =
+                                        self.key_store.hash_tree_writer(blob::LeafType::TreeList);
+                                    self.commit_to_tree(
+                                        &mut inner_tree,
+                                        entry.node_id,
+                                        top_hash_fn,
+                                    )?;
+                                    // Store a reference for the sub-tree in our tree:
+                                    let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
+                                    self.key_store.set_dir_hash(node_id, &dir_hash_ref)?;
+                                    dir_hash_ref
+                                }
+                            };
 
                             let mut hash_ref_msg = capnp::message::Builder::new_default();
                             let mut hash_ref_root =
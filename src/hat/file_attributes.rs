@@ -0,0 +1,212 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Linux file capabilities and inode flags (as set by `setcap` and `chattr`). Other systems
+//! have neither, and report them as unsupported.
+
+use libc;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+
+/// The file cannot be modified, renamed, deleted or linked to (`chattr +i`).
+pub const FLAG_IMMUTABLE: u32 = 0x10;
+/// The file can only be opened for appending (`chattr +a`).
+pub const FLAG_APPEND_ONLY: u32 = 0x20;
+/// The file should not be backed up (`chattr +d`).
+pub const FLAG_NODUMP: u32 = 0x40;
+
+/// Inode flags that are recorded in snapshots. Other flags describe how the filesystem stores
+/// the file and are left to the filesystem the file is restored to.
+pub const RECORDED_FLAGS: u32 = FLAG_IMMUTABLE | FLAG_APPEND_ONLY | FLAG_NODUMP;
+
+#[cfg(target_os = "linux")]
+const CAPABILITY_XATTR: &'static [u8] = b"security.capability\0";
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Whether an error means that the filesystem does not support the attribute at all.
+pub fn is_unsupported(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => true,
+        _ => false,
+    }
+}
+
+fn with_fd<T, F>(path: &Path, f: F) -> io::Result<T>
+where
+    F: FnOnce(libc::c_int) -> io::Result<T>,
+{
+    let c_path = c_path(path)?;
+    let flags = libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::open(c_path.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = f(fd);
+    unsafe { libc::close(fd) };
+    res
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOSYS)
+}
+
+#[cfg(target_os = "linux")]
+fn get_flags(fd: libc::c_int) -> io::Result<u32> {
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS as _, &mut flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+#[cfg(target_os = "linux")]
+fn set_flags(fd: libc::c_int, flags: u32) -> io::Result<()> {
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_SETFLAGS as _, &flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_flags(_fd: libc::c_int) -> io::Result<u32> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_flags(_fd: libc::c_int, _flags: u32) -> io::Result<()> {
+    Err(unsupported())
+}
+
+fn recorded_flags(res: io::Result<u32>) -> io::Result<Option<u32>> {
+    match res {
+        Ok(flags) => Ok(Some(flags & RECORDED_FLAGS)),
+        Err(ref e) if is_unsupported(e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the recorded inode flags of the regular file or directory at `path`.
+/// Returns `None` if the filesystem has no such flags.
+pub fn read_flags(path: &Path) -> io::Result<Option<u32>> {
+    recorded_flags(with_fd(path, get_flags))
+}
+
+/// Like `read_flags`, for an open file or directory.
+pub fn read_flags_of(file: &fs::File) -> io::Result<Option<u32>> {
+    recorded_flags(get_flags(file.as_raw_fd()))
+}
+
+/// Set the recorded inode flags of `path` to `flags`, keeping its other flags.
+/// Needs `CAP_LINUX_IMMUTABLE` to change the immutable or append-only flags.
+pub fn write_flags(path: &Path, flags: u32) -> io::Result<()> {
+    with_fd(path, |fd| {
+        let current = get_flags(fd)?;
+        let wanted = (current & !RECORDED_FLAGS) | (flags & RECORDED_FLAGS);
+        if wanted == current {
+            return Ok(());
+        }
+        set_flags(fd, wanted)
+    })
+}
+
+/// Read an attribute with `get`, which fills the buffer it is given like `getxattr`.
+#[cfg(target_os = "linux")]
+fn read_xattr<F>(get: F) -> io::Result<Option<Vec<u8>>>
+where
+    F: Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let size = get(::std::ptr::null_mut(), 0);
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ if is_unsupported(&e) => Ok(None),
+                _ => Err(e),
+            };
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let read = get(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(Some(buf));
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+        // The attribute changed between the two calls; try again.
+    }
+}
+
+/// Read the file capabilities of `path`, if it has any.
+#[cfg(target_os = "linux")]
+pub fn read_capabilities(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let c_path = c_path(path)?;
+    let name = CAPABILITY_XATTR.as_ptr() as *const libc::c_char;
+    read_xattr(|buf, len| unsafe { libc::lgetxattr(c_path.as_ptr(), name, buf, len) })
+}
+
+/// Like `read_capabilities`, for an open file.
+#[cfg(target_os = "linux")]
+pub fn read_capabilities_of(file: &fs::File) -> io::Result<Option<Vec<u8>>> {
+    let name = CAPABILITY_XATTR.as_ptr() as *const libc::c_char;
+    read_xattr(|buf, len| unsafe { libc::fgetxattr(file.as_raw_fd(), name, buf, len) })
+}
+
+/// Set the file capabilities of `path`. Needs `CAP_SETFCAP`.
+#[cfg(target_os = "linux")]
+pub fn write_capabilities(path: &Path, caps: &[u8]) -> io::Result<()> {
+    let c_path = c_path(path)?;
+    let res = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            CAPABILITY_XATTR.as_ptr() as *const libc::c_char,
+            caps.as_ptr() as *const libc::c_void,
+            caps.len(),
+            0,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_capabilities(_path: &Path) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_capabilities_of(_file: &fs::File) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn write_capabilities(_path: &Path, _caps: &[u8]) -> io::Result<()> {
+    Err(unsupported())
+}
@@ -0,0 +1,149 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Filesystem freezing for consistent snapshots of filesystems without snapshot support.
+
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+
+// From linux/fs.h, which libc does not cover.
+const FIFREEZE: libc::Ioctl = libc::_IOWR::<libc::c_int>(b'X' as u32, 119);
+const FITHAW: libc::Ioctl = libc::_IOWR::<libc::c_int>(b'X' as u32, 120);
+
+/// Exit status of the guard process when it thawed the filesystem because time ran out.
+const TIMED_OUT: libc::c_int = 2;
+
+/// Keeps a filesystem frozen until thawed or dropped.
+///
+/// A forked guard process thaws the filesystem when the timeout expires or when this process
+/// exits, even if it crashes, so the filesystem is never left frozen.
+pub struct FreezeGuard {
+    fd: libc::c_int,
+    pipe: libc::c_int,
+    guard_pid: libc::pid_t,
+    thawed: bool,
+}
+
+fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+impl FreezeGuard {
+    /// Freeze the filesystem mounted at `mountpoint` for at most `timeout`.
+    pub fn freeze(mountpoint: &Path, timeout: Duration) -> io::Result<FreezeGuard> {
+        let c_path = CString::new(mountpoint.as_os_str().as_bytes())?;
+        let max_ms = libc::c_int::max_value() as u64;
+        let timeout_ms = timeout.as_secs().saturating_mul(1000).min(max_ms) as libc::c_int;
+
+        let fd = check(unsafe {
+            libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC)
+        })?;
+
+        let mut fds = [0; 2];
+        if let Err(e) = check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let (read_end, write_end) = (fds[0], fds[1]);
+        let close_all = || unsafe {
+            libc::close(read_end);
+            libc::close(write_end);
+            libc::close(fd);
+        };
+
+        // Freeze before forking, so that the guard never thaws a freeze that is not ours.
+        if let Err(e) = check(unsafe { libc::ioctl(fd, FIFREEZE, 0) }) {
+            close_all();
+            return Err(e);
+        }
+
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            // Guard process: only async-signal-safe calls from here on.
+            unsafe {
+                libc::close(write_end);
+                let mut poll_fd = libc::pollfd {
+                    fd: read_end,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // Returns when the parent closes the pipe (on thaw or exit) or on timeout.
+                let mut ready = libc::poll(&mut poll_fd, 1, timeout_ms);
+                while ready < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                    ready = libc::poll(&mut poll_fd, 1, timeout_ms);
+                }
+                libc::ioctl(fd, FITHAW, 0);
+                libc::_exit(if ready == 0 { TIMED_OUT } else { 0 });
+            }
+        } else if pid < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::ioctl(fd, FITHAW, 0) };
+            close_all();
+            return Err(e);
+        }
+
+        unsafe { libc::close(read_end) };
+        Ok(FreezeGuard {
+            fd: fd,
+            pipe: write_end,
+            guard_pid: pid,
+            thawed: false,
+        })
+    }
+
+    /// Thaw the filesystem. Fails if the timeout thawed it earlier, as writes may then have
+    /// changed it while it was read.
+    pub fn thaw(mut self) -> io::Result<()> {
+        if self.release() {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "The freeze timed out"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Thaw the filesystem and wait for the guard process. Returns whether the guard had
+    /// thawed it already because the timeout expired.
+    fn release(&mut self) -> bool {
+        if self.thawed {
+            return false;
+        }
+        self.thawed = true;
+        let mut status = 0;
+        unsafe {
+            // Thawing fails harmlessly if the guard already did it after a timeout.
+            libc::ioctl(self.fd, FITHAW, 0);
+            libc::close(self.pipe);
+            libc::waitpid(self.guard_pid, &mut status, 0);
+            libc::close(self.fd);
+        }
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == TIMED_OUT
+    }
+}
+
+impl Drop for FreezeGuard {
+    fn drop(&mut self) {
+        if self.release() {
+            warn!("The freeze timed out before the filesystem was thawed");
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read-only estimation of what garbage collection would reclaim.
+
+use db;
+use std::collections::{HashMap, HashSet};
+
+
+/// Data that is kept alive by a single snapshot and nothing else.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PinnedData {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub chunks: u64,
+    /// Sum of the stored chunk lengths.
+    pub bytes: u64,
+}
+
+/// What purging the trash and collecting garbage would remove.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcPlan {
+    /// Trashed snapshots that are past their grace period.
+    pub purged_snapshots: Vec<(String, u64)>,
+    pub dead_chunks: u64,
+    pub dead_blobs: u64,
+    /// Size of the blobs that would be deleted. Blobs that are only partly unused are kept.
+    pub reclaimable_bytes: u64,
+    /// Data only reachable from a single snapshot, largest first.
+    pub pinned: Vec<PinnedData>,
+}
+
+/// A snapshot and the hash ids it holds references to.
+pub struct SnapshotRefs {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub purge: bool,
+    pub ids: Vec<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Owner {
+    Single(usize),
+    Shared,
+}
+
+pub fn plan(
+    nodes: Vec<db::HashNode>,
+    blobs: Vec<(i64, u64)>,
+    snapshots: Vec<SnapshotRefs>,
+) -> GcPlan {
+    let nodes: HashMap<u64, db::HashNode> = nodes.into_iter().map(|n| (n.id, n)).collect();
+
+    // Label every live hash with the snapshot keeping it alive, or as shared if there are
+    // several. A label only ever changes from single to shared, so this terminates.
+    let mut owners = HashMap::new();
+    let mut queue = vec![];
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        if !snapshot.purge {
+            queue.extend(snapshot.ids.iter().map(|&id| (id, Owner::Single(i))));
+        }
+    }
+    while let Some((id, owner)) = queue.pop() {
+        let merged = match owners.get(&id).cloned() {
+            None => owner,
+            Some(Owner::Shared) => continue,
+            Some(current) if current == owner => continue,
+            Some(_) => Owner::Shared,
+        };
+        owners.insert(id, merged);
+        if let Some(node) = nodes.get(&id) {
+            queue.extend(node.childs.iter().map(|&child| (child, merged)));
+        }
+    }
+
+    let mut plan = GcPlan::default();
+
+    let mut live_blobs = HashSet::new();
+    for node in nodes.values() {
+        if owners.contains_key(&node.id) {
+            live_blobs.insert(node.blob_id);
+        } else {
+            plan.dead_chunks += 1;
+        }
+    }
+    for (id, size) in blobs {
+        if !live_blobs.contains(&id) {
+            plan.dead_blobs += 1;
+            plan.reclaimable_bytes += size;
+        }
+    }
+
+    let mut pinned: Vec<PinnedData> = snapshots
+        .iter()
+        .map(|s| {
+            PinnedData {
+                family_name: s.family_name.clone(),
+                snapshot_id: s.snapshot_id,
+                chunks: 0,
+                bytes: 0,
+            }
+        })
+        .collect();
+    for (id, owner) in owners {
+        if let Owner::Single(i) = owner {
+            pinned[i].chunks += 1;
+            pinned[i].bytes += nodes.get(&id).map_or(0, |n| n.length);
+        }
+    }
+    plan.pinned = pinned.into_iter().filter(|p| p.chunks > 0).collect();
+    plan.pinned.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+    plan.purged_snapshots = snapshots
+        .into_iter()
+        .filter(|s| s.purge)
+        .map(|s| (s.family_name, s.snapshot_id))
+        .collect();
+
+    plan
+}
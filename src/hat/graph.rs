@@ -0,0 +1,282 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The snapshots of each family as a chain, with how much data consecutive snapshots share,
+//! for visualizing growth and sharing over time.
+
+use chrono;
+use db;
+use errors::HatError;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use util::human;
+
+
+/// A complete snapshot and the data it refers to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GraphSnapshot {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub chunks: u64,
+    /// Sum of the stored chunk lengths.
+    pub bytes: u64,
+}
+
+/// The data a snapshot has in common with the snapshot before it in its family.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GraphLink {
+    pub family_name: String,
+    pub from_id: u64,
+    pub to_id: u64,
+    pub shared_chunks: u64,
+    pub shared_bytes: u64,
+    /// Share of the data of the later snapshot that the earlier one has as well, in percent.
+    pub shared_percent: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotGraph {
+    /// Ordered by family, then by snapshot id.
+    pub snapshots: Vec<GraphSnapshot>,
+    pub links: Vec<GraphLink>,
+}
+
+/// A snapshot and the hash ids it holds references to.
+pub struct SnapshotRefs {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub ids: Vec<u64>,
+}
+
+pub fn build(nodes: Vec<db::HashNode>, mut snapshots: Vec<SnapshotRefs>) -> SnapshotGraph {
+    let nodes: HashMap<u64, db::HashNode> = nodes.into_iter().map(|n| (n.id, n)).collect();
+    snapshots.sort_by(|a, b| {
+        (&a.family_name, a.snapshot_id).cmp(&(&b.family_name, b.snapshot_id))
+    });
+
+    let mut graph = SnapshotGraph::default();
+    // Only the chunks of the previous snapshot are kept around, to compare the next one with.
+    let mut previous: Option<(String, u64, HashSet<u64>)> = None;
+    for snapshot in snapshots {
+        let mut seen = HashSet::new();
+        let mut queue = snapshot.ids;
+        while let Some(id) = queue.pop() {
+            if let Some(node) = nodes.get(&id) {
+                if seen.insert(id) {
+                    queue.extend(node.childs.iter().cloned());
+                }
+            }
+        }
+        let bytes: u64 = seen.iter().map(|id| nodes[id].length).sum();
+
+        if let Some((ref family_name, from_id, ref before)) = previous {
+            if *family_name == snapshot.family_name {
+                let shared = seen.intersection(before);
+                let (shared_chunks, shared_bytes) =
+                    shared.fold((0, 0), |(c, b), id| (c + 1, b + nodes[id].length));
+                graph.links.push(GraphLink {
+                    family_name: family_name.clone(),
+                    from_id: from_id,
+                    to_id: snapshot.snapshot_id,
+                    shared_chunks: shared_chunks,
+                    shared_bytes: shared_bytes,
+                    shared_percent: if bytes == 0 { 100 } else { shared_bytes * 100 / bytes },
+                });
+            }
+        }
+
+        graph.snapshots.push(GraphSnapshot {
+            family_name: snapshot.family_name.clone(),
+            snapshot_id: snapshot.snapshot_id,
+            created: snapshot.created,
+            chunks: seen.len() as u64,
+            bytes: bytes,
+        });
+        previous = Some((snapshot.family_name, snapshot.snapshot_id, seen));
+    }
+    graph
+}
+
+fn quoted(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn node_name(family_name: &str, snapshot_id: u64) -> String {
+    quoted(&format!("{}/{}", family_name, snapshot_id))
+}
+
+/// Write the graph in the Graphviz dot language, with one cluster per family.
+pub fn write_dot<W: Write>(graph: &SnapshotGraph, out: &mut W) -> Result<(), HatError> {
+    writeln!(out, "digraph snapshots {{")?;
+    writeln!(out, "  rankdir=LR;")?;
+    writeln!(out, "  node [shape=box];")?;
+    let mut family: Option<&str> = None;
+    for (i, snapshot) in graph.snapshots.iter().enumerate() {
+        if family != Some(&snapshot.family_name[..]) {
+            if family.is_some() {
+                writeln!(out, "  }}")?;
+            }
+            family = Some(&snapshot.family_name[..]);
+            writeln!(out, "  subgraph cluster_{} {{", i)?;
+            writeln!(out, "    label={};", quoted(&snapshot.family_name))?;
+        }
+        let label = format!(
+            "#{}\n{}\n{}",
+            snapshot.snapshot_id,
+            snapshot.created.format("%Y-%m-%d %H:%M"),
+            human::bytes(snapshot.bytes)
+        );
+        writeln!(
+            out,
+            "    {} [label={}];",
+            node_name(&snapshot.family_name, snapshot.snapshot_id),
+            quoted(&label)
+        )?;
+    }
+    if family.is_some() {
+        writeln!(out, "  }}")?;
+    }
+    for link in &graph.links {
+        writeln!(
+            out,
+            "  {} -> {} [label=\"{}% shared\"];",
+            node_name(&link.family_name, link.from_id),
+            node_name(&link.family_name, link.to_id),
+            link.shared_percent
+        )?;
+    }
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Write the graph as a JSON object with a list of snapshots and a list of links.
+pub fn write_json<W: Write>(graph: &SnapshotGraph, out: &mut W) -> Result<(), HatError> {
+    writeln!(out, "{{\"snapshots\": [")?;
+    for (i, snapshot) in graph.snapshots.iter().enumerate() {
+        writeln!(
+            out,
+            "  {{\"family\": {}, \"id\": {}, \"created\": {}, \"chunks\": {}, \"bytes\": {}}}{}",
+            quoted(&snapshot.family_name),
+            snapshot.snapshot_id,
+            quoted(&snapshot.created.to_rfc3339()),
+            snapshot.chunks,
+            snapshot.bytes,
+            if i + 1 < graph.snapshots.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "], \"links\": [")?;
+    for (i, link) in graph.links.iter().enumerate() {
+        writeln!(
+            out,
+            "  {{\"family\": {}, \"from\": {}, \"to\": {}, \"shared_chunks\": {}, \
+             \"shared_bytes\": {}, \"shared_percent\": {}}}{}",
+            quoted(&link.family_name),
+            link.from_id,
+            link.to_id,
+            link.shared_chunks,
+            link.shared_bytes,
+            link.shared_percent,
+            if i + 1 < graph.links.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "]}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u64, childs: Vec<u64>, length: u64) -> db::HashNode {
+        db::HashNode {
+            id: id,
+            childs: childs,
+            blob_id: 1,
+            length: length,
+        }
+    }
+
+    fn refs(family_name: &str, snapshot_id: u64, ids: Vec<u64>) -> SnapshotRefs {
+        SnapshotRefs {
+            family_name: family_name.to_owned(),
+            snapshot_id: snapshot_id,
+            created: chrono::Utc::now(),
+            ids: ids,
+        }
+    }
+
+    #[test]
+    fn consecutive_snapshots_are_linked() {
+        let nodes = vec![
+            node(1, vec![3, 4], 10),
+            node(2, vec![4, 5], 10),
+            node(3, vec![], 100),
+            node(4, vec![], 80),
+            node(5, vec![], 100),
+        ];
+        let graph = build(
+            nodes,
+            vec![refs("b", 1, vec![4]), refs("a", 2, vec![2]), refs("a", 1, vec![1])],
+        );
+
+        let order: Vec<_> = graph
+            .snapshots
+            .iter()
+            .map(|s| (&s.family_name[..], s.snapshot_id, s.bytes))
+            .collect();
+        assert_eq!(vec![("a", 1, 190), ("a", 2, 190), ("b", 1, 80)], order);
+        assert_eq!(
+            graph.links,
+            vec![
+                GraphLink {
+                    family_name: "a".to_owned(),
+                    from_id: 1,
+                    to_id: 2,
+                    shared_chunks: 1,
+                    shared_bytes: 80,
+                    shared_percent: 42,
+                },
+            ]
+        );
+
+        let mut dot = vec![];
+        write_dot(&graph, &mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("\"a/1\" -> \"a/2\" [label=\"42% shared\"];"));
+        assert_eq!(2, dot.matches("subgraph").count());
+
+        let mut json = vec![];
+        write_json(&graph, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("\"from\": 1, \"to\": 2, \"shared_chunks\": 1"));
+    }
+
+    #[test]
+    fn names_are_quoted() {
+        assert_eq!("\"a \\\"b\\\"\\\\\\n\"", quoted("a \"b\"\\\n"));
+    }
+}
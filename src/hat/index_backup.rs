@@ -0,0 +1,218 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Encrypted copies of the local indexes, stored with the blobs for disaster recovery.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use crypto::keys::{self, Keeper};
+use errors::HatError;
+use hat::sealed_index;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime};
+use tar;
+
+
+/// The backend name of the latest index backup. Blob names are longer, so this never clashes.
+pub const BLOB_NAME: &'static [u8] = b"idx";
+
+const NONCE_BYTES: usize = 8;
+const AD: &'static [u8] = b"hat-index-backup";
+
+fn key(keys: &Keeper) -> ::secstr::SecStr {
+    keys.from_nonce(b"hat:index-backup-key", 32)
+}
+
+/// Store the index files in `index_dir` as one encrypted blob, replacing the previous backup.
+/// The indexes should be flushed first.
+pub fn upload<B: StoreBackend>(
+    backend: &B,
+    keys: &Keeper,
+    index_dir: &Path,
+) -> Result<u64, HatError> {
+    let mut builder = tar::Builder::new(vec![]);
+    for entry in fs::read_dir(index_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        if !entry.file_type()?.is_file() || !sealed_index::is_index_file(&name) {
+            continue;
+        }
+        let mut data = vec![];
+        fs::File::open(&path)?.read_to_end(&mut data)?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o600);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, &name, &data[..])?;
+    }
+    let archive = builder.into_inner()?;
+
+    let nonce = keys::random_bytes(NONCE_BYTES);
+    let mut sealed = nonce.unsecure().to_vec();
+    sealed.extend(Keeper::symmetric_lock(
+        &archive,
+        AD,
+        nonce.unsecure(),
+        key(keys).unsecure(),
+    ));
+    let size = sealed.len() as u64;
+    backend.store(BLOB_NAME, &CipherText::new(sealed))?;
+    backend.flush()?;
+    Ok(size)
+}
+
+/// Whether the backup in `backend` is at least `interval` old at `now`, or missing. Backends
+/// that do not record when blobs were stored always get a new backup.
+pub fn is_due<B: StoreBackend>(
+    backend: &B,
+    interval: Duration,
+    now: SystemTime,
+) -> Result<bool, HatError> {
+    let stored_at = match backend.stat(BLOB_NAME)? {
+        Some(stat) => stat.stored_at,
+        None => return Ok(true),
+    };
+    Ok(match stored_at.map(|at| now.duration_since(at)) {
+        Some(Ok(age)) => age >= interval,
+        // Stored in the future, by a clock that is ahead of ours.
+        Some(Err(_)) => false,
+        None => true,
+    })
+}
+
+/// Restore the latest index backup into `index_dir`, which must not have an index yet.
+/// Returns the names of the restored files.
+pub fn download<B: StoreBackend>(
+    backend: &B,
+    keys: &Keeper,
+    index_dir: &Path,
+) -> Result<Vec<String>, HatError> {
+    if index_dir.join("hash_index.sqlite3").exists() || sealed_index::is_sealed(index_dir)? {
+        return Err(From::from(
+            format!("{} already has an index", index_dir.display()),
+        ));
+    }
+    let sealed = backend.retrieve(BLOB_NAME)?.ok_or(
+        "The repository has no index backup",
+    )?;
+    if sealed.len() < NONCE_BYTES {
+        return Err(From::from("Index backup is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+    let archive = Keeper::try_symmetric_unlock(key(keys).unsecure(), ciphertext, AD, nonce)
+        .ok_or("Could not decrypt the index backup (wrong key?)")?;
+
+    fs::create_dir_all(index_dir)?;
+    let mut names = vec![];
+    let mut reader = tar::Archive::new(&archive[..]);
+    for entry in reader.entries()? {
+        let mut entry = entry?;
+        let name = {
+            let path = entry.path()?;
+            match (path.components().next(), path.components().count()) {
+                (Some(Component::Normal(name)), 1) => name.to_string_lossy().into_owned(),
+                _ => {
+                    return Err(From::from(
+                        format!("Unexpected path in index backup: {}", path.display()),
+                    ))
+                }
+            }
+        };
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(
+            index_dir.join(&name),
+        )?;
+        io::copy(&mut entry, &mut file)?;
+        file.flush()?;
+        names.push(name);
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use crypto::keys::MasterKey;
+    use std::path::PathBuf;
+    use time;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = time::precise_time_ns();
+        ::std::env::temp_dir().join(format!("hat-index-backup-{}-{}", name, nanos))
+    }
+
+    #[test]
+    fn restores_index_files() {
+        let keys = Keeper::new_for_testing();
+        let backend = MemoryBackend::new();
+        let source = temp_dir("source");
+        fs::create_dir_all(source.join("namespaces")).unwrap();
+        fs::File::create(source.join("hash_index.sqlite3")).unwrap().write_all(b"hashes").unwrap();
+        fs::File::create(source.join("family")).unwrap().write_all(b"keys").unwrap();
+        fs::File::create(source.join("lock")).unwrap();
+        upload(&backend, &keys, &source).unwrap();
+
+        let target = temp_dir("target");
+        let mut names = download(&backend, &keys, &target).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["family".to_owned(), "hash_index.sqlite3".to_owned()]);
+        let mut data = vec![];
+        fs::File::open(target.join("family")).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"keys");
+
+        // Never overwrite an existing index, and never decrypt with another key.
+        assert!(download(&backend, &keys, &target).is_err());
+        let other = Keeper::from_master_key(&MasterKey::from_passphrase("other"));
+        assert!(download(&backend, &other, &temp_dir("other")).is_err());
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn backups_are_due_after_the_interval() {
+        use backend::FileBackend;
+
+        let keys = Keeper::new_for_testing();
+        let source = temp_dir("interval-source");
+        let blobs = temp_dir("interval-blobs");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&blobs).unwrap();
+        fs::File::create(source.join("hash_index.sqlite3")).unwrap();
+        let backend = FileBackend::new(blobs.clone());
+        let hour = Duration::from_secs(3600);
+        let now = SystemTime::now();
+
+        assert!(is_due(&backend, hour, now).unwrap());
+        upload(&backend, &keys, &source).unwrap();
+        assert!(!is_due(&backend, hour, now).unwrap());
+        assert!(is_due(&backend, hour, now + hour * 2).unwrap());
+
+        // Without the time of the last backup, every commit makes one.
+        let memory = MemoryBackend::new();
+        upload(&memory, &keys, &source).unwrap();
+        assert!(is_due(&memory, hour, now).unwrap());
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&blobs).unwrap();
+    }
+}
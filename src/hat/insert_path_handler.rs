@@ -14,24 +14,37 @@
 
 
 use backend::StoreBackend;
+use hat::file_attributes;
+use hat::jobs::JobControl;
+use hat::source_filter::SourceFilter;
 use key;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
 use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::str;
-use std::sync::{Mutex, atomic};
+use std::sync::{Arc, Mutex, atomic};
+use std::thread;
 use time;
-use util::{FileIterator, PathHandler, SyncPool};
+use util::{FileIterator, FnBox, PathHandler, SyncPool, Throttle, tee};
+use util::dirfd::{DirFd, Location};
 
 struct FileEntry {
     key_entry: key::Entry,
     metadata: fs::Metadata,
     full_path: PathBuf,
+    location: Location,
 }
 
 impl FileEntry {
-    fn new(full_path: PathBuf, parent: Option<u64>) -> Result<FileEntry, Box<Error>> {
+    /// Read the entry at `location`, whose full path is `full_path`.
+    fn new(
+        location: Location,
+        full_path: PathBuf,
+        parent: Option<u64>,
+    ) -> Result<FileEntry, Box<Error>> {
         debug!("FileEntry::new({:?})", full_path);
 
         let filename_opt = full_path.file_name().and_then(|n| n.to_str()).map(|s| {
@@ -39,22 +52,27 @@ impl FileEntry {
         });
 
         if let Some(filename) = filename_opt {
-            let meta = fs::symlink_metadata(&full_path)?;
+            let meta = location.symlink_metadata()?;
             let data = if meta.is_file() {
                 key::Data::FilePlaceholder
             } else if meta.is_dir() {
                 key::Data::DirPlaceholder
             } else if meta.file_type().is_symlink() {
-                let path = fs::read_link(&full_path)?;
+                let path = location.read_link()?;
                 key::Data::Symlink(path)
             } else {
                 // Unsupported file type. Skipping.
                 return Err(From::from(format!("unknown file kind")));
             };
+            let mut key_entry = key::Entry::new(parent, filename, data, Some(&meta));
+            if !meta.file_type().is_symlink() {
+                read_attributes(&location, &full_path, &mut key_entry.info);
+            }
             Ok(FileEntry {
-                key_entry: key::Entry::new(parent, filename, data, Some(&meta)),
+                key_entry: key_entry,
                 metadata: meta,
                 full_path: full_path,
+                location: location,
             })
         } else {
             Err(From::from("Could not parse filename."[..].to_owned()))
@@ -69,31 +87,185 @@ impl FileEntry {
     }
 }
 
+/// Record the capabilities and inode flags of the file at `location`, reported as `path`.
+/// Failing to read them is not a reason to skip the file.
+fn read_attributes(location: &Location, path: &PathBuf, info: &mut key::Info) {
+    let (caps, flags) = match *location {
+        Location::Path(ref path) => {
+            (file_attributes::read_capabilities(path), file_attributes::read_flags(path))
+        }
+        Location::At(ref dir, ref name) => {
+            match dir.open_file(name) {
+                Ok(file) => {
+                    (
+                        file_attributes::read_capabilities_of(&file),
+                        file_attributes::read_flags_of(&file),
+                    )
+                }
+                Err(e) => {
+                    warn!("Could not read attributes of '{}': {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+    };
+    match caps {
+        Ok(caps) => info.capabilities = caps,
+        Err(e) => warn!("Could not read capabilities of '{}': {}", path.display(), e),
+    }
+    match flags {
+        Ok(flags) => info.attribute_flags = flags,
+        Err(e) => warn!("Could not read attributes of '{}': {}", path.display(), e),
+    }
+}
+
+/// An entry that passed the filters and is about to be inserted.
+struct Candidate {
+    file_entry: FileEntry,
+    chunking: key::Chunking,
+    other_device: bool,
+    first_visit: bool,
+}
+
+type OpenFile = Box<FnBox<(), Option<FileIterator>>>;
+
+fn throttled(it: FileIterator, throttle: Option<Arc<Throttle>>) -> FileIterator {
+    match throttle {
+        Some(throttle) => FileIterator::Throttled(Box::new(it), throttle),
+        None => it,
+    }
+}
+
+fn open_file(
+    path: PathBuf,
+    location: Location,
+    mmap: bool,
+    throttle: Option<Arc<Throttle>>,
+) -> OpenFile {
+    Box::new(move |()| {
+        let opened = location.open().and_then(|file| FileIterator::from_file(file, mmap));
+        match opened {
+            Err(e) => {
+                warn!("Skipping '{}': {}", path.display(), e);
+                None
+            }
+            Ok(it) => {
+                let it = FileIterator::watched(it, path, location, mmap);
+                Some(throttled(it, throttle))
+            }
+        }
+    })
+}
+
+fn open_tee(
+    path: PathBuf,
+    location: Location,
+    reader: tee::TeeReader,
+    throttle: Option<Arc<Throttle>>,
+) -> OpenFile {
+    Box::new(move |()| match reader.open() {
+        Err(e) => {
+            warn!("Skipping '{}': {}", path.display(), e.to_string());
+            None
+        }
+        Ok(()) => {
+            let it = FileIterator::watched(FileIterator::Tee(reader), path, location, false);
+            Some(throttled(it, throttle))
+        }
+    })
+}
+
+/// Inserts the visited paths into one or more repositories at the same time.
+///
+/// The payload holds the id of the parent directory in each repository. Files are read once
+/// and fed to all repositories together. A repository that fails is left behind, while the
+/// others continue.
 pub struct InsertPathHandler<B: StoreBackend> {
     count: atomic::AtomicIsize,
     last_print: Mutex<time::Timespec>,
-    key_store: SyncPool<key::StoreProcess<FileIterator, B>>,
+    key_stores: Vec<SyncPool<key::StoreProcess<FileIterator, B>>>,
+    errors: Vec<Mutex<Option<key::MsgError>>>,
+    filter: SourceFilter,
+    excluded: Mutex<(u64, u64)>,
+    skipped: atomic::AtomicUsize,
+    excluded_dirs: Mutex<Vec<(PathBuf, String)>>,
+    root_device: Option<u64>,
+    visited_dirs: Mutex<HashSet<(u64, u64)>>,
+    control: Option<Arc<JobControl>>,
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl<B: StoreBackend> InsertPathHandler<B> {
-    pub fn new(key_stores: Vec<key::StoreProcess<FileIterator, B>>) -> InsertPathHandler<B> {
+    /// Insert into the repositories of `key_stores`, one list of processes per repository.
+    pub fn new(
+        key_stores: Vec<Vec<key::StoreProcess<FileIterator, B>>>,
+        filter: SourceFilter,
+    ) -> InsertPathHandler<B> {
+        let throttle = filter.read_limit.map(|limit| Arc::new(Throttle::new(limit)));
         InsertPathHandler {
             count: atomic::AtomicIsize::new(0),
             last_print: Mutex::new(time::now().to_timespec()),
-            key_store: SyncPool::new(key_stores),
+            errors: key_stores.iter().map(|_| Mutex::new(None)).collect(),
+            key_stores: key_stores.into_iter().map(SyncPool::new).collect(),
+            filter: filter,
+            excluded: Mutex::new((0, 0)),
+            skipped: atomic::AtomicUsize::new(0),
+            excluded_dirs: Mutex::new(vec![]),
+            root_device: None,
+            visited_dirs: Mutex::new(HashSet::new()),
+            control: None,
+            throttle: throttle,
         }
     }
-}
 
-impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
-    type DirItem = fs::DirEntry;
-    type DirIter = fs::ReadDir;
+    /// Report progress to `control`, and stop inserting when it is cancelled.
+    pub fn set_control(&mut self, control: Option<Arc<JobControl>>) {
+        self.control = control;
+    }
 
-    fn read_dir(&self, path: &PathBuf) -> io::Result<Self::DirIter> {
-        fs::read_dir(path)
+    /// Directories skipped because of a marker file, along with the marker.
+    pub fn excluded_dirs(&self) -> Vec<(PathBuf, String)> {
+        self.excluded_dirs.lock().unwrap().clone()
+    }
+
+    /// Only descend into directories on this device.
+    pub fn set_root_device(&mut self, dev: u64) {
+        self.root_device = Some(dev);
+    }
+
+    /// Number of files and bytes skipped by the source filter.
+    pub fn excluded(&self) -> (u64, u64) {
+        *self.excluded.lock().unwrap()
+    }
+
+    /// Number of entries skipped because they could not be read. Files that cannot be opened
+    /// are counted by the key stores instead.
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(atomic::Ordering::SeqCst)
+    }
+
+    /// The error that stopped inserting into the repository at `index`, if any.
+    pub fn take_error(&self, index: usize) -> Option<key::MsgError> {
+        self.errors[index].lock().unwrap().take()
     }
 
-    fn handle_path(&self, parent: &Option<u64>, path: &PathBuf) -> Option<Option<u64>> {
+    fn is_live(&self, index: usize) -> bool {
+        self.errors[index].lock().unwrap().is_none()
+    }
+
+    /// The marker file that excludes the directory `entry`, if any.
+    fn excluding_marker(&self, entry: &FileEntry) -> Option<String> {
+        match entry.location {
+            Location::Path(ref path) => self.filter.excluded_by_marker(path),
+            Location::At(ref dir, ref name) => {
+                // A directory that cannot be opened has no contents to exclude.
+                dir.open_dir(name).ok().and_then(|sub| self.filter.excluded_by_marker_in(&sub))
+            }
+        }
+    }
+
+    /// Read the entry at `location`, unless it is excluded or cannot be read.
+    fn prepare(&self, path: &PathBuf, location: Location) -> Option<Candidate> {
         let count = self.count.fetch_add(1, atomic::Ordering::SeqCst) + 1;
 
         if count % 16 == 0 {
@@ -106,44 +278,259 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
             }
         }
 
-        match FileEntry::new(path.clone(), *parent) {
+        match FileEntry::new(location, path.clone(), None) {
             Err(e) => {
                 println!("Skipping '{}': {}", path.display(), e);
+                self.skipped.fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            Ok(ref file_entry) if !self.filter.includes(&file_entry.metadata) => {
+                debug!("Excluded by filter: {}", path.display());
+                let mut excluded = self.excluded.lock().unwrap();
+                excluded.0 += 1;
+                excluded.1 += file_entry.metadata.len();
+            }
+            Ok(ref file_entry) if self.filter
+                .excluded_by_flags(file_entry.key_entry.info.attribute_flags) => {
+                debug!("Excluded by nodump attribute: {}", path.display());
+                if file_entry.is_directory() {
+                    self.excluded_dirs.lock().unwrap().push(
+                        (path.clone(), "nodump attribute".to_owned()),
+                    );
+                } else {
+                    let mut excluded = self.excluded.lock().unwrap();
+                    excluded.0 += 1;
+                    excluded.1 += file_entry.metadata.len();
+                }
             }
             Ok(file_entry) => {
-                let is_file = file_entry.is_file();
-                let is_directory = file_entry.is_directory();
-                let local_root = path.clone();
-                let full_path = file_entry.full_path.clone();
-
-                let ks = self.key_store.lock().unwrap();
-                match ks.send_reply(key::Msg::Insert(
-                    file_entry.key_entry,
-                    if is_file {
-                        Some(Box::new(move |()| {
-                        match FileIterator::new(&full_path) {
-                            Err(e) => {
-                                println!("Skipping '{}': {}", local_root.display(), e.to_string());
-                                None
-                            }
-                            Ok(it) => Some(it),
-                        }
-                    }))
-                    } else {
-                        None
-                    },
-                )) {
-                    Ok(key::Reply::Id(id)) => {
-                        if is_directory {
-                            return Some(Some(id));
-                        }
+                if file_entry.is_directory() {
+                    if let Some(marker) = self.excluding_marker(&file_entry) {
+                        debug!("Excluded by {}: {}", marker, path.display());
+                        self.excluded_dirs.lock().unwrap().push((path.clone(), marker));
+                        return None;
                     }
-                    Err(e) => panic!("Error from key store: {:?}", e),
-                    _ => panic!("Unexpected reply from key store."),
                 }
+                if let Some(ref control) = self.control {
+                    let is_file = file_entry.is_file();
+                    control.add_entry(if is_file { file_entry.metadata.len() } else { 0 });
+                }
+                let other_device = match self.root_device {
+                    Some(dev) => file_entry.metadata.dev() != dev,
+                    None => false,
+                };
+                let dir_id = (file_entry.metadata.dev(), file_entry.metadata.ino());
+                let first_visit = file_entry.is_directory() &&
+                    self.visited_dirs.lock().unwrap().insert(dir_id);
+                return Some(Candidate {
+                    chunking: self.filter.chunking(path),
+                    other_device: other_device,
+                    first_visit: first_visit,
+                    file_entry: file_entry,
+                });
             }
         }
 
         None
     }
+
+    /// Send one message to each repository and collect the replies. Messages with shared file
+    /// reads are sent concurrently, so that all repositories read the files together.
+    fn insert_all(
+        &self,
+        msgs: Vec<(usize, key::Msg<FileIterator>)>,
+        concurrent: bool,
+    ) -> Vec<(usize, Result<key::Reply<B>, key::MsgError>)> {
+        let guards: Vec<_> = self.key_stores.iter().map(|ks| ks.lock().unwrap()).collect();
+        if !concurrent {
+            return msgs.into_iter()
+                .map(|(i, msg)| (i, guards[i].send_reply(msg)))
+                .collect();
+        }
+
+        let mut msgs = msgs.into_iter();
+        let first = msgs.next();
+        let threads: Vec<_> = msgs.map(|(i, msg)| {
+            let ks = (*guards[i]).clone();
+            (i, thread::spawn(move || ks.send_reply(msg)))
+        }).collect();
+        let mut replies: Vec<_> = first
+            .into_iter()
+            .map(|(i, msg)| (i, guards[i].send_reply(msg)))
+            .collect();
+        for (i, t) in threads {
+            let reply = t.join().unwrap_or_else(|_| Err(From::from("Insert panicked")));
+            replies.push((i, reply));
+        }
+        replies
+    }
+}
+
+impl<B: StoreBackend> PathHandler<Vec<Option<u64>>> for InsertPathHandler<B> {
+    type DirItem = fs::DirEntry;
+    type DirIter = fs::ReadDir;
+
+    fn read_dir(&self, path: &PathBuf) -> io::Result<Self::DirIter> {
+        fs::read_dir(path)
+    }
+
+    fn handle_path(
+        &self,
+        parents: &Vec<Option<u64>>,
+        path: &PathBuf,
+    ) -> Option<Vec<Option<u64>>> {
+        self.handle_paths(parents, &[path.clone()]).pop().and_then(|dir| dir)
+    }
+
+    /// Insert a page of directory entries with a single message per repository.
+    fn handle_paths(
+        &self,
+        parents: &Vec<Option<u64>>,
+        paths: &[PathBuf],
+    ) -> Vec<Option<Vec<Option<u64>>>> {
+        let locations = paths.iter().map(|path| Location::Path(path.clone())).collect();
+        self.handle_entries(parents, paths, locations)
+    }
+
+    /// Like `handle_paths`, reading the entries by name in `dir`.
+    fn handle_paths_at(
+        &self,
+        parents: &Vec<Option<u64>>,
+        dir: &Arc<DirFd>,
+        paths: &[PathBuf],
+    ) -> Vec<Option<Vec<Option<u64>>>> {
+        let locations = paths
+            .iter()
+            .map(|path| {
+                let name = path.file_name().map(|n| n.to_owned()).unwrap_or_default();
+                Location::At(dir.clone(), name)
+            })
+            .collect();
+        self.handle_entries(parents, paths, locations)
+    }
+}
+
+impl<B: StoreBackend> InsertPathHandler<B> {
+    /// Insert the entries at `locations`, whose full paths are `paths`.
+    fn handle_entries(
+        &self,
+        parents: &Vec<Option<u64>>,
+        paths: &[PathBuf],
+        locations: Vec<Location>,
+    ) -> Vec<Option<Vec<Option<u64>>>> {
+        if self.control.as_ref().map_or(false, |c| c.is_cancelled()) {
+            for error in &self.errors {
+                let mut error = error.lock().unwrap();
+                if error.is_none() {
+                    *error = Some(From::from("Snapshot cancelled"));
+                }
+            }
+        }
+        let live: Vec<usize> = (0..self.key_stores.len()).filter(|&i| self.is_live(i)).collect();
+        if live.is_empty() {
+            // Previous inserts failed; do not store anything more.
+            return vec![None; paths.len()];
+        }
+
+        let candidates: Vec<_> = paths
+            .iter()
+            .zip(locations)
+            .map(|(path, location)| self.prepare(path, location))
+            .collect();
+        let positions: Vec<usize> = candidates
+            .iter()
+            .enumerate()
+            .filter(|&(_, c)| c.is_some())
+            .map(|(pos, _)| pos)
+            .collect();
+        if positions.is_empty() {
+            return vec![None; paths.len()];
+        }
+
+        // Share a single read of each file when there is more than one repository.
+        let fan_out = live.len() > 1 &&
+            candidates.iter().any(|c| c.as_ref().map_or(false, |c| c.file_entry.is_file()));
+        let mut batches: Vec<Vec<_>> = live.iter().map(|_| vec![]).collect();
+        for candidate in candidates.iter().filter_map(|c| c.as_ref()) {
+            let is_file = candidate.file_entry.is_file();
+            let full_path = &candidate.file_entry.full_path;
+            let location = &candidate.file_entry.location;
+            let mut readers = if is_file && fan_out {
+                tee::file(location.clone(), live.len()).into_iter()
+            } else {
+                vec![].into_iter()
+            };
+            for (n, &i) in live.iter().enumerate() {
+                let mut key_entry = candidate.file_entry.key_entry.clone();
+                key_entry.parent_id = parents[i];
+                // Shared reads of a file keep pace with each other, so throttling the first
+                // reader is enough.
+                let throttle = if n == 0 { self.throttle.clone() } else { None };
+                let open = match (is_file, readers.next()) {
+                    (true, Some(reader)) => {
+                        Some(open_tee(full_path.clone(), location.clone(), reader, throttle))
+                    }
+                    (true, None) => {
+                        let mmap = self.filter.mmap;
+                        Some(open_file(full_path.clone(), location.clone(), mmap, throttle))
+                    }
+                    (false, _) => None,
+                };
+                batches[n].push((key_entry, open, candidate.chunking));
+            }
+        }
+        let msgs: Vec<_> = live.iter()
+            .cloned()
+            .zip(batches)
+            .map(|(i, batch)| (i, key::Msg::InsertMany(batch)))
+            .collect();
+
+        let mut ids = vec![vec![None; self.key_stores.len()]; paths.len()];
+        let mut inserted = vec![false; paths.len()];
+        for (i, reply) in self.insert_all(msgs, fan_out) {
+            match reply {
+                Ok(key::Reply::Ids(new_ids)) => {
+                    for (&pos, id) in positions.iter().zip(new_ids) {
+                        ids[pos][i] = Some(id);
+                        inserted[pos] = true;
+                    }
+                }
+                Err(e) => {
+                    let dir = paths[0].parent().unwrap_or(&paths[0]);
+                    if self.key_stores.len() > 1 {
+                        warn!("Stopping in '{}' in repository {}: {}", dir.display(), i + 1, e);
+                    } else {
+                        warn!("Stopping in '{}': {}", dir.display(), e);
+                    }
+                    let mut error = self.errors[i].lock().unwrap();
+                    if error.is_none() {
+                        *error = Some(e);
+                    }
+                }
+                _ => panic!("Unexpected reply from key store."),
+            }
+        }
+
+        candidates
+            .into_iter()
+            .zip(ids)
+            .zip(inserted)
+            .map(|((candidate, ids), inserted)| match candidate {
+                Some(ref c) if inserted && c.file_entry.is_directory() => {
+                    let path = &c.file_entry.full_path;
+                    if c.other_device {
+                        // Keep the mount point, but not what is mounted on it.
+                        info!("Not crossing filesystem boundary: {}", path.display());
+                        None
+                    } else if !c.first_visit {
+                        // The same directory is reachable twice (e.g. through a bind mount).
+                        warn!("Skipping directory loop: {}", path.display());
+                        None
+                    } else {
+                        Some(ids)
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,88 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Snapshots of several independent sources at the same time, within one process.
+
+use hat::command_source::CommandSource;
+use hat::snapshot_name::NameTemplate;
+use hat::source_filter::SourceFilter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+
+/// Progress and cancellation of a snapshot, shared with whoever started it.
+#[derive(Debug, Default)]
+pub struct JobControl {
+    cancelled: AtomicBool,
+    files: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl JobControl {
+    pub fn new() -> Arc<JobControl> {
+        Arc::new(JobControl::default())
+    }
+
+    /// Stop the snapshot at the next directory entry. A cancelled snapshot is not committed.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Count an entry that is about to be inserted, with the size of its contents.
+    pub fn add_entry(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as usize, Ordering::Relaxed);
+    }
+
+    /// Entries seen so far.
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed) as u64
+    }
+
+    /// Bytes in the files seen so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed) as u64
+    }
+}
+
+/// A source directory to snapshot into a family.
+pub struct SnapshotJob {
+    pub family_name: String,
+    pub path: PathBuf,
+    pub filter: SourceFilter,
+    /// Commands whose output is stored next to the directory, run after it has been read.
+    pub commands: Vec<CommandSource>,
+    /// Name the snapshot after this template, instead of leaving it anonymous.
+    pub name_template: Option<NameTemplate>,
+    pub control: Arc<JobControl>,
+}
+
+impl SnapshotJob {
+    pub fn new(family_name: String, path: PathBuf) -> SnapshotJob {
+        SnapshotJob {
+            family_name: family_name,
+            path: path,
+            filter: SourceFilter::default(),
+            commands: vec![],
+            name_template: None,
+            control: JobControl::new(),
+        }
+    }
+}
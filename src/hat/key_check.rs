@@ -0,0 +1,107 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A check value that tells whether a master key is the one of the repository.
+//!
+//! A mistyped passphrase gives a valid but different key. Without the check, a repository
+//! opened with it would only fail on its first encrypted object, or not at all while it is
+//! still empty, and new data would be written that nobody can read.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use crypto::keys::{self, Keeper, MasterKey};
+use errors::HatError;
+use hat::repo_config;
+
+
+/// The backend name of the check value. Blob names are longer, so this never clashes.
+pub const BLOB_NAME: &'static [u8] = b"kcv";
+
+const CHECK_BYTES: usize = 32;
+const SALT: &'static [u8] = b"hat:key-check~~~";
+
+/// A MAC of a constant under `master`. It reveals nothing about the key.
+fn check_value(master: &MasterKey) -> Vec<u8> {
+    let mut out = vec![0u8; CHECK_BYTES];
+    keys::keyed_fingerprint(master.as_bytes(), b"hat key check value", SALT, &mut out);
+    out
+}
+
+/// Compare without stopping at the first difference.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Fail if `master` is not the key of the repository in `backend`. The first key accepted is
+/// recorded: that of a repository without any encrypted objects yet, or one that opens its
+/// config.
+pub fn verify_or_store<B: StoreBackend>(backend: &B, master: &MasterKey) -> Result<(), HatError> {
+    let expected = check_value(master);
+    match backend.retrieve(BLOB_NAME)? {
+        Some(ref stored) if same(&stored[..], &expected[..]) => Ok(()),
+        Some(_) => Err(From::from("Wrong key for this repository")),
+        None => {
+            // Repositories from before the check value existed are verified by their config.
+            repo_config::load(backend, &Keeper::from_master_key(master))?;
+            backend.store(BLOB_NAME, &CipherText::new(expected))?;
+            Ok(backend.flush()?)
+        }
+    }
+}
+
+/// Fail if the repository records a check value that `master` does not match.
+pub fn verify<B: StoreBackend>(backend: &B, master: &MasterKey) -> Result<(), HatError> {
+    match backend.retrieve(BLOB_NAME)? {
+        Some(ref stored) if !same(&stored[..], &check_value(master)[..]) => {
+            Err(From::from("Wrong key for this repository"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use hat::repo_config::RepoConfig;
+
+    #[test]
+    fn wrong_keys_are_rejected() {
+        let backend = MemoryBackend::new();
+        let right = MasterKey::from_bytes(vec![1; 64]);
+        let wrong = MasterKey::from_bytes(vec![2; 64]);
+
+        assert!(verify(&backend, &wrong).is_ok());
+        verify_or_store(&backend, &right).unwrap();
+        assert!(verify_or_store(&backend, &right).is_ok());
+        assert!(verify(&backend, &right).is_ok());
+        assert!(verify_or_store(&backend, &wrong).is_err());
+        assert!(verify(&backend, &wrong).is_err());
+    }
+
+    #[test]
+    fn existing_repositories_are_checked_by_their_config() {
+        let backend = MemoryBackend::new();
+        let right = MasterKey::from_bytes(vec![1; 64]);
+        let wrong = MasterKey::from_bytes(vec![2; 64]);
+        let keys = Keeper::from_master_key(&right);
+        repo_config::store(&backend, &keys, &RepoConfig::new(4 * 1024 * 1024)).unwrap();
+
+        // A wrong key is not recorded as the right one.
+        assert!(verify_or_store(&backend, &wrong).is_err());
+        assert_eq!(backend.retrieve(BLOB_NAME), Ok(None));
+        verify_or_store(&backend, &right).unwrap();
+        assert!(verify(&backend, &wrong).is_err());
+    }
+}
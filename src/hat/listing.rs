@@ -0,0 +1,55 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Directory listings of snapshots, optionally checked against the local indexes.
+
+use blob::BlobIndex;
+use hash::HashIndex;
+use hash::tree::HashRef;
+use hex::ToHex;
+use key;
+use std::path::PathBuf;
+
+
+/// An entry of a snapshot directory, as listed by `Hat::list_dir`.
+pub struct ListedEntry {
+    pub entry: key::Entry,
+    pub is_dir: bool,
+    pub link_target: Option<PathBuf>,
+    /// Why the data of the entry could not be restored, if the listing was checked.
+    pub dangling: Option<String>,
+}
+
+/// Check that the data below `hash_ref` is known to the hash index and that the blob holding
+/// it is known to the blob index. Returns what is wrong, if anything.
+///
+/// Only the top of the data is checked; `hat check` reads the blobs themselves.
+pub fn check_ref(
+    hash_index: &HashIndex,
+    blob_index: &BlobIndex,
+    hash_ref: &HashRef,
+) -> Option<String> {
+    match hash_index.fetch_persistent_ref(&hash_ref.hash) {
+        Err(_) => Some("data is not committed yet".to_owned()),
+        Ok(None) => Some("hash is not in the hash index".to_owned()),
+        Ok(Some(chunk_ref)) => {
+            match blob_index.find(&chunk_ref.blob_name) {
+                Some(_) => None,
+                None => {
+                    Some(format!("blob {} is not in the blob index", chunk_ref.blob_name.to_hex()))
+                }
+            }
+        }
+    }
+}
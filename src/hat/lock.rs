@@ -0,0 +1,94 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exclusive access to the local state of a repository.
+
+use errors::{HatError, LockError};
+use libc;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+
+/// Holds an advisory lock on a repository until dropped.
+pub struct RepositoryLock {
+    // The lock is released when the file is closed.
+    _file: fs::File,
+}
+
+impl RepositoryLock {
+    /// Lock the repository with local state in `root`, failing if another process holds it.
+    pub fn acquire(root: &Path) -> Result<RepositoryLock, HatError> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(root.join("lock"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(From::from(LockError::from(format!(
+                    "Repository {} is in use by another process",
+                    root.display()
+                ))));
+            }
+            return Err(From::from(e));
+        }
+        Ok(RepositoryLock { _file: file })
+    }
+
+    /// Whether a process holds the lock on the repository with local state in `root`.
+    pub fn is_held(root: &Path) -> Result<bool, HatError> {
+        let file = match fs::OpenOptions::new().write(true).open(root.join("lock")) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(From::from(e)),
+        };
+        // Closing the file releases the lock again.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(false);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            Ok(true)
+        } else {
+            Err(From::from(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::ErrorKind;
+
+    #[test]
+    fn second_lock_fails() {
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-lock-{}", nanos));
+        fs::create_dir_all(&root).unwrap();
+
+        assert!(!RepositoryLock::is_held(&root).unwrap());
+        let lock = RepositoryLock::acquire(&root).unwrap();
+        assert!(RepositoryLock::is_held(&root).unwrap());
+        match RepositoryLock::acquire(&root) {
+            Err(e) => assert_eq!(ErrorKind::Locked, e.kind()),
+            Ok(_) => panic!("Locked the repository twice"),
+        }
+        drop(lock);
+        assert!(!RepositoryLock::is_held(&root).unwrap());
+        assert!(RepositoryLock::acquire(&root).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Policy for applying file metadata during a restore.
+
+use filetime;
+use hat::file_attributes;
+use key;
+use libc;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+
+/// Which parts of the recorded metadata to apply to restored files.
+///
+/// Owners are recorded as numeric user and group ids and are restored as such.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MetadataPolicy {
+    pub owner: bool,
+    pub permissions: bool,
+    pub times: bool,
+    /// File capabilities and the immutable, append-only and nodump flags.
+    pub attributes: bool,
+}
+
+impl MetadataPolicy {
+    /// Apply everything the current user is allowed to. Only root can change file owners and
+    /// set capabilities or the immutable and append-only flags.
+    pub fn detect() -> MetadataPolicy {
+        let root = unsafe { libc::geteuid() } == 0;
+        MetadataPolicy {
+            owner: root,
+            permissions: true,
+            times: true,
+            attributes: root,
+        }
+    }
+
+    /// Apply no metadata at all.
+    pub fn none() -> MetadataPolicy {
+        MetadataPolicy {
+            owner: false,
+            permissions: false,
+            times: false,
+            attributes: false,
+        }
+    }
+
+    /// Apply the metadata in `info` to the restored file at `path`.
+    pub fn apply(&self, path: &Path, info: &key::Info, is_symlink: bool) -> io::Result<()> {
+        // Change owner first, as it may clear the setuid and setgid bits.
+        if let (true, Some(uid), Some(gid)) = (self.owner, info.user_id, info.group_id) {
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            let res = unsafe {
+                libc::lchown(c_path.as_ptr(), uid as libc::uid_t, gid as libc::gid_t)
+            };
+            if res != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if is_symlink {
+            // Permissions and times would be applied to the link target.
+            return Ok(());
+        }
+
+        if let (true, Some(perms)) = (self.permissions, info.permissions.clone()) {
+            fs::set_permissions(path, perms)?;
+        }
+
+        if let (true, Some(m), Some(a)) =
+            (self.times, info.modified_ts_secs, info.accessed_ts_secs)
+        {
+            let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
+            let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
+            filetime::set_file_times(path, atime, mtime)?;
+        }
+
+        if self.attributes {
+            // Capabilities are cleared by a change of owner, so set them after it. Flags go last,
+            // as an immutable file can no longer be changed.
+            if let Some(ref caps) = info.capabilities {
+                match file_attributes::write_capabilities(path, caps) {
+                    Err(ref e) if file_attributes::is_unsupported(e) => {
+                        warn!("Cannot restore capabilities of '{}': {}", path.display(), e);
+                    }
+                    res => res?,
+                }
+            }
+            if let Some(flags) = info.attribute_flags {
+                match file_attributes::write_flags(path, flags) {
+                    Err(ref e) if file_attributes::is_unsupported(e) => {
+                        if flags != 0 {
+                            warn!("Cannot restore attributes of '{}': {}", path.display(), e);
+                        }
+                    }
+                    res => res?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -18,27 +18,117 @@ use backend::StoreBackend;
 use blob;
 use capnp;
 use db;
-use errors::HatError;
-use filetime;
+use errors::VerificationError;
 use gc::{self, Gc, GcRc};
 use hash;
 use key;
 use root_capnp;
+use scoped_pool;
 use snapshot;
 use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 use std::str;
-use std::sync::{Arc, mpsc};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tags;
-use util::Process;
+use util::{FileIterator, Process};
+pub use util::{Clock, FixedClock, IdGenerator, SequentialIds, SystemClock, Throttle, TimeIds};
 use void::Void;
 use hex::ToHex;
 
+mod analyze;
+mod attest;
+mod check;
+mod client;
+mod collision;
+mod command_source;
+mod dump;
+mod dupes;
+mod export;
 mod family;
+mod file_attributes;
+mod fsfreeze;
+mod gc_plan;
+mod graph;
+mod index_backup;
 mod insert_path_handler;
+mod jobs;
+mod key_check;
+mod listing;
+mod lock;
+mod metadata;
+mod namespace;
+mod offline;
+mod path_selection;
+mod priority;
+mod repo_config;
+mod repo_format;
+mod repo_info;
+mod restore_drill;
+mod restore_order;
+mod restore_progress;
+mod sealed_index;
+mod sharing;
+mod snapshot_name;
+mod source_filter;
+mod stage;
+mod status;
+mod type_usage;
+mod walk;
 mod walker;
+use self::check::CheckProgress;
 use self::family::Family;
+use self::sealed_index::SealedIndex;
+
+pub use blob::Quota;
+pub use self::analyze::{Analysis, ChunkingEstimate, analyze as analyze_source};
+pub use self::attest::Attestation;
+pub use db::{CommandRun, PathDeletion, PathVersion, SnapshotStats};
+pub use errors::{ErrorKind, ExitCode, HatError};
+pub use self::check::{CheckLimits, CheckReport, Subset, TreeCheckReport};
+pub use self::command_source::CommandSource;
+pub use hash::cache::{CacheStats, NodeCache};
+pub use hash::cache::DEFAULT_MAX_BYTES as DEFAULT_NODE_CACHE_BYTES;
+pub use self::client::Client;
+pub use self::collision::CollisionPolicy;
+pub use crypto::keys::MasterKey;
+pub use key::DEFAULT_TEXT_INDEX_MAX_BYTES;
+pub use crypto::provider::{CommandKey, EnvKey, FileKey, KeyProvider, KeyringKey, PassphraseKey,
+                           PasswordCommandKey, RecipientKey};
+pub use crypto::recipients::{Identity, KeyFile, Recipient};
+pub use self::dupes::DuplicateFiles;
+pub use self::export::ChunkReader;
+pub use self::fsfreeze::FreezeGuard;
+pub use self::gc_plan::{GcPlan, PinnedData};
+pub use self::graph::{GraphLink, GraphSnapshot, SnapshotGraph};
+pub use self::jobs::{JobControl, SnapshotJob};
+pub use self::key_check::verify_or_store as check_key;
+pub use self::listing::ListedEntry;
+pub use self::lock::RepositoryLock;
+pub use self::metadata::MetadataPolicy;
+pub use self::namespace::{Namespace, NamespaceKey, list as list_namespaces};
+pub use self::offline::{BlobOnlyBackend, OfflineRepository, OfflineSnapshot};
+pub use self::path_selection::PathSelection;
+pub use self::priority::{set_idle_io, set_nice};
+pub use self::repo_config::{RepoConfig, MIN_BLOB_SIZE};
+pub use self::repo_format::{FORMAT_VERSION, format_version, init as init_repository};
+pub use self::repo_info::RepoInfo;
+pub use self::restore_drill::DrillReport;
+pub use self::sharing::FamilyUsage;
+pub use self::snapshot_name::NameTemplate;
+pub use self::restore_order::RestoreOrder;
+pub use self::restore_progress::{RestoreProgress, RestoreStatus};
+pub use self::source_filter::SourceFilter;
+pub use self::stage::StageReport;
+pub use self::status::Change;
+pub use self::type_usage::TypeUsage;
+pub use self::walk::{SnapshotEntry, SnapshotWalk};
 
 #[cfg(test)]
 mod tests;
@@ -116,10 +206,38 @@ impl gc::GcBackend for GcBackend {
     }
 }
 
+/// A complete snapshot, as listed by `Hat::list_snapshots`.
+#[derive(Clone, Debug)]
+pub struct SnapshotSummary {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub msg: Option<String>,
+    pub trashed: bool,
+    pub pinned: bool,
+    /// Recorded for snapshots committed by this client since accounting was added.
+    pub stats: Option<SnapshotStats>,
+    /// Files moved since the previous snapshot, as paths from and to.
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    /// Files that changed while they were read, so their data may be inconsistent.
+    pub fuzzy_files: Vec<PathBuf>,
+    /// Commands whose output was stored in the snapshot, by name.
+    pub commands: Vec<CommandRun>,
+}
+
+/// A path found by `Hat::find_paths` or `Hat::grep_snapshots`, and the snapshots containing it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathMatch {
+    pub family_name: String,
+    pub path: PathBuf,
+    pub snapshot_ids: Vec<u64>,
+}
 
 pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     keys: Arc<crypto::keys::Keeper>,
     repository_root: Option<PathBuf>,
+    // Where blobs wait for their upload to commit, if uploads should survive a restart.
+    upload_spool: Option<PathBuf>,
     migrations_dir: PathBuf,
     families: Vec<Family<B>>,
     db: Arc<db::Index>,
@@ -129,7 +247,23 @@ pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     blob_index: Arc<blob::BlobIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     blob_max_size: usize,
+    max_uploads: usize,
+    blob_size_bounds: Option<(usize, usize)>,
+    backend_timeout: Option<Duration>,
+    text_index_max_bytes: Option<usize>,
+    content_types: bool,
+    changed_retries: u32,
     gc: G,
+    node_cache: Arc<NodeCache>,
+    restore_progress: Option<Arc<RestoreProgress>>,
+    clock: Arc<Clock>,
+    ids: Arc<IdGenerator>,
+    client: Option<Client>,
+    // Dropped after the indexes are closed, and before the lock is released.
+    sealed_index: Option<SealedIndex>,
+    _lock: Option<RepositoryLock>,
+    // Opened next to a writer: the indexes are only read, and nothing is resumed.
+    read_only: bool,
 }
 
 pub type HatRc<B> = Hat<B, GcRc<GcBackend>>;
@@ -143,10 +277,73 @@ fn hash_index_name(root: PathBuf) -> String {
     concat_filename(root, "hash_index.sqlite3")
 }
 
+/// Where blobs are kept while they are uploading.
+fn upload_spool_name(root: &Path) -> PathBuf {
+    root.join("uploads")
+}
+
+/// The passphrase of repositories without recipients or another key provider. It is no
+/// secret: the data of such repositories can be read by anyone with access to the blobs.
+pub fn default_passphrase() -> String {
+    "hat-master-key".to_owned()
+}
+
+/// Run `store` as a key store process. If it panics, it is restarted with a store that takes
+/// over from the crashed one, see `key::Store::restarted`.
+fn supervised_key_store<B: StoreBackend>(
+    family_name: &str,
+    store: key::Store<B>,
+) -> key::StoreProcess<FileIterator, B> {
+    let name = format!("key store of {}", family_name);
+    let mut first = Some(store.clone());
+    Process::new_supervised(name, move || match first.take() {
+        Some(store) => Ok(store),
+        None => store.restarted(),
+    })
+}
+
 fn synthetic_roots_family() -> String {
     From::from("__hat__roots__")
 }
 
+/// What applies to every entry of a checkout.
+struct CheckoutOptions<'a> {
+    policy: &'a MetadataPolicy,
+    collisions: CollisionPolicy,
+    // Only restore the files of the content types selected here.
+    types: Option<&'a PathSelection>,
+}
+
+/// Whether a checkout restores `entry` at `output`, and if so, the selection for the entries
+/// below it. Selected entries are restored in full, and selected entries are looked for below
+/// the others.
+fn select_entry<'a>(
+    output: &Path,
+    entry: &key::Entry,
+    is_dir: bool,
+    options: &CheckoutOptions,
+    selection: Option<(&'a PathSelection, &'a Path)>,
+) -> Option<Option<(&'a PathSelection, &'a Path)>> {
+    let child_selection = match selection {
+        Some((paths, root)) => {
+            let path = output.strip_prefix(root).unwrap();
+            if paths.selects(path) {
+                None
+            } else if is_dir && paths.selects_below(path) {
+                selection
+            } else {
+                return None;
+            }
+        }
+        None => None,
+    };
+    let content_type = entry.info.content_type.as_ref().map(|t| &t[..]);
+    if !is_dir && !options.types.map_or(true, |t| t.selects_type(content_type)) {
+        return None;
+    }
+    Some(child_selection)
+}
+
 struct SnapshotLister<'a, B: StoreBackend> {
     backend: &'a key::HashStoreBackend<B>,
     family: &'a Family<B>,
@@ -205,6 +402,40 @@ fn list_snapshot<'a, B: StoreBackend>(
 }
 
 
+/// Group recorded paths by family and path, with the ids of the `snapshots` that contain them.
+fn path_matches(
+    rows: Vec<(String, db::SnapshotPath)>,
+    snapshots: HashMap<String, Vec<u64>>,
+) -> Vec<PathMatch> {
+    // A path can have several ranges of snapshots, if it was missing or different for a while.
+    let mut found: BTreeMap<(String, Vec<u8>), Vec<u64>> = BTreeMap::new();
+    for (family, row) in rows {
+        let ids = match snapshots.get(&family) {
+            Some(ids) => ids,
+            None => continue,
+        };
+        let (first, last) = (row.first_snapshot as u64, row.last_snapshot as u64);
+        found
+            .entry((family, row.path))
+            .or_insert_with(Vec::new)
+            .extend(ids.iter().filter(|&&id| first <= id && id <= last));
+    }
+
+    found
+        .into_iter()
+        .filter(|&(_, ref ids)| !ids.is_empty())
+        .map(|((family, path), mut ids)| {
+            ids.sort();
+            ids.dedup();
+            PathMatch {
+                family_name: family,
+                path: PathBuf::from(OsString::from_vec(path)),
+                snapshot_ids: ids,
+            }
+        })
+        .collect()
+}
+
 impl<B: StoreBackend> HatRc<B> {
     pub fn open_repository(
         migrations_dir: &Path,
@@ -212,14 +443,79 @@ impl<B: StoreBackend> HatRc<B> {
         backend: Arc<B>,
         max_blob_size: usize,
     ) -> Result<HatRc<B>, HatError> {
-        let keys = Arc::new(crypto::keys::Keeper::new("hat-master-key"));
+        Hat::open_repository_with_key(
+            migrations_dir,
+            repository_root,
+            backend,
+            max_blob_size,
+            &MasterKey::from_passphrase(&default_passphrase()),
+            false,
+        )
+    }
+
+    /// Open the repository of `namespace`, with local state below `cache_root` and keys derived
+    /// from `master`, the key of the whole storage location. The backend must store its blobs
+    /// in the namespace's blob directory.
+    pub fn open_namespace(
+        migrations_dir: &Path,
+        cache_root: &Path,
+        namespace: &Namespace,
+        backend: Arc<B>,
+        max_blob_size: usize,
+        master: &MasterKey,
+    ) -> Result<HatRc<B>, HatError> {
+        Hat::open_repository_with_key(
+            migrations_dir,
+            namespace.cache_dir(cache_root),
+            backend,
+            max_blob_size,
+            &namespace.master_key(master),
+            false,
+        )
+    }
+
+    /// Open a repository whose keys are derived from `master`, e.g. after unsealing it with
+    /// the identity of a recipient.
+    ///
+    /// With `encrypt_index`, the local index files are kept encrypted with a key derived from
+    /// `master` while the repository is closed. Once encrypted, they stay that way.
+    pub fn open_repository_with_key(
+        migrations_dir: &Path,
+        repository_root: PathBuf,
+        backend: Arc<B>,
+        max_blob_size: usize,
+        master: &MasterKey,
+        encrypt_index: bool,
+    ) -> Result<HatRc<B>, HatError> {
+        repo_format::check(&*backend)?;
+        key_check::verify_or_store(&*backend, master)?;
+        fs::create_dir_all(&repository_root)?;
+        let lock = RepositoryLock::acquire(&repository_root)?;
+
+        let keys = Arc::new(crypto::keys::Keeper::from_master_key(master));
+        // Once changed with `set_max_blob_size`, new blobs take the size stored in the
+        // repository, whatever `max_blob_size` asks for.
+        let max_blob_size =
+            repo_config::load_or_store(&*backend, &keys, &RepoConfig::new(max_blob_size))?
+                .max_blob_size;
         let migrations_path = migrations_dir.canonicalize().unwrap();
 
-        let hash_index_path = hash_index_name(repository_root.clone());
+        // Blobs are encrypted already, and must outlive the working copy of a sealed index.
+        let spool_dir = upload_spool_name(&repository_root);
+        let sealed_index = if encrypt_index || sealed_index::is_sealed(&repository_root)? {
+            Some(SealedIndex::open(&repository_root, &keys)?)
+        } else {
+            None
+        };
+        let index_root = match sealed_index {
+            Some(ref sealed) => sealed.work_dir().to_owned(),
+            None => repository_root,
+        };
+
+        let hash_index_path = hash_index_name(index_root.clone());
         let db_p = Arc::new(db::Index::new(&migrations_path, &hash_index_path)?);
 
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
-        let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
 
         let bi_p = Arc::new(blob::BlobIndex::new(keys.clone(), db_p.clone())?);
         let bs_p = Arc::new(blob::BlobStore::new(
@@ -228,13 +524,27 @@ impl<B: StoreBackend> HatRc<B> {
             backend.clone(),
             max_blob_size,
         ));
+        bs_p.set_spool_dir(Some(spool_dir.clone()))?;
+
+        // Finish the uploads of an interrupted run first, so that the hashes reserved for their
+        // chunks are kept when the hash index resolves its reservations.
+        let (finished, discarded) = bs_p.resume_unfinished_uploads()?;
+        if finished + discarded > 0 {
+            info!(
+                "Unfinished uploads: {} finished, {} discarded",
+                finished,
+                discarded
+            );
+        }
+        let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
 
         let mut hat = Hat {
             keys: keys,
-            repository_root: Some(repository_root),
+            repository_root: Some(index_root),
+            upload_spool: Some(spool_dir),
             migrations_dir: migrations_path,
             families: vec![],
             db: db_p,
@@ -244,7 +554,21 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            max_uploads: blob::DEFAULT_MAX_IN_FLIGHT,
+            blob_size_bounds: None,
+            backend_timeout: None,
+            text_index_max_bytes: None,
+            content_types: false,
+            changed_retries: 0,
             gc: gc,
+            node_cache: Arc::new(NodeCache::new(DEFAULT_NODE_CACHE_BYTES)),
+            restore_progress: None,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(TimeIds),
+            client: None,
+            sealed_index: sealed_index,
+            _lock: Some(lock),
+            read_only: false,
         };
 
         // Resume any unfinished commands.
@@ -253,6 +577,94 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(hat)
     }
 
+    /// Open a repository for reading its committed snapshots, e.g. to list, find or export
+    /// them, while another process may be writing to it. No lock is taken and nothing is
+    /// resumed; snapshots that are still being written are not visible. Commands that write
+    /// fail.
+    pub fn open_read_only_with_key(
+        migrations_dir: &Path,
+        repository_root: PathBuf,
+        backend: Arc<B>,
+        max_blob_size: usize,
+        master: &MasterKey,
+    ) -> Result<HatRc<B>, HatError> {
+        repo_format::check(&*backend)?;
+        key_check::verify(&*backend, master)?;
+        if sealed_index::is_sealed(&repository_root)? {
+            return Err(From::from("Encrypted indexes cannot be opened for reading only"));
+        }
+        let hash_index_path = hash_index_name(repository_root.clone());
+        if !Path::new(&hash_index_path).exists() {
+            return Err(From::from(
+                format!("No repository index in {}", repository_root.display()),
+            ));
+        }
+
+        let keys = Arc::new(crypto::keys::Keeper::from_master_key(master));
+        if let Some(stored) = repo_config::load(&*backend, &keys)? {
+            stored.check_matches(&RepoConfig::new(max_blob_size))?;
+        }
+        let migrations_path = migrations_dir.canonicalize().unwrap();
+
+        let db_p = Arc::new(db::Index::new_read_only(&hash_index_path)?);
+        let si_p = snapshot::SnapshotIndex::new(db_p.clone());
+        let hi_p = Arc::new(hash::HashIndex::new_read_only(db_p.clone())?);
+        let bi_p = Arc::new(blob::BlobIndex::new(keys.clone(), db_p.clone())?);
+        let bs_p = Arc::new(blob::BlobStore::new(
+            keys.clone(),
+            bi_p.clone(),
+            backend.clone(),
+            max_blob_size,
+        ));
+
+        let gc_backend = GcBackend { hash_index: hi_p.clone() };
+        let gc = gc::Gc::new(gc_backend);
+
+        Ok(Hat {
+            keys: keys,
+            repository_root: Some(repository_root),
+            upload_spool: None,
+            migrations_dir: migrations_path,
+            families: vec![],
+            db: db_p,
+            snapshot_index: si_p,
+            hash_index: hi_p,
+            backend: backend,
+            blob_index: bi_p,
+            blob_store: bs_p,
+            blob_max_size: max_blob_size,
+            max_uploads: blob::DEFAULT_MAX_IN_FLIGHT,
+            blob_size_bounds: None,
+            backend_timeout: None,
+            text_index_max_bytes: None,
+            content_types: false,
+            changed_retries: 0,
+            gc: gc,
+            node_cache: Arc::new(NodeCache::new(DEFAULT_NODE_CACHE_BYTES)),
+            restore_progress: None,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(TimeIds),
+            client: None,
+            sealed_index: None,
+            _lock: None,
+            read_only: true,
+        })
+    }
+
+    /// Restore the local indexes in `repository_root` from the latest index backup in
+    /// `backend`, e.g. after losing the disk they were on. Returns the restored file names.
+    /// The repository can be opened as usual afterwards.
+    pub fn bootstrap_index(
+        repository_root: &Path,
+        backend: &B,
+        master: &MasterKey,
+    ) -> Result<Vec<String>, HatError> {
+        fs::create_dir_all(repository_root)?;
+        let _lock = RepositoryLock::acquire(repository_root)?;
+        let keys = crypto::keys::Keeper::from_master_key(master);
+        index_backup::download(backend, &keys, repository_root)
+    }
+
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<HatRc<B>, HatError> {
         let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
@@ -275,6 +687,7 @@ impl<B: StoreBackend> HatRc<B> {
         let mut hat = Hat {
             keys: keys,
             repository_root: None,
+            upload_spool: None,
             migrations_dir: PathBuf::from("migrations"),
             families: vec![],
             db: db_p,
@@ -283,8 +696,22 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            max_uploads: blob::DEFAULT_MAX_IN_FLIGHT,
+            blob_size_bounds: None,
+            backend_timeout: None,
+            text_index_max_bytes: None,
+            content_types: false,
+            changed_retries: 0,
             backend: backend,
             gc: gc,
+            node_cache: Arc::new(NodeCache::new(DEFAULT_NODE_CACHE_BYTES)),
+            restore_progress: None,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(TimeIds),
+            client: None,
+            sealed_index: None,
+            _lock: None,
+            read_only: false,
         };
 
         // Resume any unfinished commands.
@@ -312,28 +739,35 @@ impl<B: StoreBackend> HatRc<B> {
         }
 
         let key_index_path = match self.repository_root {
-            Some(ref root) => concat_filename(root.clone(), &name),
+            // Families merged from other clients are named CLIENT/FAMILY.
+            Some(ref root) => concat_filename(root.clone(), &name.replace('/', "%2F")),
             None => ":memory:".to_string(),
         };
 
-        let ki_p = Arc::new(key::KeyIndex::new(&self.migrations_dir, &key_index_path)?);
+        let ki_p = if self.read_only {
+            if !Path::new(&key_index_path).exists() {
+                return Err(From::from(format!("No snapshots of family {}", name)));
+            }
+            Arc::new(key::KeyIndex::new_read_only(&key_index_path)?)
+        } else {
+            Arc::new(key::KeyIndex::new(&self.migrations_dir, &key_index_path)?)
+        };
+        // Counted together, to record what each snapshot of the family read and stored.
+        let io_stats = Arc::new(key::IoStats::new());
+        let epoch_pin = Arc::new(Mutex::new(None));
 
         let mut kss = vec![];
         for _ in 0..2 {
             // To avoid mixing chunks from different files, each key store gets its own dedicated
             // blob store.
-            let bs = Arc::new(blob::BlobStore::new(
-                self.keys.clone(),
-                self.blob_index.clone(),
-                self.backend.clone(),
-                self.blob_max_size,
-            ));
-            kss.push(Process::new(key::Store::new(
-                ki_p.clone(),
-                self.hash_index.clone(),
-                bs,
-                self.keys.clone(),
-            )));
+            let bs = Arc::new(self.new_blob_store(self.blob_max_size)?);
+            let ks = key::Store::new(ki_p.clone(), self.hash_index.clone(), bs, self.keys.clone())
+                .with_io_stats(io_stats.clone())
+                .with_epoch_pin(epoch_pin.clone())
+                .with_text_index(self.text_index_max_bytes)
+                .with_content_types(self.content_types)
+                .with_changed_retries(self.changed_retries);
+            kss.push(supervised_key_store(&name, ks));
         }
 
         let ks = key::Store::new(
@@ -341,13 +775,18 @@ impl<B: StoreBackend> HatRc<B> {
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        );
-        kss.push(Process::new(ks.clone()));
+        ).with_io_stats(io_stats)
+            .with_epoch_pin(epoch_pin)
+            .with_text_index(self.text_index_max_bytes)
+            .with_content_types(self.content_types)
+            .with_changed_retries(self.changed_retries);
+        kss.push(supervised_key_store(&name, ks.clone()));
 
         let family = Family {
             name: name.clone(),
             key_store: ks,
             key_store_process: kss,
+            backend_timeout: self.backend_timeout,
         };
         self.families.push(family.clone());
 
@@ -380,6 +819,8 @@ impl<B: StoreBackend> HatRc<B> {
                 s.set_family_name(&snapshot.family_name);
                 s.set_msg(&snapshot.msg.unwrap_or("".to_owned()));
                 s.set_utc_timestamp(snapshot.created.timestamp());
+                s.set_trashed_utc_timestamp(snapshot.trashed.map_or(0, |t| t.timestamp()));
+                s.set_pinned(snapshot.pinned);
                 let hash_ref = snapshot.hash_ref.unwrap();
                 hash::tree::HashRef::from_bytes(&mut hash_ref.as_ref())?
                     .populate_msg(s.init_hash_ref());
@@ -404,7 +845,7 @@ impl<B: StoreBackend> HatRc<B> {
 
         // Create synthetic snapshot so GC can track the needed blobs and keep them alive.
         self.hash_index.set_tag(top_id, tags::Tag::Reserved);
-        let snap_info = self.snapshot_index.reserve(synthetic_roots_family());
+        let snap_info = self.snapshot_index.reserve(synthetic_roots_family(), self.clock.now());
         self.snapshot_index.update(
             &snap_info,
             &top_ref.hash,
@@ -465,9 +906,9 @@ impl<B: StoreBackend> HatRc<B> {
         use chrono::TimeZone;
         let mut max_created = chrono::Utc.timestamp(0, 0);
 
-        for msg in hash::tree::LeafIterator::new(self.hash_backend(), root_href.clone())?
-            .unwrap()
-        {
+        let mut lists = hash::tree::LeafIterator::new(self.hash_backend(), root_href.clone())?
+            .unwrap();
+        while let Some(msg) = lists.try_next()? {
             let message_reader = capnp::serialize_packed::read_message(
                 &mut &msg[..],
                 capnp::message::ReaderOptions::new(),
@@ -489,6 +930,19 @@ impl<B: StoreBackend> HatRc<B> {
                     &hash_ref,
                     Some(db::SnapshotWorkStatus::RecoverInProgress),
                 );
+                if s.get_trashed_utc_timestamp() != 0 || s.get_pinned() {
+                    if let Some((info, _, _)) =
+                        self.snapshot_index.lookup(s.get_family_name().unwrap(), s.get_id())
+                    {
+                        if s.get_trashed_utc_timestamp() != 0 {
+                            let trashed = chrono::Utc.timestamp(s.get_trashed_utc_timestamp(), 0);
+                            self.snapshot_index.trash(&info, trashed);
+                        }
+                        if s.get_pinned() {
+                            self.snapshot_index.pin(&info);
+                        }
+                    }
+                }
             }
         }
 
@@ -606,7 +1060,7 @@ impl<B: StoreBackend> HatRc<B> {
                 self.hash_index.set_tag(id, tags::Tag::Reserved);
             }
         }
-        self.flush_blob_store();
+        self.flush_blob_store()?;
 
         let final_id = self.hash_index.get_id(&final_hash.hash).expect(
             "final hash has no id",
@@ -738,11 +1192,116 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Snapshot the sources of `jobs` concurrently, then commit each snapshot that completed.
+    /// Returns one result per job, in order. A job that fails or is cancelled leaves its family
+    /// as it was, without holding back the others. As with `commit`, call `meta_commit` and
+    /// `data_flush` afterwards.
+    pub fn run_snapshot_jobs(
+        &mut self,
+        jobs: &[SnapshotJob],
+    ) -> Vec<Result<db::SnapshotStats, HatError>> {
+        let mut names = HashSet::new();
+        let families: Vec<Result<Family<B>, HatError>> = jobs.iter()
+            .map(|job| if names.insert(job.family_name.clone()) {
+                self.open_family(job.family_name.clone())
+            } else {
+                Err(From::from(
+                    format!("Family {} is in more than one job", job.family_name),
+                ))
+            })
+            .collect();
+
+        // Each job only inserts into the key store of its own family.
+        let threads: Vec<_> = jobs.iter()
+            .zip(&families)
+            .map(|(job, family)| {
+                family.as_ref().ok().map(|family| {
+                    let family = family.clone();
+                    let (path, filter) = (job.path.clone(), job.filter.clone());
+                    let commands = job.commands.clone();
+                    let control = job.control.clone();
+                    thread::spawn(move || {
+                        family.snapshot_dir_controlled(path, &filter, control)?;
+                        for command in &commands {
+                            let run = family.snapshot_command(command)?;
+                            if !run.succeeded() {
+                                println!("Command failed: {}", run);
+                            }
+                        }
+                        family.flush()
+                    })
+                })
+            })
+            .collect();
+
+        let mut results = vec![];
+        for ((job, family), handle) in jobs.iter().zip(families).zip(threads) {
+            let scanned = match (family, handle) {
+                (Err(e), _) => Err(e),
+                (Ok(family), Some(handle)) => {
+                    match handle.join().unwrap_or_else(
+                        |_| Err(From::from("Snapshot job panicked")),
+                    ) {
+                        Ok(()) => Ok(family),
+                        Err(e) => {
+                            // Leave what the job stored so far to the GC.
+                            family.key_store.release_epoch_pin();
+                            Err(e)
+                        }
+                    }
+                }
+                (Ok(_), None) => unreachable!("Snapshot job was not started"),
+            };
+            // Commits share the snapshot index, so they run one at a time.
+            results.push(scanned.and_then(|mut family| if job.control.is_cancelled() {
+                family.key_store.release_epoch_pin();
+                Err(From::from("Snapshot cancelled"))
+            } else {
+                match job.name_template {
+                    Some(ref template) => {
+                        let name = self.snapshot_name(&job.family_name, template);
+                        self.commit_with_msg(&mut family, None, &name)
+                    }
+                    None => self.commit(&mut family, None),
+                }
+            }));
+        }
+        results
+    }
+
+    /// Name a new snapshot of `family_name` after `template`, at the current local time. If a
+    /// snapshot of the family already has the name, a number is added to make it unique.
+    pub fn snapshot_name(&mut self, family_name: &str, template: &NameTemplate) -> String {
+        let name = template.render(
+            &snapshot_name::hostname(),
+            family_name,
+            &self.clock.now().with_timezone(&chrono::Local).naive_local(),
+        );
+        let taken: HashSet<String> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name)
+            .filter_map(|s| s.msg)
+            .collect();
+        snapshot_name::unique(name, &taken)
+    }
+
     pub fn commit(
         &mut self,
         family: &mut Family<B>,
         resume_info: Option<db::SnapshotInfo>,
-    ) -> Result<(), HatError> {
+    ) -> Result<db::SnapshotStats, HatError> {
+        self.commit_with_msg(family, resume_info, "anonymous")
+    }
+
+    /// Commit a snapshot with a message describing it. Returns what the run read and stored,
+    /// as recorded with the snapshot.
+    pub fn commit_with_msg(
+        &mut self,
+        family: &mut Family<B>,
+        resume_info: Option<db::SnapshotInfo>,
+        msg: &str,
+    ) -> Result<db::SnapshotStats, HatError> {
         //  Tag 1:
         //  Reserve the snapshot and commit the reservation.
         //  Register all but the last hashes.
@@ -751,7 +1310,7 @@ impl<B: StoreBackend> HatRc<B> {
             Some(info) => info,  // Resume already started commit.
             None => {
                 // Create new commit.
-                self.snapshot_index.reserve(family.name.clone())
+                self.snapshot_index.reserve(family.name.clone(), self.clock.now())
             }
         };
         self.meta_flush();
@@ -771,12 +1330,28 @@ impl<B: StoreBackend> HatRc<B> {
         // Tag 2:
         // We update the snapshot entry with the tree hash, which we then register.
         // When the GC has seen the final hash, we flush everything so far.
-        self.snapshot_index.update(
+        self.snapshot_index.update_with_msg(
             &snap_info,
+            msg,
             &top_ref.hash,
             &top_ref,
         );
+        let stats = family.key_store.io_stats().take();
+        self.snapshot_index.set_stats(&snap_info, &stats);
+        self.snapshot_index.set_renames(&snap_info, &family.key_store.renames()?);
+        self.snapshot_index.set_fuzzy_files(&snap_info, &family.key_store.fuzzy_files()?);
+        self.snapshot_index.set_deletions(&snap_info, &family.key_store.deletions()?);
+        self.snapshot_index.set_exclusions(&snap_info, &family.key_store.exclusions()?);
+        self.snapshot_index.set_commands(&snap_info, &family.key_store.command_runs()?);
+        self.snapshot_index.set_paths(&snap_info, &family.key_store.paths()?);
+        self.snapshot_index.add_text_words(&family.key_store.text_words()?);
         self.meta_flush();
+        family.key_store.clear_renames()?;
+        family.key_store.clear_fuzzy_files()?;
+        family.key_store.clear_deletions()?;
+        family.key_store.clear_exclusions()?;
+        family.key_store.clear_command_runs()?;
+        family.key_store.clear_text_words()?;
 
         // Register the final hash.
         // At this point, the GC should still be able to either resume or rollback safely.
@@ -787,10 +1362,11 @@ impl<B: StoreBackend> HatRc<B> {
         );
         self.gc.register_final(&snap_info, hash_id)?;
         self.meta_flush();
+        family.key_store.release_epoch_pin();
 
         self.commit_finalize(snap_info, &top_ref.hash)?;
 
-        Ok(())
+        Ok(stats)
     }
 
     fn commit_finalize(
@@ -821,23 +1397,319 @@ impl<B: StoreBackend> HatRc<B> {
         for family in &self.families {
             family.flush()?
         }
-        self.blob_store.flush();
+        self.blob_store.flush()?;
         self.meta_flush();
+        if let Some(ref sealed) = self.sealed_index {
+            sealed.seal()?;
+        }
+        Ok(())
+    }
+
+    /// Store an encrypted copy of the local indexes with the blobs, replacing the previous one,
+    /// so that `bootstrap_index` can restore them without a full recovery. Returns its size.
+    pub fn backup_index(&self) -> Result<u64, HatError> {
+        self.data_flush()?;
+        match self.repository_root {
+            Some(ref root) => index_backup::upload(&*self.backend, &self.keys, root),
+            None => Err(From::from("In-memory indexes cannot be backed up")),
+        }
+    }
+
+    /// Back up the indexes like `backup_index`, unless the previous backup is less than
+    /// `interval` old. Returns the size of the new backup, if one was made.
+    pub fn backup_index_if_due(&self, interval: Duration) -> Result<Option<u64>, HatError> {
+        if !index_backup::is_due(&*self.backend, interval, SystemTime::now())? {
+            return Ok(None);
+        }
+        self.backup_index().map(Some)
+    }
+
+    /// Share the repository with other clients, as `client`. New blobs get ids from the
+    /// client's own range, claimed in the backend, and snapshots are exchanged with
+    /// `publish_snapshot` and `reconcile`. Readers write no blobs, and claim nothing.
+    pub fn set_client(&mut self, client: Client) -> Result<(), HatError> {
+        if !self.read_only {
+            let (start, end) = client.claim_blob_id_range(&*self.backend)?;
+            let (record, owner) = client.range_record(start);
+            self.blob_index.set_id_range(start, end);
+            self.blob_index.set_id_range_owner(record, owner);
+        }
+        self.client = Some(client);
+        Ok(())
+    }
+
+    /// Publish the latest snapshot of `family_name` to the other clients, as a new delta.
+    pub fn publish_snapshot(&mut self, family_name: &str) -> Result<(), HatError> {
+        let client = self.client.clone().ok_or("No client name set")?;
+        let snapshot = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name)
+            .max_by_key(|s| s.info.snapshot_id)
+            .ok_or_else(|| format!("No snapshot in family '{}'", family_name))?;
+        let hash_ref = snapshot.hash_ref.ok_or("Snapshot has no root hash")?;
+
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let root = message.init_root::<root_capnp::snapshot_list::Builder>();
+            let mut s = root.init_snapshots(1).get(0);
+            s.set_id(snapshot.info.snapshot_id);
+            s.set_family_name(family_name);
+            s.set_msg(&snapshot.msg.unwrap_or("".to_owned()));
+            s.set_utc_timestamp(snapshot.created.timestamp());
+            hash::tree::HashRef::from_bytes(&mut &hash_ref[..])?.populate_msg(s.init_hash_ref());
+        }
+        let mut delta = Vec::new();
+        capnp::serialize_packed::write_message(&mut delta, &message)?;
+
+        // Deltas are append-only: take the number after this client's last one.
+        let seq = self.backend
+            .list()?
+            .iter()
+            .filter_map(|name| client::parse_delta_name(name))
+            .filter(|&(ref c, _)| *c == client)
+            .map(|(_, seq)| seq + 1)
+            .max()
+            .unwrap_or(0);
+        let name = client.delta_name(seq);
+        let sealed = client::seal_delta(&self.keys, &name, &delta);
+        self.backend.store(&name, &crypto::CipherText::new(sealed))?;
+        Ok(self.backend.flush()?)
+    }
+
+    /// Merge the snapshots other clients have published into the local index, so that new
+    /// snapshots deduplicate against their data. Returns the number of merged snapshots.
+    pub fn reconcile(&mut self) -> Result<usize, HatError> {
+        let own = match self.client {
+            Some(ref client) => client.clone(),
+            None => return Ok(0),
+        };
+        let mut deltas: Vec<(Client, u64, Box<[u8]>)> = self.backend
+            .list()?
+            .into_iter()
+            .filter_map(|name| client::parse_delta_name(&name).map(|(c, seq)| (c, seq, name)))
+            .filter(|&(ref c, _, _)| *c != own)
+            .collect();
+        deltas.sort_by(|a, b| (a.0.name(), a.1).cmp(&(b.0.name(), b.1)));
+
+        use chrono::TimeZone;
+        let mut merged = 0;
+        for (client, _, name) in deltas {
+            let sealed = self.backend.retrieve(&name)?.ok_or("Delta disappeared")?;
+            let delta = client::unseal_delta(&self.keys, &name, &sealed)?;
+            let message_reader = capnp::serialize_packed::read_message(
+                &mut &delta[..],
+                capnp::message::ReaderOptions::new(),
+            )?;
+            let snapshot_list = message_reader.get_root::<root_capnp::snapshot_list::Reader>()?;
+
+            for s in snapshot_list.get_snapshots()?.iter() {
+                let family_name = client.foreign_family(s.get_family_name()?);
+                if self.snapshot_index.lookup(&family_name, s.get_id()).is_some() {
+                    continue;
+                }
+                if merged == 0 {
+                    // Make the blobs of other clients known before walking their trees.
+                    self.blob_store.recover()?;
+                }
+                let hash_ref = hash::tree::HashRef::read_msg(&s.get_hash_ref()?)?;
+                self.snapshot_index.recover(
+                    s.get_id(),
+                    &family_name,
+                    chrono::Utc.timestamp(s.get_utc_timestamp(), 0),
+                    s.get_msg()?,
+                    &hash_ref,
+                    Some(db::SnapshotWorkStatus::RecoverInProgress),
+                );
+                merged += 1;
+            }
+        }
+
+        if merged > 0 {
+            self.flush_snapshot_index();
+            self.resume()?;
+        }
+        Ok(merged)
+    }
+
+    /// Replace the cache of tree nodes used when reading snapshots.
+    pub fn set_node_cache(&mut self, cache: NodeCache) {
+        self.node_cache = Arc::new(cache);
+    }
+
+    pub fn node_cache_stats(&self) -> CacheStats {
+        self.node_cache.stats()
+    }
+
+    /// Read the time from `clock`, for the times snapshots are taken and trashed, and for
+    /// deciding which trashed snapshots are past their grace period.
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.clock = clock;
+    }
+
+    /// Take the ids of uploads from `ids`. Applies to the families opened after this call.
+    pub fn set_ids(&mut self, ids: Arc<IdGenerator>) {
+        self.blob_store.set_ids(ids.clone());
+        self.ids = ids;
+    }
+
+    /// Report the progress of checkouts to `progress`. The total is found from the sizes in
+    /// the snapshot before any data is written.
+    pub fn set_restore_progress(&mut self, progress: Option<Arc<RestoreProgress>>) {
+        self.restore_progress = progress;
+    }
+
+    /// Limit how much space the repository may use. The quota is checked before each file is
+    /// stored, so a snapshot stops cleanly instead of running out of space mid-write.
+    pub fn set_quota(&self, quota: blob::Quota) {
+        self.blob_index.set_quota(quota);
+    }
+
+    /// Set how many blobs each blob store may upload at the same time. Applies to the families
+    /// opened after this call.
+    pub fn set_max_uploads(&mut self, max_uploads: usize) {
+        self.max_uploads = max_uploads;
+        self.blob_store.set_max_in_flight(max_uploads);
+    }
+
+    /// Let blob stores pick the blob size between `min` and `max` bytes from the measured
+    /// latency and throughput of the backend. Applies to the families opened after this call.
+    pub fn set_blob_size_bounds(&mut self, min: usize, max: usize) {
+        assert!(min <= max);
+        self.blob_size_bounds = Some((min, max));
+        self.blob_store.set_size_bounds(min, max);
+    }
+
+    /// Give up on flushing data to the backend after `timeout`, so that a hung backend fails the
+    /// commit instead of blocking it forever. Bounds the flushes of key stores and the waits for
+    /// uploads. Applies to the families opened after this call.
+    pub fn set_backend_timeout(&mut self, timeout: Option<Duration>) {
+        self.backend_timeout = timeout;
+        self.blob_store.set_upload_timeout(timeout);
+    }
+
+    /// Write new blobs of `max_blob_size` bytes, here and in every client that opens the
+    /// repository later. The blobs already written keep their size and stay readable.
+    /// Must be called before any family is opened, as families share the blob store.
+    pub fn set_max_blob_size(&mut self, max_blob_size: usize) -> Result<(), HatError> {
+        if self.read_only {
+            return Err(From::from("The repository was opened for reading only"));
+        }
+        if !self.families.is_empty() {
+            return Err(From::from("The blob size cannot change while families are open"));
+        }
+        repo_config::set_max_blob_size(&*self.backend, &self.keys, max_blob_size)?;
+
+        // The current blob of the old store is finished with the old size.
+        self.blob_store.flush()?;
+        self.blob_store = Arc::new(self.new_blob_store(max_blob_size)?);
+        self.blob_max_size = max_blob_size;
         Ok(())
     }
 
+    /// A blob store for blobs of `max_blob_size` bytes, set up like the others.
+    fn new_blob_store(&self, max_blob_size: usize) -> Result<blob::BlobStore<B>, HatError> {
+        let blob_store = blob::BlobStore::new(
+            self.keys.clone(),
+            self.blob_index.clone(),
+            self.backend.clone(),
+            max_blob_size,
+        );
+        blob_store.set_max_in_flight(self.max_uploads);
+        blob_store.set_ids(self.ids.clone());
+        if let Some((min, max)) = self.blob_size_bounds {
+            blob_store.set_size_bounds(min, max);
+        }
+        blob_store.set_spool_dir(self.upload_spool.clone())?;
+        blob_store.set_upload_timeout(self.backend_timeout);
+        Ok(blob_store)
+    }
+
+    /// The parameters stored in the backend, which every client of the repository uses.
+    pub fn repository_config(&self) -> Result<Option<RepoConfig>, HatError> {
+        repo_config::load(&*self.backend, &self.keys)
+    }
+
+    /// Index the words of text files of at most `max_bytes` as they are read, for
+    /// `grep_snapshots`. Applies to the families opened after this call.
+    pub fn set_text_index(&mut self, max_bytes: Option<usize>) {
+        self.text_index_max_bytes = max_bytes;
+    }
+
+    /// Record the content type of files as they are read, detected from their first bytes.
+    /// Applies to the families opened after this call.
+    pub fn set_content_types(&mut self, detect: bool) {
+        self.content_types = detect;
+    }
+
+    /// Read files that change while being read up to `retries` more times, before recording
+    /// them as changed. Applies to the families opened after this call.
+    pub fn set_changed_retries(&mut self, retries: u32) {
+        self.changed_retries = retries;
+    }
+
+    /// Complete or remove the hash entries that earlier runs reserved but never committed.
+    /// Returns the number of completed and removed entries, counting those resolved when the
+    /// repository was opened.
+    pub fn resolve_reserved_hashes(&self) -> Result<(u64, u64), HatError> {
+        if self.read_only {
+            return Err(From::from("The repository was opened for reading only"));
+        }
+        let (completed, removed) = self.hash_index.resolve_reserved();
+        let (on_open_completed, on_open_removed) = self.hash_index.resolved_on_open();
+        self.meta_flush();
+        Ok((completed + on_open_completed, removed + on_open_removed))
+    }
+
+    /// Store new copies of the chunks that a check found damaged when a snapshot has the same
+    /// data, instead of referring to the damaged copies.
+    pub fn set_rewrite_suspect(&self, rewrite: bool) {
+        self.hash_index.set_rewrite_suspect(rewrite);
+    }
+
+    /// Check that `extra` more bytes can be stored within the current quota.
+    pub fn check_space(&self, extra: u64) -> Result<(), HatError> {
+        Ok(self.blob_store.check_space(extra)?)
+    }
+
     pub fn flush_snapshot_index(&mut self) {
         self.snapshot_index.flush();
     }
 
-    pub fn flush_blob_store(&self) {
-        self.blob_store.flush();
+    pub fn flush_blob_store(&self) -> Result<(), HatError> {
+        Ok(self.blob_store.flush()?)
     }
 
     pub fn checkout_in_dir(
         &mut self,
         family_name: String,
         output_dir: PathBuf,
+        policy: &MetadataPolicy,
+        order: RestoreOrder,
+    ) -> Result<(), HatError> {
+        self.checkout_selected_in_dir(
+            family_name,
+            output_dir,
+            policy,
+            order,
+            None,
+            CollisionPolicy::default(),
+        )
+    }
+
+    /// Like `checkout_in_dir`, but only restore the paths in `selection`, if given. The
+    /// directories leading to them are created as well. What is already in `output_dir` is
+    /// dealt with according to `collisions`.
+    ///
+    /// Files below a selected path are left out if their content type is not selected.
+    pub fn checkout_selected_in_dir(
+        &mut self,
+        family_name: String,
+        output_dir: PathBuf,
+        policy: &MetadataPolicy,
+        order: RestoreOrder,
+        selection: Option<&PathSelection>,
+        collisions: CollisionPolicy,
     ) -> Result<(), HatError> {
         // Extract latest snapshot info:
         let (_info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
@@ -850,13 +1722,59 @@ impl<B: StoreBackend> HatRc<B> {
             }
         };
 
+        // Reading archived blobs directly would only time out.
+        if self.backend.capabilities().archival {
+            let staged = self.stage(family_name.clone(), None)?;
+            if !staged.is_ready() {
+                return Err(From::from(format!(
+                    "The snapshot is not staged yet: {}. Run hat stage again once the \
+                     restores complete.",
+                    staged
+                )));
+            }
+        }
+
         let family = self.open_family(family_name.clone()).expect(&format!(
             "Could not open family '{}'",
             family_name
         ));
 
+        let root = output_dir.clone();
+        let options = CheckoutOptions {
+            policy: policy,
+            collisions: collisions,
+            types: selection,
+        };
+        let selection = selection.map(|s| (s, root.as_path()));
         let mut output_dir = output_dir;
-        self.checkout_dir_ref(&family, &mut output_dir, dir_ref)
+        if let Some(ref progress) = self.restore_progress {
+            let mut path = output_dir.clone();
+            let total =
+                self.restore_size(&family, &mut path, dir_ref.clone(), &options, selection)?;
+            progress.set_total(total);
+        }
+        match order {
+            RestoreOrder::Tree => {
+                self.checkout_dir_ref(&family, &mut output_dir, dir_ref, &options, selection, None)
+            }
+            RestoreOrder::Blob => {
+                // Create the tree with empty files first, then fill them in blob by blob.
+                let mut restore = restore_order::BlobOrderRestore::new();
+                {
+                    let deferred = Some(&mut restore);
+                    self.checkout_dir_ref(
+                        &family,
+                        &mut output_dir,
+                        dir_ref,
+                        &options,
+                        selection,
+                        deferred,
+                    )?;
+                }
+                let progress = self.restore_progress.as_ref().map(|p| &**p);
+                restore.run(&self.hash_backend(), policy, progress)
+            }
+        }
     }
 
     fn checkout_dir_ref(
@@ -864,39 +1782,83 @@ impl<B: StoreBackend> HatRc<B> {
         family: &Family<B>,
         output: &mut PathBuf,
         dir_hash: hash::tree::HashRef,
+        options: &CheckoutOptions,
+        selection: Option<(&PathSelection, &Path)>,
+        mut deferred: Option<&mut restore_order::BlobOrderRestore>,
     ) -> Result<(), HatError> {
         fs::create_dir_all(&output).unwrap();
         for (entry, hash_ref) in family.fetch_dir_data(dir_hash, self.hash_backend())? {
             assert!(entry.info.name.len() > 0);
 
             output.push(str::from_utf8(&entry.info.name[..]).unwrap());
+
+            let is_dir = match hash_ref {
+                walker::Content::Dir(_) => true,
+                _ => false,
+            };
+
+            let child_selection = match select_entry(output, &entry, is_dir, options, selection) {
+                Some(child_selection) => child_selection,
+                None => {
+                    output.pop();
+                    continue;
+                }
+            };
+
+            if !collision::prepare(options.collisions, output, &entry.info, is_dir)? {
+                output.pop();
+                continue;
+            }
             println!("{}", output.display());
 
-            match hash_ref {
+            let is_symlink = match hash_ref {
                 walker::Content::Data(hash_ref) => {
                     let mut fd = fs::File::create(&output).unwrap();
-                    let tree_opt = hash::tree::LeafIterator::new(self.hash_backend(), hash_ref)?;
-                    if let Some(tree) = tree_opt {
-                        family.write_file_chunks(&mut fd, tree);
+                    if let Some(ref mut restore) = deferred {
+                        let chunks = hash::tree::leaf_refs(self.hash_backend(), hash_ref)?;
+                        restore.add_file(output.clone(), chunks);
+                        if restore.is_full() {
+                            let progress = self.restore_progress.as_ref().map(|p| &**p);
+                            restore.write_files(&self.hash_backend(), progress)?;
+                        }
+                    } else {
+                        let tree_opt =
+                            hash::tree::LeafIterator::new(self.hash_backend(), hash_ref)?;
+                        if let Some(tree) = tree_opt {
+                            let progress = self.restore_progress.as_ref();
+                            family.write_file_chunks(&mut fd, tree, |len| {
+                                if let Some(p) = progress {
+                                    p.add(len as u64);
+                                }
+                            })?;
+                        }
                     }
+                    false
                 }
                 walker::Content::Dir(hash_ref) => {
-                    self.checkout_dir_ref(family, output, hash_ref)?;
+                    let child_deferred = deferred.as_mut().map(|r| &mut **r);
+                    self.checkout_dir_ref(
+                        family,
+                        output,
+                        hash_ref,
+                        options,
+                        child_selection,
+                        child_deferred,
+                    )?;
+                    false
                 }
                 walker::Content::Link(link_path) => {
                     use std::os::unix::fs::symlink;
-                    symlink(link_path, &output)?
+                    symlink(link_path, &output)?;
+                    true
                 }
-            }
-
-            if let Some(perms) = entry.info.permissions {
-                fs::set_permissions(&output, perms)?;
-            }
+            };
 
-            if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
-                let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
-                let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
-                filetime::set_file_times(&output, atime, mtime)?;
+            match deferred {
+                Some(ref mut restore) => {
+                    restore.add_metadata(output.clone(), entry.info, is_symlink)
+                }
+                None => options.policy.apply(&output, &entry.info, is_symlink)?,
             }
 
             output.pop();
@@ -904,12 +1866,563 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
-    pub fn deregister_by_name(
+    /// The number of bytes a checkout of `dir_hash` into `output` would write, from the sizes
+    /// recorded in the hash trees. Files that already exist are counted as well.
+    fn restore_size(
+        &self,
+        family: &Family<B>,
+        output: &mut PathBuf,
+        dir_hash: hash::tree::HashRef,
+        options: &CheckoutOptions,
+        selection: Option<(&PathSelection, &Path)>,
+    ) -> Result<u64, HatError> {
+        let mut total = 0;
+        for (entry, content) in family.fetch_dir_data(dir_hash, self.hash_backend())? {
+            output.push(str::from_utf8(&entry.info.name[..]).unwrap());
+            let is_dir = match content {
+                walker::Content::Dir(_) => true,
+                _ => false,
+            };
+            let selected = select_entry(output, &entry, is_dir, options, selection);
+            if let Some(child_selection) = selected {
+                total += match content {
+                    walker::Content::Data(hash_ref) => {
+                        hash::tree::tree_size(self.hash_backend(), hash_ref)?.bytes
+                    }
+                    walker::Content::Dir(hash_ref) => {
+                        self.restore_size(family, output, hash_ref, options, child_selection)?
+                    }
+                    walker::Content::Link(_) => 0,
+                };
+            }
+            output.pop();
+        }
+        Ok(total)
+    }
+
+    /// Find the top of a complete snapshot, or of the latest one if no `snapshot_id` is given.
+    fn snapshot_root(
         &mut self,
-        family_name: String,
-        snapshot_id: u64,
-    ) -> Result<(), HatError> {
-        let family = self.open_family(family_name)?;
+        family_name: &str,
+        snapshot_id: Option<u64>,
+    ) -> Result<hash::tree::HashRef, HatError> {
+        let found = match snapshot_id {
+            Some(id) => self.snapshot_index.lookup(&family_name, id),
+            None => self.snapshot_index.latest(&family_name),
+        };
+        match found {
+            Some((_, _, Some(r))) => Ok(r),
+            _ => {
+                Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        }
+    }
+
+    /// The paths deleted since the previous snapshot of the family, as recorded when the
+    /// snapshot was taken. Uses the latest snapshot of the family if no `snapshot_id` is given.
+    pub fn deleted_paths(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+    ) -> Result<Vec<PathBuf>, HatError> {
+        let found = match snapshot_id {
+            Some(id) => self.snapshot_index.lookup(family_name, id),
+            None => self.snapshot_index.latest(family_name),
+        };
+        match found {
+            Some((info, _, _)) => Ok(self.snapshot_index.deletions(&info)),
+            None => {
+                Err(From::from(format!(
+                    "No snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        }
+    }
+
+    /// The directories left out of a snapshot of the family, the latest if no id is given,
+    /// with the marker file or attribute that excluded each.
+    pub fn excluded_dirs(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+    ) -> Result<Vec<(PathBuf, String)>, HatError> {
+        let found = match snapshot_id {
+            Some(id) => self.snapshot_index.lookup(family_name, id),
+            None => self.snapshot_index.latest(family_name),
+        };
+        match found {
+            Some((info, _, _)) => Ok(self.snapshot_index.exclusions(&info)),
+            None => {
+                Err(From::from(format!(
+                    "No snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        }
+    }
+
+    /// The snapshots in which `path` was found deleted, of one family if given, oldest first.
+    /// Paths are relative to the top of the family, like the paths in its snapshots.
+    pub fn path_deletions(&mut self, family_name: Option<&str>, path: &Path) -> Vec<PathDeletion> {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        self.snapshot_index.path_deletions(family_name, path.as_os_str().as_bytes())
+    }
+
+    /// The versions of `path` in the snapshots of a family, oldest first, including the
+    /// snapshots that recorded it as deleted.
+    pub fn path_history(
+        &mut self,
+        family_name: &str,
+        path: &Path,
+    ) -> Result<Vec<PathVersion>, HatError> {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        self.snapshot_index
+            .path_history(family_name, path.as_os_str().as_bytes())
+            .ok_or_else(|| From::from(format!("No family named {}", family_name)))
+    }
+
+    /// Write the subtree at `path` of a snapshot to `out` as a tar stream.
+    /// Uses the latest snapshot of the family if no `snapshot_id` is given.
+    pub fn export_tar<W: io::Write>(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        path: &Path,
+        out: W,
+    ) -> Result<W, HatError> {
+        let dir_ref = self.snapshot_root(&family_name, snapshot_id)?;
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend();
+        let (entry, content) = export::resolve(&family, &backend, dir_ref, path)?;
+        export::write_tar(&family, &backend, entry, content, out)
+    }
+
+    /// List the directory at `path` in a snapshot, or the latest one if no `snapshot_id` is
+    /// given. With `check`, entries whose data is missing from the local indexes are flagged
+    /// as dangling, instead of failing later during a restore.
+    pub fn list_dir(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        path: &Path,
+        check: bool,
+    ) -> Result<Vec<ListedEntry>, HatError> {
+        let dir_ref = self.snapshot_root(&family_name, snapshot_id)?;
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend();
+        let dir_ref = match export::resolve(&family, &backend, dir_ref, path)?.1 {
+            walker::Content::Dir(dir_ref) => dir_ref,
+            _ => return Err(From::from(format!("Not a directory: {}", path.display()))),
+        };
+
+        let mut listing = vec![];
+        for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+            let dangling = match content {
+                walker::Content::Data(ref r) |
+                walker::Content::Dir(ref r) if check => {
+                    listing::check_ref(&self.hash_index, &self.blob_index, r)
+                }
+                _ => None,
+            };
+            listing.push(ListedEntry {
+                entry: entry,
+                is_dir: match content {
+                    walker::Content::Dir(_) => true,
+                    _ => false,
+                },
+                link_target: match content {
+                    walker::Content::Link(target) => Some(target),
+                    _ => None,
+                },
+                dangling: dangling,
+            });
+        }
+        Ok(listing)
+    }
+
+    /// List a snapshot as an iterator of its entries, fetching directories as the walk reaches
+    /// them. Uses the latest snapshot of the family if no `snapshot_id` is given.
+    pub fn walk(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+    ) -> Result<SnapshotWalk<B>, HatError> {
+        let dir_ref = self.snapshot_root(&family_name, snapshot_id)?;
+        let family = self.open_family(family_name)?;
+        Ok(SnapshotWalk::new(family, self.hash_backend(), dir_ref))
+    }
+
+    /// List how the directory `local` differs from a snapshot of it, without writing anything.
+    /// Uses the latest snapshot of the family if no `snapshot_id` is given.
+    pub fn status(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        local: &Path,
+    ) -> Result<Vec<(Change, PathBuf)>, HatError> {
+        let dir_ref = self.snapshot_root(&family_name, snapshot_id)?;
+        let family = self.open_family(family_name)?;
+        status::compare(&family, &self.hash_backend(), dir_ref, local)
+    }
+
+    /// Restore a random sample of up to `sample` files from a snapshot into `scratch`, verify
+    /// them and delete them again. Uses the latest snapshot of the family if no `snapshot_id` is
+    /// given.
+    pub fn restore_drill(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        sample: usize,
+        scratch: &Path,
+    ) -> Result<DrillReport, HatError> {
+        let dir_ref = self.snapshot_root(&family_name, snapshot_id)?;
+        let family = self.open_family(family_name)?;
+        restore_drill::run(&family, &self.hash_backend(), dir_ref, sample, scratch)
+    }
+
+    /// Request restores of the archived blobs of a snapshot, and report how many are readable.
+    /// Uses the latest snapshot of the family if no `snapshot_id` is given.
+    pub fn stage(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+    ) -> Result<StageReport, HatError> {
+        let dir_ref = self.snapshot_root(&family_name, snapshot_id)?;
+        let family = self.open_family(family_name)?;
+        stage::run(
+            &family,
+            &self.hash_index,
+            &self.hash_backend(),
+            &self.blob_index,
+            &*self.backend,
+            dir_ref,
+        )
+    }
+
+    /// Register an existing snapshot under another family without copying any data.
+    /// Returns the snapshot id of the clone.
+    pub fn clone_snapshot(
+        &mut self,
+        family_name: String,
+        snapshot_id: u64,
+        new_family_name: String,
+    ) -> Result<u64, HatError> {
+        let family = self.open_family(family_name.clone())?;
+        let (top_hash, top_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        if top_ref.leaf != blob::LeafType::TreeList {
+            return Err(From::from("Only file snapshots can be cloned"));
+        }
+
+        let snap_info = self.snapshot_index.reserve(new_family_name, self.clock.now());
+        self.snapshot_index.update(&snap_info, &top_hash, &top_ref);
+        self.meta_flush();
+
+        // Reference the same hashes as the original, as a commit would have.
+        for content in list_snapshot(&self.hash_backend(), &family, top_ref) {
+            let href = match content? {
+                walker::Content::Data(href) |
+                walker::Content::Dir(href) => href,
+                walker::Content::Link(_) => continue,
+            };
+            let id = self.hash_index.get_id(&href.hash).expect("Unknown hash in snapshot");
+            self.hash_index.set_tag(id, tags::Tag::Reserved);
+        }
+
+        let hash_id = self.hash_index.get_id(&top_hash).expect("Hash does not exist");
+        self.gc.register_final(&snap_info, hash_id)?;
+        self.meta_flush();
+
+        let new_id = snap_info.snapshot_id;
+        self.commit_finalize(snap_info, &top_hash)?;
+
+        Ok(new_id)
+    }
+
+    /// Move a snapshot to another family. Returns its new snapshot id.
+    pub fn rename_snapshot(
+        &mut self,
+        family_name: String,
+        snapshot_id: u64,
+        new_family_name: String,
+    ) -> Result<u64, HatError> {
+        let info = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((i, _, Some(_))) => i,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        let new_info = self.snapshot_index.rename(&info, &new_family_name);
+        self.flush_snapshot_index();
+        Ok(new_info.snapshot_id)
+    }
+
+    /// Move a snapshot to the trash. Its data is kept until `purge_trash` runs after the grace
+    /// period, and it can be restored with `undelete_by_name` until then.
+    pub fn trash_by_name(&mut self, family_name: String, snapshot_id: u64) -> Result<(), HatError> {
+        let info = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((i, _, Some(_))) => i,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        if self.snapshot_index.is_pinned(&info) {
+            return Err(From::from(
+                format!("Snapshot {} #{} is pinned", family_name, snapshot_id),
+            ));
+        }
+        self.snapshot_index.trash(&info, self.clock.now());
+        self.flush_snapshot_index();
+        Ok(())
+    }
+
+    /// The complete snapshots of all families, oldest first within each family.
+    pub fn list_snapshots(&mut self) -> Vec<SnapshotSummary> {
+        let roots = synthetic_roots_family();
+        let mut snapshots: Vec<SnapshotSummary> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name != roots && s.hash_ref.is_some())
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .map(|s| {
+                SnapshotSummary {
+                    family_name: s.family_name,
+                    snapshot_id: s.info.snapshot_id,
+                    created: s.created,
+                    msg: s.msg,
+                    trashed: s.trashed.is_some(),
+                    pinned: s.pinned,
+                    stats: s.stats,
+                    renames: s.renames,
+                    fuzzy_files: s.fuzzy_files,
+                    commands: s.commands,
+                }
+            })
+            .collect();
+        snapshots.sort_by(|a, b| {
+            (&a.family_name, a.snapshot_id).cmp(&(&b.family_name, b.snapshot_id))
+        });
+        snapshots
+    }
+
+    /// Find the entries whose name matches `pattern` in the complete snapshots outside the
+    /// trash, of `family_name` if given. `*` and `?` match as in `PathSelection`.
+    ///
+    /// This only reads the local index of snapshot paths, which is filled in by commits.
+    pub fn find_paths(&mut self, pattern: &str, family_name: Option<&str>) -> Vec<PathMatch> {
+        let snapshots = self.live_snapshot_ids();
+
+        // Names without wildcards are looked up directly.
+        let pattern = pattern.as_bytes();
+        let exact = if pattern.iter().any(|&b| b == b'*' || b == b'?') {
+            None
+        } else {
+            Some(pattern)
+        };
+
+        let rows = self.snapshot_index
+            .paths(family_name, exact)
+            .into_iter()
+            .filter(|&(_, ref row)| path_selection::name_matches(pattern, &row.name))
+            .collect();
+        path_matches(rows, snapshots)
+    }
+
+    /// The groups of files in a snapshot of `family_name` that have the same data, the latest
+    /// snapshot if no id is given. Groups that save the most space by sharing come first.
+    ///
+    /// This only reads the local index of snapshot paths, which is filled in by commits.
+    pub fn duplicate_files(
+        &mut self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+    ) -> Result<Vec<DuplicateFiles>, HatError> {
+        let found = match snapshot_id {
+            Some(id) => self.snapshot_index.lookup(family_name, id),
+            None => self.snapshot_index.latest(family_name),
+        };
+        let info = match found {
+            Some((info, _, _)) => info,
+            None => {
+                return Err(From::from(format!(
+                    "No snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        let rows = self.snapshot_index
+            .paths(Some(family_name), None)
+            .into_iter()
+            .map(|(_, row)| row)
+            .collect();
+        Ok(dupes::group(rows, info.snapshot_id))
+    }
+
+    /// Find the files containing `word` in the complete snapshots outside the trash, of
+    /// `family_name` if given. Only files whose words were indexed when they were read, with
+    /// `set_text_index`, can be found.
+    pub fn grep_snapshots(&mut self, word: &str, family_name: Option<&str>) -> Vec<PathMatch> {
+        let snapshots = self.live_snapshot_ids();
+        let hashes = self.snapshot_index.text_word_hashes(&word.to_lowercase());
+        let rows = self.snapshot_index.paths_with_data(family_name, &hashes);
+        path_matches(rows, snapshots)
+    }
+
+    /// Forget the indexed words of all files. Returns how many words were forgotten.
+    pub fn purge_text_index(&mut self) -> usize {
+        let purged = self.snapshot_index.purge_text_words();
+        self.flush_snapshot_index();
+        purged
+    }
+
+    /// The ids of the complete snapshots outside the trash, by family.
+    fn live_snapshot_ids(&mut self) -> HashMap<String, Vec<u64>> {
+        let mut snapshots: HashMap<String, Vec<u64>> = HashMap::new();
+        for s in self.list_snapshots() {
+            if !s.trashed {
+                snapshots.entry(s.family_name).or_insert_with(Vec::new).push(s.snapshot_id);
+            }
+        }
+        snapshots
+    }
+
+    /// Write the hash index to `out` as CSV, one line per hash, for analysis in other tools.
+    pub fn dump_hash_index<W: io::Write>(&self, out: &mut W) -> Result<(), HatError> {
+        dump::write_hashes(self.hash_index.list_rows(), out)
+    }
+
+    /// Write the key indexes of the families with snapshots to `out` as CSV, one line per
+    /// entry of the latest snapshot of each family.
+    pub fn dump_key_index<W: io::Write>(&mut self, out: &mut W) -> Result<(), HatError> {
+        let mut names: Vec<String> =
+            self.list_snapshots().into_iter().map(|s| s.family_name).collect();
+        names.dedup();
+
+        dump::write_entries_header(out)?;
+        for name in names {
+            let family = self.open_family(name.clone())?;
+            dump::write_entries(&name, family.key_store.entries()?, out)?;
+        }
+        Ok(())
+    }
+
+    /// Pin a snapshot, so that it cannot be deleted or purged from the trash until it is
+    /// unpinned again.
+    pub fn pin_by_name(&mut self, family_name: String, snapshot_id: u64) -> Result<(), HatError> {
+        let info = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((i, _, Some(_))) => i,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        self.snapshot_index.pin(&info);
+        self.flush_snapshot_index();
+        Ok(())
+    }
+
+    /// Allow a pinned snapshot to be deleted again.
+    pub fn unpin_by_name(&mut self, family_name: String, snapshot_id: u64) -> Result<(), HatError> {
+        let info = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((i, _, _)) => i,
+            None => {
+                return Err(From::from(format!(
+                    "No snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        self.snapshot_index.unpin(&info);
+        self.flush_snapshot_index();
+        Ok(())
+    }
+
+    /// Restore a snapshot from the trash. Fails for a snapshot that is not in the trash.
+    pub fn undelete_by_name(
+        &mut self,
+        family_name: String,
+        snapshot_id: u64,
+    ) -> Result<(), HatError> {
+        let info = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((i, _, _)) => i,
+            None => {
+                return Err(From::from(format!(
+                    "No snapshot found for family {} with id {:?}",
+                    family_name,
+                    snapshot_id
+                )))
+            }
+        };
+        let trashed = self.snapshot_index.list_all().into_iter().any(|s| {
+            s.family_name == family_name && s.info.snapshot_id == snapshot_id &&
+                s.trashed.is_some()
+        });
+        if !trashed {
+            return Err(From::from(format!(
+                "Snapshot {} of family {} is not in the trash",
+                snapshot_id,
+                family_name
+            )));
+        }
+        self.snapshot_index.untrash(&info);
+        self.flush_snapshot_index();
+        Ok(())
+    }
+
+    /// Delete all snapshots that have been in the trash for longer than `grace`, except for
+    /// pinned ones. Returns the number of deleted snapshots.
+    pub fn purge_trash(&mut self, grace: chrono::Duration) -> Result<u64, HatError> {
+        let cutoff = self.clock.now() - grace;
+        let mut purged = 0;
+        for snapshot in self.snapshot_index.list_all() {
+            match snapshot.trashed {
+                Some(when) if when <= cutoff && !snapshot.pinned => {
+                    self.deregister_by_name(snapshot.family_name, snapshot.info.snapshot_id)?;
+                    purged += 1;
+                }
+                _ => (),
+            }
+        }
+        Ok(purged)
+    }
+
+    pub fn deregister_by_name(
+        &mut self,
+        family_name: String,
+        snapshot_id: u64,
+    ) -> Result<(), HatError> {
+        let family = self.open_family(family_name)?;
         self.deregister(&family, snapshot_id)?;
 
         Ok(())
@@ -929,6 +2442,12 @@ impl<B: StoreBackend> HatRc<B> {
                 }
             };
 
+        if self.snapshot_index.is_pinned(&info) {
+            return Err(From::from(
+                format!("Snapshot {} #{} is pinned", family.name, snapshot_id),
+            ));
+        }
+
         // Make the snapshot to enable resuming.
         self.snapshot_index.will_delete(&info);
         self.flush_snapshot_index();
@@ -1020,39 +2539,614 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn gc(&mut self) -> Result<(u64, u64), HatError> {
+        let (deleted_hashes, live_blobs) = self.gc_offline()?;
+        self.prune()?;
+
+        Ok((deleted_hashes, live_blobs))
+    }
+
+    /// Remove unused hashes and mark the blobs no longer referenced by any hash for deletion.
+    /// This only uses the local index: no blobs are read or deleted until `prune` is called.
+    pub fn gc_offline(&mut self) -> Result<(u64, u64), HatError> {
+        // Backups may run while we collect. Their hashes are not registered yet, so keep
+        // everything they wrote or reused since they started, as told by the epoch pins.
+        let keep_from = self.hash_index.begin_gc_epoch();
+
         // Remove unused hashes.
-        let mut deleted_hashes = 0;
         let (sender, receiver) = mpsc::channel();
         self.gc.list_unused_ids(sender)?;
-        for id in receiver.iter() {
-            deleted_hashes += 1;
-            self.hash_index.delete(id);
-        }
+        let deleted_hashes = self.hash_index.delete_unused(receiver.iter().collect(), keep_from);
         self.hash_index.flush();
-        // Mark used blobs.
+
+        // Mark used blobs. Only uploaded blobs are candidates, as a backup may still be
+        // uploading others.
+        self.blob_store.retag(tags::Tag::Done, tags::Tag::Reserved);
+        self.blob_store.retag(tags::Tag::WillDelete, tags::Tag::Reserved);
         let entries = self.hash_index.list();
-        self.blob_store.tag_all(tags::Tag::InProgress);
 
         let mut live_blobs = 0;
         for entry in entries {
             if let Some(pref) = entry.persistent_ref {
                 live_blobs += 1;
-                self.blob_store.tag(pref, tags::Tag::Reserved);
+                self.blob_store.retag_chunk(pref, tags::Tag::Reserved, tags::Tag::Done);
             }
         }
-        // Anything still marked "in progress" is not referenced by any hash.
-        self.blob_store.delete_by_tag(tags::Tag::InProgress)?;
-        self.blob_store.tag_all(tags::Tag::Done);
-        self.blob_store.flush();
+        // Anything still marked "reserved" is not referenced by any hash.
+        self.blob_store.retag(tags::Tag::Reserved, tags::Tag::WillDelete);
+        self.blob_store.flush()?;
 
         Ok((deleted_hashes, live_blobs))
     }
 
+    /// Whether the backend can delete blobs, which `prune` needs to do anything.
+    pub fn can_delete(&self) -> bool {
+        self.backend.capabilities().delete
+    }
+
+    /// Number of blobs and their total size in bytes that `prune` would delete.
+    pub fn prune_pending(&self) -> (u64, u64) {
+        let marked: HashSet<i64> = self.blob_index
+            .list_by_tag(tags::Tag::WillDelete)
+            .into_iter()
+            .map(|b| b.id)
+            .collect();
+        self.blob_index
+            .list_sizes()
+            .into_iter()
+            .filter(|&(id, _)| marked.contains(&id))
+            .fold((0, 0), |(count, bytes), (_, size)| (count + 1, bytes + size))
+    }
+
+    /// Delete the blobs marked by `gc_offline` from the backend. Returns the number of blobs
+    /// deleted. Backends that cannot delete keep the blobs marked, so nothing is deleted.
+    pub fn prune(&mut self) -> Result<u64, HatError> {
+        self.prune_for(None, None)
+    }
+
+    /// Like `prune`, but stop before the next blob once `max_duration` has passed, and delete
+    /// no more blob bytes per second than `throttle` allows, so that it can run alongside
+    /// other work.
+    ///
+    /// Blobs are deleted one at a time and forgotten right away, so a prune that runs out of
+    /// time or is interrupted continues where it stopped the next time.
+    pub fn prune_for(
+        &mut self,
+        max_duration: Option<Duration>,
+        throttle: Option<&Throttle>,
+    ) -> Result<u64, HatError> {
+        let start = Instant::now();
+        let blobs = self.blob_store.list_by_tag(tags::Tag::WillDelete);
+        if !blobs.is_empty() && !self.backend.capabilities().delete {
+            warn!("Backend does not support deletion, keeping {} unused blobs", blobs.len());
+            return Ok(0);
+        }
+        let sizes: HashMap<i64, u64> = self.blob_index.list_sizes().into_iter().collect();
+
+        let mut count = 0;
+        for blob in blobs {
+            if max_duration.map_or(false, |max| start.elapsed() >= max) {
+                break;
+            }
+            if let Some(throttle) = throttle {
+                throttle.consume(sizes.get(&blob.id).cloned().unwrap_or(0) as usize);
+            }
+            self.blob_store.delete(&blob)?;
+            count += 1;
+        }
+        self.blob_store.flush()?;
+
+        Ok(count)
+    }
+
+    /// Estimate what `purge_trash(grace)` followed by `gc()` would remove, without modifying
+    /// anything. Only complete snapshots are taken into account.
+    pub fn gc_plan(&mut self, grace: chrono::Duration) -> Result<GcPlan, HatError> {
+        let cutoff = self.clock.now() - grace;
+        let mut snapshots = vec![];
+        for snapshot in self.snapshot_index.list_all() {
+            let top_ref = match (snapshot.status, snapshot.hash_ref) {
+                (db::SnapshotWorkStatus::CommitComplete, Some(bytes)) => {
+                    hash::tree::HashRef::from_bytes(&mut &bytes[..])?
+                }
+                _ => continue,
+            };
+            let family = self.open_family(snapshot.family_name.clone())?;
+            snapshots.push(gc_plan::SnapshotRefs {
+                family_name: snapshot.family_name,
+                snapshot_id: snapshot.info.snapshot_id,
+                purge: !snapshot.pinned && snapshot.trashed.map_or(false, |when| when <= cutoff),
+                ids: self.list_snapshot_ids(&family, top_ref)?,
+            });
+        }
+
+        Ok(gc_plan::plan(
+            self.hash_index.list_nodes(),
+            self.blob_index.list_sizes(),
+            snapshots,
+        ))
+    }
+
+    /// Make a signed attestation of a complete snapshot, to publish outside the repository.
+    pub fn attest(&mut self, family_name: &str, snapshot_id: u64) -> Result<String, HatError> {
+        let attestation = self.attestation_of(family_name, snapshot_id)?;
+        Ok(attestation.sign(&self.keys))
+    }
+
+    /// Check a signed attestation against the repository. Fails if the signature does not
+    /// match, if another repository made it, or if the snapshot changed since.
+    pub fn verify_attestation(&mut self, text: &str) -> Result<Attestation, HatError> {
+        let (attested, public_key) = Attestation::verify_signed(text)?;
+        if public_key[..] != self.keys.signing_public_key()[..] {
+            return Err(From::from(
+                VerificationError::from("The attestation was made by another repository"),
+            ));
+        }
+        let current = self.attestation_of(&attested.family_name, attested.snapshot_id)
+            .map_err(|e| {
+                VerificationError::from(format!("The attested snapshot is gone: {}", e))
+            })?;
+        if (&current.root_hash, current.chunks, current.bytes) !=
+            (&attested.root_hash, attested.chunks, attested.bytes)
+        {
+            return Err(From::from(VerificationError::from(format!(
+                "Snapshot {} of family {} changed since it was attested on {}",
+                attested.snapshot_id,
+                attested.family_name,
+                attested.attested.to_rfc3339()
+            ))));
+        }
+        Ok(attested)
+    }
+
+    fn attestation_of(
+        &mut self,
+        family_name: &str,
+        snapshot_id: u64,
+    ) -> Result<Attestation, HatError> {
+        let snapshot = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .find(|s| s.family_name == family_name && s.info.snapshot_id == snapshot_id)
+            .ok_or_else(|| {
+                format!("No snapshot {} in family {}", snapshot_id, family_name)
+            })?;
+        let (hash, top_ref) = match (snapshot.status, snapshot.hash, snapshot.hash_ref) {
+            (db::SnapshotWorkStatus::CommitComplete, Some(hash), Some(bytes)) => {
+                (hash, hash::tree::HashRef::from_bytes(&mut &bytes[..])?)
+            }
+            _ => return Err(From::from("Only complete snapshots can be attested")),
+        };
+        let family = self.open_family(family_name.to_owned())?;
+        let ids = self.list_snapshot_ids(&family, top_ref)?;
+        let (chunks, bytes) = attest::count_reachable(self.hash_index.list_nodes(), ids);
+        Ok(Attestation {
+            family_name: family_name.to_owned(),
+            snapshot_id: snapshot_id,
+            created: snapshot.created,
+            root_hash: hash.bytes,
+            chunks: chunks,
+            bytes: bytes,
+            attested: self.clock.now(),
+        })
+    }
+
+    /// Summarize the state of the repository, from its format to whether a process holds it.
+    pub fn repo_info(&mut self) -> Result<RepoInfo, HatError> {
+        let mut index_files = vec![];
+        if let Some(ref root) = self.repository_root {
+            for entry in fs::read_dir(root)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if sealed_index::is_index_file(&name) {
+                    index_files.push((name, entry.metadata()?.len()));
+                }
+            }
+        }
+        index_files.sort();
+
+        let locked = match (&self._lock, &self.repository_root) {
+            (&Some(_), _) => true,
+            (&None, &Some(ref root)) => RepositoryLock::is_held(root)?,
+            (&None, &None) => false,
+        };
+        let utc = |secs: i64| {
+            chrono::DateTime::from_utc(
+                chrono::NaiveDateTime::from_timestamp(secs, 0),
+                chrono::Utc,
+            )
+        };
+
+        let snapshots = self.list_snapshots();
+        let families: HashSet<&str> = snapshots.iter().map(|s| &s.family_name[..]).collect();
+        let blob_sizes = self.blob_index.list_sizes();
+        let (prunable_blobs, _) = self.prune_pending();
+        let db = self.db.lock();
+        Ok(RepoInfo {
+            format_version: repo_format::format_version(&*self.backend)?,
+            config: repo_config::load(&*self.backend, &self.keys)?,
+            sealed_index: self.sealed_index.is_some(),
+            index_files: index_files,
+            blobs: blob_sizes.len() as u64,
+            blob_bytes: blob_sizes.iter().map(|&(_, size)| size).sum(),
+            prunable_blobs: prunable_blobs,
+            snapshots: snapshots.len() as u64,
+            families: families.len() as u64,
+            reserved_hashes: db.hash_count_unready(),
+            last_gc: db.gc_last_run().map(&utc),
+            last_verified: db.blob_last_verified().map(&utc),
+            locked: locked,
+        })
+    }
+
+    /// Count the data each family keeps alive, split into chunks that only the family refers
+    /// to and chunks it shares with other families. Only complete snapshots are taken into
+    /// account, including those in the trash.
+    pub fn family_usage(&mut self) -> Result<Vec<FamilyUsage>, HatError> {
+        let mut families: Vec<(String, Vec<u64>)> = vec![];
+        for snapshot in self.snapshot_index.list_all() {
+            let top_ref = match (snapshot.status, snapshot.hash_ref) {
+                (db::SnapshotWorkStatus::CommitComplete, Some(bytes)) => {
+                    hash::tree::HashRef::from_bytes(&mut &bytes[..])?
+                }
+                _ => continue,
+            };
+            let family = self.open_family(snapshot.family_name.clone())?;
+            let ids = self.list_snapshot_ids(&family, top_ref)?;
+            match families.iter().position(|&(ref name, _)| *name == snapshot.family_name) {
+                Some(i) => families[i].1.extend(ids),
+                None => families.push((snapshot.family_name, ids)),
+            }
+        }
+        Ok(sharing::family_usage(self.hash_index.list_nodes(), families))
+    }
+
+    /// The files in the latest state of the families with snapshots by content type, as
+    /// recorded when they were committed with content type detection.
+    pub fn content_type_usage(&mut self) -> Result<Vec<TypeUsage>, HatError> {
+        let mut names: Vec<String> =
+            self.list_snapshots().into_iter().map(|s| s.family_name).collect();
+        names.sort();
+        names.dedup();
+
+        let mut counts = vec![];
+        for name in names {
+            let family = self.open_family(name)?;
+            counts.extend(family.key_store.content_types()?);
+        }
+        Ok(type_usage::merge(counts))
+    }
+
+    /// The snapshots of each family in order, linked by how much data each shares with the one
+    /// before it. Only complete snapshots outside the trash are taken into account.
+    pub fn snapshot_graph(&mut self) -> Result<SnapshotGraph, HatError> {
+        let mut snapshots = vec![];
+        for snapshot in self.snapshot_index.list_all() {
+            let top_ref = match (snapshot.status, snapshot.hash_ref) {
+                (db::SnapshotWorkStatus::CommitComplete, Some(bytes)) => {
+                    hash::tree::HashRef::from_bytes(&mut &bytes[..])?
+                }
+                _ => continue,
+            };
+            if snapshot.trashed.is_some() {
+                continue;
+            }
+            let family = self.open_family(snapshot.family_name.clone())?;
+            snapshots.push(graph::SnapshotRefs {
+                family_name: snapshot.family_name,
+                snapshot_id: snapshot.info.snapshot_id,
+                created: snapshot.created,
+                ids: self.list_snapshot_ids(&family, top_ref)?,
+            });
+        }
+        Ok(graph::build(self.hash_index.list_nodes(), snapshots))
+    }
+
+    /// Write the snapshot graph to `out` in the Graphviz dot language, or as JSON if `json`
+    /// is set.
+    pub fn write_snapshot_graph<W: io::Write>(
+        &mut self,
+        json: bool,
+        out: &mut W,
+    ) -> Result<(), HatError> {
+        let graph = self.snapshot_graph()?;
+        if json {
+            graph::write_json(&graph, out)
+        } else {
+            graph::write_dot(&graph, out)
+        }
+    }
+
+    /// Download the blobs in `subset` and check that all their chunks can be read and match
+    /// their hashes. Failing blobs are listed in the report rather than stopping the check, and
+    /// their chunks are flagged as suspect, see `set_rewrite_suspect`. Blobs that are still
+    /// being written are not checked.
+    pub fn check(&self, subset: Subset) -> Result<CheckReport, HatError> {
+        self.check_for(subset, None)
+    }
+
+    /// Like `check`, but stop before the next blob once `max_duration` has passed.
+    ///
+    /// Progress is saved after every blob, so a check that runs out of time or is interrupted
+    /// continues where it stopped the next time the same subset is checked.
+    pub fn check_for(
+        &self,
+        subset: Subset,
+        max_duration: Option<Duration>,
+    ) -> Result<CheckReport, HatError> {
+        self.check_with(subset, max_duration, CheckLimits::default())
+    }
+
+    /// Like `check_for`, with `limits.fetchers` blobs downloading at the same time and
+    /// `limits.hashers` threads verifying the downloaded blobs.
+    pub fn check_with(
+        &self,
+        subset: Subset,
+        max_duration: Option<Duration>,
+        limits: CheckLimits,
+    ) -> Result<CheckReport, HatError> {
+        assert!(limits.fetchers > 0 && limits.hashers > 0);
+        let start = Instant::now();
+        let key = subset.to_string();
+        let cursor = self.blob_index.check_cursor(&key);
+
+        // Listed newest first; check in id order so the cursor only moves forward.
+        let mut blobs = self.blob_store.list_by_tag(tags::Tag::Done);
+        blobs.reverse();
+        blobs.retain(|blob| {
+            subset.contains(&blob.name[..]) && cursor.map_or(true, |id| blob.id > id)
+        });
+        let total = blobs.len();
+
+        let checker = self.blob_store.checker();
+        let blob_index = &self.blob_index;
+        let hash_index = &self.hash_index;
+        let clock = &self.clock;
+        let queue = Mutex::new(blobs.into_iter().enumerate());
+        let progress = Mutex::new(CheckProgress::new(cursor.is_some()));
+        // Downloaded blobs wait here for a hasher, which bounds the memory held by downloads.
+        let (fetched_tx, fetched_rx) = mpsc::sync_channel(limits.hashers);
+        let fetched_rx = Mutex::new(fetched_rx);
+
+        let pool = scoped_pool::Pool::new(limits.fetchers + limits.hashers);
+        pool.scoped(|scope| {
+            let (checker, queue, progress, fetched_rx, key) =
+                (&checker, &queue, &progress, &fetched_rx, &key);
+            for _ in 0..limits.fetchers {
+                let fetched_tx = fetched_tx.clone();
+                scope.execute(move || loop {
+                    if max_duration.map_or(false, |max| start.elapsed() >= max) {
+                        break;
+                    }
+                    let next = queue.lock().unwrap().next();
+                    let (seq, blob) = match next {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let ct = checker.fetch(&blob);
+                    if fetched_tx.send((seq, blob, ct)).is_err() {
+                        break;
+                    }
+                });
+            }
+            for _ in 0..limits.hashers {
+                scope.execute(move || loop {
+                    let next = fetched_rx.lock().unwrap().recv();
+                    let (seq, blob, ct) = match next {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let outcome = ct.and_then(|ct| checker.verify_chunks(&ct[..]));
+                    match outcome {
+                        Ok(_) => blob_index.set_verified(&blob, clock.now().timestamp()),
+                        // Keep the chunks from being reused until they are stored again.
+                        Err(_) => {
+                            hash_index.mark_suspect_in_blob(blob.id);
+                        }
+                    }
+                    let outcome =
+                        outcome.map_err(|e| format!("blob {}: {}", blob.name.to_hex(), e));
+                    let mut progress = progress.lock().unwrap();
+                    if let Some(id) = progress.record(seq, blob.id, outcome) {
+                        blob_index.set_check_cursor(key, Some(id));
+                    }
+                });
+            }
+            // The hashers stop once every fetcher has dropped its sender.
+            drop(fetched_tx);
+        });
+        pool.shutdown();
+
+        let progress = progress.into_inner().unwrap();
+        let complete = progress.done() == total;
+        let mut report = progress.into_report();
+        if complete {
+            self.blob_index.set_check_cursor(&key, None);
+            report.complete = true;
+        }
+        Ok(report)
+    }
+
+    /// Check the structure of every committed snapshot, as a quick alternative to `check`.
+    ///
+    /// Walks the directory trees, looks up every hash they reach in the hash index, and makes
+    /// sure that the backend lists each blob these hashes are stored in. Only the blobs that
+    /// hold directory listings are downloaded; file data is never read.
+    pub fn check_trees(&mut self) -> Result<TreeCheckReport, HatError> {
+        let listed: HashSet<Vec<u8>> =
+            self.backend.list()?.into_iter().map(|name| name.to_vec()).collect();
+        let hash_backend = self.hash_backend();
+
+        let mut report = TreeCheckReport::default();
+        let mut seen_nodes = HashSet::new();
+        let mut seen_blobs = HashMap::new();
+        for snapshot in self.snapshot_index.list_all() {
+            let top_ref = match snapshot.hash_ref {
+                Some(bytes) => hash::tree::HashRef::from_bytes(&mut &bytes[..])?,
+                None => continue,
+            };
+            report.snapshots += 1;
+            let name = format!("{} #{}", snapshot.family_name, snapshot.info.snapshot_id);
+
+            let mut dirs = vec![];
+            match top_ref.leaf {
+                blob::LeafType::TreeList => dirs.push(top_ref.clone()),
+                blob::LeafType::SnapshotList => (),
+                blob::LeafType::FileChunk => {
+                    report.errors.push(format!("{}: snapshot of a file chunk tree", name));
+                    continue;
+                }
+            }
+            let mut problems = vec![];
+            self.check_tree_nodes(
+                &top_ref,
+                &listed,
+                &mut seen_nodes,
+                &mut seen_blobs,
+                &mut problems,
+            );
+            while let Some(dir_ref) = dirs.pop() {
+                report.directories += 1;
+                let entries = match family::fetch_dir_data(dir_ref, hash_backend.clone()) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        problems.push(format!("unreadable directory listing: {}", e));
+                        continue;
+                    }
+                };
+                for (_, content) in entries {
+                    let href = match content {
+                        walker::Content::Data(href) => href,
+                        walker::Content::Dir(href) => {
+                            dirs.push(href.clone());
+                            href
+                        }
+                        walker::Content::Link(_) => continue,
+                    };
+                    self.check_tree_nodes(
+                        &href,
+                        &listed,
+                        &mut seen_nodes,
+                        &mut seen_blobs,
+                        &mut problems,
+                    );
+                }
+            }
+            report.errors.extend(problems.into_iter().map(|p| format!("{}: {}", name, p)));
+        }
+        report.nodes = seen_nodes.len() as u64;
+        report.blobs = seen_blobs.values().filter(|&&found| found).count() as u64;
+        Ok(report)
+    }
+
+    /// Look up `top` and every node below it in the hash index and check that the blobs
+    /// holding them are in `listed`. Nodes and blobs that were checked before are skipped, so
+    /// every problem is reported once.
+    fn check_tree_nodes(
+        &self,
+        top: &hash::tree::HashRef,
+        listed: &HashSet<Vec<u8>>,
+        seen_nodes: &mut HashSet<u64>,
+        seen_blobs: &mut HashMap<Vec<u8>, bool>,
+        problems: &mut Vec<String>,
+    ) {
+        let top_id = match self.hash_index.get_id(&top.hash) {
+            Some(id) => id,
+            None => {
+                let hex = top.hash.bytes.to_hex();
+                problems.push(format!("hash {} is not in the hash index", hex));
+                return;
+            }
+        };
+        let mut queue = vec![top_id];
+        while let Some(id) = queue.pop() {
+            if !seen_nodes.insert(id) {
+                continue;
+            }
+            let entry = match self.hash_index.get_hash(id) {
+                Some(entry) => entry,
+                None => {
+                    problems.push(format!("hash id {} is not in the hash index", id));
+                    continue;
+                }
+            };
+            let hex = entry.hash.bytes.to_hex();
+            match (entry.node, entry.childs) {
+                (blob::NodeType::Leaf, None) => (),
+                (blob::NodeType::Branch(_), Some(childs)) => queue.extend(childs),
+                (blob::NodeType::Leaf, Some(_)) => {
+                    problems.push(format!("leaf {} has children", hex));
+                }
+                (blob::NodeType::Branch(_), None) => {
+                    problems.push(format!("branch {} has no children", hex));
+                }
+            }
+            let chunk_ref = match entry.persistent_ref {
+                Some(chunk_ref) => chunk_ref,
+                None => {
+                    problems.push(format!("hash {} has no stored location", hex));
+                    continue;
+                }
+            };
+            // Empty chunks are never stored.
+            if chunk_ref.offset == 0 && chunk_ref.length == 0 {
+                continue;
+            }
+            if seen_blobs.contains_key(&chunk_ref.blob_name) {
+                continue;
+            }
+            let found = listed.contains(&self.blob_index.location(&chunk_ref.blob_name[..]));
+            if !found {
+                problems.push(format!(
+                    "blob {} holding hash {} is missing from the backend",
+                    chunk_ref.blob_name.to_hex(),
+                    hex
+                ));
+            }
+            seen_blobs.insert(chunk_ref.blob_name, found);
+        }
+    }
+
+    /// Forget the progress of an unfinished check of `subset`, so the next check starts over.
+    pub fn reset_check(&self, subset: Subset) {
+        self.blob_index.set_check_cursor(&subset.to_string(), None);
+    }
+
+    /// List the hash ids that a snapshot holds references to, as counted by the GC.
+    fn list_snapshot_ids(
+        &self,
+        family: &Family<B>,
+        top_ref: hash::tree::HashRef,
+    ) -> Result<Vec<u64>, HatError> {
+        match top_ref.leaf {
+            blob::LeafType::TreeList => {
+                let hash_backend = self.hash_backend();
+                let mut ids = vec![];
+                for content in list_snapshot(&hash_backend, family, top_ref) {
+                    let href = match content? {
+                        walker::Content::Data(href) |
+                        walker::Content::Dir(href) => href,
+                        walker::Content::Link(_) => continue,
+                    };
+                    ids.push(self.hash_index.get_id(&href.hash).expect(
+                        "Unknown hash in snapshot",
+                    ));
+                }
+                Ok(ids)
+            }
+            blob::LeafType::SnapshotList => {
+                Ok(vec![
+                    self.hash_index.get_id(&top_ref.hash).expect("Unknown top ref"),
+                ])
+            }
+            blob::LeafType::FileChunk => Err(From::from("Snapshot of a file chunk tree")),
+        }
+    }
+
     fn hash_backend(&self) -> key::HashStoreBackend<B> {
         key::HashStoreBackend::new(
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        )
+        ).with_node_cache(self.node_cache.clone())
     }
 }
@@ -0,0 +1,166 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Logical repositories sharing one storage location.
+//!
+//! Every namespace has its own blobs, local index and key material, so several machines can
+//! back up to the same bucket or directory without seeing each other's data. The key material
+//! of a namespace is derived from the master key of the storage location, so namespaces are
+//! only as secret from each other as that key is: with the built-in passphrase, they keep their
+//! data apart but anyone can read it.
+
+use crypto::CryptoError;
+use crypto::keys::{self, MasterKey};
+use crypto::provider::KeyProvider;
+use errors::HatError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+/// Directory below the storage root holding one subdirectory per namespace.
+const NAMESPACE_DIR: &'static str = "namespaces";
+
+/// Salt of the derivation of namespace keys from the master key.
+const KEY_SALT: &'static [u8] = b"hat:namespace~~~";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Namespace {
+    name: String,
+}
+
+impl Namespace {
+    /// Names may contain ASCII letters, digits, '-', '_' and '.', but may not start with '.'.
+    pub fn new(name: &str) -> Result<Namespace, HatError> {
+        let valid_char = |c: char| {
+            (c as u32) < 0x80 && c.is_alphanumeric() || c == '-' || c == '_' || c == '.'
+        };
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(valid_char) {
+            return Err(From::from(format!("Invalid namespace name: '{}'", name)));
+        }
+        Ok(Namespace { name: name.to_owned() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Where this namespace keeps its blobs below the shared storage root.
+    pub fn blob_dir(&self, root: &Path) -> PathBuf {
+        root.join(NAMESPACE_DIR).join(&self.name)
+    }
+
+    /// Where this namespace keeps its local state below the cache root.
+    pub fn cache_dir(&self, root: &Path) -> PathBuf {
+        root.join(NAMESPACE_DIR).join(&self.name)
+    }
+
+    /// The master key of this namespace, derived from `master`, the secret of the whole
+    /// storage location.
+    pub fn master_key(&self, master: &MasterKey) -> MasterKey {
+        let mut key = vec![0u8; 64];
+        keys::keyed_fingerprint(master.as_bytes(), self.name.as_bytes(), KEY_SALT, &mut key);
+        MasterKey::from_bytes(key)
+    }
+}
+
+/// Derives the key of a namespace from the key `inner` provides for the storage location.
+pub struct NamespaceKey {
+    pub namespace: Namespace,
+    pub inner: Box<KeyProvider>,
+}
+
+impl KeyProvider for NamespaceKey {
+    fn master_key(&self) -> Result<MasterKey, CryptoError> {
+        Ok(self.namespace.master_key(&self.inner.master_key()?))
+    }
+}
+
+/// List the namespaces found below the shared storage root, sorted by name.
+pub fn list(root: &Path) -> Result<Vec<Namespace>, HatError> {
+    let dir = root.join(NAMESPACE_DIR);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut out = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(ns) = entry.file_name().to_str().and_then(|s| Namespace::new(s).ok()) {
+            out.push(ns);
+        }
+    }
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_validated() {
+        assert!(Namespace::new("laptop-01").is_ok());
+        assert!(Namespace::new("build_server.eu").is_ok());
+        assert!(Namespace::new("").is_err());
+        assert!(Namespace::new("..").is_err());
+        assert!(Namespace::new("a/b").is_err());
+        assert!(Namespace::new(".hidden").is_err());
+    }
+
+    #[test]
+    fn namespaces_have_separate_directories() {
+        let a = Namespace::new("a").unwrap();
+        let b = Namespace::new("b").unwrap();
+        let root = Path::new("/backup");
+        assert_ne!(a.blob_dir(root), b.blob_dir(root));
+        assert_ne!(a.cache_dir(root), b.cache_dir(root));
+    }
+
+    #[test]
+    fn namespace_keys_depend_on_the_master_key() {
+        let a = Namespace::new("a").unwrap();
+        let b = Namespace::new("b").unwrap();
+        let master = MasterKey::from_bytes(vec![1; 64]);
+        let other = MasterKey::from_bytes(vec![2; 64]);
+
+        assert_eq!(a.master_key(&master).as_bytes(), a.master_key(&master).as_bytes());
+        assert!(a.master_key(&master).as_bytes() != b.master_key(&master).as_bytes());
+        // Knowing the name is not enough to find the key.
+        assert!(a.master_key(&master).as_bytes() != a.master_key(&other).as_bytes());
+        assert!(a.master_key(&master).as_bytes() != master.as_bytes());
+    }
+
+    #[test]
+    fn list_finds_namespace_directories() {
+        let nanos = ::time::precise_time_ns();
+        let root = ::std::env::temp_dir().join(format!("hat-namespaces-{}", nanos));
+        assert_eq!(list(&root).unwrap(), Vec::<Namespace>::new());
+
+        for name in &["web", "db", "laptop"] {
+            fs::create_dir_all(Namespace::new(name).unwrap().blob_dir(&root)).unwrap();
+        }
+        fs::File::create(root.join(NAMESPACE_DIR).join("stray-file")).unwrap();
+
+        let names: Vec<String> = list(&root)
+            .unwrap()
+            .iter()
+            .map(|ns| ns.name().to_owned())
+            .collect();
+        assert_eq!(names, vec!["db", "laptop", "web"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Reading snapshots from the blobs alone, without any local index, for disaster recovery.
+
+use backend::StoreBackend;
+use blob;
+use capnp;
+use chrono::{self, TimeZone};
+use crypto;
+use errors::HatError;
+use hash;
+use hat::family;
+use hat::listing::ListedEntry;
+use hat::metadata::MetadataPolicy;
+use hat::repo_format;
+use hat::synthetic_roots_family;
+use hat::walker::Content;
+use key;
+use root_capnp;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+
+/// Reads chunks straight from the blobs that hold them, without a hash or blob index.
+///
+/// Chunks are verified against their hash, but the blobs cannot be checked against upload
+/// checksums, as those live in the blob index.
+pub struct BlobOnlyBackend<B> {
+    backend: Arc<B>,
+    keys: Arc<crypto::keys::Keeper>,
+    // Backend location of each blob, preferring the latest relocated copy.
+    locations: Arc<HashMap<Vec<u8>, Vec<u8>>>,
+    // The last blob fetched, as walkers tend to read several chunks from the same blob.
+    last: Arc<Mutex<Option<(Vec<u8>, Arc<Vec<u8>>)>>>,
+}
+
+impl<B> Clone for BlobOnlyBackend<B> {
+    fn clone(&self) -> BlobOnlyBackend<B> {
+        BlobOnlyBackend {
+            backend: self.backend.clone(),
+            keys: self.keys.clone(),
+            locations: self.locations.clone(),
+            last: self.last.clone(),
+        }
+    }
+}
+
+impl<B: StoreBackend> BlobOnlyBackend<B> {
+    pub fn new(
+        backend: Arc<B>,
+        keys: Arc<crypto::keys::Keeper>,
+    ) -> Result<BlobOnlyBackend<B>, HatError> {
+        let mut locations: HashMap<Vec<u8>, (u64, Vec<u8>)> = HashMap::new();
+        for location in backend.list()? {
+            if !blob::is_blob_name(&location) {
+                continue;
+            }
+            let (name, generation) = match blob::parse_location_name(&location) {
+                Some((name, generation)) => (name.to_vec(), generation + 1),
+                None => (location.to_vec(), 0),
+            };
+            let newer = locations.get(&name).map_or(true, |&(g, _)| generation > g);
+            if newer {
+                locations.insert(name, (generation, location.to_vec()));
+            }
+        }
+
+        Ok(BlobOnlyBackend {
+            backend: backend,
+            keys: keys,
+            locations: Arc::new(locations.into_iter().map(|(n, (_, l))| (n, l)).collect()),
+            last: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Names of all blobs with the time they were stored, newest first. Without times, in no
+    /// particular order, if the backend does not keep track of them.
+    pub fn blobs_by_age(&self) -> Result<Vec<(Vec<u8>, Option<SystemTime>)>, HatError> {
+        let mut blobs: Vec<_> = self.locations.keys().map(|name| (name.clone(), None)).collect();
+        // Backends that do not keep track read the whole blob to tell, so only ask once.
+        let tracked = match blobs.first() {
+            Some(&(ref name, _)) => self.stored_at(name)?.is_some(),
+            None => false,
+        };
+        if !tracked {
+            return Ok(blobs);
+        }
+        for blob in &mut blobs {
+            blob.1 = self.stored_at(&blob.0)?;
+        }
+        blobs.sort_by(|a, b| b.1.cmp(&a.1));
+        Ok(blobs)
+    }
+
+    /// Whether blob `name` was moved since it was written, so that its stored time says
+    /// nothing about when its contents were written.
+    pub fn is_relocated(&self, name: &[u8]) -> bool {
+        self.locations.get(name).map_or(false, |location| &location[..] != name)
+    }
+
+    fn stored_at(&self, name: &[u8]) -> Result<Option<SystemTime>, HatError> {
+        let stat = match self.locations.get(name) {
+            Some(location) => self.backend.stat(location)?,
+            None => None,
+        };
+        Ok(stat.and_then(|s| s.stored_at))
+    }
+
+    fn fetch_blob(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, HatError> {
+        if let Some((ref last_name, ref ct)) = *self.last.lock().unwrap() {
+            if &last_name[..] == name {
+                return Ok(Some(ct.clone()));
+            }
+        }
+        let ct = match self.locations.get(name) {
+            Some(location) => self.backend.retrieve(location)?,
+            None => None,
+        };
+        if let Some(ref ct) = ct {
+            *self.last.lock().unwrap() = Some((name.to_vec(), ct.clone()));
+        }
+        Ok(ct)
+    }
+
+    /// The references to all chunks in the blob `name`.
+    pub fn blob_refs(&self, name: &[u8]) -> Result<Vec<hash::tree::HashRef>, HatError> {
+        match self.fetch_blob(name)? {
+            Some(ct) => {
+                let reader =
+                    blob::BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?;
+                Ok(reader.refs()?)
+            }
+            None => Err(From::from("Blob is missing from the backend")),
+        }
+    }
+}
+
+impl<B: StoreBackend> hash::tree::HashTreeBackend for BlobOnlyBackend<B> {
+    type Err = key::MsgError;
+
+    fn fetch_chunk(&self, href: &hash::tree::HashRef) -> Result<Option<Vec<u8>>, key::MsgError> {
+        if href.persistent_ref.offset == 0 && href.persistent_ref.length == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        let ct = match self.fetch_blob(&href.persistent_ref.blob_name[..]) {
+            Ok(Some(ct)) => ct,
+            Ok(None) => return Err(From::from("Chunk is missing from the backend")),
+            Err(e) => return Err(From::from(e.to_string())),
+        };
+        let data = blob::BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))
+            .map_err(blob::BlobError::from)?
+            .read_chunk(href)?;
+        if hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]) == href.hash {
+            Ok(Some(data))
+        } else {
+            Err(From::from("Data hash does not match expectation"))
+        }
+    }
+
+    fn fetch_childs(&self, _hash: &hash::Hash) -> Option<Vec<u64>> {
+        None
+    }
+
+    fn fetch_persistent_ref(&self, _hash: &hash::Hash) -> Option<blob::ChunkRef> {
+        None
+    }
+
+    fn insert_chunk(
+        &self,
+        _chunk: &[u8],
+        _node: blob::NodeType,
+        _leaf: blob::LeafType,
+        _childs: Option<Vec<u64>>,
+        _info: Option<&key::Info>,
+    ) -> Result<(u64, hash::tree::HashRef), key::MsgError> {
+        Err(From::from("Cannot write to a repository opened from its blobs alone"))
+    }
+}
+
+/// A snapshot as recorded in the snapshot list stored in the blobs.
+#[derive(Clone, Debug)]
+pub struct OfflineSnapshot {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    pub msg: String,
+    pub trashed: Option<chrono::DateTime<chrono::Utc>>,
+    pub root: hash::tree::HashRef,
+}
+
+/// The snapshots of a repository, found by scanning its blobs.
+pub struct OfflineRepository<B> {
+    backend: BlobOnlyBackend<B>,
+    snapshots: Vec<OfflineSnapshot>,
+}
+
+impl<B: StoreBackend> OfflineRepository<B> {
+    /// Open the repository in `backend`, which `master` unlocks.
+    pub fn open_with_key(
+        backend: Arc<B>,
+        master: &crypto::keys::MasterKey,
+    ) -> Result<OfflineRepository<B>, HatError> {
+        repo_format::check(&*backend)?;
+        OfflineRepository::open(backend, Arc::new(crypto::keys::Keeper::from_master_key(master)))
+    }
+
+    /// Scan the blobs in `backend` for the latest snapshot list. Lists that cannot be read are
+    /// skipped, so that a damaged blob only loses the snapshots it alone describes.
+    ///
+    /// If the backend knows when blobs were stored, the newest blobs are read first and the
+    /// scan stops at blobs older than the newest one written with a snapshot list. Otherwise
+    /// every blob is read.
+    pub fn open(
+        backend: Arc<B>,
+        keys: Arc<crypto::keys::Keeper>,
+    ) -> Result<OfflineRepository<B>, HatError> {
+        let backend = BlobOnlyBackend::new(backend, keys)?;
+
+        let blobs = backend.blobs_by_age()?;
+        let total = blobs.len();
+        let mut best: Option<((u64, i64, usize), Vec<OfflineSnapshot>)> = None;
+        // When the newest blob written with a snapshot list was stored.
+        let mut found_at: Option<SystemTime> = None;
+        for (scanned, (name, stored_at)) in blobs.into_iter().enumerate() {
+            if let (Some(found_at), Some(stored_at)) = (found_at, stored_at) {
+                if stored_at < found_at {
+                    break;
+                }
+            }
+            if scanned > 0 && scanned % 100 == 0 {
+                info!("Searched {} of {} blobs for snapshot lists", scanned, total);
+            }
+            let refs = match backend.blob_refs(&name) {
+                Ok(refs) => refs,
+                Err(e) => {
+                    warn!("Skipping unreadable blob: {}", e);
+                    continue;
+                }
+            };
+            for href in refs {
+                if href.leaf != blob::LeafType::SnapshotList {
+                    continue;
+                }
+                let snapshots = match read_snapshot_list(&backend, href) {
+                    Ok(snapshots) => snapshots,
+                    Err(e) => {
+                        warn!("Skipping unreadable snapshot list: {}", e);
+                        continue;
+                    }
+                };
+                // Every list records the roots of the lists written before it, so the
+                // latest list is the one with the highest root id.
+                let rank = (
+                    snapshots
+                        .iter()
+                        .filter(|s| s.family_name == synthetic_roots_family())
+                        .map(|s| s.snapshot_id)
+                        .max()
+                        .unwrap_or(0),
+                    snapshots.iter().map(|s| s.created.timestamp()).max().unwrap_or(0),
+                    snapshots.len(),
+                );
+                if best.as_ref().map_or(true, |&(ref r, _)| rank > *r) {
+                    best = Some((rank, snapshots));
+                }
+                // A moved blob was stored after its contents were written.
+                if found_at.is_none() && !backend.is_relocated(&name) {
+                    found_at = stored_at;
+                }
+            }
+        }
+
+        let mut snapshots: Vec<OfflineSnapshot> = match best {
+            Some((_, snapshots)) => snapshots,
+            None => return Err(From::from("No snapshot list found in the blobs")),
+        };
+        snapshots.retain(|s| s.family_name != synthetic_roots_family());
+        snapshots.sort_by(|a, b| {
+            (&a.family_name, a.snapshot_id).cmp(&(&b.family_name, b.snapshot_id))
+        });
+
+        Ok(OfflineRepository {
+            backend: backend,
+            snapshots: snapshots,
+        })
+    }
+
+    /// All snapshots, ordered by family and id.
+    pub fn snapshots(&self) -> &[OfflineSnapshot] {
+        &self.snapshots[..]
+    }
+
+    /// Snapshot `snapshot_id` of `family_name`, or its latest one that is not in the trash.
+    pub fn snapshot(
+        &self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+    ) -> Result<&OfflineSnapshot, HatError> {
+        let found = self.snapshots
+            .iter()
+            .filter(|s| s.family_name == family_name)
+            .filter(|s| match snapshot_id {
+                Some(id) => s.snapshot_id == id,
+                None => s.trashed.is_none(),
+            })
+            .last();
+        found.ok_or_else(|| From::from(format!("No such snapshot in family: {}", family_name)))
+    }
+
+    /// The entries of the directory at `path` in a snapshot.
+    pub fn list_dir(
+        &self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        path: &Path,
+    ) -> Result<Vec<ListedEntry>, HatError> {
+        let root = self.snapshot(family_name, snapshot_id)?.root.clone();
+        let dir_ref = match self.resolve(root, path)?.1 {
+            Content::Dir(dir_ref) => dir_ref,
+            _ => return Err(From::from(format!("Not a directory: {}", path.display()))),
+        };
+        let entries = family::fetch_dir_data(dir_ref, self.backend.clone())?;
+        Ok(
+            entries
+                .into_iter()
+                .map(|(entry, content)| {
+                    let is_dir = match content {
+                        Content::Dir(_) => true,
+                        _ => false,
+                    };
+                    ListedEntry {
+                        entry: entry,
+                        is_dir: is_dir,
+                        link_target: match content {
+                            Content::Link(target) => Some(target),
+                            _ => None,
+                        },
+                        dangling: None,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Restore `path` of a snapshot, or all of it, into the directory `output`.
+    pub fn checkout(
+        &self,
+        family_name: &str,
+        snapshot_id: Option<u64>,
+        path: &Path,
+        output: &Path,
+        policy: &MetadataPolicy,
+    ) -> Result<(), HatError> {
+        let root = self.snapshot(family_name, snapshot_id)?.root.clone();
+        match self.resolve(root, path)? {
+            (None, Content::Dir(dir_ref)) => self.checkout_dir(dir_ref, output, policy),
+            (Some(entry), content) => {
+                fs::create_dir_all(output)?;
+                self.checkout_entry(entry, content, output, policy)
+            }
+            (None, _) => Err(From::from("Snapshot root is not a directory")),
+        }
+    }
+
+    fn resolve(
+        &self,
+        root: hash::tree::HashRef,
+        path: &Path,
+    ) -> Result<(Option<key::Entry>, Content), HatError> {
+        let mut current = (None, Content::Dir(root));
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.as_bytes(),
+                Component::RootDir | Component::CurDir => continue,
+                _ => {
+                    return Err(From::from(
+                        format!("Unsupported path in snapshot: {}", path.display()),
+                    ))
+                }
+            };
+            let dir_ref = match current.1 {
+                Content::Dir(dir_ref) => dir_ref,
+                _ => return Err(From::from(format!("Not a directory: {}", path.display()))),
+            };
+            current = family::fetch_dir_data(dir_ref, self.backend.clone())?
+                .into_iter()
+                .find(|&(ref entry, _)| &entry.info.name[..] == name)
+                .map(|(entry, content)| (Some(entry), content))
+                .ok_or_else(|| {
+                    format!("No such file or directory in snapshot: {}", path.display())
+                })?;
+        }
+        Ok(current)
+    }
+
+    fn checkout_dir(
+        &self,
+        dir_ref: hash::tree::HashRef,
+        output: &Path,
+        policy: &MetadataPolicy,
+    ) -> Result<(), HatError> {
+        fs::create_dir_all(output)?;
+        for (entry, content) in family::fetch_dir_data(dir_ref, self.backend.clone())? {
+            self.checkout_entry(entry, content, output, policy)?;
+        }
+        Ok(())
+    }
+
+    fn checkout_entry(
+        &self,
+        entry: key::Entry,
+        content: Content,
+        parent: &Path,
+        policy: &MetadataPolicy,
+    ) -> Result<(), HatError> {
+        let path = parent.join(OsStr::from_bytes(&entry.info.name[..]));
+        println!("{}", path.display());
+        let is_symlink = match content {
+            Content::Data(data_ref) => {
+                let mut fd = fs::File::create(&path)?;
+                if let Some(mut chunks) =
+                    hash::tree::LeafIterator::new(self.backend.clone(), data_ref)?
+                {
+                    while let Some(chunk) = chunks.try_next()? {
+                        fd.write_all(&chunk[..])?;
+                    }
+                }
+                fd.flush()?;
+                false
+            }
+            Content::Dir(dir_ref) => {
+                self.checkout_dir(dir_ref, &path, policy)?;
+                false
+            }
+            Content::Link(target) => {
+                use std::os::unix::fs::symlink;
+                symlink(target, &path)?;
+                true
+            }
+        };
+        policy.apply(&path, &entry.info, is_symlink)?;
+        Ok(())
+    }
+}
+
+fn read_snapshot_list<B: StoreBackend>(
+    backend: &BlobOnlyBackend<B>,
+    href: hash::tree::HashRef,
+) -> Result<Vec<OfflineSnapshot>, HatError> {
+    let mut snapshots = vec![];
+    let mut chunks = match hash::tree::LeafIterator::new(backend.clone(), href)? {
+        Some(chunks) => chunks,
+        None => return Ok(snapshots),
+    };
+    while let Some(msg) = chunks.try_next()? {
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut &msg[..],
+            capnp::message::ReaderOptions::new(),
+        )?;
+        let snapshot_list = message_reader.get_root::<root_capnp::snapshot_list::Reader>()?;
+        for s in snapshot_list.get_snapshots()?.iter() {
+            let trashed = s.get_trashed_utc_timestamp();
+            snapshots.push(OfflineSnapshot {
+                family_name: s.get_family_name()?.to_owned(),
+                snapshot_id: s.get_id(),
+                created: chrono::Utc.timestamp(s.get_utc_timestamp(), 0),
+                msg: s.get_msg()?.to_owned(),
+                trashed: if trashed == 0 {
+                    None
+                } else {
+                    Some(chrono::Utc.timestamp(trashed, 0))
+                },
+                root: hash::tree::HashRef::read_msg(&s.get_hash_ref()?)?,
+            });
+        }
+    }
+    Ok(snapshots)
+}
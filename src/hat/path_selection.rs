@@ -0,0 +1,190 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Selection of the paths to restore from a snapshot, by exact path, glob or content type.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+
+/// Paths in a snapshot, relative to its root. A selected directory is restored with everything
+/// below it.
+///
+/// Patterns are matched component by component: `*` matches any run of characters and `?` any
+/// single character within a name, and a `**` component matches any number of directories.
+///
+/// Files can also be selected by the content type recorded for them, with the same wildcards.
+#[derive(Clone, Debug, Default)]
+pub struct PathSelection {
+    patterns: Vec<Vec<Vec<u8>>>,
+    types: Vec<Vec<u8>>,
+}
+
+impl PathSelection {
+    pub fn new() -> PathSelection {
+        PathSelection {
+            patterns: vec![],
+            types: vec![],
+        }
+    }
+
+    /// Select every path, e.g. to only select files by content type.
+    pub fn all() -> PathSelection {
+        let mut selection = PathSelection::new();
+        selection.add(b"**");
+        selection
+    }
+
+    /// Read a manifest with one path or pattern per line, or separated by NUL bytes if
+    /// `null_delimited`. Empty lines are ignored.
+    pub fn from_manifest(manifest: &[u8], null_delimited: bool) -> PathSelection {
+        let separator = if null_delimited { b'\0' } else { b'\n' };
+        let mut selection = PathSelection::new();
+        for line in manifest.split(|&b| b == separator) {
+            let line = if !null_delimited && line.ends_with(b"\r") {
+                &line[..line.len() - 1]
+            } else {
+                line
+            };
+            if !line.is_empty() {
+                selection.add(line);
+            }
+        }
+        selection
+    }
+
+    /// Also select the paths matching `pattern`. Leading slashes are ignored, as paths are
+    /// always relative to the snapshot root.
+    pub fn add(&mut self, pattern: &[u8]) {
+        let components: Vec<Vec<u8>> = pattern
+            .split(|&b| b == b'/')
+            .filter(|c| !c.is_empty() && *c != &b"."[..])
+            .map(|c| c.to_vec())
+            .collect();
+        if !components.is_empty() {
+            self.patterns.push(components);
+        }
+    }
+
+    /// Only select the files with a content type matching `pattern`, like `image/*`, or one of
+    /// the other types added. Files without a recorded content type are not selected.
+    pub fn add_type(&mut self, pattern: &str) {
+        self.types.push(pattern.as_bytes().to_vec());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether a file of `content_type` is selected, as far as its type is concerned.
+    pub fn selects_type(&self, content_type: Option<&str>) -> bool {
+        if self.types.is_empty() {
+            return true;
+        }
+        match content_type {
+            Some(t) => self.types.iter().any(|p| name_matches(p, t.as_bytes())),
+            None => false,
+        }
+    }
+
+    /// Whether `path` matches one of the patterns, so it is restored with everything below it.
+    pub fn selects(&self, path: &Path) -> bool {
+        let path = components(path);
+        self.patterns.iter().any(|p| matches(p, &path, false))
+    }
+
+    /// Whether something below the directory `path` may be selected.
+    pub fn selects_below(&self, path: &Path) -> bool {
+        let path = components(path);
+        self.patterns.iter().any(|p| matches(p, &path, true))
+    }
+}
+
+fn components(path: &Path) -> Vec<&[u8]> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.as_bytes()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Match `path` against `pattern`, or only against its start if `prefix`.
+fn matches(pattern: &[Vec<u8>], path: &[&[u8]], prefix: bool) -> bool {
+    if pattern.is_empty() {
+        return path.is_empty();
+    }
+    if &pattern[0][..] == b"**" {
+        return matches(&pattern[1..], path, prefix) ||
+            (!path.is_empty() && matches(pattern, &path[1..], prefix));
+    }
+    if path.is_empty() {
+        return prefix;
+    }
+    name_matches(&pattern[0], path[0]) && matches(&pattern[1..], &path[1..], prefix)
+}
+
+/// Match a single name against a pattern with `*` and `?` wildcards.
+pub fn name_matches(pattern: &[u8], name: &[u8]) -> bool {
+    if pattern.is_empty() {
+        return name.is_empty();
+    }
+    match pattern[0] {
+        b'*' => (0..name.len() + 1).any(|i| name_matches(&pattern[1..], &name[i..])),
+        b'?' => !name.is_empty() && name_matches(&pattern[1..], &name[1..]),
+        c => !name.is_empty() && name[0] == c && name_matches(&pattern[1..], &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn exact_paths_and_globs() {
+        let manifest = b"/etc/hosts\nhome/*/.bashrc\n\n**/*.conf\n";
+        let selection = PathSelection::from_manifest(manifest, false);
+        assert!(selection.selects(Path::new("etc/hosts")));
+        assert!(!selection.selects(Path::new("etc/hostname")));
+        assert!(selection.selects(Path::new("home/alice/.bashrc")));
+        assert!(!selection.selects(Path::new("home/alice/src/.bashrc")));
+        assert!(selection.selects(Path::new("a.conf")));
+        assert!(selection.selects(Path::new("etc/nginx/nginx.conf")));
+
+        assert!(selection.selects_below(Path::new("etc")));
+        assert!(selection.selects_below(Path::new("home/bob")));
+        assert!(selection.selects_below(Path::new("var/lib")));
+
+        let exact = PathSelection::from_manifest(b"etc/hosts\0my file\0", true);
+        assert!(exact.selects(Path::new("my file")));
+        assert!(exact.selects_below(Path::new("etc")));
+        assert!(!exact.selects_below(Path::new("var")));
+    }
+
+    #[test]
+    fn content_types() {
+        let mut selection = PathSelection::all();
+        assert!(selection.selects(Path::new("a/b/c")));
+        assert!(selection.selects_type(None));
+
+        selection.add_type("image/*");
+        selection.add_type("text/plain");
+        assert!(selection.selects_type(Some("image/png")));
+        assert!(selection.selects_type(Some("text/plain")));
+        assert!(!selection.selects_type(Some("application/pdf")));
+        assert!(!selection.selects_type(None));
+    }
+}
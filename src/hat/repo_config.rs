@@ -0,0 +1,269 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Repository parameters stored in the backend, so that every client writes data the same way.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use crypto::keys::{self, Keeper};
+use errors::HatError;
+use hat::repo_format::FORMAT_VERSION;
+use key;
+use std::fmt;
+use std::str;
+
+
+/// The backend name of the config. Blob names are longer, so this never clashes.
+pub const BLOB_NAME: &'static [u8] = b"cfg";
+
+/// How file data is hashed into chunk names.
+pub const HASH_ALGORITHM: &'static str = "keyed-blake2b-512";
+
+/// The smallest blob size that holds any chunk, including a whole fixed-size block.
+pub const MIN_BLOB_SIZE: usize = 2 * key::FIXED_BLOCK_LEN;
+
+const NONCE_BYTES: usize = 8;
+const AD: &'static [u8] = b"hat-repository-config";
+
+fn key(keys: &Keeper) -> ::secstr::SecStr {
+    keys.from_nonce(b"hat:repository-config-key", 32)
+}
+
+/// The parameters of a repository, shared by all its clients.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RepoConfig {
+    pub format_version: u32,
+    pub hash_algorithm: String,
+    pub chunk_len: usize,
+    pub fixed_block_len: usize,
+    /// The size of new blobs. Every blob records its own size, so this can change at any time
+    /// without affecting the blobs already written.
+    pub max_blob_size: usize,
+    /// Whether `max_blob_size` was changed with `set_max_blob_size`. Until then, clients must
+    /// ask for the blob size the repository was created with.
+    pub blob_size_changed: bool,
+}
+
+impl RepoConfig {
+    /// The parameters this version of hat writes with.
+    pub fn new(max_blob_size: usize) -> RepoConfig {
+        RepoConfig {
+            format_version: FORMAT_VERSION,
+            hash_algorithm: HASH_ALGORITHM.to_owned(),
+            chunk_len: key::DEFAULT_CHUNK_LEN,
+            fixed_block_len: key::FIXED_BLOCK_LEN,
+            max_blob_size: max_blob_size,
+            blob_size_changed: false,
+        }
+    }
+
+    fn encode(&self) -> String {
+        let mut text = format!(
+            "format_version {}\nhash_algorithm {}\nchunk_len {}\nfixed_block_len {}\n\
+             max_blob_size {}\n",
+            self.format_version,
+            self.hash_algorithm,
+            self.chunk_len,
+            self.fixed_block_len,
+            self.max_blob_size
+        );
+        // Only written once set, so that the configs of other repositories stay as they were.
+        if self.blob_size_changed {
+            text.push_str("blob_size_changed 1\n");
+        }
+        text
+    }
+
+    fn decode(text: &str) -> Result<RepoConfig, String> {
+        let mut config = RepoConfig::new(0);
+        let mut seen = 0;
+        for line in text.lines() {
+            let mut parts = line.splitn(2, ' ');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(format!("Malformed repository config line: {}", line)),
+            };
+            let number = || {
+                value.parse::<usize>().map_err(|_| {
+                    format!("Invalid repository config value for {}: {}", name, value)
+                })
+            };
+            match name {
+                "format_version" => config.format_version = number()? as u32,
+                "hash_algorithm" => config.hash_algorithm = value.to_owned(),
+                "chunk_len" => config.chunk_len = number()?,
+                "fixed_block_len" => config.fixed_block_len = number()?,
+                "max_blob_size" => config.max_blob_size = number()?,
+                "blob_size_changed" => {
+                    config.blob_size_changed = number()? != 0;
+                    continue;
+                }
+                _ => return Err(format!("Unknown repository config setting: {}", name)),
+            }
+            seen += 1;
+        }
+        if seen != 5 {
+            return Err(From::from("Incomplete repository config"));
+        }
+        Ok(config)
+    }
+
+    /// Fail with the first setting in which `local` differs from this config. Once the blob
+    /// size was changed with `set_max_blob_size`, clients write new blobs with the stored one
+    /// whatever they ask for.
+    pub fn check_matches(&self, local: &RepoConfig) -> Result<(), HatError> {
+        let mismatch = |name: &str, stored: &fmt::Display, local: &fmt::Display| {
+            Err(From::from(format!(
+                "The repository uses {} {}, but {} was requested",
+                name,
+                stored,
+                local
+            )))
+        };
+        if self.hash_algorithm != local.hash_algorithm {
+            return mismatch("hash algorithm", &self.hash_algorithm, &local.hash_algorithm);
+        }
+        if self.chunk_len != local.chunk_len {
+            return mismatch("chunk length", &self.chunk_len, &local.chunk_len);
+        }
+        if self.fixed_block_len != local.fixed_block_len {
+            return mismatch("fixed block length", &self.fixed_block_len, &local.fixed_block_len);
+        }
+        if !self.blob_size_changed && self.max_blob_size != local.max_blob_size {
+            return mismatch("blob size", &self.max_blob_size, &local.max_blob_size);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RepoConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+/// The stored config, or `None` for a repository that does not have one yet.
+pub fn load<B: StoreBackend>(
+    backend: &B,
+    keys: &Keeper,
+) -> Result<Option<RepoConfig>, HatError> {
+    let sealed = match backend.retrieve(BLOB_NAME)? {
+        Some(sealed) => sealed,
+        None => return Ok(None),
+    };
+    if sealed.len() < NONCE_BYTES {
+        return Err(From::from("Repository config is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+    let text = Keeper::try_symmetric_unlock(key(keys).unsecure(), ciphertext, AD, nonce)
+        .ok_or("Could not decrypt the repository config (wrong key?)")?;
+    let text = str::from_utf8(&text[..]).map_err(|_| "Repository config is not text")?;
+    Ok(Some(RepoConfig::decode(text)?))
+}
+
+/// Store `config` as one encrypted blob. Replacing a blob is atomic in every backend, so other
+/// clients see either the old or the new config.
+pub fn store<B: StoreBackend>(
+    backend: &B,
+    keys: &Keeper,
+    config: &RepoConfig,
+) -> Result<(), HatError> {
+    let nonce = keys::random_bytes(NONCE_BYTES);
+    let mut sealed = nonce.unsecure().to_vec();
+    sealed.extend(Keeper::symmetric_lock(
+        config.encode().as_bytes(),
+        AD,
+        nonce.unsecure(),
+        key(keys).unsecure(),
+    ));
+    backend.store(BLOB_NAME, &CipherText::new(sealed))?;
+    Ok(backend.flush()?)
+}
+
+/// Check `local` against the stored config, storing it if the repository has none yet.
+pub fn load_or_store<B: StoreBackend>(
+    backend: &B,
+    keys: &Keeper,
+    local: &RepoConfig,
+) -> Result<RepoConfig, HatError> {
+    match load(backend, keys)? {
+        Some(stored) => {
+            stored.check_matches(local)?;
+            Ok(stored)
+        }
+        None => {
+            store(backend, keys, local)?;
+            Ok(local.clone())
+        }
+    }
+}
+
+/// Write new blobs of `max_blob_size` bytes from now on, in every client that opens the
+/// repository afterwards.
+pub fn set_max_blob_size<B: StoreBackend>(
+    backend: &B,
+    keys: &Keeper,
+    max_blob_size: usize,
+) -> Result<RepoConfig, HatError> {
+    if max_blob_size < MIN_BLOB_SIZE {
+        return Err(From::from(format!("Blobs must be at least {} bytes", MIN_BLOB_SIZE)));
+    }
+    let mut config = match load(backend, keys)? {
+        Some(stored) => {
+            stored.check_matches(&RepoConfig::new(stored.max_blob_size))?;
+            stored
+        }
+        None => RepoConfig::new(max_blob_size),
+    };
+    if config.max_blob_size != max_blob_size || !config.blob_size_changed {
+        config.max_blob_size = max_blob_size;
+        config.blob_size_changed = true;
+        store(backend, keys, &config)?;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use crypto::keys::MasterKey;
+
+    #[test]
+    fn config_round_trip_and_mismatch() {
+        let backend = MemoryBackend::new();
+        let keys = Keeper::from_master_key(&MasterKey::from_passphrase("config"));
+        assert_eq!(load(&backend, &keys).unwrap(), None);
+
+        let config = RepoConfig::new(4 * 1024 * 1024);
+        assert_eq!(load_or_store(&backend, &keys, &config).unwrap(), config);
+        assert_eq!(load(&backend, &keys).unwrap(), Some(config.clone()));
+        assert!(load_or_store(&backend, &keys, &RepoConfig::new(1024 * 1024)).is_err());
+
+        let mut other_chunks = RepoConfig::new(4 * 1024 * 1024);
+        other_chunks.chunk_len *= 2;
+        assert!(load_or_store(&backend, &keys, &other_chunks).is_err());
+
+        let changed = set_max_blob_size(&backend, &keys, 8 * 1024 * 1024).unwrap();
+        assert_eq!(changed.max_blob_size, 8 * 1024 * 1024);
+        assert_eq!(load(&backend, &keys).unwrap(), Some(changed.clone()));
+        assert!(set_max_blob_size(&backend, &keys, 1024).is_err());
+        // Once changed, the stored blob size wins over the local one.
+        assert_eq!(load_or_store(&backend, &keys, &RepoConfig::new(1024 * 1024)).unwrap(), changed);
+
+        let other = Keeper::from_master_key(&MasterKey::from_passphrase("other"));
+        assert!(load(&backend, &other).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The marker that identifies a repository in its backend and records the format version.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use errors::HatError;
+use std::str;
+
+
+/// The backend name of the marker. Blob names are longer, so this never clashes.
+pub const MARKER_NAME: &'static [u8] = b"fmt";
+
+/// The format written by this version of hat.
+pub const FORMAT_VERSION: u32 = 1;
+
+const MARKER_PREFIX: &'static str = "hat repository format ";
+
+/// Mark the empty backend as a repository of the current format.
+pub fn init<B: StoreBackend>(backend: &B) -> Result<(), HatError> {
+    if format_version(backend)?.is_some() {
+        return Err(From::from("The backend already holds a repository"));
+    }
+    if !backend.list()?.is_empty() {
+        return Err(From::from("The backend is not empty"));
+    }
+    let marker = format!("{}{}\n", MARKER_PREFIX, FORMAT_VERSION);
+    backend.store(MARKER_NAME, &CipherText::new(marker.into_bytes()))?;
+    Ok(backend.flush()?)
+}
+
+/// The format version of the repository, or `None` if it has no marker, e.g. because it was
+/// created before `init` existed.
+pub fn format_version<B: StoreBackend>(backend: &B) -> Result<Option<u32>, HatError> {
+    let marker = match backend.retrieve(MARKER_NAME)? {
+        Some(marker) => marker,
+        None => return Ok(None),
+    };
+    let text = str::from_utf8(&marker[..]).unwrap_or("").trim_right();
+    if text.starts_with(MARKER_PREFIX) {
+        if let Ok(version) = text[MARKER_PREFIX.len()..].parse::<u32>() {
+            return Ok(Some(version));
+        }
+    }
+    Err(From::from("The backend holds something that is not a hat repository"))
+}
+
+/// Fail if the repository has a format this version of hat does not know.
+pub fn check<B: StoreBackend>(backend: &B) -> Result<(), HatError> {
+    match format_version(backend)? {
+        Some(version) if version > FORMAT_VERSION => Err(From::from(format!(
+            "The repository has format {}, but this hat only knows up to {}",
+            version,
+            FORMAT_VERSION
+        ))),
+        _ => Ok(()),
+    }
+}
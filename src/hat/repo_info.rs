@@ -0,0 +1,103 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A summary of the state of a repository, for a quick look at its health.
+
+use chrono;
+use hat::repo_config::RepoConfig;
+use std::fmt;
+use util::human;
+
+
+/// How blobs and the repository config are encrypted.
+pub const ENCRYPTION: &'static str = "chacha20-poly1305";
+
+/// The state of a repository, as far as the local index knows it.
+#[derive(Clone, Debug)]
+pub struct RepoInfo {
+    /// `None` for repositories from before format markers.
+    pub format_version: Option<u32>,
+    /// `None` for repositories from before the stored config.
+    pub config: Option<RepoConfig>,
+    /// Whether the local index is kept encrypted while the repository is closed.
+    pub sealed_index: bool,
+    /// The local index files and their sizes.
+    pub index_files: Vec<(String, u64)>,
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    /// Blobs that garbage collection marked for deletion, not yet pruned.
+    pub prunable_blobs: u64,
+    pub snapshots: u64,
+    pub families: u64,
+    /// Hashes that were reserved by a snapshot whose data is not committed yet.
+    pub reserved_hashes: u64,
+    pub last_gc: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_verified: Option<chrono::DateTime<chrono::Utc>>,
+    /// Whether a process holds the repository lock, e.g. because a backup is running.
+    pub locked: bool,
+}
+
+fn time(t: &Option<chrono::DateTime<chrono::Utc>>) -> String {
+    match *t {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => "never".to_owned(),
+    }
+}
+
+impl fmt::Display for RepoInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.format_version {
+            Some(v) => writeln!(f, "Format version:   {}", v)?,
+            None => writeln!(f, "Format version:   unmarked")?,
+        }
+        match self.config {
+            Some(ref c) => {
+                writeln!(f, "Hashing:          {}", c.hash_algorithm)?;
+                writeln!(f, "Chunk size:       {}", human::bytes(c.chunk_len as u64))?;
+                writeln!(f, "Max blob size:    {}", human::bytes(c.max_blob_size as u64))?;
+            }
+            None => writeln!(f, "Config:           not stored")?,
+        }
+        writeln!(f, "Encryption:       {}", ENCRYPTION)?;
+        writeln!(f, "Compression:      none")?;
+        writeln!(
+            f,
+            "Local index:      {} in {} files{}",
+            human::bytes(self.index_files.iter().map(|&(_, size)| size).sum()),
+            self.index_files.len(),
+            if self.sealed_index { ", sealed" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "Blobs:            {} in {} blobs, {} to prune",
+            human::bytes(self.blob_bytes),
+            human::count(self.blobs),
+            human::count(self.prunable_blobs)
+        )?;
+        writeln!(
+            f,
+            "Snapshots:        {} in {} families",
+            human::count(self.snapshots),
+            human::count(self.families)
+        )?;
+        writeln!(f, "Reserved hashes:  {}", human::count(self.reserved_hashes))?;
+        writeln!(f, "Last GC:          {}", time(&self.last_gc))?;
+        writeln!(f, "Last verified:    {}", time(&self.last_verified))?;
+        write!(
+            f,
+            "Lock:             {}",
+            if self.locked { "held by a running process" } else { "free" }
+        )
+    }
+}
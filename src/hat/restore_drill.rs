@@ -0,0 +1,168 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Restore drills: restore a random sample of files from a snapshot and check what comes back.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::metadata::MetadataPolicy;
+use hat::walker::Content;
+use key;
+use rand::{self, Rng};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+
+/// The outcome of a restore drill.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DrillReport {
+    /// Regular files in the snapshot.
+    pub files: u64,
+    pub sampled: u64,
+    pub passed: u64,
+    /// Bytes restored.
+    pub bytes: u64,
+    /// One message per sampled file that could not be restored intact.
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+impl DrillReport {
+    pub fn is_pass(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for DrillReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} sampled files restored intact ({} bytes, {} files in snapshot)",
+            if self.is_pass() { "PASS" } else { "FAIL" },
+            self.passed,
+            self.sampled,
+            self.bytes,
+            self.files
+        )
+    }
+}
+
+/// Restore up to `sample` files picked uniformly at random from the snapshot directory `root`
+/// into `scratch`, check their contents and metadata, and delete them again.
+///
+/// Contents are verified against the hash tree while they are read. `scratch` must not exist
+/// yet and is removed afterwards.
+pub fn run<B: StoreBackend>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    root: hash::tree::HashRef,
+    sample: usize,
+    scratch: &Path,
+) -> Result<DrillReport, HatError> {
+    let mut report = DrillReport::default();
+    let mut rng = rand::thread_rng();
+
+    // Reservoir sampling, so the snapshot is only walked once.
+    let mut picked = vec![];
+    let mut dirs = vec![(PathBuf::new(), root)];
+    while let Some((path, dir_ref)) = dirs.pop() {
+        for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+            let entry_path = path.join(OsStr::from_bytes(&entry.info.name[..]));
+            match content {
+                Content::Dir(dir_ref) => dirs.push((entry_path, dir_ref)),
+                Content::Data(data_ref) => {
+                    report.files += 1;
+                    if picked.len() < sample {
+                        picked.push((entry_path, entry, data_ref));
+                    } else {
+                        let i = rng.gen_range(0, report.files) as usize;
+                        if i < sample {
+                            picked[i] = (entry_path, entry, data_ref);
+                        }
+                    }
+                }
+                Content::Link(_) => (),
+            }
+        }
+    }
+
+    fs::create_dir(scratch)?;
+    for (i, (path, entry, data_ref)) in picked.into_iter().enumerate() {
+        report.sampled += 1;
+        let target = scratch.join(i.to_string());
+        let res = restore_one(backend, &entry, data_ref, &target);
+        let _ = fs::remove_file(&target);
+        match res {
+            Ok(bytes) => {
+                report.passed += 1;
+                report.bytes += bytes;
+            }
+            Err(e) => report.failures.push((path, e)),
+        }
+    }
+    fs::remove_dir_all(scratch)?;
+
+    Ok(report)
+}
+
+fn restore_one<B: StoreBackend>(
+    backend: &key::HashStoreBackend<B>,
+    entry: &key::Entry,
+    data_ref: hash::tree::HashRef,
+    target: &Path,
+) -> Result<u64, String> {
+    let mut fd = fs::File::create(target).map_err(|e| format!("could not create: {}", e))?;
+    let mut bytes = 0;
+    let tree = hash::tree::LeafIterator::new(backend.clone(), data_ref)
+        .map_err(|e| format!("could not read: {}", e))?;
+    if let Some(mut tree) = tree {
+        while let Some(chunk) = tree.try_next().map_err(|e| format!("could not read: {}", e))? {
+            fd.write_all(&chunk[..]).map_err(|e| format!("could not write: {}", e))?;
+            bytes += chunk.len() as u64;
+        }
+    }
+    drop(fd);
+
+    let policy = MetadataPolicy {
+        owner: false,
+        permissions: true,
+        times: true,
+        attributes: false,
+    };
+    policy
+        .apply(target, &entry.info, false)
+        .map_err(|e| format!("could not apply metadata: {}", e))?;
+
+    let meta = fs::metadata(target).map_err(|e| format!("could not stat: {}", e))?;
+    if let Some(ref perms) = entry.info.permissions {
+        let (expected, actual) = (perms.mode() & 0o7777, meta.permissions().mode() & 0o7777);
+        if expected != actual {
+            return Err(format!("mode is {:o} instead of {:o}", actual, expected));
+        }
+    }
+    if let (Some(expected), Some(_)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
+        if meta.mtime() as u64 != expected {
+            return Err(format!("mtime is {} instead of {}", meta.mtime(), expected));
+        }
+    }
+
+    Ok(bytes)
+}
@@ -0,0 +1,298 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Restore of file contents in the order their chunks are stored in.
+
+use errors::HatError;
+use hash::tree::{HashRef, HashTreeBackend};
+use hat::metadata::MetadataPolicy;
+use hat::restore_progress::RestoreProgress;
+use key;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+
+/// Most files kept open while writing chunks. Files closed to stay below this are opened
+/// again when their next chunk is read.
+const MAX_OPEN_FILES: usize = 64;
+/// Most chunk references collected before the files they belong to are written, to bound the
+/// memory a checkout of many files takes. A blob with chunks in several batches is read once
+/// per batch.
+const MAX_PENDING_CHUNKS: usize = 1 << 18;
+
+/// The order in which a checkout writes file contents.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RestoreOrder {
+    /// File by file, in the order of the snapshot.
+    Tree,
+    /// Blob by blob, so that every blob is read once, however its chunks are spread over the
+    /// files. Every chunk is written at its offset in its file as soon as it is read. Files
+    /// from snapshots that did not record chunk sizes are written in order afterwards.
+    Blob,
+}
+
+impl RestoreOrder {
+    pub fn parse(s: &str) -> Result<RestoreOrder, String> {
+        match s {
+            "tree" => Ok(RestoreOrder::Tree),
+            "blob" => Ok(RestoreOrder::Blob),
+            _ => Err(format!("Unknown restore order '{}', expected tree or blob", s)),
+        }
+    }
+}
+
+/// Files and metadata collected while walking a snapshot, to be restored afterwards.
+pub struct BlobOrderRestore {
+    files: Vec<(PathBuf, Vec<HashRef>)>,
+    pending_chunks: usize,
+    metadata: Vec<(PathBuf, key::Info, bool)>,
+}
+
+impl BlobOrderRestore {
+    pub fn new() -> BlobOrderRestore {
+        BlobOrderRestore {
+            files: vec![],
+            pending_chunks: 0,
+            metadata: vec![],
+        }
+    }
+
+    /// Write the leaf `chunks` to the existing, empty file at `path`.
+    pub fn add_file(&mut self, path: PathBuf, chunks: Vec<HashRef>) {
+        self.pending_chunks += chunks.len();
+        self.files.push((path, chunks));
+    }
+
+    /// Whether enough chunks were collected that the files should be written before more
+    /// are added, with `write_files`.
+    pub fn is_full(&self) -> bool {
+        self.pending_chunks >= MAX_PENDING_CHUNKS
+    }
+
+    /// Apply `info` to `path` after all file contents have been written.
+    pub fn add_metadata(&mut self, path: PathBuf, info: key::Info, is_symlink: bool) {
+        self.metadata.push((path, info, is_symlink));
+    }
+
+    /// The offset of every chunk in its file, for the files whose chunk sizes are all known.
+    fn offsets(&self) -> Vec<Option<Vec<u64>>> {
+        self.files
+            .iter()
+            .map(|&(_, ref chunks)| {
+                let mut offsets = Vec::with_capacity(chunks.len());
+                let mut offset = 0;
+                for href in chunks {
+                    match href.size {
+                        Some(size) => {
+                            offsets.push(offset);
+                            offset += size.bytes;
+                        }
+                        None => return None,
+                    }
+                }
+                Some(offsets)
+            })
+            .collect()
+    }
+
+    /// Every chunk of the files with `offsets` as (file, position in file), grouped by blob in
+    /// the order the blobs are first needed, and by position within each blob.
+    fn schedule(&self, offsets: &[Option<Vec<u64>>]) -> Vec<(usize, usize)> {
+        let mut blob_rank = HashMap::new();
+        let mut order = vec![];
+        for (file, &(_, ref chunks)) in self.files.iter().enumerate() {
+            if offsets[file].is_none() {
+                continue;
+            }
+            for (index, href) in chunks.iter().enumerate() {
+                let pref = &href.persistent_ref;
+                let next_rank = blob_rank.len();
+                let rank = *blob_rank.entry(&pref.blob_name[..]).or_insert(next_rank);
+                order.push((rank, pref.offset, file, index));
+            }
+        }
+        order.sort();
+        order.into_iter().map(|(_, _, file, index)| (file, index)).collect()
+    }
+
+    /// Write the files and apply their metadata, counting the data written in `progress`.
+    pub fn run<B>(
+        mut self,
+        backend: &B,
+        policy: &MetadataPolicy,
+        progress: Option<&RestoreProgress>,
+    ) -> Result<(), HatError>
+    where
+        B: HashTreeBackend<Err = key::MsgError>,
+    {
+        self.write_files(backend, progress)?;
+        for (path, info, is_symlink) in self.metadata {
+            policy.apply(&path, &info, is_symlink)?;
+        }
+        Ok(())
+    }
+
+    /// Write the contents of the files added so far, and forget their chunks. Their metadata
+    /// is still applied by `run`.
+    pub fn write_files<B>(
+        &mut self,
+        backend: &B,
+        progress: Option<&RestoreProgress>,
+    ) -> Result<(), HatError>
+    where
+        B: HashTreeBackend<Err = key::MsgError>,
+    {
+        self.write_in_blob_order(backend, progress)?;
+        self.files.clear();
+        self.pending_chunks = 0;
+        Ok(())
+    }
+
+    fn write_in_blob_order<B>(
+        &self,
+        backend: &B,
+        progress: Option<&RestoreProgress>,
+    ) -> Result<(), HatError>
+    where
+        B: HashTreeBackend<Err = key::MsgError>,
+    {
+        let offsets = self.offsets();
+        {
+            let mut open = OpenFiles::new();
+            // Deduplicated chunks, like runs of zeros, are scheduled next to each other.
+            let mut last: Option<(&HashRef, Vec<u8>)> = None;
+
+            for (file, index) in self.schedule(&offsets) {
+                let (ref path, ref chunks) = self.files[file];
+                let href = &chunks[index];
+                let data = match last {
+                    Some((prev, ref data)) if prev.hash == href.hash => data.clone(),
+                    _ => backend.fetch_chunk(href)?.ok_or("Chunk is missing from the backend")?,
+                };
+                if href.size.map(|s| s.bytes) != Some(data.len() as u64) {
+                    return Err(From::from(format!(
+                        "Chunk {} of {} has an unexpected size",
+                        index,
+                        path.display()
+                    )));
+                }
+
+                let offset = offsets[file].as_ref().unwrap()[index];
+                write_all_at(open.get(file, path)?, &data[..], offset)?;
+                if let Some(progress) = progress {
+                    progress.add(data.len() as u64);
+                }
+                last = Some((href, data));
+            }
+        }
+
+        for (file, &(ref path, ref chunks)) in self.files.iter().enumerate() {
+            if offsets[file].is_some() {
+                continue;
+            }
+            let mut fd = fs::OpenOptions::new().append(true).open(path)?;
+            for href in chunks {
+                let data = backend.fetch_chunk(href)?.ok_or("Chunk is missing from the backend")?;
+                fd.write_all(&data[..])?;
+                if let Some(progress) = progress {
+                    progress.add(data.len() as u64);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files being written, at most `MAX_OPEN_FILES` of them open at a time. Chunks are
+/// written at absolute offsets, so a file can be closed and opened again at any point.
+struct OpenFiles {
+    files: HashMap<usize, (fs::File, u64)>,
+    uses: u64,
+}
+
+impl OpenFiles {
+    fn new() -> OpenFiles {
+        OpenFiles {
+            files: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// The open file number `file` at `path`, closing the least recently used one if needed.
+    fn get(&mut self, file: usize, path: &Path) -> io::Result<&fs::File> {
+        self.uses += 1;
+        if !self.files.contains_key(&file) {
+            if self.files.len() >= MAX_OPEN_FILES {
+                let oldest = self.files.iter().min_by_key(|&(_, v)| v.1).map(|(k, _)| *k);
+                if let Some(oldest) = oldest {
+                    self.files.remove(&oldest);
+                }
+            }
+            let fd = fs::OpenOptions::new().write(true).open(path)?;
+            self.files.insert(file, (fd, 0));
+        }
+        let entry = self.files.get_mut(&file).unwrap();
+        entry.1 = self.uses;
+        Ok(&entry.0)
+    }
+}
+
+fn write_all_at(fd: &fs::File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = fd.write_at(data, offset)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Could not write chunk"));
+        }
+        data = &data[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn closed_files_are_reopened_at_their_offset() {
+        let dir = ::std::env::temp_dir().join(format!("hat-open-{}", ::time::precise_time_ns()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = (0..MAX_OPEN_FILES + 1).map(|i| dir.join(i.to_string())).collect();
+        for path in &paths {
+            fs::File::create(path).unwrap();
+        }
+
+        // Every file is closed before its second chunk is written.
+        let mut open = OpenFiles::new();
+        for offset in 0..2 {
+            for (file, path) in paths.iter().enumerate() {
+                write_all_at(open.get(file, path).unwrap(), &[offset as u8 + 1], offset).unwrap();
+            }
+        }
+        assert_eq!(open.files.len(), MAX_OPEN_FILES);
+        drop(open);
+
+        for path in &paths {
+            let mut contents = vec![];
+            fs::File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, vec![1, 2]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
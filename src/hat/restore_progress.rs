@@ -0,0 +1,134 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress and time estimates of restores.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use time;
+use util::human;
+
+
+/// Weight of the latest interval in the smoothed rate. Older intervals fade out quickly, so the
+/// estimate follows the restore as it moves between well and poorly cached data.
+const RATE_WEIGHT: f64 = 0.3;
+
+/// Bytes restored so far out of the total, shared with whoever reports on the restore.
+#[derive(Debug, Default)]
+pub struct RestoreProgress {
+    total: AtomicUsize,
+    done: AtomicUsize,
+    // The previous sample as (nanoseconds, bytes done), and the smoothed rate since.
+    rate: Mutex<(Option<(u64, u64)>, Option<f64>)>,
+}
+
+/// A sample of the progress of a restore.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RestoreStatus {
+    pub done: u64,
+    pub total: u64,
+    /// Recent rate of the restore, once there are two samples to compare.
+    pub bytes_per_sec: Option<f64>,
+}
+
+impl RestoreProgress {
+    pub fn new() -> Arc<RestoreProgress> {
+        Arc::new(RestoreProgress::default())
+    }
+
+    /// Set the number of bytes the restore will write, found from the sizes in the snapshot.
+    pub fn set_total(&self, bytes: u64) {
+        self.total.store(bytes as usize, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` of file data written.
+    pub fn add(&self, bytes: u64) {
+        self.done.fetch_add(bytes as usize, Ordering::Relaxed);
+    }
+
+    /// Sample the progress. The rate is measured between calls, so call this at a steady pace.
+    pub fn status(&self) -> RestoreStatus {
+        self.status_at(time::precise_time_ns())
+    }
+
+    fn status_at(&self, now_ns: u64) -> RestoreStatus {
+        let done = self.done.load(Ordering::Relaxed) as u64;
+        let mut rate = self.rate.lock().unwrap();
+        if let Some((then_ns, then_done)) = rate.0 {
+            if now_ns > then_ns {
+                let latest = (done - then_done) as f64 * 1e9 / (now_ns - then_ns) as f64;
+                rate.1 = Some(match rate.1 {
+                    Some(smoothed) => RATE_WEIGHT * latest + (1.0 - RATE_WEIGHT) * smoothed,
+                    None => latest,
+                });
+            }
+        }
+        rate.0 = Some((now_ns, done));
+        RestoreStatus {
+            done: done,
+            total: self.total.load(Ordering::Relaxed) as u64,
+            bytes_per_sec: rate.1,
+        }
+    }
+}
+
+impl RestoreStatus {
+    /// Seconds until the restore is done at the recent rate, if it is moving at all.
+    pub fn eta_secs(&self) -> Option<u64> {
+        match self.bytes_per_sec {
+            Some(rate) if rate > 0.0 => {
+                Some((self.total.saturating_sub(self.done) as f64 / rate).ceil() as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RestoreStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Restored {} of {}", human::bytes(self.done), human::bytes(self.total))?;
+        if let Some(rate) = self.bytes_per_sec {
+            write!(f, ", {}/s", human::bytes(rate as u64))?;
+        }
+        match self.eta_secs() {
+            Some(secs) => write!(f, ", {} left", human::duration_ms(secs * 1000)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_follows_recent_intervals() {
+        let progress = RestoreProgress::new();
+        progress.set_total(1000 * 1024 * 1024);
+        assert_eq!(None, progress.status_at(0).bytes_per_sec);
+
+        progress.add(100 * 1024 * 1024);
+        let status = progress.status_at(1_000_000_000);
+        assert_eq!(Some(100.0 * 1024.0 * 1024.0), status.bytes_per_sec);
+        assert_eq!(Some(9), status.eta_secs());
+        assert_eq!("Restored 100 MiB of 1000 MiB, 100 MiB/s, 9.0s left", status.to_string());
+
+        // A slow interval, like a run of cache misses, pulls the rate down.
+        progress.add(10 * 1024 * 1024);
+        let status = progress.status_at(2_000_000_000);
+        assert!((status.bytes_per_sec.unwrap() - 73.0 * 1024.0 * 1024.0).abs() < 1.0);
+        assert_eq!(Some(13), status.eta_secs());
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Encryption of the local index files at rest.
+//!
+//! A sealed repository keeps only encrypted copies of its index files. While it is open, the
+//! plaintext lives in a private working directory, preferably on a memory-backed filesystem.
+
+use crypto::keys::{self, Keeper};
+use errors::HatError;
+use secstr::SecStr;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use time;
+
+
+const SEALED_SUFFIX: &'static str = ".sealed";
+const NONCE_BYTES: usize = 8;
+
+/// Decrypted index files of an open repository. They are encrypted again on `seal` and when
+/// dropped.
+pub struct SealedIndex {
+    root: PathBuf,
+    work_dir: PathBuf,
+    key: SecStr,
+}
+
+/// Whether the local state in `root` has sealed index files.
+pub fn is_sealed(root: &Path) -> Result<bool, HatError> {
+    if !root.is_dir() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(root)? {
+        if file_name(&entry?.path()).map_or(false, |n| n.ends_with(SEALED_SUFFIX)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    path.file_name().and_then(|n| n.to_str()).map(|n| n.to_owned())
+}
+
+/// Files in `root` that belong to the index, as opposed to the lock or sealed copies.
+/// SQLite rebuilds the shared memory files of its write-ahead logs, so they are left out.
+pub fn is_index_file(name: &str) -> bool {
+    name != "lock" && !name.ends_with(SEALED_SUFFIX) && !name.ends_with(".tmp") &&
+        !name.ends_with("-shm")
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, HatError> {
+    let mut data = vec![];
+    fs::File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), HatError> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Decrypt the sealed index files in `root` to `work_dir`, and copy plain ones there.
+fn unseal(root: &Path, work_dir: &Path, key: &SecStr) -> Result<(), HatError> {
+    let mut plain = vec![];
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = match file_name(&path) {
+            Some(name) => name,
+            None => continue,
+        };
+        if !entry.file_type()?.is_file() {
+            continue;
+        } else if name.ends_with(SEALED_SUFFIX) {
+            let name = &name[..name.len() - SEALED_SUFFIX.len()];
+            let data = decrypt(key, name, &read_file(&path)?).ok_or_else(|| {
+                format!("Could not decrypt {} (wrong key?)", path.display())
+            })?;
+            write_private(&work_dir.join(name), &data)?;
+        } else if is_index_file(&name) {
+            plain.push(name);
+        }
+    }
+    // A sealed copy is never older than a plain file next to it.
+    for name in plain {
+        let target = work_dir.join(&name);
+        if !target.exists() {
+            write_private(&target, &read_file(&root.join(&name))?)?;
+        }
+    }
+    Ok(())
+}
+
+fn encrypt(key: &SecStr, name: &str, data: &[u8]) -> Vec<u8> {
+    let nonce = keys::random_bytes(NONCE_BYTES);
+    let mut out = nonce.unsecure().to_vec();
+    out.extend(Keeper::symmetric_lock(data, name.as_bytes(), nonce.unsecure(), key.unsecure()));
+    out
+}
+
+fn decrypt(key: &SecStr, name: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_BYTES {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+    Keeper::try_symmetric_unlock(key.unsecure(), ciphertext, name.as_bytes(), nonce)
+}
+
+impl SealedIndex {
+    /// Decrypt the index files in `root` to a new working directory. Plain index files from
+    /// before encryption was enabled are copied there too, and removed on the next `seal`.
+    pub fn open(root: &Path, keys: &Keeper) -> Result<SealedIndex, HatError> {
+        let shm = Path::new("/dev/shm");
+        let base = if shm.is_dir() {
+            shm.to_owned()
+        } else {
+            env::temp_dir()
+        };
+        let work_dir = base.join(format!("hat-index-{}", time::precise_time_ns()));
+        fs::DirBuilder::new().mode(0o700).create(&work_dir)?;
+
+        let key = keys.from_nonce(b"hat:index-key", 32);
+        if let Err(e) = unseal(root, &work_dir, &key) {
+            // Nothing to seal yet; sealing now would remove what could not be read.
+            let _ = fs::remove_dir_all(&work_dir);
+            return Err(e);
+        }
+
+        Ok(SealedIndex {
+            root: root.to_owned(),
+            work_dir: work_dir,
+            key: key,
+        })
+    }
+
+    /// Where the decrypted index files are.
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Encrypt the current index files, replacing earlier sealed copies. The indexes should
+    /// be flushed first.
+    pub fn seal(&self) -> Result<(), HatError> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.work_dir)? {
+            let entry = entry?;
+            let name = match file_name(&entry.path()) {
+                Some(name) => name,
+                None => continue,
+            };
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let sealed = encrypt(&self.key, &name, &read_file(&entry.path())?);
+            let target = self.root.join(format!("{}{}", name, SEALED_SUFFIX));
+            let tmp = self.root.join(format!("{}{}.tmp", name, SEALED_SUFFIX));
+            write_private(&tmp, &sealed)?;
+            fs::rename(&tmp, &target)?;
+            names.push(name);
+        }
+
+        // Remove sealed files that are gone (e.g. finished journals) and plaintext left over
+        // from before encryption was enabled.
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            let name = match file_name(&path) {
+                Some(name) => name,
+                None => continue,
+            };
+            if !path.is_file() {
+                continue;
+            }
+            let stale = if name.ends_with(SEALED_SUFFIX) {
+                !names.contains(&name[..name.len() - SEALED_SUFFIX.len()].to_owned())
+            } else {
+                is_index_file(&name)
+            };
+            if stale {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SealedIndex {
+    fn drop(&mut self) {
+        match self.seal() {
+            Ok(()) => {
+                if let Err(e) = fs::remove_dir_all(&self.work_dir) {
+                    warn!("Could not remove {}: {}", self.work_dir.display(), e);
+                }
+            }
+            // Keep the plaintext rather than lose the changes since the last seal.
+            Err(e) => {
+                warn!(
+                    "Could not seal the index, leaving it in {}: {}",
+                    self.work_dir.display(),
+                    e
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::keys::MasterKey;
+
+    fn temp_root() -> PathBuf {
+        let nanos = time::precise_time_ns();
+        let root = env::temp_dir().join(format!("hat-sealed-index-{}", nanos));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn plain_files_are_sealed() {
+        let root = temp_root();
+        let keys = Keeper::new_for_testing();
+        write_private(&root.join("hash_index.sqlite3"), b"index").unwrap();
+        write_private(&root.join("lock"), b"").unwrap();
+
+        {
+            let index = SealedIndex::open(&root, &keys).unwrap();
+            assert_eq!(read_file(&index.work_dir().join("hash_index.sqlite3")).unwrap(), b"index");
+            write_private(&index.work_dir().join("family"), b"keys").unwrap();
+        }
+        assert!(is_sealed(&root).unwrap());
+        assert!(!root.join("hash_index.sqlite3").exists());
+        assert!(root.join("lock").exists());
+        let sealed = read_file(&root.join("family.sealed")).unwrap();
+        assert!(!sealed.windows(4).any(|w| w == b"keys"));
+
+        let index = SealedIndex::open(&root, &keys).unwrap();
+        assert_eq!(read_file(&index.work_dir().join("family")).unwrap(), b"keys");
+        drop(index);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let root = temp_root();
+        write_private(&root.join("hash_index.sqlite3"), b"index").unwrap();
+        drop(SealedIndex::open(&root, &Keeper::new_for_testing()).unwrap());
+
+        let other = Keeper::from_master_key(&MasterKey::from_passphrase("other"));
+        assert!(SealedIndex::open(&root, &other).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accounting of the data that snapshot families share with each other.
+//!
+//! Chunks are deduplicated across the whole repository, so identical content backed up by
+//! different families is stored once.
+
+use db;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use util::human;
+
+
+/// The data kept alive by the snapshots of one family.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FamilyUsage {
+    pub family_name: String,
+    /// Chunks that no other family refers to.
+    pub unique_chunks: u64,
+    /// Sum of the stored lengths of the unique chunks.
+    pub unique_bytes: u64,
+    /// Chunks that at least one other family refers to as well.
+    pub shared_chunks: u64,
+    /// Sum of the stored lengths of the shared chunks.
+    pub shared_bytes: u64,
+}
+
+impl fmt::Display for FamilyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in {} unique chunks, {} in {} chunks shared with other families",
+            self.family_name,
+            human::bytes(self.unique_bytes),
+            human::count(self.unique_chunks),
+            human::bytes(self.shared_bytes),
+            human::count(self.shared_chunks)
+        )
+    }
+}
+
+/// Count the unique and shared data of each family, given the hash ids its snapshots hold
+/// references to.
+pub fn family_usage(
+    nodes: Vec<db::HashNode>,
+    families: Vec<(String, Vec<u64>)>,
+) -> Vec<FamilyUsage> {
+    let nodes: HashMap<u64, db::HashNode> = nodes.into_iter().map(|n| (n.id, n)).collect();
+
+    let mut reachable = vec![];
+    let mut families_of: HashMap<u64, u64> = HashMap::new();
+    for &(_, ref ids) in &families {
+        let mut seen = HashSet::new();
+        let mut queue = ids.clone();
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            *families_of.entry(id).or_insert(0) += 1;
+            if let Some(node) = nodes.get(&id) {
+                queue.extend(node.childs.iter().cloned());
+            }
+        }
+        reachable.push(seen);
+    }
+
+    families
+        .into_iter()
+        .zip(reachable)
+        .map(|((family_name, _), seen)| {
+            let mut usage = FamilyUsage {
+                family_name: family_name,
+                ..FamilyUsage::default()
+            };
+            for id in seen {
+                let length = nodes.get(&id).map_or(0, |n| n.length);
+                if families_of[&id] > 1 {
+                    usage.shared_chunks += 1;
+                    usage.shared_bytes += length;
+                } else {
+                    usage.unique_chunks += 1;
+                    usage.unique_bytes += length;
+                }
+            }
+            usage
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u64, childs: Vec<u64>, length: u64) -> db::HashNode {
+        db::HashNode {
+            id: id,
+            childs: childs,
+            blob_id: 1,
+            length: length,
+        }
+    }
+
+    #[test]
+    fn shared_subtrees_are_counted_for_every_family() {
+        let nodes = vec![
+            node(1, vec![3, 4], 10),
+            node(2, vec![4], 10),
+            node(3, vec![], 100),
+            node(4, vec![5], 20),
+            node(5, vec![], 200),
+        ];
+        let usage = family_usage(
+            nodes,
+            vec![("a".to_owned(), vec![1, 1]), ("b".to_owned(), vec![2])],
+        );
+        assert_eq!(
+            usage,
+            vec![
+                FamilyUsage {
+                    family_name: "a".to_owned(),
+                    unique_chunks: 2,
+                    unique_bytes: 110,
+                    shared_chunks: 2,
+                    shared_bytes: 220,
+                },
+                FamilyUsage {
+                    family_name: "b".to_owned(),
+                    unique_chunks: 1,
+                    unique_bytes: 10,
+                    shared_chunks: 2,
+                    shared_bytes: 220,
+                },
+            ]
+        );
+    }
+}
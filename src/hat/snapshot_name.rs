@@ -0,0 +1,167 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshot names generated from templates, so scripted runs get unique names.
+
+use chrono::NaiveDateTime;
+use libc;
+use std::collections::HashSet;
+use std::ffi::CStr;
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Host,
+    Family,
+    // A strftime format.
+    Time(String),
+}
+
+/// A template like `{host}-{family}-{YYYY-MM-DD_HH:MM}` for the names of snapshots.
+///
+/// `{host}` and `{family}` are replaced by the host and family name. Any other placeholder is a
+/// time, where `YYYY`, `MM`, `DD`, `HH` and `SS` are the year, month, day, hour and second and
+/// `MM` after `HH` is the minute.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<NameTemplate, String> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("Unclosed placeholder in {}", template)),
+            };
+            parts.push(match &rest[start + 1..end] {
+                "host" => Part::Host,
+                "family" => Part::Family,
+                time => Part::Time(time_format(time)?),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        if parts.is_empty() {
+            return Err("Empty name template".to_owned());
+        }
+        Ok(NameTemplate { parts: parts })
+    }
+
+    /// The name of a snapshot of `family` taken on `host` at the local time `now`.
+    pub fn render(&self, host: &str, family: &str, now: &NaiveDateTime) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match *part {
+                Part::Literal(ref s) => name.push_str(s),
+                Part::Host => name.push_str(host),
+                Part::Family => name.push_str(family),
+                Part::Time(ref format) => name.push_str(&now.format(format).to_string()),
+            }
+        }
+        name
+    }
+}
+
+/// Translate a time placeholder into a strftime format.
+fn time_format(placeholder: &str) -> Result<String, String> {
+    let mut format = String::new();
+    let mut seen_hour = false;
+    let mut rest = placeholder;
+    while !rest.is_empty() {
+        let (spec, len) = if rest.starts_with("YYYY") {
+            ("%Y", 4)
+        } else if rest.starts_with("MM") {
+            (if seen_hour { "%M" } else { "%m" }, 2)
+        } else if rest.starts_with("DD") {
+            ("%d", 2)
+        } else if rest.starts_with("HH") {
+            seen_hour = true;
+            ("%H", 2)
+        } else if rest.starts_with("SS") {
+            ("%S", 2)
+        } else {
+            let c = rest.chars().next().unwrap();
+            if c.is_alphanumeric() {
+                return Err(format!("Unknown placeholder {{{}}}", placeholder));
+            }
+            if c == '%' {
+                format.push('%');
+            }
+            format.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        format.push_str(spec);
+        rest = &rest[len..];
+    }
+    Ok(format)
+}
+
+/// `name`, or `name-2`, `name-3` and so on if it is already `taken`.
+pub fn unique(name: String, taken: &HashSet<String>) -> String {
+    if !taken.contains(&name) {
+        return name;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", name, n))
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap()
+}
+
+/// The name of this host, or `localhost` if it cannot be read.
+pub fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) };
+    // The name may not be terminated if it was truncated.
+    buf[buf.len() - 1] = 0;
+    if res != 0 {
+        return "localhost".to_owned();
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn render_templates() {
+        let now = NaiveDate::from_ymd(2017, 11, 3).and_hms(9, 5, 7);
+        let template = NameTemplate::parse("{host}-{family}-{YYYY-MM-DD_HH:MM}").unwrap();
+        assert_eq!(template.render("box", "home", &now), "box-home-2017-11-03_09:05");
+        let seconds = NameTemplate::parse("nightly {HHMMSS}").unwrap();
+        assert_eq!(seconds.render("box", "home", &now), "nightly 090507");
+
+        assert!(NameTemplate::parse("{YYYY-MM").is_err());
+        assert!(NameTemplate::parse("{hostname}").is_err());
+        assert!(NameTemplate::parse("").is_err());
+    }
+
+    #[test]
+    fn unique_names() {
+        let taken: HashSet<String> = vec!["a".to_owned(), "a-2".to_owned()].into_iter().collect();
+        assert_eq!(unique("a".to_owned(), &taken), "a-3");
+        assert_eq!(unique("b".to_owned(), &taken), "b");
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Rules for which local files to include in a snapshot.
+
+use filetime::FileTime;
+use hat::file_attributes;
+use key;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use util::dirfd::DirFd;
+
+
+/// Signature that starts a valid CACHEDIR.TAG file (see http://www.brynosaurus.com/cachedir/).
+const CACHEDIR_TAG_SIGNATURE: &'static [u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+
+/// Filters applied to the source tree while taking a snapshot.
+///
+/// Size and age filters only apply to regular files; directories are always traversed.
+#[derive(Clone, Debug, Default)]
+pub struct SourceFilter {
+    /// Skip files larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Skip files smaller than this many bytes.
+    pub min_file_size: Option<u64>,
+    /// Skip files not modified since this time (in seconds since the epoch).
+    pub changed_since: Option<u64>,
+    /// Do not descend into directories on other devices than the snapshot root.
+    pub one_file_system: bool,
+    /// Skip directories tagged as caches with a valid CACHEDIR.TAG file.
+    pub exclude_caches: bool,
+    /// Skip directories containing a file with one of these names (e.g. `.nobackup`).
+    pub exclude_markers: Vec<String>,
+    /// Skip files and directories with the nodump attribute (`chattr +d`).
+    pub exclude_nodump: bool,
+    /// Split files whose name ends with one of these (e.g. `.img`) into fixed, aligned blocks
+    /// of `key::FIXED_BLOCK_LEN` bytes, as suits disk and virtual machine images.
+    pub fixed_block_suffixes: Vec<String>,
+    /// Read files at most this many bytes per second, to leave the disk to other programs.
+    pub read_limit: Option<u64>,
+    /// Memory map large files rather than reading them through a buffer. A mapped file that
+    /// is truncated while it is read crashes the process.
+    pub mmap: bool,
+}
+
+impl SourceFilter {
+    /// Check whether a file with this metadata should be included.
+    pub fn includes(&self, meta: &fs::Metadata) -> bool {
+        if !meta.is_file() {
+            return true;
+        }
+        if let Some(max) = self.max_file_size {
+            if meta.len() > max {
+                return false;
+            }
+        }
+        if let Some(min) = self.min_file_size {
+            if meta.len() < min {
+                return false;
+            }
+        }
+        if let Some(since) = self.changed_since {
+            if FileTime::from_last_modification_time(meta).seconds_relative_to_1970() < since {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check whether the directory at `path` is marked for exclusion.
+    /// Returns the name of the marker file if it is.
+    pub fn excluded_by_marker(&self, path: &Path) -> Option<String> {
+        if self.exclude_caches && is_cachedir_tag(fs::File::open(path.join("CACHEDIR.TAG"))) {
+            return Some("CACHEDIR.TAG".to_owned());
+        }
+        self.exclude_markers
+            .iter()
+            .find(|m| fs::symlink_metadata(path.join(m)).is_ok())
+            .cloned()
+    }
+
+    /// Like `excluded_by_marker`, for a directory opened by descriptor.
+    pub fn excluded_by_marker_in(&self, dir: &DirFd) -> Option<String> {
+        if self.exclude_caches && is_cachedir_tag(dir.open_file(OsStr::new("CACHEDIR.TAG"))) {
+            return Some("CACHEDIR.TAG".to_owned());
+        }
+        self.exclude_markers
+            .iter()
+            .find(|m| dir.symlink_metadata(OsStr::new(m)).is_ok())
+            .cloned()
+    }
+
+    /// Check whether a file with these inode flags should be excluded, along with everything
+    /// below it.
+    pub fn excluded_by_flags(&self, flags: Option<u32>) -> bool {
+        self.exclude_nodump && flags.map_or(false, |f| f & file_attributes::FLAG_NODUMP != 0)
+    }
+
+    /// How to split the data of the file at `path` into chunks.
+    pub fn chunking(&self, path: &Path) -> key::Chunking {
+        let name = path.file_name().map(|n| n.as_bytes()).unwrap_or(b"");
+        if self.fixed_block_suffixes.iter().any(|s| name.ends_with(s.as_bytes())) {
+            key::Chunking::FixedBlocks(key::FIXED_BLOCK_LEN)
+        } else {
+            key::Chunking::Default
+        }
+    }
+
+    /// A short description of the active filters, suitable for the snapshot message.
+    pub fn describe(&self) -> Option<String> {
+        let mut parts = vec![];
+        if let Some(max) = self.max_file_size {
+            parts.push(format!("max-file-size={}", max));
+        }
+        if let Some(min) = self.min_file_size {
+            parts.push(format!("min-file-size={}", min));
+        }
+        if let Some(since) = self.changed_since {
+            parts.push(format!("changed-since={}", since));
+        }
+        if self.one_file_system {
+            parts.push("one-file-system".to_owned());
+        }
+        if self.exclude_caches {
+            parts.push("exclude-caches".to_owned());
+        }
+        for marker in &self.exclude_markers {
+            parts.push(format!("exclude-if-present={}", marker));
+        }
+        if self.exclude_nodump {
+            parts.push("exclude-nodump".to_owned());
+        }
+        for suffix in &self.fixed_block_suffixes {
+            parts.push(format!("fixed-blocks={}", suffix));
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" "))
+        }
+    }
+}
+
+fn is_cachedir_tag(file: io::Result<fs::File>) -> bool {
+    let mut buf = vec![0; CACHEDIR_TAG_SIGNATURE.len()];
+    match file {
+        Ok(mut fd) => fd.read_exact(&mut buf).is_ok() && &buf[..] == CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Staging of snapshots on archival backends, whose blobs must be restored before reading.
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker::Content;
+use key;
+use std::collections::HashMap;
+use std::fmt;
+
+
+/// What staging a snapshot found and requested.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StageReport {
+    /// Blobs holding data of the snapshot, as far as it could be walked.
+    pub blobs: u64,
+    /// Blobs that can be read now.
+    pub ready: u64,
+    /// Blobs that a restore was requested for.
+    pub requested: u64,
+    /// Directories that could not be listed yet, because their blobs are still archived.
+    pub pending_dirs: u64,
+}
+
+impl StageReport {
+    /// Whether every blob of the snapshot can be read.
+    pub fn is_ready(&self) -> bool {
+        self.ready == self.blobs && self.pending_dirs == 0
+    }
+}
+
+impl fmt::Display for StageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} blobs ready, {} restores requested",
+            self.ready,
+            self.blobs,
+            self.requested
+        )?;
+        if self.pending_dirs > 0 {
+            write!(
+                f,
+                ", {} directories not listed yet (run again once restored)",
+                self.pending_dirs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Names of the blobs holding the hash tree below `hash`, from the local index.
+fn tree_blobs(hash_index: &hash::HashIndex, hash: &hash::Hash) -> Vec<Vec<u8>> {
+    let mut names = vec![];
+    let mut queue: Vec<u64> = hash_index.get_id(hash).into_iter().collect();
+    while let Some(id) = queue.pop() {
+        if let Some(entry) = hash_index.get_hash(id) {
+            if let Some(chunk) = entry.persistent_ref {
+                if blob::is_blob_name(&chunk.blob_name) {
+                    names.push(chunk.blob_name);
+                }
+            }
+            queue.extend(entry.childs.unwrap_or_default());
+        }
+    }
+    names
+}
+
+/// Request restores of every blob below `dir_ref` that cannot be read yet.
+///
+/// Directory listings are themselves stored in blobs, so a directory is only descended into
+/// once its blobs are readable. Staging again after the restores complete continues below it.
+pub fn run<B: StoreBackend>(
+    family: &Family<B>,
+    hash_index: &hash::HashIndex,
+    backend: &key::HashStoreBackend<B>,
+    blob_index: &blob::BlobIndex,
+    blob_backend: &B,
+    dir_ref: hash::tree::HashRef,
+) -> Result<StageReport, HatError> {
+    let mut report = StageReport::default();
+    let mut seen = HashMap::new();
+    let mut readable = |names: Vec<Vec<u8>>, report: &mut StageReport| -> Result<bool, HatError> {
+        let mut all_ready = true;
+        for name in names {
+            let ready = match seen.get(&name).cloned() {
+                Some(ready) => ready,
+                None => {
+                    report.blobs += 1;
+                    let location = blob_index.location(&name);
+                    let ready = blob_backend.is_readable(&location)?;
+                    if ready {
+                        report.ready += 1;
+                    } else {
+                        blob_backend.request_restore(&location)?;
+                        report.requested += 1;
+                    }
+                    seen.insert(name, ready);
+                    ready
+                }
+            };
+            all_ready &= ready;
+        }
+        Ok(all_ready)
+    };
+
+    let mut dirs = vec![dir_ref];
+    while let Some(dir) = dirs.pop() {
+        if !readable(tree_blobs(hash_index, &dir.hash), &mut report)? {
+            report.pending_dirs += 1;
+            continue;
+        }
+        for (_, content) in family.fetch_dir_data(dir, backend.clone())? {
+            match content {
+                Content::Dir(href) => dirs.push(href),
+                Content::Data(href) => {
+                    readable(tree_blobs(hash_index, &href.hash), &mut report)?;
+                }
+                Content::Link(_) => (),
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,112 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Comparison of a snapshot against the live filesystem.
+
+use backend::StoreBackend;
+use errors::HatError;
+use filetime::FileTime;
+use hash;
+use hat::family::Family;
+use hat::walker::Content;
+use key;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+
+/// How a path differs between a snapshot and the filesystem.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// Only on the filesystem. New directories are reported without their contents.
+    New,
+    /// Modification time, size or type differs.
+    Modified,
+    /// Only in the snapshot.
+    Deleted,
+}
+
+/// Compare the snapshot directory `root` with the directory `local`.
+/// Returns the changed paths relative to `local`, sorted by path.
+pub fn compare<B: StoreBackend>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    root: hash::tree::HashRef,
+    local: &Path,
+) -> Result<Vec<(Change, PathBuf)>, HatError> {
+    let mut changes = vec![];
+    let mut stack = vec![(root, PathBuf::new())];
+
+    while let Some((dir_ref, rel)) = stack.pop() {
+        let mut stored = BTreeMap::new();
+        for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+            stored.insert(OsString::from_vec(entry.info.name.clone()), (entry, content));
+        }
+
+        let mut found = BTreeMap::new();
+        match fs::read_dir(local.join(&rel)) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    found.insert(entry.file_name(), fs::symlink_metadata(entry.path())?);
+                }
+            }
+            // A directory that disappeared while comparing has no entries.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(From::from(e)),
+        }
+
+        for (name, meta) in &found {
+            let path = rel.join(name);
+            match stored.remove(name) {
+                None => changes.push((Change::New, path)),
+                Some((entry, content)) => {
+                    if let (&Content::Dir(ref child), true) = (&content, meta.is_dir()) {
+                        stack.push((child.clone(), path));
+                    } else if !unchanged(&entry, &content, meta, &local.join(&path)) {
+                        changes.push((Change::Modified, path));
+                    }
+                }
+            }
+        }
+        for (name, _) in stored {
+            changes.push((Change::Deleted, rel.join(name)));
+        }
+    }
+
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(changes)
+}
+
+fn unchanged(entry: &key::Entry, content: &Content, meta: &fs::Metadata, path: &Path) -> bool {
+    match *content {
+        Content::Dir(_) => false,
+        Content::Link(ref target) => {
+            meta.file_type().is_symlink() &&
+                fs::read_link(path).ok().as_ref() == Some(target)
+        }
+        Content::Data(_) => {
+            let mtime = FileTime::from_last_modification_time(meta).seconds_relative_to_1970();
+            // Older listings may not have recorded the length.
+            let length_matches = match entry.info.byte_length {
+                Some(n) if n > 0 => n == meta.len(),
+                _ => true,
+            };
+            meta.is_file() && entry.info.modified_ts_secs == Some(mtime) && length_matches
+        }
+    }
+}
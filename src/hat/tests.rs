@@ -13,9 +13,10 @@
 // limitations under the License.
 
 
-use backend::{MemoryBackend, StoreBackend};
+use backend::{AppendOnlyBackend, MemoryBackend, StoreBackend};
+use chrono;
 use errors::HatError;
-use hat::HatRc;
+use hat::{Client, HatRc};
 use hat::family::Family;
 use key;
 use std::collections::HashMap;
@@ -195,7 +196,8 @@ fn snapshot_reuse_index() {
     basic_snapshot(&fam);
     fam.flush().unwrap();
 
-    // No commit, so GC removes all the new hashes.
+    // No commit, so once the backup is abandoned, GC removes all the new hashes.
+    fam.key_store.release_epoch_pin();
     let (deleted, live) = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
@@ -219,6 +221,7 @@ fn snapshot_reuse_index() {
     assert_eq!(deleted2, 0);
 
     // Cleanup: only 1 snapshot was committed.
+    fam.key_store.release_epoch_pin();
     hat.deregister(&fam, 1).unwrap();
     let (deleted, live) = hat.gc().unwrap();
     assert!(deleted > 0);
@@ -232,7 +235,68 @@ fn snapshot_gc() {
     basic_snapshot(&fam);
     fam.flush().unwrap();
 
-    // No commit so everything is deleted.
+    // Not committed, but the backup may still be running.
+    let (deleted, live) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert!(live > 0);
+
+    // Abandoned without a commit, so everything is deleted.
+    fam.key_store.release_epoch_pin();
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+}
+
+#[test]
+fn gc_keeps_the_data_of_backups_in_progress() {
+    use hat::Subset;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", "reused".into()), ("b", vec![7; 300000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    hat.deregister(&fam, 1).unwrap();
+
+    // Another backup reuses the data that nothing refers to anymore and adds new data, while
+    // the GC runs.
+    let mut other = hat.open_family("other".to_owned()).unwrap();
+    snapshot_files(
+        &other,
+        vec![("a", "reused".into()), ("b", vec![7; 300000]), ("c", "new".into())],
+    ).unwrap();
+    other.flush().unwrap();
+    hat.gc().unwrap();
+
+    hat.commit(&mut other, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert!(hat.check(Subset::all()).unwrap().errors.is_empty());
+
+    let out = hat.export_tar("other".to_owned(), None, Path::new("/"), Vec::<u8>::new())
+        .unwrap();
+    let mut files = vec![];
+    for file in tar::Archive::new(&out[..]).entries().unwrap() {
+        let mut file = file.unwrap();
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).unwrap();
+        files.push((file.path().unwrap().into_owned(), contents));
+    }
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            (PathBuf::from("a"), "reused".into()),
+            (PathBuf::from("b"), vec![7; 300000]),
+            (PathBuf::from("c"), "new".into()),
+        ]
+    );
+
+    // Once committed, the backup no longer holds anything back.
+    hat.delete_all_snapshots().unwrap();
     let (deleted, live) = hat.gc().unwrap();
     assert!(deleted > 0);
     assert_eq!(live, 0);
@@ -277,3 +341,2220 @@ fn recover() {
     assert!(deleted > 0);
     assert_eq!(live4, 0);
 }
+
+#[test]
+fn check_trees_finds_missing_blobs() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![("dir/big", vec![1; 5 * 1024 * 1024]), ("small", "small".into())],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let report = hat.check_trees().unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.snapshots, 2);
+    assert_eq!(report.directories, 2);
+    assert!(report.blobs >= 2);
+
+    // Keep only the blob holding the top directory, so that the listings can still be read.
+    let root = hat.snapshot_root(&fam.name, None).unwrap();
+    for name in backend.list().unwrap() {
+        if &name[..] != &root.persistent_ref.blob_name[..] {
+            backend.delete(&name).unwrap();
+        }
+    }
+    let report = hat.check_trees().unwrap();
+    assert!(!report.errors.is_empty());
+    for error in &report.errors {
+        assert!(error.contains("missing from the backend"), "{}", error);
+    }
+}
+
+#[test]
+fn equal_directories_share_their_listings() {
+    let (_, mut hat, mut first) = setup_family();
+    let files = vec![
+        ("dir/a", "a".into()),
+        ("dir/sub/b", vec![2; 300000]),
+        ("dir/c", "c".into()),
+        ("top", "top".into()),
+    ];
+    snapshot_files(&first, files.clone()).unwrap();
+    first.flush().unwrap();
+    hat.commit(&mut first, None).unwrap();
+
+    // Another family has its own node ids, and here inserts the same files in another order.
+    let mut second = hat.open_family("second".to_owned()).unwrap();
+    snapshot_files(&second, files.into_iter().rev().collect()).unwrap();
+    second.flush().unwrap();
+    hat.commit(&mut second, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let first_root = hat.snapshot_root(&first.name, None).unwrap();
+    let second_root = hat.snapshot_root("second", None).unwrap();
+    assert_eq!(first_root.hash, second_root.hash);
+    assert_eq!(first_root.persistent_ref.blob_name, second_root.persistent_ref.blob_name);
+    assert_eq!(first_root.persistent_ref.offset, second_root.persistent_ref.offset);
+}
+
+#[test]
+fn offline_reads_snapshots_from_blobs() {
+    use crypto::keys::Keeper;
+    use hat::{MetadataPolicy, OfflineRepository};
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("top", vec![1; 300000]), ("dir/a", "first".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    snapshot_files(&fam, vec![("dir/a", "second".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // No local index is involved from here on.
+    let offline = OfflineRepository::open(backend, Arc::new(Keeper::new_for_testing())).unwrap();
+    let ids: Vec<_> = offline
+        .snapshots()
+        .iter()
+        .map(|s| (s.family_name.clone(), s.snapshot_id))
+        .collect();
+    assert_eq!(ids, vec![(fam.name.clone(), 1), (fam.name.clone(), 2)]);
+
+    let names: Vec<_> = offline
+        .list_dir(&fam.name, None, Path::new("dir"))
+        .unwrap()
+        .into_iter()
+        .map(|l| l.entry.info.name)
+        .collect();
+    assert_eq!(names, vec![b"a".to_vec()]);
+
+    let read = |path: &Path| {
+        let mut contents = vec![];
+        fs::File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    };
+    let dir = ::std::env::temp_dir().join(format!("hat-offline-{}", ::time::precise_time_ns()));
+    for &(id, contents) in &[(Some(1), &b"first"[..]), (None, &b"second"[..])] {
+        offline
+            .checkout(&fam.name, id, Path::new(""), &dir, &MetadataPolicy::none())
+            .unwrap();
+        assert_eq!(read(&dir.join("dir/a")), contents.to_vec());
+        assert_eq!(read(&dir.join("top")), vec![1; 300000]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// Remembers the order blobs were stored in, as times one second apart, and counts reads.
+struct StampedBackend {
+    inner: MemoryBackend,
+    stored: ::std::sync::Mutex<HashMap<Vec<u8>, u64>>,
+    reads: ::std::sync::atomic::AtomicUsize,
+}
+
+impl StoreBackend for StampedBackend {
+    fn store(&self, name: &[u8], data: &::crypto::CipherText) -> Result<(), String> {
+        let mut stored = self.stored.lock().unwrap();
+        let stamp = stored.len() as u64;
+        stored.insert(name.to_vec(), stamp);
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+        self.reads.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+
+    fn stat(&self, name: &[u8]) -> Result<Option<::backend::BlobStat>, String> {
+        use std::time::{Duration, UNIX_EPOCH};
+        Ok(self.stored.lock().unwrap().get(name).map(|&stamp| {
+            ::backend::BlobStat {
+                size: 0,
+                stored_at: Some(UNIX_EPOCH + Duration::from_secs(stamp)),
+            }
+        }))
+    }
+}
+
+#[test]
+fn offline_reads_the_newest_blobs_first() {
+    use crypto::keys::Keeper;
+    use hat::OfflineRepository;
+    use std::sync::atomic::Ordering;
+
+    let backend = Arc::new(StampedBackend {
+        inner: MemoryBackend::new(),
+        stored: ::std::sync::Mutex::new(HashMap::new()),
+        reads: ::std::sync::atomic::AtomicUsize::new(0),
+    });
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    for i in 0..3u8 {
+        snapshot_files(&fam, vec![(&format!("file{}", i)[..], vec![i; 300000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+    }
+
+    backend.reads.store(0, Ordering::SeqCst);
+    let offline = OfflineRepository::open(backend.clone(), Arc::new(Keeper::new_for_testing()))
+        .unwrap();
+    let ids: Vec<_> = offline.snapshots().iter().map(|s| s.snapshot_id).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+    // The blobs of the first snapshots are older than the latest snapshot list.
+    let blobs = backend.list().unwrap().len();
+    assert!(backend.reads.load(Ordering::SeqCst) < blobs);
+}
+
+#[test]
+fn trash_grace_period_across_month_end() {
+    use chrono::{Duration, TimeZone, Utc};
+    use hat::FixedClock;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let clock = Arc::new(FixedClock::new(Utc.ymd(2017, 1, 31).and_hms(12, 0, 0)));
+    hat.set_clock(clock.clone());
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(Utc.ymd(2017, 1, 31).and_hms(12, 0, 0), hat.list_snapshots()[0].created);
+
+    hat.trash_by_name(fam.name.clone(), 1).unwrap();
+    // Thirty days after January 31 is March 2, not the end of February.
+    clock.set(Utc.ymd(2017, 2, 28).and_hms(12, 0, 0));
+    assert_eq!(0, hat.purge_trash(Duration::days(30)).unwrap());
+    clock.set(Utc.ymd(2017, 3, 2).and_hms(12, 0, 0));
+    assert_eq!(1, hat.purge_trash(Duration::days(30)).unwrap());
+}
+
+#[test]
+fn trash_and_undelete() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let (_, live1) = hat.gc().unwrap();
+    assert!(live1 > 0);
+
+    // Only snapshots in the trash can be restored from it.
+    assert!(hat.undelete_by_name(fam.name.clone(), 1).is_err());
+    assert!(hat.undelete_by_name(fam.name.clone(), 2).is_err());
+
+    // Trashed snapshots keep their data alive.
+    hat.trash_by_name(fam.name.clone(), 1).unwrap();
+    assert_eq!(hat.purge_trash(chrono::Duration::days(7)).unwrap(), 0);
+    let (deleted, live2) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert_eq!(live1, live2);
+
+    // Until the grace period has passed.
+    hat.undelete_by_name(fam.name.clone(), 1).unwrap();
+    assert_eq!(hat.purge_trash(chrono::Duration::zero()).unwrap(), 0);
+
+    hat.trash_by_name(fam.name.clone(), 1).unwrap();
+    assert_eq!(hat.purge_trash(chrono::Duration::zero()).unwrap(), 1);
+    let (deleted, live3) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live3, 0);
+}
+
+#[test]
+fn snapshots_record_io_stats() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let snapshots = hat.list_snapshots();
+    assert_eq!(
+        snapshots.iter().map(|s| s.snapshot_id).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    let first = snapshots[0].stats.unwrap();
+    let second = snapshots[1].stats.unwrap();
+
+    assert!(first.bytes_read >= 4000000);
+    assert!(first.new_chunks > 0);
+    // The second file of zeros is already stored.
+    assert!(first.reused_chunks > 0);
+    assert!(first.bytes_uploaded < first.bytes_read);
+    assert_eq!(first.new_files, 16);
+    assert_eq!(first.unchanged_files, 0);
+
+    // Nothing changed, so hardly anything new is stored.
+    assert!(second.bytes_uploaded < first.bytes_uploaded);
+}
+
+#[test]
+fn unchanged_dirs_reuse_their_hash() {
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("dir1/a", "abc".into()),
+            ("dir1/sub/b", "b".into()),
+            ("dir2/c", "c".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let node_id = |fam: &Family<MemoryBackend>, name: &str| {
+        fam.list_from_key_store(None)
+            .unwrap()
+            .into_iter()
+            .find(|&(ref entry, _, _)| &entry.info.name[..] == name.as_bytes())
+            .and_then(|(entry, _, _)| entry.node_id)
+            .unwrap()
+    };
+    let dir1 = node_id(&fam, "dir1");
+    let dir2 = node_id(&fam, "dir2");
+
+    let (_, hashes) = fam.key_store.unchanged_dir_hash(dir1).unwrap().unwrap();
+    // The hashes of "a", "sub" and "sub/b".
+    assert_eq!(hashes.len(), 3);
+
+    // A new file only invalidates the directories above it.
+    snapshot_files(&fam, vec![("dir2/d", "d".into())]).unwrap();
+    assert!(fam.key_store.unchanged_dir_hash(dir1).unwrap().is_some());
+    assert!(fam.key_store.unchanged_dir_hash(dir2).unwrap().is_none());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    assert!(fam.key_store.unchanged_dir_hash(dir2).unwrap().is_some());
+
+    // The reused subtree is kept alive by the second snapshot alone.
+    hat.deregister_by_name(fam.name.clone(), 1).unwrap();
+    hat.gc().unwrap();
+
+    let out = hat.export_tar(fam.name.clone(), None, Path::new("/"), Vec::<u8>::new())
+        .unwrap();
+    let mut files = vec![];
+    for file in tar::Archive::new(&out[..]).entries().unwrap() {
+        let mut file = file.unwrap();
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).unwrap();
+        files.push((file.path().unwrap().into_owned(), contents));
+    }
+    files.sort();
+
+    assert_eq!(
+        files,
+        vec![
+            (PathBuf::from("dir1"), vec![]),
+            (PathBuf::from("dir1/a"), "abc".into()),
+            (PathBuf::from("dir1/sub"), vec![]),
+            (PathBuf::from("dir1/sub/b"), "b".into()),
+            (PathBuf::from("dir2"), vec![]),
+            (PathBuf::from("dir2/c"), "c".into()),
+            (PathBuf::from("dir2/d"), "d".into()),
+        ]
+    );
+}
+
+#[test]
+fn snapshot_paths_longer_than_path_max() {
+    use hat::SourceFilter;
+    use libc;
+    use std::ffi::{CString, OsStr};
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use util::dirfd::{DirFd, PATH_MAX};
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-long-paths-{}", nanos));
+    fs::create_dir(&dir).unwrap();
+    fs::File::create(dir.join("shallow")).unwrap().write_all(b"shallow").unwrap();
+
+    // A chain of directories deep enough that the file at the bottom is more than twice
+    // PATH_MAX from the root, created without ever resolving a long path.
+    let name = "d".repeat(100);
+    let c_name = CString::new(name.clone()).unwrap();
+    let depth = 2 * PATH_MAX / (name.len() + 1) + 1;
+    let mut current = DirFd::open(&dir).unwrap();
+    for _ in 0..depth {
+        assert_eq!(unsafe { libc::mkdirat(current.as_raw_fd(), c_name.as_ptr(), 0o755) }, 0);
+        current = current.open_dir(OsStr::new(&name)).unwrap();
+    }
+    let deep = b"deep\0".as_ptr() as *const libc::c_char;
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(current.as_raw_fd(), deep, flags, 0o644) };
+    assert!(fd >= 0);
+    unsafe { fs::File::from_raw_fd(fd) }.write_all(b"deep").unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let stats = hat.list_snapshots()[0].stats.unwrap();
+    assert_eq!((stats.new_files, stats.skipped_files), (2, 0));
+    assert_eq!(stats.bytes_read, "shallow".len() as u64 + "deep".len() as u64);
+
+    // Remove the chain from the bottom up, again by descriptor.
+    assert_eq!(unsafe { libc::unlinkat(current.as_raw_fd(), deep, 0) }, 0);
+    for _ in 0..depth {
+        let parent = current.open_dir(OsStr::new("..")).unwrap();
+        let rmdir = libc::AT_REMOVEDIR;
+        assert_eq!(unsafe { libc::unlinkat(parent.as_raw_fd(), c_name.as_ptr(), rmdir) }, 0);
+        current = parent;
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshots_record_renames() {
+    use filetime::{self, FileTime};
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-renames-{}", nanos));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::File::create(dir.join("moved")).unwrap().write_all(&vec![1; 100000]).unwrap();
+    fs::File::create(dir.join("rewritten")).unwrap().write_all(b"same data").unwrap();
+    fs::File::create(dir.join("copied")).unwrap().write_all(b"copied data").unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let meta = fs::metadata(dir.join("rewritten")).unwrap();
+    let mtime = FileTime::from_last_modification_time(&meta);
+
+    // Moved with its inode, written again under a new name, and copied.
+    fs::rename(dir.join("moved"), dir.join("sub/moved")).unwrap();
+    fs::remove_file(dir.join("rewritten")).unwrap();
+    fs::File::create(dir.join("sub/written")).unwrap().write_all(b"same data").unwrap();
+    // The new file may get the inode of the removed one; only the data should match.
+    let later = FileTime::from_seconds_since_1970(mtime.seconds_relative_to_1970() + 10, 0);
+    filetime::set_file_times(dir.join("sub/written"), later, later).unwrap();
+    fs::copy(dir.join("copied"), dir.join("sub/copy")).unwrap();
+
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    let snapshots = hat.list_snapshots();
+    assert!(snapshots[0].renames.is_empty());
+    assert_eq!(
+        snapshots[1].renames,
+        vec![
+            (root.join("moved"), root.join("sub/moved")),
+            (root.join("rewritten"), root.join("sub/written")),
+        ]
+    );
+    // Only the new files were read; the moved file kept its data.
+    let stats = snapshots[1].stats.unwrap();
+    assert_eq!(stats.bytes_read, "same data".len() as u64 + "copied data".len() as u64);
+    assert_eq!((stats.new_files, stats.unchanged_files), (2, 2));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshots_record_deletions() {
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-deletions-{}", nanos));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    for name in &["kept", "removed", "sub/b", "sub/c"] {
+        fs::File::create(dir.join(name)).unwrap().write_all(name.as_bytes()).unwrap();
+    }
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    fs::remove_file(dir.join("removed")).unwrap();
+    fs::remove_dir_all(dir.join("sub")).unwrap();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    assert!(hat.deleted_paths("familyname", Some(1)).unwrap().is_empty());
+    assert_eq!(
+        hat.deleted_paths("familyname", None).unwrap(),
+        vec![
+            root.join("removed"),
+            root.join("sub"),
+            root.join("sub/b"),
+            root.join("sub/c"),
+        ]
+    );
+
+    let found = hat.path_deletions(None, &Path::new("/").join(root).join("sub/c"));
+    assert_eq!(1, found.len());
+    assert_eq!(("familyname", 2), (&found[0].family_name[..], found[0].snapshot_id));
+    assert!(hat.path_deletions(None, &root.join("kept")).is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshots_record_excluded_dirs() {
+    use hat::SourceFilter;
+    use std::fs;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-exclusions-{}", nanos));
+    fs::create_dir_all(dir.join("cache")).unwrap();
+    fs::File::create(dir.join("cache/.nobackup")).unwrap();
+    fs::File::create(dir.join("kept")).unwrap();
+
+    let filter = SourceFilter {
+        exclude_markers: vec![".nobackup".to_owned()],
+        ..Default::default()
+    };
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &filter).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // The next snapshot starts with no exclusions of its own.
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    assert_eq!(
+        hat.excluded_dirs("familyname", Some(1)).unwrap(),
+        vec![(root.join("cache"), ".nobackup".to_owned())]
+    );
+    assert!(hat.excluded_dirs("familyname", None).unwrap().is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn path_history_lists_versions() {
+    use filetime::{self, FileTime};
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-history-{}", nanos));
+    fs::create_dir_all(&dir).unwrap();
+    let (_, mut hat, mut fam) = setup_family();
+    {
+        let mut snapshot = |contents: Option<&[u8]>, mtime: u64| {
+            match contents {
+                Some(contents) => {
+                    fs::File::create(dir.join("file")).unwrap().write_all(contents).unwrap();
+                    let time = FileTime::from_seconds_since_1970(mtime, 0);
+                    filetime::set_file_times(dir.join("file"), time, time).unwrap();
+                }
+                None => fs::remove_file(dir.join("file")).unwrap(),
+            }
+            fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+            fam.flush().unwrap();
+            hat.commit(&mut fam, None).unwrap();
+        };
+        snapshot(Some(&b"first"[..]), 1000);
+        snapshot(Some(&b"first"[..]), 1000);
+        snapshot(Some(&b"second version"[..]), 2000);
+        snapshot(None, 0);
+    }
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let versions = hat.path_history("familyname", &root.join("file")).unwrap();
+    let summary: Vec<_> = versions
+        .iter()
+        .map(|v| (v.snapshot_id, v.size, v.modified, v.changed, v.deleted))
+        .collect();
+    assert_eq!(
+        vec![
+            (1, Some(5), Some(1000), true, false),
+            (2, Some(5), Some(1000), false, false),
+            (3, Some(14), Some(2000), true, false),
+            (4, None, None, true, true),
+        ],
+        summary
+    );
+    assert!(versions[0].hash.is_some());
+    assert_eq!(versions[0].hash, versions[1].hash);
+    assert!(versions[1].hash != versions[2].hash);
+    assert!(hat.path_history("nosuchfamily", &root.join("file")).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn errors_have_stable_exit_codes() {
+    use hat::{ErrorKind, ExitCode, SourceFilter};
+
+    let (_, _, fam) = setup_family();
+    let nanos = ::time::precise_time_ns();
+    let missing = ::std::env::temp_dir().join(format!("hat-missing-{}", nanos));
+    let kind = match fam.snapshot_dir(missing, &SourceFilter::default()) {
+        Err(e) => e.kind(),
+        Ok(()) => panic!("Snapshot of a missing directory succeeded"),
+    };
+    assert_eq!(ErrorKind::Source, kind);
+    assert_eq!(3, ExitCode::from(kind).code());
+
+    assert_eq!(ExitCode::Warnings, ExitCode::Success.combine(ExitCode::Warnings));
+    assert_eq!(ExitCode::Source, ExitCode::Warnings.combine(ExitCode::Source));
+    assert_eq!(ExitCode::Locked, ExitCode::Locked.combine(ExitCode::Repository));
+    assert_eq!(ExitCode::Warnings, ExitCode::Warnings.combine(ExitCode::Success));
+}
+
+#[test]
+fn listed_files_update_the_previous_snapshot() {
+    use filetime::{self, FileTime};
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use tar;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-listed-{}", nanos));
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    fs::File::create(dir.join("a/b/c")).unwrap().write_all(b"old").unwrap();
+    fs::File::create(dir.join("a/x")).unwrap().write_all(b"kept").unwrap();
+    fs::File::create(dir.join("d")).unwrap().write_all(b"kept too").unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    fs::File::create(dir.join("a/b/c")).unwrap().write_all(b"changed").unwrap();
+    let later = FileTime::from_seconds_since_1970(nanos / 1_000_000_000 + 10, 0);
+    filetime::set_file_times(dir.join("a/b/c"), later, later).unwrap();
+    fs::File::create(dir.join("e")).unwrap().write_all(b"new").unwrap();
+
+    let listed = vec![
+        PathBuf::from("a/b/c"),
+        dir.join("e"),
+        PathBuf::from("../outside"),
+    ];
+    fam.snapshot_listed(dir.clone(), &listed, &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    let stats = hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    assert_eq!(("changed".len() + "new".len()) as u64, stats.bytes_read);
+    assert_eq!((2, 1), (stats.new_files, stats.skipped_files));
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    let out = hat.export_tar(fam.name.clone(), None, Path::new("/"), Vec::<u8>::new())
+        .unwrap();
+    let mut files = vec![];
+    for file in tar::Archive::new(&out[..]).entries().unwrap() {
+        let mut file = file.unwrap();
+        let path = file.path().unwrap().into_owned();
+        if let Ok(rel) = path.strip_prefix(root) {
+            if file.header().entry_type() == tar::EntryType::Regular {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                files.push((rel.to_owned(), contents));
+            }
+        }
+    }
+    files.sort();
+    let expected: Vec<(PathBuf, String)> =
+        vec![("a/b/c", "changed"), ("a/x", "kept"), ("d", "kept too"), ("e", "new")]
+            .into_iter()
+            .map(|(p, c)| (PathBuf::from(p), c.to_owned()))
+            .collect();
+    assert_eq!(expected, files);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn find_paths_across_snapshots() {
+    use hat::SourceFilter;
+    use std::fs;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-find-{}", nanos));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::File::create(dir.join("kept.txt")).unwrap();
+    fs::File::create(dir.join("sub/removed.txt")).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    fs::remove_file(dir.join("sub/removed.txt")).unwrap();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    let found = hat.find_paths("*.txt", None);
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].path, root.join("kept.txt"));
+    assert_eq!(found[0].snapshot_ids, vec![1, 2]);
+    assert_eq!(found[1].path, root.join("sub/removed.txt"));
+    assert_eq!(found[1].snapshot_ids, vec![1]);
+
+    assert_eq!(hat.find_paths("kept.txt", Some("familyname")).len(), 1);
+    assert!(hat.find_paths("kept.txt", Some("other")).is_empty());
+    assert!(hat.find_paths("kept", None).is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn duplicate_files_by_snapshot() {
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-dupes-{}", nanos));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    for name in &["a", "b", "sub/c"] {
+        fs::File::create(dir.join(name)).unwrap().write_all(b"same data").unwrap();
+    }
+    fs::File::create(dir.join("d")).unwrap().write_all(b"other data").unwrap();
+    fs::File::create(dir.join("empty1")).unwrap();
+    fs::File::create(dir.join("empty2")).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    fs::remove_file(dir.join("b")).unwrap();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    let first = hat.duplicate_files("familyname", Some(1)).unwrap();
+    assert_eq!(first.len(), 1);
+    assert_eq!(
+        first[0].paths,
+        vec![root.join("a"), root.join("b"), root.join("sub/c")]
+    );
+    assert_eq!(first[0].size, 9);
+    assert_eq!(first[0].savings(), 18);
+
+    let latest = hat.duplicate_files("familyname", None).unwrap();
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].paths, vec![root.join("a"), root.join("sub/c")]);
+    assert_eq!(latest[0].savings(), 9);
+
+    assert!(hat.duplicate_files("familyname", Some(3)).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn grep_indexed_text() {
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-grep-{}", nanos));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("notes")).unwrap().write_all(b"Remember the Milk").unwrap();
+    fs::File::create(dir.join("binary")).unwrap().write_all(b"milk\0milk").unwrap();
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_text_index(Some(1024));
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let found = hat.grep_snapshots("MILK", None);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].path, root.strip_prefix("/").unwrap().join("notes"));
+    assert_eq!(found[0].snapshot_ids, vec![1]);
+    assert!(hat.grep_snapshots("forget", None).is_empty());
+
+    assert!(hat.purge_text_index() > 0);
+    assert!(hat.grep_snapshots("milk", None).is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dump_indexes_as_csv() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut hashes = vec![];
+    hat.dump_hash_index(&mut hashes).unwrap();
+    let hashes = String::from_utf8(hashes).unwrap();
+    let mut lines = hashes.lines();
+    assert_eq!(
+        lines.next(),
+        Some("id,hash,height,leaf_type,tag,blob_id,length,children,parents")
+    );
+    assert!(lines.count() > 0);
+
+    let mut entries = vec![];
+    hat.dump_key_index(&mut entries).unwrap();
+    let entries = String::from_utf8(entries).unwrap();
+    assert!(entries.starts_with("family,path,size,hash\n"));
+    assert!(entries.lines().any(|l| l.starts_with("familyname,") && l.contains("zeros2")));
+}
+
+#[test]
+fn pinned_snapshots_are_kept() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    hat.pin_by_name(fam.name.clone(), 1).unwrap();
+    assert!(hat.trash_by_name(fam.name.clone(), 1).is_err());
+    assert!(hat.deregister_by_name(fam.name.clone(), 1).is_err());
+    assert!(hat.pin_by_name(fam.name.clone(), 2).is_err());
+
+    // Pinning a snapshot in the trash keeps it from being purged.
+    hat.unpin_by_name(fam.name.clone(), 1).unwrap();
+    hat.trash_by_name(fam.name.clone(), 1).unwrap();
+    hat.pin_by_name(fam.name.clone(), 1).unwrap();
+    assert_eq!(hat.purge_trash(chrono::Duration::zero()).unwrap(), 0);
+    let plan = hat.gc_plan(chrono::Duration::zero()).unwrap();
+    assert!(plan.purged_snapshots.is_empty());
+
+    hat.unpin_by_name(fam.name.clone(), 1).unwrap();
+    assert_eq!(hat.purge_trash(chrono::Duration::zero()).unwrap(), 1);
+    let (_, live) = hat.gc().unwrap();
+    assert_eq!(live, 0);
+}
+
+#[test]
+fn clone_and_rename_snapshot() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    let (_, live1) = hat.gc().unwrap();
+
+    let clone_id = hat.clone_snapshot(fam.name.clone(), 1, "golden".to_owned())
+        .unwrap();
+    assert_eq!(clone_id, 1);
+
+    // The clone keeps the data alive after the original is gone.
+    hat.deregister(&fam, 1).unwrap();
+    let (deleted, live2) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert_eq!(live1, live2);
+
+    let renamed_id = hat.rename_snapshot("golden".to_owned(), 1, "pinned".to_owned())
+        .unwrap();
+    assert_eq!(renamed_id, 1);
+    assert!(hat.deregister_by_name("golden".to_owned(), 1).is_err());
+
+    hat.deregister_by_name("pinned".to_owned(), 1).unwrap();
+    let (deleted, live3) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live3, 0);
+}
+
+#[test]
+fn checkout_orders_restore_the_same_files() {
+    use hat::{MetadataPolicy, RestoreOrder};
+    use std::fs;
+    use std::io::Read;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let mixed: Vec<u8> = (0..3000000u32).map(|i| (i * 7 / 1000) as u8).collect();
+    let files = vec![
+        ("mixed", mixed.clone()),
+        ("zeros", vec![0; 2000000]),
+        ("dir/mixed_again", mixed),
+        ("dir/small", "small".into()),
+    ];
+    snapshot_files(&fam, files.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let nanos = ::time::precise_time_ns();
+    for order in vec![RestoreOrder::Tree, RestoreOrder::Blob] {
+        let dir = ::std::env::temp_dir().join(format!("hat-checkout-{:?}-{}", order, nanos));
+        hat.checkout_in_dir(fam.name.clone(), dir.clone(), &MetadataPolicy::none(), order)
+            .unwrap();
+        for &(name, ref contents) in &files {
+            let mut restored = vec![];
+            fs::File::open(dir.join(name)).unwrap().read_to_end(&mut restored).unwrap();
+            assert!(&restored == contents, "{} differs in {:?} order", name, order);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn checkout_fails_on_missing_chunks() {
+    use hat::{MetadataPolicy, RestoreOrder};
+    use std::fs;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("big", vec![1; 5 * 1024 * 1024]), ("small", "small".into())])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Keep only the blob holding the top directory, so that the listing can still be read.
+    let root = hat.snapshot_root(&fam.name, None).unwrap();
+    for name in backend.list().unwrap() {
+        if &name[..] != &root.persistent_ref.blob_name[..] {
+            backend.delete(&name).unwrap();
+        }
+    }
+
+    // A missing chunk used to end the file early, or panic the checkout.
+    let nanos = ::time::precise_time_ns();
+    for order in vec![RestoreOrder::Tree, RestoreOrder::Blob] {
+        let dir = ::std::env::temp_dir().join(format!("hat-missing-{:?}-{}", order, nanos));
+        let policy = MetadataPolicy::none();
+        let res = hat.checkout_in_dir(fam.name.clone(), dir.clone(), &policy, order);
+        assert!(res.is_err(), "{:?} order restored a file without its data", order);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn checkout_reports_progress() {
+    use hat::{CollisionPolicy, MetadataPolicy, PathSelection, RestoreOrder, RestoreProgress};
+    use std::fs;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let files = vec![
+        ("top", vec![1; 300000]),
+        ("dir/a.txt", vec![2; 2000000]),
+        ("dir/b.bin", vec![3; 1000000]),
+    ];
+    snapshot_files(&fam, files).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let selection = PathSelection::from_manifest(b"top\n**/*.txt\n", false);
+    let nanos = ::time::precise_time_ns();
+    for order in vec![RestoreOrder::Tree, RestoreOrder::Blob] {
+        let progress = RestoreProgress::new();
+        hat.set_restore_progress(Some(progress.clone()));
+        let dir = ::std::env::temp_dir().join(format!("hat-progress-{:?}-{}", order, nanos));
+        hat.checkout_selected_in_dir(
+            fam.name.clone(),
+            dir.clone(),
+            &MetadataPolicy::none(),
+            order,
+            Some(&selection),
+            CollisionPolicy::Refuse,
+        ).unwrap();
+        let status = progress.status();
+        assert_eq!((2300000, 2300000), (status.done, status.total), "in {:?} order", order);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn checkout_only_selected_paths() {
+    use hat::{CollisionPolicy, MetadataPolicy, PathSelection, RestoreOrder};
+    use std::fs;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let files = vec![
+        ("top", "top".into()),
+        ("dir/a.txt", "a".into()),
+        ("dir/b.bin", "b".into()),
+        ("other/c.txt", "c".into()),
+    ];
+    snapshot_files(&fam, files).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let selection = PathSelection::from_manifest(b"top\n**/*.txt\n", false);
+    let dir = ::std::env::temp_dir().join(format!("hat-selected-{}", ::time::precise_time_ns()));
+    hat.checkout_selected_in_dir(
+        fam.name.clone(),
+        dir.clone(),
+        &MetadataPolicy::none(),
+        RestoreOrder::Tree,
+        Some(&selection),
+        CollisionPolicy::Refuse,
+    ).unwrap();
+    assert!(dir.join("top").exists());
+    assert!(dir.join("dir/a.txt").exists());
+    assert!(!dir.join("dir/b.bin").exists());
+    assert!(dir.join("other/c.txt").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn content_types_are_detected_and_selected() {
+    use hat::{CollisionPolicy, MetadataPolicy, PathSelection, RestoreOrder};
+    use std::fs;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_content_types(true);
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    let files = vec![
+        ("notes", "Remember the milk".into()),
+        ("dir/image.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec()),
+        ("dir/blob", vec![0, 1, 2, 0xff]),
+    ];
+    snapshot_files(&fam, files).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let usage = hat.content_type_usage().unwrap();
+    let files_of = |t: Option<&str>| {
+        usage.iter().find(|u| u.content_type.as_ref().map(|t| &t[..]) == t).map(|u| u.files)
+    };
+    assert_eq!(files_of(Some("image/png")), Some(1));
+    assert_eq!(files_of(Some("text/plain")), Some(1));
+    assert_eq!(files_of(None), Some(1));
+
+    let mut selection = PathSelection::all();
+    selection.add_type("image/*");
+    let dir = ::std::env::temp_dir().join(format!("hat-types-{}", ::time::precise_time_ns()));
+    hat.checkout_selected_in_dir(
+        fam.name.clone(),
+        dir.clone(),
+        &MetadataPolicy::none(),
+        RestoreOrder::Tree,
+        Some(&selection),
+        CollisionPolicy::Refuse,
+    ).unwrap();
+    assert!(dir.join("dir/image.png").exists());
+    assert!(!dir.join("dir/blob").exists());
+    assert!(!dir.join("notes").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn list_dir_flags_dangling_entries() {
+    use std::path::Path;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", "data".into()), ("dir/b", "more data".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let listing = hat.list_dir(fam.name.clone(), None, Path::new(""), true).unwrap();
+    let names: Vec<&[u8]> = listing.iter().map(|l| &l.entry.info.name[..]).collect();
+    assert_eq!(names, vec![&b"a"[..], &b"dir"[..]]);
+    assert!(listing[1].is_dir);
+    assert!(listing.iter().all(|l| l.dangling.is_none()));
+
+    let listing = hat.list_dir(fam.name.clone(), None, Path::new("dir"), true).unwrap();
+    assert_eq!(listing.len(), 1);
+    assert!(hat.list_dir(fam.name.clone(), None, Path::new("a"), false).is_err());
+
+    // Forget the data of "a" in the hash index.
+    let root = hat.snapshot_root(&fam.name, None).unwrap();
+    for (entry, content) in fam.fetch_dir_data(root, hat.hash_backend()).unwrap() {
+        match content {
+            hat::walker::Content::Data(ref r) if &entry.info.name[..] == b"a" => {
+                let id = hat.hash_index.get_id(&r.hash).unwrap();
+                hat.hash_index.delete(id);
+            }
+            _ => (),
+        }
+    }
+    let listing = hat.list_dir(fam.name.clone(), None, Path::new(""), true).unwrap();
+    assert!(listing[0].dangling.is_some());
+    assert!(listing[1].dangling.is_none());
+    let unchecked = hat.list_dir(fam.name.clone(), None, Path::new(""), false).unwrap();
+    assert!(unchecked[0].dangling.is_none());
+}
+
+#[test]
+fn checkout_over_existing_files() {
+    use hat::{CollisionPolicy, MetadataPolicy, RestoreOrder};
+    use std::fs;
+    use std::io::{Read, Write};
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", "snapshot".into()), ("dir/b", "snapshot".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let dir = ::std::env::temp_dir().join(format!("hat-collide-{}", ::time::precise_time_ns()));
+    fs::create_dir_all(dir.join("dir")).unwrap();
+    fs::File::create(dir.join("dir/b")).unwrap().write_all(b"local").unwrap();
+    let read = |name: &str| {
+        let mut contents = String::new();
+        fs::File::open(dir.join(name)).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    };
+
+    let mut checkout = |collisions| {
+        hat.checkout_selected_in_dir(
+            fam.name.clone(),
+            dir.clone(),
+            &MetadataPolicy::none(),
+            RestoreOrder::Tree,
+            None,
+            collisions,
+        )
+    };
+    assert!(checkout(CollisionPolicy::Refuse).is_err());
+    assert_eq!("local", read("dir/b"));
+
+    checkout(CollisionPolicy::SkipExisting).unwrap();
+    assert_eq!("snapshot", read("a"));
+    assert_eq!("local", read("dir/b"));
+
+    checkout(CollisionPolicy::BackupExisting).unwrap();
+    assert_eq!("snapshot", read("dir/b"));
+    assert_eq!("local", read("dir/b.hat-backup"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn metadata_policy() {
+    use hat::MetadataPolicy;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let nanos = ::time::precise_time_ns();
+    let path = ::std::env::temp_dir().join(format!("hat-metadata-policy-{}", nanos));
+    fs::File::create(&path).unwrap();
+
+    let mut info = key::Info::new(b"file".to_vec(), None);
+    info.permissions = Some(fs::Permissions::from_mode(0o100600));
+    info.modified_ts_secs = Some(1000000);
+    info.accessed_ts_secs = Some(1000000);
+
+    let before = fs::metadata(&path).unwrap().permissions().mode();
+    MetadataPolicy::none().apply(&path, &info, false).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode(), before);
+
+    let mut policy = MetadataPolicy::detect();
+    policy.owner = false;
+    policy.apply(&path, &info, false).unwrap();
+    let meta = fs::metadata(&path).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    assert_eq!(
+        ::filetime::FileTime::from_last_modification_time(&meta).seconds_relative_to_1970(),
+        1000000
+    );
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn source_filter() {
+    use hat::SourceFilter;
+    use hat::file_attributes;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    let nanos = ::time::precise_time_ns();
+    let path = ::std::env::temp_dir().join(format!("hat-source-filter-{}", nanos));
+    fs::File::create(&path).unwrap().write_all(&[0; 10]).unwrap();
+    let meta = fs::metadata(&path).unwrap();
+
+    assert!(SourceFilter::default().includes(&meta));
+    assert_eq!(SourceFilter::default().describe(), None);
+
+    let too_small = SourceFilter { min_file_size: Some(11), ..Default::default() };
+    assert!(!too_small.includes(&meta));
+    let too_large = SourceFilter { max_file_size: Some(9), ..Default::default() };
+    assert!(!too_large.includes(&meta));
+    let fits = SourceFilter {
+        min_file_size: Some(10),
+        max_file_size: Some(10),
+        ..Default::default()
+    };
+    assert!(fits.includes(&meta));
+
+    let future = ::filetime::FileTime::from_last_modification_time(&meta)
+        .seconds_relative_to_1970() + 3600;
+    let old = SourceFilter { changed_since: Some(future), ..Default::default() };
+    assert!(!old.includes(&meta));
+    assert_eq!(old.describe(), Some(format!("changed-since={}", future)));
+
+    let one_fs = SourceFilter { one_file_system: true, ..Default::default() };
+    assert!(one_fs.includes(&meta));
+    assert_eq!(one_fs.describe(), Some("one-file-system".to_owned()));
+
+    // Directories are never filtered.
+    assert!(too_small.includes(&fs::metadata(::std::env::temp_dir()).unwrap()));
+
+    let nodump = SourceFilter { exclude_nodump: true, ..Default::default() };
+    assert!(nodump.excluded_by_flags(Some(file_attributes::FLAG_NODUMP)));
+    assert!(!nodump.excluded_by_flags(Some(file_attributes::FLAG_IMMUTABLE)));
+    assert!(!nodump.excluded_by_flags(None));
+    assert!(!SourceFilter::default().excluded_by_flags(Some(file_attributes::FLAG_NODUMP)));
+    assert_eq!(nodump.describe(), Some("exclude-nodump".to_owned()));
+
+    let images = SourceFilter {
+        fixed_block_suffixes: vec![".img".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(
+        images.chunking(Path::new("/vm/disk.img")),
+        key::Chunking::FixedBlocks(key::FIXED_BLOCK_LEN)
+    );
+    assert_eq!(images.chunking(Path::new("/vm/disk.img.txt")), key::Chunking::Default);
+    assert_eq!(images.describe(), Some("fixed-blocks=.img".to_owned()));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn source_filter_markers() {
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-source-markers-{}", nanos));
+    fs::create_dir(&dir).unwrap();
+
+    let filter = SourceFilter {
+        exclude_caches: true,
+        exclude_markers: vec![".nobackup".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(filter.excluded_by_marker(&dir), None);
+
+    // A CACHEDIR.TAG without the signature does not count.
+    fs::File::create(dir.join("CACHEDIR.TAG")).unwrap().write_all(b"junk").unwrap();
+    assert_eq!(filter.excluded_by_marker(&dir), None);
+
+    fs::File::create(dir.join("CACHEDIR.TAG"))
+        .unwrap()
+        .write_all(b"Signature: 8a477f597d28d172789f06886806bc55\n")
+        .unwrap();
+    assert_eq!(filter.excluded_by_marker(&dir), Some("CACHEDIR.TAG".to_owned()));
+    assert_eq!(SourceFilter::default().excluded_by_marker(&dir), None);
+
+    fs::remove_file(dir.join("CACHEDIR.TAG")).unwrap();
+    fs::File::create(dir.join(".nobackup")).unwrap();
+    assert_eq!(filter.excluded_by_marker(&dir), Some(".nobackup".to_owned()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn export_subtree_as_tar() {
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("dir1/a", "abc".into()),
+            ("dir1/sub/b", vec![7; 300000]),
+            ("dir2/c", "c".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let out = hat.export_tar(fam.name.clone(), None, Path::new("/dir1"), Vec::<u8>::new())
+        .unwrap();
+    let mut files = vec![];
+    for file in tar::Archive::new(&out[..]).entries().unwrap() {
+        let mut file = file.unwrap();
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).unwrap();
+        files.push((file.path().unwrap().into_owned(), contents));
+    }
+    files.sort();
+
+    assert_eq!(
+        files,
+        vec![
+            (PathBuf::from("dir1"), vec![]),
+            (PathBuf::from("dir1/a"), "abc".into()),
+            (PathBuf::from("dir1/sub"), vec![]),
+            (PathBuf::from("dir1/sub/b"), vec![7; 300000]),
+        ]
+    );
+
+    let missing = hat.export_tar(fam.name.clone(), None, Path::new("dir1/nope"), Vec::<u8>::new());
+    assert!(missing.is_err());
+    let no_snapshot =
+        hat.export_tar(fam.name.clone(), Some(2), Path::new("dir1"), Vec::<u8>::new());
+    assert!(no_snapshot.is_err());
+}
+
+#[test]
+fn walk_snapshot_entries() {
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("dir1/a", "abc".into()),
+            ("dir1/sub/b", vec![7; 300000]),
+            ("c", "c".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let mut seen: Vec<PathBuf> = vec![];
+    let mut files = vec![];
+    for entry in hat.walk(fam.name.clone(), None).unwrap() {
+        let entry = entry.unwrap();
+        // Directories come before their contents.
+        if let Some(parent) = entry.path.parent() {
+            assert!(parent == PathBuf::from("") || seen.iter().any(|p| p == parent));
+        }
+        seen.push(entry.path.clone());
+
+        let mut contents = vec![];
+        match entry.open().unwrap() {
+            Some(mut reader) => {
+                reader.read_to_end(&mut contents).unwrap();
+            }
+            None => assert!(entry.is_dir()),
+        }
+        files.push((entry.path, contents));
+    }
+    files.sort();
+
+    assert_eq!(
+        files,
+        vec![
+            (PathBuf::from("c"), "c".into()),
+            (PathBuf::from("dir1"), vec![]),
+            (PathBuf::from("dir1/a"), "abc".into()),
+            (PathBuf::from("dir1/sub"), vec![]),
+            (PathBuf::from("dir1/sub/b"), vec![7; 300000]),
+        ]
+    );
+    assert!(hat.walk(fam.name.clone(), Some(2)).is_err());
+}
+
+#[test]
+fn status_against_live_files() {
+    use filetime::{self, FileTime};
+    use hat::{Change, SourceFilter};
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-status-{}", nanos));
+    fs::create_dir_all(dir.join("same")).unwrap();
+    fs::File::create(dir.join("same/x")).unwrap().write_all(b"x").unwrap();
+    fs::File::create(dir.join("changed")).unwrap().write_all(b"old").unwrap();
+    fs::File::create(dir.join("gone")).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone(), &SourceFilter::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    assert!(hat.status(fam.name.clone(), None, &dir).unwrap().is_empty());
+
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(dir.join("changed")).unwrap());
+    let later = FileTime::from_seconds_since_1970(mtime.seconds_relative_to_1970() + 10, 0);
+    fs::File::create(dir.join("changed")).unwrap().write_all(b"new!").unwrap();
+    filetime::set_file_times(dir.join("changed"), later, later).unwrap();
+    fs::remove_file(dir.join("gone")).unwrap();
+    fs::create_dir_all(dir.join("new_dir/sub")).unwrap();
+    fs::File::create(dir.join("same/y")).unwrap();
+
+    assert_eq!(
+        hat.status(fam.name.clone(), None, &dir).unwrap(),
+        vec![
+            (Change::Modified, PathBuf::from("changed")),
+            (Change::Deleted, PathBuf::from("gone")),
+            (Change::New, PathBuf::from("new_dir")),
+            (Change::New, PathBuf::from("same/y")),
+        ]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_to_mirror() {
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dir = ::std::env::temp_dir().join(format!("hat-mirror-{}", nanos));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::File::create(dir.join("a")).unwrap().write_all(b"abc").unwrap();
+    fs::File::create(dir.join("sub/b")).unwrap().write_all(&vec![7; 20000000]).unwrap();
+
+    let (_, mut hat1, mut fam1) = setup_family();
+    let (_, mut hat2, mut fam2) = setup_family();
+    let results = fam1.snapshot_dir_mirrored(&[&fam2], dir.clone(), &SourceFilter::default());
+    assert_eq!(2, results.len());
+    for res in results {
+        res.unwrap();
+    }
+    fam1.flush().unwrap();
+    hat1.commit(&mut fam1, None).unwrap();
+    fam2.flush().unwrap();
+    hat2.commit(&mut fam2, None).unwrap();
+
+    // Both repositories hold the same files.
+    let root = fs::canonicalize(&dir).unwrap();
+    let path = root.strip_prefix("/").unwrap();
+    let tar1 = hat1.export_tar(fam1.name.clone(), None, path, vec![]).unwrap();
+    let tar2 = hat2.export_tar(fam2.name.clone(), None, path, vec![]).unwrap();
+    assert!(tar1 == tar2);
+    assert!(tar1.len() > 20000000);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn gc_dry_run() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    snapshot_files(&fam, vec![("unique", vec![3; 100000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Nothing to collect while both snapshots are alive.
+    let plan = hat.gc_plan(chrono::Duration::zero()).unwrap();
+    assert_eq!(plan.dead_chunks, 0);
+    assert_eq!(plan.dead_blobs, 0);
+    assert!(plan.purged_snapshots.is_empty());
+    assert!(plan.pinned.iter().any(|p| p.snapshot_id == 2 && p.chunks > 0));
+
+    hat.trash_by_name(fam.name.clone(), 2).unwrap();
+    let plan = hat.gc_plan(chrono::Duration::days(7)).unwrap();
+    assert_eq!(plan.dead_chunks, 0);
+
+    let plan = hat.gc_plan(chrono::Duration::zero()).unwrap();
+    assert_eq!(plan.purged_snapshots, vec![(fam.name.clone(), 2)]);
+    assert!(plan.dead_chunks > 0);
+    assert!(plan.pinned.iter().all(|p| p.snapshot_id != 2));
+
+    // The plan matches what is actually removed.
+    assert_eq!(hat.purge_trash(chrono::Duration::zero()).unwrap(), 1);
+    let (deleted, _) = hat.gc().unwrap();
+    assert_eq!(deleted, plan.dead_chunks);
+}
+
+#[test]
+fn offline_gc_and_prune() {
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let blobs_before = backend.list().unwrap().len();
+    hat.deregister(&fam, 1).unwrap();
+
+    // Planning only touches the index.
+    let (deleted, live) = hat.gc_offline().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+    let (pending, pending_bytes) = hat.prune_pending();
+    assert!(pending > 0);
+    assert!(pending_bytes > 0);
+    assert_eq!(backend.list().unwrap().len(), blobs_before);
+
+    // Marks survive another planning run.
+    hat.gc_offline().unwrap();
+    assert_eq!(hat.prune_pending(), (pending, pending_bytes));
+
+    assert_eq!(hat.prune().unwrap(), pending);
+    assert_eq!(hat.prune_pending(), (0, 0));
+    assert_eq!(backend.list().unwrap().len(), blobs_before - pending as usize);
+}
+
+#[test]
+fn prune_stops_and_continues() {
+    use std::time::Duration;
+    use util::Throttle;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    hat.deregister(&fam, 1).unwrap();
+    hat.gc_offline().unwrap();
+
+    let blobs_before = backend.list().unwrap().len();
+    let (pending, pending_bytes) = hat.prune_pending();
+    assert!(pending > 0);
+
+    // Out of time before the first blob.
+    assert_eq!(hat.prune_for(Some(Duration::from_secs(0)), None).unwrap(), 0);
+    assert_eq!(hat.prune_pending(), (pending, pending_bytes));
+
+    let throttle = Throttle::new(u64::max_value());
+    assert_eq!(hat.prune_for(None, Some(&throttle)).unwrap(), pending);
+    assert_eq!(hat.prune_pending(), (0, 0));
+    assert_eq!(backend.list().unwrap().len(), blobs_before - pending as usize);
+}
+
+#[test]
+fn prune_keeps_blobs_without_delete() {
+    let backend = Arc::new(AppendOnlyBackend::new(MemoryBackend::new()));
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let blobs_before = backend.list().unwrap().len();
+    hat.deregister(&fam, 1).unwrap();
+    let (deleted, _) = hat.gc().unwrap();
+    assert!(deleted > 0);
+
+    // The unused blobs stay marked, so they are still accounted for.
+    let (pending, _) = hat.prune_pending();
+    assert!(pending > 0);
+    assert_eq!(hat.prune().unwrap(), 0);
+    assert_eq!(hat.prune_pending().0, pending);
+    assert_eq!(backend.list().unwrap().len(), blobs_before);
+}
+
+#[test]
+fn clients_share_snapshots() {
+    let (backend, mut laptop, mut fam) = setup_family();
+    laptop.set_client(Client::new("laptop").unwrap()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    laptop.commit(&mut fam, None).unwrap();
+    laptop.data_flush().unwrap();
+    laptop.publish_snapshot("familyname").unwrap();
+
+    let mut server = setup_hat(backend.clone());
+    server.set_client(Client::new("server").unwrap()).unwrap();
+    assert_eq!(server.reconcile().unwrap(), 1);
+    assert!(server.snapshot_index.lookup("laptop/familyname", 1).is_some());
+
+    // Deltas are only merged once, and never by the client that published them.
+    assert_eq!(server.reconcile().unwrap(), 0);
+    assert_eq!(laptop.reconcile().unwrap(), 0);
+}
+
+#[test]
+fn clients_stop_when_their_id_range_is_lost() {
+    let (backend, mut laptop, mut fam) = setup_family();
+    let client = Client::new("laptop").unwrap();
+    laptop.set_client(client).unwrap();
+
+    // Another client claimed the same range, and its claim was the one that stuck.
+    let records: Vec<_> = backend
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|name| name.starts_with(&b"hat-range/"[..]))
+        .collect();
+    assert_eq!(records.len(), 1);
+    backend.store(&records[0], &::crypto::CipherText::new(b"server".to_vec())).unwrap();
+
+    basic_snapshot(&fam);
+    let failed = fam.flush().is_err() || laptop.commit(&mut fam, None).is_err();
+    assert!(failed);
+    assert!(backend.list().unwrap().iter().all(|name| !::blob::is_blob_name(name)));
+}
+
+#[test]
+fn check_reads_every_blob_once() {
+    use hat::Subset;
+
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let full = hat.check(Subset::all()).unwrap();
+    assert!(full.blobs > 0);
+    assert!(full.chunks > 0);
+    assert!(full.errors.is_empty());
+
+    let (mut blobs, mut chunks) = (0, 0);
+    for part in 1..4 {
+        let report = hat.check(Subset { part: part, parts: 3 }).unwrap();
+        assert!(report.errors.is_empty());
+        blobs += report.blobs;
+        chunks += report.chunks;
+    }
+    assert_eq!((full.blobs, full.chunks), (blobs, chunks));
+
+    // Flip a byte in one blob.
+    let name = backend
+        .list()
+        .unwrap()
+        .into_iter()
+        .find(|name| ::blob::is_blob_name(name))
+        .unwrap();
+    let mut data = backend.retrieve(&name).unwrap().unwrap().to_vec();
+    data[0] ^= 1;
+    backend.delete(&name).unwrap();
+    backend.store(&name, &::crypto::CipherText::new(data)).unwrap();
+
+    let broken = hat.check(Subset::all()).unwrap();
+    assert_eq!(full.blobs, broken.blobs);
+    assert_eq!(1, broken.errors.len());
+}
+
+#[test]
+fn check_resumes_where_it_stopped() {
+    use hat::Subset;
+    use std::time::Duration;
+
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Out of time before the first blob.
+    let stopped = hat.check_for(Subset::all(), Some(Duration::from_secs(0))).unwrap();
+    assert!(!stopped.complete);
+    assert_eq!(0, stopped.blobs);
+
+    let full = hat.check(Subset::all()).unwrap();
+    assert!(full.complete);
+    assert!(full.blobs > 0);
+
+    // Pretend a check was interrupted after the first blob.
+    let first = hat.blob_store.list_by_tag(::tags::Tag::Done).into_iter().map(|b| b.id).min();
+    hat.blob_index.set_check_cursor("1/1", first);
+    let resumed = hat.check(Subset::all()).unwrap();
+    assert!(resumed.resumed && resumed.complete);
+    assert_eq!(full.blobs - 1, resumed.blobs);
+
+    // Finished checks start over.
+    assert_eq!(full.blobs, hat.check(Subset::all()).unwrap().blobs);
+
+    hat.blob_index.set_check_cursor("1/1", first);
+    hat.reset_check(Subset::all());
+    assert!(!hat.check(Subset::all()).unwrap().resumed);
+}
+
+#[test]
+fn parallel_check_matches_serial_check() {
+    use hat::{CheckLimits, Subset};
+
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let serial = CheckLimits {
+        fetchers: 1,
+        hashers: 1,
+    };
+    let parallel = CheckLimits {
+        fetchers: 3,
+        hashers: 2,
+    };
+    let one = hat.check_with(Subset::all(), None, serial).unwrap();
+    let many = hat.check_with(Subset::all(), None, parallel).unwrap();
+    assert!(many.complete && many.errors.is_empty());
+    assert!(many.blobs > 0);
+    assert_eq!(one, many);
+    assert_eq!(None, hat.blob_index.check_cursor("1/1"));
+}
+
+#[test]
+fn restore_drill_samples_files() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("a", "abc".into()),
+            ("dir1/b", vec![7; 300000]),
+            ("dir1/sub/c", "c".into()),
+            ("dir2/d", vec![]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let scratch = ::std::env::temp_dir().join(format!("hat-drill-{}", ::time::precise_time_ns()));
+    let all = hat.restore_drill(fam.name.clone(), None, 10, &scratch).unwrap();
+    assert!(all.is_pass());
+    assert_eq!((4, 4, 4), (all.files, all.sampled, all.passed));
+    assert_eq!(300004, all.bytes);
+    assert!(!scratch.exists());
+
+    let some = hat.restore_drill(fam.name.clone(), None, 2, &scratch).unwrap();
+    assert!(some.is_pass());
+    assert_eq!((4, 2, 2), (some.files, some.sampled, some.passed));
+    assert!(!scratch.exists());
+
+    assert!(hat.restore_drill(fam.name.clone(), Some(2), 2, &scratch).is_err());
+}
+
+#[test]
+fn init_marks_only_empty_backends() {
+    use crypto::CipherText;
+    use hat::{FORMAT_VERSION, format_version, init_repository};
+    use std::path::Path;
+
+    let backend = Arc::new(MemoryBackend::new());
+    assert_eq!(format_version(&*backend).unwrap(), None);
+    init_repository(&*backend).unwrap();
+    assert_eq!(format_version(&*backend).unwrap(), Some(FORMAT_VERSION));
+    assert!(init_repository(&*backend).is_err());
+
+    let used = MemoryBackend::new();
+    used.store(b"some blob", &CipherText::new(vec![1, 2, 3])).unwrap();
+    assert!(init_repository(&used).is_err());
+
+    // Repositories of a later format are not opened.
+    let later = format!("hat repository format {}\n", FORMAT_VERSION + 1);
+    backend.store(b"fmt", &CipherText::new(later.into_bytes())).unwrap();
+    let dir = ::std::env::temp_dir().join(format!("hat-format-{}", ::time::precise_time_ns()));
+    let opened = HatRc::open_repository(Path::new("migrations"), dir, backend, 4 * 1024 * 1024);
+    assert!(opened.is_err());
+}
+
+#[test]
+fn readers_see_committed_snapshots_during_a_backup() {
+    use hat::{MasterKey, default_passphrase};
+    use std::fs;
+    use std::path::Path;
+    use tar;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let dir = ::std::env::temp_dir().join(format!("hat-readers-{}", ::time::precise_time_ns()));
+    let migrations = Path::new("migrations");
+    let max_blob_size = 4 * 1024 * 1024;
+    let open_reader = || {
+        HatRc::open_read_only_with_key(
+            migrations,
+            dir.clone(),
+            backend.clone(),
+            max_blob_size,
+            &MasterKey::from_passphrase(&default_passphrase()),
+        ).unwrap()
+    };
+
+    let mut writer = HatRc::open_repository(migrations, dir.clone(), backend.clone(), max_blob_size)
+        .unwrap();
+    let mut fam = writer.open_family("family".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", "first".into())]).unwrap();
+    fam.flush().unwrap();
+    writer.commit(&mut fam, None).unwrap();
+    writer.meta_commit().unwrap();
+    writer.data_flush().unwrap();
+
+    // The writer holds the repository while it reads the next snapshot.
+    assert!(HatRc::open_repository(migrations, dir.clone(), backend.clone(), max_blob_size)
+        .is_err());
+    snapshot_files(&fam, vec![("a", "second".into()), ("b", "new".into())]).unwrap();
+    fam.flush().unwrap();
+
+    let mut reader = open_reader();
+    assert_eq!(1, reader.list_snapshots().len());
+    let out = reader
+        .export_tar("family".to_owned(), None, Path::new("/"), Vec::<u8>::new())
+        .unwrap();
+    let names: Vec<_> = tar::Archive::new(&out[..])
+        .entries()
+        .unwrap()
+        .map(|f| f.unwrap().path().unwrap().into_owned())
+        .collect();
+    assert_eq!(vec![Path::new("a").to_owned()], names);
+    assert!(reader.open_family("unknown".to_owned()).is_err());
+
+    // Committing is not held up by the reader, which keeps seeing what it saw first.
+    writer.commit(&mut fam, None).unwrap();
+    writer.meta_commit().unwrap();
+    writer.data_flush().unwrap();
+    assert_eq!(1, reader.list_snapshots().len());
+    assert_eq!(2, open_reader().list_snapshots().len());
+
+    drop(reader);
+    drop(fam);
+    drop(writer);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn read_blobs_written_with_different_sizes() {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use tar;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let dir = ::std::env::temp_dir().join(format!("hat-blob-sizes-{}", ::time::precise_time_ns()));
+    let migrations = Path::new("migrations");
+    let (small, large) = (2 * 1024 * 1024, 3 * 1024 * 1024);
+    let big_file: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    let read_all = |hat: &mut HatRc<MemoryBackend>| {
+        let out = hat.export_tar("family".to_owned(), None, Path::new("/"), Vec::<u8>::new())
+            .unwrap();
+        let mut files = vec![];
+        for file in tar::Archive::new(&out[..]).entries().unwrap() {
+            let mut file = file.unwrap();
+            let name = file.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = vec![];
+            file.read_to_end(&mut contents).unwrap();
+            files.push((name, contents));
+        }
+        files.sort();
+        files
+    };
+
+    let mut hat = HatRc::open_repository(migrations, dir.clone(), backend.clone(), small).unwrap();
+    {
+        let mut fam = hat.open_family("family".to_owned()).unwrap();
+        snapshot_files(&fam, vec![("a", big_file.clone()), ("b", "small".into())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    // Open families share the blob store, so the size only changes before any is open.
+    assert!(hat.set_max_blob_size(large).is_err());
+    drop(hat);
+
+    let mut hat = HatRc::open_repository(migrations, dir.clone(), backend.clone(), small).unwrap();
+    assert!(hat.set_max_blob_size(1024).is_err());
+    hat.set_max_blob_size(large).unwrap();
+    {
+        let mut fam = hat.open_family("family".to_owned()).unwrap();
+        snapshot_files(&fam, vec![("c", big_file.iter().rev().cloned().collect())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let sizes: BTreeSet<u64> = hat.blob_index.list_sizes().into_iter().map(|(_, s)| s).collect();
+    assert_eq!(sizes, vec![small as u64, large as u64].into_iter().collect());
+
+    let expected = vec![
+        ("a".to_owned(), big_file.clone()),
+        ("b".to_owned(), b"small".to_vec()),
+        ("c".to_owned(), big_file.iter().rev().cloned().collect()),
+    ];
+    assert_eq!(read_all(&mut hat), expected);
+    drop(hat);
+
+    // The stored size wins over the one asked for when opening.
+    let mut hat = HatRc::open_repository(migrations, dir.clone(), backend.clone(), small).unwrap();
+    assert_eq!(hat.repository_config().unwrap().unwrap().max_blob_size, large);
+    assert_eq!(read_all(&mut hat), expected);
+
+    drop(hat);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stage_restores_archived_blobs_before_checkout() {
+    use backend::Capabilities;
+    use crypto::CipherText;
+    use hat::{MetadataPolicy, RestoreOrder};
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    /// Keeps blobs archived until a requested restore is completed with `thaw`.
+    struct ArchivalBackend {
+        inner: MemoryBackend,
+        archived: Mutex<HashSet<Vec<u8>>>,
+        requested: Mutex<HashSet<Vec<u8>>>,
+    }
+
+    impl ArchivalBackend {
+        fn archive_all(&self) {
+            let names = self.inner.list().unwrap();
+            let blobs = names.into_iter().filter(|n| ::blob::is_blob_name(n));
+            self.archived.lock().unwrap().extend(blobs.map(|n| n.to_vec()));
+        }
+
+        fn thaw(&self) {
+            let mut archived = self.archived.lock().unwrap();
+            for name in self.requested.lock().unwrap().drain() {
+                archived.remove(&name);
+            }
+        }
+    }
+
+    impl StoreBackend for ArchivalBackend {
+        fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+            self.inner.store(name, data)
+        }
+
+        fn retrieve(&self, name: &[u8]) -> Result<Option<Arc<Vec<u8>>>, String> {
+            if self.archived.lock().unwrap().contains(name) {
+                return Err("Blob is archived".to_owned());
+            }
+            self.inner.retrieve(name)
+        }
+
+        fn delete(&self, name: &[u8]) -> Result<(), String> {
+            self.inner.delete(name)
+        }
+
+        fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+            self.inner.list()
+        }
+
+        fn flush(&self) -> Result<(), String> {
+            self.inner.flush()
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                delete: true,
+                rename: false,
+                archival: true,
+            }
+        }
+
+        fn is_readable(&self, name: &[u8]) -> Result<bool, String> {
+            Ok(!self.archived.lock().unwrap().contains(name))
+        }
+
+        fn request_restore(&self, name: &[u8]) -> Result<(), String> {
+            self.requested.lock().unwrap().insert(name.to_vec());
+            Ok(())
+        }
+    }
+
+    let backend = Arc::new(ArchivalBackend {
+        inner: MemoryBackend::new(),
+        archived: Mutex::new(HashSet::new()),
+        requested: Mutex::new(HashSet::new()),
+    });
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 100]), ("dir/b", vec![2; 100])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    backend.archive_all();
+
+    let dir = ::std::env::temp_dir().join(format!("hat-stage-{}", ::time::precise_time_ns()));
+    let policy = MetadataPolicy::none();
+    let checkout = hat.checkout_in_dir(fam.name.clone(), dir.clone(), &policy, RestoreOrder::Tree);
+    assert!(checkout.is_err());
+
+    let mut rounds = 0;
+    loop {
+        let report = hat.stage(fam.name.clone(), None).unwrap();
+        if report.is_ready() {
+            assert_eq!(0, report.requested);
+            break;
+        }
+        assert!(report.requested > 0);
+        backend.thaw();
+        rounds += 1;
+        assert!(rounds < 10);
+    }
+
+    hat.checkout_in_dir(fam.name.clone(), dir.clone(), &policy, RestoreOrder::Tree).unwrap();
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_jobs_run_side_by_side() {
+    use hat::SnapshotJob;
+    use std::fs;
+    use std::io::Write;
+
+    let nanos = ::time::precise_time_ns();
+    let dirs: Vec<_> = (0..3)
+        .map(|i| ::std::env::temp_dir().join(format!("hat-jobs-{}-{}", i, nanos)))
+        .collect();
+    for (i, dir) in dirs.iter().enumerate() {
+        fs::create_dir_all(dir).unwrap();
+        fs::File::create(dir.join("file")).unwrap().write_all(&vec![i as u8; 1000]).unwrap();
+    }
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let jobs = vec![
+        SnapshotJob::new("first".to_owned(), dirs[0].clone()),
+        SnapshotJob::new("second".to_owned(), dirs[1].clone()),
+        SnapshotJob::new("cancelled".to_owned(), dirs[2].clone()),
+        SnapshotJob::new("first".to_owned(), dirs[2].clone()),
+    ];
+    jobs[2].control.cancel();
+
+    let results = hat.run_snapshot_jobs(&jobs);
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    assert!(results[0].is_ok() && results[1].is_ok());
+    assert!(results[2].is_err());
+    assert!(results[3].is_err());
+    assert!(jobs[0].control.files() > 0);
+    assert_eq!(1000, jobs[1].control.bytes());
+
+    let families: Vec<String> = hat.list_snapshots().into_iter().map(|s| s.family_name).collect();
+    assert_eq!(vec!["first".to_owned(), "second".to_owned()], families);
+
+    for dir in dirs {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn snapshot_names_from_template() {
+    use hat::NameTemplate;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let template = NameTemplate::parse("nightly-{family}").unwrap();
+    for _ in 0..2 {
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        let name = hat.snapshot_name(&fam.name, &template);
+        hat.commit_with_msg(&mut fam, None, &name).unwrap();
+    }
+    hat.data_flush().unwrap();
+
+    let names: Vec<Option<String>> = hat.list_snapshots().into_iter().map(|s| s.msg).collect();
+    assert_eq!(
+        names,
+        vec![
+            Some("nightly-familyname".to_owned()),
+            Some("nightly-familyname-2".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn repo_info_summary() {
+    let (_, mut hat, mut fam) = setup_family();
+    let info = hat.repo_info().unwrap();
+    assert_eq!((info.snapshots, info.blobs, info.last_gc), (0, 0, None));
+
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    hat.gc().unwrap();
+
+    let info = hat.repo_info().unwrap();
+    assert_eq!((info.snapshots, info.families), (1, 1));
+    assert!(info.blobs > 0 && info.blob_bytes > 0);
+    assert_eq!(info.reserved_hashes, 0);
+    assert!(info.last_gc.is_some());
+    assert!(!info.locked);
+    assert!(format!("{}", info).contains("Last GC:"));
+}
+
+#[test]
+fn families_share_identical_content() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let mut other = hat.open_family("other".to_owned()).unwrap();
+    snapshot_files(
+        &other,
+        vec![("copy", vec![1; 1000000]), ("own", "only here".into())],
+    ).unwrap();
+    other.flush().unwrap();
+    let stats = hat.commit(&mut other, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // The copied file is stored once, under the first family.
+    assert!(stats.reused_chunks > 0);
+
+    let usage = hat.family_usage().unwrap();
+    assert_eq!(usage.len(), 2);
+    let (fam_usage, other_usage) = if usage[0].family_name == "other" {
+        (&usage[1], &usage[0])
+    } else {
+        (&usage[0], &usage[1])
+    };
+    assert!(fam_usage.unique_chunks > 0);
+    assert!(other_usage.unique_chunks > 0);
+    assert!(other_usage.shared_bytes > 0);
+    assert_eq!(fam_usage.shared_chunks, other_usage.shared_chunks);
+    assert_eq!(fam_usage.shared_bytes, other_usage.shared_bytes);
+}
+
+#[test]
+fn read_limit_slows_down_reading() {
+    use hat::SourceFilter;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = ::std::env::temp_dir().join(format!("hat-read-limit-{}", ::time::precise_time_ns()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("big")).unwrap().write_all(&vec![7; 1500000]).unwrap();
+
+    // A second's worth of data is read at once, and the rest at the limit.
+    let filter = SourceFilter {
+        read_limit: Some(1000000),
+        ..SourceFilter::default()
+    };
+    let (_, mut hat, mut fam) = setup_family();
+    let start = ::time::precise_time_ns();
+    fam.snapshot_dir(dir.clone(), &filter).unwrap();
+    fam.flush().unwrap();
+    assert!(::time::precise_time_ns() - start >= 400000000);
+
+    let stats = hat.commit(&mut fam, None).unwrap();
+    assert_eq!(stats.new_files, 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn command_output_is_stored_with_its_exit_code() {
+    use hat::{CommandRun, CommandSource};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tar;
+
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    let source = CommandSource::new("dump.sql".to_owned(), "echo dumped; exit 2".to_owned());
+    let run = fam.snapshot_command(&source).unwrap();
+    assert!(!run.succeeded());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let snapshots = hat.list_snapshots();
+    assert_eq!(
+        snapshots[0].commands,
+        vec![
+            CommandRun {
+                name: "dump.sql".to_owned(),
+                command: "echo dumped; exit 2".to_owned(),
+                exit_code: Some(2),
+                timed_out: false,
+            },
+        ]
+    );
+
+    let out = hat.export_tar(fam.name.clone(), None, Path::new("dump.sql"), Vec::<u8>::new())
+        .unwrap();
+    let mut archive = tar::Archive::new(&out[..]);
+    let mut file = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(file.path().unwrap().into_owned(), PathBuf::from("dump.sql"));
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"dumped\n");
+}
+
+#[test]
+fn attestations_detect_changed_snapshots() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let statement = hat.attest(&fam.name, 1).unwrap();
+    let attestation = hat.verify_attestation(&statement).unwrap();
+    assert_eq!((attestation.family_name.as_str(), attestation.snapshot_id), ("familyname", 1));
+    assert!(attestation.chunks > 0);
+
+    // Any edit breaks the signature.
+    let edited = statement.replace(
+        &format!("chunks: {}", attestation.chunks),
+        &format!("chunks: {}", attestation.chunks + 1),
+    );
+    assert!(hat.verify_attestation(&edited).is_err());
+
+    // A repository without the snapshot cannot confirm it.
+    let (_, mut other, _) = setup_family();
+    assert!(other.verify_attestation(&statement).is_err());
+
+    assert!(hat.attest(&fam.name, 2).is_err());
+}
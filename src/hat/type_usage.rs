@@ -0,0 +1,102 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accounting of the data in the latest snapshots by content type.
+
+use std::collections::HashMap;
+use std::fmt;
+use util::human;
+
+
+/// The files of one content type in the latest snapshots of the families.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeUsage {
+    /// `None` for files whose type was not detected or recorded.
+    pub content_type: Option<String>,
+    pub files: u64,
+    /// Sum of the file sizes, before deduplication.
+    pub bytes: u64,
+}
+
+impl fmt::Display for TypeUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in {} files",
+            self.content_type.as_ref().map_or("unknown", |t| &t[..]),
+            human::bytes(self.bytes),
+            human::count(self.files)
+        )
+    }
+}
+
+/// Add up the (content type, files, bytes) counts of several families, largest type first.
+pub fn merge(counts: Vec<(Option<String>, u64, u64)>) -> Vec<TypeUsage> {
+    let mut types: HashMap<Option<String>, (u64, u64)> = HashMap::new();
+    for (content_type, files, bytes) in counts {
+        let total = types.entry(content_type).or_insert((0, 0));
+        total.0 += files;
+        total.1 += bytes;
+    }
+    let mut usage: Vec<TypeUsage> = types
+        .into_iter()
+        .map(|(content_type, (files, bytes))| {
+            TypeUsage {
+                content_type: content_type,
+                files: files,
+                bytes: bytes,
+            }
+        })
+        .collect();
+    usage.sort_by(|a, b| {
+        b.bytes.cmp(&a.bytes).then_with(|| a.content_type.cmp(&b.content_type))
+    });
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_are_merged_by_type() {
+        let png = Some("image/png".to_owned());
+        let usage = merge(vec![
+            (png.clone(), 2, 100),
+            (None, 1, 50),
+            (png.clone(), 1, 20),
+            (Some("text/plain".to_owned()), 3, 50),
+        ]);
+        assert_eq!(
+            usage,
+            vec![
+                TypeUsage {
+                    content_type: png,
+                    files: 3,
+                    bytes: 120,
+                },
+                TypeUsage {
+                    content_type: None,
+                    files: 1,
+                    bytes: 50,
+                },
+                TypeUsage {
+                    content_type: Some("text/plain".to_owned()),
+                    files: 3,
+                    bytes: 50,
+                },
+            ]
+        );
+    }
+}
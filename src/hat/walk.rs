@@ -0,0 +1,133 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lazy listing of snapshots, for using hat as a library.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::export::ChunkReader;
+use hat::family::Family;
+use hat::walker::Content;
+use key;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::vec;
+
+
+/// An entry of a snapshot, as listed by `SnapshotWalk`.
+pub struct SnapshotEntry<B: StoreBackend> {
+    /// Path of the entry below the snapshot root.
+    pub path: PathBuf,
+    pub entry: key::Entry,
+    content: Content,
+    backend: key::HashStoreBackend<B>,
+}
+
+impl<B: StoreBackend> SnapshotEntry<B> {
+    pub fn is_dir(&self) -> bool {
+        match self.content {
+            Content::Dir(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn link_target(&self) -> Option<&Path> {
+        match self.content {
+            Content::Link(ref target) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Open the data of a file. Chunks are fetched as the reader gets to them.
+    /// Returns `None` for directories and links.
+    pub fn open(&self) -> Result<Option<ChunkReader<B>>, HatError> {
+        match self.content {
+            Content::Data(ref data_ref) => {
+                let chunks = hash::tree::LeafIterator::new(self.backend.clone(), data_ref.clone())?;
+                Ok(Some(ChunkReader::new(chunks)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Lists a snapshot depth first, each directory before its contents.
+///
+/// A directory listing is only fetched when the walk continues into it. If that fails, the
+/// error is returned in place of its contents and the walk continues after the directory.
+pub struct SnapshotWalk<B: StoreBackend> {
+    family: Family<B>,
+    backend: key::HashStoreBackend<B>,
+    // The directory returned last, to be listed on the next call.
+    pending: Option<(PathBuf, hash::tree::HashRef)>,
+    // The remaining entries of each directory being listed, innermost last.
+    stack: Vec<(PathBuf, vec::IntoIter<(key::Entry, Content)>)>,
+}
+
+impl<B: StoreBackend> SnapshotWalk<B> {
+    pub fn new(
+        family: Family<B>,
+        backend: key::HashStoreBackend<B>,
+        root: hash::tree::HashRef,
+    ) -> SnapshotWalk<B> {
+        SnapshotWalk {
+            family: family,
+            backend: backend,
+            pending: Some((PathBuf::new(), root)),
+            stack: vec![],
+        }
+    }
+}
+
+impl<B: StoreBackend> Iterator for SnapshotWalk<B> {
+    type Item = Result<SnapshotEntry<B>, HatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((path, dir_ref)) = self.pending.take() {
+            match self.family.fetch_dir_data(dir_ref, self.backend.clone()) {
+                Ok(children) => self.stack.push((path, children.into_iter())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        loop {
+            let next = match self.stack.last_mut() {
+                None => return None,
+                Some(&mut (ref parent, ref mut children)) => {
+                    children.next().map(|(entry, content)| {
+                        (parent.join(OsStr::from_bytes(&entry.info.name[..])), entry, content)
+                    })
+                }
+            };
+            match next {
+                None => {
+                    self.stack.pop();
+                }
+                Some((path, entry, content)) => {
+                    if let Content::Dir(ref dir_ref) = content {
+                        self.pending = Some((path.clone(), dir_ref.clone()));
+                    }
+                    return Some(Ok(SnapshotEntry {
+                        path: path,
+                        entry: entry,
+                        content: content,
+                        backend: self.backend.clone(),
+                    }));
+                }
+            }
+        }
+    }
+}
@@ -19,6 +19,7 @@ use key::tests::*;
 use std::sync::Arc;
 use test::Bencher;
 use util::Process;
+use util::corpus::{self, Corpus};
 
 #[bench]
 fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
@@ -43,7 +44,8 @@ fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    Chunking::Default))
             .unwrap();
     });
 
@@ -79,7 +81,8 @@ fn insert_1_key_x_128000_unique(bench: &mut Bencher) {
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    Chunking::Default))
             .unwrap();
     });
 
@@ -101,7 +104,8 @@ fn insert_1_key_x_16_x_128000_zeros(bench: &mut Bencher) {
             key_entry: Entry::new(None, vec![1u8, 2, 3].to_vec(), Data::FilePlaceholder, None),
         };
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    Chunking::Default))
             .unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -144,7 +148,8 @@ fn insert_1_key_x_16_x_128000_unique(bench: &mut Bencher) {
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    Chunking::Default))
             .unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -167,7 +172,7 @@ fn insert_1_key_unchanged_empty(bench: &mut Bencher) {
             data: None,
             key_entry: Entry::new(None, vec![1u8, 2, 3], Data::FilePlaceholder, None),
         };
-        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None))
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None, Chunking::Default))
             .unwrap();
     });
 
@@ -199,10 +204,14 @@ fn insert_1_key_updated_empty(bench: &mut Bencher) {
                     permissions: None,
                     byte_length: None,
                     hat_snapshot_ts: 0,
+                    capabilities: None,
+                    attribute_flags: None,
+                    inode: None,
+                    content_type: None,
                 },
             },
         };
-        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None))
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None, Chunking::Default))
             .unwrap();
     });
 
@@ -227,9 +236,72 @@ fn insert_1_key_unique_empty(bench: &mut Bencher) {
                 None,
             ),
         };
-        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None))
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None, Chunking::Default))
             .unwrap();
     });
 
     ks_p.send_reply(Msg::Flush).unwrap();
 }
+
+/// Insert 4 MiB of new data per iteration, read in 64 KiB pieces like a file.
+fn insert_corpus(bench: &mut Bencher, corpus: Corpus, chunking: Chunking) {
+    let backend = Arc::new(DevNullBackend);
+    let ks_p: StoreProcess<EntryStub, _> =
+        Process::new(Store::new_for_testing(backend, 4 * 1024 * 1024).unwrap());
+
+    let len = 4 * 1024 * 1024;
+    let mut bytes = corpus::generate(corpus, len);
+
+    let mut i = 0i32;
+    bench.iter(|| {
+        i += 1;
+
+        // Make every chunk new, whatever the chunk length.
+        for pos in (0..len).filter(|p| p % 4096 == 0) {
+            bytes[pos] = i as u8;
+            bytes[pos + 1] = (i / 256) as u8;
+        }
+
+        let entry = EntryStub {
+            data: Some(bytes.chunks(64 * 1024).map(|c| c.to_vec()).collect()),
+            key_entry: Entry::new(None, vec![1u8, 2, 3], Data::FilePlaceholder, None),
+        };
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
+                                    Some(Box::new(move |()| Some(entry))),
+                                    chunking))
+            .unwrap();
+    });
+
+    ks_p.send_reply(Msg::Flush).unwrap();
+    bench.bytes = len as u64;
+}
+
+#[bench]
+fn insert_4_mb_text(bench: &mut Bencher) {
+    insert_corpus(bench, Corpus::Text, Chunking::Default);
+}
+
+#[bench]
+fn insert_4_mb_text_fixed_blocks(bench: &mut Bencher) {
+    insert_corpus(bench, Corpus::Text, Chunking::FixedBlocks(FIXED_BLOCK_LEN));
+}
+
+#[bench]
+fn insert_4_mb_binary(bench: &mut Bencher) {
+    insert_corpus(bench, Corpus::Binary, Chunking::Default);
+}
+
+#[bench]
+fn insert_4_mb_binary_fixed_blocks(bench: &mut Bencher) {
+    insert_corpus(bench, Corpus::Binary, Chunking::FixedBlocks(FIXED_BLOCK_LEN));
+}
+
+#[bench]
+fn insert_4_mb_random(bench: &mut Bencher) {
+    insert_corpus(bench, Corpus::Random, Chunking::Default);
+}
+
+#[bench]
+fn insert_4_mb_random_fixed_blocks(bench: &mut Bencher) {
+    insert_corpus(bench, Corpus::Random, Chunking::FixedBlocks(FIXED_BLOCK_LEN));
+}
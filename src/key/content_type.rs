@@ -0,0 +1,107 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Content types of files, detected from the magic bytes at their start.
+
+use std::str;
+
+
+/// Signatures as (offset, bytes, content type), checked in order.
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"II*\0", "image/tiff"),
+    (0, b"MM\0*", "image/tiff"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\0", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (4, b"ftyp", "video/mp4"),
+    (257, b"ustar", "application/x-tar"),
+];
+
+/// Formats in a RIFF container, by the four bytes that follow the container header.
+const RIFF: &[(&[u8], &str)] = &[
+    (b"WEBP", "image/webp"),
+    (b"WAVE", "audio/wav"),
+    (b"AVI ", "video/x-msvideo"),
+];
+
+/// The content type of data starting with `data`, or `None` if it is not recognized.
+/// Data without a known signature is taken as text if it is valid UTF-8 without NUL bytes.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.is_empty() {
+        return None;
+    }
+    for &(offset, magic, content_type) in MAGIC {
+        if data.len() >= offset + magic.len() && &data[offset..offset + magic.len()] == magic {
+            return Some(content_type);
+        }
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" {
+        for &(format, content_type) in RIFF {
+            if &data[8..12] == format {
+                return Some(content_type);
+            }
+        }
+    }
+    if data.contains(&0) {
+        return None;
+    }
+    match str::from_utf8(data) {
+        Ok(_) => Some("text/plain"),
+        // The data may end in the middle of a character when it is cut off at a chunk boundary.
+        Err(e) if e.error_len().is_none() => Some("text/plain"),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_known_signatures() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"%PDF-1.4\n"), Some("application/pdf"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+
+        let mut tar = vec![0; 512];
+        tar[..5].copy_from_slice(b"hello");
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff(&tar[..]), Some("application/x-tar"));
+    }
+
+    #[test]
+    fn sniff_text() {
+        assert_eq!(sniff(b"Hello, world\n"), Some("text/plain"));
+        // Cut off in the middle of a two-byte character.
+        assert_eq!(sniff(b"caf\xc3"), Some("text/plain"));
+        assert_eq!(sniff(b"binary\0data"), None);
+        assert_eq!(sniff(&[0xff, 0xfe, b'a']), None);
+        assert_eq!(sniff(b""), None);
+    }
+}
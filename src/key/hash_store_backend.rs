@@ -18,15 +18,23 @@ use blob;
 use crypto;
 use errors::RetryError;
 use hash;
+use hash::cache::NodeCache;
 use hash::tree::HashTreeBackend;
-use key::MsgError;
+use key::{IoStats, MsgError};
 use key;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+type KnownChunks = HashMap<Vec<u8>, (u64, hash::tree::HashRef)>;
+
 pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    node_cache: Option<Arc<NodeCache>>,
+    // Stored chunks looked up ahead of their insertion; shared between clones.
+    known: Arc<Mutex<KnownChunks>>,
+    io_stats: Option<Arc<IoStats>>,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
@@ -34,6 +42,9 @@ impl<B> Clone for HashStoreBackend<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            node_cache: self.node_cache.clone(),
+            known: self.known.clone(),
+            io_stats: self.io_stats.clone(),
         }
     }
 }
@@ -48,8 +59,36 @@ impl<B: StoreBackend> HashStoreBackend<B> {
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            node_cache: None,
+            known: Arc::new(Mutex::new(HashMap::new())),
+            io_stats: None,
+        }
+    }
+
+    /// Look up the file chunks with `hashes` in a single query, so that inserting any of them
+    /// that are already stored needs no further lookups. Replaces the previous prefetch.
+    pub fn prefetch(&self, hashes: &[hash::Hash]) {
+        let found = self.hash_index.fetch_hash_refs(hashes);
+        let mut known = self.known.lock().unwrap();
+        known.clear();
+        for (hash, entry) in hashes.iter().zip(found) {
+            if let Some(entry) = entry {
+                known.insert(hash.bytes.clone(), entry);
+            }
         }
     }
+
+    /// Share `cache` between the tree walkers using this backend.
+    pub fn with_node_cache(mut self, cache: Arc<NodeCache>) -> HashStoreBackend<B> {
+        self.node_cache = Some(cache);
+        self
+    }
+
+    /// Count the chunks inserted through this backend in `stats`.
+    pub fn with_io_stats(mut self, stats: Arc<IoStats>) -> HashStoreBackend<B> {
+        self.io_stats = Some(stats);
+        self
+    }
 }
 
 impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
@@ -58,19 +97,22 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
     fn fetch_chunk(&self, href: &hash::tree::HashRef) -> Result<Option<Vec<u8>>, MsgError> {
         assert!(!href.hash.bytes.is_empty());
 
-        Ok(self.blob_store.retrieve(&href)?.and_then(|data| {
-            let actual_hash = hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]);
-            if href.hash == actual_hash {
-                Some(data)
-            } else {
-                error!(
-                    "Data hash does not match expectation: {:?} instead of {:?}",
-                    actual_hash,
-                    href.hash
-                );
-                None
-            }
-        }))
+        // Walkers cannot continue without the chunk, so report why it is unavailable.
+        let data = match self.blob_store.retrieve(&href)? {
+            Some(data) => data,
+            None => return Err(From::from("Chunk is missing from the backend")),
+        };
+        let actual_hash = hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]);
+        if href.hash == actual_hash {
+            Ok(Some(data))
+        } else {
+            error!(
+                "Data hash does not match expectation: {:?} instead of {:?}",
+                actual_hash,
+                href.hash
+            );
+            Err(From::from("Data hash does not match expectation"))
+        }
     }
 
     fn fetch_persistent_ref(&self, hash: &hash::Hash) -> Option<blob::ChunkRef> {
@@ -91,6 +133,10 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         }
     }
 
+    fn node_cache(&self) -> Option<&NodeCache> {
+        self.node_cache.as_ref().map(|c| &**c)
+    }
+
     fn insert_chunk(
         &self,
         chunk: &[u8],
@@ -107,6 +153,20 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
             persistent_ref: None,
         };
 
+        if node == blob::NodeType::Leaf && leaf == blob::LeafType::FileChunk {
+            if let Some(ref stats) = self.io_stats {
+                stats.add_read(chunk.len());
+            }
+        }
+
+        if let Some(&(id, ref href)) = self.known.lock().unwrap().get(&hash_entry.hash.bytes) {
+            debug!("Reuse prefetched hash {}: {}", id, chunk.len());
+            if let Some(ref stats) = self.io_stats {
+                stats.add_reused_chunk();
+            }
+            return Ok((id, href.clone()));
+        }
+
         match self.hash_index.reserve(&hash_entry) {
             hash::ReserveResult::HashKnown(id) => {
                 debug!(
@@ -118,6 +178,9 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 );
 
                 // Someone came before us: piggyback on their result.
+                if let Some(ref stats) = self.io_stats {
+                    stats.add_reused_chunk();
+                }
                 let pref = self.fetch_persistent_ref(&hash_entry.hash).expect(
                     "Could not find persistent ref for known hash",
                 );
@@ -128,6 +191,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                         node: node,
                         leaf: leaf,
                         info: None,
+                        size: None,
                         persistent_ref: pref,
                     },
                 ))
@@ -162,7 +226,11 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                     leaf,
                     info,
                     callback,
-                );
+                )?;
+
+                if let Some(ref stats) = self.io_stats {
+                    stats.add_new_chunk(href.persistent_ref.length);
+                }
 
                 // Update the hash entry now to enable reuse before the hash is fully committed.
                 hash_entry.persistent_ref = Some(href.persistent_ref.clone());
@@ -15,11 +15,13 @@
 //! Local state for keys in the snapshot in progress (the "index").
 
 
+use std::collections::{BTreeMap, HashMap};
 use std::str;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 
 use chrono;
+use db;
 use diesel;
 use diesel::prelude::*;
 use diesel::connection::TransactionManager;
@@ -69,6 +71,15 @@ pub struct Info {
 
     pub byte_length: Option<u64>,
     pub hat_snapshot_ts: i64,
+
+    /// Linux file capabilities, as stored in the `security.capability` xattr.
+    pub capabilities: Option<Vec<u8>>,
+    /// Immutable, append-only and nodump inode flags.
+    pub attribute_flags: Option<u32>,
+    /// Inode number on the source filesystem, used to recognize moved files.
+    pub inode: Option<u64>,
+    /// Content type detected from the first chunk of the file, if detection is enabled.
+    pub content_type: Option<String>,
 }
 
 impl Entry {
@@ -123,6 +134,11 @@ impl Info {
 
             byte_length: meta.map(|m| m.len()),
             hat_snapshot_ts: chrono::Utc::now().timestamp(),
+
+            capabilities: None,
+            attribute_flags: None,
+            inode: meta.map(|m| m.st_ino()),
+            content_type: None,
         }
     }
 
@@ -153,6 +169,20 @@ impl Info {
             byte_length: Some(msg.get_byte_length()),
 
             hat_snapshot_ts: msg.get_utc_timestamp(),
+
+            capabilities: match msg.get_capabilities()? {
+                caps if caps.is_empty() => None,
+                caps => Some(caps.to_vec()),
+            },
+            attribute_flags: match msg.get_attribute_flags() {
+                0 => None,
+                flags => Some(flags),
+            },
+            inode: None,
+            content_type: match msg.get_content_type()? {
+                t if t.is_empty() => None,
+                t => Some(t.to_owned()),
+            },
         })
     }
     pub fn populate_msg(&self, mut msg: root_capnp::file_info::Builder) {
@@ -186,6 +216,14 @@ impl Info {
         }
 
         msg.borrow().set_utc_timestamp(self.hat_snapshot_ts);
+
+        if let Some(ref caps) = self.capabilities {
+            msg.borrow().set_capabilities(caps);
+        }
+        msg.borrow().set_attribute_flags(self.attribute_flags.unwrap_or(0));
+        if let Some(ref content_type) = self.content_type {
+            msg.borrow().set_content_type(content_type);
+        }
     }
 }
 
@@ -200,6 +238,8 @@ pub struct InternalKeyIndex {
 impl InternalKeyIndex {
     fn new(migrations_dir: &Path, path: &str) -> Result<InternalKeyIndex, DieselError> {
         let conn = SqliteConnection::establish(path)?;
+        // Readers in other processes see the last commit instead of waiting for the writer.
+        conn.execute("PRAGMA journal_mode = WAL;")?;
 
         let ki = InternalKeyIndex {
             conn: conn,
@@ -234,6 +274,22 @@ impl InternalKeyIndex {
         Ok(ki)
     }
 
+    fn new_read_only(path: &str) -> Result<InternalKeyIndex, DieselError> {
+        let conn = SqliteConnection::establish(path)?;
+        conn.execute("PRAGMA query_only = ON;")?;
+
+        let ki = InternalKeyIndex {
+            conn: conn,
+            flush_timer: PeriodicTimer::new(Duration::seconds(5)),
+        };
+        {
+            let tm = ki.conn.transaction_manager();
+            tm.begin_transaction(&ki.conn)?;
+        }
+
+        Ok(ki)
+    }
+
     fn last_insert_rowid(&self) -> Result<i64, DieselError> {
         let id = diesel::select(diesel::expression::sql("last_insert_rowid()"))
             .first::<i64>(&self.conn)?;
@@ -299,13 +355,19 @@ impl InternalKeyIndex {
                 symbolic_link_path: link_path.map(|s| s.as_bytes()),
                 hash: hash_ref_opt.map(|h| &h.hash.bytes[..]),
                 hash_ref: hash_ref_bytes.as_ref().map(|v| &v[..]),
+                capabilities: entry.info.capabilities.as_ref().map(|c| &c[..]),
+                attribute_flags: entry.info.attribute_flags.map(|f| f as i64),
+                inode: entry.info.inode.map(|i| i as i64),
+                byte_length: entry.info.byte_length.map(|l| l as i64),
+                content_type: entry.info.content_type.as_ref().map(|t| &t[..]),
             };
 
             // Insert replaces when (node_id, committed) already exists.
             use super::schema::key_data::dsl::*;
             diesel::insert(&new).into(key_data).execute(&self.conn)?;
-            self.maybe_flush()?;
         }
+        self.invalidate_dir_hashes(entry.node_id.unwrap())?;
+        self.maybe_flush()?;
 
         Ok(entry)
     }
@@ -360,6 +422,10 @@ impl InternalKeyIndex {
                     group_id: data.group_id.map(|x| x as u64),
                     byte_length: None,
                     hat_snapshot_ts: 0,
+                    capabilities: data.capabilities,
+                    attribute_flags: data.attribute_flags.map(|f| f as u32),
+                    inode: data.inode.map(|i| i as u64),
+                    content_type: data.content_type,
                 },
             }))
         } else {
@@ -372,17 +438,35 @@ impl InternalKeyIndex {
     fn list_dir(
         &mut self,
         parent_opt: Option<u64>,
+    ) -> Result<Vec<(Entry, Option<hash::tree::HashRef>)>, DieselError> {
+        self.list_dir_page(parent_opt, None, i64::max_value() as usize)
+    }
+
+    /// List at most `limit` entries of a directory, ordered by name and starting after the
+    /// name `after`, if given. The order does not depend on when entries were inserted, so
+    /// equal directories are always listed the same way.
+    fn list_dir_page(
+        &mut self,
+        parent_opt: Option<u64>,
+        after: Option<Vec<u8>>,
+        limit: usize,
     ) -> Result<Vec<(Entry, Option<hash::tree::HashRef>)>, DieselError> {
         use diesel::prelude::*;
         use super::schema::key_tree::dsl::*;
         use super::schema::key_data::dsl::{committed, key_data};
 
+        // Entries always have a name, so every name comes after the empty one.
+        let after = after.unwrap_or_else(Vec::new);
+        let limit = limit as i64;
         let rows = match parent_opt {
             Some(p) => {
                 key_tree
                     .inner_join(key_data)
                     .filter(parent_id.eq(p as i64))
                     .filter(committed.eq(true))
+                    .filter(name.gt(after))
+                    .order(name.asc())
+                    .limit(limit)
                     .load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?
             }
             None => {
@@ -390,6 +474,9 @@ impl InternalKeyIndex {
                     .inner_join(key_data)
                     .filter(parent_id.is_null())
                     .filter(committed.eq(true))
+                    .filter(name.gt(after))
+                    .order(name.asc())
+                    .limit(limit)
                     .load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?
             }
         };
@@ -426,6 +513,10 @@ impl InternalKeyIndex {
                                 group_id: data.group_id.map(|x| x as u64),
                                 byte_length: None,
                                 hat_snapshot_ts: 0,
+                                capabilities: data.capabilities,
+                                attribute_flags: data.attribute_flags.map(|f| f as u32),
+                                inode: data.inode.map(|i| i as u64),
+                                content_type: data.content_type,
                             },
                         },
                         data.hash_ref.as_mut().map(|p| {
@@ -489,9 +580,13 @@ impl InternalKeyIndex {
             if tag_ == Tag::Reserved as i64 {
                 self.cleanup_unused(Some(id as u64))?;
             } else {
+                self.add_deletions(id as u64)?;
                 diesel::delete(key_tree.filter(node_id.eq(id))).execute(
                     &self.conn,
                 )?;
+                if let Some(p) = parent_opt {
+                    self.invalidate_dir_hashes(p)?;
+                }
             }
         }
 
@@ -499,6 +594,430 @@ impl InternalKeyIndex {
 
         Ok(())
     }
+
+    /// The hash of directory `node` as of the last commit, unless something below it changed.
+    fn dir_hash(&mut self, node: u64) -> Result<Option<hash::tree::HashRef>, DieselError> {
+        use super::schema::dir_hashes::dsl::*;
+        let bytes = dir_hashes
+            .filter(node_id.eq(node as i64))
+            .select(hash_ref)
+            .first::<Vec<u8>>(&self.conn)
+            .optional()?;
+        Ok(bytes.map(|b| hash::tree::HashRef::from_bytes(&mut &b[..]).unwrap()))
+    }
+
+    fn set_dir_hash(
+        &mut self,
+        node: u64,
+        dir_ref: &hash::tree::HashRef,
+    ) -> Result<(), DieselError> {
+        let bytes = dir_ref.as_bytes();
+        let new = schema::NewDirHash {
+            node_id: node as i64,
+            hash_ref: &bytes[..],
+        };
+        diesel::insert(&new).into(schema::dir_hashes::table).execute(&self.conn)?;
+        self.maybe_flush()?;
+        Ok(())
+    }
+
+    /// Forget the stored hashes of `node` and of the directories above it.
+    /// A directory is only hashed after everything below it, so when a parent has no stored
+    /// hash, neither have the directories above it and the walk can stop.
+    fn invalidate_dir_hashes(&mut self, node: u64) -> Result<(), DieselError> {
+        use super::schema::dir_hashes;
+        use super::schema::key_tree::dsl::*;
+
+        diesel::delete(dir_hashes::table.filter(dir_hashes::node_id.eq(node as i64)))
+            .execute(&self.conn)?;
+        let mut current = node as i64;
+        while let Some(Some(parent)) = key_tree
+            .filter(node_id.eq(current))
+            .select(parent_id)
+            .first::<Option<i64>>(&self.conn)
+            .optional()?
+        {
+            let deleted =
+                diesel::delete(dir_hashes::table.filter(dir_hashes::node_id.eq(parent)))
+                    .execute(&self.conn)?;
+            if deleted == 0 {
+                break;
+            }
+            current = parent;
+        }
+        Ok(())
+    }
+
+    /// The stored hashes of the files and directories below directory `node`.
+    /// Returns `None` if a directory below `node` has no stored hash.
+    fn subtree_hashes(&mut self, node: u64) -> Result<Option<Vec<Vec<u8>>>, DieselError> {
+        use super::schema::key_tree::dsl::{key_tree, node_id, parent_id};
+        use super::schema::key_data::dsl::{committed, key_data, symbolic_link_path};
+        use super::schema::key_data::dsl::hash as data_hash;
+
+        let mut out = vec![];
+        let mut dirs = vec![node as i64];
+        while let Some(dir) = dirs.pop() {
+            let children = key_tree
+                .inner_join(key_data)
+                .filter(parent_id.eq(dir))
+                .filter(committed.eq(true))
+                .select((node_id, data_hash, symbolic_link_path))
+                .load::<(Option<i64>, Option<Vec<u8>>, Option<Vec<u8>>)>(&self.conn)?;
+            for (child, hash_opt, link_opt) in children {
+                match (hash_opt, link_opt) {
+                    (Some(file_hash), _) => out.push(file_hash),
+                    (None, None) => {
+                        let child = child.expect("Directory without id");
+                        match self.dir_hash(child as u64)? {
+                            Some(dir_ref) => out.push(dir_ref.hash.bytes),
+                            None => return Ok(None),
+                        }
+                        dirs.push(child);
+                    }
+                    (None, Some(_)) => (),
+                }
+            }
+        }
+        Ok(Some(out))
+    }
+
+    /// A committed file with the inode, size and modification time of `entry`, that has not
+    /// been seen yet in this snapshot. Returns its id and data reference.
+    fn find_moved(
+        &mut self,
+        entry: &Entry,
+    ) -> Result<Option<(u64, hash::tree::HashRef)>, DieselError> {
+        use super::schema::key_data::dsl::{byte_length, committed, hash_ref, inode, key_data,
+                                           modified, node_id, tag};
+
+        let (ino, len, mtime) = match (
+            entry.info.inode,
+            entry.info.byte_length,
+            entry.info.modified_ts_secs,
+        ) {
+            (Some(ino), Some(len), Some(mtime)) => (ino as i64, len as i64, mtime as i64),
+            _ => return Ok(None),
+        };
+        let row = key_data
+            .filter(inode.eq(ino))
+            .filter(byte_length.eq(len))
+            .filter(modified.eq(mtime))
+            .filter(committed.eq(true))
+            .filter(tag.ne(Tag::Reserved as i64))
+            .filter(hash_ref.is_not_null())
+            .select((node_id, hash_ref))
+            .first::<(Option<i64>, Option<Vec<u8>>)>(&self.conn)
+            .optional()?;
+        Ok(match row {
+            Some((Some(id), Some(bytes))) => {
+                Some((id as u64, hash::tree::HashRef::from_bytes(&mut &bytes[..]).unwrap()))
+            }
+            _ => None,
+        })
+    }
+
+    /// The committed file other than `node` with the data hash `hash_bytes`, if there is
+    /// exactly one.
+    fn find_by_hash(&mut self, node: u64, hash_bytes: &[u8]) -> Result<Option<u64>, DieselError> {
+        use super::schema::key_data::dsl::{committed, key_data, node_id};
+        use super::schema::key_data::dsl::hash as data_hash;
+
+        let ids = key_data
+            .filter(data_hash.eq(hash_bytes))
+            .filter(committed.eq(true))
+            .filter(node_id.ne(node as i64))
+            .select(node_id)
+            .limit(2)
+            .load::<Option<i64>>(&self.conn)?;
+        if ids.len() == 1 {
+            Ok(ids[0].map(|id| id as u64))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The names from the top of the index down to `node`, separated by `/`.
+    fn node_path(&mut self, node: u64) -> Result<Vec<u8>, DieselError> {
+        use super::schema::key_tree::dsl::*;
+
+        let mut names = vec![];
+        let mut current = Some(node as i64);
+        while let Some(id) = current {
+            let (parent, node_name) = key_tree
+                .filter(node_id.eq(id))
+                .select((parent_id, name))
+                .first::<(Option<i64>, Vec<u8>)>(&self.conn)?;
+            names.push(node_name);
+            current = parent;
+        }
+        names.reverse();
+        Ok(names.join(&b'/'))
+    }
+
+    /// The paths of all entries, as names joined with `/`, with their size, modification time
+    /// and the hash of their data.
+    fn entries(&mut self) -> Result<Vec<db::PathRecord>, DieselError> {
+        let rows = schema::key_tree::table.load::<schema::KeyNode>(&self.conn)?;
+        let nodes: HashMap<i64, (Option<i64>, &[u8])> = rows.iter()
+            .filter_map(|row| row.node_id.map(|id| (id, (row.parent_id, &row.name[..]))))
+            .collect();
+        let data: HashMap<i64, (Option<i64>, Option<i64>, Option<Vec<u8>>)> = {
+            use super::schema::key_data::dsl::{byte_length, committed, key_data, modified,
+                                               node_id};
+            use super::schema::key_data::dsl::hash as data_hash;
+            key_data
+                .filter(committed.eq(true))
+                .select((node_id, byte_length, modified, data_hash))
+                .load::<(Option<i64>, Option<i64>, Option<i64>, Option<Vec<u8>>)>(&self.conn)?
+                .into_iter()
+                .filter_map(|(id, len, mtime, h)| id.map(|id| (id, (len, mtime, h))))
+                .collect()
+        };
+
+        // Rows only hold their own name, so a path is its parent's path plus that name. Paths are
+        // built once per directory and reused for everything below it, instead of walking up
+        // from every node, which is quadratic in the depth of the tree.
+        let mut paths: HashMap<i64, Vec<u8>> = HashMap::with_capacity(nodes.len());
+        for &id in nodes.keys() {
+            let mut pending = vec![];
+            let mut current = Some(id);
+            while let Some(node) = current {
+                if paths.contains_key(&node) {
+                    break;
+                }
+                match nodes.get(&node) {
+                    Some(&(parent, _)) => {
+                        pending.push(node);
+                        current = parent;
+                    }
+                    None => break,
+                }
+            }
+            while let Some(node) = pending.pop() {
+                let (parent, node_name) = nodes[&node];
+                let mut path = match parent.and_then(|p| paths.get(&p)) {
+                    Some(parent_path) => {
+                        let mut path = Vec::with_capacity(parent_path.len() + 1 + node_name.len());
+                        path.extend_from_slice(parent_path);
+                        path.push(b'/');
+                        path
+                    }
+                    None => Vec::with_capacity(node_name.len()),
+                };
+                path.extend_from_slice(node_name);
+                paths.insert(node, path);
+            }
+        }
+
+        let mut entries = vec![];
+        for (id, path) in paths {
+            let (len, mtime, h) = data.get(&id).cloned().unwrap_or((None, None, None));
+            entries.push(db::PathRecord {
+                path: path,
+                size: len.map(|l| l as u64),
+                modified: mtime.map(|m| m as u64),
+                hash: h,
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// The number of files and their total size for each recorded content type, including
+    /// files without one.
+    fn content_types(&mut self) -> Result<Vec<(Option<String>, u64, u64)>, DieselError> {
+        use super::schema::key_data::dsl::{byte_length, committed, content_type, key_data};
+        use super::schema::key_data::dsl::hash as data_hash;
+        let rows = key_data
+            .filter(committed.eq(true))
+            .filter(data_hash.is_not_null())
+            .select((content_type, byte_length))
+            .load::<(Option<String>, Option<i64>)>(&self.conn)?;
+
+        let mut types: BTreeMap<Option<String>, (u64, u64)> = BTreeMap::new();
+        for (t, len) in rows {
+            let counts = types.entry(t).or_insert((0, 0));
+            counts.0 += 1;
+            counts.1 += len.unwrap_or(0) as u64;
+        }
+        Ok(types.into_iter().map(|(t, (files, bytes))| (t, files, bytes)).collect())
+    }
+
+    /// Remember the words in the data with hash `hash_bytes`, until the snapshot is committed.
+    fn add_text_words(&mut self, hash_bytes: &[u8], words: &[String]) -> Result<(), DieselError> {
+        for w in words {
+            let new = schema::NewTextWord {
+                hash: hash_bytes,
+                word: &w[..],
+            };
+            diesel::insert(&new).into(schema::text_words::table).execute(&self.conn)?;
+        }
+        Ok(())
+    }
+
+    fn text_words(&mut self) -> Result<Vec<(Vec<u8>, String)>, DieselError> {
+        use super::schema::text_words::dsl::{text_words, word};
+        use super::schema::text_words::dsl::hash as word_hash;
+        text_words.select((word_hash, word)).load::<(Vec<u8>, String)>(&self.conn)
+    }
+
+    fn clear_text_words(&mut self) -> Result<(), DieselError> {
+        diesel::delete(schema::text_words::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Remember that `node` may have been moved from `source`. This is a rename if `source` is
+    /// gone once the snapshot is committed.
+    fn add_rename(&mut self, node: u64, source: u64) -> Result<(), DieselError> {
+        let path = self.node_path(source)?;
+        let new = schema::NewKeyRename {
+            node_id: node as i64,
+            source_id: source as i64,
+            source_path: &path[..],
+        };
+        diesel::insert(&new).into(schema::key_renames::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// The remembered moves whose source no longer exists, as paths from and to.
+    fn renames(&mut self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DieselError> {
+        use super::schema::key_tree::dsl::{key_tree, node_id};
+
+        let rows = schema::key_renames::table.load::<schema::KeyRename>(&self.conn)?;
+        let mut renames = vec![];
+        for row in rows {
+            let source_exists = key_tree
+                .filter(node_id.eq(row.source_id))
+                .select(node_id)
+                .first::<Option<i64>>(&self.conn)
+                .optional()?
+                .is_some();
+            if !source_exists {
+                renames.push((row.source_path, self.node_path(row.node_id as u64)?));
+            }
+        }
+        renames.sort();
+        Ok(renames)
+    }
+
+    fn clear_renames(&mut self) -> Result<(), DieselError> {
+        diesel::delete(schema::key_renames::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Remember that the data of `node` changed while it was read.
+    fn add_fuzzy_file(&mut self, node: u64) -> Result<(), DieselError> {
+        let new = schema::NewKeyFuzzyFile { node_id: node as i64 };
+        diesel::insert(&new).into(schema::key_fuzzy_files::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// The paths of the remembered files that changed while they were read, sorted.
+    fn fuzzy_files(&mut self) -> Result<Vec<Vec<u8>>, DieselError> {
+        let nodes = schema::key_fuzzy_files::table
+            .select(schema::key_fuzzy_files::node_id)
+            .load::<i64>(&self.conn)?;
+        let mut paths = vec![];
+        for node in nodes {
+            paths.push(self.node_path(node as u64)?);
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn clear_fuzzy_files(&mut self) -> Result<(), DieselError> {
+        diesel::delete(schema::key_fuzzy_files::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Remember the paths of `node` and everything below it, before they are deleted.
+    fn add_deletions(&mut self, node: u64) -> Result<(), DieselError> {
+        use super::schema::key_tree::dsl::*;
+
+        let mut pending = vec![node as i64];
+        while let Some(id) = pending.pop() {
+            let path_ = self.node_path(id as u64)?;
+            let new = schema::NewKeyDeletion { path: &path_[..] };
+            diesel::insert(&new).into(schema::key_deletions::table).execute(&self.conn)?;
+            let children = key_tree
+                .filter(parent_id.eq(id))
+                .select(node_id)
+                .load::<Option<i64>>(&self.conn)?;
+            pending.extend(children.into_iter().filter_map(|c| c));
+        }
+        Ok(())
+    }
+
+    /// The paths deleted since the remembered deletions were last cleared, sorted.
+    fn deletions(&mut self) -> Result<Vec<Vec<u8>>, DieselError> {
+        use super::schema::key_deletions::dsl::*;
+        Ok(key_deletions.select(path).order(path.asc()).load::<Vec<u8>>(&self.conn)?)
+    }
+
+    fn clear_deletions(&mut self) -> Result<(), DieselError> {
+        diesel::delete(schema::key_deletions::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn add_exclusion(&mut self, path_: &[u8], reason_: &str) -> Result<(), DieselError> {
+        let new = schema::NewKeyExclusion {
+            path: path_,
+            reason: reason_,
+        };
+        diesel::insert(&new).into(schema::key_exclusions::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// The directories excluded since the remembered exclusions were last cleared, sorted.
+    fn exclusions(&mut self) -> Result<Vec<(Vec<u8>, String)>, DieselError> {
+        use super::schema::key_exclusions::dsl::*;
+        Ok(key_exclusions
+            .select((path, reason))
+            .order(path.asc())
+            .load::<(Vec<u8>, String)>(&self.conn)?)
+    }
+
+    fn clear_exclusions(&mut self) -> Result<(), DieselError> {
+        diesel::delete(schema::key_exclusions::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    /// Remember how a command whose output was inserted ended. A later run of a command with
+    /// the same name replaces it.
+    fn add_command_run(&mut self, run: &db::CommandRun) -> Result<(), DieselError> {
+        let new = schema::NewKeyCommand {
+            name: &run.name,
+            command: &run.command,
+            exit_code: run.exit_code,
+            timed_out: run.timed_out,
+        };
+        diesel::insert(&new).into(schema::key_commands::table).execute(&self.conn)?;
+        Ok(())
+    }
+
+    fn command_runs(&mut self) -> Result<Vec<db::CommandRun>, DieselError> {
+        use super::schema::key_commands::dsl::*;
+
+        let rows = key_commands.order(name).load::<schema::KeyCommand>(&self.conn)?;
+        Ok(
+            rows.into_iter()
+                .map(|row| {
+                    db::CommandRun {
+                        name: row.name,
+                        command: row.command,
+                        exit_code: row.exit_code,
+                        timed_out: row.timed_out,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    fn clear_command_runs(&mut self) -> Result<(), DieselError> {
+        diesel::delete(schema::key_commands::table).execute(&self.conn)?;
+        Ok(())
+    }
 }
 
 impl KeyIndex {
@@ -506,6 +1025,11 @@ impl KeyIndex {
         InternalKeyIndex::new(migration_dir, name).map(|index| KeyIndex(Mutex::new(index)))
     }
 
+    /// Open the key index `name` for reading only, next to a writer if there is one.
+    pub fn new_read_only(name: &str) -> Result<KeyIndex, DieselError> {
+        InternalKeyIndex::new_read_only(name).map(|index| KeyIndex(Mutex::new(index)))
+    }
+
     #[cfg(test)]
     pub fn new_for_testing() -> Result<KeyIndex, DieselError> {
         KeyIndex::new(Path::new("migrations"), ":memory:")
@@ -515,6 +1039,11 @@ impl KeyIndex {
         self.0.lock().expect("index-process has failed")
     }
 
+    /// Whether a thread panicked while using the index.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+
     pub fn insert(
         &self,
         entry: Entry,
@@ -538,6 +1067,15 @@ impl KeyIndex {
         self.lock().list_dir(parent_opt)
     }
 
+    pub fn list_dir_page(
+        &self,
+        parent_opt: Option<u64>,
+        after: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Entry, Option<hash::tree::HashRef>)>, DieselError> {
+        self.lock().list_dir_page(parent_opt, after, limit)
+    }
+
     pub fn mark_reserved(&self, entry: &Entry) -> Result<(), DieselError> {
         self.lock().mark_reserved(entry)
     }
@@ -550,6 +1088,113 @@ impl KeyIndex {
         self.lock().cleanup_unused(parent_opt)
     }
 
+    pub fn dir_hash(&self, node: u64) -> Result<Option<hash::tree::HashRef>, DieselError> {
+        self.lock().dir_hash(node)
+    }
+
+    pub fn set_dir_hash(
+        &self,
+        node: u64,
+        dir_ref: &hash::tree::HashRef,
+    ) -> Result<(), DieselError> {
+        self.lock().set_dir_hash(node, dir_ref)
+    }
+
+    pub fn subtree_hashes(&self, node: u64) -> Result<Option<Vec<Vec<u8>>>, DieselError> {
+        self.lock().subtree_hashes(node)
+    }
+
+    pub fn find_moved(
+        &self,
+        entry: &Entry,
+    ) -> Result<Option<(u64, hash::tree::HashRef)>, DieselError> {
+        self.lock().find_moved(entry)
+    }
+
+    pub fn find_by_hash(&self, node: u64, hash_bytes: &[u8]) -> Result<Option<u64>, DieselError> {
+        self.lock().find_by_hash(node, hash_bytes)
+    }
+
+    pub fn add_rename(&self, node: u64, source: u64) -> Result<(), DieselError> {
+        self.lock().add_rename(node, source)
+    }
+
+    pub fn renames(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DieselError> {
+        self.lock().renames()
+    }
+
+    pub fn clear_renames(&self) -> Result<(), DieselError> {
+        self.lock().clear_renames()
+    }
+
+    pub fn add_fuzzy_file(&self, node: u64) -> Result<(), DieselError> {
+        self.lock().add_fuzzy_file(node)
+    }
+
+    pub fn fuzzy_files(&self) -> Result<Vec<Vec<u8>>, DieselError> {
+        self.lock().fuzzy_files()
+    }
+
+    pub fn clear_fuzzy_files(&self) -> Result<(), DieselError> {
+        self.lock().clear_fuzzy_files()
+    }
+
+    pub fn deletions(&self) -> Result<Vec<Vec<u8>>, DieselError> {
+        self.lock().deletions()
+    }
+
+    pub fn clear_deletions(&self) -> Result<(), DieselError> {
+        self.lock().clear_deletions()
+    }
+
+    pub fn add_exclusion(&self, path: &[u8], reason: &str) -> Result<(), DieselError> {
+        self.lock().add_exclusion(path, reason)
+    }
+
+    pub fn exclusions(&self) -> Result<Vec<(Vec<u8>, String)>, DieselError> {
+        self.lock().exclusions()
+    }
+
+    pub fn clear_exclusions(&self) -> Result<(), DieselError> {
+        self.lock().clear_exclusions()
+    }
+
+    pub fn add_command_run(&self, run: &db::CommandRun) -> Result<(), DieselError> {
+        self.lock().add_command_run(run)
+    }
+
+    pub fn command_runs(&self) -> Result<Vec<db::CommandRun>, DieselError> {
+        self.lock().command_runs()
+    }
+
+    pub fn clear_command_runs(&self) -> Result<(), DieselError> {
+        self.lock().clear_command_runs()
+    }
+
+    pub fn entries(&self) -> Result<Vec<(Vec<u8>, Option<u64>, Option<Vec<u8>>)>, DieselError> {
+        Ok(self.paths()?.into_iter().map(|r| (r.path, r.size, r.hash)).collect())
+    }
+
+    pub fn paths(&self) -> Result<Vec<db::PathRecord>, DieselError> {
+        self.lock().entries()
+    }
+
+    pub fn content_types(&self) -> Result<Vec<(Option<String>, u64, u64)>, DieselError> {
+        self.lock().content_types()
+    }
+
+    pub fn add_text_words(&self, hash_bytes: &[u8], words: &[String]) -> Result<(), DieselError> {
+        self.lock().add_text_words(hash_bytes, words)
+    }
+
+    pub fn text_words(&self) -> Result<Vec<(Vec<u8>, String)>, DieselError> {
+        self.lock().text_words()
+    }
+
+    pub fn clear_text_words(&self) -> Result<(), DieselError> {
+        self.lock().clear_text_words()
+    }
+
     pub fn flush(&self) -> Result<(), DieselError> {
         self.lock().flush()
     }
@@ -18,18 +18,26 @@
 use backend::StoreBackend;
 use blob;
 use crypto;
+use db;
 use errors::{DieselError, RetryError};
 use hash;
 use hash::tree::{LeafIterator, SimpleHashTreeWriter};
 use std::borrow::Cow;
+use std::cmp;
+use std::ffi::OsString;
 use std::io;
-use std::sync::Arc;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-use util::{FnBox, MsgHandler, Process};
+use util::{FnBox, MsgHandler, Process, ProcessError};
 
 mod schema;
+mod content_type;
 mod index;
 mod hash_store_backend;
+mod stats;
+mod text;
 
 #[cfg(test)]
 mod tests;
@@ -38,6 +46,8 @@ mod benchmarks;
 
 pub use self::hash_store_backend::HashStoreBackend;
 pub use self::index::{Data, Entry, Info, KeyIndex};
+pub use self::stats::IoStats;
+pub use self::text::DEFAULT_TEXT_INDEX_MAX_BYTES;
 
 
 error_type! {
@@ -56,11 +66,59 @@ error_type! {
         },
         Blob(blob::BlobError) {
             cause;
+        },
+        Process(ProcessError) {
+            cause;
         }
      }
 }
 
 
+/// Length of the chunks file data is split into, unless an entry asks for fixed blocks.
+pub const DEFAULT_CHUNK_LEN: usize = 128 * 1024;
+
+/// Length of fixed blocks, e.g. for disk and virtual machine images.
+pub const FIXED_BLOCK_LEN: usize = 1024 * 1024;
+
+/// File data read ahead so that its chunks can be looked up in the hash index together.
+const LOOKUP_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// How the data of an entry is split into chunks.
+///
+/// Chunks always start at multiples of their length from the start of the file, so that data
+/// that does not move within a file is deduplicated between snapshots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Chunking {
+    /// Chunks of `DEFAULT_CHUNK_LEN` bytes.
+    Default,
+    /// Chunks of exactly this many bytes (except for the last one).
+    FixedBlocks(usize),
+}
+
+/// The data of an entry, as read by the key store.
+pub trait DataSource: io::Read {
+    /// Whether the data may have changed while it was read, e.g. because the file was
+    /// modified. Asked once the data has been read to the end.
+    fn changed(&mut self) -> bool {
+        false
+    }
+
+    /// Start over from the beginning of the current data, for another attempt after a change.
+    /// Returns false if the data cannot be read again.
+    fn restart(&mut self) -> bool {
+        false
+    }
+}
+
+impl Chunking {
+    pub fn chunk_len(&self) -> usize {
+        match *self {
+            Chunking::Default => DEFAULT_CHUNK_LEN,
+            Chunking::FixedBlocks(len) => len,
+        }
+    }
+}
+
 pub type StoreProcess<IT, B> = Process<Msg<IT>, Reply<B>, MsgError>;
 
 pub type DirElem<B> = (Entry, Option<hash::tree::HashRef>, Option<HashTreeReaderInitializer<B>>);
@@ -83,13 +141,23 @@ impl<B: StoreBackend> HashTreeReaderInitializer<B> {
 pub enum Msg<IT> {
     /// Insert a key into the index. If this key has associated data a "chunk-iterator creator"
     /// can be passed along with it. If the data turns out to be unreadable, this iterator proc
-    /// can return `None`. Returns `Id` with the new entry ID.
-    Insert(Entry, Option<Box<FnBox<(), Option<IT>>>>),
+    /// can return `None`. The data is split into chunks as given by `Chunking`.
+    /// Returns `Id` with the new entry ID.
+    Insert(Entry, Option<Box<FnBox<(), Option<IT>>>>, Chunking),
+
+    /// Insert several keys, e.g. the entries of a directory, in one message.
+    /// Returns `Ids` with the new entry IDs, in the order of the keys.
+    InsertMany(Vec<(Entry, Option<Box<FnBox<(), Option<IT>>>>, Chunking)>),
 
     /// List a "directory" (aka. a `level`) in the index.
     /// Returns `ListResult` with all the entries under the given parent.
     ListDir(Option<u64>),
 
+    /// List at most `limit` entries of a "directory" in name order, starting after the entry
+    /// with the given name. Returns `ListPage` with the entries and the name to continue after,
+    /// if there may be more entries.
+    ListDirPage(Option<u64>, Option<Vec<u8>>, usize),
+
     /// Commit all reserved nodes and optionally execute recursive cleanup of part of the tree.
     /// Returns `Ok`.
     CommitReservedNodes(Option<Option<u64>>),
@@ -101,7 +169,9 @@ pub enum Msg<IT> {
 
 pub enum Reply<B> {
     Id(u64),
+    Ids(Vec<u64>),
     ListResult(Vec<DirElem<B>>),
+    ListPage(Vec<DirElem<B>>, Option<Vec<u8>>),
     Ok,
     FlushOk,
 }
@@ -111,6 +181,15 @@ pub struct Store<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    io_stats: Arc<IoStats>,
+    // Index the words of text files up to this size.
+    text_index_max_bytes: Option<usize>,
+    // Record the content type of the files that are read.
+    detect_content_types: bool,
+    // Read files that change while being read up to this many more times.
+    changed_retries: u32,
+    // Held from the first insert until the snapshot is registered with the GC.
+    epoch_pin: Arc<Mutex<Option<hash::EpochPin>>>,
 }
 impl<B> Clone for Store<B> {
     fn clone(&self) -> Store<B> {
@@ -119,6 +198,11 @@ impl<B> Clone for Store<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            io_stats: self.io_stats.clone(),
+            text_index_max_bytes: self.text_index_max_bytes,
+            detect_content_types: self.detect_content_types,
+            changed_retries: self.changed_retries,
+            epoch_pin: self.epoch_pin.clone(),
         }
     }
 }
@@ -136,9 +220,81 @@ impl<B: StoreBackend> Store<B> {
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            io_stats: Arc::new(IoStats::new()),
+            text_index_max_bytes: None,
+            detect_content_types: false,
+            changed_retries: 0,
+            epoch_pin: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Count the data inserted through this store in `stats`, e.g. to share the counters
+    /// between the key stores of a family.
+    pub fn with_io_stats(mut self, stats: Arc<IoStats>) -> Store<B> {
+        self.io_stats = stats;
+        self
+    }
+
+    /// Remember the words of text files of at most `max_bytes` that are read, so that they can
+    /// be found by content once the snapshot is committed.
+    pub fn with_text_index(mut self, max_bytes: Option<usize>) -> Store<B> {
+        self.text_index_max_bytes = max_bytes;
+        self
+    }
+
+    /// Record the content type of files that are read, as detected from their first chunk.
+    pub fn with_content_types(mut self, detect: bool) -> Store<B> {
+        self.detect_content_types = detect;
+        self
+    }
+
+    /// Read files that change while being read up to `retries` more times, before recording
+    /// them as changed with whatever was read last.
+    pub fn with_changed_retries(mut self, retries: u32) -> Store<B> {
+        self.changed_retries = retries;
+        self
+    }
+
+    /// Hold the GC epoch pin in `pin`, e.g. to share it between the key stores of a family, so
+    /// that releasing it covers everything they inserted.
+    pub fn with_epoch_pin(mut self, pin: Arc<Mutex<Option<hash::EpochPin>>>) -> Store<B> {
+        self.epoch_pin = pin;
+        self
+    }
+
+    pub fn io_stats(&self) -> &Arc<IoStats> {
+        &self.io_stats
+    }
+
+    /// Keep the GC from deleting what this store inserts or reuses until `release_epoch_pin`.
+    fn pin_epoch(&self) {
+        let mut pin = self.epoch_pin.lock().expect("Epoch pin mutex poisoned");
+        if pin.is_none() {
+            *pin = Some(self.hash_index.pin_epoch());
         }
     }
 
+    /// Let the GC consider what was inserted so far, once it is reachable from a registered
+    /// snapshot.
+    pub fn release_epoch_pin(&self) {
+        self.epoch_pin.lock().expect("Epoch pin mutex poisoned").take();
+    }
+
+    /// A store to take over from one whose handler panicked.
+    ///
+    /// The indexes and the epoch pin are shared with the other key stores of the family, so
+    /// they cannot be rebuilt; fails if the crash poisoned them. The chunks the crashed
+    /// handler left in the blob store are flushed, so the new store starts on a new blob.
+    pub fn restarted(&self) -> Result<Store<B>, MsgError> {
+        if self.index.is_poisoned() || self.hash_index.is_poisoned() ||
+            self.epoch_pin.is_poisoned()
+        {
+            return Err(From::from("An index was left inconsistent by the crash"));
+        }
+        self.blob_store.flush()?;
+        Ok(self.clone())
+    }
+
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<Store<B>, DieselError> {
         use crypto;
@@ -160,30 +316,436 @@ impl<B: StoreBackend> Store<B> {
             hash_index: hi_p,
             blob_store: bs_p,
             keys: Arc::new(crypto::keys::Keeper::new_for_testing()),
+            io_stats: Arc::new(IoStats::new()),
+            text_index_max_bytes: None,
+            detect_content_types: false,
+            changed_retries: 0,
+            epoch_pin: Arc::new(Mutex::new(None)),
         })
     }
 
     pub fn flush(&mut self) -> Result<(), MsgError> {
-        self.blob_store.flush();
+        self.blob_store.flush()?;
         self.hash_index.flush();
         self.index.flush()?;
 
         Ok(())
     }
 
+    fn hash_backend(&self) -> HashStoreBackend<B> {
+        HashStoreBackend::new(
+            self.hash_index.clone(),
+            self.blob_store.clone(),
+            self.keys.clone(),
+        ).with_io_stats(self.io_stats.clone())
+    }
+
     pub fn hash_tree_writer(
         &mut self,
         leaf: blob::LeafType,
     ) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
-        let backend = HashStoreBackend::new(
-            self.hash_index.clone(),
-            self.blob_store.clone(),
-            self.keys.clone(),
+        SimpleHashTreeWriter::new(leaf, 8, self.hash_backend())
+    }
+
+    /// The hash of directory `node` from an earlier commit, along with the file and directory
+    /// hashes below it, if nothing below the directory has changed since and its data is still
+    /// stored.
+    pub fn unchanged_dir_hash(
+        &self,
+        node: u64,
+    ) -> Result<Option<(hash::tree::HashRef, Vec<hash::Hash>)>, MsgError> {
+        self.pin_epoch();
+        let dir_ref = match self.index.dir_hash(node)? {
+            Some(dir_ref) => dir_ref,
+            None => return Ok(None),
+        };
+        if !self.hash_index.hash_exists(&dir_ref.hash) {
+            return Ok(None);
+        }
+        Ok(self.index.subtree_hashes(node)?.map(|hashes| {
+            let hashes = hashes.into_iter().map(|bytes| hash::Hash { bytes: bytes }).collect();
+            (dir_ref, hashes)
+        }))
+    }
+
+    /// Remember the committed hash of directory `node` for `unchanged_dir_hash`.
+    pub fn set_dir_hash(&self, node: u64, dir_ref: &hash::tree::HashRef) -> Result<(), MsgError> {
+        self.index.set_dir_hash(node, dir_ref)?;
+        Ok(())
+    }
+
+    /// Files moved since the last commit, as paths from and to, sorted by the old path.
+    /// A file counts as moved if a new path has the inode, size and modification time or the
+    /// data of a path that is gone by now.
+    pub fn renames(&self) -> Result<Vec<(PathBuf, PathBuf)>, MsgError> {
+        let to_path = |bytes: Vec<u8>| PathBuf::from(OsString::from_vec(bytes));
+        Ok(
+            self.index
+                .renames()?
+                .into_iter()
+                .map(|(from, to)| (to_path(from), to_path(to)))
+                .collect(),
+        )
+    }
+
+    /// Forget the moves reported by `renames`, once they are recorded with a snapshot.
+    pub fn clear_renames(&self) -> Result<(), MsgError> {
+        self.index.clear_renames()?;
+        Ok(())
+    }
+
+    /// Files whose data changed while they were read since the last commit, sorted. Their
+    /// stored data may mix old and new contents.
+    pub fn fuzzy_files(&self) -> Result<Vec<PathBuf>, MsgError> {
+        Ok(
+            self.index
+                .fuzzy_files()?
+                .into_iter()
+                .map(|bytes| PathBuf::from(OsString::from_vec(bytes)))
+                .collect(),
+        )
+    }
+
+    /// Forget the files reported by `fuzzy_files`, once they are recorded with a snapshot.
+    pub fn clear_fuzzy_files(&self) -> Result<(), MsgError> {
+        self.index.clear_fuzzy_files()?;
+        Ok(())
+    }
+
+    /// Paths deleted since the last commit, sorted. Everything below a deleted directory is
+    /// listed along with it.
+    pub fn deletions(&self) -> Result<Vec<PathBuf>, MsgError> {
+        Ok(
+            self.index
+                .deletions()?
+                .into_iter()
+                .map(|bytes| PathBuf::from(OsString::from_vec(bytes)))
+                .collect(),
+        )
+    }
+
+    /// Forget the paths reported by `deletions`, once they are recorded with a snapshot.
+    pub fn clear_deletions(&self) -> Result<(), MsgError> {
+        self.index.clear_deletions()?;
+        Ok(())
+    }
+
+    /// Remember that the directory at `path` was left out of the snapshot, and why, to keep
+    /// with the next snapshot. Paths are names joined with `/`, like those of `paths`.
+    pub fn add_exclusion(&self, path: &[u8], reason: &str) -> Result<(), MsgError> {
+        self.index.add_exclusion(path, reason)?;
+        Ok(())
+    }
+
+    /// Directories left out since the last commit, sorted, with the reason they were.
+    pub fn exclusions(&self) -> Result<Vec<(PathBuf, String)>, MsgError> {
+        Ok(
+            self.index
+                .exclusions()?
+                .into_iter()
+                .map(|(bytes, reason)| (PathBuf::from(OsString::from_vec(bytes)), reason))
+                .collect(),
+        )
+    }
+
+    /// Forget the directories reported by `exclusions`, once they are recorded with a snapshot.
+    pub fn clear_exclusions(&self) -> Result<(), MsgError> {
+        self.index.clear_exclusions()?;
+        Ok(())
+    }
+
+    /// Record how a command whose output was inserted ended, to keep with the next snapshot.
+    pub fn add_command_run(&self, run: &db::CommandRun) -> Result<(), MsgError> {
+        self.index.add_command_run(run)?;
+        Ok(())
+    }
+
+    /// The commands run since the last commit, by name.
+    pub fn command_runs(&self) -> Result<Vec<db::CommandRun>, MsgError> {
+        Ok(self.index.command_runs()?)
+    }
+
+    /// Forget the runs reported by `command_runs`, once they are recorded with a snapshot.
+    pub fn clear_command_runs(&self) -> Result<(), MsgError> {
+        self.index.clear_command_runs()?;
+        Ok(())
+    }
+
+    /// The paths of all entries in the index, as names joined with `/`, with their size,
+    /// modification time and the hash of their data.
+    pub fn paths(&self) -> Result<Vec<db::PathRecord>, MsgError> {
+        Ok(self.index.paths()?)
+    }
+
+    /// The paths of all entries in the index, with their size and the hash of their data.
+    pub fn entries(&self) -> Result<Vec<(Vec<u8>, Option<u64>, Option<Vec<u8>>)>, MsgError> {
+        Ok(self.index.entries()?)
+    }
+
+    /// The number of files and their total size by content type.
+    pub fn content_types(&self) -> Result<Vec<(Option<String>, u64, u64)>, MsgError> {
+        Ok(self.index.content_types()?)
+    }
+
+    /// The words found in text files since the last `clear_text_words`, by data hash.
+    pub fn text_words(&self) -> Result<Vec<(Vec<u8>, String)>, MsgError> {
+        Ok(self.index.text_words()?)
+    }
+
+    pub fn clear_text_words(&self) -> Result<(), MsgError> {
+        self.index.clear_text_words()?;
+        Ok(())
+    }
+
+    /// Attach data readers to the listed entries of a directory.
+    fn dir_elems(&self, entries: Vec<(Entry, Option<hash::tree::HashRef>)>) -> Vec<DirElem<B>> {
+        let mut my_entries: Vec<DirElem<B>> = Vec::with_capacity(entries.len());
+        for (entry, hash_ref_opt) in entries {
+            let hash_ref = hash_ref_opt.or_else(|| match entry.data {
+                Data::FileHash(ref hash_bytes) => {
+                    let h = hash::Hash { bytes: hash_bytes.clone() };
+                    self.hash_index.fetch_hash_ref(&h).expect("Unknown hash")
+                }
+                _ => None,
+            });
+            let open_fn = hash_ref.as_ref().map(|r| {
+                HashTreeReaderInitializer {
+                    hash_ref: r.clone(),
+                    hash_index: self.hash_index.clone(),
+                    blob_store: self.blob_store.clone(),
+                    keys: self.keys.clone(),
+                }
+            });
+
+            my_entries.push((entry, hash_ref, open_fn));
+        }
+        my_entries
+    }
+
+    /// Insert `insert_entry` along with the data read by the iterator from `chunk_it_opt`.
+    fn insert_entry<IT: DataSource>(
+        &mut self,
+        insert_entry: Entry,
+        chunk_it_opt: Option<Box<FnBox<(), Option<IT>>>>,
+        chunking: Chunking,
+    ) -> Result<u64, MsgError> {
+        self.pin_epoch();
+        let mut entry = match self.index.lookup(
+            insert_entry.parent_id,
+            insert_entry.info.name.clone(),
+        )? {
+            Some(ref stored_entry) if insert_entry.data_looks_unchanged(stored_entry) => {
+                match &stored_entry.data {
+                    &Data::FileHash(ref hash_bytes) if chunk_it_opt.is_some() => {
+                        let hash = hash::Hash { bytes: hash_bytes.to_vec() };
+                        if self.hash_index.hash_exists(&hash) {
+                            // Short-circuit: We have the data.
+                            debug!("Skip entry: {:?}", stored_entry.info.name);
+                            self.index.mark_reserved(&stored_entry)?;
+                            self.io_stats.add_unchanged_file();
+                            return Ok(stored_entry.node_id.unwrap());
+                        }
+                    }
+                    _ if chunk_it_opt.is_none() => {
+                        // Short-circuit: No data needed.
+                        debug!("Skip empty entry: {:?}", stored_entry.info.name);
+                        self.index.mark_reserved(&stored_entry)?;
+                        return Ok(stored_entry.node_id.unwrap());
+                    }
+                    _ => (),
+                }
+                // Our stored entry is incomplete.
+                Entry {
+                    node_id: stored_entry.node_id,
+                    ..insert_entry
+                }
+            }
+            Some(entry) => {
+                Entry {
+                    node_id: entry.node_id,
+                    ..insert_entry
+                }
+            }
+            None => insert_entry,
+        };
+
+        // A file that was moved here keeps its data, so there is no need to read it again.
+        if entry.node_id.is_none() && chunk_it_opt.is_some() {
+            if let Some((source, hash_ref)) = self.index.find_moved(&entry)? {
+                if self.hash_index.hash_exists(&hash_ref.hash) {
+                    debug!("Moved entry: {:?}", entry.info.name);
+                    let entry = self.index.insert(entry, Some(&hash_ref))?;
+                    let node = entry.node_id.unwrap();
+                    self.index.add_rename(node, source)?;
+                    self.io_stats.add_unchanged_file();
+                    return Ok(node);
+                }
+            }
+        }
+
+        // Stop before reading data that would not fit in the repository.
+        if let (true, Some(size)) = (chunk_it_opt.is_some(), entry.info.byte_length) {
+            if let Err(e) = self.blob_store.check_space(size) {
+                return Err(From::from(e));
+            }
+        }
+
+        // Check if we have an data source:
+        let has_data = chunk_it_opt.is_some();
+        let it_opt = chunk_it_opt.and_then(|open| open.call(()));
+        if it_opt.is_none() {
+            if has_data {
+                // The file could not be opened.
+                self.io_stats.add_skipped_files(1);
+            }
+            // No data is associated with this entry.
+            debug!("Insert entry: {:?}", entry.info.name);
+            let entry = self.index.insert(entry, None)?;
+
+            // Bail out before storing data that does not exist:
+            return Ok(entry.node_id.unwrap());
+        }
+        self.io_stats.add_new_file();
+
+        // Read the data, again from the start if it changes while being read and there are
+        // retries left. Chunks stored by an earlier attempt are collected as garbage later.
+        let mut reader = it_opt.unwrap();
+        let mut retries = self.changed_retries;
+        let mut data = self.read_data(&mut reader, chunking, &entry)?;
+        let mut fuzzy = reader.changed();
+        while fuzzy && retries > 0 && reader.restart() {
+            debug!("Reading changed file again: {:?}", entry.info.name);
+            retries -= 1;
+            data = self.read_data(&mut reader, chunking, &entry)?;
+            fuzzy = reader.changed();
+        }
+        if fuzzy {
+            println!("Warning: File changed while reading it: {:?}", entry.info.name);
+        }
+        let (mut tree, file_len, text_data, content_type) = data;
+        if self.detect_content_types {
+            entry.info.content_type = content_type.map(|t| t.to_owned());
+        }
+
+        // Warn the user if we did not read the expected size:
+        entry.info.byte_length.map(|s| {
+            file_size_warning(&entry.info.name, s, file_len);
+        });
+
+        // Get top tree hash:
+        let hash_ref = tree.hash(Some(&entry.info))?;
+
+        // It is OK that this has is not yet valid, as we check hashes at snapshot time.
+        debug!("Insert entry: {:?}", entry.info.name);
+        let is_new = entry.node_id.is_none();
+        let entry = self.index.insert(entry, Some(&hash_ref))?;
+        let node = entry.node_id.unwrap();
+        if fuzzy {
+            self.index.add_fuzzy_file(node)?;
+            self.io_stats.add_fuzzy_file();
+        }
+
+        // New data at a new path may have been moved here from elsewhere.
+        if is_new {
+            if let Some(source) = self.index.find_by_hash(node, &hash_ref.hash.bytes)? {
+                self.index.add_rename(node, source)?;
+            }
+        }
+
+        if let Some(words) = text_data.and_then(|data| text::words(&data[..])) {
+            self.index.add_text_words(&hash_ref.hash.bytes, &words)?;
+        }
+
+        Ok(node)
+    }
+
+    /// Read and store all data from `reader`, a batch of chunks at a time so that the chunks
+    /// that are already stored are looked up together (see `HashStoreBackend::prefetch`).
+    /// Returns the tree of the data, its length, the data itself if its words should be
+    /// indexed and the content type detected from the first chunk.
+    fn read_data<IT: DataSource>(
+        &mut self,
+        reader: &mut IT,
+        chunking: Chunking,
+        entry: &Entry,
+    ) -> Result<ReadData<B>, MsgError> {
+        let backend = self.hash_backend();
+        let mut tree = SimpleHashTreeWriter::new(
+            blob::LeafType::FileChunk,
+            8,
+            backend.clone(),
         );
-        SimpleHashTreeWriter::new(leaf, 8, backend)
+
+        let max_chunk_len = chunking.chunk_len();
+        let batch_len = cmp::max(1, LOOKUP_BATCH_BYTES / max_chunk_len);
+        let mut file_len = 0u64;
+        let mut eof = false;
+        let mut content_type = None;
+        // The data of small files, for indexing their words.
+        let text_max = self.text_index_max_bytes;
+        let mut text_data = match (text_max, entry.info.byte_length) {
+            (Some(max), Some(len)) if len > max as u64 => None,
+            (Some(_), _) => Some(vec![]),
+            (None, _) => None,
+        };
+        while !eof {
+            let mut batch = vec![];
+            while !eof && batch.len() < batch_len {
+                let mut chunk = vec![0; max_chunk_len];
+                let mut chunk_len = 0;
+                while chunk_len < max_chunk_len {
+                    chunk_len += match reader.read(&mut chunk[chunk_len..]) {
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => size,
+                    }
+                }
+                if chunk_len == 0 {
+                    eof = true;
+                } else {
+                    chunk.truncate(chunk_len);
+                    batch.push(chunk);
+                }
+            }
+
+            let hashes: Vec<_> = batch
+                .iter()
+                .map(|chunk| {
+                    hash::Hash::new(
+                        &self.keys,
+                        blob::NodeType::Leaf,
+                        blob::LeafType::FileChunk,
+                        chunk,
+                    )
+                })
+                .collect();
+            backend.prefetch(&hashes);
+            for chunk in batch {
+                if file_len == 0 && self.detect_content_types {
+                    content_type = content_type::sniff(&chunk[..]);
+                }
+                file_len += chunk.len() as u64;
+                if text_data.is_some() && file_len > text_max.unwrap_or(0) as u64 {
+                    text_data = None;
+                }
+                if let Some(ref mut data) = text_data {
+                    data.extend_from_slice(&chunk[..]);
+                }
+                tree.append(&chunk[..])?
+            }
+        }
+        Ok((tree, file_len, text_data, content_type))
     }
 }
 
+// The tree, length, text data and content type of what was read from a file.
+type ReadData<B> = (
+    SimpleHashTreeWriter<HashStoreBackend<B>>,
+    u64,
+    Option<Vec<u8>>,
+    Option<&'static str>,
+);
+
 fn file_size_warning(name: &[u8], wanted: u64, got: u64) {
     if wanted < got {
         println!(
@@ -202,7 +764,7 @@ fn file_size_warning(name: &[u8], wanted: u64, got: u64) {
     }
 }
 
-impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
+impl<IT: DataSource, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
     type Err = MsgError;
 
     fn handle<F: FnOnce(Result<Reply<B>, MsgError>)>(
@@ -228,33 +790,21 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
 
             Msg::ListDir(parent) => {
                 match self.index.list_dir(parent) {
-                    Ok(entries) => {
-                        let mut my_entries: Vec<DirElem<B>> = Vec::with_capacity(entries.len());
-                        for (entry, hash_ref_opt) in entries {
-                            let hash_ref = hash_ref_opt.or_else(|| match entry.data {
-                                Data::FileHash(ref hash_bytes) => {
-                                    let h = hash::Hash { bytes: hash_bytes.clone() };
-                                    self.hash_index.fetch_hash_ref(&h).expect("Unknown hash")
-                                }
-                                _ => None,
-                            });
-                            let open_fn = hash_ref.as_ref().map(|r| {
-                                HashTreeReaderInitializer {
-                                    hash_ref: r.clone(),
-                                    hash_index: self.hash_index.clone(),
-                                    blob_store: self.blob_store.clone(),
-                                    keys: self.keys.clone(),
-                                }
-                            });
-
-                            my_entries.push((entry, hash_ref, open_fn));
-                        }
-                        reply_ok!(Reply::ListResult(my_entries))
-                    }
+                    Ok(entries) => reply_ok!(Reply::ListResult(self.dir_elems(entries))),
                     Err(e) => reply_err!(From::from(e)),
                 }
             }
 
+            Msg::ListDirPage(parent, after, limit) => {
+                let entries = self.index.list_dir_page(parent, after, limit)?;
+                let next = if entries.len() < limit {
+                    None
+                } else {
+                    entries.last().map(|&(ref entry, _)| entry.info.name.clone())
+                };
+                reply_ok!(Reply::ListPage(self.dir_elems(entries), next))
+            }
+
             Msg::CommitReservedNodes(clean_parent_opt) => {
                 self.index.commit_reserved_nodes()?;
                 if let Some(parent) = clean_parent_opt {
@@ -263,94 +813,17 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 return reply_ok!(Reply::Ok);
             }
 
-            Msg::Insert(insert_entry, chunk_it_opt) => {
-                let entry = match self.index.lookup(
-                    insert_entry.parent_id,
-                    insert_entry.info.name.clone(),
-                )? {
-                    Some(ref stored_entry) if insert_entry.data_looks_unchanged(stored_entry) => {
-                        match &stored_entry.data {
-                            &Data::FileHash(ref hash_bytes) if chunk_it_opt.is_some() => {
-                                let hash = hash::Hash { bytes: hash_bytes.to_vec() };
-                                if self.hash_index.hash_exists(&hash) {
-                                    // Short-circuit: We have the data.
-                                    debug!("Skip entry: {:?}", stored_entry.info.name);
-                                    self.index.mark_reserved(&stored_entry)?;
-                                    return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
-                                }
-                            }
-                            _ if chunk_it_opt.is_none() => {
-                                // Short-circuit: No data needed.
-                                debug!("Skip empty entry: {:?}", stored_entry.info.name);
-                                self.index.mark_reserved(&stored_entry)?;
-                                return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
-                            }
-                            _ => (),
-                        }
-                        // Our stored entry is incomplete.
-                        Entry {
-                            node_id: stored_entry.node_id,
-                            ..insert_entry
-                        }
-                    }
-                    Some(entry) => {
-                        Entry {
-                            node_id: entry.node_id,
-                            ..insert_entry
-                        }
-                    }
-                    None => insert_entry,
-                };
-
-                // Check if we have an data source:
-                let it_opt = chunk_it_opt.and_then(|open| open.call(()));
-                if it_opt.is_none() {
-                    // No data is associated with this entry.
-                    debug!("Insert entry: {:?}", entry.info.name);
-                    let entry = self.index.insert(entry, None)?;
-
-                    // Bail out before storing data that does not exist:
-                    return reply_ok!(Reply::Id(entry.node_id.unwrap()));
-                }
-
-                // Setup hash tree structure
-                let mut tree = self.hash_tree_writer(blob::LeafType::FileChunk);
+            Msg::Insert(entry, chunk_it_opt, chunking) => {
+                let id = self.insert_entry(entry, chunk_it_opt, chunking)?;
+                reply_ok!(Reply::Id(id))
+            }
 
-                // Read and insert all file chunks:
-                // (see HashStoreBackend::insert_chunk above)
-                let max_chunk_len = 128 * 1024;
-                let mut chunk = vec![0; max_chunk_len];
-                let mut reader = it_opt.unwrap();
-                let mut file_len = 0u64;
-                loop {
-                    let mut chunk_len = 0;
-                    while chunk_len < max_chunk_len {
-                        chunk_len += match reader.read(&mut chunk[chunk_len..]) {
-                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                            Ok(0) | Err(_) => break,
-                            Ok(size) => size,
-                        }
-                    }
-                    if chunk_len == 0 {
-                        break;
-                    }
-                    file_len += chunk_len as u64;
-                    tree.append(&chunk[..chunk_len])?
+            Msg::InsertMany(inserts) => {
+                let mut ids = Vec::with_capacity(inserts.len());
+                for (entry, chunk_it_opt, chunking) in inserts {
+                    ids.push(self.insert_entry(entry, chunk_it_opt, chunking)?);
                 }
-
-                // Warn the user if we did not read the expected size:
-                entry.info.byte_length.map(|s| {
-                    file_size_warning(&entry.info.name, s, file_len);
-                });
-
-                // Get top tree hash:
-                let hash_ref = tree.hash(Some(&entry.info))?;
-
-                // It is OK that this has is not yet valid, as we check hashes at snapshot time.
-                debug!("Insert entry: {:?}", entry.info.name);
-                let entry = self.index.insert(entry, Some(&hash_ref))?;
-
-                return reply_ok!(Reply::Id(entry.node_id.unwrap()));
+                reply_ok!(Reply::Ids(ids))
             }
         }
     }
@@ -42,6 +42,64 @@ table! {
 
         hash -> Nullable<Binary>,
         hash_ref -> Nullable<Binary>,
+
+        capabilities -> Nullable<Binary>,
+        attribute_flags -> Nullable<BigInt>,
+
+        inode -> Nullable<BigInt>,
+        byte_length -> Nullable<BigInt>,
+
+        content_type -> Nullable<VarChar>,
+    }
+}
+
+table! {
+    key_renames (node_id) {
+        node_id -> BigInt,
+        source_id -> BigInt,
+        source_path -> Binary,
+    }
+}
+
+table! {
+    key_fuzzy_files (node_id) {
+        node_id -> BigInt,
+    }
+}
+
+table! {
+    key_deletions (path) {
+        path -> Binary,
+    }
+}
+
+table! {
+    key_exclusions (path) {
+        path -> Binary,
+        reason -> Text,
+    }
+}
+
+table! {
+    key_commands (name) {
+        name -> Text,
+        command -> Text,
+        exit_code -> Nullable<Integer>,
+        timed_out -> Bool,
+    }
+}
+
+table! {
+    dir_hashes (node_id) {
+        node_id -> BigInt,
+        hash_ref -> Binary,
+    }
+}
+
+table! {
+    text_words (word, hash) {
+        hash -> Binary,
+        word -> Text,
     }
 }
 
@@ -82,6 +140,14 @@ pub struct KeyData {
 
     pub hash: Option<Vec<u8>>,
     pub hash_ref: Option<Vec<u8>>,
+
+    pub capabilities: Option<Vec<u8>>,
+    pub attribute_flags: Option<i64>,
+
+    pub inode: Option<i64>,
+    pub byte_length: Option<i64>,
+
+    pub content_type: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -103,4 +169,77 @@ pub struct NewKeyData<'a> {
 
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
+
+    pub capabilities: Option<&'a [u8]>,
+    pub attribute_flags: Option<i64>,
+
+    pub inode: Option<i64>,
+    pub byte_length: Option<i64>,
+
+    pub content_type: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[table_name = "dir_hashes"]
+pub struct NewDirHash<'a> {
+    pub node_id: i64,
+    pub hash_ref: &'a [u8],
+}
+
+#[derive(Queryable)]
+pub struct KeyRename {
+    pub node_id: i64,
+    pub source_id: i64,
+    pub source_path: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name = "key_renames"]
+pub struct NewKeyRename<'a> {
+    pub node_id: i64,
+    pub source_id: i64,
+    pub source_path: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "key_fuzzy_files"]
+pub struct NewKeyFuzzyFile {
+    pub node_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "key_deletions"]
+pub struct NewKeyDeletion<'a> {
+    pub path: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "key_exclusions"]
+pub struct NewKeyExclusion<'a> {
+    pub path: &'a [u8],
+    pub reason: &'a str,
+}
+
+#[derive(Queryable)]
+pub struct KeyCommand {
+    pub name: String,
+    pub command: String,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "key_commands"]
+pub struct NewKeyCommand<'a> {
+    pub name: &'a str,
+    pub command: &'a str,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "text_words"]
+pub struct NewTextWord<'a> {
+    pub hash: &'a [u8],
+    pub word: &'a str,
 }
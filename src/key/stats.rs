@@ -0,0 +1,105 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Counters for the data handled by a snapshot run.
+
+use db::SnapshotStats;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+
+/// Counts what the key stores of a family read and store, shared between them.
+pub struct IoStats {
+    bytes_read: AtomicUsize,
+    bytes_uploaded: AtomicUsize,
+    new_chunks: AtomicUsize,
+    reused_chunks: AtomicUsize,
+    new_files: AtomicUsize,
+    unchanged_files: AtomicUsize,
+    skipped_files: AtomicUsize,
+    fuzzy_files: AtomicUsize,
+    started: Mutex<Instant>,
+}
+
+impl IoStats {
+    pub fn new() -> IoStats {
+        IoStats {
+            bytes_read: AtomicUsize::new(0),
+            bytes_uploaded: AtomicUsize::new(0),
+            new_chunks: AtomicUsize::new(0),
+            reused_chunks: AtomicUsize::new(0),
+            new_files: AtomicUsize::new(0),
+            unchanged_files: AtomicUsize::new(0),
+            skipped_files: AtomicUsize::new(0),
+            fuzzy_files: AtomicUsize::new(0),
+            started: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn add_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a chunk that had to be stored, taking up `bytes` in its blob.
+    pub fn add_new_chunk(&self, bytes: usize) {
+        self.new_chunks.fetch_add(1, Ordering::Relaxed);
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_reused_chunk(&self) {
+        self.reused_chunks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a file whose data is read, because it is new or has changed.
+    pub fn add_new_file(&self) {
+        self.new_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a file that keeps its data from an earlier snapshot.
+    pub fn add_unchanged_file(&self) {
+        self.unchanged_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count files that were left out because they could not be read.
+    pub fn add_skipped_files(&self, files: usize) {
+        self.skipped_files.fetch_add(files, Ordering::Relaxed);
+    }
+
+    /// Count a file that changed while it was read.
+    pub fn add_fuzzy_file(&self) {
+        self.fuzzy_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The totals since the previous call, or since creation.
+    pub fn take(&self) -> SnapshotStats {
+        let mut started = self.started.lock().unwrap();
+        let elapsed = started.elapsed();
+        *started = Instant::now();
+
+        let take = |counter: &AtomicUsize| counter.swap(0, Ordering::Relaxed) as u64;
+        SnapshotStats {
+            bytes_read: take(&self.bytes_read),
+            bytes_uploaded: take(&self.bytes_uploaded),
+            new_chunks: take(&self.new_chunks),
+            reused_chunks: take(&self.reused_chunks),
+            new_files: take(&self.new_files),
+            unchanged_files: take(&self.unchanged_files),
+            skipped_files: take(&self.skipped_files),
+            fuzzy_files: take(&self.fuzzy_files),
+            wall_time_ms: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos()) / 1_000_000,
+        }
+    }
+}
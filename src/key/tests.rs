@@ -22,7 +22,7 @@ use rand::Rng;
 use rand::thread_rng;
 use std::io;
 use std::sync::Arc;
-use util::Process;
+use util::{FnBox, Process};
 
 fn random_ascii_bytes() -> Vec<u8> {
     let ascii: String = thread_rng().gen_ascii_chars().take(32).collect();
@@ -40,7 +40,12 @@ impl io::Read for EntryStub {
         match self.data.as_mut() {
             Some(x) => {
                 if !x.is_empty() {
-                    let c = x.remove(0);
+                    let mut c = x.remove(0);
+                    if c.len() > buf.len() {
+                        // Keep what does not fit for the next read.
+                        let rest = c.split_off(buf.len());
+                        x.insert(0, rest);
+                    }
                     buf[..c.len()].copy_from_slice(&c[..]);
                     Ok(c.len())
                 } else {
@@ -52,6 +57,8 @@ impl io::Read for EntryStub {
     }
 }
 
+impl DataSource for EntryStub {}
+
 #[derive(Clone, Debug)]
 struct FileSystem {
     file: EntryStub,
@@ -105,6 +112,10 @@ fn rng_filesystem(size: usize) -> FileSystem {
                         group_id: None,
 
                         hat_snapshot_ts: 0,
+                        capabilities: Some(random_ascii_bytes()),
+                        attribute_flags: thread_rng().gen(),
+                        inode: None,
+                        content_type: Some("text/plain".to_owned()),
                     },
                 },
             };
@@ -134,6 +145,10 @@ fn rng_filesystem(size: usize) -> FileSystem {
                 group_id: None,
                 byte_length: None,
                 hat_snapshot_ts: 0,
+                capabilities: None,
+                attribute_flags: None,
+                inode: None,
+                content_type: None,
             },
         },
     };
@@ -153,6 +168,7 @@ fn insert_and_update_fs<B: StoreBackend>(fs: &mut FileSystem, ks_p: &StoreProces
         } else {
             None
         },
+        Chunking::Default,
     )).unwrap() {
         Reply::Id(id) => Some(id),
         _ => panic!("unexpected reply from key store"),
@@ -193,6 +209,12 @@ fn verify_filesystem<B: StoreBackend>(fs: &FileSystem, ks_p: &StoreProcess<Entry
                     dir.file.key_entry.info.modified_ts_secs,
                     entry.info.modified_ts_secs
                 );
+                assert_eq!(dir.file.key_entry.info.capabilities, entry.info.capabilities);
+                assert_eq!(
+                    dir.file.key_entry.info.attribute_flags,
+                    entry.info.attribute_flags
+                );
+                assert_eq!(dir.file.key_entry.info.content_type, entry.info.content_type);
 
                 match dir.file.data {
                     Some(ref original) => {
@@ -255,3 +277,192 @@ fn identity() {
     }
     quickcheck::quickcheck(prop as fn(u8) -> bool);
 }
+
+#[test]
+fn fixed_blocks_are_aligned() {
+    let backend = Arc::new(MemoryBackend::new());
+    let ks_p = Process::new(Store::new_for_testing(backend, 4 * 1024 * 1024).unwrap());
+
+    // Two and a half blocks, read in pieces that do not line up with the blocks.
+    let piece = 100 * 1024;
+    let total = FIXED_BLOCK_LEN * 5 / 2;
+    let mut pieces = vec![];
+    for i in 0..(total + piece - 1) / piece {
+        let len = ::std::cmp::min(piece, total - i * piece);
+        pieces.push(vec![i as u8; len]);
+    }
+    let file = EntryStub {
+        data: Some(pieces),
+        key_entry: Entry::new(None, b"disk.img".to_vec(), Data::FilePlaceholder, None),
+    };
+
+    let local_file = file.clone();
+    match ks_p.send_reply(Msg::Insert(
+        file.key_entry.clone(),
+        Some(Box::new(move |()| Some(local_file))),
+        Chunking::FixedBlocks(FIXED_BLOCK_LEN),
+    )).unwrap() {
+        Reply::Id(_) => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+
+    let listing = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls,
+        _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(1, listing.len());
+    let (_, _, tree_data) = listing.into_iter().next().unwrap();
+    let lens: Vec<usize> = tree_data
+        .expect("has data")
+        .init()
+        .unwrap()
+        .expect("has chunks")
+        .map(|chunk| chunk.len())
+        .collect();
+    assert_eq!(vec![FIXED_BLOCK_LEN, FIXED_BLOCK_LEN, FIXED_BLOCK_LEN / 2], lens);
+}
+
+#[test]
+fn insert_many_returns_ids_in_order() {
+    let backend = Arc::new(MemoryBackend::new());
+    let ks_p = Process::new(Store::new_for_testing(backend, 1024).unwrap());
+
+    let names: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+    let inserts = names
+        .iter()
+        .map(|name| {
+            let file = EntryStub {
+                data: Some(vec![name.to_vec()]),
+                key_entry: Entry::new(None, name.to_vec(), Data::FilePlaceholder, None),
+            };
+            let open: Box<FnBox<(), Option<EntryStub>>> = Box::new(move |()| Some(file));
+            (
+                Entry::new(None, name.to_vec(), Data::FilePlaceholder, None),
+                Some(open),
+                Chunking::Default,
+            )
+        })
+        .collect();
+    let ids = match ks_p.send_reply(Msg::InsertMany(inserts)).unwrap() {
+        Reply::Ids(ids) => ids,
+        _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(3, ids.len());
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+
+    let listing = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls,
+        _ => panic!("Unexpected result from key store."),
+    };
+    assert_eq!(3, listing.len());
+    for (entry, _, _) in listing {
+        let pos = names.iter().position(|n| &n[..] == &entry.info.name[..]).unwrap();
+        assert_eq!(Some(ids[pos]), entry.node_id);
+    }
+}
+
+#[test]
+fn list_dir_in_pages() {
+    let backend = Arc::new(MemoryBackend::new());
+    let ks_p: StoreProcess<EntryStub, MemoryBackend> =
+        Process::new(Store::new_for_testing(backend, 1024).unwrap());
+
+    for i in 0..5 {
+        let name = format!("{}", i).into_bytes();
+        let entry = Entry::new(None, name, Data::DirPlaceholder, None);
+        match ks_p.send_reply(Msg::Insert(entry, None, Chunking::Default)).unwrap() {
+            Reply::Id(_) => (),
+            _ => panic!("Unexpected result from key store."),
+        }
+    }
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+
+    let mut pages = vec![];
+    let mut after = None;
+    loop {
+        match ks_p.send_reply(Msg::ListDirPage(None, after, 2)).unwrap() {
+            Reply::ListPage(page, next) => {
+                pages.push(page.into_iter().map(|(e, _, _)| e.info.name).collect::<Vec<_>>());
+                after = next;
+            }
+            _ => panic!("Unexpected result from key store."),
+        }
+        if after.is_none() {
+            break;
+        }
+    }
+
+    let names: Vec<Vec<Vec<u8>>> = vec![
+        vec![b"0".to_vec(), b"1".to_vec()],
+        vec![b"2".to_vec(), b"3".to_vec()],
+        vec![b"4".to_vec()],
+    ];
+    assert_eq!(names, pages);
+}
+
+/// Data that reports a change the first `changes` times it is read to the end.
+struct ChangingStub {
+    data: Vec<u8>,
+    pos: usize,
+    changes: usize,
+}
+
+impl io::Read for ChangingStub {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = io::Read::read(&mut &self.data[self.pos..], buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl DataSource for ChangingStub {
+    fn changed(&mut self) -> bool {
+        if self.changes > 0 {
+            self.changes -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn restart(&mut self) -> bool {
+        self.pos = 0;
+        true
+    }
+}
+
+#[test]
+fn files_changing_while_read_are_retried_or_recorded() {
+    fn insert(retries: u32, name: &[u8]) -> Vec<::std::path::PathBuf> {
+        let backend = Arc::new(MemoryBackend::new());
+        let ks = Store::new_for_testing(backend, 1024).unwrap().with_changed_retries(retries);
+        let ks_p = Process::new(ks.clone());
+        let source = ChangingStub {
+            data: b"contents".to_vec(),
+            pos: 0,
+            changes: 1,
+        };
+        match ks_p.send_reply(Msg::Insert(
+            Entry::new(None, name.to_vec(), Data::FilePlaceholder, None),
+            Some(Box::new(move |()| Some(source))),
+            Chunking::Default,
+        )).unwrap() {
+            Reply::Id(_) => (),
+            _ => panic!("Unexpected result from key store."),
+        }
+        ks.fuzzy_files().unwrap()
+    }
+
+    assert!(insert(1, b"retried").is_empty());
+    assert_eq!(vec![::std::path::PathBuf::from("changed")], insert(0, b"changed"));
+}
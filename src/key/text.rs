@@ -0,0 +1,58 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Words extracted from small text files, so that snapshots can be searched by content.
+
+use std::str;
+
+
+/// Largest file whose words are indexed, unless configured otherwise.
+pub const DEFAULT_TEXT_INDEX_MAX_BYTES: usize = 256 * 1024;
+
+const MIN_WORD_LEN: usize = 2;
+const MAX_WORD_LEN: usize = 64;
+
+/// The distinct words in `data`, in lowercase, or `None` if it does not look like text.
+pub fn words(data: &[u8]) -> Option<Vec<String>> {
+    if data.contains(&0) {
+        return None;
+    }
+    let text = match str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return None,
+    };
+    let mut words: Vec<String> = text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|w| w.len() >= MIN_WORD_LEN && w.len() <= MAX_WORD_LEN)
+        .map(|w| w.to_lowercase())
+        .collect();
+    words.sort();
+    words.dedup();
+    Some(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_of_text_only() {
+        assert_eq!(
+            words(b"Hello, hello world_wide\nx 42").unwrap(),
+            vec!["42".to_owned(), "hello".to_owned(), "world_wide".to_owned()]
+        );
+        assert_eq!(words(b"binary\0data"), None);
+        assert_eq!(words(&[0xff, 0xfe, b'a']), None);
+    }
+}
@@ -30,12 +30,14 @@ extern crate argon2rs;
 extern crate byteorder;
 extern crate capnp;
 extern crate chrono;
+extern crate libc;
 extern crate libsodium_sys;
 extern crate hex;
 extern crate secstr;
 extern crate scoped_pool;
 extern crate void;
 extern crate filetime;
+extern crate tar;
 
 // Error definition macros.
 #[macro_use]
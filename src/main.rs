@@ -16,6 +16,7 @@
 extern crate hat;
 
 // Rust crates.
+extern crate chrono;
 extern crate env_logger;
 extern crate libsodium_sys;
 
@@ -24,18 +25,425 @@ extern crate libsodium_sys;
 extern crate clap;
 
 use std::env;
-use clap::{App, SubCommand};
+use clap::{App, Arg, Shell, SubCommand};
 
-use hat::backend;
+use hat::backend::{self, StoreBackend};
+use hat::hat::{Client, Identity, KeyFile, KeyProvider, MasterKey, Namespace, PassphraseKey,
+               Recipient};
 use std::borrow::ToOwned;
 use std::convert::From;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 static MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
 
-fn blob_dir() -> PathBuf {
-    PathBuf::from("blobs")
+fn blob_dir(namespace: Option<&Namespace>) -> PathBuf {
+    let root = PathBuf::from("blobs");
+    match namespace {
+        Some(ns) => ns.blob_dir(&root),
+        None => root,
+    }
+}
+
+/// The blob storage of a repository: the blob backend, backed by the cold tier if configured.
+type RepoBackend = backend::TieredBackend<backend::DynBackend, backend::DynBackend>;
+
+/// Where the blobs of the repository, or the one of `namespace` if given, are stored: at `url`,
+/// or in the blob directory by default.
+fn blob_url(url: Option<&str>, namespace: Option<&Namespace>) -> backend::BackendUrl {
+    let url = match url {
+        Some(url) => {
+            backend::BackendUrl::parse(url).unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(1);
+            })
+        }
+        None => backend::BackendUrl::parse(&blob_dir(None).to_string_lossy()).unwrap(),
+    };
+    match namespace {
+        Some(ns) => url.join(ns.blob_dir(Path::new(""))),
+        None => url,
+    }
+}
+
+/// Open the backend at `url`, exiting on failure.
+fn open_backend(url: &backend::BackendUrl) -> backend::DynBackend {
+    backend::Registry::default().open(url).unwrap_or_else(|e| {
+        println!("Could not open {}: {}", url, e);
+        std::process::exit(1);
+    })
+}
+
+fn repo_backend(repo: &RepoOptions) -> RepoBackend {
+    let cold = repo.cold_dir.map(|url| open_backend(&blob_url(Some(url), repo.namespace)));
+    backend::TieredBackend::new(open_backend(&blob_url(repo.blobs, repo.namespace)), cold)
+}
+
+/// Where the repository, or the one of `namespace` if given, keeps its local state.
+fn state_dir(cache_dir: &Path, namespace: Option<&Namespace>) -> PathBuf {
+    match namespace {
+        Some(ns) => ns.cache_dir(cache_dir),
+        None => cache_dir.to_owned(),
+    }
+}
+
+/// Where a repository records the recipients its master key is sealed to.
+fn recipients_file(namespace: Option<&Namespace>) -> PathBuf {
+    blob_dir(namespace).join("recipients")
+}
+
+/// Accept a file such as ~/.ssh/id_ed25519.pub in place of the key itself.
+fn read_key(key: &str) -> String {
+    let mut contents = String::new();
+    match std::fs::File::open(key) {
+        Ok(mut f) => {
+            std::io::Read::read_to_string(&mut f, &mut contents).unwrap();
+            contents.trim().to_owned()
+        }
+        Err(_) => key.to_owned(),
+    }
+}
+
+/// Where a repository keeps its master key as wrapped by an external key service.
+fn wrapped_key_file(namespace: Option<&Namespace>) -> PathBuf {
+    blob_dir(namespace).join("wrapped-key")
+}
+
+/// Choose where the master key comes from, in this order:
+///
+/// - `provider` is `env:VAR` for a passphrase in the environment variable VAR, `file:PATH` for
+///   the first line of PATH, `password-command:CMD` for the passphrase CMD prints,
+///   `keyring:ACCOUNT` for the passphrase stored in the keyring for ACCOUNT, or
+///   `command:CMD` to have CMD unwrap the key stored by `key wrap`.
+/// - `password_file` holds the passphrase on its first line.
+/// - The environment variable HAT_PASSWORD holds the passphrase.
+/// - Otherwise, repositories with recipients are unlocked with `identity` (by default
+///   ~/.ssh/id_ed25519), and all others use the built-in passphrase.
+///
+/// Passphrases are shared by every namespace of the storage location, so a namespace derives
+/// its key from the one they give. So does a namespace without recipients of its own, from the
+/// key of the storage location.
+fn key_provider(
+    namespace: Option<&Namespace>,
+    provider: Option<&str>,
+    password_file: Option<&str>,
+    identity: Option<&str>,
+) -> Result<Box<KeyProvider>, String> {
+    if let Some(spec) = provider {
+        return if spec.starts_with("env:") {
+            Ok(namespaced(namespace, Box::new(hat::hat::EnvKey { var: spec[4..].to_owned() })))
+        } else if spec.starts_with("file:") {
+            let path = PathBuf::from(&spec[5..]);
+            Ok(namespaced(namespace, Box::new(hat::hat::FileKey { path: path })))
+        } else if spec.starts_with("password-command:") {
+            let command = spec[17..].to_owned();
+            Ok(namespaced(namespace, Box::new(hat::hat::PasswordCommandKey { command: command })))
+        } else if spec.starts_with("keyring:") {
+            let account = spec[8..].to_owned();
+            Ok(namespaced(namespace, Box::new(hat::hat::KeyringKey { account: account })))
+        } else if spec.starts_with("command:") {
+            let path = wrapped_key_file(namespace);
+            let mut wrapped = String::new();
+            std::fs::File::open(&path)
+                .and_then(|mut f| std::io::Read::read_to_string(&mut f, &mut wrapped))
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            Ok(Box::new(hat::hat::CommandKey {
+                command: spec[8..].to_owned(),
+                wrapped: wrapped.trim().to_owned(),
+            }))
+        } else {
+            Err(format!("Unknown key provider: {}", spec))
+        };
+    }
+    if let Some(path) = password_file {
+        return Ok(namespaced(namespace, Box::new(hat::hat::FileKey { path: PathBuf::from(path) })));
+    }
+    if env::var_os("HAT_PASSWORD").is_some() {
+        let var = "HAT_PASSWORD".to_owned();
+        return Ok(namespaced(namespace, Box::new(hat::hat::EnvKey { var: var })));
+    }
+
+    let path = recipients_file(namespace);
+    if !path.exists() {
+        return match namespace {
+            Some(_) => Ok(namespaced(namespace, key_provider(None, None, None, identity)?)),
+            None => Ok(Box::new(PassphraseKey(hat::hat::default_passphrase()))),
+        };
+    }
+    let identity = match identity {
+        Some(identity) => PathBuf::from(identity),
+        None => {
+            let home = env::var_os("HOME").ok_or("--identity required")?;
+            PathBuf::from(home).join(".ssh/id_ed25519")
+        }
+    };
+    Ok(Box::new(hat::hat::RecipientKey {
+        keys: KeyFile::load(&path).map_err(|e| e.to_string())?,
+        identity: Identity::load(&identity).map_err(|e| e.to_string())?,
+    }))
+}
+
+/// The key of `namespace` derived from the key `provider` gives, or that key without one.
+fn namespaced(namespace: Option<&Namespace>, provider: Box<KeyProvider>) -> Box<KeyProvider> {
+    match namespace {
+        Some(ns) => Box::new(hat::hat::NamespaceKey {
+            namespace: ns.clone(),
+            inner: provider,
+        }),
+        None => provider,
+    }
+}
+
+/// Which repository to open and how to unlock it.
+struct RepoOptions<'a> {
+    namespace: Option<&'a Namespace>,
+    key_provider: Option<&'a str>,
+    password_file: Option<&'a str>,
+    identity: Option<&'a str>,
+    encrypt_index: bool,
+    client: Option<&'a Client>,
+    node_cache_bytes: Option<u64>,
+    node_cache_dir: Option<&'a str>,
+    /// URL of the blob backend, if not the blob directory.
+    blobs: Option<&'a str>,
+    cold_dir: Option<&'a str>,
+    /// Use a fixed clock and sequential ids, for reproducible output.
+    deterministic: bool,
+}
+
+/// The master key of the repository, from the chosen provider. Exits on failure.
+fn master_key(repo: &RepoOptions) -> MasterKey {
+    key_provider(
+        repo.namespace,
+        repo.key_provider,
+        repo.password_file,
+        repo.identity,
+    )
+        .and_then(|p| p.master_key().map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            println!("Could not get the repository key: {}", e);
+            std::process::exit(1);
+        })
+}
+
+/// The master key of the repository, checked against the one it was written with. Exits on
+/// failure, e.g. after a mistyped passphrase.
+fn checked_master_key(repo: &RepoOptions) -> MasterKey {
+    let master = master_key(repo);
+    hat::hat::check_key(&repo_backend(repo), &master)
+        .unwrap_or_else(|e| fail("Could not get the repository key", e));
+    master
+}
+
+/// Open the repository, or the one of a namespace if given, exiting on failure.
+fn open_repository(
+    migrations_dir: &Path,
+    cache_dir: &Path,
+    repo: &RepoOptions,
+) -> hat::hat::HatRc<RepoBackend> {
+    let backend = Arc::new(repo_backend(repo));
+    let hat = hat::Hat::open_repository_with_key(
+        migrations_dir,
+        state_dir(cache_dir, repo.namespace),
+        backend,
+        MAX_BLOB_SIZE,
+        &master_key(repo),
+        repo.encrypt_index,
+    ).unwrap_or_else(|e| fail("Could not open repository", e));
+    configure(hat, repo)
+}
+
+/// Open the repository for commands that only read committed snapshots, so that they can run
+/// while another process writes to it. Encrypted indexes are opened as usual.
+fn open_repository_read_only(
+    migrations_dir: &Path,
+    cache_dir: &Path,
+    repo: &RepoOptions,
+) -> hat::hat::HatRc<RepoBackend> {
+    if repo.encrypt_index {
+        return open_repository(migrations_dir, cache_dir, repo);
+    }
+    let hat = hat::Hat::open_read_only_with_key(
+        migrations_dir,
+        state_dir(cache_dir, repo.namespace),
+        Arc::new(repo_backend(repo)),
+        MAX_BLOB_SIZE,
+        &master_key(repo),
+    ).unwrap_or_else(|e| fail("Could not open repository", e));
+    configure(hat, repo)
+}
+
+/// Apply the options that do not affect how the repository is opened.
+fn configure(
+    mut hat: hat::hat::HatRc<RepoBackend>,
+    repo: &RepoOptions,
+) -> hat::hat::HatRc<RepoBackend> {
+    if let Some(client) = repo.client {
+        hat.set_client(client.clone())
+            .unwrap_or_else(|e| fail("Could not claim blob ids for the client", e));
+    }
+    if repo.node_cache_bytes.is_some() || repo.node_cache_dir.is_some() {
+        let bytes = repo.node_cache_bytes.map_or(hat::hat::DEFAULT_NODE_CACHE_BYTES, |b| {
+            b as usize
+        });
+        let mut cache = hat::hat::NodeCache::new(bytes);
+        if let Some(dir) = repo.node_cache_dir {
+            cache = cache.with_disk_dir(PathBuf::from(dir)).unwrap_or_else(|e| {
+                println!("Could not use the node cache directory: {}", e);
+                std::process::exit(1);
+            });
+        }
+        hat.set_node_cache(cache);
+    }
+    if repo.deterministic {
+        hat.set_clock(Arc::new(hat::hat::FixedClock::deterministic()));
+        hat.set_ids(Arc::new(hat::hat::SequentialIds::new(1)));
+    }
+    hat
+}
+
+/// Exit with `code`.
+fn exit_with(code: hat::hat::ExitCode) -> ! {
+    std::process::exit(code.code())
+}
+
+/// Print `context` and `e`, then exit with the code for the kind of error.
+fn fail(context: &str, e: hat::hat::HatError) -> ! {
+    println!("{}: {}", context, e);
+    exit_with(e.kind().into())
+}
+
+/// How long to keep the previous index backup before replacing it, from
+/// `--index-backup-interval`. Exits if it is not a number of seconds.
+fn index_backup_interval(cmd: &clap::ArgMatches) -> std::time::Duration {
+    let secs = cmd.value_of("index-backup-interval").map_or(3600, |t| {
+        t.parse::<u64>().unwrap_or_else(|_| {
+            println!("--index-backup-interval must be a number of seconds");
+            std::process::exit(1);
+        })
+    });
+    std::time::Duration::from_secs(secs)
+}
+
+/// Names the repository in messages, if a commit writes to more than one.
+fn in_repo(label: &str) -> String {
+    if label.is_empty() {
+        String::new()
+    } else {
+        format!(" in {}", label)
+    }
+}
+
+/// Parse a byte count with an optional K, M, G or T suffix (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+        Some('T') | Some('t') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let n = digits.parse::<u64>().map_err(|e| format!("invalid size '{}': {}", s, e))?;
+    n.checked_mul(1 << shift).ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// Parse a point in time as seconds since the epoch, a date or an RFC 3339 timestamp.
+fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms(0, 0, 0).timestamp() as u64);
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|t| t.timestamp() as u64)
+        .map_err(|e| format!("invalid time '{}': {}", s, e))
+}
+
+/// The snapshot name template given with `--snapshot-name`, exiting if it is invalid.
+fn name_template_arg(cmd: &clap::ArgMatches) -> Option<hat::hat::NameTemplate> {
+    cmd.value_of("snapshot-name").map(|t| {
+        hat::hat::NameTemplate::parse(t).unwrap_or_else(|e| {
+            println!("--snapshot-name: {}", e);
+            std::process::exit(1);
+        })
+    })
+}
+
+/// Read the paths in `from`, one per line, or from stdin if it is `-`.
+fn read_file_list(from: &str) -> std::io::Result<Vec<PathBuf>> {
+    use std::io::BufRead;
+    use std::os::unix::ffi::OsStrExt;
+
+    let stdin = std::io::stdin();
+    let reader: Box<BufRead> = if from == "-" {
+        Box::new(stdin.lock())
+    } else {
+        Box::new(std::io::BufReader::new(std::fs::File::open(from)?))
+    };
+    let mut paths = vec![];
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if !line.is_empty() {
+            paths.push(PathBuf::from(std::ffi::OsStr::from_bytes(&line)));
+        }
+    }
+    Ok(paths)
+}
+
+/// The local directories hat writes to for `repo`: its local state, and its blobs and cold
+/// tier when those are stored in directories.
+fn local_dirs(cache_dir: &Path, repo: &RepoOptions) -> Vec<PathBuf> {
+    let mut dirs = vec![state_dir(cache_dir, repo.namespace)];
+    let blobs = Some(repo.blobs).into_iter().chain(repo.cold_dir.map(Some));
+    for url in blobs.map(|url| blob_url(url, repo.namespace)) {
+        if url.scheme == "file" {
+            dirs.push(PathBuf::from(url.location));
+        }
+    }
+    dirs
+}
+
+/// The device holding `path`, or the directory it would be created in.
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut dir = Some(path);
+    while let Some(p) = dir {
+        let p = if p.as_os_str().is_empty() { Path::new(".") } else { p };
+        if let Ok(meta) = std::fs::metadata(p) {
+            return Some(meta.dev());
+        }
+        dir = p.parent();
+    }
+    None
+}
+
+/// Freeze the filesystem at `mountpoint`, exiting on failure. The local state and the blobs
+/// in `written` must stay writable, so refuse to freeze the filesystem they live on.
+fn freeze(mountpoint: &str, timeout: Option<&str>, written: &[PathBuf]) -> hat::hat::FreezeGuard {
+    let timeout = timeout.map_or(60, |t| {
+        t.parse::<u64>().expect("--freeze-timeout must be a number of seconds")
+    });
+    let frozen = match std::fs::metadata(mountpoint) {
+        Ok(_) => device(Path::new(mountpoint)),
+        Err(_) => {
+            println!("Could not freeze {}: not found", mountpoint);
+            std::process::exit(1);
+        }
+    };
+    if written.iter().any(|dir| device(dir) == frozen) {
+        println!("Refusing to freeze {}: hat writes to this filesystem", mountpoint);
+        std::process::exit(1);
+    }
+
+    hat::hat::FreezeGuard::freeze(Path::new(mountpoint), std::time::Duration::from_secs(timeout))
+        .unwrap_or_else(|e| {
+            println!("Could not freeze {}: {}", mountpoint, e);
+            std::process::exit(1);
+        })
 }
 
 fn license() {
@@ -53,46 +461,513 @@ fn main() {
     let arg_template = "<NAME> 'Name of the snapshot'
                         <PATH> 'The path of the snapshot'";
 
+    // Template for commands that move a snapshot between families.
+    let snapshot_template = "<NAME> 'Name of the snapshot family'
+                             <ID> 'The snapshot id'
+                             <NEW_NAME> 'Name of the target snapshot family'";
+
+    // Template for the policy of the tier commands.
+    let tier_template = "--max-age=[DAYS] 'Move blobs older than this (default 7)'
+                         --max-hot=[SIZE] 'Move the oldest blobs until the blob directory holds \
+                                           at most this much'";
+
     // Create valid arguments
-    let matches = App::new("hat")
+    let mut app = App::new("hat")
         .version(&format!("v{}", crate_version!())[..])
         .about("Create backup snapshots")
         .args_from_usage(
             "-l, --license 'Display the license'
                           --hat_migrations_dir=[DIR] 'Location of Hat SQL migrations'
-                          --hat_cache_dir=[DIR] 'Location of Hat local state'",
+                          --hat_cache_dir=[DIR] 'Location of Hat local state'
+                          --namespace=[NAME] 'Use the repository NAME within the shared \
+                                              blob storage'
+                          --identity=[FILE] 'Age or SSH private key to unlock a repository \
+                                             with recipients (default ~/.ssh/id_ed25519)'
+                          --key-provider=[SPEC] 'Get the repository key from env:VAR, \
+                                                 file:PATH, password-command:CMD or \
+                                                 keyring:ACCOUNT, or have command:CMD unwrap it'
+                          --password-file=[FILE] 'Read the repository passphrase from FILE'
+                          --encrypt-index 'Keep the local index files encrypted (permanent \
+                                           once enabled)'
+                          --client=[NAME] 'Share the repository with other clients, as NAME'
+                          --node-cache=[SIZE] 'Memory for caching snapshot tree nodes \
+                                               (default 64M)'
+                          --node-cache-dir=[DIR] 'Also cache snapshot tree nodes in DIR'
+                          --cache-stats 'Report node cache hit rates when done'
+                          --blobs=[URL] 'Store blobs at URL instead of the blob directory, \
+                                         like file:///mnt/backup, see hat backends'
+                          --cold-dir=[DIR] 'Read blobs missing from the blob directory from \
+                                            this cold tier, a directory or URL, see hat tier'
+                          --deterministic 'Take the time from a clock starting at \
+                                           2000-01-01 and number uploads from 1, so that \
+                                           output is the same on every run, for tests'
+                          --nice=[N] 'Run at CPU niceness N, from 0 to 19'
+                          --ionice-idle 'Only use the disk when no other program does'",
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Create a new repository in an empty directory")
+                .args_from_usage(
+                    "--recipient=[KEY]... 'Encrypt with a random key sealed to this public \
+                                           key, or a file containing it'",
+                ),
         )
         .subcommand(
             SubCommand::with_name("commit")
                 .about("Commit a new snapshot")
-                .args_from_usage(arg_template),
+                .args_from_usage(arg_template)
+                .args_from_usage(
+                    "--quota=[SIZE] 'Maximum repository size, e.g. 500G'
+                     --min_free=[SIZE] 'Free space to keep on the backend target, e.g. 10G'
+                     --max-file-size=[SIZE] 'Skip files larger than this'
+                     --min-file-size=[SIZE] 'Skip files smaller than this'
+                     --changed-since=[TIME] 'Skip files not modified since TIME \
+                                             (seconds since epoch, YYYY-MM-DD or RFC 3339)'
+                     -x --one-file-system 'Do not cross filesystem boundaries'
+                     --exclude-caches 'Skip directories containing a valid CACHEDIR.TAG'
+                     --exclude-if-present=[FILE]... 'Skip directories containing FILE, \
+                                                     e.g. .nobackup'
+                     --exclude-nodump 'Skip files and directories with the nodump \
+                                       attribute (chattr +d)'
+                     --fixed-blocks=[SUFFIX]... 'Split files whose name ends with SUFFIX \
+                                                 (e.g. .img) into aligned 1 MiB blocks'
+                     --freeze=[MOUNTPOINT] 'Freeze the filesystem at MOUNTPOINT while \
+                                            scanning it (requires root)'
+                     --freeze-timeout=[SECONDS] 'Always thaw after this long (default 60)'
+                     --uploads=[N] 'Number of blobs to upload at the same time (default 4)'
+                     --backend-timeout=[SECONDS] 'Fail if the backend does not take the \
+                                                  data within SECONDS when flushing'
+                     --min-blob-size=[SIZE] 'Adapt the blob size to the backend, \
+                                             but keep it above SIZE (at least 2M)'
+                     --max-blob-size=[SIZE] 'Adapt the blob size to the backend, \
+                                             but keep it below SIZE'
+                     --no-index-backup 'Do not store a copy of the indexes with the blobs'
+                     --index-backup-interval=[SECONDS] 'Replace the copy of the indexes at \
+                                                        most this often (default 3600)'
+                     --index-text 'Index the words of text files up to 256K, for hat grep'
+                     --content-types 'Record the content type of files read, detected from \
+                                      their first bytes, for checkout --type and stats --types'
+                     --also-to=[URL] 'Also commit to the repository with blobs at URL, \
+                                      reading every file only once'
+                     --also-to-cache=[DIR] 'Location of the local state of the --also-to \
+                                            repository'
+                     --read-limit=[SIZE] 'Read files at most SIZE bytes per second, e.g. 50M'
+                     --command=[NAME=COMMAND]... 'Also store the output of COMMAND as the \
+                                                  file NAME at the top of the snapshot, \
+                                                  e.g. all.sql=pg_dumpall'
+                     --command-timeout=[SECONDS] 'Kill commands running longer than this'
+                     --retry-changed=[N] 'Read files that change while being read up to N \
+                                          more times (default 0)'
+                     --rewrite-suspect 'Store new copies of data that hat check found \
+                                        damaged, instead of referring to it'
+                     --mmap 'Memory map files of 16M and more instead of reading them; \
+                             only for sources where files are not truncated during the backup'
+                     --files-from=[FILE] 'Only commit the paths listed in FILE (- for stdin), \
+                                          one per line and relative to PATH, keeping the \
+                                          rest from the previous snapshot'
+                     --snapshot-name=[TEMPLATE] 'Name the snapshot after TEMPLATE, e.g. \
+                                                 {host}-{family}-{YYYY-MM-DD_HH:MM}'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("commit-many")
+                .about("Commit several sources into their own families at the same time")
+                .args_from_usage(
+                    "<JOB>... 'NAME=PATH to commit PATH into the family NAME'
+                     --no-index-backup 'Do not store a copy of the local indexes with the blobs'
+                     --index-backup-interval=[SECONDS] 'Replace the copy of the indexes at \
+                                                        most this often (default 3600)'
+                     --snapshot-name=[TEMPLATE] 'Name the snapshots after TEMPLATE, e.g. \
+                                                 {host}-{family}-{YYYY-MM-DD_HH:MM}'",
+                ),
         )
         .subcommand(
             SubCommand::with_name("checkout")
                 .about("Checkout a snapshot")
-                .args_from_usage(arg_template),
+                .args_from_usage(arg_template)
+                .args_from_usage(
+                    "--no-owner 'Do not restore file owners (default unless run as root)'
+                     --owner 'Restore file owners'
+                     --no-perms 'Do not restore file permissions'
+                     --no-times 'Do not restore file modification and access times'
+                     --no-attrs 'Do not restore file capabilities and immutable, \
+                                 append-only or nodump attributes'
+                     --order=[ORDER] 'Write files in tree order, or in blob order to read \
+                                      every blob once (tree or blob, default tree)'
+                     --files-from=[FILE] 'Only restore the paths and glob patterns listed in \
+                                          FILE, one per line (- for stdin)'
+                     -0 --null 'Paths in the --files-from list are separated by NUL bytes'
+                     --overwrite 'Replace files that are already in PATH'
+                     --skip-existing 'Keep files that are already in PATH'
+                     --only-if-newer 'Replace files in PATH that are older than in the snapshot'
+                     --backup-existing 'Rename files that are already in PATH to \
+                                        NAME.hat-backup before restoring'
+                     --type=[TYPE]... 'Only restore files of this content type, like image/*, \
+                                       as detected by commit --content-types'
+                     --progress 'Report the bytes restored, the current rate and the time \
+                                 left to stderr every few seconds'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write a directory from a snapshot to stdout")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <PATH> 'Path inside the snapshot to export'
+                     --id=[ID] 'The snapshot id (default: latest)'
+                     --tar 'Write a tar stream'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory in a snapshot")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     [PATH] 'Directory inside the snapshot (default: the top)'
+                     --id=[ID] 'The snapshot id (default: latest)'
+                     --check 'Flag entries whose data is missing from the local indexes'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Estimate how a directory would chunk and deduplicate, storing nothing")
+                .args_from_usage(
+                    "<PATH> 'The directory to sample'
+                     --sample=[SIZE] 'Read at most SIZE of file data (default: 1G)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("deleted")
+                .about("List the paths deleted since the previous snapshot, or when PATH was")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     [PATH] 'A path in the snapshots, to list the snapshots it was deleted in'
+                     --id=[ID] 'The snapshot id (default: latest)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("excluded")
+                .about("List the directories left out of a snapshot by a marker or attribute")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     --id=[ID] 'The snapshot id (default: latest)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List the snapshots containing a path, to see when it changed")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <PATH> 'A path in the snapshots'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("List what changed in a directory since a snapshot of it")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <PATH> 'The directory the snapshot was taken of'
+                     --id=[ID] 'The snapshot id (default: latest)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check that the stored data can be read back")
+                .args_from_usage(
+                    "--read-data-subset=[N/M] 'Only read part N of M of the blobs; \
+                                               auto/M picks the part from the current week'
+                     --max-duration=[SECONDS] 'Stop after this long; the next check continues \
+                                               where this one stopped'
+                     --restart 'Start over instead of continuing an unfinished check'
+                     --fetchers=[N] 'Number of blobs to download at the same time (default: 4)'
+                     --hashers=[N] 'Number of threads verifying downloaded blobs (default: 2)'
+                     --trees-only 'Only check that every snapshot tree is intact and that the \
+                                   blobs it refers to exist, without reading file data'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("test-restore")
+                .about("Restore a random sample of files to a temporary directory and verify them")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     --id=[ID] 'The snapshot id (default: latest)'
+                     --sample=[N] 'Number of files to restore (default: 20)'
+                     --dir=[DIR] 'Where to create the temporary directory \
+                                  (default: the system temporary directory)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stage")
+                .about("Request restores of the archived blobs a snapshot needs")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     --id=[ID] 'The snapshot id (default: latest)'",
+                ),
         )
         .subcommand(SubCommand::with_name("recover").about(
             "Recover list of commit'ed snapshots",
         ))
+        .subcommand(SubCommand::with_name("bootstrap").about(
+            "Restore the local indexes from the copy stored with the blobs",
+        ))
+        .subcommand(
+            SubCommand::with_name("offline")
+                .about(
+                    "Read snapshots straight from the blobs, without any local index, e.g. \
+                     when the cache directory is lost",
+                )
+                .subcommand(SubCommand::with_name("snapshots").about(
+                    "List the snapshots found in the blobs",
+                ))
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .about("List a directory in a snapshot")
+                        .args_from_usage(
+                            "<NAME> 'Name of the snapshot family'
+                             [PATH] 'Directory inside the snapshot (default: the top)'
+                             --id=[ID] 'The snapshot id (default: latest)'",
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("checkout")
+                        .about("Restore a snapshot, or a path inside it")
+                        .args_from_usage(
+                            "<NAME> 'Name of the snapshot family'
+                             <DEST> 'Directory to restore into'
+                             [PATH] 'Path inside the snapshot (default: all of it)'
+                             --id=[ID] 'The snapshot id (default: latest)'",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshots")
+                .about("List the snapshots")
+                .args_from_usage(
+                    "-v --verbose 'Show what each snapshot run read, stored and saw move'",
+                ),
+        )
+        .subcommand(SubCommand::with_name("info").about(
+            "Summarize the repository: format, settings, index and blob sizes, last GC and \
+             check, unfinished work and whether it is in use",
+        ))
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show how much data each family shares with the others")
+                .args_from_usage(
+                    "--types 'Show the size of the files of each content type instead, as \
+                              detected by commit --content-types'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Write the snapshots of each family as a graph, with how much data \
+                        consecutive snapshots share")
+                .args_from_usage(
+                    "--dot 'Write the Graphviz dot language (the default)'
+                     --json 'Write JSON'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("attest")
+                .about("Print a signed statement of a snapshot's root hash and size, to \
+                        publish outside the repository")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id to attest'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-attestation")
+                .about("Check that a snapshot still matches an attestation of it")
+                .args_from_usage("<FILE> 'The attestation, or - to read it from stdin'"),
+        )
+        .subcommand(
+            SubCommand::with_name("find")
+                .about("Find files by name in the snapshots")
+                .args_from_usage(
+                    "<PATTERN> 'Name to look for; * and ? match any characters'
+                     --family=[NAME] 'Only search the snapshots of this family'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("Find files containing a word, if committed with --index-text")
+                .args_from_usage(
+                    "[WORD] 'Word to look for, in any case'
+                     --family=[NAME] 'Only search the snapshots of this family'
+                     --purge 'Forget the indexed words instead of searching'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dupes")
+                .about("List files with the same data in a snapshot, and the space shared")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     --id=[ID] 'The snapshot id (default: latest)'
+                     --limit=[N] 'Only list the N groups saving the most space'",
+                ),
+        )
         .subcommand(
             SubCommand::with_name("delete")
-                .about("Delete a snapshot")
+                .about("Move a snapshot to the trash")
                 .args_from_usage(
                     "<NAME> 'Name of the snapshot family'
                                                         \
-                              <ID> 'The snapshot id to delete'",
+                              <ID> 'The snapshot id to delete'
+                     --now 'Skip the trash and delete the snapshot immediately'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Manage snapshot records without touching their data")
+                .subcommand(
+                    SubCommand::with_name("clone")
+                        .about("Register a copy of a snapshot under another family")
+                        .args_from_usage(snapshot_template),
+                )
+                .subcommand(
+                    SubCommand::with_name("rename")
+                        .about("Move a snapshot to another family")
+                        .args_from_usage(snapshot_template),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("undelete")
+                .about("Restore a snapshot from the trash")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id to restore'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pin")
+                .about("Protect a snapshot from deletion until it is unpinned")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id to pin'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unpin")
+                .about("Allow a pinned snapshot to be deleted again")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <ID> 'The snapshot id to unpin'",
                 ),
         )
         .subcommand(
             SubCommand::with_name("gc")
                 .about("Garbage collect: identify and remove unused data blocks.")
-                .args_from_usage("-p --pretend 'Do not modify any data'"),
+                .args_from_usage(
+                    "-n --dry-run 'Report what would be removed without modifying any data'
+                     -p --pretend 'Same as --dry-run'
+                     --offline 'Only update the local index; delete unused blobs later \
+                                with prune --execute'
+                     --grace=[DAYS] 'Days to keep deleted snapshots in the trash (default 7)'
+                     --max-duration=[SECONDS] 'Stop deleting unused blobs after this long; the \
+                                               next gc or prune continues where this one stopped'
+                     --max-rate=[BYTES] 'Delete at most this many bytes of unused blobs per \
+                                         second, to keep the backend responsive'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump-index")
+                .about("Write the local indexes to stdout for analysis in other tools")
+                .args_from_usage(
+                    "--format=[FORMAT] 'Output format (only csv, the default)'
+                     --keys 'Write the file entries of every family instead of the hashes'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Delete blobs marked as unused by gc --offline")
+                .args_from_usage("-e --execute 'Delete the blobs instead of listing the total'"),
+        )
+        .subcommand(
+            SubCommand::with_name("repo")
+                .about("Inspect the repositories sharing the blob storage")
+                .subcommand(
+                    SubCommand::with_name("list").about("List the repository namespaces"),
+                )
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .about("Show or change the parameters stored in the repository")
+                        .args_from_usage(
+                            "--max-blob-size=[SIZE] 'Write new blobs of this size from now on; \
+                                                     existing blobs keep theirs'",
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("layout")
+                        .about("Show or change how blob files are arranged on disk")
+                        .args_from_usage(
+                            "--fan-out=[LEVELS] 'Move blobs into LEVELS levels of \
+                                                 subdirectories (0 for a flat directory)'",
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("resolve-reserved")
+                        .about("Complete or remove the hashes that interrupted runs reserved but \
+                                never committed; opening the repository for writing also does \
+                                this"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tier")
+                .about("Move older blobs from the blob directory to the --cold-dir tier")
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Show the blobs in each tier and how many are due to move")
+                        .args_from_usage(tier_template),
+                )
+                .subcommand(
+                    SubCommand::with_name("offload")
+                        .about("Move the blobs that are due to the cold tier")
+                        .args_from_usage(tier_template),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("key")
+                .about("Manage who can unlock the repository")
+                .subcommand(
+                    SubCommand::with_name("add-recipient")
+                        .about("Seal the repository key to an age or SSH Ed25519 public key")
+                        .args_from_usage("<KEY> 'Public key, or a file containing it'"),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove-recipient")
+                        .about("Remove a recipient; does not change the repository key")
+                        .args_from_usage("<KEY> 'Public key, file containing it or key comment'"),
+                )
+                .subcommand(
+                    SubCommand::with_name("list-recipients").about("List the recipients"),
+                )
+                .subcommand(
+                    SubCommand::with_name("wrap")
+                        .about("Store the repository key wrapped by an external key service")
+                        .args_from_usage(
+                            "<COMMAND> 'Command that reads the hex-encoded key on stdin and \
+                                        prints it wrapped'",
+                        ),
+                ),
         )
         .subcommand(SubCommand::with_name("resume").about(
             "Resume previous failed command.",
         ))
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("backends")
+                .about("List the kinds of blob storage --blobs can name, and what the current one \
+                        supports"),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a completion script for the given shell")
+                .arg(
+                    Arg::from_usage("<SHELL> 'The shell to complete in'")
+                        .possible_values(&Shell::variants()),
+                ),
+        );
+    let matches = app.clone().get_matches();
 
     // Check for license flag
     if matches.is_present("license") {
@@ -100,6 +975,33 @@ fn main() {
         std::process::exit(0);
     }
 
+    // Completions need no repository, so print them before looking for one.
+    if let ("completions", Some(cmd)) = matches.subcommand() {
+        let shell = cmd.value_of("SHELL").unwrap().parse::<Shell>().unwrap();
+        // Complete the name of the installed binary, which Cargo.toml sets.
+        app.gen_completions_to("hatbin", shell, &mut std::io::stdout());
+        std::process::exit(0);
+    }
+
+    // Analyzing a directory stores nothing, so it needs no repository either.
+    if let ("analyze", Some(cmd)) = matches.subcommand() {
+        let path = cmd.value_of("PATH").unwrap();
+        let sample = cmd.value_of("sample").map_or(1 << 30, |s| {
+            parse_size(s).unwrap_or_else(|e| {
+                println!("--sample: {}", e);
+                std::process::exit(1);
+            })
+        });
+        match hat::hat::analyze_source(Path::new(path), sample) {
+            Ok(analysis) => println!("{}", analysis),
+            Err(e) => {
+                println!("Could not analyze {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
     let flag_or_env = |name: &str| {
         matches
             .value_of(name)
@@ -114,81 +1016,1448 @@ fn main() {
     let migrations_dir_str = flag_or_env("hat_migrations_dir");
     let migrations_dir = Path::new(&migrations_dir_str);
     let cache_dir = PathBuf::from(flag_or_env("hat_cache_dir"));
+    let identity_flag = matches
+        .value_of("identity")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_IDENTITY").ok());
+    let key_provider_flag = matches
+        .value_of("key-provider")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_KEY_PROVIDER").ok());
+    let password_file_flag = matches
+        .value_of("password-file")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_PASSWORD_FILE").ok());
+    let blobs_flag = matches
+        .value_of("blobs")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_BLOBS").ok());
+    let cold_dir_flag = matches
+        .value_of("cold-dir")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_COLD_DIR").ok());
+    let namespace = matches
+        .value_of("namespace")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_NAMESPACE").ok())
+        .map(|name| {
+            Namespace::new(&name).unwrap_or_else(|e| {
+                println!("--namespace: {}", e);
+                std::process::exit(1);
+            })
+        });
+    let client = matches
+        .value_of("client")
+        .map(|x| x.to_owned())
+        .or_else(|| env::var("HAT_CLIENT").ok())
+        .map(|name| {
+            Client::new(&name).unwrap_or_else(|e| {
+                println!("--client: {}", e);
+                std::process::exit(1);
+            })
+        });
+    let repo = RepoOptions {
+        namespace: namespace.as_ref(),
+        key_provider: key_provider_flag.as_ref().map(|x| &x[..]),
+        password_file: password_file_flag.as_ref().map(|x| &x[..]),
+        identity: identity_flag.as_ref().map(|x| &x[..]),
+        encrypt_index: matches.is_present("encrypt-index") ||
+            env::var_os("HAT_ENCRYPT_INDEX").is_some(),
+        client: client.as_ref(),
+        node_cache_bytes: matches.value_of("node-cache").map(|s| {
+            parse_size(s).unwrap_or_else(|e| {
+                println!("--node-cache: {}", e);
+                std::process::exit(1);
+            })
+        }),
+        node_cache_dir: matches.value_of("node-cache-dir"),
+        blobs: blobs_flag.as_ref().map(|x| &x[..]),
+        cold_dir: cold_dir_flag.as_ref().map(|x| &x[..]),
+        deterministic: matches.is_present("deterministic") ||
+            env::var_os("HAT_DETERMINISTIC").is_some(),
+    };
+
+    // Lower the priorities before any threads are started, so that they all inherit them.
+    if let Some(nice) = matches.value_of("nice") {
+        let nice = match nice.parse::<i32>() {
+            Ok(n) if n >= 0 && n <= 19 => n,
+            _ => {
+                println!("--nice: must be a number from 0 to 19");
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = hat::hat::set_nice(nice) {
+            println!("--nice: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if matches.is_present("ionice-idle") {
+        if let Err(e) = hat::hat::set_idle_io() {
+            println!("--ionice-idle: {}", e);
+            std::process::exit(1);
+        }
+    }
 
     // Initialize sodium (must only be called once)
     unsafe { libsodium_sys::sodium_init() };
 
     match matches.subcommand() {
+        ("init", Some(cmd)) => {
+            let dir = blob_dir(namespace.as_ref());
+            let url = blob_url(repo.blobs, namespace.as_ref());
+            // Also catch files the blob backend would not list, such as a stray recipients file.
+            if url.scheme == "file" {
+                let is_empty = std::fs::read_dir(&url.location)
+                    .map(|mut entries| entries.next().is_none());
+                if !is_empty.unwrap_or(true) {
+                    println!("Refusing to create a repository in {}: it is not empty", url);
+                    std::process::exit(1);
+                }
+            }
+            let backend = open_backend(&url);
+            if let Ok(Some(_)) = hat::hat::format_version(&backend) {
+                println!("{} already holds a repository", url);
+                std::process::exit(1);
+            }
+            std::fs::create_dir_all(&dir).unwrap();
+            hat::hat::init_repository(&backend).unwrap_or_else(|e| {
+                println!("Could not create the repository: {}", e);
+                std::process::exit(1);
+            });
+
+            if let Some(keys) = cmd.values_of("recipient") {
+                let master = MasterKey::generate();
+                let mut file = KeyFile::new();
+                for key in keys {
+                    let recipient = Recipient::parse(&read_key(key)).unwrap_or_else(|e| {
+                        println!("Invalid recipient: {}", e);
+                        std::process::exit(1);
+                    });
+                    file.add(&master, recipient);
+                }
+                file.save(&recipients_file(namespace.as_ref())).unwrap();
+            }
+            println!("Created a format {} repository in {}", hat::hat::FORMAT_VERSION, url);
+        }
+        ("backends", Some(_cmd)) => {
+            for (scheme, description) in backend::Registry::default().schemes() {
+                println!("{}://  {}", scheme, description);
+            }
+            let url = blob_url(repo.blobs, repo.namespace);
+            let caps = open_backend(&url).capabilities();
+            println!(
+                "Blobs are stored at {} (delete: {}, rename: {}, archival: {})",
+                url,
+                caps.delete,
+                caps.rename,
+                caps.archival
+            );
+        }
         ("resume", Some(_cmd)) => {
             // Setting up the repository triggers automatic resume.
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+            open_repository(migrations_dir, &cache_dir, &repo);
         }
         ("commit", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            // Also commit to a second repository, e.g. on another disk, reading the source once.
+            // It has its own blobs and local state, and commits or fails on its own.
+            let mirror = cmd.value_of("also-to").map(|url| {
+                let mirror_cache = cmd.value_of("also-to-cache").unwrap_or_else(|| {
+                    println!("--also-to needs --also-to-cache for the local state of {}", url);
+                    std::process::exit(1);
+                });
+                let mirror_cache = PathBuf::from(mirror_cache);
+                if mirror_cache == cache_dir {
+                    println!("--also-to-cache must not be the local state of this repository");
+                    std::process::exit(1);
+                }
+                (RepoOptions { blobs: Some(url), cold_dir: None, ..repo }, mirror_cache, url)
+            });
+            let repos: Vec<_> =
+                Some((repo, cache_dir.clone(), "")).into_iter().chain(mirror).collect();
+            let name_template = name_template_arg(cmd);
+
+            let size_arg = |arg: &str| {
+                cmd.value_of(arg).map(|s| parse_size(s).unwrap_or_else(|e| {
+                    println!("--{}: {}", arg, e);
+                    std::process::exit(1);
+                }))
+            };
+            let uploads = cmd.value_of("uploads").map(|n| match n.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    println!("--uploads must be a positive number");
+                    std::process::exit(1);
+                }
+            });
+
+            let changed_retries = match cmd.value_of("retry-changed").map(|n| n.parse::<u32>()) {
+                None => 0,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => {
+                    println!("--retry-changed must be a number");
+                    std::process::exit(1);
+                }
+            };
+
+            let command_timeout = cmd.value_of("command-timeout").map(|t| match t.parse::<u64>() {
+                Ok(secs) if secs > 0 => std::time::Duration::from_secs(secs),
+                _ => {
+                    println!("--command-timeout must be a positive number of seconds");
+                    std::process::exit(1);
+                }
+            });
+            let commands: Vec<hat::hat::CommandSource> = cmd.values_of("command")
+                .map(|vs| vs.collect())
+                .unwrap_or(vec![])
+                .into_iter()
+                .map(|c| match c.find('=') {
+                    Some(pos) if pos > 0 => hat::hat::CommandSource {
+                        timeout: command_timeout,
+                        ..hat::hat::CommandSource::new(c[..pos].to_owned(), c[pos + 1..].to_owned())
+                    },
+                    _ => {
+                        println!("--command: expected NAME=COMMAND: {}", c);
+                        std::process::exit(1);
+                    }
+                })
+                .collect();
+
+            let backend_timeout = cmd.value_of("backend-timeout").map(|t| match t.parse::<u64>() {
+                Ok(secs) if secs > 0 => std::time::Duration::from_secs(secs),
+                _ => {
+                    println!("--backend-timeout must be a positive number of seconds");
+                    std::process::exit(1);
+                }
+            });
+
+            let blob_size_bounds = match (size_arg("min-blob-size"), size_arg("max-blob-size")) {
+                (None, None) => None,
+                (min, max) => {
+                    let min = min.map_or(hat::hat::MIN_BLOB_SIZE, |n| n as usize);
+                    let max = max.map_or(std::cmp::max(min, MAX_BLOB_SIZE), |n| n as usize);
+                    if min < hat::hat::MIN_BLOB_SIZE || max < min {
+                        println!(
+                            "Blob sizes must be at least {} bytes, and --min-blob-size must not \
+                             exceed --max-blob-size",
+                            hat::hat::MIN_BLOB_SIZE
+                        );
+                        std::process::exit(1);
+                    }
+                    Some((min, max))
+                }
+            };
+
+            let mut exit = hat::hat::ExitCode::Success;
+            let mut targets = vec![];
+            for &(ref repo, ref repo_cache_dir, label) in &repos {
+                let mut hat = open_repository(migrations_dir, repo_cache_dir, repo);
+                hat.set_quota(hat::hat::Quota {
+                    max_repository_bytes: size_arg("quota"),
+                    min_free_bytes: size_arg("min_free"),
+                });
+                if let Some(n) = uploads {
+                    hat.set_max_uploads(n);
+                }
+                hat.set_backend_timeout(backend_timeout);
+                hat.set_changed_retries(changed_retries);
+                hat.set_rewrite_suspect(cmd.is_present("rewrite-suspect"));
+                if let Some((min, max)) = blob_size_bounds {
+                    hat.set_blob_size_bounds(min, max);
+                }
+                if cmd.is_present("index-text") {
+                    hat.set_text_index(Some(hat::hat::DEFAULT_TEXT_INDEX_MAX_BYTES));
+                }
+                hat.set_content_types(cmd.is_present("content-types"));
+
+                // Deduplicate against what other clients have stored since the last commit.
+                match hat.reconcile() {
+                    Ok(0) => (),
+                    Ok(n) => {
+                        println!("Merged {} snapshots from other clients{}", n, in_repo(label))
+                    }
+                    Err(e) => {
+                        println!(
+                            "Could not merge snapshots from other clients{}: {}",
+                            in_repo(label),
+                            e
+                        );
+                        exit = exit.combine(e.kind().into());
+                        continue;
+                    }
+                }
+
+                // Refuse to start if we are already out of space.
+                if let Err(e) = hat.check_space(0) {
+                    println!("Not starting commit{}: {}", in_repo(label), e);
+                    exit = exit.combine(e.kind().into());
+                    continue;
+                }
+
+                // Update the family index.
+                let family = hat.open_family(name.clone()).expect(&format!(
+                    "Could not open family '{}'",
+                    name
+                ));
+                targets.push((hat, family, repo.client.is_some(), label));
+            }
+            if targets.is_empty() {
+                exit_with(exit);
+            }
+
+            let filter = hat::hat::SourceFilter {
+                max_file_size: size_arg("max-file-size"),
+                min_file_size: size_arg("min-file-size"),
+                changed_since: cmd.value_of("changed-since").map(|s| {
+                    parse_time(s).unwrap_or_else(|e| {
+                        println!("--changed-since: {}", e);
+                        std::process::exit(1);
+                    })
+                }),
+                one_file_system: cmd.is_present("one-file-system"),
+                exclude_caches: cmd.is_present("exclude-caches"),
+                exclude_markers: cmd.values_of("exclude-if-present")
+                    .map(|vs| vs.map(|v| v.to_owned()).collect())
+                    .unwrap_or(vec![]),
+                exclude_nodump: cmd.is_present("exclude-nodump"),
+                fixed_block_suffixes: cmd.values_of("fixed-blocks")
+                    .map(|vs| vs.map(|v| v.to_owned()).collect())
+                    .unwrap_or(vec![]),
+                read_limit: match size_arg("read-limit") {
+                    Some(0) => {
+                        println!("--read-limit: must be more than 0");
+                        std::process::exit(1);
+                    }
+                    limit => limit,
+                },
+                mmap: cmd.is_present("mmap"),
+            };
+
+            let listed = cmd.value_of("files-from").map(|from| {
+                read_file_list(from).unwrap_or_else(|e| {
+                    println!("--files-from: {}", e);
+                    std::process::exit(1);
+                })
+            });
 
-            // Update the family index.
-            let mut family = hat.open_family(name.clone()).expect(&format!(
-                "Could not open family '{}'",
-                name
-            ));
-            family.snapshot_dir(PathBuf::from(path));
+            let scans: Vec<Result<(), hat::hat::HatError>> = {
+                // Hold the freeze until every file has been read.
+                let freeze_guard = cmd.value_of("freeze").map(|mountpoint| {
+                    let written: Vec<PathBuf> =
+                        repos.iter().flat_map(|r| local_dirs(&r.1, &r.0)).collect();
+                    freeze(mountpoint, cmd.value_of("freeze-timeout"), &written)
+                });
+                let scans: Vec<_> = match listed {
+                    Some(ref files) => {
+                        targets
+                            .iter()
+                            .map(|t| t.1.snapshot_listed(PathBuf::from(path), files, &filter))
+                            .collect()
+                    }
+                    None => {
+                        let mirrors: Vec<_> = targets[1..].iter().map(|t| &t.1).collect();
+                        targets[0].1.snapshot_dir_mirrored(
+                            &mirrors[..],
+                            PathBuf::from(path),
+                            &filter,
+                        )
+                    }
+                };
+                // Files read after the timeout thawed the filesystem may be inconsistent.
+                match freeze_guard.map(|guard| guard.thaw()) {
+                    Some(Err(e)) => {
+                        let thawed = || format!("{} while files were read", e);
+                        scans.into_iter().map(|s| s.and(Err(From::from(thawed())))).collect()
+                    }
+                    _ => scans,
+                }
+            };
 
-            // Commit the updated index.
-            hat.commit(&mut family, None).unwrap();
+            // Each repository commits the snapshot, or fails, independently of the others.
+            for ((mut hat, mut family, publish, label), scan) in targets.into_iter().zip(scans) {
+                let commands_run = scan.and_then(|()| {
+                    for command in &commands {
+                        let run = family.snapshot_command(command)?;
+                        if !run.succeeded() {
+                            println!("Command failed{}: {}", in_repo(label), run);
+                            exit = exit.combine(hat::hat::ExitCode::Warnings);
+                        }
+                    }
+                    Ok(())
+                });
+                if let Err(e) = commands_run.and_then(|()| family.flush()) {
+                    println!("Commit stopped{}: {}", in_repo(label), e);
+                    exit = exit.combine(e.kind().into());
+                    // Make the data stored so far durable before giving up.
+                    if let Err(e) = hat.data_flush() {
+                        println!("Could not flush{}: {}", in_repo(label), e);
+                    }
+                    continue;
+                }
 
-            // Meta commit.
-            hat.meta_commit().unwrap();
+                // Commit the updated index, named after the template or noting any filters.
+                let msg = match name_template {
+                    Some(ref template) => hat.snapshot_name(&name, template),
+                    None => filter.describe().unwrap_or("anonymous".to_owned()),
+                };
+                let fuzzy_files = family.key_store.fuzzy_files().map_err(hat::hat::HatError::from);
+                let res = fuzzy_files.and_then(|fuzzy_files| {
+                    let stats = hat.commit_with_msg(&mut family, None, &msg)?;
+                    hat.meta_commit()?;
+                    hat.data_flush()?;
+                    Ok((stats, fuzzy_files))
+                });
+                let (stats, fuzzy_files) = match res {
+                    Ok(done) => done,
+                    Err(e) => {
+                        println!("Commit failed{}: {}", in_repo(label), e);
+                        exit = exit.combine(e.kind().into());
+                        continue;
+                    }
+                };
 
-            // Flush any remaining blobs.
-            hat.data_flush().unwrap();
+                if publish {
+                    if let Err(e) = hat.publish_snapshot(&name) {
+                        println!("Could not publish the snapshot{}: {}", in_repo(label), e);
+                        exit = exit.combine(hat::hat::ExitCode::Warnings);
+                    }
+                }
+                println!("Committed{}: {}", in_repo(label), stats);
+                for path in &fuzzy_files {
+                    println!("    Changed while reading: {}", path.display());
+                }
+                if stats.skipped_files > 0 || stats.fuzzy_files > 0 {
+                    exit = exit.combine(hat::hat::ExitCode::Warnings);
+                }
+
+                // Keep a copy of the indexes with the blobs, for `bootstrap`.
+                if !cmd.is_present("no-index-backup") {
+                    if let Err(e) = hat.backup_index_if_due(index_backup_interval(cmd)) {
+                        println!("Could not back up the index{}: {}", in_repo(label), e);
+                    }
+                }
+            }
+            if exit != hat::hat::ExitCode::Success {
+                exit_with(exit);
+            }
+        }
+        ("commit-many", Some(cmd)) => {
+            let name_template = name_template_arg(cmd);
+            let jobs: Vec<hat::hat::SnapshotJob> = cmd.values_of("JOB")
+                .unwrap()
+                .map(|job| match job.find('=') {
+                    Some(pos) if pos > 0 => {
+                        let (name, path) = (&job[..pos], &job[pos + 1..]);
+                        let mut job =
+                            hat::hat::SnapshotJob::new(name.to_owned(), PathBuf::from(path));
+                        job.name_template = name_template.clone();
+                        job
+                    }
+                    _ => {
+                        println!("Expected NAME=PATH: {}", job);
+                        std::process::exit(1);
+                    }
+                })
+                .collect();
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            if let Err(e) = hat.check_space(0) {
+                println!("Not starting commit: {}", e);
+                std::process::exit(1);
+            }
+
+            // Report the progress of every job until they are all done.
+            let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            {
+                let done = done.clone();
+                let controls: Vec<_> =
+                    jobs.iter().map(|j| (j.family_name.clone(), j.control.clone())).collect();
+                std::thread::spawn(move || while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                    for &(ref name, ref control) in &controls {
+                        println!("{}: {} files, {} bytes", name, control.files(), control.bytes());
+                    }
+                });
+            }
+            let results = hat.run_snapshot_jobs(&jobs);
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+
+            if let Err(e) = hat.meta_commit().and_then(|()| hat.data_flush()) {
+                fail("Could not store the snapshots", e);
+            }
+            if !cmd.is_present("no-index-backup") {
+                if let Err(e) = hat.backup_index_if_due(index_backup_interval(cmd)) {
+                    println!("Could not back up the index: {}", e);
+                }
+            }
+
+            let mut exit = hat::hat::ExitCode::Success;
+            for (job, result) in jobs.iter().zip(results) {
+                match result {
+                    Ok(stats) => {
+                        println!("{}: committed; {}", job.family_name, stats);
+                        if stats.skipped_files > 0 || stats.fuzzy_files > 0 {
+                            exit = exit.combine(hat::hat::ExitCode::Warnings);
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}: failed: {}", job.family_name, e);
+                        exit = exit.combine(e.kind().into());
+                    }
+                }
+            }
+            if exit != hat::hat::ExitCode::Success {
+                exit_with(exit);
+            }
         }
         ("checkout", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+
+            let mut policy = hat::hat::MetadataPolicy::detect();
+            if cmd.is_present("owner") {
+                policy.owner = true;
+            }
+            if cmd.is_present("no-owner") {
+                policy.owner = false;
+            }
+            policy.permissions = !cmd.is_present("no-perms");
+            policy.times = !cmd.is_present("no-times");
+            if cmd.is_present("no-attrs") {
+                policy.attributes = false;
+            }
+            let order = match cmd.value_of("order") {
+                None => hat::hat::RestoreOrder::Tree,
+                Some(o) => hat::hat::RestoreOrder::parse(o).unwrap_or_else(|e| {
+                    println!("--order: {}", e);
+                    std::process::exit(1);
+                }),
+            };
+
+            let selection = cmd.value_of("files-from").map(|file| {
+                let mut manifest = vec![];
+                let read = if file == "-" {
+                    std::io::Read::read_to_end(&mut std::io::stdin(), &mut manifest)
+                } else {
+                    std::fs::File::open(file)
+                        .and_then(|mut f| std::io::Read::read_to_end(&mut f, &mut manifest))
+                };
+                if let Err(e) = read {
+                    println!("Could not read {}: {}", file, e);
+                    std::process::exit(1);
+                }
+                hat::hat::PathSelection::from_manifest(&manifest, cmd.is_present("null"))
+            });
+            let selection = match cmd.values_of("type") {
+                Some(types) => {
+                    let mut selection = selection.unwrap_or_else(hat::hat::PathSelection::all);
+                    for t in types {
+                        selection.add_type(t);
+                    }
+                    Some(selection)
+                }
+                None => selection,
+            };
 
-            hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
+            // Without a choice, a checkout stops at the first file that is already there.
+            let choices = [
+                ("overwrite", hat::hat::CollisionPolicy::Overwrite),
+                ("skip-existing", hat::hat::CollisionPolicy::SkipExisting),
+                ("only-if-newer", hat::hat::CollisionPolicy::OnlyIfNewer),
+                ("backup-existing", hat::hat::CollisionPolicy::BackupExisting),
+            ];
+            let chosen: Vec<_> = choices.iter().filter(|&&(arg, _)| cmd.is_present(arg)).collect();
+            let collisions = match chosen.len() {
+                0 => hat::hat::CollisionPolicy::default(),
+                1 => chosen[0].1,
+                _ => {
+                    println!(
+                        "Choose one of --overwrite, --skip-existing, --only-if-newer and \
+                         --backup-existing"
+                    );
+                    std::process::exit(1);
+                }
+            };
+
+            // Report the progress until the checkout is done.
+            let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            if cmd.is_present("progress") {
+                let progress = hat::hat::RestoreProgress::new();
+                hat.set_restore_progress(Some(progress.clone()));
+                let done = done.clone();
+                std::thread::spawn(move || while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                    eprintln!("{}", progress.status());
+                });
+            }
+            hat.checkout_selected_in_dir(
+                name,
+                PathBuf::from(path),
+                &policy,
+                order,
+                selection.as_ref(),
+                collisions,
+            ).unwrap();
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+            if matches.is_present("cache-stats") {
+                eprintln!("Node cache: {}", hat.node_cache_stats());
+            }
+        }
+        ("export", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let path = cmd.value_of("PATH").unwrap();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+            if !cmd.is_present("tar") {
+                eprintln!("Only --tar exports are supported");
+                std::process::exit(1);
+            }
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+
+            let stdout = std::io::stdout();
+            let out = std::io::BufWriter::new(stdout.lock());
+            let mut out = match hat.export_tar(name, id, Path::new(path), out) {
+                Ok(out) => out,
+                Err(e) => {
+                    eprintln!("Export failed: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            out.flush().unwrap();
+            if matches.is_present("cache-stats") {
+                eprintln!("Node cache: {}", hat.node_cache_stats());
+            }
+        }
+        ("ls", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let path = cmd.value_of("PATH").unwrap_or("");
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let listing = hat.list_dir(name, id, Path::new(path), cmd.is_present("check"))
+                .unwrap_or_else(|e| fail("Listing failed", e));
+            let mut dangling = 0;
+            for listed in listing {
+                let name = String::from_utf8_lossy(&listed.entry.info.name[..]);
+                match (listed.is_dir, listed.link_target) {
+                    (true, _) => println!("{}/", name),
+                    (false, Some(target)) => println!("{} -> {}", name, target.display()),
+                    (false, None) => {
+                        println!("{}  {}", name, listed.entry.info.byte_length.unwrap_or(0))
+                    }
+                }
+                if let Some(problem) = listed.dangling {
+                    println!("    Dangling: {}", problem);
+                    dangling += 1;
+                }
+            }
+            if dangling > 0 {
+                println!("{} dangling entries", dangling);
+                exit_with(hat::hat::ExitCode::Verification);
+            }
+        }
+        ("deleted", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            match cmd.value_of("PATH") {
+                Some(path) => {
+                    for deletion in hat.path_deletions(Some(&name), Path::new(path)) {
+                        println!(
+                            "{} #{}  {}",
+                            deletion.family_name,
+                            deletion.snapshot_id,
+                            deletion.created.format("%Y-%m-%d %H:%M:%S")
+                        );
+                    }
+                }
+                None => {
+                    let paths = hat.deleted_paths(&name, id)
+                        .unwrap_or_else(|e| fail("Listing deletions failed", e));
+                    for path in paths {
+                        println!("{}", path.display());
+                    }
+                }
+            }
+        }
+        ("excluded", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let excluded = hat.excluded_dirs(&name, id)
+                .unwrap_or_else(|e| fail("Listing excluded directories failed", e));
+            for (path, reason) in excluded {
+                println!("{}  ({})", path.display(), reason);
+            }
+        }
+        ("history", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap();
+            let path = cmd.value_of("PATH").unwrap();
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let versions = hat.path_history(name, Path::new(path))
+                .unwrap_or_else(|e| fail("Listing the history failed", e));
+            for version in versions {
+                let created = version.created.format("%Y-%m-%d %H:%M:%S");
+                if version.deleted {
+                    println!("#{}  {}  deleted", version.snapshot_id, created);
+                    continue;
+                }
+                let modified = version.modified.map_or("-".to_owned(), |m| {
+                    chrono::NaiveDateTime::from_timestamp(m as i64, 0)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                });
+                let hash = version.hash.as_ref().map_or("-".to_owned(), |h| {
+                    h.iter().map(|b| format!("{:02x}", b)).collect()
+                });
+                println!(
+                    "#{}  {}  {} bytes  modified {}  {}{}",
+                    version.snapshot_id,
+                    created,
+                    version.size.map_or("-".to_owned(), |s| s.to_string()),
+                    modified,
+                    hash,
+                    if version.changed { "  changed" } else { "" }
+                );
+            }
+        }
+        ("status", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let path = cmd.value_of("PATH").unwrap();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            let changes = hat.status(name, id, Path::new(path)).unwrap_or_else(|e| {
+                println!("Status failed: {}", e);
+                std::process::exit(1);
+            });
+            for (change, path) in changes {
+                let mark = match change {
+                    hat::hat::Change::New => "A",
+                    hat::hat::Change::Modified => "M",
+                    hat::hat::Change::Deleted => "D",
+                };
+                println!("{} {}", mark, path.display());
+            }
+            if matches.is_present("cache-stats") {
+                eprintln!("Node cache: {}", hat.node_cache_stats());
+            }
+        }
+        ("check", Some(cmd)) => {
+            if cmd.is_present("trees-only") {
+                let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+                let report = hat.check_trees().unwrap_or_else(|e| fail("Check failed", e));
+                for error in &report.errors {
+                    println!("{}", error);
+                }
+                println!("Checked trees: {}", report);
+                if !report.errors.is_empty() {
+                    exit_with(hat::hat::ExitCode::Verification);
+                }
+                return;
+            }
+
+            let subset = match cmd.value_of("read-data-subset") {
+                None => hat::hat::Subset::all(),
+                Some(s) => {
+                    hat::hat::Subset::parse(s).unwrap_or_else(|e| {
+                        println!("--read-data-subset: {}", e);
+                        std::process::exit(1);
+                    })
+                }
+            };
+
+            let max_duration = cmd.value_of("max-duration").map(|t| match t.parse::<u64>() {
+                Ok(secs) => std::time::Duration::from_secs(secs),
+                Err(_) => {
+                    println!("--max-duration must be a number of seconds");
+                    std::process::exit(1);
+                }
+            });
+
+            let limit = |arg: &str, default: usize| match cmd.value_of(arg) {
+                None => default,
+                Some(n) => {
+                    match n.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => {
+                            println!("--{} must be a positive number", arg);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            };
+            let defaults = hat::hat::CheckLimits::default();
+            let limits = hat::hat::CheckLimits {
+                fetchers: limit("fetchers", defaults.fetchers),
+                hashers: limit("hashers", defaults.hashers),
+            };
+
+            let hat = open_repository(migrations_dir, &cache_dir, &repo);
+            if cmd.is_present("restart") {
+                hat.reset_check(subset);
+            }
+            let report = hat.check_with(subset, max_duration, limits)
+                .unwrap_or_else(|e| fail("Check failed", e));
+            for error in &report.errors {
+                println!("{}", error);
+            }
+            println!("Checked part {}: {}", subset, report);
+            if !report.errors.is_empty() {
+                exit_with(hat::hat::ExitCode::Verification);
+            }
+        }
+        ("test-restore", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+            let sample = cmd.value_of("sample").map_or(20, |n| {
+                n.parse::<usize>().expect("--sample must be a number")
+            });
+            let scratch = cmd.value_of("dir").map_or_else(env::temp_dir, PathBuf::from).join(
+                format!("hat-test-restore-{}", chrono::Utc::now().timestamp_nanos()),
+            );
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            let report = hat.restore_drill(name, id, sample, &scratch)
+                .unwrap_or_else(|e| fail("Restore test failed", e));
+            for &(ref path, ref error) in &report.failures {
+                println!("{}: {}", path.display(), error);
+            }
+            println!("{}", report);
+            if !report.is_pass() {
+                exit_with(hat::hat::ExitCode::Verification);
+            }
+        }
+        ("stage", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            let report = hat.stage(name, id).unwrap_or_else(|e| fail("Staging failed", e));
+            println!("{}", report);
+            if !report.is_ready() {
+                exit_with(hat::hat::ExitCode::Warnings);
+            }
         }
         ("recover", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
 
             hat.recover().unwrap();
         }
+        ("bootstrap", Some(_cmd)) => {
+            let backend = repo_backend(&repo);
+            let names = hat::hat::HatRc::<RepoBackend>::bootstrap_index(
+                &state_dir(&cache_dir, repo.namespace),
+                &backend,
+                &master_key(&repo),
+            ).unwrap_or_else(|e| {
+                println!("Could not restore the index: {}", e);
+                std::process::exit(1);
+            });
+            println!("Restored {} index files", names.len());
+
+            // Opening finishes any command that was in progress when the backup was taken.
+            open_repository(migrations_dir, &cache_dir, &repo);
+        }
         ("delete", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let id = cmd.value_of("ID").unwrap().to_owned();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+
+            if cmd.is_present("now") {
+                hat.deregister_by_name(name, id.parse::<u64>().unwrap())
+                    .unwrap();
+            } else {
+                hat.trash_by_name(name, id.parse::<u64>().unwrap())
                     .unwrap();
+            }
+        }
+        ("offline", Some(cmd)) => {
+            let (op, args) = match cmd.subcommand() {
+                (op, Some(args)) => (op, args),
+                _ => {
+                    println!("{}", cmd.usage());
+                    std::process::exit(1);
+                }
+            };
+            let offline = hat::hat::OfflineRepository::open_with_key(
+                Arc::new(repo_backend(&repo)),
+                &master_key(&repo),
+            ).unwrap_or_else(|e| fail("Could not read the blobs", e));
+            let id = args.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+
+            match op {
+                "snapshots" => {
+                    for snapshot in offline.snapshots() {
+                        println!(
+                            "{} #{}  {}  {}{}",
+                            snapshot.family_name,
+                            snapshot.snapshot_id,
+                            snapshot.created.format("%Y-%m-%d %H:%M:%S"),
+                            snapshot.msg,
+                            if snapshot.trashed.is_some() { " (in trash)" } else { "" }
+                        );
+                    }
+                }
+                "ls" => {
+                    let name = args.value_of("NAME").unwrap();
+                    let path = Path::new(args.value_of("PATH").unwrap_or(""));
+                    let listing = offline
+                        .list_dir(name, id, path)
+                        .unwrap_or_else(|e| fail("Listing failed", e));
+                    for listed in listing {
+                        let name = String::from_utf8_lossy(&listed.entry.info.name[..]);
+                        match (listed.is_dir, listed.link_target) {
+                            (true, _) => println!("{}/", name),
+                            (false, Some(target)) => println!("{} -> {}", name, target.display()),
+                            (false, None) => {
+                                println!("{}  {}", name, listed.entry.info.byte_length.unwrap_or(0))
+                            }
+                        }
+                    }
+                }
+                "checkout" => {
+                    let name = args.value_of("NAME").unwrap();
+                    let dest = Path::new(args.value_of("DEST").unwrap());
+                    let path = Path::new(args.value_of("PATH").unwrap_or(""));
+                    offline
+                        .checkout(name, id, path, dest, &hat::hat::MetadataPolicy::detect())
+                        .unwrap_or_else(|e| fail("Checkout failed", e));
+                }
+                _ => unreachable!(),
+            }
+        }
+        ("snapshot", Some(cmd)) => {
+            let (op, args) = match cmd.subcommand() {
+                (op, Some(args)) => (op, args),
+                _ => {
+                    println!("{}", cmd.usage());
+                    std::process::exit(1);
+                }
+            };
+            let name = args.value_of("NAME").unwrap().to_owned();
+            let id = args.value_of("ID").unwrap().parse::<u64>().unwrap();
+            let new_name = args.value_of("NEW_NAME").unwrap().to_owned();
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+
+            let new_id = match op {
+                "clone" => hat.clone_snapshot(name, id, new_name.clone()).unwrap(),
+                "rename" => hat.rename_snapshot(name, id, new_name.clone()).unwrap(),
+                _ => unreachable!(),
+            };
+            println!("{} #{}", new_name, new_id);
+        }
+        ("undelete", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("ID").unwrap().to_owned();
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
 
-            hat.deregister_by_name(name, id.parse::<u64>().unwrap())
+            hat.undelete_by_name(name, id.parse::<u64>().unwrap())
                 .unwrap();
         }
-        ("gc", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
-            let (deleted_hashes, live_blobs) = hat.gc().unwrap();
+        ("find", Some(cmd)) => {
+            let pattern = cmd.value_of("PATTERN").unwrap();
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let found = hat.find_paths(pattern, cmd.value_of("family"));
+            for m in &found {
+                let ids: Vec<String> = m.snapshot_ids.iter().map(|id| id.to_string()).collect();
+                println!("{} #{}  {}", m.family_name, ids.join(","), m.path.display());
+            }
+            if found.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("dupes", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<u64>().expect("--id must be a number")
+            });
+            let limit = cmd.value_of("limit").map(|n| {
+                n.parse::<usize>().expect("--limit must be a number")
+            });
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let groups = hat.duplicate_files(name, id)
+                .unwrap_or_else(|e| fail("Listing duplicates failed", e));
+            let total: u64 = groups.iter().map(|g| g.savings()).sum();
+            let shown = limit.unwrap_or(groups.len());
+            for group in groups.iter().take(shown) {
+                println!(
+                    "{} copies of {} bytes, {} bytes shared",
+                    group.paths.len(),
+                    group.size,
+                    group.savings()
+                );
+                for path in &group.paths {
+                    println!("    {}", path.display());
+                }
+            }
+            println!(
+                "{} groups of duplicate files, {} bytes shared in total",
+                groups.len(),
+                total
+            );
+        }
+        ("grep", Some(cmd)) => {
+            if cmd.is_present("purge") {
+                let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+                println!("Forgot {} indexed words", hat.purge_text_index());
+                return;
+            }
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let word = cmd.value_of("WORD").unwrap_or_else(|| {
+                println!("{}", cmd.usage());
+                std::process::exit(1);
+            });
+            let found = hat.grep_snapshots(word, cmd.value_of("family"));
+            for m in &found {
+                let ids: Vec<String> = m.snapshot_ids.iter().map(|id| id.to_string()).collect();
+                println!("{} #{}  {}", m.family_name, ids.join(","), m.path.display());
+            }
+            if found.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("dump-index", Some(cmd)) => {
+            match cmd.value_of("format") {
+                None | Some("csv") => (),
+                Some(other) => {
+                    eprintln!("Unsupported format {}: only csv is supported", other);
+                    std::process::exit(1);
+                }
+            }
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            let dumped = if cmd.is_present("keys") {
+                hat.dump_key_index(&mut out)
+            } else {
+                hat.dump_hash_index(&mut out)
+            };
+            if let Err(e) = dumped {
+                eprintln!("Dump failed: {}", e);
+                std::process::exit(1);
+            }
+            out.flush().unwrap();
+        }
+        ("snapshots", Some(cmd)) => {
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            for snapshot in hat.list_snapshots() {
+                println!(
+                    "{} #{}  {}  {}{}{}",
+                    snapshot.family_name,
+                    snapshot.snapshot_id,
+                    snapshot.created.format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.msg.as_ref().map_or("", |m| &m[..]),
+                    if snapshot.pinned { " (pinned)" } else { "" },
+                    if snapshot.trashed { " (in trash)" } else { "" }
+                );
+                if !cmd.is_present("verbose") {
+                    continue;
+                }
+                match snapshot.stats {
+                    Some(stats) => {
+                        println!(
+                            "    Read {} bytes, uploaded {} bytes",
+                            stats.bytes_read,
+                            stats.bytes_uploaded
+                        );
+                        println!(
+                            "    Chunks: {} new, {} reused",
+                            stats.new_chunks,
+                            stats.reused_chunks
+                        );
+                        println!(
+                            "    Took {}.{:03} s, {} bytes/s",
+                            stats.wall_time_ms / 1000,
+                            stats.wall_time_ms % 1000,
+                            stats.throughput()
+                        );
+                        println!("    {}", stats);
+                    }
+                    None => println!("    No statistics recorded"),
+                }
+                for &(ref from, ref to) in &snapshot.renames {
+                    println!("    Renamed {} -> {}", from.display(), to.display());
+                }
+                for path in &snapshot.fuzzy_files {
+                    println!("    Changed while reading: {}", path.display());
+                }
+                for run in &snapshot.commands {
+                    println!("    Command {}", run);
+                }
+            }
+        }
+        ("info", Some(_)) => {
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let info = hat.repo_info().unwrap_or_else(|e| fail("Could not read the repository", e));
+            println!("{}", info);
+        }
+        ("stats", Some(cmd)) => {
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            if cmd.is_present("types") {
+                for usage in hat.content_type_usage().unwrap() {
+                    println!("{}", usage);
+                }
+            } else {
+                for usage in hat.family_usage().unwrap() {
+                    println!("{}", usage);
+                }
+            }
+        }
+        ("graph", Some(cmd)) => {
+            if cmd.is_present("dot") && cmd.is_present("json") {
+                println!("Choose one of --dot and --json");
+                std::process::exit(1);
+            }
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            if let Err(e) = hat.write_snapshot_graph(cmd.is_present("json"), &mut out) {
+                eprintln!("Could not write the graph: {}", e);
+                std::process::exit(1);
+            }
+            out.flush().unwrap();
+        }
+        ("attest", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap();
+            let id = cmd.value_of("ID").unwrap().parse::<u64>().unwrap();
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            match hat.attest(name, id) {
+                Ok(statement) => print!("{}", statement),
+                Err(e) => fail("Could not attest the snapshot", e),
+            }
+        }
+        ("verify-attestation", Some(cmd)) => {
+            use std::io::Read;
+
+            let file = cmd.value_of("FILE").unwrap();
+            let mut text = String::new();
+            let read = if file == "-" {
+                std::io::stdin().read_to_string(&mut text)
+            } else {
+                std::fs::File::open(file).and_then(|mut f| f.read_to_string(&mut text))
+            };
+            if let Err(e) = read {
+                println!("Could not read {}: {}", file, e);
+                std::process::exit(1);
+            }
+
+            let mut hat = open_repository_read_only(migrations_dir, &cache_dir, &repo);
+            match hat.verify_attestation(&text) {
+                Ok(attestation) => {
+                    println!(
+                        "Snapshot {} of family {} matches its attestation from {}",
+                        attestation.snapshot_id,
+                        attestation.family_name,
+                        attestation.attested.to_rfc3339()
+                    )
+                }
+                Err(e) => fail("Attestation does not match", e),
+            }
+        }
+        ("pin", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("ID").unwrap().parse::<u64>().unwrap();
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            hat.pin_by_name(name, id).unwrap();
+        }
+        ("unpin", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("ID").unwrap().parse::<u64>().unwrap();
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            hat.unpin_by_name(name, id).unwrap();
+        }
+        ("gc", Some(cmd)) => {
+            let grace_days = cmd.value_of("grace")
+                .map(|d| d.parse::<i64>().expect("--grace must be a number of days"))
+                .unwrap_or(7);
+            let max_duration = cmd.value_of("max-duration").map(|t| match t.parse::<u64>() {
+                Ok(secs) => std::time::Duration::from_secs(secs),
+                Err(_) => {
+                    println!("--max-duration must be a number of seconds");
+                    std::process::exit(1);
+                }
+            });
+            let max_rate = cmd.value_of("max-rate").map(|r| match r.parse::<u64>() {
+                Ok(rate) if rate > 0 => rate,
+                _ => {
+                    println!("--max-rate must be a positive number of bytes");
+                    std::process::exit(1);
+                }
+            });
+
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+            if cmd.is_present("dry-run") || cmd.is_present("pretend") {
+                let plan = hat.gc_plan(chrono::Duration::days(grace_days)).unwrap();
+                println!("Snapshots to purge from trash: {}", plan.purged_snapshots.len());
+                for &(ref name, id) in &plan.purged_snapshots {
+                    println!("  {} #{}", name, id);
+                }
+                println!("Unused chunks: {}", plan.dead_chunks);
+                println!("Unused blobs: {}", plan.dead_blobs);
+                println!("Estimated bytes freed: {}", plan.reclaimable_bytes);
+                if !plan.pinned.is_empty() {
+                    println!("Data only kept alive by a single snapshot:");
+                }
+                for pinned in plan.pinned.iter().take(10) {
+                    println!(
+                        "  {} #{}: {} chunks, {} bytes",
+                        pinned.family_name,
+                        pinned.snapshot_id,
+                        pinned.chunks,
+                        pinned.bytes
+                    );
+                }
+                return;
+            }
+
+            let purged = hat.purge_trash(chrono::Duration::days(grace_days)).unwrap();
+            println!("Purged snapshots from trash: {}", purged);
+            if cmd.is_present("offline") {
+                let (deleted_hashes, _) = hat.gc_offline().unwrap();
+                let (blobs, bytes) = hat.prune_pending();
+                println!("Deleted hashes: {:?}", deleted_hashes);
+                println!("Blobs marked for deletion: {} ({} bytes)", blobs, bytes);
+                println!("Run prune --execute to delete them");
+                return;
+            }
+            let (deleted_hashes, live_blobs) = hat.gc_offline().unwrap();
+            let throttle = max_rate.map(hat::hat::Throttle::new);
+            let pruned = hat.prune_for(max_duration, throttle.as_ref())
+                .unwrap_or_else(|e| fail("Deleting unused blobs failed", e));
             println!("Deleted hashes: {:?}", deleted_hashes);
             println!("Live data blobs after deletion: {:?}", live_blobs);
+            let (pending, _) = hat.prune_pending();
+            if pending > 0 {
+                println!("Deleted {} unused blobs, {} left for the next run", pruned, pending);
+            }
+
+        }
+        ("repo", Some(cmd)) => {
+            match cmd.subcommand() {
+                ("list", Some(_)) => {
+                    let namespaces = hat::hat::list_namespaces(&blob_dir(None)).unwrap();
+                    for ns in namespaces {
+                        println!("{}", ns.name());
+                    }
+                }
+                ("config", Some(args)) => {
+                    let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
+                    if let Some(size) = args.value_of("max-blob-size") {
+                        let size = parse_size(size).unwrap_or_else(|e| {
+                            println!("--max-blob-size: {}", e);
+                            std::process::exit(1);
+                        });
+                        hat.set_max_blob_size(size as usize)
+                            .unwrap_or_else(|e| fail("Changing the blob size failed", e));
+                    }
+                    print!("{}", hat.repository_config().unwrap().unwrap());
+                    let dir = blob_dir(namespace.as_ref());
+                    println!("layout {}", backend::Layout::read(&dir).unwrap());
+                }
+                ("layout", Some(args)) => {
+                    let dir = blob_dir(namespace.as_ref());
+                    let levels = match args.value_of("fan-out") {
+                        None => {
+                            println!("{}", backend::Layout::read(&dir).unwrap());
+                            return;
+                        }
+                        Some(n) => {
+                            match n.parse::<usize>() {
+                                Ok(n) if n <= backend::MAX_FAN_OUT => Some(n),
+                                _ => None,
+                            }
+                        }
+                    };
+                    let layout = match levels {
+                        Some(0) => backend::Layout::Flat,
+                        Some(n) => backend::Layout::FanOut(n),
+                        None => {
+                            println!("--fan-out must be between 0 and {}", backend::MAX_FAN_OUT);
+                            std::process::exit(1);
+                        }
+                    };
+
+                    // Keep the repository from being used while its blobs move.
+                    let local_state = state_dir(&cache_dir, namespace.as_ref());
+                    std::fs::create_dir_all(&local_state).unwrap();
+                    let _lock = hat::hat::RepositoryLock::acquire(&local_state)
+                        .unwrap_or_else(|e| fail("Could not lock the repository", e));
+                    let moved = backend::FileBackend::migrate_layout(&dir, layout).unwrap();
+                    println!("Moved {} blobs to the {} layout", moved, layout);
+                }
+                ("resolve-reserved", Some(_)) => {
+                    let hat = open_repository(migrations_dir, &cache_dir, &repo);
+                    let (completed, removed) = hat.resolve_reserved_hashes()
+                        .unwrap_or_else(|e| fail("Could not resolve reserved hashes", e));
+                    println!(
+                        "Completed {} and removed {} hashes left reserved by earlier runs",
+                        completed,
+                        removed
+                    );
+                }
+                _ => {
+                    println!("{}", cmd.usage());
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("tier", Some(cmd)) => {
+            let (op, args) = match cmd.subcommand() {
+                (op, Some(args)) => (op, args),
+                _ => {
+                    println!("{}", cmd.usage());
+                    std::process::exit(1);
+                }
+            };
+            let mut policy = backend::TierPolicy::default();
+            if let Some(days) = args.value_of("max-age") {
+                let days = days.parse::<u64>().expect("--max-age must be a number of days");
+                policy.max_age = std::time::Duration::from_secs(days * 24 * 60 * 60);
+            }
+            policy.max_hot_bytes = args.value_of("max-hot").map(|s| {
+                parse_size(s).unwrap_or_else(|e| {
+                    println!("--max-hot: {}", e);
+                    std::process::exit(1);
+                })
+            });
+
+            let tiers = repo_backend(&repo);
+            match op {
+                "status" => {
+                    let status = tiers.status(&policy).unwrap();
+                    println!("hot:  {} blobs, {} bytes", status.hot_blobs, status.hot_bytes);
+                    println!("cold: {} blobs, {} bytes", status.cold_blobs, status.cold_bytes);
+                    println!("due:  {} blobs, {} bytes", status.due_blobs, status.due_bytes);
+                }
+                _ => {
+                    if repo.cold_dir.is_none() {
+                        println!("Set --cold-dir to offload blobs");
+                        std::process::exit(1);
+                    }
+                    let (blobs, bytes) = tiers.offload(&policy).unwrap_or_else(|e| {
+                        println!("Offload failed: {}", e);
+                        std::process::exit(1);
+                    });
+                    println!("Moved {} blobs ({} bytes) to the cold tier", blobs, bytes);
+                }
+            }
+        }
+        ("key", Some(cmd)) => {
+            let path = recipients_file(namespace.as_ref());
+            let mut keys = if path.exists() {
+                KeyFile::load(&path).unwrap()
+            } else {
+                KeyFile::new()
+            };
+            let key_arg = |args: &clap::ArgMatches| read_key(args.value_of("KEY").unwrap());
+
+            match cmd.subcommand() {
+                ("add-recipient", Some(args)) => {
+                    let recipient = Recipient::parse(&key_arg(args)).unwrap_or_else(|e| {
+                        println!("Invalid recipient: {}", e);
+                        std::process::exit(1);
+                    });
+                    // A new repository gets a random key. An existing one without recipients
+                    // keeps its passphrase-derived key, so its data stays readable.
+                    let url = blob_url(repo.blobs, namespace.as_ref());
+                    let is_empty = open_backend(&url).list().unwrap().is_empty();
+                    let master = if !path.exists() && is_empty {
+                        MasterKey::generate()
+                    } else {
+                        checked_master_key(&repo)
+                    };
+                    keys.add(&master, recipient);
+                    keys.save(&path).unwrap();
+                }
+                ("remove-recipient", Some(args)) => {
+                    let before = keys.recipients().len();
+                    let removed = keys.remove(&key_arg(args));
+                    if removed == 0 {
+                        println!("No matching recipient");
+                        std::process::exit(1);
+                    }
+                    if removed == before {
+                        println!("Refusing to remove every recipient");
+                        std::process::exit(1);
+                    }
+                    keys.save(&path).unwrap();
+                    println!("Removed {} recipients", removed);
+                }
+                ("list-recipients", Some(_)) => {
+                    for recipient in keys.recipients() {
+                        println!("{}", recipient.text());
+                    }
+                }
+                ("wrap", Some(args)) => {
+                    let master = checked_master_key(&repo);
+                    let command = args.value_of("COMMAND").unwrap();
+                    let wrapped = hat::hat::CommandKey::wrap(command, &master).unwrap_or_else(|e| {
+                        println!("Could not wrap the repository key: {}", e);
+                        std::process::exit(1);
+                    });
+                    // Replace the wrapped key whole, so that it is never lost half-written.
+                    let path = wrapped_key_file(namespace.as_ref());
+                    let tmp = path.with_extension("tmp");
+                    std::fs::File::create(&tmp)
+                        .and_then(|mut f| writeln!(f, "{}", wrapped).and_then(|()| f.sync_all()))
+                        .and_then(|()| std::fs::rename(&tmp, &path))
+                        .unwrap_or_else(|e| {
+                            println!("Could not store {}: {}", path.display(), e);
+                            std::process::exit(1);
+                        });
+                    println!("Unlock with --key-provider='command:<unwrap command>'");
+                }
+                _ => {
+                    println!("{}", cmd.usage());
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("prune", Some(cmd)) => {
+            let mut hat = open_repository(migrations_dir, &cache_dir, &repo);
 
+            let (blobs, bytes) = hat.prune_pending();
+            if cmd.is_present("execute") {
+                let deleted = hat.prune().unwrap();
+                if blobs > 0 && !hat.can_delete() {
+                    println!("The backend does not support deleting blobs, nothing was deleted");
+                } else {
+                    println!("Deleted blobs: {} ({} bytes)", deleted, bytes);
+                }
+            } else {
+                println!("Blobs marked for deletion: {} ({} bytes)", blobs, bytes);
+            }
         }
         _ => {
             println!(
@@ -18,6 +18,7 @@
 use chrono;
 use db;
 use hash;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tags;
 
@@ -46,8 +47,13 @@ impl SnapshotIndex {
         self.index.lock().snapshot_lookup(family_name, snapshot_id)
     }
 
-    pub fn reserve(&mut self, family: String) -> db::SnapshotInfo {
-        self.index.lock().snapshot_reserve(family)
+    /// Reserve the next snapshot of `family`, taken at `created`.
+    pub fn reserve(
+        &mut self,
+        family: String,
+        created: chrono::DateTime<chrono::Utc>,
+    ) -> db::SnapshotInfo {
+        self.index.lock().snapshot_reserve(family, created)
     }
 
     /// Update existing snapshot.
@@ -57,12 +63,18 @@ impl SnapshotIndex {
         hash: &hash::Hash,
         hash_ref: &hash::tree::HashRef,
     ) {
-        self.index.lock().snapshot_update(
-            snapshot,
-            "anonymous",
-            hash,
-            hash_ref,
-        );
+        self.update_with_msg(snapshot, "anonymous", hash, hash_ref)
+    }
+
+    /// Update existing snapshot and set its message.
+    pub fn update_with_msg(
+        &mut self,
+        snapshot: &db::SnapshotInfo,
+        msg: &str,
+        hash: &hash::Hash,
+        hash_ref: &hash::tree::HashRef,
+    ) {
+        self.index.lock().snapshot_update(snapshot, msg, hash, hash_ref);
     }
 
     /// ReadyCommit.
@@ -97,6 +109,123 @@ impl SnapshotIndex {
         )
     }
 
+    /// Give this snapshot a new family and snapshot id. The snapshot data is untouched.
+    pub fn rename(&mut self, snapshot: &db::SnapshotInfo, family: &str) -> db::SnapshotInfo {
+        self.index.lock().snapshot_move(snapshot, family)
+    }
+
+    /// Move this snapshot to the trash. It stays registered with the GC until it is purged.
+    pub fn trash(&mut self, snapshot: &db::SnapshotInfo, when: chrono::DateTime<chrono::Utc>) {
+        self.index.lock().snapshot_set_trashed(snapshot, Some(when))
+    }
+
+    /// Restore this snapshot from the trash.
+    pub fn untrash(&mut self, snapshot: &db::SnapshotInfo) {
+        self.index.lock().snapshot_set_trashed(snapshot, None)
+    }
+
+    /// Exempt this snapshot from deletion until it is unpinned.
+    pub fn pin(&mut self, snapshot: &db::SnapshotInfo) {
+        self.index.lock().snapshot_set_pinned(snapshot, true)
+    }
+
+    /// Allow this snapshot to be deleted again.
+    pub fn unpin(&mut self, snapshot: &db::SnapshotInfo) {
+        self.index.lock().snapshot_set_pinned(snapshot, false)
+    }
+
+    pub fn is_pinned(&mut self, snapshot: &db::SnapshotInfo) -> bool {
+        self.index.lock().snapshot_is_pinned(snapshot)
+    }
+
+    /// Record what the run that created this snapshot read and stored.
+    pub fn set_stats(&mut self, snapshot: &db::SnapshotInfo, stats: &db::SnapshotStats) {
+        self.index.lock().snapshot_set_stats(snapshot, stats)
+    }
+
+    /// Record the files moved since the previous snapshot.
+    pub fn set_renames(&mut self, snapshot: &db::SnapshotInfo, renames: &[(PathBuf, PathBuf)]) {
+        self.index.lock().snapshot_set_renames(snapshot, renames)
+    }
+
+    /// Record the paths deleted since the previous snapshot.
+    pub fn set_deletions(&mut self, snapshot: &db::SnapshotInfo, paths: &[PathBuf]) {
+        self.index.lock().snapshot_set_deletions(snapshot, paths)
+    }
+
+    /// The paths deleted since the previous snapshot of the same family.
+    pub fn deletions(&mut self, snapshot: &db::SnapshotInfo) -> Vec<PathBuf> {
+        self.index.lock().snapshot_deletions(snapshot)
+    }
+
+    /// Record the directories left out of the snapshot, and why.
+    pub fn set_exclusions(&mut self, snapshot: &db::SnapshotInfo, excluded: &[(PathBuf, String)]) {
+        self.index.lock().snapshot_set_exclusions(snapshot, excluded)
+    }
+
+    /// The directories left out of the snapshot, sorted, with the reason they were.
+    pub fn exclusions(&mut self, snapshot: &db::SnapshotInfo) -> Vec<(PathBuf, String)> {
+        self.index.lock().snapshot_exclusions(snapshot)
+    }
+
+    /// The snapshots that recorded `path` as deleted, of one family if given, oldest first.
+    pub fn path_deletions(&mut self, family: Option<&str>, path: &[u8]) -> Vec<db::PathDeletion> {
+        self.index.lock().path_deletions(family, path)
+    }
+
+    /// The snapshots of `family` that contained `path` or recorded it as deleted, oldest first.
+    pub fn path_history(&mut self, family: &str, path: &[u8]) -> Option<Vec<db::PathVersion>> {
+        self.index.lock().path_history(family, path)
+    }
+
+    /// Record the files that changed while they were read for this snapshot.
+    pub fn set_fuzzy_files(&mut self, snapshot: &db::SnapshotInfo, paths: &[PathBuf]) {
+        self.index.lock().snapshot_set_fuzzy_files(snapshot, paths)
+    }
+
+    /// Record how the commands whose output is stored in this snapshot ended.
+    pub fn set_commands(&mut self, snapshot: &db::SnapshotInfo, runs: &[db::CommandRun]) {
+        self.index.lock().snapshot_set_commands(snapshot, runs)
+    }
+
+    /// Record the paths in the snapshot and the hashes of their data.
+    pub fn set_paths(&mut self, snapshot: &db::SnapshotInfo, paths: &[db::PathRecord]) {
+        self.index.lock().snapshot_set_paths(snapshot, paths)
+    }
+
+    /// The recorded paths, of one family if given, with the given name if given.
+    pub fn paths(
+        &mut self,
+        family: Option<&str>,
+        name: Option<&[u8]>,
+    ) -> Vec<(String, db::SnapshotPath)> {
+        self.index.lock().snapshot_paths(family, name)
+    }
+
+    /// The recorded paths whose data has one of `hashes`, of one family if given.
+    pub fn paths_with_data(
+        &mut self,
+        family: Option<&str>,
+        hashes: &[Vec<u8>],
+    ) -> Vec<(String, db::SnapshotPath)> {
+        self.index.lock().snapshot_paths_with_data(family, hashes)
+    }
+
+    /// Remember the words in the data with these hashes.
+    pub fn add_text_words(&mut self, words: &[(Vec<u8>, String)]) {
+        self.index.lock().text_words_add(words)
+    }
+
+    /// The hashes of the data containing `word`.
+    pub fn text_word_hashes(&mut self, word: &str) -> Vec<Vec<u8>> {
+        self.index.lock().text_word_hashes(word)
+    }
+
+    /// Forget all remembered words. Returns how many there were.
+    pub fn purge_text_words(&mut self) -> usize {
+        self.index.lock().text_words_purge()
+    }
+
     /// Extract latest snapshot data for family.
     pub fn latest(
         &mut self,
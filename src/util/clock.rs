@@ -0,0 +1,98 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The current time, replaceable so that tests and golden-file runs give the same results
+//! every time.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::sync::Mutex;
+
+
+/// Tells the time for everything that records or compares against it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The time of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when it is told to, or by a fixed step every time it is read.
+#[derive(Debug)]
+pub struct FixedClock {
+    now: Mutex<DateTime<Utc>>,
+    step: Duration,
+}
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> FixedClock {
+        FixedClock::stepping(now, Duration::zero())
+    }
+
+    /// A clock that moves `step` ahead after every reading, so that times stay distinct.
+    pub fn stepping(start: DateTime<Utc>, step: Duration) -> FixedClock {
+        FixedClock {
+            now: Mutex::new(start),
+            step: step,
+        }
+    }
+
+    /// A stepping clock starting at 2000-01-01 00:00:00 UTC, for reproducible runs.
+    pub fn deterministic() -> FixedClock {
+        FixedClock::stepping(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0), Duration::seconds(1))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        let mut now = self.now.lock().unwrap();
+        let current = *now;
+        *now = current + self.step;
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock_moves_when_told() {
+        let start = Utc.ymd(2017, 1, 31).and_hms(23, 59, 59);
+        let clock = FixedClock::new(start);
+        assert_eq!(start, clock.now());
+        assert_eq!(start, clock.now());
+        clock.advance(Duration::seconds(1));
+        assert_eq!(Utc.ymd(2017, 2, 1).and_hms(0, 0, 0), clock.now());
+
+        let clock = FixedClock::deterministic();
+        assert_eq!(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0), clock.now());
+        assert_eq!(Utc.ymd(2000, 1, 1).and_hms(0, 0, 1), clock.now());
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Sample data for benchmarks, so that results can be compared across kinds of input.
+
+use rand::{Rng, thread_rng};
+use std::env;
+use std::fs;
+use std::io::Read;
+
+
+/// Kinds of file contents that behave differently under chunking, hashing and compression.
+#[derive(Clone, Copy, Debug)]
+pub enum Corpus {
+    /// Words and lines of text; compresses well.
+    Text,
+    /// The running executable; structured, partly repetitive data.
+    Binary,
+    /// Incompressible bytes without any repetition.
+    Random,
+}
+
+const WORDS: &'static [&'static str] = &[
+    "the", "backup", "of", "a", "snapshot", "is", "stored", "in", "blobs", "and", "each", "file",
+    "chunk", "hash", "tree", "index", "with", "key", "family", "restore", "data", "to",
+];
+
+/// `len` bytes of the given kind.
+pub fn generate(corpus: Corpus, len: usize) -> Vec<u8> {
+    let mut rng = thread_rng();
+    let mut out = Vec::with_capacity(len);
+    match corpus {
+        Corpus::Text => {
+            while out.len() < len {
+                let word = WORDS[rng.gen_range(0, WORDS.len())];
+                out.extend_from_slice(word.as_bytes());
+                out.push(if rng.gen_weighted_bool(12) { b'\n' } else { b' ' });
+            }
+        }
+        Corpus::Binary => {
+            let mut exe = vec![];
+            fs::File::open(env::current_exe().unwrap())
+                .and_then(|mut f| f.read_to_end(&mut exe))
+                .unwrap();
+            assert!(!exe.is_empty());
+            while out.len() < len {
+                out.extend_from_slice(&exe[..]);
+            }
+        }
+        Corpus::Random => {
+            out.resize(len, 0);
+            rng.fill_bytes(&mut out[..]);
+        }
+    }
+    out.truncate(len);
+    out
+}
@@ -0,0 +1,218 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Directories opened by descriptor, to reach entries whose paths are too long to resolve.
+
+use libc;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+
+/// Longest path the kernel resolves, counting the terminating nul.
+pub const PATH_MAX: usize = libc::PATH_MAX as usize;
+
+/// Longest name of a single directory entry.
+pub const NAME_MAX: usize = 255;
+
+const DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+
+fn check(fd: libc::c_int) -> io::Result<libc::c_int> {
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
+}
+
+/// An open directory. Its entries are found by name relative to it, so their full paths are
+/// never resolved.
+pub struct DirFd {
+    file: fs::File,
+}
+
+impl DirFd {
+    /// Open the directory at `path`.
+    pub fn open(path: &Path) -> io::Result<DirFd> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = check(unsafe { libc::open(c_path.as_ptr(), DIR_FLAGS) })?;
+        Ok(DirFd { file: unsafe { fs::File::from_raw_fd(fd) } })
+    }
+
+    fn open_at(&self, name: &OsStr, flags: libc::c_int) -> io::Result<fs::File> {
+        let c_name = CString::new(name.as_bytes())?;
+        let fd = check(unsafe { libc::openat(self.file.as_raw_fd(), c_name.as_ptr(), flags) })?;
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    /// Open the directory `name` in this one, without following symlinks.
+    pub fn open_dir(&self, name: &OsStr) -> io::Result<DirFd> {
+        Ok(DirFd { file: self.open_at(name, DIR_FLAGS | libc::O_NOFOLLOW)? })
+    }
+
+    /// Open the file `name` in this one for reading, without following symlinks.
+    pub fn open_file(&self, name: &OsStr) -> io::Result<fs::File> {
+        let flags = libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        self.open_at(name, flags)
+    }
+
+    /// The metadata of `name` in this directory; of the symlink itself if it is one.
+    pub fn symlink_metadata(&self, name: &OsStr) -> io::Result<fs::Metadata> {
+        // A path descriptor can be taken of any kind of file without opening it.
+        self.open_at(name, libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC)?.metadata()
+    }
+
+    /// The target of the symlink `name` in this directory.
+    pub fn read_link(&self, name: &OsStr) -> io::Result<PathBuf> {
+        let c_name = CString::new(name.as_bytes())?;
+        let mut buf = vec![0u8; 256];
+        loop {
+            let len = unsafe {
+                libc::readlinkat(
+                    self.file.as_raw_fd(),
+                    c_name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if (len as usize) < buf.len() {
+                buf.truncate(len as usize);
+                return Ok(PathBuf::from(OsString::from_vec(buf)));
+            }
+            // The target may have been cut short; try again with more room.
+            let doubled = buf.len() * 2;
+            buf.resize(doubled, 0);
+        }
+    }
+
+    /// The names of the entries in this directory, other than `.` and `..`.
+    pub fn read_dir(&self) -> io::Result<Vec<OsString>> {
+        // The stream takes over the descriptor it is given, so give it a copy.
+        let fd = check(unsafe { libc::dup(self.file.as_raw_fd()) })?;
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        // The copy shares its offset with this descriptor, which may have been read before.
+        unsafe { libc::rewinddir(stream) };
+
+        let mut names = vec![];
+        let mut error = None;
+        loop {
+            // The end of the stream leaves errno alone, unlike a failure.
+            unsafe { *libc::__errno_location() = 0 };
+            let entry = unsafe { libc::readdir(stream) };
+            if entry.is_null() {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() != Some(0) {
+                    error = Some(e);
+                }
+                break;
+            }
+            let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+            if name != b"." && name != b".." {
+                names.push(OsString::from_vec(name.to_vec()));
+            }
+        }
+        unsafe { libc::closedir(stream) };
+        match error {
+            Some(e) => Err(e),
+            None => Ok(names),
+        }
+    }
+}
+
+impl AsRawFd for DirFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Where to find a file: by its path, or by name in an open directory when its path is too
+/// long to resolve.
+#[derive(Clone)]
+pub enum Location {
+    Path(PathBuf),
+    At(Arc<DirFd>, OsString),
+}
+
+impl Location {
+    /// Open the file for reading.
+    pub fn open(&self) -> io::Result<fs::File> {
+        match *self {
+            Location::Path(ref path) => fs::File::open(path),
+            Location::At(ref dir, ref name) => dir.open_file(name),
+        }
+    }
+
+    /// The metadata of the file; of the symlink itself if it is one.
+    pub fn symlink_metadata(&self) -> io::Result<fs::Metadata> {
+        match *self {
+            Location::Path(ref path) => fs::symlink_metadata(path),
+            Location::At(ref dir, ref name) => dir.symlink_metadata(name),
+        }
+    }
+
+    /// The target of the symlink.
+    pub fn read_link(&self) -> io::Result<PathBuf> {
+        match *self {
+            Location::Path(ref path) => fs::read_link(path),
+            Location::At(ref dir, ref name) => dir.read_link(name),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn entries_are_found_by_name() {
+        let dir = ::std::env::temp_dir().join(format!("hat-dirfd-{}", ::time::precise_time_ns()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::File::create(dir.join("file")).unwrap().write_all(b"data").unwrap();
+        symlink("file", dir.join("link")).unwrap();
+
+        let fd = DirFd::open(&dir).unwrap();
+        let mut names = fd.read_dir().unwrap();
+        names.sort();
+        assert_eq!(names, vec![OsString::from("file"), "link".into(), "sub".into()]);
+        // Listing again starts over.
+        assert_eq!(fd.read_dir().unwrap().len(), 3);
+
+        let mut data = vec![];
+        fd.open_file(OsStr::new("file")).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data");
+        assert!(fd.open_file(OsStr::new("link")).is_err());
+
+        assert!(fd.symlink_metadata(OsStr::new("link")).unwrap().file_type().is_symlink());
+        assert_eq!(fd.symlink_metadata(OsStr::new("file")).unwrap().len(), 4);
+        assert_eq!(fd.read_link(OsStr::new("link")).unwrap(), PathBuf::from("file"));
+        assert!(fd.open_dir(OsStr::new("sub")).unwrap().read_dir().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -12,25 +12,80 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use key::DataSource;
 use std::fs;
 use std::io;
 use std::io::Read;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
+use std::process::ChildStdout;
+use std::sync::Arc;
+use util::Throttle;
+use util::dirfd::Location;
+use util::mmap::Mmap;
+use util::tee::TeeReader;
+
+/// Files at least this large are memory mapped by `FileIterator::new_mapped`.
+pub const MMAP_MIN_LEN: u64 = 16 * 1024 * 1024;
 
 pub enum FileIterator {
     File(io::BufReader<fs::File>),
+    Mapped(Mmap, usize),
     Buf(Vec<u8>, usize),
+    Tee(TeeReader),
+    /// Reads the output of a command.
+    Pipe(ChildStdout),
+    /// Reads no faster than the throttle allows.
+    Throttled(Box<FileIterator>, Arc<Throttle>),
+    /// Reads a file and tells whether it changed since it was opened.
+    Watched(Box<FileIterator>, FileWatch),
     #[cfg(all(test, feature = "benchmarks"))]
     Reader(Box<Read + Send>),
 }
 
 impl FileIterator {
     pub fn new(path: &PathBuf) -> io::Result<FileIterator> {
-        match fs::File::open(path) {
-            Ok(f) => Ok(FileIterator::File(io::BufReader::new(f))),
-            Err(e) => Err(e),
+        FileIterator::from_file(fs::File::open(path)?, false)
+    }
+
+    /// Like `new`, but memory maps large files, which saves copying their data through the
+    /// kernel. Falls back to buffered reads when mapping fails, and on 32-bit targets where
+    /// address space is scarce.
+    pub fn new_mapped(path: &PathBuf) -> io::Result<FileIterator> {
+        FileIterator::from_file(fs::File::open(path)?, true)
+    }
+
+    /// Read the already opened `file`, memory mapping it like `new_mapped` if `mmap` is set.
+    pub fn from_file(file: fs::File, mmap: bool) -> io::Result<FileIterator> {
+        if mmap && cfg!(target_pointer_width = "64") && file.metadata()?.len() >= MMAP_MIN_LEN {
+            match Mmap::open(&file) {
+                Ok(map) => return Ok(FileIterator::Mapped(map, 0)),
+                Err(e) => debug!("Reading a file without mapping it: {}", e),
+            }
         }
+        Ok(FileIterator::File(io::BufReader::new(file)))
     }
+
+    /// Tell whether the file at `location`, just opened for `it`, changes while it is read.
+    /// The file is reported as `path`.
+    pub fn watched(
+        it: FileIterator,
+        path: PathBuf,
+        location: Location,
+        mmap: bool,
+    ) -> FileIterator {
+        let state = FileState::of(&location);
+        FileIterator::Watched(
+            Box::new(it),
+            FileWatch {
+                path: path,
+                location: location,
+                mmap: mmap,
+                state: state,
+            },
+        )
+    }
+
     pub fn from_bytes(contents: Vec<u8>) -> FileIterator {
         FileIterator::Buf(contents, 0)
     }
@@ -48,6 +103,19 @@ impl Read for FileIterator {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
             FileIterator::File(ref mut f) => f.read(buf),
+            FileIterator::Mapped(ref map, ref mut pos) => {
+                let n = (&map.as_slice()[*pos..]).read(buf)?;
+                *pos += n;
+                Ok(n)
+            }
+            FileIterator::Tee(ref mut t) => t.read(buf),
+            FileIterator::Pipe(ref mut p) => p.read(buf),
+            FileIterator::Throttled(ref mut it, ref throttle) => {
+                let n = it.read(buf)?;
+                throttle.consume(n);
+                Ok(n)
+            }
+            FileIterator::Watched(ref mut it, _) => it.read(buf),
             FileIterator::Buf(ref vec, ref mut pos) => {
                 use std::cmp;
                 if *pos >= vec.len() {
@@ -64,3 +132,138 @@ impl Read for FileIterator {
         }
     }
 }
+
+impl DataSource for FileIterator {
+    fn changed(&mut self) -> bool {
+        match *self {
+            FileIterator::Throttled(ref mut it, _) => it.changed(),
+            FileIterator::Watched(_, ref watch) => watch.state != FileState::of(&watch.location),
+            _ => false,
+        }
+    }
+
+    fn restart(&mut self) -> bool {
+        match *self {
+            FileIterator::Throttled(ref mut it, _) => it.restart(),
+            // Shared reads cannot go back without the other readers.
+            FileIterator::Watched(ref it, _) if is_tee(it) => false,
+            FileIterator::Watched(ref mut it, ref mut watch) => {
+                let reopened = watch.location.open().and_then(|file| {
+                    FileIterator::from_file(file, watch.mmap)
+                });
+                match reopened {
+                    Ok(file) => {
+                        watch.state = FileState::of(&watch.location);
+                        *it = Box::new(file);
+                        true
+                    }
+                    Err(e) => {
+                        debug!("Could not read {} again: {}", watch.path.display(), e);
+                        false
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_tee(it: &FileIterator) -> bool {
+    match *it {
+        FileIterator::Tee(_) => true,
+        _ => false,
+    }
+}
+
+/// A file being read, and what it looked like when it was opened.
+pub struct FileWatch {
+    path: PathBuf,
+    location: Location,
+    mmap: bool,
+    state: Option<FileState>,
+}
+
+/// The properties of a file that change when its data is replaced or modified.
+#[derive(Debug, Eq, PartialEq)]
+struct FileState {
+    inode: u64,
+    len: u64,
+    modified_secs: i64,
+    modified_nsecs: i64,
+}
+
+impl FileState {
+    /// The current state of the file at `location`, or `None` if it is gone.
+    fn of(location: &Location) -> Option<FileState> {
+        location.symlink_metadata().ok().map(|meta| {
+            FileState {
+                inode: meta.ino(),
+                len: meta.len(),
+                modified_secs: meta.mtime(),
+                modified_nsecs: meta.mtime_nsec(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    #[cfg_attr(not(target_pointer_width = "64"), ignore)]
+    fn large_files_are_mapped() {
+        let dir = ::std::env::temp_dir().join(format!("hat-mapped-{}", ::time::precise_time_ns()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..MMAP_MIN_LEN).map(|i| (i % 251) as u8).collect();
+        fs::File::create(dir.join("large")).unwrap().write_all(&data).unwrap();
+        fs::File::create(dir.join("small")).unwrap().write_all(b"small").unwrap();
+
+        let mut it = FileIterator::new_mapped(&dir.join("large")).unwrap();
+        match it {
+            FileIterator::Mapped(..) => (),
+            _ => panic!("Large file was not mapped"),
+        }
+        let mut read = vec![];
+        it.read_to_end(&mut read).unwrap();
+        assert!(read == data);
+
+        let mut it = FileIterator::new_mapped(&dir.join("small")).unwrap();
+        match it {
+            FileIterator::File(..) => (),
+            _ => panic!("Small file was mapped"),
+        }
+        let mut read = vec![];
+        it.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"small");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changes_while_reading_are_detected() {
+        let dir = ::std::env::temp_dir().join(format!("hat-watched-{}", ::time::precise_time_ns()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::File::create(&path).unwrap().write_all(b"before").unwrap();
+
+        let location = Location::Path(path.clone());
+        let mut it =
+            FileIterator::watched(FileIterator::new(&path).unwrap(), path.clone(), location, false);
+        let mut read = vec![];
+        it.read_to_end(&mut read).unwrap();
+        assert!(!it.changed());
+
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b", after").unwrap();
+        assert!(it.changed());
+
+        assert!(it.restart());
+        let mut read = vec![];
+        it.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"before, after");
+        assert!(!it.changed());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unique ids for uploads and temporary names, replaceable by a sequence for reproducible runs.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use time;
+
+
+pub trait IdGenerator: Send + Sync {
+    /// An id that differs from the ids handed out before.
+    fn next_id(&self) -> u64;
+}
+
+/// Ids from a nanosecond clock, unique across processes in practice.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeIds;
+
+impl IdGenerator for TimeIds {
+    fn next_id(&self) -> u64 {
+        time::precise_time_ns()
+    }
+}
+
+/// Ids counting up from a fixed start.
+#[derive(Debug, Default)]
+pub struct SequentialIds {
+    next: AtomicUsize,
+}
+
+impl SequentialIds {
+    pub fn new(start: u64) -> SequentialIds {
+        SequentialIds { next: AtomicUsize::new(start as usize) }
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst) as u64
+    }
+}
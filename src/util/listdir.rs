@@ -20,6 +20,9 @@ use std::fs;
 use std::io;
 use std::iter;
 use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use util::dirfd::{DirFd, NAME_MAX, PATH_MAX};
 
 
 pub trait HasPath {
@@ -33,6 +36,178 @@ impl HasPath for fs::DirEntry {
 }
 
 
+struct QueueState<T> {
+    pending: Vec<T>,
+    busy: usize,
+    panicked: bool,
+}
+
+/// Shared stack of directories left to visit.
+///
+/// Workers pop directories until the stack is empty and no other worker is busy (and might still
+/// push more directories). A worker that panics stops the others.
+struct WorkQueue<T> {
+    state: Mutex<QueueState<T>>,
+    cond: Condvar,
+}
+
+impl<T> WorkQueue<T> {
+    fn new(initial: T) -> WorkQueue<T> {
+        WorkQueue {
+            state: Mutex::new(QueueState {
+                pending: vec![initial],
+                busy: 0,
+                panicked: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.push(item);
+        self.cond.notify_one();
+    }
+
+    /// Take the next item, blocking while other workers may still produce more.
+    /// The worker is busy with the item until the returned guard is dropped.
+    fn pop(&self) -> Option<(T, Busy<T>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.panicked {
+                return None;
+            }
+            if let Some(item) = state.pending.pop() {
+                state.busy += 1;
+                return Some((item, Busy(self)));
+            }
+            if state.busy == 0 {
+                return None;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    fn done(&self, panicked: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.busy -= 1;
+        if panicked {
+            state.panicked = true;
+            state.pending.clear();
+            self.cond.notify_all();
+        } else if state.busy == 0 && state.pending.is_empty() {
+            // Wake up idle workers so they can exit.
+            self.cond.notify_all();
+        }
+    }
+
+    fn panicked(&self) -> bool {
+        self.state.lock().unwrap().panicked
+    }
+}
+
+/// Marks an item of a `WorkQueue` as done when dropped, also when its worker panics.
+struct Busy<'a, T: 'a>(&'a WorkQueue<T>);
+
+impl<'a, T> Drop for Busy<'a, T> {
+    fn drop(&mut self) {
+        self.0.done(thread::panicking());
+    }
+}
+
+/// A directory left to visit, with the payload for its entries and, once paths get too long
+/// to resolve, the directory it is in.
+type Pending<P> = (PathBuf, P, Option<Arc<DirFd>>);
+
+
+/// Number of directory entries handed to `PathHandler::handle_paths` at a time.
+const PAGE_LEN: usize = 256;
+
+fn visit_dir<P, H>(handler: &H, queue: &WorkQueue<Pending<P>>, pending: Pending<P>)
+where
+    P: Send + 'static,
+    H: PathHandler<P> + ?Sized,
+{
+    let (root, payload, parent) = pending;
+    if parent.is_some() || root.as_os_str().len() + 1 + NAME_MAX >= PATH_MAX {
+        // The paths of some entries may be too long to resolve.
+        return visit_dir_at(handler, queue, root, payload, parent);
+    }
+
+    let handle_page = |paths: Vec<PathBuf>| {
+        let dirs = handler.handle_paths(&payload, &paths);
+        for (path, dir) in paths.into_iter().zip(dirs) {
+            if let Some(dir) = dir {
+                queue.push((path, dir, None));
+            }
+        }
+    };
+
+    match handler.read_dir(&root) {
+        Ok(dir) => {
+            let mut page = Vec::with_capacity(PAGE_LEN);
+            for entry_res in dir {
+                match entry_res {
+                    Ok(entry) => {
+                        page.push(entry.path());
+                        if page.len() == PAGE_LEN {
+                            handle_page(page);
+                            page = Vec::with_capacity(PAGE_LEN);
+                        }
+                    }
+                    Err(err) => {
+                        // For some reason, we failed to read this entry.
+                        // Just skip it and continue with the next.
+                        warn!("Could not read directory entry: {}", err);
+                    }
+                }
+            }
+            if !page.is_empty() {
+                handle_page(page);
+            }
+        }
+        Err(err) => {
+            // Cannot read this directory.
+            warn!("Skipping unreadable directory {:?}: {}", root, err);
+        }
+    }
+}
+
+/// Visit a directory through a descriptor, opened in its `parent` if that was visited the same
+/// way. Its entries are handled by name, and its subdirectories visited the same way.
+fn visit_dir_at<P, H>(
+    handler: &H,
+    queue: &WorkQueue<Pending<P>>,
+    root: PathBuf,
+    payload: P,
+    parent: Option<Arc<DirFd>>,
+) where
+    P: Send + 'static,
+    H: PathHandler<P> + ?Sized,
+{
+    let opened = match (parent, root.file_name()) {
+        (Some(parent), Some(name)) => parent.open_dir(name),
+        _ => DirFd::open(&root),
+    };
+    let (dir, names) = match opened.and_then(|dir| dir.read_dir().map(|names| (dir, names))) {
+        Ok((dir, names)) => (Arc::new(dir), names),
+        Err(err) => {
+            warn!("Skipping unreadable directory {:?}: {}", root, err);
+            return;
+        }
+    };
+    for page in names.chunks(PAGE_LEN) {
+        let paths: Vec<PathBuf> = page.iter().map(|name| root.join(name)).collect();
+        let dirs = handler.handle_paths_at(&payload, &dir, &paths);
+        for (path, sub) in paths.into_iter().zip(dirs) {
+            if let Some(sub) = sub {
+                queue.push((path, sub, Some(dir.clone())));
+            }
+        }
+    }
+}
+
+
 pub trait PathHandler<P: Send + 'static>: Sync {
     type DirItem: HasPath;
     type DirIter: iter::Iterator<Item = io::Result<Self::DirItem>>;
@@ -40,38 +215,44 @@ pub trait PathHandler<P: Send + 'static>: Sync {
     fn read_dir(&self, &PathBuf) -> io::Result<Self::DirIter>;
     fn handle_path(&self, &P, &PathBuf) -> Option<P>;
 
-    fn recurse_worker<'a>(&'a self, scope: &scoped_pool::Scope<'a>, root: PathBuf, payload: P) {
-        scope.recurse(move |scope| {
-            match self.read_dir(&root) {
-                Ok(dir) => {
-                    for entry_res in dir {
-                        match entry_res {
-                            Ok(entry) => {
-                                let path = entry.path();
-                                if let Some(dir) = self.handle_path(&payload, &path) {
-                                    self.recurse_worker(scope, path, dir);
-                                }
-                            }
-                            Err(err) => {
-                                // For some reason, we failed to read this entry.
-                                // Just skip it and continue with the next.
-                                warn!("Could not read directory entry: {}", err);
-                            }
-                        }
-                    }
-                }
-                Err(err) => {
-                    // Cannot read this directory.
-                    warn!("Skipping unreadable directory {:?}: {}", root, err);
-                }
-            }
-        });
+    /// Handle a page of entries from the same directory, returning a result per path.
+    /// Handlers can override this to process the entries of a directory together.
+    fn handle_paths(&self, payload: &P, paths: &[PathBuf]) -> Vec<Option<P>> {
+        paths.iter().map(|path| self.handle_path(payload, path)).collect()
+    }
+
+    /// Handle a page of entries of `dir`, whose paths may be too long to resolve. The entries
+    /// must be read by name relative to `dir`; `paths` are their full paths, for reporting.
+    /// Handlers that only work with paths skip these entries.
+    fn handle_paths_at(
+        &self,
+        _payload: &P,
+        _dir: &Arc<DirFd>,
+        paths: &[PathBuf],
+    ) -> Vec<Option<P>> {
+        for path in paths {
+            warn!("Skipping '{}': its path is too long", path.display());
+        }
+        paths.iter().map(|_| None).collect()
     }
 
+    /// Visit everything below `root` using a pool of workers sharing an explicit queue of
+    /// directories, so the depth of the tree does not affect the call stack. Directories too
+    /// deep to reach by path are read through descriptors. Panics if a handler panics.
     fn recurse(&self, root: PathBuf, payload: P) {
-        let pool = scoped_pool::Pool::new(10);
-        pool.scoped(move |scope| { self.recurse_worker(scope, root, payload); });
+        let workers = 10;
+        let display = root.display().to_string();
+        let queue = WorkQueue::new((root, payload, None));
+        let pool = scoped_pool::Pool::new(workers);
+        pool.scoped(|scope| for _ in 0..workers {
+            scope.execute(|| while let Some((pending, _busy)) = queue.pop() {
+                visit_dir(self, &queue, pending);
+            });
+        });
         pool.shutdown();
+        if queue.panicked() {
+            panic!("Walking {} stopped: a worker panicked", display);
+        }
     }
 }
 
@@ -83,6 +264,7 @@ mod tests {
     use std::io;
     use std::path::PathBuf;
     use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::vec;
 
     use super::*;
@@ -187,4 +369,95 @@ mod tests {
         assert_eq!(handler.not_visited(), vec![PathBuf::from("/")]);
     }
 
+    /// A single chain of nested directories named by their depth.
+    struct DeepPathHandler {
+        depth: usize,
+        visited: AtomicUsize,
+        panic_at: Option<usize>,
+    }
+
+    impl PathHandler<usize> for DeepPathHandler {
+        type DirItem = PathBuf;
+        type DirIter = vec::IntoIter<io::Result<Self::DirItem>>;
+
+        fn read_dir(&self, path: &PathBuf) -> io::Result<Self::DirIter> {
+            let level: usize = path.to_str().unwrap()[1..].parse().unwrap();
+            if level < self.depth {
+                Ok(vec![Ok(PathBuf::from(format!("/{}", level + 1)))].into_iter())
+            } else {
+                Ok(vec![].into_iter())
+            }
+        }
+
+        fn handle_path(&self, parent: &usize, _path: &PathBuf) -> Option<usize> {
+            if Some(parent + 1) == self.panic_at {
+                panic!("Handler failed");
+            }
+            self.visited.fetch_add(1, Ordering::SeqCst);
+            Some(parent + 1)
+        }
+    }
+
+    #[test]
+    fn can_visit_deep_tree() {
+        let handler = DeepPathHandler {
+            depth: 20000,
+            visited: AtomicUsize::new(0),
+            panic_at: None,
+        };
+        handler.recurse(PathBuf::from("/0"), 0);
+
+        assert_eq!(handler.visited.load(Ordering::SeqCst), 20000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn handler_panics_stop_the_walk() {
+        // Without stopping the other workers, they would wait for the panicked one forever.
+        let handler = DeepPathHandler {
+            depth: 100,
+            visited: AtomicUsize::new(0),
+            panic_at: Some(50),
+        };
+        handler.recurse(PathBuf::from("/0"), 0);
+    }
+
+    /// A single directory with many files, recording the pages it is handed.
+    struct WidePathHandler {
+        files: usize,
+        pages: Mutex<Vec<usize>>,
+    }
+
+    impl PathHandler<()> for WidePathHandler {
+        type DirItem = PathBuf;
+        type DirIter = vec::IntoIter<io::Result<Self::DirItem>>;
+
+        fn read_dir(&self, _path: &PathBuf) -> io::Result<Self::DirIter> {
+            let files: Vec<_> = (0..self.files)
+                .map(|i| Ok(PathBuf::from(format!("/{}", i))))
+                .collect();
+            Ok(files.into_iter())
+        }
+
+        fn handle_path(&self, _: &(), _path: &PathBuf) -> Option<()> {
+            unreachable!()
+        }
+
+        fn handle_paths(&self, _: &(), paths: &[PathBuf]) -> Vec<Option<()>> {
+            self.pages.lock().unwrap().push(paths.len());
+            vec![None; paths.len()]
+        }
+    }
+
+    #[test]
+    fn hands_out_directories_in_pages() {
+        let handler = WidePathHandler {
+            files: 2 * PAGE_LEN + 10,
+            pages: Mutex::new(vec![]),
+        };
+        handler.recurse(PathBuf::from("/"), ());
+
+        assert_eq!(*handler.pages.lock().unwrap(), vec![PAGE_LEN, PAGE_LEN, 10]);
+    }
+
 }
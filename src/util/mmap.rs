@@ -0,0 +1,95 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read-only memory maps of whole files.
+
+use libc;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+
+/// A file mapped into memory for reading from start to end.
+///
+/// The file must not shrink while it is mapped: reading past its new end raises `SIGBUS`.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by this value.
+unsafe impl Send for Mmap {}
+
+impl Mmap {
+    /// Map all of `file`, which must not be empty.
+    pub fn open(file: &fs::File) -> io::Result<Mmap> {
+        let len = file.metadata()?.len();
+        if len == 0 || len > usize::max_value() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "File size cannot be mapped",
+            ));
+        }
+        let len = len as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Only a hint to read ahead more aggressively, so failing is harmless.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mmap { ptr: ptr, len: len })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn maps_file_contents() {
+        let path = ::std::env::temp_dir().join(format!("hat-mmap-{}", ::time::precise_time_ns()));
+        fs::File::create(&path).unwrap().write_all(b"mapped data").unwrap();
+
+        let map = Mmap::open(&fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(map.as_slice(), &b"mapped data"[..]);
+        drop(map);
+
+        // Empty files cannot be mapped.
+        fs::File::create(&path).unwrap();
+        assert!(Mmap::open(&fs::File::open(&path).unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
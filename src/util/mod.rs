@@ -12,23 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod clock;
 mod counter;
+#[cfg(all(test, feature = "benchmarks"))]
+pub mod corpus;
+pub mod dirfd;
 mod file_iterator;
 mod fnbox;
+pub mod human;
+mod ids;
 mod infowriter;
 mod listdir;
+mod mmap;
 mod sync_pool;
 mod ordered_collection;
 mod periodic_timer;
 mod process;
+pub mod tee;
+mod throttle;
 mod unique_priority_queue;
 
+pub use self::clock::{Clock, FixedClock, SystemClock};
 pub use self::counter::Counter;
-pub use self::file_iterator::FileIterator;
+pub use self::file_iterator::{FileIterator, MMAP_MIN_LEN};
 pub use self::fnbox::FnBox;
+pub use self::ids::{IdGenerator, SequentialIds, TimeIds};
 pub use self::infowriter::InfoWriter;
 pub use self::listdir::{HasPath, PathHandler};
 pub use self::periodic_timer::PeriodicTimer;
-pub use self::process::{MsgHandler, Process};
+pub use self::process::{MsgHandler, Process, ProcessError};
 pub use self::sync_pool::{SyncPool, SyncPoolGuard};
+pub use self::throttle::Throttle;
 pub use self::unique_priority_queue::UniquePriorityQueue;
@@ -50,11 +50,45 @@
 /// A handler is allowed to call `reply()` **exactly** once. Not calling it or calling it multiple
 /// times will likely cause runtime panicure when using `send_reply()`.
 
+use std::any::Any;
+use std::error;
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 
+/// Why a supervised `process` could not handle a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The handler panicked. The next message goes to a new handler.
+    Crashed { process: String, reason: String },
+    /// A new handler could not be built after a crash.
+    RestartFailed { process: String, reason: String },
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProcessError::Crashed {
+                ref process,
+                ref reason,
+            } => write!(f, "Process {} crashed: {}", process, reason),
+            ProcessError::RestartFailed {
+                ref process,
+                ref reason,
+            } => write!(f, "Process {} could not be restarted: {}", process, reason),
+        }
+    }
+}
+
+impl error::Error for ProcessError {
+    fn description(&self) -> &str {
+        "process failed"
+    }
+}
+
 pub struct Process<Msg, Reply, E> {
     sender: mpsc::SyncSender<(Msg, mpsc::Sender<Result<Reply, E>>)>,
 }
@@ -77,6 +111,39 @@ pub trait MsgHandler<Msg, Reply>: 'static + Send {
     ) -> Result<(), Self::Err>;
 }
 
+/// Let `handler` handle a single message and make sure the sender gets exactly one reply.
+fn handle_one<Msg, Reply, E, H>(handler: &mut H, msg: Msg, rep: &mpsc::Sender<Result<Reply, E>>)
+where
+    E: fmt::Debug,
+    H: MsgHandler<Msg, Reply, Err = E>,
+{
+    let mut did_reply = false;
+    // The sender may have stopped waiting for the reply after a timeout.
+    let ret = handler.handle(msg, |r| {
+        did_reply = true;
+        let _ = rep.send(r);
+    });
+    match (ret, did_reply) {
+        (Ok(()), true) => (),
+        (Ok(()), false) => panic!("Handler returned without replying"),
+        (Err(e), true) => panic!("Encountered unrecoverable error: {:?}", e),
+        (Err(e), false) => {
+            let _ = rep.send(Err(e));
+        }
+    }
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => {
+            payload.downcast_ref::<String>().cloned().unwrap_or_else(
+                || "unknown panic".to_owned(),
+            )
+        }
+    }
+}
+
 impl<Msg, Reply, E> Process<Msg, Reply, E>
 where
     Msg: 'static + Send,
@@ -91,16 +158,63 @@ where
         let (sender, receiver) = mpsc::sync_channel::<(Msg, mpsc::Sender<Result<Reply, E>>)>(10);
 
         thread::spawn(move || while let Ok((msg, rep)) = receiver.recv() {
-            let mut did_reply = false;
-            let ret = handler.handle(msg, |r| {
-                did_reply = true;
-                rep.send(r).expect("Message reply ignored");
-            });
-            match (ret, did_reply) {
-                (Ok(()), true) => (),
-                (Ok(()), false) => panic!("Handler returned without replying"),
-                (Err(e), true) => panic!("Encountered unrecoverable error: {:?}", e),
-                (Err(e), false) => rep.send(Err(e)).expect("Message reply ignored"),
+            handle_one(&mut handler, msg, &rep);
+        });
+
+        Process { sender: sender }
+    }
+
+    /// Create and start a new process with a handler from `constructor`, restarting it when it
+    /// panics.
+    ///
+    /// The message being handled when the handler panicked fails with `ProcessError::Crashed`.
+    /// The crashed handler is dropped, and later messages go to a new handler from
+    /// `constructor`, which should build it from state the crash cannot have left half-updated.
+    pub fn new_supervised<H, C>(name: String, mut constructor: C) -> Process<Msg, Reply, E>
+    where
+        H: MsgHandler<Msg, Reply, Err = E>,
+        C: 'static + Send + FnMut() -> Result<H, E>,
+        E: From<ProcessError>,
+    {
+        let (sender, receiver) = mpsc::sync_channel::<(Msg, mpsc::Sender<Result<Reply, E>>)>(10);
+
+        thread::spawn(move || {
+            let mut handler_opt = None;
+            while let Ok((msg, rep)) = receiver.recv() {
+                if handler_opt.is_none() {
+                    match panic::catch_unwind(AssertUnwindSafe(|| constructor())) {
+                        Ok(Ok(handler)) => handler_opt = Some(handler),
+                        Ok(Err(e)) => {
+                            error!("Could not start process {}: {:?}", name, e);
+                            let _ = rep.send(Err(e));
+                            continue;
+                        }
+                        Err(payload) => {
+                            let error = ProcessError::RestartFailed {
+                                process: name.clone(),
+                                reason: panic_message(&*payload),
+                            };
+                            error!("{}", error);
+                            let _ = rep.send(Err(From::from(error)));
+                            continue;
+                        }
+                    }
+                }
+
+                let res = {
+                    let handler = handler_opt.as_mut().unwrap();
+                    panic::catch_unwind(AssertUnwindSafe(|| handle_one(handler, msg, &rep)))
+                };
+                if let Err(payload) = res {
+                    let error = ProcessError::Crashed {
+                        process: name.clone(),
+                        reason: panic_message(&*payload),
+                    };
+                    error!("{}, restarting it", error);
+                    handler_opt = None;
+                    // Nobody is listening if the handler already replied.
+                    let _ = rep.send(Err(From::from(error)));
+                }
             }
         });
 
@@ -118,4 +232,143 @@ where
         );
         receiver.recv().expect("Could not read reply")
     }
+
+    /// Synchronous send that gives up after `timeout`.
+    ///
+    /// Fails with a timeout error if the message could not be delivered and answered in time.
+    /// The `process` still handles a message that timed out, but its reply is dropped.
+    pub fn send_reply_timeout(&self, msg: Msg, timeout: Duration) -> Result<Reply, E>
+    where
+        E: From<String>,
+    {
+        let deadline = Instant::now() + timeout;
+        let timed_out = || From::from(format!("No reply within {} seconds", timeout.as_secs()));
+        let (sender, receiver) = mpsc::channel();
+
+        // The input-channel is bounded, so wait for room without passing the deadline.
+        let mut pending = (msg, sender);
+        loop {
+            match self.sender.try_send(pending) {
+                Ok(()) => break,
+                Err(mpsc::TrySendError::Full(p)) => {
+                    if Instant::now() >= deadline {
+                        return Err(timed_out());
+                    }
+                    pending = p;
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    panic!("Could not send message; process looks dead")
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let left = if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        };
+        match receiver.recv_timeout(left) {
+            Ok(reply) => reply,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(timed_out()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => panic!("Could not read reply"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    struct Fragile;
+
+    impl MsgHandler<bool, ()> for Fragile {
+        type Err = ProcessError;
+
+        fn handle<F: FnOnce(Result<(), ProcessError>)>(
+            &mut self,
+            crash: bool,
+            reply: F,
+        ) -> Result<(), ProcessError> {
+            if crash {
+                panic!("asked to crash");
+            }
+            reply(Ok(()));
+            Ok(())
+        }
+    }
+
+    struct Slow;
+
+    impl MsgHandler<u64, ()> for Slow {
+        type Err = String;
+
+        fn handle<F: FnOnce(Result<(), String>)>(
+            &mut self,
+            ms: u64,
+            reply: F,
+        ) -> Result<(), String> {
+            thread::sleep(Duration::from_millis(ms));
+            reply(Ok(()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_reply_times_out() {
+        let p = Process::new(Slow);
+
+        assert_eq!(Ok(()), p.send_reply_timeout(0, Duration::from_secs(10)));
+        assert!(p.send_reply_timeout(500, Duration::from_millis(50)).is_err());
+        // The process survives the reply it could not deliver.
+        assert_eq!(Ok(()), p.send_reply(0));
+    }
+
+    #[test]
+    fn supervised_process_restarts() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = starts.clone();
+        let p = Process::new_supervised("fragile".to_owned(), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Fragile)
+        });
+
+        assert_eq!(Ok(()), p.send_reply(false));
+        assert_eq!(
+            Err(ProcessError::Crashed {
+                process: "fragile".to_owned(),
+                reason: "asked to crash".to_owned(),
+            }),
+            p.send_reply(true)
+        );
+        assert_eq!(Ok(()), p.send_reply(false));
+        assert_eq!(2, starts.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn supervised_process_reports_failed_restarts() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = starts.clone();
+        let p = Process::new_supervised("fragile".to_owned(), move || {
+            if counter.fetch_add(1, Ordering::SeqCst) > 0 {
+                panic!("state is gone");
+            }
+            Ok(Fragile)
+        });
+
+        assert!(p.send_reply(true).is_err());
+        assert_eq!(
+            Err(ProcessError::RestartFailed {
+                process: "fragile".to_owned(),
+                reason: "state is gone".to_owned(),
+            }),
+            p.send_reply(false)
+        );
+    }
 }
@@ -0,0 +1,202 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Reading a file once for several consumers at the same time.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use util::dirfd::Location;
+
+
+/// How far the fastest reader may get ahead of the slowest one.
+const MAX_BUFFERED: usize = 8 * 1024 * 1024;
+const READ_LEN: usize = 64 * 1024;
+
+enum Source {
+    Closed(Location),
+    Open(fs::File),
+    Failed(io::ErrorKind, String),
+}
+
+struct State {
+    source: Source,
+    eof: bool,
+    buf: Vec<u8>,
+    // File offset of the first buffered byte.
+    start: u64,
+    // File offset of each reader, or `None` once it has been dropped.
+    positions: Vec<Option<u64>>,
+}
+
+/// One of several readers of the same file. The file is opened by the first reader that needs
+/// it and read only once; the data is buffered until every reader has seen it.
+///
+/// The readers must be consumed concurrently, or dropped: a reader that gets too far ahead of
+/// the others blocks until they catch up.
+pub struct TeeReader {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    index: usize,
+}
+
+/// Create `readers` readers of the file at `location`.
+pub fn file(location: Location, readers: usize) -> Vec<TeeReader> {
+    let shared = Arc::new((
+        Mutex::new(State {
+            source: Source::Closed(location),
+            eof: false,
+            buf: vec![],
+            start: 0,
+            positions: vec![Some(0); readers],
+        }),
+        Condvar::new(),
+    ));
+    (0..readers)
+        .map(|i| {
+            TeeReader {
+                shared: shared.clone(),
+                index: i,
+            }
+        })
+        .collect()
+}
+
+impl State {
+    fn open(&mut self) -> io::Result<()> {
+        let opened = match self.source {
+            Source::Closed(ref location) => Some(location.open()),
+            _ => None,
+        };
+        match opened {
+            Some(Ok(f)) => self.source = Source::Open(f),
+            Some(Err(e)) => self.source = Source::Failed(e.kind(), e.to_string()),
+            None => (),
+        }
+        match self.source {
+            Source::Failed(kind, ref msg) => Err(io::Error::new(kind, msg.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.open()?;
+        let mut chunk = vec![0; READ_LEN];
+        let len = match self.source {
+            Source::Open(ref mut f) => f.read(&mut chunk)?,
+            _ => unreachable!(),
+        };
+        if len == 0 {
+            self.eof = true;
+        }
+        self.buf.extend_from_slice(&chunk[..len]);
+        Ok(())
+    }
+
+    // Drop the data that every reader has seen.
+    fn trim(&mut self) {
+        let end = self.start + self.buf.len() as u64;
+        let min = self.positions.iter().filter_map(|&p| p).min().unwrap_or(end);
+        self.buf.drain(..(min - self.start) as usize);
+        self.start = min;
+    }
+}
+
+impl TeeReader {
+    fn lock(&self) -> MutexGuard<State> {
+        self.shared.0.lock().expect("Tee was poisoned")
+    }
+
+    /// Open the file, unless another reader already has.
+    pub fn open(&self) -> io::Result<()> {
+        self.lock().open()
+    }
+}
+
+impl Read for TeeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let cvar = &self.shared.1;
+        let mut state = self.lock();
+        loop {
+            let pos = state.positions[self.index].unwrap();
+            let offset = (pos - state.start) as usize;
+            if offset < state.buf.len() {
+                let len = cmp::min(out.len(), state.buf.len() - offset);
+                out[..len].copy_from_slice(&state.buf[offset..offset + len]);
+                state.positions[self.index] = Some(pos + len as u64);
+                state.trim();
+                cvar.notify_all();
+                return Ok(len);
+            } else if state.eof {
+                return Ok(0);
+            } else if state.buf.len() >= MAX_BUFFERED {
+                // Wait for the slowest reader to catch up.
+                state = cvar.wait(state).expect("Tee was poisoned");
+            } else {
+                state.fill()?;
+            }
+        }
+    }
+}
+
+impl Drop for TeeReader {
+    fn drop(&mut self) {
+        let mut state = self.lock();
+        state.positions[self.index] = None;
+        state.trim();
+        self.shared.1.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::thread;
+
+    #[test]
+    fn readers_see_the_whole_file() {
+        let path = ::std::env::temp_dir().join(format!("hat-tee-{}", ::time::precise_time_ns()));
+        let data: Vec<u8> = (0..3 * MAX_BUFFERED).map(|i| (i % 251) as u8).collect();
+        fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let mut readers = file(Location::Path(path.clone()), 3);
+        // An unused reader does not hold back the others.
+        drop(readers.pop());
+        let threads: Vec<_> = readers
+            .into_iter()
+            .map(|mut reader| {
+                thread::spawn(move || {
+                    reader.open().unwrap();
+                    let mut out = vec![];
+                    reader.read_to_end(&mut out).unwrap();
+                    out
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(data, t.join().unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_errors_reach_every_reader() {
+        let readers = file(Location::Path(PathBuf::from("/nonexistent/hat-tee")), 2);
+        assert!(readers[0].open().is_err());
+        assert!(readers[1].open().is_err());
+    }
+}
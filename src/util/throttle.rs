@@ -0,0 +1,92 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rate limiting shared between threads.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Seconds of unused rate that can be saved up for a burst after being idle.
+const MAX_BURST_SECS: f64 = 1.0;
+
+struct State {
+    last: Instant,
+    // Seconds the consumed bytes are ahead of the rate; negative while saving up for a burst.
+    ahead: f64,
+}
+
+/// Limits how many bytes per second several threads consume together.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    state: Mutex<State>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        assert!(bytes_per_sec > 0);
+        Throttle {
+            bytes_per_sec: bytes_per_sec,
+            state: Mutex::new(State {
+                last: Instant::now(),
+                ahead: -MAX_BURST_SECS,
+            }),
+        }
+    }
+
+    /// Account for `bytes` that were consumed, and wait until the rate allows them.
+    pub fn consume(&self, bytes: usize) {
+        let wait = self.reserve(bytes as u64, Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// How long to wait at `now` before consuming `bytes` more.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = if now > state.last {
+            let d = now - state.last;
+            d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+        } else {
+            0.0
+        };
+        state.last = now;
+        state.ahead = (state.ahead - elapsed).max(-MAX_BURST_SECS) +
+            bytes as f64 / self.bytes_per_sec as f64;
+        let wait = state.ahead.max(0.0);
+        Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_once_the_burst_is_used_up() {
+        let throttle = Throttle::new(1000);
+        let start = Instant::now() + Duration::from_secs(10);
+
+        // An idle throttle allows a burst of one second.
+        assert_eq!(throttle.reserve(2000, start), Duration::from_secs(1));
+        assert_eq!(throttle.reserve(500, start), Duration::from_millis(1500));
+
+        // Waiting pays off the debt.
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(throttle.reserve(0, later), Duration::from_secs(0));
+        assert_eq!(throttle.reserve(1000, later), Duration::from_secs(1));
+    }
+}